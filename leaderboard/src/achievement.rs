@@ -0,0 +1,298 @@
+//! # Achievements
+//!
+//! The achievements of a shard are defined by the list of `AchievementDefinition`s stored under
+//! the KV key `decs:{shard}:achievements`. Players make progress towards them through the events
+//! the leaderboard subscribes to:
+//! - `event.decs.{shard}.{entity}.mining.completed` adds the mined quantity to `MineNResources`
+//! - `event.decs.components.{shard}.{entity}.position.change` adds the distance from the last
+//!   position seen to `TravelDistance`
+//! - `event.decs.{shard}.{entity}.combat.kill`, published for the entity that destroyed an enemy,
+//!   adds one to `KillNEnemies`
+//!
+//! A player's progress is kept under `decs:achievements:{shard}:{entity}`. Once the progress of an
+//! achievement reaches its trigger's threshold, the achievement is marked `completed` so it can't
+//! be earned again, its reward is paid into the player's wallet and the unlock is announced on
+//! `event.decs.system.achievement.unlocked`.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::wallet::read_wallet;
+
+const ACHIEVEMENT_UNLOCKED_EVENT: &str = "event.decs.system.achievement.unlocked";
+
+/// Something a player did that may count towards an achievement
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Deed {
+    Mined(u32),
+    Travelled(f64),
+    Killed(u32),
+}
+
+impl Deed {
+    /// How much the deed adds to an achievement with the trigger, if it counts towards it at all
+    fn counts_towards(&self, trigger: &AchievementTrigger) -> Option<f64> {
+        match (self, trigger) {
+            (Deed::Mined(qty), AchievementTrigger::MineNResources(_)) => Some(f64::from(*qty)),
+            (Deed::Travelled(km), AchievementTrigger::TravelDistance(_)) => Some(*km),
+            (Deed::Killed(n), AchievementTrigger::KillNEnemies(_)) => Some(f64::from(*n)),
+            _ => None,
+        }
+    }
+}
+
+/// Counts the mined quantity of a completed extraction
+pub(crate) fn handle_mining_completed(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+) -> CallResult {
+    let (shard, entity_id) = shard_and_entity(&msg.subject, 2)?;
+    let completed: MiningCompleted = serde_json::from_slice(&msg.body)?;
+    handle_achievement_check(ctx, shard, entity_id, Deed::Mined(completed.qty))
+}
+
+/// Counts the distance between the entity's current position and the last one seen
+pub(crate) fn handle_position_change(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+) -> CallResult {
+    let (shard, entity_id) = shard_and_entity(&msg.subject, 3)?;
+    // Change events only carry the fields that changed, so the whole position is read instead
+    let position: Position = match ctx
+        .kv()
+        .get(&format!("decs:components:{}:{}:position", shard, entity_id))?
+    {
+        Some(s) => serde_json::from_str(&s)?,
+        None => return Ok(vec![]),
+    };
+    let last_key = format!("decs:achievements:{}:{}:last_position", shard, entity_id);
+    let last: Option<Position> = match ctx.kv().get(&last_key)? {
+        Some(s) => serde_json::from_str(&s).ok(),
+        None => None,
+    };
+    ctx.kv()
+        .set(&last_key, &serde_json::to_string(&position)?, None)?;
+
+    match last {
+        Some(last) => handle_achievement_check(
+            ctx,
+            shard,
+            entity_id,
+            Deed::Travelled(last.distance_to_3d(&position)),
+        ),
+        None => Ok(vec![]),
+    }
+}
+
+/// Counts an enemy destroyed by the entity
+pub(crate) fn handle_kill(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
+    let (shard, entity_id) = shard_and_entity(&msg.subject, 2)?;
+    handle_achievement_check(ctx, shard, entity_id, Deed::Killed(1))
+}
+
+/// Adds the deed to the player's progress and awards every achievement it completes. The
+/// progress is saved before any reward is paid, so an achievement is never paid out twice
+fn handle_achievement_check(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    deed: Deed,
+) -> CallResult {
+    let definitions: Vec<AchievementDefinition> =
+        match ctx.kv().get(&format!("decs:{}:achievements", shard))? {
+            Some(s) => serde_json::from_str(&s)?,
+            None => return Ok(vec![]),
+        };
+    let progress_key = format!("decs:achievements:{}:{}", shard, entity_id);
+    let mut progress: Vec<AchievementProgress> = match ctx.kv().get(&progress_key)? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => vec![],
+    };
+
+    let unlocked = record_progress(&definitions, &mut progress, deed);
+    ctx.kv()
+        .set(&progress_key, &serde_json::to_string(&progress)?, None)?;
+    if unlocked.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let credits: i64 = unlocked.iter().map(|a| i64::from(a.reward.credits)).sum();
+    if credits != 0 {
+        let wallet = match ctx
+            .kv()
+            .get(&format!("decs:components:{}:{}:wallet", shard, entity_id))?
+        {
+            Some(s) => {
+                let (wallet, warning) = read_wallet(&s)?;
+                if let Some(warning) = warning {
+                    ctx.log(&format!("{} for {}", warning, entity_id));
+                }
+                wallet
+            }
+            None => Wallet::default(),
+        };
+        ctx.msg().publish(
+            &format!("call.decs.components.{}.{}.wallet.set", shard, entity_id),
+            None,
+            &serde_json::to_vec(&json!({ "params": wallet.credit(credits)? }))?,
+        )?;
+    }
+    for achievement in unlocked {
+        ctx.msg().publish(
+            ACHIEVEMENT_UNLOCKED_EVENT,
+            None,
+            &serde_json::to_vec(&json!({
+                "shard": shard,
+                "entity": entity_id,
+                "achievement": achievement.id,
+                "reward": achievement.reward,
+            }))?,
+        )?;
+    }
+
+    Ok(vec![])
+}
+
+/// Adds the deed to the progress of every achievement not yet completed that it counts towards,
+/// marking and returning the achievements whose threshold it reaches
+fn record_progress(
+    definitions: &[AchievementDefinition],
+    progress: &mut Vec<AchievementProgress>,
+    deed: Deed,
+) -> Vec<AchievementDefinition> {
+    let mut unlocked = Vec::new();
+    for definition in definitions {
+        let amount = match deed.counts_towards(&definition.trigger) {
+            Some(amount) if amount.is_finite() && amount > 0.0 => amount,
+            _ => continue,
+        };
+        let index = match progress.iter().position(|p| p.id == definition.id) {
+            Some(index) => index,
+            None => {
+                progress.push(AchievementProgress {
+                    id: definition.id.to_string(),
+                    ..Default::default()
+                });
+                progress.len() - 1
+            }
+        };
+        let entry = &mut progress[index];
+        if entry.completed {
+            continue;
+        }
+        entry.current += amount;
+        if definition.trigger.is_met(entry.current) {
+            entry.completed = true;
+            unlocked.push(definition.clone());
+        }
+    }
+    unlocked
+}
+
+/// The shard and entity named by the two subject tokens starting at `index`
+fn shard_and_entity(
+    subject: &str,
+    index: usize,
+) -> std::result::Result<(&str, &str), Box<dyn std::error::Error>> {
+    let tokens: Vec<&str> = subject.split('.').collect();
+    match (tokens.get(index), tokens.get(index + 1)) {
+        (Some(shard), Some(entity_id)) => Ok((shard, entity_id)),
+        _ => Err(format!("no shard and entity in subject '{}'", subject).into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::record_progress;
+    use super::shard_and_entity;
+    use super::AchievementDefinition;
+    use super::AchievementProgress;
+    use super::AchievementTrigger;
+    use super::Deed;
+    use super::Reward;
+
+    fn definition(id: &str, trigger: AchievementTrigger) -> AchievementDefinition {
+        AchievementDefinition {
+            id: id.to_string(),
+            trigger,
+            reward: Reward { credits: 100 },
+        }
+    }
+
+    fn definitions() -> Vec<AchievementDefinition> {
+        vec![
+            definition("prospector", AchievementTrigger::MineNResources(10)),
+            definition("voyager", AchievementTrigger::TravelDistance(50.0)),
+            definition("ace", AchievementTrigger::KillNEnemies(2)),
+        ]
+    }
+
+    fn ids(unlocked: &[AchievementDefinition]) -> Vec<&str> {
+        unlocked.iter().map(|a| a.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_mining_unlocks_once_threshold_reached() {
+        let mut progress = vec![];
+        assert!(record_progress(&definitions(), &mut progress, Deed::Mined(7)).is_empty());
+        assert_eq!(
+            progress,
+            vec![AchievementProgress {
+                id: "prospector".to_string(),
+                current: 7.0,
+                completed: false,
+            }]
+        );
+
+        let unlocked = record_progress(&definitions(), &mut progress, Deed::Mined(3));
+        assert_eq!(ids(&unlocked), vec!["prospector"]);
+        assert!(progress[0].completed);
+
+        // A completed achievement can't trigger again
+        assert!(record_progress(&definitions(), &mut progress, Deed::Mined(50)).is_empty());
+        assert_eq!(progress[0].current, 10.0);
+    }
+
+    #[test]
+    fn test_travel_accumulates_distance() {
+        let mut progress = vec![];
+        for _ in 0..3 {
+            assert!(
+                record_progress(&definitions(), &mut progress, Deed::Travelled(12.5)).is_empty()
+            );
+        }
+        let unlocked = record_progress(&definitions(), &mut progress, Deed::Travelled(12.5));
+        assert_eq!(ids(&unlocked), vec!["voyager"]);
+        assert_eq!(progress[0].current, 50.0);
+        assert!(progress[0].completed);
+
+        // Unusable distances are ignored
+        let mut progress = vec![];
+        record_progress(&definitions(), &mut progress, Deed::Travelled(f64::NAN));
+        record_progress(&definitions(), &mut progress, Deed::Travelled(-5.0));
+        assert!(progress.is_empty());
+    }
+
+    #[test]
+    fn test_kills_only_count_towards_kill_achievements() {
+        let mut progress = vec![];
+        assert!(record_progress(&definitions(), &mut progress, Deed::Killed(1)).is_empty());
+        let unlocked = record_progress(&definitions(), &mut progress, Deed::Killed(1));
+        assert_eq!(ids(&unlocked), vec!["ace"]);
+        assert_eq!(unlocked[0].reward, Reward { credits: 100 });
+        assert_eq!(progress.len(), 1);
+        assert_eq!(progress[0].id, "ace");
+    }
+
+    #[test]
+    fn test_shard_and_entity_from_subject() {
+        assert_eq!(
+            shard_and_entity("event.decs.the_void.player1.mining.completed", 2).unwrap(),
+            ("the_void", "player1")
+        );
+        assert_eq!(
+            shard_and_entity("event.decs.components.the_void.player1.position.change", 3).unwrap(),
+            ("the_void", "player1")
+        );
+        assert!(shard_and_entity("event.decs", 2).is_err());
+    }
+}
@@ -4,11 +4,12 @@ use stacktrader_types as trader;
 use std::collections::HashMap;
 use std::sync::RwLock;
 use trader::components::*;
+use trader::wallet::read_wallet;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 struct LeaderBoardEntry {
     pub player: String,
-    pub amount: i32,
+    pub amount: i64,
 }
 
 impl Default for LeaderBoardEntry {
@@ -21,7 +22,7 @@ impl Default for LeaderBoardEntry {
 }
 
 lazy_static! {
-    static ref SCORES: RwLock<HashMap<String, HashMap<String, i32>>> = RwLock::new(HashMap::new());
+    static ref SCORES: RwLock<HashMap<String, HashMap<String, i64>>> = RwLock::new(HashMap::new());
 }
 
 pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
@@ -59,7 +60,7 @@ fn publish_changes(
 fn put_score(
     shard: &str,
     entity: &str,
-    amount: i32,
+    amount: i64,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
     let mut scores = SCORES.write().unwrap();
     scores.entry(shard.to_string()).or_insert_with(HashMap::new);
@@ -74,13 +75,18 @@ fn get_wallet(
     ctx: &CapabilitiesContext,
     shard: &str,
     entity: &str,
-) -> std::result::Result<CreditWallet, Box<dyn std::error::Error>> {
+) -> std::result::Result<Wallet, Box<dyn std::error::Error>> {
     let raw = ctx
         .kv()
         .get(&format!("decs:components:{}:{}:wallet", shard, entity))?;
     match raw {
-        Some(s) => match serde_json::from_str(&s) {
-            Ok(w) => Ok(w),
+        Some(s) => match read_wallet(&s) {
+            Ok((w, warning)) => {
+                if let Some(warning) = warning {
+                    ctx.log(&format!("{} for {}", warning, entity));
+                }
+                Ok(w)
+            }
             Err(_) => Err("unable to fetch wallet".into()),
         },
         None => Err("no such wallet".into()),
@@ -90,7 +96,7 @@ fn get_wallet(
 // Rank all players according to their score, then return the top 10
 // if there are less than 10 players with scores, fill the remaining slots
 // with "Nobody"
-fn rank_shard(shardmap: Option<&HashMap<String, i32>>) -> Vec<LeaderBoardEntry> {
+fn rank_shard(shardmap: Option<&HashMap<String, i64>>) -> Vec<LeaderBoardEntry> {
     match shardmap {
         Some(shardmap) => {
             let mut entries: Vec<_> = shardmap
@@ -151,12 +157,11 @@ pub(crate) fn handle_get_single(
     let idx: usize = tokens[3].parse()?; // decs.(shard).leaderboard.(idx)
     let shard = tokens[1];
     let ranks = rank_shard(SCORES.read().unwrap().get(shard));
-    let result = 
-        json!({
-            "result": {
-                "model": ranks[idx]
-            }
-        });    
+    let result = json!({
+        "result": {
+            "model": ranks[idx]
+        }
+    });
     ctx.msg()
         .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
     Ok(vec![])
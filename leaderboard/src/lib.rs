@@ -26,6 +26,7 @@ extern crate waxosuit_guest as guest;
 
 use decs::systemmgr::*;
 use guest::prelude::*;
+use stacktrader_types::metrics;
 
 call_handler!(handle_call);
 
@@ -45,7 +46,11 @@ pub fn handle_call(ctx: &CapabilitiesContext, operation: &str, msg: &[u8]) -> Ca
 
 /// Routes message to corresponding function depending on the subject of the message
 /// `decs.system.registry` => handle_ping function for registry pings
-/// `decs.frames.{shard}.{system}` => handle_frame for updating the leaderboard
+/// `decs.frames.{shard}.shard_ldrboard` => handle_frame for updating the leaderboard, then the
+/// shard statistics
+/// `decs.frames.{shard}.radar` => the shard statistics, counting radar scans
+/// `event.decs.{shard}.{entity}.mining.completed`, `event.decs.{shard}.{entity}.combat.kill` and
+/// `event.decs.components.{shard}.{entity}.position.change` => the achievement system
 fn handle_message(
     ctx: &CapabilitiesContext,
     msg: impl Into<messaging::DeliverMessage>,
@@ -58,7 +63,22 @@ fn handle_message(
         } else if msg.subject.starts_with("decs.frames.")
             && msg.subject.ends_with(".shard_ldrboard")
         {
-            leaderboard::handle_frame(ctx, msg)
+            timed_frame(ctx, msg, |ctx, msg| {
+                leaderboard::handle_frame(ctx, msg.clone())?;
+                shard_stats::handle_shard_stats_frame(ctx, msg)
+            })
+        } else if msg.subject.starts_with("decs.frames.") && msg.subject.ends_with(".radar") {
+            shard_stats::handle_scan_event(ctx, &msg)
+        } else if msg.subject.starts_with("event.decs.components.")
+            && msg.subject.ends_with(".position.change")
+        {
+            achievement::handle_position_change(ctx, &msg)
+        } else if msg.subject.starts_with("event.decs.")
+            && msg.subject.ends_with(".mining.completed")
+        {
+            achievement::handle_mining_completed(ctx, &msg)
+        } else if msg.subject.starts_with("event.decs.") && msg.subject.ends_with(".combat.kill") {
+            achievement::handle_kill(ctx, &msg)
         } else {
             match ResProtocolRequest::from(msg.subject.as_str()) {
                 ResProtocolRequest::Get(rid) if msg.subject.ends_with("leaderboard") => {
@@ -86,6 +106,26 @@ fn handle_access(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> C
     Ok(vec![])
 }
 
+/// Runs a frame handler, publishing how long the frame took to process and storing a summary
+/// of the system's recent frame times every 100 frames, see `metrics::timed_frame`
+fn timed_frame(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+    handler: fn(&CapabilitiesContext, messaging::BrokerMessage) -> CallResult,
+) -> CallResult {
+    let body = msg.body.clone();
+    let subject = msg.subject.clone();
+    metrics::timed_frame(
+        &metrics::SystemClock,
+        metrics::frame_system(&subject),
+        &body,
+        || handler(ctx, msg),
+        |subject, payload| ctx.msg().publish(subject, None, payload),
+        |key, value| ctx.kv().set(key, value, None),
+        |msg| ctx.log(msg),
+    )
+}
+
 /// Receives messages on the subject `system.registry` and replies with radar system metadata
 fn handle_ping(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
     let payload = System {
@@ -107,4 +147,6 @@ fn handle_ping(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> Call
     Ok(vec![])
 }
 
+mod achievement;
 mod leaderboard;
+mod shard_stats;
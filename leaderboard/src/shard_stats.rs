@@ -0,0 +1,324 @@
+//! # Shard Statistics
+//!
+//! Every `STATS_INTERVAL` leaderboard frames of a shard, the shard's statistics are aggregated
+//! and set on its `decs.components.{shard}.global.shard_stats` component. The KV store can't scan
+//! keys, so entities are counted through the shard's `decs:{shard}:{component}:entities` sets:
+//! - `total_entities` counts the entities with any of the `COUNTED_COMPONENTS`
+//! - `active_miners` counts the entities with at least one extractor
+//! - `total_resources_available` adds up the quantity of every `mining_resource`
+//! - `radar_scan_count_per_minute` counts the radar frames, one per scanning entity, of the radar
+//!   system's last complete minute
+//!
+//! Frames and scans are counted in memory, per shard, for the lifetime of the actor.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use trader::components::*;
+
+/// Number of leaderboard frames of a shard between two aggregations
+const STATS_INTERVAL: u64 = 60;
+const RADAR_FRAMERATE: u32 = 1;
+const MS_PER_MINUTE: u64 = 60_000;
+const EXTRACTORS: &str = "extractors";
+const MINING_RESOURCE: &str = "mining_resource";
+
+/// The components whose entities make up the shard's population
+const COUNTED_COMPONENTS: &[&str] = &[
+    "position",
+    "transponder",
+    "wallet",
+    EXTRACTORS,
+    MINING_RESOURCE,
+];
+
+lazy_static! {
+    // shard -> leaderboard frames seen since the last aggregation
+    static ref FRAMES: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+    // shard -> radar scans counted by minute
+    static ref SCANS: Mutex<HashMap<String, ScanWindow>> = Mutex::new(HashMap::new());
+}
+
+/// Aggregated counts of a shard's entities and resources
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub(crate) struct ShardStats {
+    pub total_entities: u64,
+    pub active_miners: u32,
+    pub total_resources_available: f64,
+    pub radar_scan_count_per_minute: u32,
+}
+
+/// The radar scans of the current minute and of the last complete one
+#[derive(Debug, Default)]
+struct ScanWindow {
+    minute: u64,
+    count: u32,
+    last_minute_count: u32,
+}
+
+impl ScanWindow {
+    fn record(&mut self, minute: u64) {
+        if minute > self.minute {
+            // A minute without any scan in between leaves nothing to report
+            self.last_minute_count = if minute == self.minute + 1 {
+                self.count
+            } else {
+                0
+            };
+            self.minute = minute;
+            self.count = 0;
+        }
+        self.count += 1;
+    }
+}
+
+/// Counts the leaderboard frame, aggregating and publishing the shard's statistics once every
+/// `STATS_INTERVAL` frames
+pub(crate) fn handle_shard_stats_frame(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+    if !record_frame(&frame.shard) {
+        return Ok(vec![]);
+    }
+    let snapshot = read_snapshot(ctx, &frame.shard)?;
+    let stats = aggregate(&frame.shard, &snapshot, scans_per_minute(&frame.shard));
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.global.shard_stats.set",
+            frame.shard
+        ),
+        None,
+        &serde_json::to_vec(&json!({ "params": stats }))?,
+    )?;
+    Ok(vec![])
+}
+
+/// Counts a radar frame on `decs.frames.{shard}.radar` as one scan
+pub(crate) fn handle_scan_event(
+    _ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+    let minute = trader::clock::frame_time_ms(&frame, RADAR_FRAMERATE) / MS_PER_MINUTE;
+    SCANS
+        .lock()
+        .unwrap()
+        .entry(frame.shard)
+        .or_default()
+        .record(minute);
+    Ok(vec![])
+}
+
+/// Counts a frame of the shard, returning whether it is time to aggregate
+fn record_frame(shard: &str) -> bool {
+    let mut frames = FRAMES.lock().unwrap();
+    let count = frames.entry(shard.to_string()).or_insert(0);
+    *count += 1;
+    if *count >= STATS_INTERVAL {
+        *count = 0;
+        true
+    } else {
+        false
+    }
+}
+
+fn scans_per_minute(shard: &str) -> u32 {
+    SCANS
+        .lock()
+        .unwrap()
+        .get(shard)
+        .map_or(0, |window| window.last_minute_count)
+}
+
+/// Reads the values and members of the KV keys the statistics are aggregated from: the entity
+/// sets of the counted components, the extractor lists of the entities that have extractors
+/// and the entities' mining resources
+fn read_snapshot(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+) -> std::result::Result<KvSnapshot, Box<dyn std::error::Error>> {
+    let mut snapshot = KvSnapshot::default();
+    for component in COUNTED_COMPONENTS {
+        let key = entities_key(shard, component);
+        let entities = ctx.kv().set_members(&key)?;
+        for entity_id in &entities {
+            if *component == EXTRACTORS {
+                let list = component_key(shard, entity_id, EXTRACTORS);
+                let extractors = ctx.kv().list_range(&list, 0, -1)?;
+                snapshot.members.insert(list, extractors);
+            } else if *component == MINING_RESOURCE {
+                let resource = component_key(shard, entity_id, MINING_RESOURCE);
+                if let Some(value) = ctx.kv().get(&resource)? {
+                    snapshot.values.insert(resource, value);
+                }
+            }
+        }
+        snapshot.members.insert(key, entities);
+    }
+    Ok(snapshot)
+}
+
+/// The parts of the KV store the statistics are aggregated from, sets and lists by their
+/// members and plain keys by their value
+#[derive(Debug, Default)]
+struct KvSnapshot {
+    members: HashMap<String, Vec<String>>,
+    values: HashMap<String, String>,
+}
+
+impl KvSnapshot {
+    fn members(&self, key: &str) -> &[String] {
+        self.members.get(key).map_or(&[], |m| m.as_slice())
+    }
+}
+
+/// Aggregates the shard's statistics from what was read of the KV store. Resources that can't
+/// be read count for nothing
+fn aggregate(shard: &str, snapshot: &KvSnapshot, scans_per_minute: u32) -> ShardStats {
+    let entities: BTreeSet<&String> = COUNTED_COMPONENTS
+        .iter()
+        .flat_map(|component| snapshot.members(&entities_key(shard, component)))
+        .collect();
+    let active_miners = snapshot
+        .members(&entities_key(shard, EXTRACTORS))
+        .iter()
+        .filter(|entity_id| {
+            !snapshot
+                .members(&component_key(shard, entity_id, EXTRACTORS))
+                .is_empty()
+        })
+        .count();
+    let total_resources_available = snapshot
+        .members(&entities_key(shard, MINING_RESOURCE))
+        .iter()
+        .filter_map(|entity_id| {
+            snapshot
+                .values
+                .get(&component_key(shard, entity_id, MINING_RESOURCE))
+        })
+        .filter_map(|value| serde_json::from_str::<MiningResource>(value).ok())
+        .map(|resource| f64::from(resource.qty))
+        .sum();
+    ShardStats {
+        total_entities: entities.len() as u64,
+        active_miners: active_miners as u32,
+        total_resources_available,
+        radar_scan_count_per_minute: scans_per_minute,
+    }
+}
+
+fn entities_key(shard: &str, component: &str) -> String {
+    format!("decs:{}:{}:entities", shard, component)
+}
+
+fn component_key(shard: &str, entity_id: &str, component: &str) -> String {
+    format!("decs:components:{}:{}:{}", shard, entity_id, component)
+}
+
+#[cfg(test)]
+mod test {
+    use super::aggregate;
+    use super::record_frame;
+    use super::KvSnapshot;
+    use super::ScanWindow;
+    use super::ShardStats;
+    use super::STATS_INTERVAL;
+
+    fn members(snapshot: &mut KvSnapshot, key: &str, members: &[&str]) {
+        snapshot.members.insert(
+            key.to_string(),
+            members.iter().map(|m| m.to_string()).collect(),
+        );
+    }
+
+    fn snapshot() -> KvSnapshot {
+        let mut snapshot = KvSnapshot::default();
+        members(
+            &mut snapshot,
+            "decs:the_void:position:entities",
+            &["player1", "player2", "asteroid1", "asteroid2"],
+        );
+        members(
+            &mut snapshot,
+            "decs:the_void:wallet:entities",
+            &["player1", "player2", "trader1"],
+        );
+        members(
+            &mut snapshot,
+            "decs:the_void:extractors:entities",
+            &["player1", "player2"],
+        );
+        members(
+            &mut snapshot,
+            "decs:components:the_void:player1:extractors",
+            &["decs.components.the_void.player1.extractors.1"],
+        );
+        members(
+            &mut snapshot,
+            "decs:components:the_void:player2:extractors",
+            &[],
+        );
+        members(
+            &mut snapshot,
+            "decs:the_void:mining_resource:entities",
+            &["asteroid1", "asteroid2", "asteroid3"],
+        );
+        for (entity_id, resource) in &[
+            ("asteroid1", r#"{"stack_type": "tasty", "qty": 7}"#),
+            ("asteroid2", r#"{"stack_type": "spendy", "qty": 5}"#),
+            ("asteroid3", "not a resource"),
+        ] {
+            snapshot.values.insert(
+                format!("decs:components:the_void:{}:mining_resource", entity_id),
+                resource.to_string(),
+            );
+        }
+        snapshot
+    }
+
+    #[test]
+    fn test_aggregation_counts_each_entity_once() {
+        assert_eq!(
+            aggregate("the_void", &snapshot(), 12),
+            ShardStats {
+                total_entities: 6,
+                active_miners: 1,
+                total_resources_available: 12.0,
+                radar_scan_count_per_minute: 12,
+            }
+        );
+        // Another shard's keys count for nothing
+        assert_eq!(
+            aggregate("shard-two", &snapshot(), 0),
+            ShardStats::default()
+        );
+    }
+
+    #[test]
+    fn test_stats_are_due_every_interval() {
+        // Frames are counted per shard for the lifetime of the actor, so this shard is only
+        // used by this test
+        for _ in 1..STATS_INTERVAL {
+            assert!(!record_frame("counted_void"));
+        }
+        assert!(record_frame("counted_void"));
+        assert!(!record_frame("counted_void"));
+    }
+
+    #[test]
+    fn test_scans_are_reported_for_the_last_complete_minute() {
+        let mut window = ScanWindow::default();
+        for _ in 0..3 {
+            window.record(0);
+        }
+        assert_eq!(window.last_minute_count, 0);
+        window.record(1);
+        assert_eq!(window.last_minute_count, 3);
+        // Nothing was scanned in minute 2
+        window.record(3);
+        assert_eq!(window.last_minute_count, 0);
+        assert_eq!(window.count, 1);
+    }
+}
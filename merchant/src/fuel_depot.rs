@@ -0,0 +1,214 @@
+//! # Fuel Depot
+//!
+//! The fuel depot system awaits frames for entities that have a `fuel_depot` component. Each
+//! frame, every ship whose `docked_ship` component is docked to the depot's entity receives up to
+//! `refuel_rate_per_ms * elapsed_ms` units of fuel, limited by the room left in its `fuel_tank`
+//! and by the fuel remaining in the depot. Ships are refueled in entity ID order, so when the
+//! depot is running low the first ships get topped up first.
+//!
+//! When the depot runs dry, an event is published on `event.decs.system.fuel_depot.empty`
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::validation::Validate;
+
+const DEPOT_EMPTY_EVENT: &str = "event.decs.system.fuel_depot.empty";
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let depot_value = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard,
+        frame.entity_id,
+        super::FUEL_DEPOT
+    ))?;
+    if let Some(depot_str) = depot_value {
+        let depot: FuelDepot = serde_json::from_str(&depot_str)?;
+        let tanks = get_docked_tanks(ctx, &frame.shard, &frame.entity_id)?;
+        let (new_depot, refueled) = plan_refuel(&depot, &tanks, frame.elapsed_ms);
+
+        if refueled.is_empty() {
+            return Ok(vec![]);
+        }
+        // Refuel no ship at all rather than only some of them
+        for (_, tank) in &refueled {
+            tank.validate()?;
+        }
+        for (ship, tank) in &refueled {
+            publish_set(ctx, &frame.shard, ship, super::FUEL_TANK, tank)?;
+        }
+        publish_set(
+            ctx,
+            &frame.shard,
+            &frame.entity_id,
+            super::FUEL_DEPOT,
+            &new_depot,
+        )?;
+        if new_depot.fuel_available <= 0.0 {
+            ctx.msg().publish(
+                DEPOT_EMPTY_EVENT,
+                None,
+                &serde_json::to_vec(&serde_json::json!({
+                    "shard": frame.shard,
+                    "depot": frame.entity_id,
+                }))?,
+            )?;
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// Retrieve the fuel tanks of all ships docked to the depot, sorted by entity ID
+fn get_docked_tanks(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    depot: &str,
+) -> std::result::Result<Vec<(String, FuelTank)>, Box<dyn std::error::Error>> {
+    let mut ships =
+        ctx.kv()
+            .set_members(&format!("decs:{}:{}:entities", shard, super::DOCKED_SHIP))?;
+    ships.sort();
+
+    let mut tanks = Vec::new();
+    for ship in ships {
+        let docked: Option<DockedShip> = get_component(ctx, shard, &ship, super::DOCKED_SHIP)?;
+        if docked.map(|d| d.docked_to == depot).unwrap_or(false) {
+            if let Some(tank) = get_component(ctx, shard, &ship, super::FUEL_TANK)? {
+                tanks.push((ship, tank));
+            }
+        }
+    }
+    Ok(tanks)
+}
+
+fn get_component<T: serde::de::DeserializeOwned>(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity: &str,
+    component: &str,
+) -> std::result::Result<Option<T>, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity, component
+    ))? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+fn publish_set<T: serde::Serialize>(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity: &str,
+    component: &str,
+    value: &T,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard, entity, component
+        ),
+        None,
+        &serde_json::to_vec(&serde_json::json!({ "params": value }))?,
+    )?;
+    Ok(())
+}
+
+/// Transfers fuel from the depot into each tank in turn. Returns the updated depot and the
+/// tanks that received fuel
+fn plan_refuel(
+    depot: &FuelDepot,
+    tanks: &[(String, FuelTank)],
+    elapsed_ms: u32,
+) -> (FuelDepot, Vec<(String, FuelTank)>) {
+    let max_transfer = depot.refuel_rate_per_ms * f64::from(elapsed_ms);
+    let mut fuel_available = depot.fuel_available;
+    let mut refueled = Vec::new();
+
+    for (ship, tank) in tanks {
+        let transfer = max_transfer
+            .min(tank.max - tank.current)
+            .min(fuel_available);
+        if transfer > 0.0 {
+            fuel_available -= transfer;
+            refueled.push((
+                ship.to_string(),
+                FuelTank {
+                    current: tank.current + transfer,
+                    ..tank.clone()
+                },
+            ));
+        }
+    }
+
+    (
+        FuelDepot {
+            fuel_available,
+            ..depot.clone()
+        },
+        refueled,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::plan_refuel;
+    use super::FuelDepot;
+    use super::FuelTank;
+
+    fn depot(fuel_available: f64) -> FuelDepot {
+        FuelDepot {
+            fuel_available,
+            refuel_rate_per_ms: 0.01,
+            fuel_type: "deuterium".to_string(),
+        }
+    }
+
+    fn tank(current: f64, max: f64) -> FuelTank {
+        FuelTank { current, max }
+    }
+
+    #[test]
+    fn test_partial_refuel() {
+        let tanks = vec![("ship1".to_string(), tank(20.0, 100.0))];
+        let (new_depot, refueled) = plan_refuel(&depot(500.0), &tanks, 1000);
+
+        assert_eq!(new_depot.fuel_available, 490.0);
+        assert_eq!(refueled, vec![("ship1".to_string(), tank(30.0, 100.0))]);
+    }
+
+    #[test]
+    fn test_full_tank_stops_refueling() {
+        let tanks = vec![
+            ("ship1".to_string(), tank(95.0, 100.0)),
+            ("ship2".to_string(), tank(100.0, 100.0)),
+        ];
+        let (new_depot, refueled) = plan_refuel(&depot(500.0), &tanks, 1000);
+
+        assert_eq!(new_depot.fuel_available, 495.0);
+        assert_eq!(refueled, vec![("ship1".to_string(), tank(100.0, 100.0))]);
+    }
+
+    #[test]
+    fn test_depot_exhaustion() {
+        let tanks = vec![
+            ("ship1".to_string(), tank(0.0, 100.0)),
+            ("ship2".to_string(), tank(0.0, 100.0)),
+        ];
+        let (new_depot, refueled) = plan_refuel(&depot(15.0), &tanks, 1000);
+
+        assert_eq!(new_depot.fuel_available, 0.0);
+        assert_eq!(
+            refueled,
+            vec![
+                ("ship1".to_string(), tank(10.0, 100.0)),
+                ("ship2".to_string(), tank(5.0, 100.0))
+            ]
+        );
+
+        let (_, refueled) = plan_refuel(&new_depot, &tanks, 1000);
+        assert!(refueled.is_empty());
+    }
+}
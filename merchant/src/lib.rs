@@ -17,12 +17,24 @@ extern crate waxosuit_guest as guest;
 
 use decs::systemmgr::*;
 use guest::prelude::*;
+use stacktrader_types::metrics;
 
 call_handler!(handle_call);
 
 const NO_MESSAGE: &str = "(no message)";
 const SELL_LIST: &str = "sell_list";
 const SYSTEM_NAME: &str = "merchant";
+const FUEL_DEPOT_SYSTEM_NAME: &str = "fuel_depot";
+const FUEL_DEPOT: &str = "fuel_depot";
+const FUEL_TANK: &str = "fuel_tank";
+const SUPPLY_CHAIN_SYSTEM_NAME: &str = "supply_chain";
+const RESOURCE_PRODUCER: &str = "resource_producer";
+const RESOURCE_CONSUMER: &str = "resource_consumer";
+const SUPPLY_LINK: &str = "supply_link";
+const RECYCLER_SYSTEM_NAME: &str = "recycler";
+const RESOURCE_RECYCLER: &str = "resource_recycler";
+const RECYCLE_QUEUE: &str = "recycle_queue";
+const DOCKED_SHIP: &str = "docked_ship";
 const WALLET: &str = "wallet";
 const REGISTRY_SUBJECT: &str = "decs.system.registry";
 const FRAMERATE: u32 = 1;
@@ -35,7 +47,10 @@ pub fn handle_call(ctx: &CapabilitiesContext, operation: &str, msg: &[u8]) -> Ca
     }
 }
 
-/// Routes message either to the `handle_ping` function for registry pings or `handle_frame` for position updates
+/// Routes message either to the `handle_ping` function for registry pings,
+/// `fuel_depot::handle_frame` for fuel depot frames, `supply_chain::handle_frame` for supply
+/// chain frames, `recycler::handle_frame` for recycler frames or `handle_frame` for position
+/// updates
 fn handle_message(
     ctx: &CapabilitiesContext,
     msg: impl Into<messaging::DeliverMessage>,
@@ -51,29 +66,81 @@ fn handle_message(
     match subject.as_ref() {
         NO_MESSAGE => Err("No message".into()),
         REGISTRY_SUBJECT => handle_ping(ctx, msg.unwrap()),
-        _ => merchant::handle_frame(ctx, msg.unwrap()),
+        s if s.starts_with("decs.frames.") && s.ends_with(".fuel_depot") => {
+            timed_frame(ctx, msg.unwrap(), fuel_depot::handle_frame)
+        }
+        s if s.starts_with("decs.frames.") && s.ends_with(".supply_chain") => {
+            timed_frame(ctx, msg.unwrap(), supply_chain::handle_frame)
+        }
+        s if s.starts_with("decs.frames.") && s.ends_with(".recycler") => {
+            timed_frame(ctx, msg.unwrap(), recycler::handle_frame)
+        }
+        _ => timed_frame(ctx, msg.unwrap(), merchant::handle_frame),
     }
 }
 
-/// Receives messages on the subject `system.registry` and replies with physics system metadata
+/// Runs a frame handler, publishing how long the frame took to process and storing a summary
+/// of the system's recent frame times every 100 frames, see `metrics::timed_frame`
+fn timed_frame(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+    handler: fn(&CapabilitiesContext, messaging::BrokerMessage) -> CallResult,
+) -> CallResult {
+    let body = msg.body.clone();
+    let subject = msg.subject.clone();
+    metrics::timed_frame(
+        &metrics::SystemClock,
+        metrics::frame_system(&subject),
+        &body,
+        || handler(ctx, msg),
+        |subject, payload| ctx.msg().publish(subject, None, payload),
+        |key, value| ctx.kv().set(key, value, None),
+        |msg| ctx.log(msg),
+    )
+}
+
+/// Receives messages on the subject `system.registry` and replies with the metadata of
+/// the merchant, fuel depot, supply chain and recycler systems
 fn handle_ping(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
-    let payload = System {
-        name: SYSTEM_NAME.to_string(),
-        framerate: FRAMERATE,
-        components: vec![SELL_LIST.to_string()],
-    };
+    let payloads = vec![
+        System {
+            name: SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![SELL_LIST.to_string()],
+        },
+        System {
+            name: FUEL_DEPOT_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![FUEL_DEPOT.to_string()],
+        },
+        System {
+            name: SUPPLY_CHAIN_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![RESOURCE_PRODUCER.to_string()],
+        },
+        System {
+            name: RECYCLER_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![RESOURCE_RECYCLER.to_string()],
+        },
+    ];
     let reply_to = if msg.reply_to.is_empty() {
         format!("{}.replies", REGISTRY_SUBJECT)
     } else {
         msg.reply_to
     };
-    if let Err(e) = ctx
-        .msg()
-        .publish(&reply_to, None, &serde_json::to_vec(&payload)?)
-    {
-        return Err(format!("Error publishing message: {}", e).into());
-    };
+    for payload in payloads {
+        if let Err(e) = ctx
+            .msg()
+            .publish(&reply_to, None, &serde_json::to_vec(&payload)?)
+        {
+            return Err(format!("Error publishing message: {}", e).into());
+        };
+    }
     Ok(vec![])
 }
 
+mod fuel_depot;
 mod merchant;
+mod recycler;
+mod supply_chain;
@@ -15,10 +15,8 @@ use decscloud_common::gateway::*;
 use guest::prelude::*;
 use stacktrader_types as trader;
 use trader::components::*;
-
-const STACK_SPENDY: &str = "spendy";
-const STACK_TASTY: &str = "tasty";
-const STACK_CRITICAL: &str = "critical";
+use trader::migration::migrate_component;
+use trader::wallet::read_wallet;
 
 /// Receives an entity, shard, elapsed time, etc from an EntityFrame
 /// published on decs.frames.{shard}.{system}, e.g. `decs.frames.the_void.physics`
@@ -49,19 +47,15 @@ fn get_sell_list_rids(ctx: &CapabilitiesContext, shard: &str, entity: &str) -> R
     Ok(ctx.kv().list_range(&key, 0, -1)?)
 }
 
-/// Retrieve the contents of an inventory item from the KV store. Note that due to current
-/// game design, this value is identical to a `MiningResource`. In the future, there might be
-/// another structure for an inventory item
+/// Retrieve the contents of an inventory item from the KV store. Items sold from an older
+/// inventory, stored as a bare `MiningResource`, are upgraded to an `InventoryItem`
 fn get_sell_item(
     ctx: &CapabilitiesContext,
     rid: &str,
-) -> std::result::Result<MiningResource, Box<dyn std::error::Error>> {
+) -> std::result::Result<InventoryItem, Box<dyn std::error::Error>> {
     let key = rid.replace('.', ":");
     match &ctx.kv().get(&key)? {
-        Some(ref s) => {
-            let mr: MiningResource = serde_json::from_str(s)?;
-            Ok(mr)
-        }
+        Some(ref s) => Ok(migrate_component(s)?.into_inner()),
         None => Err("no such item".into()),
     }
 }
@@ -95,30 +89,24 @@ fn publish_credits_add(
     ctx: &CapabilitiesContext,
     shard: &str,
     entity: &str,
-    item: &MiningResource,
+    item: &InventoryItem,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
     let key = format!("decs:components:{}:{}:{}", shard, entity, super::WALLET);
 
-    let wallet: CreditWallet = {
-        match ctx.kv().get(&key)? {
-            Some(s) => serde_json::from_str(&s)?,
-            None => CreditWallet::default(),
+    let wallet = match ctx.kv().get(&key)? {
+        Some(s) => {
+            let (wallet, warning) = read_wallet(&s)?;
+            if let Some(warning) = warning {
+                ctx.log(&format!("{} for {}", warning, entity));
+            }
+            wallet
         }
+        None => Wallet::default(),
     };
 
-    let itemval: i32 = if item.stack_type == STACK_CRITICAL {
-        100
-    } else if item.stack_type == STACK_TASTY {
-        50
-    } else if item.stack_type == STACK_SPENDY {
-        30
-    } else {
-        0 // this shouldn't happen unless there's a malformed mining resource in the player's inv
-    };
-    let new_amount = (itemval * item.qty as i32) + wallet.credits; // TODO: this is not idempotent and potentially problematic with multiple merchant systems running...
-    let wallet = serde_json::json!({"params": CreditWallet {
-        credits: new_amount,
-    }});
+    // TODO: this is not idempotent and potentially problematic with multiple merchant systems running...
+    let wallet = wallet.credit(i64::from(item.unit_value) * i64::from(item.qty))?;
+    let wallet = serde_json::json!({ "params": wallet });
 
     let setreq = ResProtocolRequest::Set(key.replace(':', ".").to_string());
     ctx.msg()
@@ -0,0 +1,269 @@
+//! # Recycler
+//!
+//! The recycler system awaits frames for entities that have a `resource_recycler` component. A
+//! recycler counts its `remaining_ms` down by the elapsed time. Once it is ready, at 0, it takes
+//! the item at the front of its `recycle_queue` and breaks it down into the raw materials of the
+//! item's `recycle_value`, looked up under `decs:item_properties:{item}`. Each material's quantity
+//! is scaled by the recycler's `efficiency`, rounded down, and added to the recycler's inventory.
+//! Recycling an item starts a new cycle of `cycle_time_ms`; with an empty queue the recycler
+//! idles, ready for the next item. Items without properties are dropped from the queue without
+//! yielding anything.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const INVENTORY: &str = "inventory";
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let recycler: ResourceRecycler = match get_component(
+        ctx,
+        &frame.shard,
+        &frame.entity_id,
+        super::RESOURCE_RECYCLER,
+    )? {
+        Some(recycler) => recycler,
+        None => return Ok(vec![]),
+    };
+    let queue: RecycleQueue =
+        get_component(ctx, &frame.shard, &frame.entity_id, super::RECYCLE_QUEUE)?
+            .unwrap_or_default();
+    let (new_recycler, new_queue, item) = plan_cycle(&recycler, &queue, frame.elapsed_ms);
+
+    if let Some(item) = item {
+        match get_item_properties(ctx, &item)? {
+            Some(properties) => {
+                for (subject, payload) in recycle_messages(
+                    &frame.shard,
+                    &frame.entity_id,
+                    &properties,
+                    recycler.efficiency,
+                ) {
+                    ctx.msg()
+                        .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+                }
+            }
+            None => ctx.log(&format!(
+                "Recycler {} dropped {}, an item without properties",
+                frame.entity_id, item
+            )),
+        }
+    }
+    if new_queue != queue {
+        publish_set(
+            ctx,
+            &frame.shard,
+            &frame.entity_id,
+            super::RECYCLE_QUEUE,
+            &new_queue,
+        )?;
+    }
+    if new_recycler != recycler {
+        publish_set(
+            ctx,
+            &frame.shard,
+            &frame.entity_id,
+            super::RESOURCE_RECYCLER,
+            &new_recycler,
+        )?;
+    }
+
+    Ok(vec![])
+}
+
+fn get_item_properties(
+    ctx: &CapabilitiesContext,
+    item: &str,
+) -> std::result::Result<Option<ItemProperties>, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&format!("decs:item_properties:{}", item))? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+fn get_component<T: serde::de::DeserializeOwned>(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity: &str,
+    component: &str,
+) -> std::result::Result<Option<T>, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity, component
+    ))? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+fn publish_set<T: serde::Serialize>(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity: &str,
+    component: &str,
+    value: &T,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard, entity, component
+        ),
+        None,
+        &serde_json::to_vec(&serde_json::json!({ "params": value }))?,
+    )?;
+    Ok(())
+}
+
+/// Runs the recycler's cycle for the elapsed time. A ready recycler takes the front item off the
+/// queue and starts a new cycle. Returns the updated recycler and queue and the item to recycle
+fn plan_cycle(
+    recycler: &ResourceRecycler,
+    queue: &RecycleQueue,
+    elapsed_ms: u32,
+) -> (ResourceRecycler, RecycleQueue, Option<String>) {
+    let remaining_ms = (recycler.remaining_ms - f64::from(elapsed_ms)).max(0.0);
+    let mut queue = queue.clone();
+    let item = if remaining_ms <= 0.0 {
+        queue.items.pop_front()
+    } else {
+        None
+    };
+    let remaining_ms = if item.is_some() {
+        recycler.cycle_time_ms
+    } else {
+        remaining_ms
+    };
+
+    (
+        ResourceRecycler {
+            remaining_ms,
+            ..recycler.clone()
+        },
+        queue,
+        item,
+    )
+}
+
+/// The raw materials an item breaks down into at the given efficiency, in name order. Materials
+/// that round down to nothing are left out
+fn recycle_outputs(properties: &ItemProperties, efficiency: f64) -> Vec<(String, u32)> {
+    let mut outputs: Vec<(String, u32)> = properties
+        .recycle_value
+        .iter()
+        .map(|(material, qty)| {
+            (
+                material.to_string(),
+                (qty * efficiency.max(0.0)).floor() as u32,
+            )
+        })
+        .filter(|(_, qty)| *qty > 0)
+        .collect();
+    outputs.sort();
+    outputs
+}
+
+/// The `new` calls adding each recycled material to the recycler's inventory
+fn recycle_messages(
+    shard: &str,
+    entity_id: &str,
+    properties: &ItemProperties,
+    efficiency: f64,
+) -> Vec<(String, serde_json::Value)> {
+    recycle_outputs(properties, efficiency)
+        .into_iter()
+        .map(|(material, qty)| {
+            let item = InventoryItem::from(&MiningResource {
+                stack_type: material,
+                qty,
+                ..Default::default()
+            });
+            (
+                format!(
+                    "call.decs.components.{}.{}.{}.new",
+                    shard, entity_id, INVENTORY
+                ),
+                serde_json::json!({ "params": item }),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::plan_cycle;
+    use super::recycle_messages;
+    use super::ItemProperties;
+    use super::RecycleQueue;
+    use super::ResourceRecycler;
+
+    fn recycler(remaining_ms: f64) -> ResourceRecycler {
+        ResourceRecycler {
+            efficiency: 0.5,
+            cycle_time_ms: 3000.0,
+            remaining_ms,
+        }
+    }
+
+    fn queue(items: &[&str]) -> RecycleQueue {
+        RecycleQueue {
+            items: items.iter().map(|i| i.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_ready_recycler_takes_front_item() {
+        let (new_recycler, new_queue, item) =
+            plan_cycle(&recycler(500.0), &queue(&["hull_plate", "drone"]), 1000);
+        assert_eq!(item, Some("hull_plate".to_string()));
+        assert_eq!(new_queue, queue(&["drone"]));
+        assert_eq!(new_recycler.remaining_ms, 3000.0);
+
+        // Mid cycle the queue waits
+        let (new_recycler, new_queue, item) = plan_cycle(&new_recycler, &new_queue, 1000);
+        assert_eq!(item, None);
+        assert_eq!(new_queue, queue(&["drone"]));
+        assert_eq!(new_recycler.remaining_ms, 2000.0);
+    }
+
+    #[test]
+    fn test_empty_queue_idles() {
+        let (new_recycler, new_queue, item) = plan_cycle(&recycler(500.0), &queue(&[]), 1000);
+        assert_eq!(item, None);
+        assert_eq!(new_queue, queue(&[]));
+        assert_eq!(new_recycler.remaining_ms, 0.0);
+
+        // An idle recycler stays ready and unchanged
+        let (idle, _, _) = plan_cycle(&new_recycler, &new_queue, 1000);
+        assert_eq!(idle, new_recycler);
+    }
+
+    #[test]
+    fn test_item_breaks_down_into_each_material() {
+        let properties = ItemProperties {
+            recycle_value: vec![
+                ("tasty".to_string(), 9.0),
+                ("critical".to_string(), 4.0),
+                ("spendy".to_string(), 1.0),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let messages = recycle_messages("the_void", "recycler1", &properties, 0.5);
+
+        // Half of 1 spendy rounds down to nothing
+        assert_eq!(messages.len(), 2);
+        for (subject, _) in &messages {
+            assert_eq!(
+                subject,
+                "call.decs.components.the_void.recycler1.inventory.new"
+            );
+        }
+        assert_eq!(messages[0].1["params"]["name"], "critical");
+        assert_eq!(messages[0].1["params"]["qty"], 2);
+        assert_eq!(messages[0].1["params"]["unit_value"], 100);
+        assert_eq!(messages[1].1["params"]["name"], "tasty");
+        assert_eq!(messages[1].1["params"]["qty"], 4);
+        assert_eq!(messages[1].1["params"]["kind"], "resource");
+    }
+}
@@ -0,0 +1,221 @@
+//! # Supply Chain
+//!
+//! The supply chain system awaits frames for entities that have a `resource_producer` component.
+//! Each frame the producer's `output_buffer` grows by `output_rate_per_ms * elapsed_ms`, up to its
+//! `max_buffer`. Every consumer whose `supply_link` names the producer's entity, and whose
+//! `input_resource` is the producer's `output_resource`, then draws up to
+//! `input_rate_per_ms * elapsed_ms` from the buffer, limited by the room left in its own buffer.
+//! Consumers draw in entity ID order, so when production can't keep up the last consumers starve
+//! first.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let producer: ResourceProducer = match get_component(
+        ctx,
+        &frame.shard,
+        &frame.entity_id,
+        super::RESOURCE_PRODUCER,
+    )? {
+        Some(producer) => producer,
+        None => return Ok(vec![]),
+    };
+    let consumers = get_linked_consumers(ctx, &frame.shard, &frame.entity_id, &producer)?;
+    let (new_producer, supplied) = plan_supply(&producer, &consumers, frame.elapsed_ms);
+
+    for (consumer, resource_consumer) in &supplied {
+        publish_set(
+            ctx,
+            &frame.shard,
+            consumer,
+            super::RESOURCE_CONSUMER,
+            resource_consumer,
+        )?;
+    }
+    if new_producer != producer {
+        publish_set(
+            ctx,
+            &frame.shard,
+            &frame.entity_id,
+            super::RESOURCE_PRODUCER,
+            &new_producer,
+        )?;
+    }
+
+    Ok(vec![])
+}
+
+/// Retrieve the consumers linked to the producer that consume what it produces, sorted by
+/// entity ID
+fn get_linked_consumers(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    producer_entity: &str,
+    producer: &ResourceProducer,
+) -> std::result::Result<Vec<(String, ResourceConsumer)>, Box<dyn std::error::Error>> {
+    let mut linked =
+        ctx.kv()
+            .set_members(&format!("decs:{}:{}:entities", shard, super::SUPPLY_LINK))?;
+    linked.sort();
+
+    let mut consumers = Vec::new();
+    for entity in linked {
+        let link: Option<SupplyLink> = get_component(ctx, shard, &entity, super::SUPPLY_LINK)?;
+        if link.map(|l| l.producer_entity == producer_entity) != Some(true) {
+            continue;
+        }
+        let consumer: Option<ResourceConsumer> =
+            get_component(ctx, shard, &entity, super::RESOURCE_CONSUMER)?;
+        if let Some(consumer) = consumer.filter(|c| c.input_resource == producer.output_resource) {
+            consumers.push((entity, consumer));
+        }
+    }
+    Ok(consumers)
+}
+
+fn get_component<T: serde::de::DeserializeOwned>(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity: &str,
+    component: &str,
+) -> std::result::Result<Option<T>, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity, component
+    ))? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+fn publish_set<T: serde::Serialize>(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity: &str,
+    component: &str,
+    value: &T,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard, entity, component
+        ),
+        None,
+        &serde_json::to_vec(&serde_json::json!({ "params": value }))?,
+    )?;
+    Ok(())
+}
+
+/// Fills the producer's buffer for the elapsed time and then moves what each consumer can take
+/// out of it. Returns the updated producer and the consumers that received anything
+fn plan_supply(
+    producer: &ResourceProducer,
+    consumers: &[(String, ResourceConsumer)],
+    elapsed_ms: u32,
+) -> (ResourceProducer, Vec<(String, ResourceConsumer)>) {
+    let elapsed_ms = f64::from(elapsed_ms);
+    let mut output_buffer = (producer.output_buffer + producer.output_rate_per_ms * elapsed_ms)
+        .min(producer.max_buffer);
+    let mut supplied = Vec::new();
+
+    for (entity, consumer) in consumers {
+        let transfer = (consumer.input_rate_per_ms * elapsed_ms)
+            .min(output_buffer)
+            .min(consumer.max_buffer - consumer.input_buffer);
+        if transfer > 0.0 {
+            output_buffer -= transfer;
+            supplied.push((
+                entity.to_string(),
+                ResourceConsumer {
+                    input_buffer: consumer.input_buffer + transfer,
+                    ..consumer.clone()
+                },
+            ));
+        }
+    }
+
+    (
+        ResourceProducer {
+            output_buffer,
+            ..producer.clone()
+        },
+        supplied,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::plan_supply;
+    use super::ResourceConsumer;
+    use super::ResourceProducer;
+
+    fn producer(output_buffer: f64, max_buffer: f64) -> ResourceProducer {
+        ResourceProducer {
+            output_resource: "ore".to_string(),
+            output_rate_per_ms: 0.01,
+            output_buffer,
+            max_buffer,
+        }
+    }
+
+    fn consumer(input_buffer: f64) -> ResourceConsumer {
+        ResourceConsumer {
+            input_resource: "ore".to_string(),
+            input_rate_per_ms: 0.005,
+            input_buffer,
+            max_buffer: 100.0,
+        }
+    }
+
+    #[test]
+    fn test_buffer_fills_at_output_rate() {
+        let (new_producer, supplied) = plan_supply(&producer(20.0, 100.0), &[], 1000);
+        assert_eq!(new_producer.output_buffer, 30.0);
+        assert!(supplied.is_empty());
+
+        // Linked consumers draw from what was produced
+        let consumers = vec![("factory1".to_string(), consumer(0.0))];
+        let (new_producer, supplied) = plan_supply(&producer(20.0, 100.0), &consumers, 1000);
+        assert_eq!(new_producer.output_buffer, 25.0);
+        assert_eq!(supplied, vec![("factory1".to_string(), consumer(5.0))]);
+    }
+
+    #[test]
+    fn test_buffers_never_overflow() {
+        // Production stops at the producer's max buffer
+        let (new_producer, _) = plan_supply(&producer(95.0, 100.0), &[], 1000);
+        assert_eq!(new_producer.output_buffer, 100.0);
+        let (new_producer, _) = plan_supply(&new_producer, &[], 1000);
+        assert_eq!(new_producer.output_buffer, 100.0);
+
+        // A consumer only takes what fits in its own buffer, a full one takes nothing
+        let consumers = vec![
+            ("factory1".to_string(), consumer(98.0)),
+            ("factory2".to_string(), consumer(100.0)),
+        ];
+        let (new_producer, supplied) = plan_supply(&producer(50.0, 100.0), &consumers, 1000);
+        assert_eq!(new_producer.output_buffer, 58.0);
+        assert_eq!(supplied, vec![("factory1".to_string(), consumer(100.0))]);
+    }
+
+    #[test]
+    fn test_consumers_starve_when_production_falls_short() {
+        let mut idle = producer(2.0, 100.0);
+        idle.output_rate_per_ms = 0.0;
+        let consumers = vec![
+            ("factory1".to_string(), consumer(0.0)),
+            ("factory2".to_string(), consumer(0.0)),
+        ];
+        let (new_producer, supplied) = plan_supply(&idle, &consumers, 1000);
+        assert_eq!(new_producer.output_buffer, 0.0);
+        assert_eq!(supplied, vec![("factory1".to_string(), consumer(2.0))]);
+
+        // With the buffer empty, nobody gets anything
+        let (new_producer, supplied) = plan_supply(&new_producer, &consumers, 1000);
+        assert_eq!(new_producer.output_buffer, 0.0);
+        assert!(supplied.is_empty());
+    }
+}
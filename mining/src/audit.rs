@@ -0,0 +1,241 @@
+//! # Extraction Audit Log
+//!
+//! Every resource delivered to a miner's inventory is recorded on its shard's audit log, the KV
+//! list `decs:mining:log:{shard}`, as a compact record of who mined what, from where and when.
+//! Records are appended oldest first and the oldest are dropped once the log grows past the
+//! shard's maximum length, a plain number under `decs:{shard}:mining:log_max_len` (1000 records by
+//! default). A call on `call.decs.{shard}.mining.log` replies with the most recent records, newest
+//! first, a page at a time: `limit` records after skipping the `offset` most recent ones.
+use crate::capabilities::*;
+use guest::prelude::*;
+use serde_json::Value;
+
+const DEFAULT_LOG_MAX_LEN: usize = 1000;
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 200;
+
+/// One extraction, as it is stored on the audit log
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct ExtractionRecord {
+    pub miner: String,     // Entity ID of the miner
+    pub asteroid: String,  // Entity ID of the asteroid mined
+    pub resource: String,  // Stack type of the resource
+    pub qty: u32,          // Quantity delivered to the inventory
+    pub timestamp_ms: u64, // Game time the deposit was taken
+}
+
+/// Appends the record to the shard's audit log, trimming the log to its maximum length
+pub(crate) fn log_extraction(
+    ctx: &impl Capabilities,
+    shard: &str,
+    record: &ExtractionRecord,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let key = log_key(shard);
+    let len = ctx.kv().list_add(&key, &serde_json::to_string(record)?)?;
+    let max_len = log_max_len(ctx, shard)?;
+    if len > max_len {
+        for expired in ctx.kv().list_range(&key, 0, (len - max_len) as isize - 1)? {
+            ctx.kv().list_del_item(&key, &expired)?;
+        }
+    }
+    Ok(())
+}
+
+/// Receives calls on `call.decs.{shard}.mining.log` and replies with a page of the shard's most
+/// recent extraction records
+pub(crate) fn handle_log_query(
+    ctx: &impl Capabilities,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let shard = match subject.as_slice() {
+        ["call", "decs", shard, "mining", "log"] => *shard,
+        _ => return Err(format!("Unexpected mining log subject: {}", msg.subject).into()),
+    };
+    let params: Value = serde_json::from_slice::<Value>(&msg.body)
+        .map(|body| body["params"].clone())
+        .unwrap_or(Value::Null);
+    let limit = params["limit"]
+        .as_u64()
+        .map_or(DEFAULT_PAGE_SIZE, |l| l as usize)
+        .min(MAX_PAGE_SIZE);
+    let offset = params["offset"].as_u64().unwrap_or(0) as usize;
+
+    let records = query_log(ctx, shard, limit, offset)?;
+    if !msg.reply_to.is_empty() {
+        ctx.msg().publish(
+            &msg.reply_to,
+            None,
+            &serde_json::to_vec(&json!({ "result": records }))?,
+        )?;
+    }
+
+    Ok(vec![])
+}
+
+/// The shard's records, newest first, skipping the `offset` most recent
+fn query_log(
+    ctx: &impl Capabilities,
+    shard: &str,
+    limit: usize,
+    offset: usize,
+) -> std::result::Result<Vec<ExtractionRecord>, Box<dyn std::error::Error>> {
+    if limit == 0 {
+        return Ok(vec![]);
+    }
+    // The newest record is last on the list, so the page is counted back from its end
+    let stop = -(offset as isize) - 1;
+    let start = stop - limit as isize + 1;
+    let mut records = ctx
+        .kv()
+        .list_range(&log_key(shard), start, stop)?
+        .iter()
+        .map(|s| serde_json::from_str(s))
+        .collect::<std::result::Result<Vec<ExtractionRecord>, _>>()?;
+    records.reverse();
+    Ok(records)
+}
+
+fn log_max_len(ctx: &impl Capabilities, shard: &str) -> Result<usize> {
+    let configured = ctx
+        .kv()
+        .get(&format!("decs:{}:mining:log_max_len", shard))?
+        .and_then(|s| s.parse::<usize>().ok());
+    Ok(configured.unwrap_or(DEFAULT_LOG_MAX_LEN))
+}
+
+fn log_key(shard: &str) -> String {
+    format!("decs:mining:log:{}", shard)
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle_log_query;
+    use super::log_extraction;
+    use super::ExtractionRecord;
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const LOG: &str = "decs:mining:log:the_void";
+
+    fn record(timestamp_ms: u64) -> ExtractionRecord {
+        ExtractionRecord {
+            miner: "player1".to_string(),
+            asteroid: "asteroid1".to_string(),
+            resource: "tasty".to_string(),
+            qty: 3,
+            timestamp_ms,
+        }
+    }
+
+    fn query(ctx: &MockCapabilitiesContext, params: serde_json::Value) -> Vec<u64> {
+        handle_log_query(
+            ctx,
+            BrokerMessage {
+                subject: "call.decs.the_void.mining.log".to_string(),
+                reply_to: "_INBOX.log1".to_string(),
+                body: serde_json::to_vec(&json!({ "params": params })).unwrap(),
+            },
+        )
+        .unwrap();
+        let reply = ctx.published.borrow_mut().pop().unwrap();
+        assert_eq!(reply.0, "_INBOX.log1");
+        let reply: serde_json::Value = serde_json::from_slice(&reply.1).unwrap();
+        reply["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["timestamp_ms"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_completed_extraction_is_logged() {
+        let ctx = MockCapabilitiesContext::new()
+            .with_extractor(
+                "decs.components.the_void.player1.extractors.1",
+                json!({
+                    "target": "decs.components.the_void.asteroid1.mining_resource",
+                    "remaining_ms": 500.0,
+                    "total_ms": 1000.0
+                }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_resource",
+                json!({ "stack_type": "tasty", "qty": 3 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_lock",
+                json!({
+                    "extractor": "decs.components.the_void.player1.extractors.1",
+                    "owner": "player1"
+                }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:transponder",
+                json!({ "object_type": "asteroid", "display_name": "Rock", "color": "gray" }),
+            );
+        handle_frame(
+            &ctx,
+            BrokerMessage {
+                subject: "decs.frames.the_void.mining".to_string(),
+                body: serde_json::to_vec(&json!({
+                    "seq_no": 12,
+                    "elapsed_ms": 1000,
+                    "shard": "the_void",
+                    "system": "mining",
+                    "entity_id": "player1"
+                }))
+                .unwrap(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let log = ctx.lists.borrow()[LOG].clone();
+        assert_eq!(log.len(), 1);
+        let logged: serde_json::Value = serde_json::from_str(&log[0]).unwrap();
+        assert_eq!(
+            logged,
+            json!({
+                "miner": "player1",
+                "asteroid": "asteroid1",
+                "resource": "tasty",
+                "qty": 3,
+                "timestamp_ms": 12000
+            })
+        );
+    }
+
+    #[test]
+    fn test_log_is_trimmed_to_max_len() {
+        let ctx =
+            MockCapabilitiesContext::new().with_setting("decs:the_void:mining:log_max_len", "3");
+        for timestamp_ms in 1..=5 {
+            log_extraction(&ctx, "the_void", &record(timestamp_ms)).unwrap();
+        }
+
+        let log: Vec<ExtractionRecord> = ctx.lists.borrow()[LOG]
+            .iter()
+            .map(|s| serde_json::from_str(s).unwrap())
+            .collect();
+        assert_eq!(log, vec![record(3), record(4), record(5)]);
+    }
+
+    #[test]
+    fn test_query_pages_back_from_newest() {
+        let ctx = MockCapabilitiesContext::new();
+        for timestamp_ms in 1..=5 {
+            log_extraction(&ctx, "the_void", &record(timestamp_ms)).unwrap();
+        }
+
+        assert_eq!(query(&ctx, json!({ "limit": 2 })), vec![5, 4]);
+        assert_eq!(query(&ctx, json!({ "limit": 2, "offset": 2 })), vec![3, 2]);
+        // The last page is short, and paging past the end finds nothing
+        assert_eq!(query(&ctx, json!({ "limit": 2, "offset": 4 })), vec![1]);
+        assert!(query(&ctx, json!({ "limit": 2, "offset": 6 })).is_empty());
+        // Without params the first page has up to 20 records
+        assert_eq!(query(&ctx, json!(null)), vec![5, 4, 3, 2, 1]);
+    }
+}
@@ -0,0 +1,449 @@
+//! # Auto Mining
+//!
+//! The auto miner system awaits frames for entities with an `auto_miner` component. While the
+//! auto miner is `enabled` and the entity has no extractor, every frame looks for the nearest
+//! asteroid within `max_range` km of the entity that holds an unlocked resource and isn't
+//! depleted. Asteroids at the same distance are chosen by entity ID, so two frames never
+//! disagree. The deposit is locked first and an extractor targeting it is then added to the
+//! entity's `extractors` collection. Since the new extractor's ID is only known once it has been
+//! added, the lock names the collection as its extractor.
+//!
+//! If the lock can't be taken, or the extractor can't be created after it was, the auto miner
+//! backs off and skips its next `BACKOFF_FRAMES` frames. It waits as long after engaging an
+//! asteroid, giving the new extractor time to be stored before it looks again.
+use crate::capabilities::*;
+use crate::duration::extraction_duration_ms;
+use crate::lock::{get_lock, lock_rid};
+use crate::mining::{extractor_params, get_extractor_rids, lock_release_message};
+use crate::retarget::lock_acquire_message;
+use crate::transponder::transponder_depleted;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::units::Millis;
+
+/// The number of frames the auto miner skips after trying to engage an asteroid
+const BACKOFF_FRAMES: u32 = 3;
+
+/// An asteroid the auto miner could engage
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Candidate {
+    pub entity_id: String,
+    pub target: String, // Fully-qualified ID of the deposit to mine
+    pub resource: MiningResource,
+    pub position: Position,
+    pub locked: bool,
+    pub depleted: bool,
+}
+
+pub(crate) fn handle_frame(ctx: &impl Capabilities, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+    let (shard, entity_id) = (frame.shard.as_str(), frame.entity_id.as_str());
+
+    let auto_miner: AutoMiner = match get_component(ctx, shard, entity_id, super::AUTO_MINER)? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => return Ok(vec![]),
+    };
+    if !auto_miner.enabled || backing_off(ctx, shard, entity_id)? {
+        return Ok(vec![]);
+    }
+    if !get_extractor_rids(ctx, shard, entity_id)?.is_empty() {
+        return Ok(vec![]);
+    }
+    let miner: Position = match get_component(ctx, shard, entity_id, super::POSITION)? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => return Ok(vec![]),
+    };
+
+    let candidates = get_candidates(ctx, shard, entity_id)?;
+    if let Some(asteroid) = choose_asteroid(&miner, &candidates, auto_miner.max_range) {
+        engage(ctx, shard, entity_id, asteroid)?;
+        back_off(ctx, shard, entity_id)?;
+    }
+
+    Ok(vec![])
+}
+
+/// Locks the asteroid's deposit and adds an extractor targeting it to the entity's `extractors`
+/// collection. The lock is released again if the extractor can't be created
+fn engage(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    asteroid: &Candidate,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let extractors = format!(
+        "decs.components.{}.{}.{}",
+        shard,
+        entity_id,
+        super::EXTRACTORS
+    );
+    let lock_rid = lock_rid(shard, &asteroid.entity_id, &asteroid.target);
+    let (subject, payload) = lock_acquire_message(&lock_rid, entity_id, &extractors);
+    if let Err(e) = ctx
+        .msg()
+        .publish(&subject, None, &serde_json::to_vec(&payload)?)
+    {
+        ctx.log(&format!(
+            "Auto miner of entity {} couldn't lock {}: {}",
+            entity_id, asteroid.target, e
+        ));
+        return Ok(());
+    }
+
+    let duration_ms = extraction_duration_ms(&asteroid.resource);
+    let extractor = MiningExtractor {
+        target: asteroid.target.to_string(),
+        remaining_ms: Millis(duration_ms),
+        total_ms: duration_ms,
+        ..MiningExtractor::default()
+    };
+    let created = ctx.msg().publish(
+        &format!("call.{}.new", extractors),
+        None,
+        &serde_json::to_vec(&json!({ "params": extractor_params(&extractor)? }))?,
+    );
+    match created {
+        Ok(_) => ctx.log(&format!(
+            "Auto miner of entity {} engaged {}",
+            entity_id, asteroid.target
+        )),
+        Err(e) => {
+            ctx.log(&format!(
+                "Auto miner of entity {} couldn't create an extractor: {}",
+                entity_id, e
+            ));
+            let (subject, payload) = lock_release_message(&lock_rid);
+            ctx.msg()
+                .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Retrieve every asteroid on the shard holding a resource, either in a `mining_resource`
+/// component or as a deposit of a `mining_resources` collection
+pub(crate) fn get_candidates(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<Vec<Candidate>, Box<dyn std::error::Error>> {
+    let mut asteroids = ctx.kv().set_members(&format!(
+        "decs:{}:{}:entities",
+        shard,
+        super::MINING_RESOURCE
+    ))?;
+    asteroids.extend(ctx.kv().set_members(&format!(
+        "decs:{}:{}:entities",
+        shard,
+        super::MINING_RESOURCES
+    ))?);
+    asteroids.sort();
+    asteroids.dedup();
+
+    let mut candidates = Vec::new();
+    for asteroid in asteroids.into_iter().filter(|a| a != entity_id) {
+        let target = match deposit_rid(ctx, shard, &asteroid)? {
+            Some(target) => target,
+            None => continue,
+        };
+        let resource: MiningResource = match ctx.kv().get(&target.replace('.', ":"))? {
+            Some(s) => serde_json::from_str(&s)?,
+            None => continue,
+        };
+        let position: Position = match get_component(ctx, shard, &asteroid, super::POSITION)? {
+            Some(s) => serde_json::from_str(&s)?,
+            None => continue,
+        };
+        candidates.push(Candidate {
+            locked: get_lock(ctx, &lock_rid(shard, &asteroid, &target))?.is_some(),
+            depleted: transponder_depleted(ctx, shard, &asteroid)?,
+            entity_id: asteroid,
+            target,
+            resource,
+            position,
+        });
+    }
+    Ok(candidates)
+}
+
+/// The fully-qualified ID of the asteroid's deposit to mine next, the first one nobody holds the
+/// lock on if there is one
+fn deposit_rid(
+    ctx: &impl Capabilities,
+    shard: &str,
+    asteroid: &str,
+) -> std::result::Result<Option<String>, Box<dyn std::error::Error>> {
+    if get_component(ctx, shard, asteroid, super::MINING_RESOURCE)?.is_some() {
+        return Ok(Some(format!(
+            "decs.components.{}.{}.{}",
+            shard,
+            asteroid,
+            super::MINING_RESOURCE
+        )));
+    }
+    let collection = format!(
+        "decs:components:{}:{}:{}",
+        shard,
+        asteroid,
+        super::MINING_RESOURCES
+    );
+    let deposits = ctx.kv().list_range(&collection, 0, -1)?;
+    for deposit in &deposits {
+        if get_lock(ctx, &lock_rid(shard, asteroid, deposit))?.is_none() {
+            return Ok(Some(deposit.to_string()));
+        }
+    }
+    Ok(deposits.into_iter().next())
+}
+
+/// The nearest asteroid within range that is neither locked nor depleted. Asteroids at the same
+/// distance are ordered by entity ID
+pub(crate) fn choose_asteroid<'a>(
+    miner: &Position,
+    candidates: &'a [Candidate],
+    max_range: f64,
+) -> Option<&'a Candidate> {
+    candidates
+        .iter()
+        .filter(|c| !c.locked && !c.depleted)
+        .map(|c| (miner.distance_to_3d(&c.position), c))
+        .filter(|(distance, _)| *distance <= max_range)
+        .min_by(|(a, ca), (b, cb)| a.total_cmp(b).then_with(|| ca.entity_id.cmp(&cb.entity_id)))
+        .map(|(_, c)| c)
+}
+
+/// Whether the auto miner is skipping this frame, counting the frame off if it is
+fn backing_off(ctx: &impl Capabilities, shard: &str, entity_id: &str) -> Result<bool> {
+    let key = backoff_key(shard, entity_id);
+    let remaining = ctx
+        .kv()
+        .get(&key)?
+        .and_then(|s| s.parse::<u32>().ok())
+        .unwrap_or(0);
+    match remaining {
+        0 => Ok(false),
+        1 => ctx.kv().del_key(&key).map(|_| true),
+        n => ctx.kv().set(&key, &(n - 1).to_string(), None).map(|_| true),
+    }
+}
+
+fn back_off(ctx: &impl Capabilities, shard: &str, entity_id: &str) -> Result<()> {
+    ctx.kv().set(
+        &backoff_key(shard, entity_id),
+        &BACKOFF_FRAMES.to_string(),
+        None,
+    )
+}
+
+fn backoff_key(shard: &str, entity_id: &str) -> String {
+    format!("decs:mining:auto_backoff:{}:{}", shard, entity_id)
+}
+
+fn get_component(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> Result<Option<String>> {
+    ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, component
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::choose_asteroid;
+    use super::handle_frame;
+    use super::Candidate;
+    use super::MiningResource;
+    use super::Position;
+    use super::BACKOFF_FRAMES;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const LOCK_SUBJECT: &str = "call.decs.components.the_void.asteroid1.mining_lock.set";
+    const NEW_EXTRACTOR: &str = "call.decs.components.the_void.player1.extractors.new";
+
+    fn candidate(entity_id: &str, x: f64) -> Candidate {
+        Candidate {
+            entity_id: entity_id.to_string(),
+            target: format!("decs.components.the_void.{}.mining_resource", entity_id),
+            resource: MiningResource::default(),
+            position: Position::new(x, 0.0, 0.0),
+            locked: false,
+            depleted: false,
+        }
+    }
+
+    fn chosen(candidates: &[Candidate], max_range: f64) -> Option<&str> {
+        choose_asteroid(&Position::new(0.0, 0.0, 0.0), candidates, max_range)
+            .map(|c| c.entity_id.as_str())
+    }
+
+    /// A miner at the origin with an auto miner reaching 20 km and an asteroid 5 km away
+    fn auto_mining() -> MockCapabilitiesContext {
+        MockCapabilitiesContext::new()
+            .with_value(
+                "decs:components:the_void:player1:auto_miner",
+                json!({ "enabled": true, "max_range": 20.0 }),
+            )
+            .with_value(
+                "decs:components:the_void:player1:position",
+                json!({ "x": 0.0, "y": 0.0, "z": 0.0 }),
+            )
+            .with_member("decs:the_void:mining_resource:entities", "asteroid1")
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_resource",
+                json!({ "stack_type": "tasty", "qty": 4 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:position",
+                json!({ "x": 5.0, "y": 0.0, "z": 0.0 }),
+            )
+    }
+
+    fn frame() -> BrokerMessage {
+        BrokerMessage {
+            subject: "decs.frames.the_void.auto_miner".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 3,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "auto_miner",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_nearest_asteroid_is_chosen() {
+        let candidates = vec![
+            candidate("asteroid3", 9.0),
+            candidate("asteroid1", -4.0),
+            candidate("asteroid2", 6.0),
+        ];
+        assert_eq!(chosen(&candidates, 20.0), Some("asteroid1"));
+
+        // Asteroids at the same distance are ordered by ID, whatever order they are found in
+        let candidates = vec![candidate("asteroid2", 4.0), candidate("asteroid1", -4.0)];
+        assert_eq!(chosen(&candidates, 20.0), Some("asteroid1"));
+    }
+
+    #[test]
+    fn test_locked_and_depleted_asteroids_are_skipped() {
+        let mut locked = candidate("asteroid1", 1.0);
+        locked.locked = true;
+        let mut depleted = candidate("asteroid2", 2.0);
+        depleted.depleted = true;
+        let candidates = vec![locked, depleted, candidate("asteroid3", 3.0)];
+        assert_eq!(chosen(&candidates, 20.0), Some("asteroid3"));
+    }
+
+    #[test]
+    fn test_no_candidates_in_range() {
+        assert_eq!(chosen(&[], 20.0), None);
+        assert_eq!(chosen(&[candidate("asteroid1", 25.0)], 20.0), None);
+
+        // Nothing is published when there is nothing to mine
+        let ctx = auto_mining().with_value(
+            "decs:components:the_void:asteroid1:position",
+            json!({ "x": 50.0, "y": 0.0, "z": 0.0 }),
+        );
+        handle_frame(&ctx, frame()).unwrap();
+        assert!(ctx.subjects().is_empty());
+    }
+
+    #[test]
+    fn test_frame_locks_then_creates_extractor() {
+        let ctx = auto_mining();
+        handle_frame(&ctx, frame()).unwrap();
+
+        assert_eq!(ctx.subjects(), vec![LOCK_SUBJECT, NEW_EXTRACTOR]);
+        let lock = ctx.payload(LOCK_SUBJECT).unwrap();
+        assert_eq!(lock["params"]["owner"], "player1");
+        let extractor = ctx.payload(NEW_EXTRACTOR).unwrap();
+        assert_eq!(
+            extractor["params"]["target"],
+            "decs.components.the_void.asteroid1.mining_resource"
+        );
+        assert_eq!(extractor["params"]["total_ms"], 4000.0);
+
+        // An entity that is already extracting is left alone
+        let ctx = auto_mining().with_extractor(
+            "decs.components.the_void.player1.extractors.1",
+            json!({ "target": "decs.components.the_void.asteroid9.mining_resource" }),
+        );
+        handle_frame(&ctx, frame()).unwrap();
+        assert!(ctx.subjects().is_empty());
+    }
+
+    #[test]
+    fn test_failed_lock_backs_off() {
+        let ctx = auto_mining().with_failing_subject(LOCK_SUBJECT);
+        handle_frame(&ctx, frame()).unwrap();
+        assert!(ctx.subjects().is_empty());
+
+        // The next frames are skipped, even though the lock could be taken now
+        ctx.failing.borrow_mut().remove(LOCK_SUBJECT);
+        for _ in 0..BACKOFF_FRAMES {
+            handle_frame(&ctx, frame()).unwrap();
+            assert!(ctx.subjects().is_empty());
+        }
+        handle_frame(&ctx, frame()).unwrap();
+        assert_eq!(ctx.subjects(), vec![LOCK_SUBJECT, NEW_EXTRACTOR]);
+    }
+
+    #[test]
+    fn test_failed_extractor_releases_lock() {
+        let ctx = auto_mining().with_failing_subject(NEW_EXTRACTOR);
+        handle_frame(&ctx, frame()).unwrap();
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                LOCK_SUBJECT,
+                "call.decs.components.the_void.asteroid1.mining_lock.delete"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_free_deposit_of_a_shared_asteroid_is_engaged() {
+        let deposits = ["deposit1", "deposit2"].map(|deposit| {
+            format!(
+                "decs.components.the_void.asteroid2.mining_resources.{}",
+                deposit
+            )
+        });
+        let ctx = auto_mining()
+            .with_member("decs:the_void:mining_resources:entities", "asteroid2")
+            .with_value(
+                "decs:components:the_void:asteroid2:position",
+                json!({ "x": 2.0, "y": 0.0, "z": 0.0 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid2:mining_locks:deposit1",
+                json!({ "extractor": "decs.components.the_void.player2.extractors.1", "owner": "player2" }),
+            );
+        for deposit in &deposits {
+            ctx.values.borrow_mut().insert(
+                deposit.replace('.', ":"),
+                json!({ "stack_type": "tasty", "qty": 4 }).to_string(),
+            );
+        }
+        ctx.lists.borrow_mut().insert(
+            "decs:components:the_void:asteroid2:mining_resources".to_string(),
+            deposits.to_vec(),
+        );
+        handle_frame(&ctx, frame()).unwrap();
+
+        // Another entity mining the first deposit leaves the second one to this miner
+        let lock_subject = "call.decs.components.the_void.asteroid2.mining_locks.deposit2.set";
+        assert_eq!(ctx.subjects(), vec![lock_subject, NEW_EXTRACTOR]);
+        let extractor = ctx.payload(NEW_EXTRACTOR).unwrap();
+        assert_eq!(extractor["params"]["target"], deposits[1].as_str());
+    }
+}
@@ -0,0 +1,190 @@
+//! # Auto Targeting
+//!
+//! A client that doesn't know the rid of the resource it wants to mine, e.g. one mining from the
+//! radar screen, can create an extractor with the target `"auto"`. On the extractor's first frame
+//! the mining system picks the nearest asteroid within the miner's mining range that holds an
+//! unlocked resource and isn't depleted, the same way the auto miner does. The deposit is locked
+//! for the extractor and the extractor is set with the deposit as its target and the duration
+//! of its resource. The extractor makes no progress on that frame. If nothing is in range, the
+//! extractor is deleted and the failure is published with the reason `no_target`.
+use crate::auto::{choose_asteroid, get_candidates};
+use crate::cache::FrameCache;
+use crate::capabilities::*;
+use crate::duration::extraction_duration_ms;
+use crate::lock::lock_rid;
+use crate::mining::{abandon_extraction, lock_release_message, publish_extractor};
+use crate::range::{get_mining_upgrade, get_position, mining_range};
+use crate::retarget::lock_acquire_message;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::units::Millis;
+
+pub(crate) const AUTO_TARGET: &str = "auto";
+const NO_TARGET: &str = "no_target";
+
+/// Whether the extractor is waiting for the mining system to choose its target
+pub(crate) fn is_auto_target(extractor: &MiningExtractor) -> bool {
+    extractor.target == AUTO_TARGET
+}
+
+/// Points the extractor at the nearest free deposit in range, locking the deposit first, or
+/// abandons it if there is none
+pub(crate) fn resolve_target(
+    ctx: &impl Capabilities,
+    cache: &mut FrameCache,
+    extractor: &MiningExtractor,
+    extractor_rid: &str,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let range = mining_range(ctx, shard)?
+        * get_mining_upgrade(ctx, cache, shard, entity_id)?.range_multiplier;
+    let candidates = get_candidates(ctx, shard, entity_id)?;
+    let asteroid = match get_position(ctx, cache, shard, entity_id)? {
+        Some(miner) => choose_asteroid(&miner, &candidates, range),
+        None => None,
+    };
+    let asteroid = match asteroid {
+        Some(asteroid) => asteroid,
+        None => {
+            ctx.log(&format!(
+                "Abandoning extraction for entity {}: nothing to mine in range",
+                entity_id
+            ));
+            abandon_extraction(ctx, extractor, extractor_rid, shard, entity_id, NO_TARGET)?;
+            return Ok(());
+        }
+    };
+
+    let lock_rid = lock_rid(shard, &asteroid.entity_id, &asteroid.target);
+    let (subject, payload) = lock_acquire_message(&lock_rid, entity_id, extractor_rid);
+    ctx.msg()
+        .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+    let duration_ms = extraction_duration_ms(&asteroid.resource);
+    let resolved = MiningExtractor {
+        target: asteroid.target.to_string(),
+        remaining_ms: Millis(duration_ms),
+        total_ms: duration_ms,
+        ..extractor.clone()
+    };
+    if let Err(e) = publish_extractor(ctx, &resolved, extractor_rid) {
+        let (subject, payload) = lock_release_message(&lock_rid);
+        ctx.msg()
+            .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+        return Err(e);
+    }
+    ctx.log(&format!(
+        "Extractor {} of entity {} is now targeting {}",
+        extractor_rid, entity_id, resolved.target
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const EXTRACTOR_RID: &str = "decs.components.the_void.player1.extractors.1";
+    const EXTRACTOR_SET: &str = "call.decs.components.the_void.player1.extractors.1.set";
+    const LOCK_SUBJECT: &str = "call.decs.components.the_void.asteroid2.mining_lock.set";
+
+    /// A miner at the origin with an auto targeting extractor, an asteroid 4 km away, a closer
+    /// one that is locked and one out of range
+    fn context() -> MockCapabilitiesContext {
+        let mut ctx = MockCapabilitiesContext::new()
+            .with_extractor(
+                EXTRACTOR_RID,
+                json!({ "target": "auto", "remaining_ms": 0.0, "total_ms": 0.0 }),
+            )
+            .with_value(
+                "decs:components:the_void:player1:position",
+                json!({ "x": 0.0, "y": 0.0, "z": 0.0 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_lock",
+                json!({ "extractor": "decs.components.the_void.player2.extractors.1", "owner": "player2" }),
+            );
+        for (asteroid, x) in &[("asteroid1", 1.0), ("asteroid2", 4.0), ("asteroid3", 50.0)] {
+            ctx = ctx
+                .with_member("decs:the_void:mining_resource:entities", asteroid)
+                .with_value(
+                    &format!("decs:components:the_void:{}:mining_resource", asteroid),
+                    json!({ "stack_type": "tasty", "qty": 6 }),
+                )
+                .with_value(
+                    &format!("decs:components:the_void:{}:position", asteroid),
+                    json!({ "x": x, "y": 0.0, "z": 0.0 }),
+                );
+        }
+        ctx
+    }
+
+    fn frame() -> BrokerMessage {
+        BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 1,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_nearest_free_asteroid_is_targeted() {
+        let ctx = context();
+        handle_frame(&ctx, frame()).unwrap();
+
+        // The deposit is locked before the extractor is pointed at it
+        assert_eq!(ctx.subjects(), vec![LOCK_SUBJECT, EXTRACTOR_SET]);
+        let lock = ctx.payload(LOCK_SUBJECT).unwrap();
+        assert_eq!(lock["params"]["owner"], "player1");
+        assert_eq!(lock["params"]["extractor"], EXTRACTOR_RID);
+        let extractor = ctx.payload(EXTRACTOR_SET).unwrap();
+        assert_eq!(
+            extractor["params"]["target"],
+            "decs.components.the_void.asteroid2.mining_resource"
+        );
+        assert_eq!(extractor["params"]["remaining_ms"], 6000.0);
+        assert_eq!(extractor["params"]["total_ms"], 6000.0);
+    }
+
+    #[test]
+    fn test_failed_rewrite_releases_lock() {
+        let ctx = context().with_failing_subject(EXTRACTOR_SET);
+        assert!(handle_frame(&ctx, frame()).is_err());
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                LOCK_SUBJECT,
+                "call.decs.components.the_void.asteroid2.mining_lock.delete"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nothing_in_range_deletes_extractor() {
+        let ctx = context().with_setting("decs:the_void:mining:range", "0.5");
+        handle_frame(&ctx, frame()).unwrap();
+
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "event.decs.the_void.player1.mining.failed",
+                "call.decs.components.the_void.player1.extractors.delete",
+                "call.decs.shard.the_void.mining_events.new"
+            ]
+        );
+        let failed = ctx
+            .payload("event.decs.the_void.player1.mining.failed")
+            .unwrap();
+        assert_eq!(failed["target"], "auto");
+        assert_eq!(failed["reason"], "no_target");
+    }
+}
@@ -0,0 +1,134 @@
+//! # Batched Completions
+//!
+//! Completing an extraction takes a burst of publishes: the deposit's deletion, the inventory
+//! add, the extractor's and the lock's deletion and the completion event. With
+//! `decs:{shard}:mining:batch_publishes` set to `true`, they are assembled into one
+//! `BatchRequest` published on `call.decs.batch.{shard}` for a batch-aware component manager to
+//! apply all of them or none. Without it, they are published one by one as before.
+use crate::capabilities::*;
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use trader::batch::BatchRequest;
+
+/// Whether the shard's completions are published as batches
+pub(crate) fn batch_enabled(ctx: &impl Capabilities, shard: &str) -> Result<bool> {
+    Ok(ctx
+        .kv()
+        .get(&format!("decs:{}:mining:batch_publishes", shard))?
+        .is_some_and(|s| s == "true"))
+}
+
+/// Publishes the messages (subject and payload) as a single batch
+pub(crate) fn publish_batch(
+    ctx: &impl Capabilities,
+    shard: &str,
+    messages: Vec<(String, Value)>,
+) -> Result<()> {
+    let batch = BatchRequest::new(shard, messages);
+    ctx.msg()
+        .publish(&batch.subject(), None, &serde_json::to_vec(&batch)?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const BATCH: &str = "call.decs.batch.the_void";
+    const EXTRACTOR_RID: &str = "decs.components.the_void.player1.extractors.1";
+
+    fn context() -> MockCapabilitiesContext {
+        MockCapabilitiesContext::new()
+            .with_setting("decs:the_void:mining:batch_publishes", "true")
+            .with_extractor(
+                EXTRACTOR_RID,
+                json!({
+                    "target": "decs.components.the_void.asteroid1.mining_resource",
+                    "remaining_ms": 100.0,
+                    "total_ms": 7000.0
+                }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_resource",
+                json!({ "stack_type": "tasty", "qty": 7 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_lock",
+                json!({ "extractor": EXTRACTOR_RID, "owner": "player1" }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:transponder",
+                json!({ "object_type": "asteroid", "display_name": "Rock", "color": "gray" }),
+            )
+    }
+
+    fn frame() -> BrokerMessage {
+        BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 12,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_completion_is_published_as_one_batch() {
+        let ctx = context();
+        handle_frame(&ctx, frame()).unwrap();
+
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                BATCH,
+                "call.decs.shard.the_void.mining_events.new",
+                "call.decs.components.the_void.player1.mining_stats.set",
+                "call.decs.components.the_void.asteroid1.transponder.set",
+                "call.decs.components.the_void.asteroid1.asteroid_respawn.set",
+            ]
+        );
+        let batch = ctx.payload(BATCH).unwrap();
+        assert_eq!(batch["shard"], "the_void");
+        let subjects: Vec<&str> = batch["operations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|operation| operation["subject"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            subjects,
+            vec![
+                "call.decs.components.the_void.asteroid1.mining_resource.delete",
+                "call.decs.components.the_void.player1.inventory.new",
+                "call.decs.components.the_void.player1.extractors.delete",
+                "call.decs.components.the_void.asteroid1.mining_lock.delete",
+                "event.decs.the_void.player1.mining.completed",
+            ]
+        );
+        assert_eq!(batch["operations"][1]["payload"]["params"]["qty"], 7);
+        assert!(!ctx
+            .values
+            .borrow()
+            .keys()
+            .any(|key| key.starts_with("decs:mining:pending")));
+    }
+
+    #[test]
+    fn test_failed_batch_changes_nothing() {
+        let ctx = context().with_failing_subject(BATCH);
+        assert!(handle_frame(&ctx, frame()).is_err());
+        assert!(ctx.subjects().is_empty());
+
+        // Nothing was claimed, so the next frame tries again
+        ctx.failing.borrow_mut().clear();
+        handle_frame(&ctx, frame()).unwrap();
+        assert_eq!(ctx.subjects()[0], BATCH);
+    }
+}
@@ -0,0 +1,126 @@
+//! # Timed Buffs
+//!
+//! Buffs on `mining_efficiency` speed up (or slow down) the entity's extractions on top of its
+//! `mining_efficiency` component and rig upgrade until they expire. They are applied by an event
+//! on `event.decs.{shard}.{entity}.buff.applied` carrying the buff, and buffs on other stats are
+//! left to the systems those stats belong to. Each mining frame clears the entity's expired buffs
+//! and publishes `event.decs.system.buff.expired` for each of them.
+use crate::capabilities::*;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::buffs::{self, StatTarget, TimedBuff};
+
+/// Receives buffs on `event.decs.{shard}.{entity}.buff.applied`, keeping those on mining
+/// efficiency
+pub(crate) fn handle_buff_applied(
+    ctx: &impl Capabilities,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let (shard, entity_id) = match subject.as_slice() {
+        ["event", "decs", shard, entity_id, "buff", "applied"] => (*shard, *entity_id),
+        _ => return Err(format!("Unexpected buff subject: {}", msg.subject).into()),
+    };
+    let buff: TimedBuff = serde_json::from_slice(&msg.body)?;
+    if buff.stat == StatTarget::MiningEfficiency {
+        ctx.log(&format!(
+            "Applying buff {} to the mining efficiency of entity {}",
+            buff.id, entity_id
+        ));
+        buffs::apply_buff(shard, entity_id, buff);
+    }
+    Ok(vec![])
+}
+
+/// Clears the entity's expired buffs as of the frame, publishing an event for each of them
+pub(crate) fn handle_buff_expiry_check(
+    ctx: &impl Capabilities,
+    frame: &decs::systemmgr::EntityFrame,
+) -> CallResult {
+    let now_ms = trader::clock::frame_time_ms(frame, super::FRAMERATE);
+    for (subject, payload) in buffs::plan_buff_expiry(&frame.shard, &frame.entity_id, now_ms) {
+        ctx.msg()
+            .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+    }
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle_buff_applied;
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    // The buffs outlive the tests, so this entity is used by this test alone
+    const EXTRACTOR_RID: &str = "decs.components.the_void.buffed_miner.extractors.1";
+    const EXTRACTOR_SET: &str = "call.decs.components.the_void.buffed_miner.extractors.1.set";
+    const BUFF_EXPIRED: &str = "event.decs.system.buff.expired";
+
+    fn frame(seq_no: u64) -> BrokerMessage {
+        BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": seq_no,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "buffed_miner"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn apply(ctx: &MockCapabilitiesContext, buff: serde_json::Value) {
+        handle_buff_applied(
+            ctx,
+            BrokerMessage {
+                subject: "event.decs.the_void.buffed_miner.buff.applied".to_string(),
+                body: serde_json::to_vec(&buff).unwrap(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_buff_speeds_up_mining_until_it_expires() {
+        let ctx = MockCapabilitiesContext::new().with_extractor(
+            EXTRACTOR_RID,
+            json!({
+                "target": "decs.components.the_void.asteroid1.mining_resource",
+                "remaining_ms": 9000.0,
+                "total_ms": 10000.0
+            }),
+        );
+        apply(
+            &ctx,
+            json!({ "id": "overdrive", "stat": "mining_efficiency", "multiplier": 2.0, "expires_at_ms": 3000 }),
+        );
+        // Speed buffs belong to physics, mining ignores them
+        apply(
+            &ctx,
+            json!({ "id": "haste", "stat": "speed", "multiplier": 3.0, "expires_at_ms": 3000 }),
+        );
+
+        handle_frame(&ctx, frame(2)).unwrap();
+        assert_eq!(
+            ctx.payload(EXTRACTOR_SET).unwrap()["params"]["remaining_ms"],
+            7000.0
+        );
+        assert!(!ctx.subjects().contains(&BUFF_EXPIRED.to_string()));
+
+        // By the third frame the buff has run out
+        ctx.published.borrow_mut().clear();
+        handle_frame(&ctx, frame(3)).unwrap();
+        assert_eq!(ctx.subjects()[0], BUFF_EXPIRED);
+        let expired = ctx.payload(BUFF_EXPIRED).unwrap();
+        assert_eq!(expired["entity_id"], "buffed_miner");
+        assert_eq!(expired["id"], "overdrive");
+        assert_eq!(
+            ctx.payload(EXTRACTOR_SET).unwrap()["params"]["remaining_ms"],
+            8000.0
+        );
+    }
+}
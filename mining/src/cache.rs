@@ -0,0 +1,132 @@
+//! # Component Cache
+//!
+//! A frame with several extractors reads some of the same components more than once, like the
+//! miner's `mining_efficiency`. Reads made through `fetch_component` during a frame go through a
+//! `ComponentCache`, so each key is only read from the KV store once per frame. The cache lives
+//! only as long as the frame it was created for and holds at most `capacity` entries, evicting
+//! the least recently used one to make room.
+use crate::capabilities::*;
+use guest::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// The number of components a frame's cache holds
+pub(crate) const FRAME_CACHE_CAPACITY: usize = 32;
+
+/// A map of at most `capacity` entries that evicts the least recently used entry when full
+#[derive(Debug)]
+pub(crate) struct ComponentCache<K, V> {
+    capacity: usize,
+    entries: HashMap<K, V>,
+    // Keys from least to most recently used
+    order: VecDeque<K>,
+}
+
+impl<K: Eq + Hash + Clone, V> ComponentCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        ComponentCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Retrieves the entry for the key, marking it as the most recently used
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        if self.entries.contains_key(key) {
+            self.touch(key);
+        }
+        self.entries.get(key)
+    }
+
+    /// Stores the entry, evicting the least recently used one if the cache is full
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.insert(key.clone(), value).is_some() {
+            self.touch(&key);
+            return;
+        }
+        self.order.push_back(key);
+        if self.order.len() > self.capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.entries.remove(&evicted);
+            }
+        }
+    }
+
+    fn touch(&mut self, key: &K) {
+        if let Some(index) = self.order.iter().position(|k| k == key) {
+            if let Some(k) = self.order.remove(index) {
+                self.order.push_back(k);
+            }
+        }
+    }
+}
+
+/// The raw values read from the KV store during a frame, keyed by KV key. Components that
+/// don't exist are cached too, so a missing component isn't looked up again either
+pub(crate) type FrameCache = ComponentCache<String, Option<String>>;
+
+/// Retrieve the raw value stored under the KV key, consulting the frame's cache first
+pub(crate) fn fetch_component(
+    ctx: &impl Capabilities,
+    cache: &mut FrameCache,
+    key: &str,
+) -> Result<Option<String>> {
+    if let Some(value) = cache.get(&key.to_string()) {
+        return Ok(value.clone());
+    }
+    let value = ctx.kv().get(key)?;
+    cache.insert(key.to_string(), value.clone());
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::fetch_component;
+    use super::ComponentCache;
+    use super::FrameCache;
+    use super::FRAME_CACHE_CAPACITY;
+    use crate::test_utils::MockCapabilitiesContext;
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let mut cache = ComponentCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        // Reading "a" makes "b" the least recently used entry
+        assert_eq!(cache.get(&"a"), Some(&1));
+        cache.insert("c", 3);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        assert_eq!(cache.get(&"c"), Some(&3));
+
+        // Replacing an entry doesn't evict anything
+        cache.insert("a", 10);
+        assert_eq!(cache.get(&"a"), Some(&10));
+        assert_eq!(cache.get(&"c"), Some(&3));
+    }
+
+    #[test]
+    fn test_repeated_reads_hit_the_kv_store_once() {
+        let ctx = MockCapabilitiesContext::new().with_value(
+            "decs:components:the_void:player1:mining_efficiency",
+            json!({ "multiplier": 2.0 }),
+        );
+        let mut cache = FrameCache::new(FRAME_CACHE_CAPACITY);
+        let key = "decs:components:the_void:player1:mining_efficiency";
+
+        let first = fetch_component(&ctx, &mut cache, key).unwrap();
+        let second = fetch_component(&ctx, &mut cache, key).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(ctx.reads(key), 1);
+
+        // Missing components are only looked up once as well
+        let missing = "decs:components:the_void:player1:fuel_tank";
+        assert_eq!(fetch_component(&ctx, &mut cache, missing).unwrap(), None);
+        assert_eq!(fetch_component(&ctx, &mut cache, missing).unwrap(), None);
+        assert_eq!(ctx.reads(missing), 1);
+    }
+}
@@ -0,0 +1,117 @@
+//! # Capabilities
+//!
+//! The subset of the waxosuit `CapabilitiesContext` the mining system relies on, as traits, so
+//! the system's handlers can run against `test_utils::MockCapabilitiesContext` in tests. The
+//! methods mirror the ones of the context, so handlers read the same either way.
+use crate::event_log;
+use guest::prelude::*;
+
+pub(crate) trait Capabilities {
+    fn kv(&self) -> Box<dyn KeyValueStore + '_>;
+    fn msg(&self) -> Box<dyn MessageBroker + '_>;
+    fn extras(&self) -> Box<dyn Extras + '_>;
+    fn log(&self, msg: &str);
+}
+
+pub(crate) trait KeyValueStore {
+    fn get(&self, key: &str) -> Result<Option<String>>;
+    fn set(&self, key: &str, value: &str, expires: Option<u32>) -> Result<()>;
+    fn del_key(&self, key: &str) -> Result<()>;
+    fn atomic_add(&self, key: &str, value: i32) -> Result<i32>;
+    fn list_add(&self, key: &str, item: &str) -> Result<usize>;
+    fn list_del_item(&self, key: &str, item: &str) -> Result<usize>;
+    fn list_range(&self, key: &str, start: isize, stop_inclusive: isize) -> Result<Vec<String>>;
+    fn set_add(&self, key: &str, value: &str) -> Result<usize>;
+    fn set_remove(&self, key: &str, value: &str) -> Result<usize>;
+    fn set_members(&self, key: &str) -> Result<Vec<String>>;
+}
+
+pub(crate) trait MessageBroker {
+    fn publish(&self, subject: &str, reply_to: Option<&str>, payload: &[u8]) -> Result<()>;
+}
+
+pub(crate) trait Extras {
+    fn get_random(&self, min: u32, max: u32) -> Result<u32>;
+}
+
+impl Capabilities for CapabilitiesContext {
+    fn kv(&self) -> Box<dyn KeyValueStore + '_> {
+        Box::new(Host(self))
+    }
+
+    fn msg(&self) -> Box<dyn MessageBroker + '_> {
+        Box::new(Host(self))
+    }
+
+    fn extras(&self) -> Box<dyn Extras + '_> {
+        Box::new(Host(self))
+    }
+
+    fn log(&self, msg: &str) {
+        CapabilitiesContext::log(self, msg)
+    }
+}
+
+/// Forwards each capability to the host through the real context
+struct Host<'a>(&'a CapabilitiesContext);
+
+impl KeyValueStore for Host<'_> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.0.kv().get(key)
+    }
+
+    fn set(&self, key: &str, value: &str, expires: Option<u32>) -> Result<()> {
+        self.0.kv().set(key, value, expires)
+    }
+
+    fn del_key(&self, key: &str) -> Result<()> {
+        self.0.kv().del_key(key)
+    }
+
+    fn atomic_add(&self, key: &str, value: i32) -> Result<i32> {
+        self.0.kv().atomic_add(key, value)
+    }
+
+    fn list_add(&self, key: &str, item: &str) -> Result<usize> {
+        self.0.kv().list_add(key, item)
+    }
+
+    fn list_del_item(&self, key: &str, item: &str) -> Result<usize> {
+        self.0.kv().list_del_item(key, item)
+    }
+
+    fn list_range(&self, key: &str, start: isize, stop_inclusive: isize) -> Result<Vec<String>> {
+        self.0.kv().list_range(key, start, stop_inclusive)
+    }
+
+    fn set_add(&self, key: &str, value: &str) -> Result<usize> {
+        self.0.kv().set_add(key, value)
+    }
+
+    fn set_remove(&self, key: &str, value: &str) -> Result<usize> {
+        self.0.kv().set_remove(key, value)
+    }
+
+    fn set_members(&self, key: &str) -> Result<Vec<String>> {
+        self.0.kv().set_members(key)
+    }
+}
+
+/// Component changes that went out are also appended to the event log. The publish succeeded
+/// regardless of whether that works
+impl MessageBroker for Host<'_> {
+    fn publish(&self, subject: &str, reply_to: Option<&str>, payload: &[u8]) -> Result<()> {
+        self.0.msg().publish(subject, reply_to, payload)?;
+        if let Err(e) = event_log::record(self, subject, payload) {
+            self.0
+                .log(&format!("Failed to log the change on {}: {}", subject, e));
+        }
+        Ok(())
+    }
+}
+
+impl Extras for Host<'_> {
+    fn get_random(&self, min: u32, max: u32) -> Result<u32> {
+        self.0.extras().get_random(min, max)
+    }
+}
@@ -0,0 +1,278 @@
+//! # Checkpoints
+//!
+//! A call on `call.decs.{shard}.checkpoint.save` with a `checkpoint_id` in its params snapshots
+//! the shard's components, copying each `decs:components:{shard}:{entity}:{component}` value to
+//! `decs:checkpoints:{checkpoint_id}:{entity}:{component}`. The KV store can't scan keys, so the
+//! components are found through the shard's `decs:{shard}:{component}:entities` sets, for each of
+//! the components in `CHECKPOINT_COMPONENTS`, and the saved keys are listed in the KV set
+//! `decs:checkpoints:{checkpoint_id}`. Saving under an existing ID replaces that checkpoint.
+//!
+//! A call on `call.decs.{shard}.checkpoint.restore` publishes a `set` for every component of the
+//! checkpoint, putting each back to its saved value. Components created after the checkpoint was
+//! saved are left alone.
+use crate::capabilities::*;
+use guest::prelude::*;
+use serde_json::Value;
+
+const SAVE: &str = "save";
+const RESTORE: &str = "restore";
+
+/// The components a checkpoint holds, those the mining systems keep their state in
+const CHECKPOINT_COMPONENTS: &[&str] = &[
+    super::POSITION,
+    super::MINING_RESOURCE,
+    super::MINING_LOCK,
+    super::MINING_EFFICIENCY,
+    super::MINING_UPGRADE,
+    super::MINING_STATS,
+    super::CARGO_HOLD,
+    super::AUTO_MINER,
+    super::ASTEROID_RESPAWN,
+    super::SCHEDULER,
+    "fuel_tank",
+    "transponder",
+];
+
+/// Receives calls on `call.decs.{shard}.checkpoint.save` and `call.decs.{shard}.checkpoint.restore`
+/// and replies with the number of components saved or restored
+pub(crate) fn handle_call(ctx: &impl Capabilities, msg: messaging::BrokerMessage) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let (shard, action) = match subject.as_slice() {
+        ["call", "decs", shard, "checkpoint", action] if *action == SAVE || *action == RESTORE => {
+            (*shard, *action)
+        }
+        _ => return Err(format!("Unexpected checkpoint call subject: {}", msg.subject).into()),
+    };
+    let checkpoint_id = serde_json::from_slice::<Value>(&msg.body)
+        .ok()
+        .and_then(|body| body["params"]["checkpoint_id"].as_str().map(String::from))
+        .filter(|id| is_valid_id(id));
+
+    let response = match checkpoint_id {
+        Some(checkpoint_id) => {
+            let components = if action == SAVE {
+                save_checkpoint(ctx, shard, &checkpoint_id)?
+            } else {
+                restore_checkpoint(ctx, shard, &checkpoint_id)?
+            };
+            json!({
+                "result": {
+                    "checkpoint_id": checkpoint_id,
+                    "components": components
+                }
+            })
+        }
+        None => json!({
+            "error": {
+                "code": "system.invalidParams",
+                "message": "params must have a checkpoint_id without '.' or ':'"
+            }
+        }),
+    };
+    if !msg.reply_to.is_empty() {
+        ctx.msg()
+            .publish(&msg.reply_to, None, &serde_json::to_vec(&response)?)?;
+    }
+
+    Ok(vec![])
+}
+
+/// Copies every checkpointed component of the shard under the checkpoint, returning how many
+/// were saved
+pub(crate) fn save_checkpoint(
+    ctx: &impl Capabilities,
+    shard: &str,
+    checkpoint_id: &str,
+) -> std::result::Result<usize, Box<dyn std::error::Error>> {
+    let index = index_key(checkpoint_id);
+    for stale in ctx.kv().set_members(&index)? {
+        ctx.kv().del_key(&saved_key(checkpoint_id, &stale))?;
+        ctx.kv().set_remove(&index, &stale)?;
+    }
+    let mut saved = 0;
+    for component in CHECKPOINT_COMPONENTS {
+        let mut entities = ctx
+            .kv()
+            .set_members(&format!("decs:{}:{}:entities", shard, component))?;
+        entities.sort();
+        for entity_id in entities {
+            let relative = format!("{}:{}", entity_id, component);
+            if let Some(value) = ctx
+                .kv()
+                .get(&format!("decs:components:{}:{}", shard, relative))?
+            {
+                ctx.kv()
+                    .set(&saved_key(checkpoint_id, &relative), &value, None)?;
+                ctx.kv().set_add(&index, &relative)?;
+                saved += 1;
+            }
+        }
+    }
+    Ok(saved)
+}
+
+/// Publishes a `set` putting every component of the checkpoint back to its saved value,
+/// returning how many were restored
+pub(crate) fn restore_checkpoint(
+    ctx: &impl Capabilities,
+    shard: &str,
+    checkpoint_id: &str,
+) -> std::result::Result<usize, Box<dyn std::error::Error>> {
+    let mut keys = ctx.kv().set_members(&index_key(checkpoint_id))?;
+    keys.sort();
+    let mut restored = 0;
+    for relative in keys {
+        let value = match ctx.kv().get(&saved_key(checkpoint_id, &relative))? {
+            Some(value) => serde_json::from_str::<Value>(&value)?,
+            None => continue,
+        };
+        ctx.msg().publish(
+            &format!(
+                "call.decs.components.{}.{}.set",
+                shard,
+                relative.replace(':', ".")
+            ),
+            None,
+            &serde_json::to_vec(&json!({ "params": value }))?,
+        )?;
+        restored += 1;
+    }
+    Ok(restored)
+}
+
+/// IDs become part of KV keys, so they can't hold the separators of keys or subjects
+fn is_valid_id(checkpoint_id: &str) -> bool {
+    !checkpoint_id.is_empty() && !checkpoint_id.contains(['.', ':'])
+}
+
+fn index_key(checkpoint_id: &str) -> String {
+    format!("decs:checkpoints:{}", checkpoint_id)
+}
+
+fn saved_key(checkpoint_id: &str, relative: &str) -> String {
+    format!("decs:checkpoints:{}:{}", checkpoint_id, relative)
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle_call;
+    use super::restore_checkpoint;
+    use super::save_checkpoint;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const POSITION_KEY: &str = "decs:components:the_void:player1:position";
+    const RESOURCE_KEY: &str = "decs:components:the_void:asteroid1:mining_resource";
+
+    fn context() -> MockCapabilitiesContext {
+        MockCapabilitiesContext::new()
+            .with_value(POSITION_KEY, json!({ "x": 1.0, "y": 2.0, "z": 3.0 }))
+            .with_member("decs:the_void:position:entities", "player1")
+            .with_value(RESOURCE_KEY, json!({ "stack_type": "tasty", "qty": 7 }))
+            .with_member("decs:the_void:mining_resource:entities", "asteroid1")
+    }
+
+    /// Stores the params of every `set` published so far, the way decs applies them
+    fn apply_sets(ctx: &MockCapabilitiesContext) {
+        let published: Vec<(String, Vec<u8>)> = ctx.published.borrow_mut().drain(..).collect();
+        for (subject, payload) in published {
+            let rid = subject
+                .trim_start_matches("call.")
+                .trim_end_matches(".set")
+                .replace('.', ":");
+            let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+            ctx.values
+                .borrow_mut()
+                .insert(rid, payload["params"].to_string());
+        }
+    }
+
+    fn value(ctx: &MockCapabilitiesContext, key: &str) -> serde_json::Value {
+        serde_json::from_str(&ctx.values.borrow()[key]).unwrap()
+    }
+
+    #[test]
+    fn test_restore_returns_components_to_saved_state() {
+        let ctx = context();
+        assert_eq!(save_checkpoint(&ctx, "the_void", "before_raid").unwrap(), 2);
+        assert_eq!(
+            value(&ctx, "decs:checkpoints:before_raid:player1:position"),
+            json!({ "x": 1.0, "y": 2.0, "z": 3.0 })
+        );
+
+        ctx.values.borrow_mut().insert(
+            POSITION_KEY.to_string(),
+            json!({ "x": 50.0, "y": 0.0, "z": 0.0 }).to_string(),
+        );
+        ctx.values.borrow_mut().insert(
+            RESOURCE_KEY.to_string(),
+            json!({ "stack_type": "tasty", "qty": 1 }).to_string(),
+        );
+
+        assert_eq!(
+            restore_checkpoint(&ctx, "the_void", "before_raid").unwrap(),
+            2
+        );
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "call.decs.components.the_void.asteroid1.mining_resource.set",
+                "call.decs.components.the_void.player1.position.set",
+            ]
+        );
+        apply_sets(&ctx);
+        assert_eq!(
+            value(&ctx, POSITION_KEY),
+            json!({ "x": 1.0, "y": 2.0, "z": 3.0 })
+        );
+        assert_eq!(
+            value(&ctx, RESOURCE_KEY),
+            json!({ "stack_type": "tasty", "qty": 7 })
+        );
+    }
+
+    #[test]
+    fn test_saving_again_replaces_the_checkpoint() {
+        let ctx = context();
+        save_checkpoint(&ctx, "the_void", "before_raid").unwrap();
+        ctx.sets
+            .borrow_mut()
+            .get_mut("decs:the_void:mining_resource:entities")
+            .unwrap()
+            .clear();
+
+        assert_eq!(save_checkpoint(&ctx, "the_void", "before_raid").unwrap(), 1);
+        assert!(!ctx
+            .values
+            .borrow()
+            .contains_key("decs:checkpoints:before_raid:asteroid1:mining_resource"));
+        assert_eq!(
+            restore_checkpoint(&ctx, "the_void", "before_raid").unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_call_replies_with_count() {
+        let ctx = context();
+        let call = |action: &str, checkpoint_id: &str| BrokerMessage {
+            subject: format!("call.decs.the_void.checkpoint.{}", action),
+            reply_to: "reply".to_string(),
+            body: serde_json::to_vec(&json!({ "params": { "checkpoint_id": checkpoint_id } }))
+                .unwrap(),
+        };
+
+        handle_call(&ctx, call("save", "nightly")).unwrap();
+        assert_eq!(
+            ctx.payload("reply").unwrap(),
+            json!({ "result": { "checkpoint_id": "nightly", "components": 2 } })
+        );
+        ctx.published.borrow_mut().clear();
+
+        handle_call(&ctx, call("restore", "bad:id")).unwrap();
+        assert_eq!(
+            ctx.payload("reply").unwrap()["error"]["code"],
+            "system.invalidParams"
+        );
+    }
+}
@@ -0,0 +1,403 @@
+//! # Derelict Cleanup
+//!
+//! An entity with a `survival_timer` is removed once it has been idle for longer than the timer's
+//! `max_idle_ms`. The cleanup system awaits frames for entities with a timer and, once it has
+//! expired, deletes every component of the entity and publishes
+//! `event.decs.system.cleanup.entity_removed`.
+//!
+//! The components of an entity are known from the KV set
+//! `decs:components:{shard}:{entity}:component_registry`, which is kept by the component change
+//! hook. The actor only receives the changes of the components in `TRACKED_CHANGES` and of
+//! extractors, rather than every component event of the game. Each of them adds the component to
+//! its entity's registry and resets the entity's timer to the latest game time. Changes to the same
+//! component of an entity are noted at most once every `NOTE_INTERVAL_MS` of game time, so a
+//! moving ship's position changes don't each cost a round of KV reads and writes. Until the
+//! actor has seen a frame of the shard it has no game time to reset a timer to, and only
+//! registers the component.
+use crate::capabilities::*;
+use crate::mining::member_delete_message;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use trader::components::*;
+use trader::rid::ResourceIdentifier;
+
+const COMPONENT_REGISTRY: &str = "component_registry";
+const ENTITY_REMOVED_EVENT: &str = "event.decs.system.cleanup.entity_removed";
+// Game time during which further changes to a component of an entity are ignored once noted
+const NOTE_INTERVAL_MS: u64 = 10_000;
+
+lazy_static! {
+    // (shard, entity, component) -> game time the component's last change was noted at
+    static ref NOTED: Mutex<HashMap<(String, String, String), u64>> = Mutex::new(HashMap::new());
+}
+
+/// The change events of the components the cleanup system keeps track of, besides extractors
+/// whose changes are already received for their durations. Items of a collection change on
+/// their own subject
+pub(crate) const TRACKED_CHANGES: &[&str] = &[
+    "event.decs.components.*.*.position.change",
+    "event.decs.components.*.*.transponder.change",
+    "event.decs.components.*.*.radar_receiver.change",
+    "event.decs.components.*.*.cargo_hold.change",
+    "event.decs.components.*.*.fuel_tank.change",
+    "event.decs.components.*.*.energy_core.change",
+    "event.decs.components.*.*.hull_integrity.change",
+    "event.decs.components.*.*.wallet.change",
+    "event.decs.components.*.*.inventory.*.change",
+];
+
+/// Receives the change events of `TRACKED_CHANGES`, see `note_component_change`
+pub(crate) fn handle_component_change(
+    ctx: &impl Capabilities,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    note_component_change(ctx, &msg.subject)?;
+    Ok(vec![])
+}
+
+/// Adds the changed component to its entity's registry and resets the entity's survival timer,
+/// unless a change to the component was noted less than `NOTE_INTERVAL_MS` ago. Changes to the
+/// timer itself don't count, or resetting it would reset it again, and neither does anything
+/// before the shard's first frame, when there is no game time to reset it to
+pub(crate) fn note_component_change(
+    ctx: &impl Capabilities,
+    subject: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let subject: Vec<&str> = subject.split('.').collect();
+    let (shard, entity_id, component) = match subject.as_slice() {
+        ["event", "decs", "components", shard, entity_id, component, .., "change"] => {
+            (*shard, *entity_id, *component)
+        }
+        _ => return Ok(()),
+    };
+    let now_ms = trader::clock::latest_time_ms(shard);
+    if !should_note(shard, entity_id, component, now_ms) {
+        return Ok(());
+    }
+    ctx.kv()
+        .set_add(&registry_key(shard, entity_id), component)?;
+    if component == super::SURVIVAL_TIMER || now_ms == 0 {
+        return Ok(());
+    }
+
+    if let Some(timer) = get_timer(ctx, shard, entity_id)? {
+        if timer.idle_since_ms < now_ms {
+            ctx.msg().publish(
+                &format!("call.{}.set", timer_rid(shard, entity_id)?),
+                None,
+                &serde_json::to_vec(&json!({ "params": { "idle_since_ms": now_ms } }))?,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a change to the component is noted at the given game time, remembering it if so. At
+/// game time 0 the shard hasn't had a frame yet, so the change is noted but not remembered
+fn should_note(shard: &str, entity_id: &str, component: &str, now_ms: u64) -> bool {
+    let mut noted = NOTED.lock().unwrap();
+    let key = (
+        shard.to_string(),
+        entity_id.to_string(),
+        component.to_string(),
+    );
+    match noted.get(&key) {
+        Some(noted_ms) if now_ms >= *noted_ms && now_ms - noted_ms < NOTE_INTERVAL_MS => false,
+        _ => {
+            if now_ms > 0 {
+                noted.insert(key, now_ms);
+            }
+            true
+        }
+    }
+}
+
+/// Forgets the noted changes of a removed entity, so one created in its place is registered
+fn forget_noted(shard: &str, entity_id: &str) {
+    NOTED
+        .lock()
+        .unwrap()
+        .retain(|(noted_shard, noted_entity, _), _| {
+            noted_shard != shard || noted_entity != entity_id
+        });
+}
+
+pub(crate) fn handle_frame(ctx: &impl Capabilities, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+    let now_ms = trader::clock::frame_time_ms(&frame, super::FRAMERATE);
+    trader::clock::observe(&frame.shard, now_ms);
+
+    if let Some(timer) = get_timer(ctx, &frame.shard, &frame.entity_id)? {
+        if timer.is_expired(now_ms) {
+            remove_entity(ctx, &frame.shard, &frame.entity_id, &timer, now_ms)?;
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// Deletes every registered component of the entity, the timer last so a frame that fails
+/// half way is retried, then forgets the registry
+fn remove_entity(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    timer: &SurvivalTimer,
+    now_ms: u64,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let registry = registry_key(shard, entity_id);
+    let components = plan_removal(ctx.kv().set_members(&registry)?);
+    for component in &components {
+        let rid = ResourceIdentifier::new(shard, entity_id, component)?.to_rid();
+        let (subject, payload) = member_delete_message(&rid, None);
+        ctx.msg()
+            .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+    }
+    ctx.kv().del_key(&registry)?;
+    forget_noted(shard, entity_id);
+    ctx.msg().publish(
+        ENTITY_REMOVED_EVENT,
+        None,
+        &serde_json::to_vec(&json!({
+            "shard": shard,
+            "entity_id": entity_id,
+            "components": components,
+            "idle_ms": now_ms.saturating_sub(timer.idle_since_ms),
+        }))?,
+    )?;
+    ctx.log(&format!("Removed idle entity {}", entity_id));
+    Ok(())
+}
+
+/// The components to delete, in order, ending with the survival timer whether or not it was
+/// registered
+fn plan_removal(registered: Vec<String>) -> Vec<String> {
+    let mut components: Vec<String> = registered
+        .into_iter()
+        .filter(|component| component != super::SURVIVAL_TIMER)
+        .collect();
+    components.sort();
+    components.push(super::SURVIVAL_TIMER.to_string());
+    components
+}
+
+fn get_timer(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<Option<SurvivalTimer>, Box<dyn std::error::Error>> {
+    match ctx
+        .kv()
+        .get(&ResourceIdentifier::new(shard, entity_id, super::SURVIVAL_TIMER)?.to_key())?
+    {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+fn timer_rid(shard: &str, entity_id: &str) -> std::result::Result<String, String> {
+    Ok(ResourceIdentifier::new(shard, entity_id, super::SURVIVAL_TIMER)?.to_rid())
+}
+
+fn registry_key(shard: &str, entity_id: &str) -> String {
+    format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, COMPONENT_REGISTRY
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle_frame;
+    use super::note_component_change;
+    use super::plan_removal;
+    use super::SurvivalTimer;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+    use std::collections::HashSet;
+
+    const REGISTRY: &str = "decs:components:derelicts:wreck1:component_registry";
+    const TIMER_KEY: &str = "decs:components:derelicts:wreck1:survival_timer";
+    const REMOVED: &str = "event.decs.system.cleanup.entity_removed";
+
+    fn frame(seq_no: u64) -> BrokerMessage {
+        BrokerMessage {
+            subject: "decs.frames.derelicts.cleanup".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": seq_no,
+                "elapsed_ms": 1000,
+                "shard": "derelicts",
+                "system": "cleanup",
+                "entity_id": "wreck1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn registered(ctx: &MockCapabilitiesContext, registry: &str) -> HashSet<String> {
+        ctx.sets.borrow().get(registry).cloned().unwrap_or_default()
+    }
+
+    #[test]
+    fn test_timer_expires_after_max_idle_time() {
+        let timer = SurvivalTimer {
+            idle_since_ms: 10_000,
+            max_idle_ms: 5_000,
+        };
+        assert!(!timer.is_expired(0));
+        assert!(!timer.is_expired(15_000));
+        assert!(timer.is_expired(15_001));
+    }
+
+    #[test]
+    fn test_expired_entity_is_removed() {
+        let ctx = MockCapabilitiesContext::new()
+            .with_value(
+                TIMER_KEY,
+                json!({ "idle_since_ms": 10_000, "max_idle_ms": 5_000 }),
+            )
+            .with_member(REGISTRY, "position")
+            .with_member(REGISTRY, "survival_timer")
+            .with_member(REGISTRY, "cargo_hold");
+
+        // Still within its idle time at 15s
+        handle_frame(&ctx, frame(15)).unwrap();
+        assert!(ctx.subjects().is_empty());
+
+        handle_frame(&ctx, frame(16)).unwrap();
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "call.decs.components.derelicts.wreck1.cargo_hold.delete",
+                "call.decs.components.derelicts.wreck1.position.delete",
+                "call.decs.components.derelicts.wreck1.survival_timer.delete",
+                REMOVED,
+            ]
+        );
+        assert_eq!(
+            ctx.payload("call.decs.components.derelicts.wreck1.position.delete")
+                .unwrap(),
+            json!({ "params": { "rid": "decs.components.derelicts.wreck1.position" } })
+        );
+        assert_eq!(
+            ctx.payload(REMOVED).unwrap(),
+            json!({
+                "shard": "derelicts",
+                "entity_id": "wreck1",
+                "components": ["cargo_hold", "position", "survival_timer"],
+                "idle_ms": 6_000
+            })
+        );
+        assert!(registered(&ctx, REGISTRY).is_empty());
+    }
+
+    #[test]
+    fn test_component_changes_are_registered_and_reset_the_timer() {
+        let ctx = MockCapabilitiesContext::new().with_value(
+            "decs:components:wrecks:wreck1:survival_timer",
+            json!({ "idle_since_ms": 0, "max_idle_ms": 5_000 }),
+        );
+        // A shard of its own, as frames of other tests move the game time of theirs
+        stacktrader_types::clock::observe("wrecks", 12_000);
+
+        note_component_change(&ctx, "event.decs.components.wrecks.wreck1.position.change").unwrap();
+        note_component_change(
+            &ctx,
+            "event.decs.components.wrecks.wreck1.extractors.1.change",
+        )
+        .unwrap();
+        assert_eq!(
+            registered(&ctx, "decs:components:wrecks:wreck1:component_registry"),
+            vec!["position", "extractors"]
+                .into_iter()
+                .map(String::from)
+                .collect()
+        );
+        assert_eq!(
+            ctx.payload("call.decs.components.wrecks.wreck1.survival_timer.set")
+                .unwrap(),
+            json!({ "params": { "idle_since_ms": 12_000 } })
+        );
+
+        // The timer's own changes and other events don't reset it
+        ctx.published.borrow_mut().clear();
+        note_component_change(
+            &ctx,
+            "event.decs.components.wrecks.wreck1.survival_timer.change",
+        )
+        .unwrap();
+        note_component_change(
+            &ctx,
+            "event.decs.components.wrecks.wreck1.thruster.set_throttle",
+        )
+        .unwrap();
+        assert!(ctx.subjects().is_empty());
+        assert_eq!(
+            registered(&ctx, "decs:components:wrecks:wreck1:component_registry").len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_changes_are_noted_once_per_interval() {
+        let ctx = MockCapabilitiesContext::new().with_value(
+            "decs:components:busy:wreck1:survival_timer",
+            json!({ "idle_since_ms": 0, "max_idle_ms": 60_000 }),
+        );
+        let position = "event.decs.components.busy.wreck1.position.change";
+        let fuel = "event.decs.components.busy.wreck1.fuel_tank.change";
+        stacktrader_types::clock::observe("busy", 1_000);
+
+        note_component_change(&ctx, position).unwrap();
+        assert_eq!(ctx.reads("decs:components:busy:wreck1:survival_timer"), 1);
+
+        // Further position changes within the interval are ignored, other components aren't
+        stacktrader_types::clock::observe("busy", 5_000);
+        note_component_change(&ctx, position).unwrap();
+        note_component_change(&ctx, fuel).unwrap();
+        assert_eq!(ctx.reads("decs:components:busy:wreck1:survival_timer"), 2);
+        assert_eq!(
+            registered(&ctx, "decs:components:busy:wreck1:component_registry").len(),
+            2
+        );
+
+        stacktrader_types::clock::observe("busy", 11_000);
+        note_component_change(&ctx, position).unwrap();
+        assert_eq!(ctx.reads("decs:components:busy:wreck1:survival_timer"), 3);
+    }
+
+    #[test]
+    fn test_timer_is_not_reset_before_the_first_frame() {
+        let ctx = MockCapabilitiesContext::new().with_value(
+            "decs:components:unseen:wreck1:survival_timer",
+            json!({ "idle_since_ms": 0, "max_idle_ms": 5_000 }),
+        );
+
+        note_component_change(&ctx, "event.decs.components.unseen.wreck1.position.change").unwrap();
+        assert!(ctx.subjects().is_empty());
+        assert_eq!(
+            registered(&ctx, "decs:components:unseen:wreck1:component_registry").len(),
+            1
+        );
+
+        // Once the shard has a game time, the same component's next change resets the timer
+        stacktrader_types::clock::observe("unseen", 3_000);
+        note_component_change(&ctx, "event.decs.components.unseen.wreck1.position.change").unwrap();
+        assert_eq!(
+            ctx.payload("call.decs.components.unseen.wreck1.survival_timer.set")
+                .unwrap(),
+            json!({ "params": { "idle_since_ms": 3_000 } })
+        );
+    }
+
+    #[test]
+    fn test_timer_is_removed_last_even_when_unregistered() {
+        assert_eq!(
+            plan_removal(vec!["position".to_string()]),
+            vec!["position", "survival_timer"]
+        );
+    }
+}
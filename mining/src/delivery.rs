@@ -0,0 +1,209 @@
+//! # Resource Delivery
+//!
+//! An extractor with `deliver_to` set sends the mined resource to another entity's inventory,
+//! for example from a mining drone to its mothership. The recipient must be a fully-qualified
+//! entity ID on the miner's shard, must have a position and must be within the shard's transfer
+//! range of the miner. Otherwise the resource goes to the miner's own inventory and a warning is
+//! published on `event.decs.{shard}.{entity}.mining.delivery_failed`.
+use crate::capabilities::*;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::rid::parse_component_rid;
+
+const DEFAULT_TRANSFER_RANGE: f64 = 25.0;
+const INVALID_RECIPIENT: &str = "invalid_recipient";
+const RECIPIENT_MISSING: &str = "recipient_missing";
+const OUT_OF_RANGE: &str = "out_of_range";
+
+/// The entity whose inventory receives the extractor's resource
+pub(crate) fn recipient(
+    ctx: &impl Capabilities,
+    extractor: &MiningExtractor,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let deliver_to = match &extractor.deliver_to {
+        Some(deliver_to) => deliver_to,
+        None => return Ok(entity_id.to_string()),
+    };
+    let miner_position = get_position(ctx, shard, entity_id)?;
+    let recipient_position = match parse_component_rid(deliver_to) {
+        Ok(rid) if rid.shard == shard && rid.path.is_empty() => {
+            get_position(ctx, shard, rid.entity)?
+        }
+        _ => None,
+    };
+    let range = transfer_range(ctx, shard)?;
+    match check_delivery(
+        deliver_to,
+        shard,
+        miner_position.as_ref(),
+        recipient_position.as_ref(),
+        range,
+    ) {
+        Ok(recipient) => Ok(recipient.to_string()),
+        Err(reason) => {
+            ctx.log(&format!(
+                "Delivering to entity {} instead of {}: {}",
+                entity_id, deliver_to, reason
+            ));
+            ctx.msg().publish(
+                &format!("event.decs.{}.{}.mining.delivery_failed", shard, entity_id),
+                None,
+                &serde_json::to_vec(&json!({
+                    "deliver_to": deliver_to,
+                    "reason": reason
+                }))?,
+            )?;
+            Ok(entity_id.to_string())
+        }
+    }
+}
+
+/// Checks that the resource can be delivered, returning the recipient entity or the reason
+/// the delivery falls back to the miner
+fn check_delivery<'a>(
+    deliver_to: &'a str,
+    shard: &str,
+    miner_position: Option<&Position>,
+    recipient_position: Option<&Position>,
+    range: f64,
+) -> std::result::Result<&'a str, &'static str> {
+    let rid = parse_component_rid(deliver_to).map_err(|_| INVALID_RECIPIENT)?;
+    if rid.shard != shard || !rid.path.is_empty() {
+        return Err(INVALID_RECIPIENT);
+    }
+    match (miner_position, recipient_position) {
+        (Some(miner), Some(recipient)) if miner.distance_to_3d(recipient) <= range => {
+            Ok(rid.entity)
+        }
+        (Some(_), Some(_)) => Err(OUT_OF_RANGE),
+        _ => Err(RECIPIENT_MISSING),
+    }
+}
+
+/// The transfer range of the shard, stored in the KV store under
+/// `decs:{shard}:mining:transfer_range`
+fn transfer_range(ctx: &impl Capabilities, shard: &str) -> Result<f64> {
+    let configured = ctx
+        .kv()
+        .get(&format!("decs:{}:mining:transfer_range", shard))?
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|range| *range >= 0.0);
+    Ok(configured.unwrap_or(DEFAULT_TRANSFER_RANGE))
+}
+
+fn get_position(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<Option<Position>, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard,
+        entity_id,
+        super::POSITION
+    ))? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::check_delivery;
+    use super::recipient;
+    use super::MiningExtractor;
+    use super::Position;
+    use super::{INVALID_RECIPIENT, OUT_OF_RANGE, RECIPIENT_MISSING};
+    use crate::test_utils::MockCapabilitiesContext;
+
+    const MOTHERSHIP: &str = "decs.components.the_void.mothership";
+
+    fn origin() -> Position {
+        Position::new(0.0, 0.0, 0.0)
+    }
+
+    #[test]
+    fn test_delivery_within_range() {
+        assert_eq!(
+            check_delivery(
+                MOTHERSHIP,
+                "the_void",
+                Some(&origin()),
+                Some(&Position::new(3.0, 4.0, 0.0)),
+                5.0
+            ),
+            Ok("mothership")
+        );
+    }
+
+    #[test]
+    fn test_delivery_range_check() {
+        let check = |x| {
+            check_delivery(
+                MOTHERSHIP,
+                "the_void",
+                Some(&origin()),
+                Some(&Position::new(x, 0.0, 0.0)),
+                25.0,
+            )
+        };
+        assert_eq!(check(25.0), Ok("mothership"));
+        assert_eq!(check(25.1), Err(OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn test_delivery_falls_back_for_bad_recipients() {
+        let far = Some(Position::new(1.0, 0.0, 0.0));
+        let check = |deliver_to, recipient: Option<&Position>| {
+            check_delivery(deliver_to, "the_void", Some(&origin()), recipient, 25.0)
+        };
+        assert_eq!(check(MOTHERSHIP, None), Err(RECIPIENT_MISSING));
+        assert_eq!(check("mothership", far.as_ref()), Err(INVALID_RECIPIENT));
+        assert_eq!(
+            check("decs.components.the_abyss.mothership", far.as_ref()),
+            Err(INVALID_RECIPIENT)
+        );
+        assert_eq!(
+            check(
+                "decs.components.the_void.mothership.inventory",
+                far.as_ref()
+            ),
+            Err(INVALID_RECIPIENT)
+        );
+    }
+
+    #[test]
+    fn test_missing_recipient_falls_back_to_miner_with_warning() {
+        let ctx = MockCapabilitiesContext::new().with_value(
+            "decs:components:the_void:drone1:position",
+            json!({ "x": 0.0, "y": 0.0, "z": 0.0 }),
+        );
+        let extractor = MiningExtractor {
+            deliver_to: Some(MOTHERSHIP.to_string()),
+            ..MiningExtractor::default()
+        };
+        assert_eq!(
+            recipient(&ctx, &extractor, "the_void", "drone1").unwrap(),
+            "drone1"
+        );
+        assert_eq!(
+            ctx.payload("event.decs.the_void.drone1.mining.delivery_failed"),
+            Some(json!({ "deliver_to": MOTHERSHIP, "reason": RECIPIENT_MISSING }))
+        );
+
+        // Once the mothership is in range, the drone delivers to it without a warning
+        let ctx = ctx.with_value(
+            "decs:components:the_void:mothership:position",
+            json!({ "x": 10.0, "y": 0.0, "z": 0.0 }),
+        );
+        ctx.published.borrow_mut().clear();
+        assert_eq!(
+            recipient(&ctx, &extractor, "the_void", "drone1").unwrap(),
+            "mothership"
+        );
+        assert!(ctx.subjects().is_empty());
+    }
+}
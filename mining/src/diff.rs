@@ -0,0 +1,171 @@
+//! # Partial Updates
+//!
+//! Most frames change only a field or two of a component, e.g. an extractor's `remaining_ms`.
+//! A `DiffPublisher` compares the previous and the next value of a component and sets only the
+//! fields that changed, `{ "params": { "remaining_ms": 900.0, "percent_complete": 10.0 } }`,
+//! rather than the whole component. When more than half of the fields changed, the whole
+//! component is set as before. Either way, a field left out of the next value, e.g. an optional
+//! field that was cleared, is deleted with `{ "action": "delete" }` as in the RES protocol.
+use crate::capabilities::*;
+use guest::prelude::*;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Sets the fields of a component that changed between two of its values
+pub(crate) struct DiffPublisher<'a, T> {
+    previous: &'a T,
+    next: &'a T,
+    params: fn(&T) -> Result<Value>,
+}
+
+impl<'a, T: Serialize + PartialEq> DiffPublisher<'a, T> {
+    pub fn new(previous: &'a T, next: &'a T) -> Self {
+        DiffPublisher {
+            previous,
+            next,
+            params: |value| Ok(serde_json::to_value(value)?),
+        }
+    }
+
+    /// Replaces how a value is turned into the params of its `set`, e.g. to validate it or add
+    /// derived fields
+    pub fn with_params(self, params: fn(&T) -> Result<Value>) -> Self {
+        DiffPublisher { params, ..self }
+    }
+
+    /// The payload of the `set`, none if nothing changed
+    pub fn payload(&self) -> Result<Option<Value>> {
+        if self.previous == self.next {
+            return Ok(None);
+        }
+        let next = (self.params)(self.next)?;
+        // A previous value that can't be turned into params is replaced whole
+        let params = match (self.params)(self.previous) {
+            Ok(previous) => plan_diff(&previous, next),
+            Err(_) => next,
+        };
+        Ok(Some(json!({ "params": params })))
+    }
+
+    /// Publishes the `set` on the subject, unless nothing changed
+    pub fn publish(&self, ctx: &impl Capabilities, subject: &str) -> Result<()> {
+        match self.payload()? {
+            Some(payload) => ctx
+                .msg()
+                .publish(subject, None, &serde_json::to_vec(&payload)?),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The fields of the next params that differ from the previous ones, or all of them if more
+/// than half did, along with the deletion of the previous fields missing from the next params
+fn plan_diff(previous: &Value, next: Value) -> Value {
+    let (previous, fields) = match (previous.as_object(), next.as_object()) {
+        (Some(previous), Some(fields)) => (previous, fields),
+        _ => return next,
+    };
+    let changed = fields
+        .iter()
+        .filter(|(key, value)| previous.get(*key) != Some(value))
+        .count();
+    let mut params: Map<String, Value> = if changed * 2 > fields.len() {
+        fields.clone()
+    } else {
+        fields
+            .iter()
+            .filter(|(key, value)| previous.get(*key) != Some(value))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    };
+    for key in previous.keys().filter(|key| !fields.contains_key(*key)) {
+        params.insert(key.clone(), json!({ "action": "delete" }));
+    }
+    Value::Object(params)
+}
+
+#[cfg(test)]
+mod test {
+    use super::plan_diff;
+    use super::DiffPublisher;
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+    use stacktrader_types::components::FuelTank;
+
+    #[test]
+    fn test_single_field_change_is_a_minimal_diff() {
+        let previous = FuelTank {
+            current: 10.0,
+            max: 20.0,
+        };
+        let next = FuelTank {
+            current: 9.5,
+            max: 20.0,
+        };
+        assert_eq!(
+            DiffPublisher::new(&previous, &next).payload().unwrap(),
+            Some(json!({ "params": { "current": 9.5 } }))
+        );
+        assert_eq!(
+            DiffPublisher::new(&previous, &previous).payload().unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_mostly_changed_value_is_set_whole() {
+        let previous = json!({ "a": 1, "b": 2, "c": 3 });
+        assert_eq!(
+            plan_diff(&previous, json!({ "a": 1, "b": 5, "c": 6 })),
+            json!({ "a": 1, "b": 5, "c": 6 })
+        );
+        assert_eq!(
+            plan_diff(&previous, json!({ "a": 1, "b": 2, "c": 6 })),
+            json!({ "c": 6 })
+        );
+        // A field that was left out is deleted
+        assert_eq!(
+            plan_diff(&previous, json!({ "a": 1, "b": 2 })),
+            json!({ "c": { "action": "delete" } })
+        );
+        assert_eq!(
+            plan_diff(&previous, json!({ "a": 5, "b": 6 })),
+            json!({ "a": 5, "b": 6, "c": { "action": "delete" } })
+        );
+        // A field that appears is a change like any other
+        assert_eq!(
+            plan_diff(&previous, json!({ "a": 1, "b": 2, "c": 3, "d": 4 })),
+            json!({ "d": 4 })
+        );
+    }
+
+    #[test]
+    fn test_extraction_progress_sets_only_the_progress() {
+        let ctx = MockCapabilitiesContext::new().with_extractor(
+            "decs.components.the_void.player1.extractors.1",
+            json!({
+                "target": "decs.components.the_void.asteroid1.mining_resource",
+                "remaining_ms": 10000.0,
+                "total_ms": 10000.0
+            }),
+        );
+        let frame = BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 1,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        };
+        handle_frame(&ctx, frame).unwrap();
+        assert_eq!(
+            ctx.payload("call.decs.components.the_void.player1.extractors.1.set"),
+            Some(json!({ "params": { "remaining_ms": 9000.0, "percent_complete": 10.0 } }))
+        );
+    }
+}
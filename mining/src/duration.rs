@@ -0,0 +1,233 @@
+//! # Extraction Duration
+//!
+//! The server, not the client, decides how long an extraction takes. When an extractor is
+//! created the mining actor receives
+//! `event.decs.components.{shard}.{entity}.extractors.{id}.change`, looks up the targeted
+//! resource and computes the extraction's duration from the resource's quantity. If the
+//! extractor's `total_ms` or `remaining_ms` is further than `DURATION_TOLERANCE_MS` from that
+//! duration, the extractor is set again with the authoritative duration. An extractor created
+//! against an asteroid whose transponder is marked depleted is abandoned straight away.
+use crate::capabilities::*;
+use crate::mining::{abandon_extraction, parse_target, TARGET_DEPLETED};
+use crate::transponder::transponder_depleted;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::units::Millis;
+
+/// Time it takes to extract one unit of a resource
+const MS_PER_UNIT: f64 = 1000.0;
+/// Allowance for the time between the client creating the extractor and the check
+const DURATION_TOLERANCE_MS: f64 = 250.0;
+
+/// Receives messages on the subject `event.decs.components.{shard}.{entity}.extractors.{id}.change`
+/// and corrects the duration of newly created extractors
+pub(crate) fn handle_extractor_change(
+    ctx: &impl Capabilities,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    if subject.len() < 8 {
+        return Err(format!("Unexpected extractor change subject: {}", msg.subject).into());
+    }
+    let (shard, entity_id) = (subject[3], subject[4]);
+    let extractor_rid = subject[1..subject.len() - 1].join(".");
+    let change: serde_json::Value = serde_json::from_slice(&msg.body)?;
+    // Only a new extractor (or a new target) names its target, the progress updates
+    // published on every frame only change the remaining time
+    if change["values"]["target"].is_null() {
+        return Ok(vec![]);
+    }
+
+    let extractor_value = ctx.kv().get(&extractor_rid.replace('.', ":"))?;
+    if let Some(extractor_str) = extractor_value {
+        let extractor: MiningExtractor = serde_json::from_str(&extractor_str)?;
+        // An unusable target, like a missing resource, is cleaned up by the extractor's next
+        // frame
+        let target = match parse_target(&extractor.target, shard) {
+            Ok(target) => target,
+            Err(_) => return Ok(vec![]),
+        };
+        if transponder_depleted(ctx, shard, target.entity())? {
+            ctx.log(&format!(
+                "Rejecting extractor of entity {}: {} is depleted",
+                entity_id, extractor.target
+            ));
+            return abandon_extraction(
+                ctx,
+                &extractor,
+                &extractor_rid,
+                shard,
+                entity_id,
+                TARGET_DEPLETED,
+            );
+        }
+        if let Some(resource_str) = ctx.kv().get(&target.to_key())? {
+            let resource: MiningResource = serde_json::from_str(&resource_str)?;
+            if let Some(corrected) = plan_duration_correction(&extractor, &resource) {
+                ctx.log(&format!(
+                    "Correcting extraction duration of entity {} from {}ms to {}ms",
+                    entity_id, extractor.remaining_ms, corrected.remaining_ms
+                ));
+                crate::mining::publish_extractor(ctx, &corrected, &extractor_rid)?;
+            }
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// The duration of extracting the resource, longer the rarer the resource is
+pub(crate) fn extraction_duration_ms(resource: &MiningResource) -> f64 {
+    f64::from(resource.qty) * MS_PER_UNIT * resource.rarity.duration_multiplier()
+}
+
+/// Returns the extractor with the authoritative duration if it deviates from it by more
+/// than the tolerance
+fn plan_duration_correction(
+    extractor: &MiningExtractor,
+    resource: &MiningResource,
+) -> Option<MiningExtractor> {
+    let duration_ms = extraction_duration_ms(resource);
+    let deviates = |ms: f64| ms.is_nan() || (ms - duration_ms).abs() > DURATION_TOLERANCE_MS;
+    if deviates(extractor.total_ms) || deviates(extractor.remaining_ms.0) {
+        Some(MiningExtractor {
+            remaining_ms: Millis(duration_ms),
+            total_ms: duration_ms,
+            ..extractor.clone()
+        })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::extraction_duration_ms;
+    use super::handle_extractor_change;
+    use super::plan_duration_correction;
+    use super::Millis;
+    use super::MiningExtractor;
+    use super::MiningResource;
+    use super::Rarity;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const TARGET: &str = "decs.components.the_void.asteroid1.mining_resource";
+
+    fn resource() -> MiningResource {
+        MiningResource {
+            stack_type: "tasty".to_string(),
+            qty: 11,
+            ..MiningResource::default()
+        }
+    }
+
+    #[test]
+    fn test_cheating_client_is_corrected() {
+        let cheat = MiningExtractor {
+            target: TARGET.to_string(),
+            remaining_ms: Millis(1.0),
+            total_ms: 1.0,
+            paused: false,
+            deliver_to: None,
+            max_mining_speed: None,
+            heat: 0.0,
+            overheated: false,
+            heat_limit: None,
+        };
+        let corrected = plan_duration_correction(&cheat, &resource()).unwrap();
+        assert_eq!(corrected.remaining_ms, Millis(11_000.0));
+        assert_eq!(corrected.total_ms, 11_000.0);
+        assert_eq!(corrected.target, TARGET);
+
+        // Claiming the full duration while leaving almost nothing remaining is no better
+        let sneaky = MiningExtractor {
+            remaining_ms: Millis(1.0),
+            ..corrected
+        };
+        assert!(plan_duration_correction(&sneaky, &resource()).is_some());
+    }
+
+    #[test]
+    fn test_honest_client_is_left_alone() {
+        let honest = MiningExtractor {
+            target: TARGET.to_string(),
+            remaining_ms: Millis(11_000.0),
+            total_ms: 11_000.0,
+            paused: false,
+            deliver_to: None,
+            max_mining_speed: None,
+            heat: 0.0,
+            overheated: false,
+            heat_limit: None,
+        };
+        assert_eq!(plan_duration_correction(&honest, &resource()), None);
+
+        // A frame may already have been applied by the time the check runs
+        let started = MiningExtractor {
+            remaining_ms: Millis(10_900.0),
+            ..honest
+        };
+        assert_eq!(plan_duration_correction(&started, &resource()), None);
+    }
+
+    #[test]
+    fn test_rarer_resources_take_longer() {
+        let epic = MiningResource {
+            rarity: Rarity::Epic,
+            ..resource()
+        };
+        assert_eq!(extraction_duration_ms(&resource()), 11_000.0);
+        assert_eq!(extraction_duration_ms(&epic), 22_000.0);
+    }
+
+    fn created(depleted: bool) -> MockCapabilitiesContext {
+        MockCapabilitiesContext::new()
+            .with_extractor(
+                "decs.components.the_void.player1.extractors.1",
+                json!({ "target": TARGET, "remaining_ms": 11000.0, "total_ms": 11000.0 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_resource",
+                json!({ "stack_type": "tasty", "qty": 11 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:transponder",
+                json!({
+                    "object_type": "asteroid",
+                    "display_name": "Ceres",
+                    "color": "#A9A9A9",
+                    "depleted": depleted
+                }),
+            )
+    }
+
+    fn change() -> BrokerMessage {
+        BrokerMessage {
+            subject: "event.decs.components.the_void.player1.extractors.1.change".to_string(),
+            body: serde_json::to_vec(&json!({ "values": { "target": TARGET } })).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_extractor_on_depleted_target_is_rejected() {
+        let ctx = created(true);
+        handle_extractor_change(&ctx, change()).unwrap();
+        let failed = ctx
+            .payload("event.decs.the_void.player1.mining.failed")
+            .unwrap();
+        assert_eq!(failed["reason"], "target_depleted");
+        assert!(ctx
+            .subjects()
+            .contains(&"call.decs.components.the_void.player1.extractors.delete".to_string()));
+    }
+
+    #[test]
+    fn test_extractor_on_fresh_target_is_accepted() {
+        let ctx = created(false);
+        handle_extractor_change(&ctx, change()).unwrap();
+        assert!(ctx.subjects().is_empty());
+    }
+}
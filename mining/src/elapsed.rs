@@ -0,0 +1,124 @@
+//! # Frame Elapsed Time
+//!
+//! The elapsed time of a mining frame is clamped to at most `max_frame_elapsed_ms`, read from
+//! `decs:{shard}:mining:max_frame_elapsed_ms` and 10 seconds by default, so a glitch in the
+//! system manager sending a huge `elapsed_ms` can't complete every extraction in the shard at
+//! once. Clamping is logged to make the upstream problem visible. A frame in which no time
+//! passed publishes nothing.
+use crate::capabilities::*;
+use guest::prelude::*;
+
+const DEFAULT_MAX_FRAME_ELAPSED_MS: u32 = 10_000;
+
+/// The frame's elapsed time, clamped to the shard's maximum
+pub(crate) fn frame_elapsed_ms(
+    ctx: &impl Capabilities,
+    frame: &decs::systemmgr::EntityFrame,
+) -> Result<u32> {
+    let max_ms = max_frame_elapsed_ms(ctx, &frame.shard)?;
+    let elapsed_ms = clamp_elapsed(frame.elapsed_ms, max_ms);
+    if elapsed_ms < frame.elapsed_ms {
+        ctx.log(&format!(
+            "Clamped elapsed time of frame {} for entity {} from {} ms to {} ms",
+            frame.seq_no, frame.entity_id, frame.elapsed_ms, elapsed_ms
+        ));
+    }
+    Ok(elapsed_ms)
+}
+
+/// The maximum elapsed time of a frame on the shard, stored in the KV store under
+/// `decs:{shard}:mining:max_frame_elapsed_ms`
+fn max_frame_elapsed_ms(ctx: &impl Capabilities, shard: &str) -> Result<u32> {
+    let configured = ctx
+        .kv()
+        .get(&format!("decs:{}:mining:max_frame_elapsed_ms", shard))?
+        .and_then(|s| s.parse::<u32>().ok());
+    Ok(configured.unwrap_or(DEFAULT_MAX_FRAME_ELAPSED_MS))
+}
+
+fn clamp_elapsed(elapsed_ms: u32, max_ms: u32) -> u32 {
+    elapsed_ms.min(max_ms)
+}
+
+#[cfg(test)]
+mod test {
+    use super::clamp_elapsed;
+    use super::DEFAULT_MAX_FRAME_ELAPSED_MS;
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const EXTRACTOR_SET: &str = "call.decs.components.the_void.player1.extractors.1.set";
+
+    fn context() -> MockCapabilitiesContext {
+        MockCapabilitiesContext::new().with_extractor(
+            "decs.components.the_void.player1.extractors.1",
+            json!({
+                "target": "decs.components.the_void.asteroid1.mining_resource",
+                "remaining_ms": 60000.0,
+                "total_ms": 60000.0
+            }),
+        )
+    }
+
+    fn frame(elapsed_ms: u32) -> BrokerMessage {
+        BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 1,
+                "elapsed_ms": elapsed_ms,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_clamp() {
+        assert_eq!(clamp_elapsed(1000, DEFAULT_MAX_FRAME_ELAPSED_MS), 1000);
+        assert_eq!(clamp_elapsed(10_000, DEFAULT_MAX_FRAME_ELAPSED_MS), 10_000);
+        assert_eq!(
+            clamp_elapsed(u32::MAX, DEFAULT_MAX_FRAME_ELAPSED_MS),
+            DEFAULT_MAX_FRAME_ELAPSED_MS
+        );
+    }
+
+    #[test]
+    fn test_glitched_frame_is_clamped() {
+        let ctx = context();
+        handle_frame(&ctx, frame(u32::MAX)).unwrap();
+        assert_eq!(ctx.subjects(), vec![EXTRACTOR_SET]);
+        assert_eq!(
+            ctx.payload(EXTRACTOR_SET).unwrap()["params"]["remaining_ms"],
+            50000.0
+        );
+
+        // The shard can allow longer frames
+        let ctx = context().with_setting("decs:the_void:mining:max_frame_elapsed_ms", "30000");
+        handle_frame(&ctx, frame(u32::MAX)).unwrap();
+        assert_eq!(
+            ctx.payload(EXTRACTOR_SET).unwrap()["params"]["remaining_ms"],
+            30000.0
+        );
+    }
+
+    #[test]
+    fn test_zero_elapsed_frame_publishes_nothing() {
+        let ctx = context();
+        handle_frame(&ctx, frame(0)).unwrap();
+        assert!(ctx.subjects().is_empty());
+    }
+
+    #[test]
+    fn test_normal_frame() {
+        let ctx = context();
+        handle_frame(&ctx, frame(1000)).unwrap();
+        assert_eq!(
+            ctx.payload(EXTRACTOR_SET).unwrap()["params"]["remaining_ms"],
+            59000.0
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! # Energy
+//!
+//! Mining draws on the ship's `energy_core` for every frame its extractors run, at the
+//! `cost_per_ms` of the `mining` entry in the entity's `energy_costs`. Mining comes after the
+//! shields and weapons in the energy priority, so it only draws on what is left once their costs
+//! for the frame are set aside. Without enough energy the whole frame is skipped, extractors
+//! neither progressing nor heating, and `event.decs.system.energy.insufficient` is published.
+//! Entities without an energy core, or without a mining cost, mine for free.
+use crate::capabilities::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::energy::{self, EnergyDraw};
+
+const ENERGY_CORE: &str = "energy_core";
+const ENERGY_COSTS: &str = "energy_costs";
+
+/// Draws the energy for the system's frame, publishing the drained core. Returns whether the
+/// system has the energy to run
+pub(crate) fn draw_frame_energy(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    system: &str,
+    elapsed_ms: u32,
+) -> std::result::Result<bool, Box<dyn std::error::Error>> {
+    let core: Option<EnergyCore> = get_component(ctx, shard, entity_id, ENERGY_CORE)?;
+    let costs: Vec<EnergyCost> =
+        get_component(ctx, shard, entity_id, ENERGY_COSTS)?.unwrap_or_default();
+
+    match energy::draw_energy(core.as_ref(), &costs, system, elapsed_ms) {
+        EnergyDraw::Free => Ok(true),
+        EnergyDraw::Powered(new_core) => {
+            if Some(&new_core) != core.as_ref() {
+                ctx.msg().publish(
+                    &format!(
+                        "call.decs.components.{}.{}.{}.set",
+                        shard, entity_id, ENERGY_CORE
+                    ),
+                    None,
+                    &serde_json::to_vec(&json!({ "params": new_core }))?,
+                )?;
+            }
+            Ok(true)
+        }
+        EnergyDraw::Insufficient {
+            required,
+            available,
+        } => {
+            ctx.msg().publish(
+                energy::INSUFFICIENT_ENERGY_EVENT,
+                None,
+                &serde_json::to_vec(&energy::insufficient_energy_event(
+                    shard, entity_id, system, required, available,
+                ))?,
+            )?;
+            Ok(false)
+        }
+    }
+}
+
+fn get_component<T: serde::de::DeserializeOwned>(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> std::result::Result<Option<T>, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, component
+    ))? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const EXTRACTOR_RID: &str = "decs.components.the_void.player1.extractors.1";
+    const ENERGY_CORE_SET: &str = "call.decs.components.the_void.player1.energy_core.set";
+    const INSUFFICIENT: &str = "event.decs.system.energy.insufficient";
+
+    fn context(current_energy: f64) -> MockCapabilitiesContext {
+        MockCapabilitiesContext::new()
+            .with_extractor(
+                EXTRACTOR_RID,
+                json!({
+                    "target": "decs.components.the_void.asteroid1.mining_resource",
+                    "remaining_ms": 5000.0,
+                    "total_ms": 10000.0
+                }),
+            )
+            .with_value(
+                "decs:components:the_void:player1:energy_core",
+                json!({
+                    "max_energy": 100.0,
+                    "current_energy": current_energy,
+                    "regen_rate_per_ms": 0.01
+                }),
+            )
+            .with_value(
+                "decs:components:the_void:player1:energy_costs",
+                json!([
+                    { "system": "weapons", "cost_per_ms": 0.02 },
+                    { "system": "mining", "cost_per_ms": 0.03 }
+                ]),
+            )
+    }
+
+    fn frame() -> BrokerMessage {
+        BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 1,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_powered_mining_drains_the_core() {
+        let ctx = context(60.0);
+        handle_frame(&ctx, frame()).unwrap();
+
+        assert_eq!(
+            ctx.payload(ENERGY_CORE_SET).unwrap()["params"]["current_energy"],
+            30.0
+        );
+        let extractor = ctx.payload(&format!("call.{}.set", EXTRACTOR_RID)).unwrap();
+        assert_eq!(extractor["params"]["remaining_ms"], 4000.0);
+        assert!(!ctx.subjects().contains(&INSUFFICIENT.to_string()));
+    }
+
+    #[test]
+    fn test_starved_mining_skips_its_frame() {
+        // 40 energy would pay for the 30 mining needs, but 20 is kept for the weapons
+        let ctx = context(40.0);
+        handle_frame(&ctx, frame()).unwrap();
+
+        assert_eq!(ctx.subjects(), vec![INSUFFICIENT.to_string()]);
+        let event = ctx.payload(INSUFFICIENT).unwrap();
+        assert_eq!(event["entity_id"], "player1");
+        assert_eq!(event["system"], "mining");
+        assert_eq!(event["required"], 30.0);
+        assert_eq!(event["available"], 20.0);
+    }
+}
@@ -0,0 +1,468 @@
+//! # Event Log
+//!
+//! Every component change the mining system publishes, a `set`, `new` or `delete` on
+//! `call.decs.components.{shard}.{entity}...` on its own or as an operation of a batch, is
+//! appended to its shard's event log, the KV list `decs:event_log:{shard}`, once the publish
+//! succeeded. Changes published by other actors or the client aren't logged. Neither are the
+//! `set`s of `PROGRESS_COMPONENTS`, which a running extraction writes on every frame. Each
+//! `LogEntry` is stamped with the shard's latest game time, so the log is in time order. The
+//! oldest entries are dropped once the log grows past the shard's maximum length, a plain number
+//! under `decs:{shard}:event_log:max_len` (10000 entries by default).
+//!
+//! A call on `call.decs.{shard}.event_log.replay` replies with the components of an entity as the
+//! changes still logged up to a game time left them, see `replay_entity_state`.
+use crate::capabilities::*;
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use std::collections::HashMap;
+use trader::batch::BatchRequest;
+use trader::rid::ResourceIdentifier;
+
+const DEFAULT_LOG_MAX_LEN: usize = 10_000;
+
+/// Components whose `set`s are the progress of an extraction, published on every frame
+const PROGRESS_COMPONENTS: &[&str] = &["extractors", "fuel_tank"];
+
+/// The kind of call that changed a component
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ChangeType {
+    Set,    // Fields of the component replaced or removed
+    New,    // An item added to the collection
+    Delete, // The component, or the item of the collection named by the params' `rid`, removed
+}
+
+/// One component change, as it is stored on the event log
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct LogEntry {
+    pub timestamp_ms: u64,
+    pub shard: String,
+    pub entity_id: String,
+    pub component: String, // The component's path below the entity, e.g. `extractors.1`
+    pub change_type: ChangeType,
+    pub payload: Value, // The params of the call
+}
+
+/// Appends the component changes published on the subject to their shard's event log
+pub(crate) fn record(
+    kv: &dyn KeyValueStore,
+    subject: &str,
+    payload: &[u8],
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let messages = if subject.starts_with("call.decs.batch.") {
+        let batch: BatchRequest = serde_json::from_slice(payload)?;
+        batch
+            .operations
+            .into_iter()
+            .map(|operation| (operation.subject, operation.payload))
+            .collect()
+    } else if subject.starts_with("call.decs.components.") {
+        vec![(subject.to_string(), serde_json::from_slice(payload)?)]
+    } else {
+        return Ok(());
+    };
+    for (subject, payload) in messages {
+        if let Some((rid, change_type)) = parse_change(&subject) {
+            if change_type == ChangeType::Set && PROGRESS_COMPONENTS.contains(&rid.component()) {
+                continue;
+            }
+            let entry = LogEntry {
+                timestamp_ms: trader::clock::latest_time_ms(rid.shard()),
+                shard: rid.shard().to_string(),
+                entity_id: rid.entity().to_string(),
+                component: component_path(&rid),
+                change_type,
+                payload: payload["params"].clone(),
+            };
+            let key = log_key(&entry.shard);
+            let len = kv.list_add(&key, &serde_json::to_string(&entry)?)?;
+            let max_len = log_max_len(kv, &entry.shard)?;
+            if len > max_len {
+                for expired in kv.list_range(&key, 0, (len - max_len) as isize - 1)? {
+                    kv.list_del_item(&key, &expired)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Receives calls on `call.decs.{shard}.event_log.replay` and replies with the entity's
+/// components as of the game time, by default all of the logged changes
+pub(crate) fn handle_replay(ctx: &impl Capabilities, msg: messaging::BrokerMessage) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let shard = match subject.as_slice() {
+        ["call", "decs", shard, "event_log", "replay"] => *shard,
+        _ => return Err(format!("Unexpected event log subject: {}", msg.subject).into()),
+    };
+    let params: Value = serde_json::from_slice::<Value>(&msg.body)
+        .map(|body| body["params"].clone())
+        .unwrap_or(Value::Null);
+    let entity_id = match params["entity_id"].as_str() {
+        Some(entity_id) => entity_id,
+        None => return Err("Event log replay without an entity_id".into()),
+    };
+    let up_to_ms = params["up_to_ms"].as_u64().unwrap_or(u64::MAX);
+
+    let state = replay_entity_state(ctx, shard, entity_id, up_to_ms)?;
+    if !msg.reply_to.is_empty() {
+        ctx.msg().publish(
+            &msg.reply_to,
+            None,
+            &serde_json::to_vec(&json!({ "result": state }))?,
+        )?;
+    }
+
+    Ok(vec![])
+}
+
+/// The entity's components, by their path below the entity, after the changes logged up to and
+/// including the game time. Items added with `new` are listed under their collection in the order
+/// they were added, since the IDs they are stored under aren't known until the component manager
+/// creates them
+pub(crate) fn replay_entity_state(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    up_to_ms: u64,
+) -> std::result::Result<HashMap<String, Value>, Box<dyn std::error::Error>> {
+    let entries = ctx
+        .kv()
+        .list_range(&log_key(shard), 0, -1)?
+        .iter()
+        .map(|s| serde_json::from_str(s))
+        .collect::<std::result::Result<Vec<LogEntry>, _>>()?;
+    Ok(replay(
+        entries
+            .iter()
+            .filter(|entry| entry.entity_id == entity_id)
+            .take_while(|entry| entry.timestamp_ms <= up_to_ms),
+    ))
+}
+
+fn replay<'a>(entries: impl IntoIterator<Item = &'a LogEntry>) -> HashMap<String, Value> {
+    let mut state = HashMap::new();
+    for entry in entries {
+        match entry.change_type {
+            ChangeType::Set => {
+                let component = state
+                    .entry(entry.component.to_string())
+                    .or_insert_with(|| json!({}));
+                apply_set(component, &entry.payload);
+            }
+            ChangeType::New => {
+                let collection = state
+                    .entry(entry.component.to_string())
+                    .or_insert_with(|| json!([]));
+                match collection.as_array_mut() {
+                    Some(items) => items.push(entry.payload.clone()),
+                    None => *collection = json!([entry.payload]),
+                }
+            }
+            ChangeType::Delete => {
+                let deleted = entry.payload["rid"]
+                    .as_str()
+                    .and_then(|rid| ResourceIdentifier::parse_rid(rid).ok())
+                    .filter(|rid| rid.entity() == entry.entity_id)
+                    .map(|rid| component_path(&rid))
+                    .unwrap_or_else(|| entry.component.to_string());
+                state.remove(&deleted);
+            }
+        }
+    }
+    state
+}
+
+/// Applies the params of a `set` the way the component manager does: the fields of a partial
+/// update replace those of the component, and fields set to `{ "action": "delete" }` are removed
+fn apply_set(component: &mut Value, params: &Value) {
+    let fields = match params.as_object() {
+        Some(fields) => fields,
+        None => {
+            *component = params.clone();
+            return;
+        }
+    };
+    if !component.is_object() {
+        *component = json!({});
+    }
+    if let Some(stored) = component.as_object_mut() {
+        for (field, value) in fields {
+            if *value == json!({ "action": "delete" }) {
+                stored.remove(field);
+            } else {
+                stored.insert(field.to_string(), value.clone());
+            }
+        }
+    }
+}
+
+/// The rid of the component the call on the subject changes, and how
+fn parse_change(subject: &str) -> Option<(ResourceIdentifier, ChangeType)> {
+    let (rid, call) = subject.strip_prefix("call.")?.rsplit_once('.')?;
+    let change_type = match call {
+        "set" => ChangeType::Set,
+        "new" => ChangeType::New,
+        "delete" => ChangeType::Delete,
+        _ => return None,
+    };
+    Some((ResourceIdentifier::parse_rid(rid).ok()?, change_type))
+}
+
+fn component_path(rid: &ResourceIdentifier) -> String {
+    std::iter::once(rid.component())
+        .chain(rid.item_path().iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn log_max_len(kv: &dyn KeyValueStore, shard: &str) -> Result<usize> {
+    let configured = kv
+        .get(&format!("decs:{}:event_log:max_len", shard))?
+        .and_then(|s| s.parse::<usize>().ok());
+    Ok(configured.unwrap_or(DEFAULT_LOG_MAX_LEN))
+}
+
+fn log_key(shard: &str) -> String {
+    format!("decs:event_log:{}", shard)
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle_replay;
+    use super::record;
+    use super::replay_entity_state;
+    use super::ChangeType;
+    use super::LogEntry;
+    use crate::capabilities::KeyValueStore;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+    use stacktrader_types::batch::BatchRequest;
+
+    const LOG: &str = "decs:event_log:the_void";
+
+    fn entry(
+        timestamp_ms: u64,
+        entity_id: &str,
+        component: &str,
+        change_type: ChangeType,
+        payload: serde_json::Value,
+    ) -> LogEntry {
+        LogEntry {
+            timestamp_ms,
+            shard: "the_void".to_string(),
+            entity_id: entity_id.to_string(),
+            component: component.to_string(),
+            change_type,
+            payload,
+        }
+    }
+
+    /// A miner creating an extractor, which makes progress and completes, delivering a stack
+    fn logged_extraction() -> MockCapabilitiesContext {
+        let ctx = MockCapabilitiesContext::new();
+        for entry in &[
+            entry(
+                1000,
+                "player1",
+                "fuel_tank",
+                ChangeType::Set,
+                json!({ "capacity": 100.0, "current": 100.0 }),
+            ),
+            entry(
+                1000,
+                "player1",
+                "extractors.1",
+                ChangeType::Set,
+                json!({ "target": "decs.components.the_void.asteroid1.mining_resource", "remaining_ms": 2000.0 }),
+            ),
+            entry(
+                2000,
+                "asteroid1",
+                "mining_lock",
+                ChangeType::Set,
+                json!({ "owner": "player1" }),
+            ),
+            entry(
+                2000,
+                "player1",
+                "extractors.1",
+                ChangeType::Set,
+                json!({ "remaining_ms": 1000.0 }),
+            ),
+            entry(
+                2000,
+                "player1",
+                "fuel_tank",
+                ChangeType::Set,
+                json!({ "current": 90.0 }),
+            ),
+            entry(
+                3000,
+                "player1",
+                "inventory",
+                ChangeType::New,
+                json!({ "stack_type": "tasty", "qty": 7 }),
+            ),
+            entry(
+                3000,
+                "player1",
+                "extractors",
+                ChangeType::Delete,
+                json!({ "rid": "decs.components.the_void.player1.extractors.1" }),
+            ),
+        ] {
+            (&ctx)
+                .list_add(LOG, &serde_json::to_string(entry).unwrap())
+                .unwrap();
+        }
+        ctx
+    }
+
+    #[test]
+    fn test_replay_produces_the_final_state() {
+        let state = replay_entity_state(&logged_extraction(), "the_void", "player1", 3000).unwrap();
+        assert_eq!(state.len(), 2);
+        assert_eq!(
+            state["fuel_tank"],
+            json!({ "capacity": 100.0, "current": 90.0 })
+        );
+        assert_eq!(
+            state["inventory"],
+            json!([{ "stack_type": "tasty", "qty": 7 }])
+        );
+    }
+
+    #[test]
+    fn test_replay_stops_at_the_game_time() {
+        let ctx = logged_extraction();
+        let state = replay_entity_state(&ctx, "the_void", "player1", 2500).unwrap();
+        assert_eq!(
+            state["extractors.1"],
+            json!({ "target": "decs.components.the_void.asteroid1.mining_resource", "remaining_ms": 1000.0 })
+        );
+        assert!(!state.contains_key("inventory"));
+
+        let state = replay_entity_state(&ctx, "the_void", "player1", 999).unwrap();
+        assert!(state.is_empty());
+        let state = replay_entity_state(&ctx, "the_void", "asteroid1", 3000).unwrap();
+        assert_eq!(state["mining_lock"], json!({ "owner": "player1" }));
+    }
+
+    #[test]
+    fn test_component_publishes_are_logged() {
+        let ctx = MockCapabilitiesContext::new();
+        stacktrader_types::clock::observe("logged_shard", 4000);
+        let set = "call.decs.components.logged_shard.player1.cargo_hold.set";
+        record(&&ctx, set, br#"{"params": {"current": 5.0}}"#).unwrap();
+        let batch = BatchRequest::new(
+            "logged_shard",
+            vec![
+                (
+                    "call.decs.components.logged_shard.player1.cargo_hold.set".to_string(),
+                    json!({ "params": { "current": 4.0, "capacity": { "action": "delete" } } }),
+                ),
+                (
+                    "event.decs.logged_shard.player1.mining.completed".to_string(),
+                    json!({ "qty": 7 }),
+                ),
+            ],
+        );
+        record(
+            &&ctx,
+            &batch.subject(),
+            &serde_json::to_vec(&batch).unwrap(),
+        )
+        .unwrap();
+        // Neither events nor calls on anything but components are changes
+        record(
+            &&ctx,
+            "event.decs.logged_shard.player1.mining.failed",
+            b"{}",
+        )
+        .unwrap();
+        record(&&ctx, "call.decs.logged_shard.mining.log", b"{}").unwrap();
+
+        let logged = ctx.lists.borrow()["decs:event_log:logged_shard"].clone();
+        assert_eq!(logged.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<LogEntry>(&logged[0]).unwrap(),
+            LogEntry {
+                timestamp_ms: 4000,
+                shard: "logged_shard".to_string(),
+                entity_id: "player1".to_string(),
+                component: "cargo_hold".to_string(),
+                change_type: ChangeType::Set,
+                payload: json!({ "current": 5.0 }),
+            }
+        );
+
+        handle_replay(
+            &ctx,
+            BrokerMessage {
+                subject: "call.decs.logged_shard.event_log.replay".to_string(),
+                reply_to: "_INBOX.replay1".to_string(),
+                body: serde_json::to_vec(&json!({ "params": { "entity_id": "player1" } })).unwrap(),
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            ctx.payload("_INBOX.replay1").unwrap(),
+            json!({ "result": { "cargo_hold": { "current": 4.0 } } })
+        );
+    }
+
+    #[test]
+    fn test_extraction_progress_is_not_logged() {
+        let ctx = MockCapabilitiesContext::new();
+        let progress = [
+            "call.decs.components.progress_shard.player1.extractors.1.set",
+            "call.decs.components.progress_shard.player1.fuel_tank.set",
+        ];
+        for subject in &progress {
+            record(&&ctx, subject, br#"{"params": {"remaining_ms": 500.0}}"#).unwrap();
+        }
+        assert!(!ctx
+            .lists
+            .borrow()
+            .contains_key("decs:event_log:progress_shard"));
+
+        // The extractor's removal is logged
+        record(
+            &&ctx,
+            "call.decs.components.progress_shard.player1.extractors.delete",
+            br#"{"params": {"rid": "decs.components.progress_shard.player1.extractors.1"}}"#,
+        )
+        .unwrap();
+        assert_eq!(ctx.lists.borrow()["decs:event_log:progress_shard"].len(), 1);
+    }
+
+    #[test]
+    fn test_log_keeps_the_most_recent_entries() {
+        let ctx = MockCapabilitiesContext::new()
+            .with_setting("decs:trimmed_shard:event_log:max_len", "2");
+        for current in 1..=3 {
+            record(
+                &&ctx,
+                "call.decs.components.trimmed_shard.player1.cargo_hold.set",
+                format!(r#"{{"params": {{"used": {}}}}}"#, current).as_bytes(),
+            )
+            .unwrap();
+        }
+        let state = replay_entity_state(&ctx, "trimmed_shard", "player1", u64::MAX).unwrap();
+        assert_eq!(state["cargo_hold"], json!({ "used": 3 }));
+        let logged = ctx.lists.borrow()["decs:event_log:trimmed_shard"].clone();
+        let logged: Vec<LogEntry> = logged
+            .iter()
+            .map(|entry| serde_json::from_str(entry).unwrap())
+            .collect();
+        assert_eq!(
+            logged
+                .iter()
+                .map(|entry| entry.payload["used"].clone())
+                .collect::<Vec<_>>(),
+            vec![json!(2), json!(3)]
+        );
+    }
+}
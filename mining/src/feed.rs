@@ -0,0 +1,200 @@
+//! # Mining Events Feed
+//!
+//! Every extraction that ends on a shard is added to the shard's activity feed, the resgate
+//! collection `decs.shard.{shard}.mining_events`, with a `new` call holding a `MiningFeedEvent`:
+//! who mined what, when, and whether the extraction completed, failed or was cancelled. Events
+//! are numbered by the KV counter `decs:{shard}:mining_events:seq` and their `seq` addresses them
+//! in the collection. The feed is bounded to `feed_max_len` events, read from
+//! `decs:{shard}:mining:feed_max_len` and 50 by default: once it is full, each new event deletes
+//! the oldest one.
+use crate::capabilities::*;
+use crate::mining::{member_delete_message, OUT_OF_RANGE, RESOURCE_MISSING};
+use decs::gateway::ResProtocolRequest;
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const DEFAULT_FEED_MAX_LEN: u64 = 50;
+
+/// Adds an extraction that delivered its resource to the shard's feed
+pub(crate) fn publish_completed(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    target: &str,
+    mined: &MiningResource,
+    timestamp_ms: u64,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    publish_event(
+        ctx,
+        shard,
+        MiningFeedEvent {
+            seq: 0,
+            miner: entity_id.to_string(),
+            target: target.to_string(),
+            stack_type: Some(mined.stack_type.clone()),
+            qty: Some(mined.qty),
+            timestamp_ms,
+            outcome: MiningOutcome::Completed,
+            reason: None,
+        },
+    )
+}
+
+/// Adds an extraction that ended without reaching the inventory to the shard's feed, failed if
+/// its resource is gone or out of range and cancelled otherwise, as telemetry counts them
+pub(crate) fn publish_failure(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    target: &str,
+    reason: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let outcome = match reason {
+        RESOURCE_MISSING | OUT_OF_RANGE => MiningOutcome::Failed,
+        _ => MiningOutcome::Cancelled,
+    };
+    publish_event(
+        ctx,
+        shard,
+        MiningFeedEvent {
+            seq: 0,
+            miner: entity_id.to_string(),
+            target: target.to_string(),
+            stack_type: None,
+            qty: None,
+            timestamp_ms: trader::clock::latest_time_ms(shard),
+            outcome,
+            reason: Some(reason.to_string()),
+        },
+    )
+}
+
+/// Numbers the event and publishes it, trimming the feed
+fn publish_event(
+    ctx: &impl Capabilities,
+    shard: &str,
+    event: MiningFeedEvent,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let seq = ctx
+        .kv()
+        .atomic_add(&format!("decs:{}:mining_events:seq", shard), 1)? as u64;
+    let max_len = feed_max_len(ctx, shard)?;
+    for (subject, payload) in plan_feed_publish(shard, MiningFeedEvent { seq, ..event }, max_len) {
+        ctx.msg()
+            .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+    }
+    Ok(())
+}
+
+/// The `new` call adding the event to the feed, followed by the deletion of the event that no
+/// longer fits in it, if any
+fn plan_feed_publish(shard: &str, event: MiningFeedEvent, max_len: u64) -> Vec<(String, Value)> {
+    let collection = format!("decs.shard.{}.mining_events", shard);
+    let expired = event.seq.saturating_sub(max_len);
+    let mut publishes = vec![(
+        ResProtocolRequest::New(collection.clone()).to_string(),
+        json!({ "params": event }),
+    )];
+    if expired > 0 {
+        publishes.push(member_delete_message(
+            &format!("{}.{}", collection, expired),
+            Some(collection),
+        ));
+    }
+    publishes
+}
+
+fn feed_max_len(ctx: &impl Capabilities, shard: &str) -> Result<u64> {
+    let configured = ctx
+        .kv()
+        .get(&format!("decs:{}:mining:feed_max_len", shard))?
+        .and_then(|s| s.parse::<u64>().ok());
+    Ok(configured.unwrap_or(DEFAULT_FEED_MAX_LEN))
+}
+
+#[cfg(test)]
+mod test {
+    use super::publish_failure;
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const FEED_NEW: &str = "call.decs.shard.the_void.mining_events.new";
+    const FEED_DELETE: &str = "call.decs.shard.the_void.mining_events.delete";
+    const TARGET: &str = "decs.components.the_void.asteroid1.mining_resource";
+
+    #[test]
+    fn test_feed_is_trimmed_to_max_len() {
+        let ctx =
+            MockCapabilitiesContext::new().with_setting("decs:the_void:mining:feed_max_len", "2");
+        for _ in 0..3 {
+            publish_failure(&ctx, "the_void", "player1", TARGET, "cargo_full").unwrap();
+        }
+        assert_eq!(
+            ctx.subjects(),
+            vec![FEED_NEW, FEED_NEW, FEED_NEW, FEED_DELETE]
+        );
+        let published = ctx.published.borrow();
+        let last: serde_json::Value = serde_json::from_slice(&published[2].1).unwrap();
+        assert_eq!(last["params"]["seq"], 3);
+        assert_eq!(last["params"]["outcome"], "cancelled");
+        assert_eq!(
+            ctx.payload(FEED_DELETE).unwrap(),
+            json!({ "params": { "rid": "decs.shard.the_void.mining_events.1" } })
+        );
+    }
+
+    #[test]
+    fn test_completed_extraction_is_added_to_feed() {
+        let ctx = MockCapabilitiesContext::new()
+            .with_extractor(
+                "decs.components.the_void.player1.extractors.1",
+                json!({ "target": TARGET, "remaining_ms": 500.0, "total_ms": 1000.0 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_lock",
+                json!({
+                    "extractor": "decs.components.the_void.player1.extractors.1",
+                    "owner": "player1"
+                }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_resource",
+                json!({ "stack_type": "tasty", "qty": 3 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:transponder",
+                json!({ "object_type": "asteroid", "display_name": "Rock", "color": "gray" }),
+            );
+        let frame = BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 5,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        };
+        handle_frame(&ctx, frame).unwrap();
+        assert_eq!(
+            ctx.payload(FEED_NEW).unwrap(),
+            json!({
+                "params": {
+                    "seq": 1,
+                    "miner": "player1",
+                    "target": TARGET,
+                    "stack_type": "tasty",
+                    "qty": 3,
+                    "timestamp_ms": 5000,
+                    "outcome": "completed"
+                }
+            })
+        );
+        assert!(!ctx.subjects().contains(&FEED_DELETE.to_string()));
+    }
+}
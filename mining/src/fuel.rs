@@ -0,0 +1,227 @@
+//! # Fuel Cost
+//!
+//! Running an extractor burns fuel from the miner's `fuel_tank`, `fuel_per_sec` units per second
+//! of the frame's elapsed time for each running extractor. When the tank runs dry the extraction
+//! is paused rather than cancelled, and an event is published on
+//! `event.decs.{shard}.{entity}.mining.out_of_fuel`. Refuelling and resuming the extractor picks
+//! up where it left off. Entities without a fuel tank mine for free.
+use crate::capabilities::*;
+use crate::diff::DiffPublisher;
+use crate::mining::publish_extractor;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::validation::Validate;
+
+const FUEL_TANK: &str = "fuel_tank";
+const DEFAULT_FUEL_PER_SEC: f64 = 1.0;
+
+/// Retrieve the entity's fuel tank, if it has one
+pub(crate) fn get_fuel_tank(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<Option<FuelTank>, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, FUEL_TANK
+    ))? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+/// Burns the shard's fuel cost for the frame from the tank, returning the tank after the burn
+pub(crate) fn burn_fuel(
+    ctx: &impl Capabilities,
+    tank: &FuelTank,
+    shard: &str,
+    elapsed_ms: u32,
+) -> Result<FuelTank> {
+    Ok(plan_burn(tank, fuel_per_sec(ctx, shard)?, elapsed_ms))
+}
+
+/// Publishes the fields of the tank that changed since the previous value, see `diff`
+pub(crate) fn publish_fuel_tank(
+    ctx: &impl Capabilities,
+    previous: &FuelTank,
+    tank: &FuelTank,
+    shard: &str,
+    entity_id: &str,
+) -> Result<()> {
+    tank.validate()?;
+    DiffPublisher::new(previous, tank).publish(
+        ctx,
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard, entity_id, FUEL_TANK
+        ),
+    )
+}
+
+/// Publishes the extractor paused, along with the out of fuel event
+pub(crate) fn pause_out_of_fuel(
+    ctx: &impl Capabilities,
+    extractor: &MiningExtractor,
+    extractor_rid: &str,
+    shard: &str,
+    entity_id: &str,
+) -> CallResult {
+    ctx.log(&format!(
+        "Entity {} ran out of fuel, pausing its extraction from {}",
+        entity_id, extractor.target
+    ));
+    let extractor = MiningExtractor {
+        paused: true,
+        ..extractor.clone()
+    };
+    publish_extractor(ctx, &extractor, extractor_rid)?;
+    ctx.msg().publish(
+        &format!("event.decs.{}.{}.mining.out_of_fuel", shard, entity_id),
+        None,
+        &serde_json::to_vec(&json!({
+            "target": extractor.target,
+            "remaining_ms": extractor.remaining_ms
+        }))?,
+    )?;
+    Ok(vec![])
+}
+
+/// Whether the tank has no fuel left to run an extractor
+pub(crate) fn is_empty(tank: &FuelTank) -> bool {
+    tank.current <= 0.0
+}
+
+/// The fuel cost of the shard, stored in the KV store under `decs:{shard}:mining:fuel_per_sec`
+fn fuel_per_sec(ctx: &impl Capabilities, shard: &str) -> Result<f64> {
+    let configured = ctx
+        .kv()
+        .get(&format!("decs:{}:mining:fuel_per_sec", shard))?
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|rate| *rate >= 0.0);
+    Ok(configured.unwrap_or(DEFAULT_FUEL_PER_SEC))
+}
+
+/// Takes the fuel burnt over the elapsed time out of the tank, which never drops below empty
+fn plan_burn(tank: &FuelTank, fuel_per_sec: f64, elapsed_ms: u32) -> FuelTank {
+    let burnt = fuel_per_sec * f64::from(elapsed_ms) / 1000.0;
+    FuelTank {
+        current: (tank.current - burnt).max(0.0),
+        ..tank.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_empty;
+    use super::plan_burn;
+    use super::FuelTank;
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    fn tank(current: f64) -> FuelTank {
+        FuelTank { current, max: 50.0 }
+    }
+
+    fn context(tank: f64) -> MockCapabilitiesContext {
+        MockCapabilitiesContext::new()
+            .with_extractor(
+                "decs.components.the_void.player1.extractors.1",
+                json!({
+                    "target": "decs.components.the_void.asteroid1.mining_resource",
+                    "remaining_ms": 900.0,
+                    "total_ms": 1000.0
+                }),
+            )
+            .with_value(
+                "decs:components:the_void:player1:fuel_tank",
+                json!({ "current": tank, "max": 50.0 }),
+            )
+            .with_setting("decs:the_void:mining:fuel_per_sec", "2")
+    }
+
+    fn frame() -> BrokerMessage {
+        BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 12,
+                "elapsed_ms": 500,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_normal_burn() {
+        assert_eq!(plan_burn(&tank(10.0), 2.0, 500), tank(9.0));
+        assert!(!is_empty(&plan_burn(&tank(10.0), 2.0, 500)));
+        // Burning more than is left empties the tank, but never overdraws it
+        assert_eq!(plan_burn(&tank(0.5), 2.0, 500), tank(0.0));
+
+        let ctx = context(10.0);
+        handle_frame(&ctx, frame()).unwrap();
+        assert_eq!(
+            ctx.payload("call.decs.components.the_void.player1.fuel_tank.set"),
+            Some(json!({ "params": { "current": 9.0 } }))
+        );
+        let extractor = ctx
+            .payload("call.decs.components.the_void.player1.extractors.1.set")
+            .unwrap();
+        assert_eq!(extractor["params"]["remaining_ms"], 400.0);
+        assert_ne!(extractor["params"]["paused"], true);
+    }
+
+    #[test]
+    fn test_exact_empty() {
+        assert_eq!(plan_burn(&tank(1.0), 2.0, 500), tank(0.0));
+        assert!(is_empty(&plan_burn(&tank(1.0), 2.0, 500)));
+    }
+
+    #[test]
+    fn test_pause_on_empty() {
+        // The frame that drains the tank still progresses, then the extraction pauses
+        let ctx = context(1.0);
+        handle_frame(&ctx, frame()).unwrap();
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "call.decs.components.the_void.player1.extractors.1.set",
+                "event.decs.the_void.player1.mining.out_of_fuel",
+                "call.decs.components.the_void.player1.fuel_tank.set",
+            ]
+        );
+        let extractor = ctx
+            .payload("call.decs.components.the_void.player1.extractors.1.set")
+            .unwrap();
+        assert_eq!(extractor["params"]["remaining_ms"], 400.0);
+        assert_eq!(extractor["params"]["paused"], true);
+        assert_eq!(
+            ctx.payload("event.decs.the_void.player1.mining.out_of_fuel"),
+            Some(json!({
+                "target": "decs.components.the_void.asteroid1.mining_resource",
+                "remaining_ms": 400.0
+            }))
+        );
+
+        // An extractor resumed without refuelling pauses again without progressing
+        let ctx = context(0.0);
+        handle_frame(&ctx, frame()).unwrap();
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "call.decs.components.the_void.player1.extractors.1.set",
+                "event.decs.the_void.player1.mining.out_of_fuel",
+            ]
+        );
+        let extractor = ctx
+            .payload("call.decs.components.the_void.player1.extractors.1.set")
+            .unwrap();
+        assert_eq!(extractor["params"]["remaining_ms"], 900.0);
+        assert_eq!(extractor["params"]["paused"], true);
+    }
+}
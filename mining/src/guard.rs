@@ -0,0 +1,111 @@
+//! # Completion Guard
+//!
+//! Two frames for the same extractor can arrive before the extractor published by the first
+//! has landed in the KV store, and both would then complete the extraction. Before completing,
+//! an extraction claims a guard key for the entity and target; only the first claim succeeds.
+//! The guard key expires shortly after, so the same player can mine the target again once it
+//! has respawned. A completion that couldn't take the deposit releases its claim, so the next
+//! frame can try again right away.
+use crate::capabilities::*;
+use guest::prelude::*;
+
+// Long enough to outlast any frames already in flight for the extractor
+const GUARD_TTL_SECONDS: u32 = 30;
+
+/// Claims the completion of the entity's extraction from the target. Returns false if the
+/// completion has already been claimed, in which case nothing must be published
+pub(crate) fn claim_completion(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    target: &str,
+) -> Result<bool> {
+    let key = guard_key(shard, entity_id, target);
+    // The increment is atomic, so exactly one claimant sees the counter go from 0 to 1
+    if ctx.kv().atomic_add(&key, 1)? != 1 {
+        return Ok(false);
+    }
+    // Resetting the counter gives the key its expiry, later claimants still see it above 1. A
+    // key left without one would block the completion for good, so the claim is given up instead
+    if let Err(e) = ctx.kv().set(&key, "1", Some(GUARD_TTL_SECONDS)) {
+        ctx.kv().del_key(&key)?;
+        return Err(e);
+    }
+    Ok(true)
+}
+
+/// Gives up a claim on a completion that took nothing, so the next frame can claim it again
+pub(crate) fn release_completion(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    target: &str,
+) -> Result<()> {
+    ctx.kv().del_key(&guard_key(shard, entity_id, target))
+}
+
+fn guard_key(shard: &str, entity_id: &str, target: &str) -> String {
+    format!("decs:mining:completed:{}:{}:{}", shard, entity_id, target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::claim_completion;
+    use super::guard_key;
+    use super::release_completion;
+    use crate::test_utils::MockCapabilitiesContext;
+
+    const TARGET: &str = "decs.components.the_void.asteroid1.mining_resource";
+
+    #[test]
+    fn test_double_frame_race_completes_once() {
+        let ctx = MockCapabilitiesContext::new();
+
+        // Both frames see an extractor with no time remaining
+        let inventory_adds = (0..2)
+            .filter(|_| claim_completion(&ctx, "the_void", "player1", TARGET).unwrap())
+            .count();
+        assert_eq!(inventory_adds, 1);
+    }
+
+    #[test]
+    fn test_guard_is_per_entity() {
+        let ctx = MockCapabilitiesContext::new();
+        assert!(claim_completion(&ctx, "the_void", "player1", TARGET).unwrap());
+        assert!(claim_completion(&ctx, "the_void", "player2", TARGET).unwrap());
+    }
+
+    #[test]
+    fn test_guard_expires_for_respawned_target() {
+        let ctx = MockCapabilitiesContext::new();
+        assert!(claim_completion(&ctx, "the_void", "player1", TARGET).unwrap());
+        assert!(ctx
+            .expiring
+            .borrow()
+            .contains(&guard_key("the_void", "player1", TARGET)));
+
+        ctx.expire_all();
+        assert!(claim_completion(&ctx, "the_void", "player1", TARGET).unwrap());
+    }
+
+    #[test]
+    fn test_released_guard_can_be_claimed_again() {
+        let ctx = MockCapabilitiesContext::new();
+        assert!(claim_completion(&ctx, "the_void", "player1", TARGET).unwrap());
+        assert!(!claim_completion(&ctx, "the_void", "player1", TARGET).unwrap());
+
+        release_completion(&ctx, "the_void", "player1", TARGET).unwrap();
+        assert!(claim_completion(&ctx, "the_void", "player1", TARGET).unwrap());
+    }
+
+    #[test]
+    fn test_guard_without_expiry_is_given_up() {
+        let key = guard_key("the_void", "player1", TARGET);
+        let ctx = MockCapabilitiesContext::new().with_failing_key(&key);
+        assert!(claim_completion(&ctx, "the_void", "player1", TARGET).is_err());
+        assert!(!ctx.values.borrow().contains_key(&key));
+
+        ctx.failing.borrow_mut().clear();
+        assert!(claim_completion(&ctx, "the_void", "player1", TARGET).unwrap());
+    }
+}
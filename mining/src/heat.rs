@@ -0,0 +1,245 @@
+//! # Extractor Heat
+//!
+//! An extractor with a `heat_limit` heats up while it extracts and cools down while it is paused.
+//! Once its `heat` reaches `max_heat`, the extractor pauses itself and is marked `overheated`, and
+//! `event.decs.{shard}.{entity}.mining.overheated` is published. An overheated extractor keeps
+//! cooling while paused and resumes by itself once its heat is down to `resume_heat`, publishing
+//! `event.decs.{shard}.{entity}.mining.cooled`. Resuming well below the limit keeps the extractor
+//! from flickering between running and overheated on every frame. Extractors without a heat
+//! limit, including every legacy extractor, never heat up.
+use crate::capabilities::*;
+use crate::mining::publish_extractor_diff;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+/// Heats a running extractor, or cools a paused one, for the elapsed time, pausing it if it
+/// overheats and resuming it once it has cooled off from overheating
+pub(crate) fn integrate_heat(extractor: MiningExtractor, elapsed_ms: u32) -> MiningExtractor {
+    let limit = match &extractor.heat_limit {
+        Some(limit) => limit.clone(),
+        None => return extractor,
+    };
+    let elapsed_ms = f64::from(elapsed_ms);
+    if extractor.paused {
+        let heat = (extractor.heat - limit.cooling_rate * elapsed_ms).max(0.0);
+        let resumed = extractor.overheated && heat <= limit.resume_heat();
+        MiningExtractor {
+            heat,
+            overheated: extractor.overheated && !resumed,
+            paused: !resumed,
+            ..extractor
+        }
+    } else {
+        let heat = extractor.heat + limit.heating_rate * elapsed_ms;
+        let overheated = heat >= limit.max_heat;
+        MiningExtractor {
+            heat,
+            overheated,
+            paused: overheated,
+            ..extractor
+        }
+    }
+}
+
+/// Whether the paused extractor still has heat to lose
+pub(crate) fn is_cooling(extractor: &MiningExtractor) -> bool {
+    extractor.paused && extractor.heat_limit.is_some() && extractor.heat > 0.0
+}
+
+/// Publishes the extractor that paused itself by overheating and tells the HUD why it stopped
+pub(crate) fn publish_overheated(
+    ctx: &impl Capabilities,
+    stored: &MiningExtractor,
+    extractor: &MiningExtractor,
+    extractor_rid: &str,
+    shard: &str,
+    entity_id: &str,
+) -> CallResult {
+    ctx.log(&format!(
+        "Extractor {} of entity {} overheated, pausing its extraction from {}",
+        extractor_rid, entity_id, extractor.target
+    ));
+    publish_extractor_diff(ctx, stored, extractor, extractor_rid)?;
+    publish_heat_event(ctx, extractor, shard, entity_id, "overheated")
+}
+
+/// Publishes the paused extractor after it cooled down for a frame, announcing that it resumed
+/// if it was overheated and is cool enough now
+pub(crate) fn publish_cooled(
+    ctx: &impl Capabilities,
+    stored: &MiningExtractor,
+    extractor: &MiningExtractor,
+    extractor_rid: &str,
+    shard: &str,
+    entity_id: &str,
+) -> CallResult {
+    publish_extractor_diff(ctx, stored, extractor, extractor_rid)?;
+    if stored.overheated && !extractor.overheated {
+        publish_heat_event(ctx, extractor, shard, entity_id, "cooled")?;
+    }
+    Ok(vec![])
+}
+
+fn publish_heat_event(
+    ctx: &impl Capabilities,
+    extractor: &MiningExtractor,
+    shard: &str,
+    entity_id: &str,
+    event: &str,
+) -> CallResult {
+    ctx.msg().publish(
+        &format!("event.decs.{}.{}.mining.{}", shard, entity_id, event),
+        None,
+        &serde_json::to_vec(&json!({
+            "target": extractor.target,
+            "heat": extractor.heat,
+            "remaining_ms": extractor.remaining_ms
+        }))?,
+    )?;
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod test {
+    use super::integrate_heat;
+    use super::HeatLimit;
+    use super::MiningExtractor;
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+    use stacktrader_types::units::Millis;
+
+    const EXTRACTOR_RID: &str = "decs.components.the_void.player1.extractors.1";
+    const OVERHEATED: &str = "event.decs.the_void.player1.mining.overheated";
+    const COOLED: &str = "event.decs.the_void.player1.mining.cooled";
+
+    fn limited(heat: f64) -> MiningExtractor {
+        MiningExtractor {
+            target: "decs.components.the_void.asteroid1.mining_resource".to_string(),
+            remaining_ms: Millis(10_000.0),
+            total_ms: 10_000.0,
+            heat,
+            heat_limit: Some(HeatLimit {
+                max_heat: 3000.0,
+                resume_heat: None,
+                heating_rate: 1.0,
+                cooling_rate: 1.0,
+            }),
+            ..MiningExtractor::default()
+        }
+    }
+
+    /// Runs a mining frame and applies the extractor it published, the way the component
+    /// manager would before the next frame. Returns the subjects published on
+    fn run_frame(ctx: &MockCapabilitiesContext) -> Vec<String> {
+        handle_frame(
+            ctx,
+            BrokerMessage {
+                subject: "decs.frames.the_void.mining".to_string(),
+                body: serde_json::to_vec(&json!({
+                    "seq_no": 1,
+                    "elapsed_ms": 1000,
+                    "shard": "the_void",
+                    "system": "mining",
+                    "entity_id": "player1"
+                }))
+                .unwrap(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let published: Vec<(String, Vec<u8>)> = ctx.published.borrow_mut().drain(..).collect();
+        for (subject, payload) in &published {
+            if *subject == format!("call.{}.set", EXTRACTOR_RID) {
+                let payload: serde_json::Value = serde_json::from_slice(payload).unwrap();
+                ctx.apply_set(EXTRACTOR_RID, &payload["params"]);
+            }
+        }
+        published.into_iter().map(|(subject, _)| subject).collect()
+    }
+
+    fn extractor(ctx: &MockCapabilitiesContext) -> MiningExtractor {
+        serde_json::from_str(&ctx.values.borrow()[&EXTRACTOR_RID.replace('.', ":")]).unwrap()
+    }
+
+    #[test]
+    fn test_running_extractor_heats_up_until_it_overheats() {
+        let warm = integrate_heat(limited(0.0), 1000);
+        assert_eq!(warm.heat, 1000.0);
+        assert!(!warm.paused);
+
+        let hot = integrate_heat(limited(2500.0), 1000);
+        assert_eq!(hot.heat, 3500.0);
+        assert!(hot.overheated);
+        assert!(hot.paused);
+    }
+
+    #[test]
+    fn test_overheated_extractor_resumes_below_resume_heat() {
+        let mut hot = integrate_heat(limited(2500.0), 1000);
+        // Cooling below the limit isn't enough, it has to get down to half of it
+        hot = integrate_heat(hot, 1000);
+        assert_eq!(hot.heat, 2500.0);
+        assert!(hot.paused && hot.overheated);
+        hot = integrate_heat(hot, 1000);
+        assert_eq!(hot.heat, 1500.0);
+        assert!(!hot.paused && !hot.overheated);
+
+        // An extractor paused by the player cools down but stays paused
+        let paused = MiningExtractor {
+            paused: true,
+            ..limited(2000.0)
+        };
+        let cooled = integrate_heat(paused, 5000);
+        assert_eq!(cooled.heat, 0.0);
+        assert!(cooled.paused);
+    }
+
+    #[test]
+    fn test_extractor_without_limit_never_heats() {
+        let legacy = MiningExtractor {
+            heat_limit: None,
+            ..limited(0.0)
+        };
+        assert_eq!(integrate_heat(legacy.clone(), 60_000), legacy);
+        // Nor does an extractor without heat show any in its payload
+        let json = serde_json::to_value(&legacy).unwrap();
+        assert!(json.get("heat").is_none());
+        assert!(json.get("overheated").is_none());
+    }
+
+    #[test]
+    fn test_long_extraction_overheats_cools_and_resumes() {
+        let ctx = MockCapabilitiesContext::new()
+            .with_extractor(EXTRACTOR_RID, serde_json::to_value(limited(0.0)).unwrap());
+
+        // Three seconds of extraction reach the limit
+        run_frame(&ctx);
+        run_frame(&ctx);
+        assert_eq!(extractor(&ctx).heat, 2000.0);
+        let subjects = run_frame(&ctx);
+        assert!(subjects.contains(&OVERHEATED.to_string()));
+        let overheated = extractor(&ctx);
+        assert!(overheated.paused && overheated.overheated);
+        assert_eq!(overheated.remaining_ms, Millis(7000.0));
+
+        // While it cools down the extraction makes no progress
+        let subjects = run_frame(&ctx);
+        assert!(!subjects.contains(&COOLED.to_string()));
+        assert_eq!(extractor(&ctx).heat, 2000.0);
+        assert_eq!(extractor(&ctx).remaining_ms, Millis(7000.0));
+        let subjects = run_frame(&ctx);
+        assert!(subjects.contains(&COOLED.to_string()));
+        let resumed = extractor(&ctx);
+        assert!(!resumed.paused && !resumed.overheated);
+        assert_eq!(resumed.heat, 1000.0);
+
+        // Running again, it overheats sooner since it never cooled off completely
+        run_frame(&ctx);
+        assert_eq!(extractor(&ctx).remaining_ms, Millis(6000.0));
+        let subjects = run_frame(&ctx);
+        assert!(subjects.contains(&OVERHEATED.to_string()));
+        assert_eq!(extractor(&ctx).remaining_ms, Millis(5000.0));
+    }
+}
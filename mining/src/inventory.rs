@@ -0,0 +1,201 @@
+//! # Inventory
+//!
+//! Helpers for placing extracted resources into an entity's `inventory` collection as
+//! `InventoryItem`s. Items with the same kind and name are merged into a single inventory item,
+//! and an entity's `cargo_hold` limits how many items its inventory can contain.
+use crate::capabilities::*;
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::migration::migrate_component;
+
+/// How an extracted resource ends up in an inventory
+#[derive(Debug, PartialEq)]
+pub(crate) enum InventoryAdd {
+    /// Replace the existing item at the given rid with the merged stack
+    Stack(String, InventoryItem),
+    /// Add the resource to the inventory as a new item
+    New(InventoryItem),
+}
+
+/// Retrieve the fully-qualified RIDs of all items in the entity's `inventory` collection
+pub(crate) fn get_inventory_rids(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity: &str,
+) -> Result<Vec<String>> {
+    let key = format!("decs:components:{}:{}:{}", shard, entity, super::INVENTORY);
+    ctx.kv().list_range(&key, 0, -1)
+}
+
+/// Retrieve the contents of each inventory item, upgrading items from older inventories and
+/// skipping any that can't be read
+pub(crate) fn get_inventory_items(
+    ctx: &impl Capabilities,
+    rids: &[String],
+) -> Vec<(String, InventoryItem)> {
+    rids.iter()
+        .filter_map(|rid| match ctx.kv().get(&rid.replace('.', ":")) {
+            Ok(Some(s)) => migrate_component(&s)
+                .ok()
+                .map(|item| (rid.to_string(), item.into_inner())),
+            _ => None,
+        })
+        .collect()
+}
+
+pub(crate) fn get_cargo_hold(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity: &str,
+) -> std::result::Result<Option<CargoHold>, Box<dyn std::error::Error>> {
+    let raw = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard,
+        entity,
+        super::CARGO_HOLD
+    ))?;
+    match raw {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+/// Whether one more item fits in the inventory. An entity without a cargo hold has unlimited space
+pub(crate) fn has_cargo_space(hold: Option<&CargoHold>, inventory_size: usize) -> bool {
+    hold.map(|h| inventory_size < h.capacity as usize)
+        .unwrap_or(true)
+}
+
+/// Determines whether the item can be merged into an existing stack or needs a new item
+pub(crate) fn plan_inventory_add(
+    inventory: &[(String, InventoryItem)],
+    item: &InventoryItem,
+) -> InventoryAdd {
+    match inventory
+        .iter()
+        .find(|(_, existing)| existing.stack_key() == item.stack_key())
+    {
+        Some((rid, existing)) => InventoryAdd::Stack(
+            rid.to_string(),
+            InventoryItem {
+                qty: existing.qty + item.qty,
+                version: INVENTORY_ITEM_VERSION,
+                ..existing.clone()
+            },
+        ),
+        None => InventoryAdd::New(item.clone()),
+    }
+}
+
+/// The subject and payload that apply an inventory addition, either a `set` on the merged
+/// item or a `new` on the inventory collection
+pub(crate) fn inventory_add_message(
+    shard: &str,
+    entity: &str,
+    add: &InventoryAdd,
+) -> (String, Value) {
+    let (subject, item) = match add {
+        InventoryAdd::Stack(rid, item) => (format!("call.{}.set", rid), item),
+        InventoryAdd::New(item) => (
+            format!(
+                "call.decs.components.{}.{}.{}.new",
+                shard,
+                entity,
+                super::INVENTORY
+            ),
+            item,
+        ),
+    };
+    (subject, json!({ "params": item }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::has_cargo_space;
+    use super::plan_inventory_add;
+    use super::CargoHold;
+    use super::InventoryAdd;
+    use super::InventoryItem;
+    use super::MiningResource;
+    use super::Rarity;
+
+    fn resource(stack_type: &str, qty: u32) -> InventoryItem {
+        InventoryItem::from(&MiningResource {
+            stack_type: stack_type.to_string(),
+            qty,
+            ..MiningResource::default()
+        })
+    }
+
+    #[test]
+    fn test_cargo_hold_exactly_full() {
+        let hold = CargoHold { capacity: 3 };
+        assert!(!has_cargo_space(Some(&hold), 3));
+    }
+
+    #[test]
+    fn test_cargo_hold_one_below_full() {
+        let hold = CargoHold { capacity: 3 };
+        assert!(has_cargo_space(Some(&hold), 2));
+    }
+
+    #[test]
+    fn test_missing_cargo_hold_is_unlimited() {
+        assert!(has_cargo_space(None, 10_000));
+    }
+
+    #[test]
+    fn test_first_item_is_created() {
+        assert_eq!(
+            plan_inventory_add(&[], &resource("tasty", 5)),
+            InventoryAdd::New(resource("tasty", 5))
+        );
+    }
+
+    #[test]
+    fn test_identical_resources_merge_into_stack() {
+        let inventory = vec![
+            (
+                "decs.components.the_void.player1.inventory.item1".to_string(),
+                resource("spendy", 2),
+            ),
+            (
+                "decs.components.the_void.player1.inventory.item2".to_string(),
+                resource("tasty", 4),
+            ),
+        ];
+        assert_eq!(
+            plan_inventory_add(&inventory, &resource("tasty", 7)),
+            InventoryAdd::Stack(
+                "decs.components.the_void.player1.inventory.item2".to_string(),
+                resource("tasty", 11)
+            )
+        );
+    }
+
+    #[test]
+    fn test_different_resources_do_not_merge() {
+        let inventory = vec![(
+            "decs.components.the_void.player1.inventory.item1".to_string(),
+            resource("spendy", 2),
+        )];
+        assert_eq!(
+            plan_inventory_add(&inventory, &resource("critical", 1)),
+            InventoryAdd::New(resource("critical", 1))
+        );
+
+        // A rare resource is worth more than a common one and gets its own stack
+        let rare = InventoryItem::from(&MiningResource {
+            stack_type: "spendy".to_string(),
+            qty: 1,
+            rarity: Rarity::Rare,
+            base_value: None,
+        });
+        assert_eq!(
+            plan_inventory_add(&inventory, &rare),
+            InventoryAdd::New(rare.clone())
+        );
+    }
+}
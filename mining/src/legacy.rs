@@ -0,0 +1,116 @@
+//! # Legacy Extractors
+//!
+//! Entities used to hold a single `extractor` component rather than an `extractors` collection.
+//! For one release, the extractor migration system awaits frames for entities that still have
+//! one and moves it into the entity's `extractors` collection, where the mining system picks it
+//! up on its next frame.
+use crate::capabilities::*;
+use crate::mining::extractor_delete_message;
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::migration::migrate_component;
+
+pub(crate) fn handle_frame(ctx: &impl Capabilities, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let extractor_value = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard,
+        frame.entity_id,
+        super::EXTRACTOR
+    ))?;
+    if let Some(extractor_str) = extractor_value {
+        let extractor = migrate_component(&extractor_str)?.into_inner();
+        ctx.log(&format!(
+            "Moving the extractor of entity {} into its extractors collection",
+            frame.entity_id
+        ));
+        for (subject, payload) in plan_migration(&extractor, &frame.shard, &frame.entity_id) {
+            ctx.msg()
+                .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// Produces the messages (subject and payload) that move a legacy extractor: the addition to
+/// the entity's `extractors` collection followed by the deletion of the `extractor` component
+fn plan_migration(
+    extractor: &MiningExtractor,
+    shard: &str,
+    entity_id: &str,
+) -> Vec<(String, Value)> {
+    let legacy_rid = format!(
+        "decs.components.{}.{}.{}",
+        shard,
+        entity_id,
+        super::EXTRACTOR
+    );
+    vec![
+        (
+            format!(
+                "call.decs.components.{}.{}.{}.new",
+                shard,
+                entity_id,
+                super::EXTRACTORS
+            ),
+            json!({ "params": extractor }),
+        ),
+        extractor_delete_message(&legacy_rid),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    #[test]
+    fn test_legacy_extractor_moves_into_collection() {
+        let ctx = MockCapabilitiesContext::new().with_value(
+            "decs:components:the_void:player1:extractor",
+            json!({
+                "target": "decs.components.the_void.asteroid1.mining_resource",
+                "remaining_ms": 900.0,
+                "total_ms": 1000.0
+            }),
+        );
+        let msg = BrokerMessage {
+            subject: "decs.frames.the_void.extractor_migration".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 12,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "extractor_migration",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        };
+
+        handle_frame(&ctx, msg).unwrap();
+
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "call.decs.components.the_void.player1.extractors.new",
+                "call.decs.components.the_void.player1.extractor.delete",
+            ]
+        );
+        let added = ctx
+            .payload("call.decs.components.the_void.player1.extractors.new")
+            .unwrap();
+        assert_eq!(added["params"]["remaining_ms"], 900.0);
+        let deleted = ctx
+            .payload("call.decs.components.the_void.player1.extractor.delete")
+            .unwrap();
+        assert_eq!(
+            deleted["params"]["rid"],
+            "decs.components.the_void.player1.extractor"
+        );
+    }
+}
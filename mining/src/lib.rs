@@ -12,20 +12,48 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[macro_use]
+extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 extern crate decscloud_common as decs;
 extern crate waxosuit_guest as guest;
+#[macro_use]
+extern crate lazy_static;
 
 use decs::systemmgr::*;
 use guest::prelude::*;
+use router::HandlerRegistry;
+use stacktrader_types::metrics;
 
 call_handler!(handle_call);
 
 const NO_MESSAGE: &str = "(no message)";
 const EXTRACTOR: &str = "extractor";
+const EXTRACTORS: &str = "extractors";
 const INVENTORY: &str = "inventory";
+const CARGO_HOLD: &str = "cargo_hold";
+const POSITION: &str = "position";
+const MINING_RESOURCE: &str = "mining_resource";
+const MINING_RESOURCES: &str = "mining_resources";
+const MINING_EFFICIENCY: &str = "mining_efficiency";
+const MINING_UPGRADE: &str = "mining_upgrade";
+const MINING_STATS: &str = "mining_stats";
+const AUTO_MINER: &str = "auto_miner";
 const SYSTEM_NAME: &str = "mining";
+const LOCK_SYSTEM_NAME: &str = "mining_lock";
+const DEPOSIT_LOCK_SYSTEM_NAME: &str = "deposit_lock";
+const MIGRATION_SYSTEM_NAME: &str = "extractor_migration";
+const AUTO_MINER_SYSTEM_NAME: &str = "auto_miner";
+const CLEANUP_SYSTEM_NAME: &str = "cleanup";
+const MINING_LOCK: &str = "mining_lock";
+const MINING_LOCKS: &str = "mining_locks";
+const RESPAWN_SYSTEM_NAME: &str = "asteroid_respawn";
+const SCHEDULER_SYSTEM_NAME: &str = "scheduler";
+const SCHEDULER: &str = "scheduler";
+const ASTEROID_RESPAWN: &str = "asteroid_respawn";
+const RESOURCE_RESPAWN: &str = "resource_respawn";
+const SURVIVAL_TIMER: &str = "survival_timer";
 const REGISTRY_SUBJECT: &str = "decs.system.registry";
 const FRAMERATE: u32 = 1;
 
@@ -37,7 +65,7 @@ pub fn handle_call(ctx: &CapabilitiesContext, operation: &str, msg: &[u8]) -> Ca
     }
 }
 
-/// Routes message either to the `handle_ping` function for registry pings or `handle_frame` for position updates
+/// Routes messages by subject, see `routes`
 fn handle_message(
     ctx: &CapabilitiesContext,
     msg: impl Into<messaging::DeliverMessage>,
@@ -50,32 +78,214 @@ fn handle_message(
         "Received message from broker on subject '{}'",
         subject
     ));
-    match subject.as_ref() {
-        NO_MESSAGE => Err("No message".into()),
-        REGISTRY_SUBJECT => handle_ping(ctx, msg.unwrap()),
-        _ => mining::handle_frame(ctx, msg.unwrap()),
+    match msg {
+        Some(msg) => routes().dispatch(ctx, msg),
+        None => Err("No message".into()),
     }
 }
 
-/// Receives messages on the subject `system.registry` and replies with physics system metadata
+/// The handlers of the actor's subjects: `handle_ping` for registry pings, `pause::handle_call`
+/// for pausing and resuming extractions, `retarget::handle_call` for pointing an extractor at a
+/// new target, `rig::handle_apply_upgrade` for applying mining rig upgrades,
+/// `audit::handle_log_query` for reading the extraction audit log, `event_log::handle_replay` for
+/// replaying an entity's logged component changes, `scheduler::handle_schedule_event` for
+/// scheduling events, `checkpoint::handle_call` for saving and restoring checkpoints,
+/// `buffs::handle_buff_applied` for timed buffs, `handle_extractor_change` for new extractors,
+/// `cleanup::handle_component_change` for keeping track of the components of each entity, and
+/// the frame handlers of each of the actor's systems, ending with `mining::handle_frame` for
+/// extractions
+fn routes() -> HandlerRegistry<CapabilitiesContext> {
+    let registry = HandlerRegistry::new()
+        .register(REGISTRY_SUBJECT, handle_ping)
+        .register("call.decs.*.*.mining.pause", |ctx, msg| {
+            pause::handle_call(ctx, msg)
+        })
+        .register("call.decs.*.*.mining.resume", |ctx, msg| {
+            pause::handle_call(ctx, msg)
+        })
+        .register("call.decs.*.*.mining.retarget", |ctx, msg| {
+            retarget::handle_call(ctx, msg)
+        })
+        .register("call.decs.*.*.mining.apply_upgrade", |ctx, msg| {
+            rig::handle_apply_upgrade(ctx, msg)
+        })
+        .register("call.decs.*.mining.log", |ctx, msg| {
+            audit::handle_log_query(ctx, msg)
+        })
+        .register("call.decs.*.event_log.replay", |ctx, msg| {
+            event_log::handle_replay(ctx, msg)
+        })
+        .register("call.decs.*.scheduler.schedule", |ctx, msg| {
+            scheduler::handle_schedule_event(ctx, msg)
+        })
+        .register("call.decs.*.checkpoint.save", |ctx, msg| {
+            checkpoint::handle_call(ctx, msg)
+        })
+        .register("call.decs.*.checkpoint.restore", |ctx, msg| {
+            checkpoint::handle_call(ctx, msg)
+        })
+        .register("event.decs.*.*.buff.applied", |ctx, msg| {
+            buffs::handle_buff_applied(ctx, msg)
+        })
+        .register(
+            "event.decs.components.*.*.extractors.*.change",
+            |ctx, msg| {
+                cleanup::note_component_change(ctx, &msg.subject)?;
+                duration::handle_extractor_change(ctx, msg)
+            },
+        );
+    cleanup::TRACKED_CHANGES
+        .iter()
+        .fold(registry, |registry, pattern| {
+            registry.register(pattern, |ctx, msg| {
+                cleanup::handle_component_change(ctx, msg)
+            })
+        })
+        .register("decs.frames.*.mining_lock", |ctx, msg| {
+            timed_frame(ctx, msg, lock::handle_frame)
+        })
+        .register("decs.frames.*.deposit_lock", |ctx, msg| {
+            timed_frame(ctx, msg, lock::handle_deposit_frame)
+        })
+        .register("decs.frames.*.asteroid_respawn", |ctx, msg| {
+            timed_frame(ctx, msg, respawn::handle_frame)
+        })
+        .register("decs.frames.*.extractor_migration", |ctx, msg| {
+            timed_frame(ctx, msg, legacy::handle_frame)
+        })
+        .register("decs.frames.*.scheduler", |ctx, msg| {
+            timed_frame(ctx, msg, scheduler::handle_scheduled_frame)
+        })
+        .register("decs.frames.*.auto_miner", |ctx, msg| {
+            timed_frame(ctx, msg, auto::handle_frame)
+        })
+        .register("decs.frames.*.cleanup", |ctx, msg| {
+            timed_frame(ctx, msg, cleanup::handle_frame)
+        })
+        .register("decs.frames.*.mining", |ctx, msg| {
+            timed_frame(ctx, msg, mining::handle_frame)
+        })
+}
+
+/// Runs a frame handler, publishing how long the frame took to process and storing a summary
+/// of the system's recent frame times every 100 frames, see `metrics::timed_frame`
+fn timed_frame(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+    handler: fn(&CapabilitiesContext, messaging::BrokerMessage) -> CallResult,
+) -> CallResult {
+    let body = msg.body.clone();
+    let subject = msg.subject.clone();
+    metrics::timed_frame(
+        &metrics::SystemClock,
+        metrics::frame_system(&subject),
+        &body,
+        || handler(ctx, msg),
+        |subject, payload| ctx.msg().publish(subject, None, payload),
+        |key, value| ctx.kv().set(key, value, None),
+        |msg| ctx.log(msg),
+    )
+}
+
+/// Receives messages on the subject `system.registry` and replies with the metadata of
+/// the mining, mining lock, deposit lock, asteroid respawn, extractor migration, scheduler, auto
+/// miner and cleanup systems
 fn handle_ping(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
-    let payload = System {
-        name: SYSTEM_NAME.to_string(),
-        framerate: FRAMERATE,
-        components: vec![EXTRACTOR.to_string()],
-    };
+    let payloads = vec![
+        System {
+            name: SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![EXTRACTORS.to_string()],
+        },
+        System {
+            name: LOCK_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![MINING_LOCK.to_string()],
+        },
+        System {
+            name: DEPOSIT_LOCK_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![MINING_RESOURCES.to_string()],
+        },
+        System {
+            name: RESPAWN_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![ASTEROID_RESPAWN.to_string()],
+        },
+        System {
+            name: MIGRATION_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![EXTRACTOR.to_string()],
+        },
+        System {
+            name: SCHEDULER_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![SCHEDULER.to_string()],
+        },
+        System {
+            name: AUTO_MINER_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![AUTO_MINER.to_string()],
+        },
+        System {
+            name: CLEANUP_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![SURVIVAL_TIMER.to_string()],
+        },
+    ];
     let reply_to = if msg.reply_to.is_empty() {
         format!("{}.replies", REGISTRY_SUBJECT)
     } else {
         msg.reply_to
     };
-    if let Err(e) = ctx
-        .msg()
-        .publish(&reply_to, None, &serde_json::to_vec(&payload)?)
-    {
-        return Err(format!("Error publishing message: {}", e).into());
-    };
+    for payload in payloads {
+        if let Err(e) = ctx
+            .msg()
+            .publish(&reply_to, None, &serde_json::to_vec(&payload)?)
+        {
+            return Err(format!("Error publishing message: {}", e).into());
+        };
+    }
     Ok(vec![])
 }
 
+mod audit;
+mod auto;
+mod auto_target;
+mod batch;
+mod buffs;
+mod cache;
+mod capabilities;
+mod checkpoint;
+mod cleanup;
+mod delivery;
+mod diff;
+mod duration;
+mod elapsed;
+mod energy;
+mod event_log;
+mod feed;
+mod fuel;
+mod guard;
+mod heat;
+mod inventory;
+mod legacy;
+mod lock;
 mod mining;
+mod pause;
+mod pending;
+mod queue;
+mod range;
+mod respawn;
+mod retarget;
+mod rig;
+mod router;
+mod scarcity;
+mod scheduler;
+mod speed;
+mod stats;
+mod telemetry;
+#[cfg(test)]
+mod test_utils;
+mod transponder;
+mod variance;
@@ -0,0 +1,411 @@
+//! # Mining Lock
+//!
+//! The mining lock system awaits frames for entities that have a `mining_lock` component. Locks
+//! are created by the client without a timestamp, so the first time a lock is seen it is stamped
+//! with the current game time. Once a lock has outlived its `expires_after_ms` it is deleted, an
+//! event is published on `event.decs.{shard}.{entity}.mining.lock_expired`, and the extractor
+//! holding the lock is deleted if it still extracts what the lock guards. An extraction that is
+//! paused, by the player, an empty fuel tank or its own heat, makes no progress, so its lock is
+//! stamped again instead of expiring under it.
+//!
+//! Each deposit in an asteroid's `mining_resources` collection has a lock of its own, an item
+//! of the asteroid's `mining_locks` collection named after the deposit, so different entities
+//! can mine different deposits of one asteroid at the same time. The deposit lock system awaits
+//! frames for entities with a `mining_resources` collection and maintains the lock of each of
+//! their deposits the same way.
+use crate::capabilities::*;
+use crate::mining::{deposit_collection, extractor_delete_message, lock_release_message};
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::migration::migrate_component;
+use trader::rid::{component_rid, parse_component_rid};
+
+const LOCK_MISSING: &str = "lock_missing";
+const LOCK_NOT_OWNED: &str = "lock_not_owned";
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+    let now_ms = trader::clock::frame_time_ms(&frame, super::FRAMERATE);
+
+    let lock_rid = component_rid(&frame.shard, &frame.entity_id, super::MINING_LOCK);
+    sweep_lock(ctx, &frame.shard, &frame.entity_id, &lock_rid, now_ms)
+}
+
+/// Maintains the lock of every deposit in the entity's `mining_resources` collection
+pub(crate) fn handle_deposit_frame(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+    let now_ms = trader::clock::frame_time_ms(&frame, super::FRAMERATE);
+
+    let deposits = ctx.kv().list_range(
+        &format!(
+            "decs:components:{}:{}:{}",
+            frame.shard,
+            frame.entity_id,
+            super::MINING_RESOURCES
+        ),
+        0,
+        -1,
+    )?;
+    for deposit in deposits {
+        let lock_rid = lock_rid(&frame.shard, &frame.entity_id, &deposit);
+        sweep_lock(ctx, &frame.shard, &frame.entity_id, &lock_rid, now_ms)?;
+    }
+
+    Ok(vec![])
+}
+
+/// Stamps, expires or leaves alone the lock stored under the rid, if there is one
+fn sweep_lock(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    lock_rid: &str,
+    now_ms: u64,
+) -> CallResult {
+    if let Some(lock) = get_lock(ctx, lock_rid)? {
+        let holder = lock_holder(ctx, &lock, lock_rid)?;
+        for (subject, payload) in plan_lock_sweep(shard, entity_id, lock_rid, &lock, holder, now_ms)
+        {
+            ctx.msg()
+                .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// The fully-qualified ID of the lock guarding the target on the asteroid: the deposit's item
+/// in the asteroid's `mining_locks` collection for a deposit of its `mining_resources`, e.g.
+/// `decs.components.{shard}.{asteroid}.mining_locks.{deposit}`, and the asteroid's
+/// `mining_lock` component for anything else
+pub(crate) fn lock_rid(shard: &str, asteroid_entity_id: &str, target: &str) -> String {
+    match deposit_collection(target) {
+        Some(collection) => format!(
+            "{}.{}",
+            component_rid(shard, asteroid_entity_id, super::MINING_LOCKS),
+            &target[collection.len() + 1..]
+        ),
+        None => component_rid(shard, asteroid_entity_id, super::MINING_LOCK),
+    }
+}
+
+/// Retrieve the mining lock stored under the lock rid, if there is one
+pub(crate) fn get_lock(
+    ctx: &impl Capabilities,
+    lock_rid: &str,
+) -> std::result::Result<Option<MiningLock>, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&lock_rid.replace('.', ":"))? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+/// Checks that the entity extracting a resource holds the lock on it. Returns the reason the
+/// extraction must be aborted otherwise
+pub(crate) fn verify_lock_owner(
+    lock: Option<&MiningLock>,
+    entity_id: &str,
+) -> std::result::Result<(), &'static str> {
+    match lock {
+        None => Err(LOCK_MISSING),
+        Some(l) if l.owner_id() != Some(entity_id) => Err(LOCK_NOT_OWNED),
+        Some(_) => Ok(()),
+    }
+}
+
+/// What became of the extractor a lock was taken for
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum LockHolder {
+    /// Deleted, or extracting something the lock doesn't guard
+    Gone,
+    Extracting,
+    Paused,
+}
+
+/// Whether the extractor holding the lock still exists and is extracting what the lock guards
+fn lock_holder(
+    ctx: &CapabilitiesContext,
+    lock: &MiningLock,
+    lock_rid: &str,
+) -> std::result::Result<LockHolder, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&lock.extractor.replace('.', ":"))? {
+        Some(s) => {
+            let extractor: MiningExtractor = migrate_component(&s)?.into_inner();
+            Ok(
+                if target_lock(&extractor.target).as_deref() != Some(lock_rid) {
+                    LockHolder::Gone
+                } else if extractor.paused {
+                    LockHolder::Paused
+                } else {
+                    LockHolder::Extracting
+                },
+            )
+        }
+        None => Ok(LockHolder::Gone),
+    }
+}
+
+/// The rid of the lock guarding a fully qualified component ID such as
+/// `decs.components.{shard}.{entity}.mining_resource`
+fn target_lock(target: &str) -> Option<String> {
+    parse_component_rid(target)
+        .ok()
+        .map(|rid| lock_rid(rid.shard, rid.entity, target))
+}
+
+/// Produces the messages (subject and payload) needed to maintain a lock at the given game time:
+/// nothing for a live lock, a `set` stamping `created_ms` on a new lock or an expired one held by
+/// a paused extractor, or the cleanup sequence for any other expired one
+fn plan_lock_sweep(
+    shard: &str,
+    entity_id: &str,
+    lock_rid: &str,
+    lock: &MiningLock,
+    holder: LockHolder,
+    now_ms: u64,
+) -> Vec<(String, Value)> {
+    let paused_too_long = holder == LockHolder::Paused && lock.is_expired(now_ms);
+    if lock.created_ms.is_none() || paused_too_long {
+        let stamped = MiningLock {
+            created_ms: Some(now_ms),
+            ..lock.clone()
+        };
+        return vec![(
+            format!("call.{}.set", lock_rid),
+            json!({ "params": stamped }),
+        )];
+    }
+    if !lock.is_expired(now_ms) {
+        return vec![];
+    }
+
+    let mut publishes = vec![
+        lock_release_message(lock_rid),
+        (
+            format!("event.decs.{}.{}.mining.lock_expired", shard, entity_id),
+            json!({ "lock": lock_rid, "extractor": lock.extractor }),
+        ),
+    ];
+    if holder == LockHolder::Extracting {
+        publishes.push(extractor_delete_message(&lock.extractor));
+    }
+    publishes
+}
+
+#[cfg(test)]
+mod test {
+    use super::lock_rid;
+    use super::plan_lock_sweep;
+    use super::target_lock;
+    use super::verify_lock_owner;
+    use super::LockHolder;
+    use super::MiningLock;
+    use super::LOCK_MISSING;
+    use super::LOCK_NOT_OWNED;
+
+    const EXTRACTOR: &str = "decs.components.the_void.player1.extractor";
+    const LOCK_RID: &str = "decs.components.the_void.asteroid1.mining_lock";
+
+    fn lock(created_ms: Option<u64>) -> MiningLock {
+        MiningLock {
+            extractor: EXTRACTOR.to_string(),
+            owner: "player1".to_string(),
+            created_ms,
+            expires_after_ms: 10_000,
+        }
+    }
+
+    #[test]
+    fn test_new_lock_is_stamped() {
+        let publishes = plan_lock_sweep(
+            "the_void",
+            "asteroid1",
+            LOCK_RID,
+            &lock(None),
+            LockHolder::Extracting,
+            4_000,
+        );
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(
+            publishes[0].0,
+            "call.decs.components.the_void.asteroid1.mining_lock.set"
+        );
+        assert_eq!(publishes[0].1["params"]["created_ms"], 4_000);
+        assert_eq!(publishes[0].1["params"]["extractor"], EXTRACTOR);
+    }
+
+    #[test]
+    fn test_lock_not_expired() {
+        let l = lock(Some(4_000));
+        assert!(!l.is_expired(13_999));
+        assert!(plan_lock_sweep(
+            "the_void",
+            "asteroid1",
+            LOCK_RID,
+            &l,
+            LockHolder::Extracting,
+            13_999
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_lock_expired() {
+        let l = lock(Some(4_000));
+        assert!(l.is_expired(14_000));
+        assert!(!plan_lock_sweep(
+            "the_void",
+            "asteroid1",
+            LOCK_RID,
+            &l,
+            LockHolder::Extracting,
+            14_000
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_expired_lock_cleanup_sequence() {
+        let publishes = plan_lock_sweep(
+            "the_void",
+            "asteroid1",
+            LOCK_RID,
+            &lock(Some(0)),
+            LockHolder::Extracting,
+            60_000,
+        );
+        let subjects: Vec<&str> = publishes.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(
+            subjects,
+            vec![
+                "call.decs.components.the_void.asteroid1.mining_lock.delete",
+                "event.decs.the_void.asteroid1.mining.lock_expired",
+                "call.decs.components.the_void.player1.extractor.delete",
+            ]
+        );
+        assert_eq!(
+            publishes[0].1["params"]["rid"],
+            "decs.components.the_void.asteroid1.mining_lock"
+        );
+        assert_eq!(publishes[1].1["extractor"], EXTRACTOR);
+        assert_eq!(publishes[2].1["params"]["rid"], EXTRACTOR);
+    }
+
+    #[test]
+    fn test_expired_lock_leaves_unrelated_extractor() {
+        let publishes = plan_lock_sweep(
+            "the_void",
+            "asteroid1",
+            LOCK_RID,
+            &lock(Some(0)),
+            LockHolder::Gone,
+            60_000,
+        );
+        assert_eq!(publishes.len(), 2);
+    }
+
+    #[test]
+    fn test_lock_of_paused_extractor_is_stamped_again() {
+        let l = lock(Some(0));
+        assert!(plan_lock_sweep(
+            "the_void",
+            "asteroid1",
+            LOCK_RID,
+            &l,
+            LockHolder::Paused,
+            9_999
+        )
+        .is_empty());
+
+        // Paused for longer than the lock lasts, the extractor keeps it
+        let publishes = plan_lock_sweep(
+            "the_void",
+            "asteroid1",
+            LOCK_RID,
+            &l,
+            LockHolder::Paused,
+            60_000,
+        );
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(
+            publishes[0].0,
+            "call.decs.components.the_void.asteroid1.mining_lock.set"
+        );
+        assert_eq!(publishes[0].1["params"]["created_ms"], 60_000);
+    }
+
+    #[test]
+    fn test_expired_deposit_lock_is_deleted_through_its_collection() {
+        let deposit_lock = "decs.components.the_void.asteroid1.mining_locks.deposit1";
+        let publishes = plan_lock_sweep(
+            "the_void",
+            "asteroid1",
+            deposit_lock,
+            &lock(Some(0)),
+            LockHolder::Gone,
+            60_000,
+        );
+        assert_eq!(
+            publishes[0].0,
+            "call.decs.components.the_void.asteroid1.mining_locks.delete"
+        );
+        assert_eq!(publishes[0].1["params"]["rid"], deposit_lock);
+        assert_eq!(publishes[1].1["lock"], deposit_lock);
+    }
+
+    #[test]
+    fn test_each_deposit_has_its_own_lock() {
+        assert_eq!(
+            lock_rid(
+                "the_void",
+                "asteroid1",
+                "decs.components.the_void.asteroid1.mining_resources.deposit1"
+            ),
+            "decs.components.the_void.asteroid1.mining_locks.deposit1"
+        );
+        assert_eq!(
+            lock_rid(
+                "the_void",
+                "asteroid1",
+                "decs.components.the_void.asteroid1.mining_resources.deposit2"
+            ),
+            "decs.components.the_void.asteroid1.mining_locks.deposit2"
+        );
+        // A lone resource is guarded by the asteroid's lock
+        assert_eq!(
+            lock_rid(
+                "the_void",
+                "asteroid1",
+                "decs.components.the_void.asteroid1.mining_resource"
+            ),
+            LOCK_RID
+        );
+    }
+
+    #[test]
+    fn test_target_lock() {
+        assert_eq!(
+            target_lock("decs.components.the_void.asteroid1.mining_resource").as_deref(),
+            Some(LOCK_RID)
+        );
+        assert_eq!(target_lock("asteroid1"), None);
+    }
+
+    #[test]
+    fn test_only_lock_owner_may_extract() {
+        // Two extractors racing for the same asteroid, only the lock owner gets the resource
+        let l = lock(Some(0));
+        assert_eq!(verify_lock_owner(Some(&l), "player1"), Ok(()));
+        assert_eq!(verify_lock_owner(Some(&l), "player2"), Err(LOCK_NOT_OWNED));
+    }
+
+    #[test]
+    fn test_missing_lock_aborts_extraction() {
+        assert_eq!(verify_lock_owner(None, "player1"), Err(LOCK_MISSING));
+    }
+}
@@ -1,8 +1,47 @@
+use crate::audit::{log_extraction, ExtractionRecord};
+use crate::auto_target::{is_auto_target, resolve_target};
+use crate::batch::{batch_enabled, publish_batch};
+use crate::buffs::handle_buff_expiry_check;
+use crate::cache::{fetch_component, FrameCache, FRAME_CACHE_CAPACITY};
+use crate::capabilities::*;
+use crate::delivery::recipient;
+use crate::diff::DiffPublisher;
+use crate::elapsed::frame_elapsed_ms;
+use crate::energy::draw_frame_energy;
+use crate::feed::{publish_completed, publish_failure};
+use crate::fuel::{burn_fuel, get_fuel_tank, is_empty, pause_out_of_fuel, publish_fuel_tank};
+use crate::guard::{claim_completion, release_completion};
+use crate::heat::{integrate_heat, is_cooling, publish_cooled, publish_overheated};
+use crate::inventory::*;
+use crate::lock::{get_lock, lock_rid, verify_lock_owner};
+use crate::pending::{clear_pending, get_pending, save_pending, PendingDelivery};
+use crate::queue::{flush_and_report, MessageQueue};
+use crate::range::{get_mining_upgrade, target_in_range};
+use crate::respawn::schedule_respawn;
+use crate::scarcity::{deplete_supply, scarcity_yield};
+use crate::speed::{get_velocity, is_too_fast, warn_too_fast};
+use crate::stats::record_extraction;
+use crate::telemetry::{count, count_failure, publish_counters, COMPLETED};
+use crate::transponder::{deplete_transponder, transponder_depleted};
+use crate::variance::{apply_yield_multiplier, apply_yield_variance};
 use guest::prelude::*;
+use serde_json::Value;
 use stacktrader_types as trader;
+use trader::buffs::{buff_multiplier, StatTarget};
 use trader::components::*;
+use trader::migration::{migrate_component, Migrated};
+use trader::rid::{validate_entity_id, validate_shard, ResourceIdentifier};
+use trader::units::Millis;
+use trader::validation::Validate;
 
-const DEPLETED_COLOR: &str = "#A9A9A9";
+const CARGO_FULL: &str = "cargo_full";
+pub(crate) const RESOURCE_MISSING: &str = "resource_missing";
+const INVALID_TARGET: &str = "invalid_target";
+const CROSS_SHARD_TARGET: &str = "cross_shard_target";
+const CORRUPT_EXTRACTOR: &str = "corrupt_extractor";
+pub(crate) const OUT_OF_RANGE: &str = "out_of_range";
+pub(crate) const TARGET_DEPLETED: &str = "target_depleted";
+const FRAME_REJECTED_EVENT: &str = "event.decs.system.frame.rejected";
 
 /// Receives an entity, shard, elapsed time, etc from an EntityFrame
 /// published on decs.frames.{shard}.{system}, e.g. `decs.frames.the_void.physics`
@@ -10,167 +49,2021 @@ const DEPLETED_COLOR: &str = "#A9A9A9";
 /// on call.decs.components.{shard-id}.{entity-id}.{component-name}.set or appropriate
 /// collection add
 pub(crate) fn handle_frame(
-    ctx: &CapabilitiesContext,
+    ctx: &impl Capabilities,
     msg: guest::prelude::messaging::BrokerMessage,
 ) -> CallResult {
     let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+    // IDs that can't be embedded in subjects and keys never make it into one
+    if let Err(reason) =
+        validate_shard(&frame.shard).and_then(|_| validate_entity_id(&frame.entity_id))
+    {
+        return reject_frame(ctx, &frame, &reason);
+    }
+    handle_buff_expiry_check(ctx, &frame)?;
+    publish_counters(ctx, &frame)?;
+    trader::clock::observe(
+        &frame.shard,
+        trader::clock::frame_time_ms(&frame, super::FRAMERATE),
+    );
+    // Nothing changes in a frame in which no time passed
+    let elapsed_ms = frame_elapsed_ms(ctx, &frame)?;
+    if elapsed_ms == 0 {
+        return Ok(vec![]);
+    }
+    let frame = decs::systemmgr::EntityFrame {
+        elapsed_ms,
+        ..frame
+    };
 
-    let extractor_value = ctx.kv().get(&format!(
-        "decs:components:{}:{}:{}",
-        frame.shard,
-        frame.entity_id,
-        super::EXTRACTOR
-    ))?;
-    if let Some(extractor_str) = extractor_value {
-        // Either publish an update to the extractor (less time remaining)
-        // or delete the extractor and add the resource to the player's inventory
-        let extractor: MiningExtractor = serde_json::from_str(&extractor_str)?;
-        let extractor = update_extractor(extractor, frame.elapsed_ms);
-        if extractor.remaining_ms <= 0.0 {
-            extract_resource(ctx, &extractor, &frame.shard, &frame.entity_id)?;
-        } else {
-            publish_extractor(ctx, &extractor, &frame.shard, &frame.entity_id)?;
+    let extractor_rids = get_extractor_rids(ctx, &frame.shard, &frame.entity_id)?;
+    if extractor_rids.is_empty()
+        || !draw_frame_energy(
+            ctx,
+            &frame.shard,
+            &frame.entity_id,
+            super::SYSTEM_NAME,
+            frame.elapsed_ms,
+        )?
+    {
+        return Ok(vec![]);
+    }
+    let velocity = get_velocity(ctx, &frame.shard, &frame.entity_id)?;
+    let tank = get_fuel_tank(ctx, &frame.shard, &frame.entity_id)?;
+    // Each of the entity's extractors progresses and completes independently of the others,
+    // all of them burning fuel from the same tank
+    let mut remaining_fuel = tank.clone();
+    let mut cache = FrameCache::new(FRAME_CACHE_CAPACITY);
+    for extractor_rid in extractor_rids {
+        if let Some(extractor_str) =
+            fetch_component(ctx, &mut cache, &extractor_rid.replace('.', ":"))?
+        {
+            remaining_fuel = advance_extractor(
+                ctx,
+                &mut cache,
+                &frame,
+                &extractor_rid,
+                &extractor_str,
+                velocity.as_ref(),
+                remaining_fuel,
+            )?;
         }
     }
+    if let (Some(tank), Some(remaining_fuel)) = (tank, remaining_fuel) {
+        publish_fuel_tank(ctx, &tank, &remaining_fuel, &frame.shard, &frame.entity_id)?;
+    }
 
     Ok(vec![])
 }
 
-fn publish_extractor(
-    ctx: &CapabilitiesContext,
-    extractor: &MiningExtractor,
+/// Publishes the reason the frame is ignored on `event.decs.system.frame.rejected`
+fn reject_frame(
+    ctx: &impl Capabilities,
+    frame: &decs::systemmgr::EntityFrame,
+    reason: &str,
+) -> CallResult {
+    ctx.log(&format!("Rejecting frame: {}", reason));
+    ctx.msg().publish(
+        FRAME_REJECTED_EVENT,
+        None,
+        &serde_json::to_vec(&json!({
+            "system": super::SYSTEM_NAME,
+            "shard": frame.shard,
+            "entity_id": frame.entity_id,
+            "reason": reason
+        }))?,
+    )?;
+    Ok(vec![])
+}
+
+/// Retrieve the fully-qualified RIDs of all extractors in the entity's `extractors` collection
+pub(crate) fn get_extractor_rids(
+    ctx: &impl Capabilities,
     shard: &str,
     entity_id: &str,
+) -> Result<Vec<String>> {
+    ctx.kv().list_range(
+        &format!(
+            "decs:components:{}:{}:{}",
+            shard,
+            entity_id,
+            super::EXTRACTORS
+        ),
+        0,
+        -1,
+    )
+}
+
+/// Either publishes an update to the extractor (less time remaining) or deletes the extractor
+/// and adds the resource to the player's inventory. Returns what is left in the fuel tank
+fn advance_extractor(
+    ctx: &impl Capabilities,
+    cache: &mut FrameCache,
+    frame: &decs::systemmgr::EntityFrame,
+    extractor_rid: &str,
+    extractor_str: &str,
+    velocity: Option<&Velocity>,
+    tank: Option<FuelTank>,
+) -> std::result::Result<Option<FuelTank>, Box<dyn std::error::Error>> {
+    let (shard, entity_id) = (frame.shard.as_str(), frame.entity_id.as_str());
+    let stored: MiningExtractor = match migrate_component(extractor_str)? {
+        Migrated::Current(extractor) => extractor,
+        Migrated::Upgraded(extractor) => {
+            // Persist the upgrade so later frames read the current schema. An extractor
+            // with no time left has no valid total and is completed this frame anyway
+            if extractor.remaining_ms > Millis::ZERO {
+                publish_extractor(ctx, &extractor, extractor_rid)?;
+            }
+            extractor
+        }
+    };
+    // Negative numbers in a stored extractor are zeroed, an extractor holding numbers that can't
+    // be made sense of is abandoned rather than ticking forever
+    let extractor = match stored.sanitize() {
+        Ok(extractor) => extractor,
+        Err(e) => {
+            ctx.log(&format!(
+                "Abandoning extraction for entity {}: {}",
+                entity_id, e
+            ));
+            abandon_extraction(
+                ctx,
+                &stored,
+                extractor_rid,
+                shard,
+                entity_id,
+                CORRUPT_EXTRACTOR,
+            )?;
+            return Ok(tank);
+        }
+    };
+    // An extractor created without a target gets one chosen on its first frame
+    if is_auto_target(&extractor) {
+        resolve_target(ctx, cache, &extractor, extractor_rid, shard, entity_id)?;
+        return Ok(tank);
+    }
+    // A paused extraction neither progresses nor completes until it is resumed, it only cools
+    // down. One that paused itself by overheating resumes once it has cooled off
+    if extractor.paused {
+        if is_cooling(&extractor) {
+            let cooled = update_extractor(extractor.clone(), frame.elapsed_ms, 0.0);
+            publish_cooled(ctx, &stored, &cooled, extractor_rid, shard, entity_id)?;
+        }
+        return Ok(tank);
+    }
+    // Neither does one whose miner is moving too fast, the HUD is told why
+    if let Some(velocity) = velocity.filter(|v| is_too_fast(Some(v), extractor.max_mining_speed()))
+    {
+        publish_extractor(ctx, &extractor, extractor_rid)?;
+        warn_too_fast(
+            ctx,
+            shard,
+            entity_id,
+            velocity,
+            extractor.max_mining_speed(),
+        )?;
+        return Ok(tank);
+    }
+    // A target that drifted out of reach of the beam can't be mined any further
+    let upgrade = get_mining_upgrade(ctx, cache, shard, entity_id)?;
+    if !target_in_range(ctx, cache, &extractor, shard, entity_id, &upgrade)? {
+        ctx.log(&format!(
+            "Abandoning extraction for entity {}: {} is out of range",
+            entity_id, extractor.target
+        ));
+        abandon_extraction(
+            ctx,
+            &extractor,
+            extractor_rid,
+            shard,
+            entity_id,
+            OUT_OF_RANGE,
+        )?;
+        return Ok(tank);
+    }
+    // Running the extractor costs fuel, an empty tank pauses the extraction
+    if tank.as_ref().is_some_and(is_empty) {
+        pause_out_of_fuel(ctx, &extractor, extractor_rid, shard, entity_id)?;
+        return Ok(tank);
+    }
+    let tank = match tank {
+        Some(tank) => Some(burn_fuel(ctx, &tank, shard, frame.elapsed_ms)?),
+        None => None,
+    };
+    let now_ms = trader::clock::frame_time_ms(frame, super::FRAMERATE);
+    let multiplier = efficiency_multiplier(ctx, cache, shard, entity_id)?
+        * upgrade.speed_multiplier
+        * buff_multiplier(shard, entity_id, StatTarget::MiningEfficiency, now_ms);
+    // Frames don't end when the extraction does, the frame time left over is reported with it
+    let overshoot_ms = (Millis::from(frame.elapsed_ms) * multiplier - extractor.remaining_ms)
+        .max(Millis::ZERO)
+        .0;
+    let extractor = update_extractor(extractor, frame.elapsed_ms, multiplier);
+    if extractor.remaining_ms <= Millis::ZERO {
+        extract_resource(
+            ctx,
+            cache,
+            &extractor,
+            extractor_rid,
+            shard,
+            entity_id,
+            now_ms,
+            overshoot_ms,
+        )?;
+    } else if extractor.overheated {
+        publish_overheated(ctx, &stored, &extractor, extractor_rid, shard, entity_id)?;
+    } else if tank.as_ref().is_some_and(is_empty) {
+        pause_out_of_fuel(ctx, &extractor, extractor_rid, shard, entity_id)?;
+    } else {
+        publish_extractor_diff(ctx, &stored, &extractor, extractor_rid)?;
+    }
+    Ok(tank)
+}
+
+pub(crate) fn publish_extractor(
+    ctx: &impl Capabilities,
+    extractor: &MiningExtractor,
+    extractor_rid: &str,
 ) -> CallResult {
-    let subject = format!(
-        "call.decs.components.{}.{}.{}.set",
-        shard,
-        entity_id,
-        super::EXTRACTOR
-    );
-    let payload = json!({ "params": extractor });
+    let subject = format!("call.{}.set", extractor_rid);
+    let payload = json!({ "params": extractor_params(extractor)? });
     ctx.msg()
         .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
     Ok(vec![])
 }
 
-fn update_extractor(extractor: MiningExtractor, elapsed_ms: u32) -> MiningExtractor {
-    let mut remaining = extractor.remaining_ms - f64::from(elapsed_ms);
-    if remaining <= 0.0 {
-        remaining = 0.0;
+/// Publishes the fields of the extractor that changed since it was stored, see `diff`
+pub(crate) fn publish_extractor_diff(
+    ctx: &impl Capabilities,
+    stored: &MiningExtractor,
+    extractor: &MiningExtractor,
+    extractor_rid: &str,
+) -> CallResult {
+    DiffPublisher::new(stored, extractor)
+        .with_params(extractor_params)
+        .publish(ctx, &format!("call.{}.set", extractor_rid))?;
+    Ok(vec![])
+}
+
+/// The published extractor carries its progress so the client HUD doesn't have to
+/// remember the original duration. An extractor that fails validation is never published
+pub(crate) fn extractor_params(extractor: &MiningExtractor) -> Result<serde_json::Value> {
+    extractor.validate()?;
+    let mut params = serde_json::to_value(extractor)?;
+    params["percent_complete"] = json!(extractor.percent_complete());
+    Ok(params)
+}
+
+/// Retrieve the rate at which the entity's extractions progress from its `mining_efficiency`
+/// component. Entities without one, or with an unusable multiplier, mine at the normal rate
+fn efficiency_multiplier(
+    ctx: &impl Capabilities,
+    cache: &mut FrameCache,
+    shard: &str,
+    entity_id: &str,
+) -> Result<f64> {
+    let efficiency_value = fetch_component(
+        ctx,
+        cache,
+        &format!(
+            "decs:components:{}:{}:{}",
+            shard,
+            entity_id,
+            super::MINING_EFFICIENCY
+        ),
+    )?;
+    let efficiency: MiningEfficiency = match efficiency_value {
+        Some(efficiency_str) => serde_json::from_str(&efficiency_str)?,
+        None => return Ok(1.0),
+    };
+    if efficiency.is_valid() {
+        Ok(efficiency.multiplier)
+    } else {
+        ctx.log(&format!(
+            "Ignoring mining efficiency multiplier {} of entity {}, mining at the normal rate",
+            efficiency.multiplier, entity_id
+        ));
+        Ok(1.0)
     }
-    MiningExtractor {
-        remaining_ms: remaining,
-        ..extractor
+}
+
+/// Subtracts the frame's elapsed time, scaled by the entity's efficiency and upgrade
+/// multipliers, from the time remaining and heats the extractor for the elapsed time. No time
+/// passes for a paused extractor, it only cools down
+fn update_extractor(
+    extractor: MiningExtractor,
+    elapsed_ms: u32,
+    multiplier: f64,
+) -> MiningExtractor {
+    if extractor.paused {
+        return integrate_heat(extractor, elapsed_ms);
     }
+    let remaining =
+        (extractor.remaining_ms - Millis::from(elapsed_ms) * multiplier).max(Millis::ZERO);
+    integrate_heat(
+        MiningExtractor {
+            remaining_ms: remaining,
+            ..extractor
+        },
+        elapsed_ms,
+    )
 }
 
+#[allow(clippy::too_many_arguments)]
 fn extract_resource(
-    ctx: &CapabilitiesContext,
+    ctx: &impl Capabilities,
+    cache: &mut FrameCache,
     extractor: &MiningExtractor,
+    extractor_rid: &str,
     shard: &str,
     entity_id: &str,
+    now_ms: u64,
+    overshoot_ms: f64,
 ) -> CallResult {
-    let resource_value = ctx.kv().get(&extractor.target.replace(".", ":"))?;
+    // An extractor whose target can't be read is cleaned up rather than failing again on
+    // every frame from now on
+    let target = match parse_target(&extractor.target, shard) {
+        Ok(target) => target,
+        Err((reason, message)) => {
+            ctx.log(&format!(
+                "Abandoning extraction for entity {}: {}",
+                entity_id, message
+            ));
+            return abandon_extraction(ctx, extractor, extractor_rid, shard, entity_id, reason);
+        }
+    };
+    let asteroid_entity_id = target.entity();
+    // A deposit taken by an earlier frame whose resource never reached the inventory is
+    // delivered before anything else. The retry is claimed like a completion, or two frames
+    // seeing the same pending delivery would both deliver it
+    if let Some(pending) = get_pending(ctx, shard, entity_id, &extractor.target)? {
+        if !claim_completion(ctx, shard, entity_id, &extractor.target)? {
+            return Ok(vec![]);
+        }
+        return deliver(
+            ctx,
+            extractor,
+            extractor_rid,
+            shard,
+            entity_id,
+            asteroid_entity_id,
+            &pending,
+        );
+    }
+    // A depleted asteroid's resource may linger briefly, but it can't be mined any more
+    if transponder_depleted(ctx, shard, asteroid_entity_id)? {
+        ctx.log(&format!(
+            "Abandoning extraction for entity {}: {} is depleted",
+            entity_id, extractor.target
+        ));
+        return abandon_extraction(
+            ctx,
+            extractor,
+            extractor_rid,
+            shard,
+            entity_id,
+            TARGET_DEPLETED,
+        );
+    }
+    let resource_value = fetch_component(ctx, cache, &target.to_key())?;
     if let Some(resource_str) = resource_value {
-        // This works because the frame's entity and shard are that of the
-        // "owner" of the extractor component
-        let player_inventory = format!(
-            "decs.components.{}.{}.{}",
+        // A frame racing another frame that already completed this extraction does nothing
+        if !claim_completion(ctx, shard, entity_id, &extractor.target)? {
+            return Ok(vec![]);
+        }
+        // Until the deposit is taken, a failure leaves nothing done and the next frame must be
+        // able to claim the completion again
+        let taken = take_deposit(
+            ctx,
+            cache,
+            extractor,
+            extractor_rid,
             shard,
             entity_id,
-            super::INVENTORY
-        );
-        let inv_subject = format!("call.{}.new", player_inventory);
-        let mining_resource: MiningResource = serde_json::from_str(&resource_str)?;
-        let add_payload = json!({ "params": mining_resource });
-        // Take the resource item as-is from the mining resource and add to player inventory
-        ctx.msg()
-            .publish(&inv_subject, None, &serde_json::to_vec(&add_payload)?)?;
-        // The extractor target must always be the fully qualified ID of the mining_resource component
-        let del_subject = format!("call.{}.delete", extractor.target);
-        let params = json!({
-            "params": {
-                "rid": extractor.target
+            asteroid_entity_id,
+            &resource_str,
+            now_ms,
+            overshoot_ms,
+        );
+        match taken {
+            Ok(DepositTaken::Nothing) => Ok(vec![]),
+            Ok(DepositTaken::Batched(pending)) => deliver_batch(
+                ctx,
+                extractor,
+                extractor_rid,
+                shard,
+                entity_id,
+                asteroid_entity_id,
+                &pending,
+            ),
+            Ok(DepositTaken::Deleted(pending)) => deliver(
+                ctx,
+                extractor,
+                extractor_rid,
+                shard,
+                entity_id,
+                asteroid_entity_id,
+                &pending,
+            ),
+            Err(e) => {
+                release_completion(ctx, shard, entity_id, &extractor.target)?;
+                Err(e)
             }
-        });
-        // Delete the extractor target component
-        ctx.msg()
-            .publish(&del_subject, None, &serde_json::to_vec(&params)?)?;
+        }
+    } else {
+        abandon_extraction(
+            ctx,
+            extractor,
+            extractor_rid,
+            shard,
+            entity_id,
+            RESOURCE_MISSING,
+        )
+    }
+}
 
-        // Delete the extractor component
-        let del_extractor_subject = format!(
-            "call.decs.components.{}.{}.extractor.delete",
-            shard, entity_id
-        );
-        ctx.msg().publish(
-            &del_extractor_subject,
-            None,
-            &serde_json::to_vec(&json!({
-                "params": {
-                    "rid": format!("decs.components.{}.{}.extractor", shard, entity_id)
-                }
-            }))?,
-        )?;
+/// What a claimed completion did with the deposit
+enum DepositTaken {
+    /// The extraction failed and the extractor is gone, there is nothing to deliver
+    Nothing,
+    /// The deposit's deletion is left to the batch delivering the resource
+    Batched(PendingDelivery),
+    /// The deposit is deleted and the resource waits to be delivered
+    Deleted(PendingDelivery),
+}
 
-        let asteroid_entity_id = extractor.target.split('.').collect::<Vec<&str>>()[3];
+/// Takes the resource from the deposit of a claimed completion, recording the delivery it
+/// is owed before the deposit is deleted
+#[allow(clippy::too_many_arguments)]
+fn take_deposit(
+    ctx: &impl Capabilities,
+    cache: &mut FrameCache,
+    extractor: &MiningExtractor,
+    extractor_rid: &str,
+    shard: &str,
+    entity_id: &str,
+    asteroid_entity_id: &str,
+    resource_str: &str,
+    now_ms: u64,
+    overshoot_ms: f64,
+) -> std::result::Result<DepositTaken, Box<dyn std::error::Error>> {
+    // Only the holder of the deposit's lock may take the resource. Anyone else only
+    // gets their own extractor cleaned up, the lock and the resource stay as they are
+    let lock_rid = lock_rid(shard, asteroid_entity_id, &extractor.target);
+    let lock = get_lock(ctx, &lock_rid)?;
+    if let Err(reason) = verify_lock_owner(lock.as_ref(), entity_id) {
+        let mut queue = MessageQueue::new();
+        queue.push_message(mining_failed_message(extractor, shard, entity_id, reason))?;
+        queue.push_message(extractor_delete_message(extractor_rid))?;
+        flush_and_report(ctx, queue, shard, entity_id)?;
+        count_failure(ctx, shard, reason)?;
+        publish_failure(ctx, shard, entity_id, &extractor.target, reason)?;
+        return Ok(DepositTaken::Nothing);
+    }
 
-        // Delete lock component
-        let del_lock_subject = format!(
-            "call.decs.components.{}.{}.mining_lock.delete",
-            shard, asteroid_entity_id
-        );
+    let mining_resource: MiningResource = serde_json::from_str(resource_str)?;
+    // The deposit yields a varied quantity, raised by the miner's upgrade and the resource's
+    // scarcity, but respawns with its original one
+    let upgrade = get_mining_upgrade(ctx, cache, shard, entity_id)?;
+    let mined = apply_yield_multiplier(
+        apply_yield_variance(ctx, &mining_resource)?,
+        upgrade.yield_multiplier * scarcity_yield(ctx, shard, &mining_resource.stack_type)?,
+    );
+    ctx.log(&format!(
+        "Entity {} extracted {} of {} from {}",
+        entity_id, mined.qty, mining_resource.qty, extractor.target
+    ));
+
+    // This works because the frame's entity and shard are that of the
+    // "owner" of the extractor component, unless it delivers to another entity
+    let recipient = recipient(ctx, extractor, shard, entity_id)?;
+    let inventory_rids = get_inventory_rids(ctx, shard, &recipient)?;
+    let inventory = get_inventory_items(ctx, &inventory_rids);
+    let add = plan_inventory_add(&inventory, &InventoryItem::from(&mined));
+
+    // A full cargo hold fails the extraction but leaves the asteroid and its resource
+    // untouched so it can be mined again later. Merging into an existing stack needs no space
+    if let InventoryAdd::New(_) = add {
+        let hold = get_cargo_hold(ctx, shard, &recipient)?;
+        if !has_cargo_space(hold.as_ref(), inventory_rids.len()) {
+            let mut queue = MessageQueue::new();
+            queue.push_message(mining_failed_message(
+                extractor, shard, entity_id, CARGO_FULL,
+            ))?;
+            queue.push_message(extractor_delete_message(extractor_rid))?;
+            queue.push_message(lock_release_message(&lock_rid))?;
+            flush_and_report(ctx, queue, shard, entity_id)?;
+            count_failure(ctx, shard, CARGO_FULL)?;
+            publish_failure(ctx, shard, entity_id, &extractor.target, CARGO_FULL)?;
+            return Ok(DepositTaken::Nothing);
+        }
+    }
+
+    // The deposit is taken before the inventory is touched. If it can't be, nothing has
+    // changed and the next frame tries again
+    let pending = PendingDelivery {
+        inventory_add: inventory_add_message(shard, &recipient, &add),
+        mined,
+        resource: mining_resource,
+        mined_at_ms: now_ms,
+        overshoot_ms,
+    };
+    save_pending(ctx, shard, entity_id, &extractor.target, &pending)?;
+    if batch_enabled(ctx, shard)? {
+        return Ok(DepositTaken::Batched(pending));
+    }
+    let (subject, payload) = deposit_delete_message(&extractor.target);
+    if let Err(e) = ctx
+        .msg()
+        .publish(&subject, None, &serde_json::to_vec(&payload)?)
+    {
+        clear_pending(ctx, shard, entity_id, &extractor.target)?;
+        return Err(e);
+    }
+    mark_deposit_taken(ctx, shard, asteroid_entity_id, &extractor.target)?;
+    Ok(DepositTaken::Deleted(pending))
+}
+
+/// Adds a resource taken from its deposit to the inventory and, once it is there, deletes the
+/// extractor and the lock and depletes the asteroid if this was its last deposit. If the resource
+/// can't be added, the failure is published on `event.decs.{shard}.{entity}.mining.delivery_pending`
+/// and the pending delivery and the extractor are kept, and the claim released, for the next
+/// frame to try again
+fn deliver(
+    ctx: &impl Capabilities,
+    extractor: &MiningExtractor,
+    extractor_rid: &str,
+    shard: &str,
+    entity_id: &str,
+    asteroid_entity_id: &str,
+    pending: &PendingDelivery,
+) -> CallResult {
+    let (subject, payload) = &pending.inventory_add;
+    if let Err(e) = ctx
+        .msg()
+        .publish(subject, None, &serde_json::to_vec(payload)?)
+    {
+        ctx.log(&format!(
+            "Delivery from {} to entity {} is pending: {}",
+            extractor.target, entity_id, e
+        ));
+        release_completion(ctx, shard, entity_id, &extractor.target)?;
         ctx.msg().publish(
-            &del_lock_subject,
+            &format!("event.decs.{}.{}.mining.delivery_pending", shard, entity_id),
             None,
             &serde_json::to_vec(&json!({
-                "params": {
-                    "rid": format!("{}.mining_lock", extractor.target)
-                }
+                "target": extractor.target,
+                "stack_type": pending.mined.stack_type,
+                "qty": pending.mined.qty,
+                "error": e.to_string()
             }))?,
         )?;
+        return Ok(vec![]);
+    }
+    clear_pending(ctx, shard, entity_id, &extractor.target)?;
 
-        let old_tp = get_transponder(ctx, shard, asteroid_entity_id)?;
-        let new_tp = deplete_transponder(&old_tp);
+    // Every one of these is attempted even if an earlier one fails
+    let mut queue = MessageQueue::new();
+    for message in plan_completion(
+        extractor,
+        extractor_rid,
+        shard,
+        entity_id,
+        asteroid_entity_id,
+        &pending.mined,
+        pending.overshoot_ms,
+    ) {
+        queue.push_message(message)?;
+    }
+    flush_and_report(ctx, queue, shard, entity_id)?;
+    record_completion(
+        ctx,
+        extractor,
+        shard,
+        entity_id,
+        asteroid_entity_id,
+        pending,
+    )
+}
 
-        // Update the transponder to indicate the asteroid is empty
-        let update_asteroid_subject = format!(
-            "call.decs.components.{}.{}.transponder.set",
-            shard, asteroid_entity_id
-        );
-        ctx.msg().publish(
-            &update_asteroid_subject,
-            None,
-            &serde_json::to_vec(&json!({ "params": new_tp }))?,
-        )?;
+/// Takes the deposit, adds the resource to the inventory and deletes the extractor and the lock
+/// in a single batch, see `batch`. If the batch can't be published, nothing has changed and the
+/// next frame tries again
+fn deliver_batch(
+    ctx: &impl Capabilities,
+    extractor: &MiningExtractor,
+    extractor_rid: &str,
+    shard: &str,
+    entity_id: &str,
+    asteroid_entity_id: &str,
+    pending: &PendingDelivery,
+) -> CallResult {
+    let mut messages = vec![
+        deposit_delete_message(&extractor.target),
+        pending.inventory_add.clone(),
+    ];
+    messages.extend(plan_completion(
+        extractor,
+        extractor_rid,
+        shard,
+        entity_id,
+        asteroid_entity_id,
+        &pending.mined,
+        pending.overshoot_ms,
+    ));
+    if let Err(e) = publish_batch(ctx, shard, messages) {
+        clear_pending(ctx, shard, entity_id, &extractor.target)?;
+        release_completion(ctx, shard, entity_id, &extractor.target)?;
+        return Err(e);
+    }
+    clear_pending(ctx, shard, entity_id, &extractor.target)?;
+    mark_deposit_taken(ctx, shard, asteroid_entity_id, &extractor.target)?;
+    record_completion(
+        ctx,
+        extractor,
+        shard,
+        entity_id,
+        asteroid_entity_id,
+        pending,
+    )
+}
 
-        Ok(vec![])
-    } else {
-        Err("Resource mining target did not exist".into())
+/// Remembers that a deposit of an asteroid's `mining_resources` collection was taken
+fn mark_deposit_taken(
+    ctx: &impl Capabilities,
+    shard: &str,
+    asteroid_entity_id: &str,
+    target: &str,
+) -> Result<()> {
+    if deposit_collection(target).is_some() {
+        ctx.kv()
+            .set_add(&taken_deposits_key(shard, asteroid_entity_id), target)?;
     }
+    Ok(())
 }
 
-fn get_transponder(
-    ctx: &CapabilitiesContext,
+/// Counts, records and logs a completed extraction, takes it out of the resource's supply and
+/// depletes the asteroid if this was its last deposit
+fn record_completion(
+    ctx: &impl Capabilities,
+    extractor: &MiningExtractor,
     shard: &str,
-    entity: &str,
-) -> std::result::Result<RadarTransponder, Box<dyn std::error::Error>> {
-    let raw = ctx
-        .kv()
-        .get(&format!("decs:components:{}:{}:transponder", shard, entity))?;
-    match raw {
-        Some(s) => match serde_json::from_str(&s) {
-            Ok(t) => Ok(t),
-            Err(_) => Err("unable to retrieve transponder".into()),
+    entity_id: &str,
+    asteroid_entity_id: &str,
+    pending: &PendingDelivery,
+) -> CallResult {
+    count(ctx, shard, COMPLETED)?;
+    publish_completed(
+        ctx,
+        shard,
+        entity_id,
+        &extractor.target,
+        &pending.mined,
+        pending.mined_at_ms,
+    )?;
+    record_extraction(ctx, shard, entity_id, &pending.mined)?;
+    deplete_supply(ctx, shard, &pending.mined)?;
+    log_extraction(
+        ctx,
+        shard,
+        &ExtractionRecord {
+            miner: entity_id.to_string(),
+            asteroid: asteroid_entity_id.to_string(),
+            resource: pending.mined.stack_type.clone(),
+            qty: pending.mined.qty,
+            timestamp_ms: pending.mined_at_ms,
         },
-        None => Err("attempted to retrieve non-existent transponder".into()),
+    )?;
+
+    // An asteroid with several deposits is only depleted once every one of them has been taken,
+    // by this entity or any other
+    let taken_key = taken_deposits_key(shard, asteroid_entity_id);
+    let (remaining_deposits, taken_deposits) = match deposit_collection(&extractor.target) {
+        Some(collection) => (
+            ctx.kv().list_range(&collection.replace('.', ":"), 0, -1)?,
+            ctx.kv().set_members(&taken_key)?,
+        ),
+        None => (vec![], vec![]),
+    };
+    if is_depleted(&remaining_deposits, &taken_deposits) {
+        if !taken_deposits.is_empty() {
+            ctx.kv().del_key(&taken_key)?;
+        }
+        // Update the transponder to indicate the asteroid is empty, keeping the original
+        // in a backup and in the respawn marker
+        let old_tp = deplete_transponder(ctx, shard, asteroid_entity_id)?;
+        schedule_respawn(
+            ctx,
+            shard,
+            asteroid_entity_id,
+            &extractor.target,
+            &pending.resource,
+            old_tp,
+        )?;
+    }
+
+    Ok(vec![])
+}
+
+/// Gives up on an extraction whose resource is gone (or was never addressable), deleting the
+/// extractor and the lock on the target so the next frame has nothing left to do
+pub(crate) fn abandon_extraction(
+    ctx: &impl Capabilities,
+    extractor: &MiningExtractor,
+    extractor_rid: &str,
+    shard: &str,
+    entity_id: &str,
+    reason: &str,
+) -> CallResult {
+    let mut queue = MessageQueue::new();
+    for message in plan_abandon_extraction(extractor, extractor_rid, shard, entity_id, reason) {
+        queue.push_message(message)?;
     }
+    flush_and_report(ctx, queue, shard, entity_id)?;
+    count_failure(ctx, shard, reason)?;
+    publish_failure(ctx, shard, entity_id, &extractor.target, reason)?;
+    Ok(vec![])
+}
+
+/// Produces the messages (subject and payload) that abandon an extraction: the failure event,
+/// the deletion of the extractor and, if the target names an entity, the deletion of its lock
+fn plan_abandon_extraction(
+    extractor: &MiningExtractor,
+    extractor_rid: &str,
+    shard: &str,
+    entity_id: &str,
+    reason: &str,
+) -> Vec<(String, Value)> {
+    let mut publishes = vec![
+        mining_failed_message(extractor, shard, entity_id, reason),
+        extractor_delete_message(extractor_rid),
+    ];
+    // Only a well formed target on this shard names a lock that is ours to clean up
+    if let Ok(target) = parse_target(&extractor.target, shard) {
+        publishes.push(lock_release_message(&lock_rid(
+            shard,
+            target.entity(),
+            &extractor.target,
+        )));
+    }
+    publishes
+}
+
+/// Parses the extractor's target, naming the component being mined. Extraction is refused for
+/// targets that aren't component rids and for targets on a different shard than the extractor.
+/// The error holds the failure reason and a message naming the offending target
+pub(crate) fn parse_target(
+    target: &str,
+    shard: &str,
+) -> std::result::Result<ResourceIdentifier, (&'static str, String)> {
+    let rid = ResourceIdentifier::parse_rid(target).map_err(|message| (INVALID_TARGET, message))?;
+    if rid.shard() != shard {
+        return Err((
+            CROSS_SHARD_TARGET,
+            format!(
+                "target '{}' is on shard '{}', not '{}'",
+                target,
+                rid.shard(),
+                shard
+            ),
+        ));
+    }
+    Ok(rid)
+}
+
+/// Produces the messages (subject and payload) that finish an extraction whose resource has
+/// reached the inventory: the deletion of the extractor and the lock, and finally
+/// `event.decs.{shard}.{entity}.mining.completed`
+fn plan_completion(
+    extractor: &MiningExtractor,
+    extractor_rid: &str,
+    shard: &str,
+    entity_id: &str,
+    asteroid_entity_id: &str,
+    resource: &MiningResource,
+    overshoot_ms: f64,
+) -> Vec<(String, Value)> {
+    let completed = MiningCompleted {
+        target: extractor.target.to_string(),
+        stack_type: resource.stack_type.to_string(),
+        qty: resource.qty,
+        elapsed_ms: extractor.total_ms,
+        rarity: resource.rarity,
+        overshoot_ms,
+    };
+    vec![
+        extractor_delete_message(extractor_rid),
+        lock_release_message(&lock_rid(shard, asteroid_entity_id, &extractor.target)),
+        (
+            format!("event.decs.{}.{}.mining.completed", shard, entity_id),
+            json!(completed),
+        ),
+    ]
+}
+
+/// Deletes the mined deposit. The extractor target is the fully qualified ID of either an
+/// asteroid's single `mining_resource` component or an item in its `mining_resources` collection
+fn deposit_delete_message(target: &str) -> (String, Value) {
+    member_delete_message(target, deposit_collection(target))
+}
+
+/// Returns the rid of the `mining_resources` collection the target deposit belongs to, e.g.
+/// `decs.components.{shard}.{asteroid}.mining_resources` for the target
+/// `decs.components.{shard}.{asteroid}.mining_resources.{deposit}`. Targets on an asteroid's
+/// single `mining_resource` component don't belong to a collection
+pub(crate) fn deposit_collection(target: &str) -> Option<String> {
+    member_collection(target, super::MINING_RESOURCES)
+}
+
+/// Deletes the extractor, either an item in its entity's `extractors` collection or a legacy
+/// single `extractor` component
+pub(crate) fn extractor_delete_message(extractor_rid: &str) -> (String, Value) {
+    member_delete_message(
+        extractor_rid,
+        member_collection(extractor_rid, super::EXTRACTORS),
+    )
+}
+
+/// Returns the rid of the named collection the item belongs to, if it is an item of one
+pub(crate) fn member_collection(rid: &str, collection: &str) -> Option<String> {
+    let parts: Vec<&str> = rid.split('.').collect();
+    if parts.len() > 5 && parts[4] == collection {
+        Some(parts[..5].join("."))
+    } else {
+        None
+    }
+}
+
+/// Items of a collection are deleted through the collection, components on their own
+pub(crate) fn member_delete_message(rid: &str, collection: Option<String>) -> (String, Value) {
+    (
+        format!("call.{}.delete", collection.as_deref().unwrap_or(rid)),
+        json!({
+            "params": {
+                "rid": rid
+            }
+        }),
+    )
+}
+
+/// Whether every deposit still listed on the asteroid has already been taken. Deposits are
+/// listed until the component manager has applied their deletion, which may be after another
+/// entity took the last of them
+fn is_depleted(remaining_deposits: &[String], taken_deposits: &[String]) -> bool {
+    remaining_deposits
+        .iter()
+        .all(|rid| taken_deposits.contains(rid))
+}
+
+/// The KV set of the deposits of an asteroid's `mining_resources` taken since it was last
+/// depleted
+fn taken_deposits_key(shard: &str, asteroid_entity_id: &str) -> String {
+    format!("decs:mining:taken:{}:{}", shard, asteroid_entity_id)
 }
 
-fn deplete_transponder(old_tp: &RadarTransponder) -> RadarTransponder {
-    RadarTransponder {
-        color: DEPLETED_COLOR.to_string(),
-        display_name: format!("{} (depleted)", old_tp.display_name),
-        object_type: old_tp.object_type.clone(),
+/// Deletes the mining lock, the asteroid's `mining_lock` component or a deposit's item in its
+/// `mining_locks` collection. The rid in the params is the lock's own so the component manager
+/// deletes the lock it was called for
+pub(crate) fn lock_release_message(lock_rid: &str) -> (String, Value) {
+    member_delete_message(lock_rid, member_collection(lock_rid, super::MINING_LOCKS))
+}
+
+/// The `event.decs.{shard}.{entity}.mining.failed` event that tells the owner of the extractor
+/// why the extraction didn't produce anything
+fn mining_failed_message(
+    extractor: &MiningExtractor,
+    shard: &str,
+    entity_id: &str,
+    reason: &str,
+) -> (String, Value) {
+    (
+        format!("event.decs.{}.{}.mining.failed", shard, entity_id),
+        json!({
+            "target": extractor.target,
+            "reason": reason
+        }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::claim_completion;
+    use super::deposit_collection;
+    use super::extract_resource;
+    use super::extractor_params;
+    use super::handle_frame;
+    use super::is_depleted;
+    use super::lock_release_message;
+    use super::parse_target;
+    use super::plan_abandon_extraction;
+    use super::plan_completion;
+    use super::update_extractor;
+    use super::FrameCache;
+    use super::Millis;
+    use super::MiningCompleted;
+    use super::MiningEfficiency;
+    use super::MiningExtractor;
+    use super::MiningResource;
+    use super::Rarity;
+    use super::CROSS_SHARD_TARGET;
+    use super::FRAME_CACHE_CAPACITY;
+    use super::INVALID_TARGET;
+    use super::RESOURCE_MISSING;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+    use std::collections::HashMap;
+
+    const EXTRACTOR_RID: &str = "decs.components.the_void.player1.extractors.1";
+
+    fn extractor(target: &str) -> MiningExtractor {
+        MiningExtractor {
+            target: target.to_string(),
+            remaining_ms: Millis(0.0),
+            total_ms: 1200.0,
+            paused: false,
+            deliver_to: None,
+            max_mining_speed: None,
+            heat: 0.0,
+            overheated: false,
+            heat_limit: None,
+        }
+    }
+
+    #[test]
+    fn test_extractor_params_include_progress() {
+        let extractor = MiningExtractor {
+            target: "decs.components.the_void.asteroid1.mining_resource".to_string(),
+            remaining_ms: Millis(300.0),
+            total_ms: 1200.0,
+            paused: false,
+            deliver_to: None,
+            max_mining_speed: None,
+            heat: 0.0,
+            overheated: false,
+            heat_limit: None,
+        };
+        let params = extractor_params(&extractor).unwrap();
+
+        assert_eq!(params["percent_complete"], 75.0);
+        assert_eq!(params["remaining_ms"], 300.0);
+        assert_eq!(params["total_ms"], 1200.0);
+        assert_eq!(params["target"], extractor.target.as_str());
+    }
+
+    #[test]
+    fn test_efficiency_scales_extraction_rate() {
+        let mut extractor = extractor("decs.components.the_void.asteroid1.mining_resource");
+        extractor.remaining_ms = Millis(1200.0);
+        let efficiency = MiningEfficiency { multiplier: 2.0 };
+        assert!(efficiency.is_valid());
+
+        let improved = update_extractor(extractor.clone(), 100, efficiency.multiplier);
+        assert_eq!(improved.remaining_ms, Millis(1000.0));
+        let improved = update_extractor(improved, 100, efficiency.multiplier);
+        assert_eq!(improved.remaining_ms, Millis(800.0));
+
+        // Without a mining_efficiency component the multiplier is 1.0
+        let standard = update_extractor(extractor, 100, MiningEfficiency::default().multiplier);
+        assert_eq!(standard.remaining_ms, Millis(1100.0));
+        assert_eq!(standard.total_ms, 1200.0);
+    }
+
+    #[test]
+    fn test_paused_extraction_makes_no_progress() {
+        let mut extractor = extractor("decs.components.the_void.asteroid1.mining_resource");
+        extractor.remaining_ms = Millis(1200.0);
+        extractor.paused = true;
+
+        let paused = update_extractor(extractor, 500, 1.0);
+        let paused = update_extractor(paused, 500, 2.0);
+        assert_eq!(paused.remaining_ms, Millis(1200.0));
+
+        let resumed = MiningExtractor {
+            paused: false,
+            ..paused
+        };
+        let resumed = update_extractor(resumed, 500, 1.0);
+        assert_eq!(resumed.remaining_ms, Millis(700.0));
+    }
+
+    #[test]
+    fn test_paused_extractor_does_not_complete() {
+        let ctx = MockCapabilitiesContext::new().with_extractor(
+            EXTRACTOR_RID,
+            json!({
+                "target": "decs.components.the_void.asteroid1.mining_resource",
+                "remaining_ms": 0.0,
+                "total_ms": 7000.0,
+                "paused": true
+            }),
+        );
+        let msg = BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 12,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        };
+
+        handle_frame(&ctx, msg).unwrap();
+        assert!(ctx.subjects().is_empty());
+    }
+
+    #[test]
+    fn test_unusable_efficiency_multipliers() {
+        for multiplier in &[0.0, -1.5, f64::NAN, f64::INFINITY] {
+            assert!(!MiningEfficiency {
+                multiplier: *multiplier
+            }
+            .is_valid());
+        }
+    }
+
+    #[test]
+    fn test_invalid_extractor_is_not_published() {
+        let mut extractor = extractor("decs.components.the_void.asteroid1.mining_resource");
+        extractor.remaining_ms = Millis(-5.0);
+
+        // publish_extractor builds its payload with extractor_params before publishing
+        let err = extractor_params(&extractor).unwrap_err();
+        assert!(err.to_string().contains("remaining_ms"), "{}", err);
+    }
+
+    #[test]
+    fn test_deposit_collection() {
+        assert_eq!(
+            deposit_collection("decs.components.the_void.asteroid1.mining_resources.deposit1"),
+            Some("decs.components.the_void.asteroid1.mining_resources".to_string())
+        );
+        assert_eq!(
+            deposit_collection("decs.components.the_void.asteroid1.mining_resource"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_two_deposit_asteroid_depletes_after_last_extraction() {
+        let first = "decs.components.the_void.asteroid1.mining_resources.deposit1".to_string();
+        let second = "decs.components.the_void.asteroid1.mining_resources.deposit2".to_string();
+
+        // The first extraction leaves the second deposit behind
+        let deposits = vec![first.clone(), second.clone()];
+        assert!(!is_depleted(&deposits, std::slice::from_ref(&first)));
+        let deposits = vec![second.clone()];
+
+        // Mining the last deposit depletes the asteroid, whether or not the deletion of the
+        // deposit has reached the KV store yet
+        assert!(is_depleted(&deposits, &[first.clone(), second.clone()]));
+        assert!(is_depleted(&[], std::slice::from_ref(&second)));
+
+        // Even if neither deletion has been applied when the second deposit is taken
+        let deposits = vec![first.clone(), second.clone()];
+        assert!(is_depleted(&deposits, &[second, first]));
+    }
+
+    #[test]
+    fn test_single_resource_asteroid_depletes() {
+        assert!(is_depleted(&[], &[]));
+    }
+
+    #[test]
+    fn test_missing_resource_cleans_up_once() {
+        let extractor = extractor("decs.components.the_void.asteroid1.mining_resource");
+        let publishes = plan_abandon_extraction(
+            &extractor,
+            EXTRACTOR_RID,
+            "the_void",
+            "player1",
+            RESOURCE_MISSING,
+        );
+        let subjects: Vec<&str> = publishes.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(
+            subjects,
+            vec![
+                "event.decs.the_void.player1.mining.failed",
+                "call.decs.components.the_void.player1.extractors.delete",
+                "call.decs.components.the_void.asteroid1.mining_lock.delete",
+            ]
+        );
+        assert_eq!(publishes[0].1["reason"], RESOURCE_MISSING);
+
+        // Apply the deletes to a store holding the extractor and the lock. Once they have
+        // been applied the next frame finds no extractor and does nothing
+        let mut store: HashMap<String, String> = vec![
+            EXTRACTOR_RID,
+            "decs.components.the_void.asteroid1.mining_lock",
+        ]
+        .into_iter()
+        .map(|rid| (rid.to_string(), "{}".to_string()))
+        .collect();
+        for (_, payload) in publishes.iter().filter(|(s, _)| s.ends_with(".delete")) {
+            assert!(store
+                .remove(payload["params"]["rid"].as_str().unwrap())
+                .is_some());
+        }
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_lock_release_names_the_same_component_twice() {
+        let (subject, payload) =
+            lock_release_message("decs.components.the_void.asteroid1.mining_lock");
+        let rid = payload["params"]["rid"].as_str().unwrap();
+        assert_eq!(rid, "decs.components.the_void.asteroid1.mining_lock");
+        assert_eq!(subject, format!("call.{}.delete", rid));
+
+        // Completing an extraction releases the same lock
+        let extractor = extractor("decs.components.the_void.asteroid1.mining_resource");
+        let publishes = plan_completion(
+            &extractor,
+            EXTRACTOR_RID,
+            "the_void",
+            "player1",
+            "asteroid1",
+            &MiningResource::default(),
+            0.0,
+        );
+        assert!(publishes.contains(&(subject, payload)));
+    }
+
+    #[test]
+    fn test_malformed_target_only_removes_extractor() {
+        let publishes = plan_abandon_extraction(
+            &extractor("asteroid1"),
+            EXTRACTOR_RID,
+            "the_void",
+            "player1",
+            INVALID_TARGET,
+        );
+        assert_eq!(publishes.len(), 2);
+        assert_eq!(publishes[0].1["reason"], INVALID_TARGET);
+        assert_eq!(publishes[1].1["params"]["rid"], EXTRACTOR_RID);
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            parse_target(
+                "decs.components.the_void.asteroid1.mining_resource",
+                "the_void"
+            )
+            .unwrap()
+            .entity(),
+            "asteroid1"
+        );
+    }
+
+    #[test]
+    fn test_short_and_empty_targets_are_errors() {
+        for target in &[
+            "",
+            "asteroid1",
+            "decs.components.the_void",
+            "decs.components.the_void.asteroid1",
+            // An entity ID corrupting the KV key of the resource
+            "decs.components.the_void.aster:oid1.mining_resource",
+        ] {
+            let (reason, message) = parse_target(target, "the_void").unwrap_err();
+            assert_eq!(reason, INVALID_TARGET);
+            assert!(message.contains(&format!("'{}'", target)), "{}", message);
+        }
+    }
+
+    #[test]
+    fn test_cross_shard_target_is_refused() {
+        let target = "decs.components.shard-two.asteroid1.mining_resource";
+        let (reason, message) = parse_target(target, "the_void").unwrap_err();
+        assert_eq!(reason, CROSS_SHARD_TARGET);
+        assert!(message.contains(target));
+
+        // Abandoning it leaves the other shard's lock alone
+        let publishes = plan_abandon_extraction(
+            &extractor(target),
+            EXTRACTOR_RID,
+            "the_void",
+            "player1",
+            reason,
+        );
+        assert_eq!(publishes.len(), 2);
+    }
+
+    #[test]
+    fn test_completion_publishes_completed_event_last() {
+        let mut extractor = extractor("decs.components.the_void.asteroid1.mining_resource");
+        extractor.total_ms = 4500.0;
+        let resource = MiningResource {
+            stack_type: "tasty".to_string(),
+            qty: 7,
+            rarity: Rarity::Rare,
+            ..MiningResource::default()
+        };
+        let publishes = plan_completion(
+            &extractor,
+            EXTRACTOR_RID,
+            "the_void",
+            "player1",
+            "asteroid1",
+            &resource,
+            250.0,
+        );
+        let subjects: Vec<&str> = publishes.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(
+            subjects,
+            vec![
+                "call.decs.components.the_void.player1.extractors.delete",
+                "call.decs.components.the_void.asteroid1.mining_lock.delete",
+                "event.decs.the_void.player1.mining.completed",
+            ]
+        );
+
+        let completed: MiningCompleted = serde_json::from_value(publishes[2].1.clone()).unwrap();
+        assert_eq!(
+            completed,
+            MiningCompleted {
+                target: extractor.target.to_string(),
+                stack_type: "tasty".to_string(),
+                qty: 7,
+                elapsed_ms: 4500.0,
+                rarity: Rarity::Rare,
+                overshoot_ms: 250.0,
+            }
+        );
+        // The client colors the toast by rarity
+        assert_eq!(publishes[2].1["rarity"], "rare");
+    }
+
+    #[test]
+    fn test_handle_frame_completes_extraction() {
+        let target = "decs.components.the_void.asteroid1.mining_resource";
+        let ctx = MockCapabilitiesContext::new()
+            .with_extractor(
+                EXTRACTOR_RID,
+                json!({ "target": target, "remaining_ms": 100.0, "total_ms": 7000.0 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_resource",
+                json!({ "stack_type": "tasty", "qty": 7 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_lock",
+                json!({
+                    "extractor": EXTRACTOR_RID,
+                    "owner": "player1"
+                }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:transponder",
+                json!({ "object_type": "asteroid", "display_name": "Rock", "color": "gray" }),
+            );
+        let frame = json!({
+            "seq_no": 12,
+            "elapsed_ms": 1000,
+            "shard": "the_void",
+            "system": "mining",
+            "entity_id": "player1"
+        });
+        let msg = BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&frame).unwrap(),
+            ..Default::default()
+        };
+
+        handle_frame(&ctx, msg).unwrap();
+
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "call.decs.components.the_void.asteroid1.mining_resource.delete",
+                "call.decs.components.the_void.player1.inventory.new",
+                "call.decs.components.the_void.player1.extractors.delete",
+                "call.decs.components.the_void.asteroid1.mining_lock.delete",
+                "event.decs.the_void.player1.mining.completed",
+                "call.decs.shard.the_void.mining_events.new",
+                "call.decs.components.the_void.player1.mining_stats.set",
+                "call.decs.components.the_void.asteroid1.transponder.set",
+                "call.decs.components.the_void.asteroid1.asteroid_respawn.set",
+            ]
+        );
+        let added = ctx
+            .payload("call.decs.components.the_void.player1.inventory.new")
+            .unwrap();
+        assert_eq!(
+            added["params"],
+            json!({
+                "name": "tasty",
+                "kind": "resource",
+                "qty": 7,
+                "unit_value": 50,
+                "version": 2
+            })
+        );
+        let completed = ctx
+            .payload("event.decs.the_void.player1.mining.completed")
+            .unwrap();
+        assert_eq!(completed["elapsed_ms"], 7000.0);
+        let stats = ctx
+            .payload("call.decs.components.the_void.player1.mining_stats.set")
+            .unwrap();
+        assert_eq!(stats["params"]["asteroids_mined"], 1);
+        assert_eq!(stats["params"]["resources"]["tasty"], 7);
+
+        // A second frame racing the first one finds the completion already claimed
+        let msg = BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&frame).unwrap(),
+            ..Default::default()
+        };
+        handle_frame(&ctx, msg).unwrap();
+        assert_eq!(ctx.subjects().len(), 9);
+        let transponder = ctx
+            .payload("call.decs.components.the_void.asteroid1.transponder.set")
+            .unwrap();
+        assert_eq!(transponder["params"]["depleted"], true);
+    }
+
+    #[test]
+    fn test_extraction_into_existing_stack_publishes_full_sequence() {
+        let target = "decs.components.the_void.asteroid1.mining_resource";
+        let inventory_rid = "decs.components.the_void.player1.inventory.4";
+        let ctx = MockCapabilitiesContext::new()
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_resource",
+                json!({ "stack_type": "tasty", "qty": 5 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_lock",
+                json!({ "extractor": EXTRACTOR_RID, "owner": "player1" }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:transponder",
+                json!({ "object_type": "asteroid", "display_name": "Rock", "color": "gray" }),
+            )
+            .with_value(
+                &inventory_rid.replace('.', ":"),
+                json!({ "name": "tasty", "kind": "resource", "qty": 2, "unit_value": 50 }),
+            );
+        ctx.lists.borrow_mut().insert(
+            "decs:components:the_void:player1:inventory".to_string(),
+            vec![inventory_rid.to_string()],
+        );
+        let mut cache = FrameCache::new(FRAME_CACHE_CAPACITY);
+
+        extract_resource(
+            &ctx,
+            &mut cache,
+            &extractor(target),
+            EXTRACTOR_RID,
+            "the_void",
+            "player1",
+            12000,
+            0.0,
+        )
+        .unwrap();
+
+        // The deposit is taken first, and only once the resource has reached the inventory is
+        // anything cleaned up
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "call.decs.components.the_void.asteroid1.mining_resource.delete",
+                "call.decs.components.the_void.player1.inventory.4.set",
+                "call.decs.components.the_void.player1.extractors.delete",
+                "call.decs.components.the_void.asteroid1.mining_lock.delete",
+                "event.decs.the_void.player1.mining.completed",
+                "call.decs.shard.the_void.mining_events.new",
+                "call.decs.components.the_void.player1.mining_stats.set",
+                "call.decs.components.the_void.asteroid1.transponder.set",
+                "call.decs.components.the_void.asteroid1.asteroid_respawn.set",
+            ]
+        );
+        let merged = ctx
+            .payload("call.decs.components.the_void.player1.inventory.4.set")
+            .unwrap();
+        assert_eq!(merged["params"]["qty"], 7);
+        assert_eq!(
+            ctx.payload("call.decs.components.the_void.player1.extractors.delete")
+                .unwrap()["params"]["rid"],
+            EXTRACTOR_RID
+        );
+        assert_eq!(
+            ctx.payload("call.decs.components.the_void.asteroid1.mining_lock.delete")
+                .unwrap()["params"]["rid"],
+            "decs.components.the_void.asteroid1.mining_lock"
+        );
+        let transponder = ctx
+            .payload("call.decs.components.the_void.asteroid1.transponder.set")
+            .unwrap();
+        assert_eq!(transponder["params"]["depleted"], true);
+    }
+
+    const DEPOSIT_DELETE: &str = "call.decs.components.the_void.asteroid1.mining_resource.delete";
+    const INVENTORY_NEW: &str = "call.decs.components.the_void.player1.inventory.new";
+    const EXTRACTOR_DELETE: &str = "call.decs.components.the_void.player1.extractors.delete";
+    const PENDING_KEY: &str =
+        "decs:mining:pending:the_void:player1:decs.components.the_void.asteroid1.mining_resource";
+
+    /// A context holding an extraction of a lone deposit that completes on the next frame
+    fn completing_extraction() -> MockCapabilitiesContext {
+        MockCapabilitiesContext::new()
+            .with_extractor(
+                EXTRACTOR_RID,
+                json!({
+                    "target": "decs.components.the_void.asteroid1.mining_resource",
+                    "remaining_ms": 100.0,
+                    "total_ms": 7000.0
+                }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_resource",
+                json!({ "stack_type": "tasty", "qty": 7 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_lock",
+                json!({ "extractor": EXTRACTOR_RID, "owner": "player1" }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:transponder",
+                json!({ "object_type": "asteroid", "display_name": "Rock", "color": "gray" }),
+            )
+    }
+
+    fn frame() -> BrokerMessage {
+        BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 12,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn count(ctx: &MockCapabilitiesContext, subject: &str) -> usize {
+        ctx.subjects().iter().filter(|s| *s == subject).count()
+    }
+
+    #[test]
+    fn test_yield_is_independent_of_frame_rate() {
+        const COMPLETED: &str = "event.decs.the_void.player1.mining.completed";
+        let run = |elapsed_ms: u32| {
+            let ctx = completing_extraction().with_value(
+                &EXTRACTOR_RID.replace('.', ":"),
+                json!({
+                    "target": "decs.components.the_void.asteroid1.mining_resource",
+                    "remaining_ms": 150.0,
+                    "total_ms": 7000.0
+                }),
+            );
+            let msg = BrokerMessage {
+                subject: "decs.frames.the_void.mining".to_string(),
+                body: serde_json::to_vec(&json!({
+                    "seq_no": 12,
+                    "elapsed_ms": elapsed_ms,
+                    "shard": "the_void",
+                    "system": "mining",
+                    "entity_id": "player1"
+                }))
+                .unwrap(),
+                ..Default::default()
+            };
+            // The progress published each frame is stored, as decs would
+            for _ in 0..10 {
+                handle_frame(&ctx, msg.clone()).unwrap();
+                if ctx.payload(COMPLETED).is_some() {
+                    break;
+                }
+                let progress = ctx.payload(&format!("call.{}.set", EXTRACTOR_RID)).unwrap();
+                ctx.apply_set(EXTRACTOR_RID, &progress["params"]);
+                ctx.published.borrow_mut().clear();
+            }
+            let completed = ctx.payload(COMPLETED).unwrap();
+            let delivered = ctx.payload(INVENTORY_NEW).unwrap()["params"]["qty"].clone();
+            (completed, delivered)
+        };
+
+        // Completing 50ms into the second 100ms frame or 850ms into a single 1000ms frame
+        // delivers the same deposit, only how late it completed differs
+        let (fast, fast_delivered) = run(100);
+        let (slow, slow_delivered) = run(1000);
+        assert_eq!(fast_delivered, json!(7));
+        assert_eq!(slow_delivered, fast_delivered);
+        assert_eq!(fast["qty"], slow["qty"]);
+        assert_eq!(fast["overshoot_ms"], 50.0);
+        assert_eq!(slow["overshoot_ms"], 850.0);
+    }
+
+    #[test]
+    fn test_corrupt_extractor_is_sanitized() {
+        // A negative total would fail validation on every frame
+        let ctx = completing_extraction().with_value(
+            &EXTRACTOR_RID.replace('.', ":"),
+            json!({
+                "target": "decs.components.the_void.asteroid1.mining_resource",
+                "remaining_ms": 3000.0,
+                "total_ms": -7000.0,
+                "heat": -50.0
+            }),
+        );
+        handle_frame(&ctx, frame()).unwrap();
+        let params = &ctx.payload(&format!("call.{}.set", EXTRACTOR_RID)).unwrap()["params"];
+        assert_eq!(params["remaining_ms"], 2000.0);
+        assert_eq!(params["total_ms"], 3000.0);
+
+        // Negative time left completes the extraction
+        let ctx = completing_extraction().with_value(
+            &EXTRACTOR_RID.replace('.', ":"),
+            json!({
+                "target": "decs.components.the_void.asteroid1.mining_resource",
+                "remaining_ms": -3000.0,
+                "total_ms": 7000.0
+            }),
+        );
+        handle_frame(&ctx, frame()).unwrap();
+        assert_eq!(count(&ctx, INVENTORY_NEW), 1);
+        assert_eq!(count(&ctx, EXTRACTOR_DELETE), 1);
+    }
+
+    #[test]
+    fn test_failed_deposit_delete_leaves_extraction_to_retry() {
+        let ctx = completing_extraction().with_failing_subject(DEPOSIT_DELETE);
+
+        assert!(handle_frame(&ctx, frame()).is_err());
+        // Nothing was delivered and nothing is left claimed
+        assert!(ctx.subjects().is_empty());
+        assert!(!ctx.values.borrow().contains_key(PENDING_KEY));
+
+        ctx.failing.borrow_mut().remove(DEPOSIT_DELETE);
+        handle_frame(&ctx, frame()).unwrap();
+        handle_frame(&ctx, frame()).unwrap();
+        assert_eq!(count(&ctx, DEPOSIT_DELETE), 1);
+        assert_eq!(count(&ctx, INVENTORY_NEW), 1);
+        assert_eq!(count(&ctx, EXTRACTOR_DELETE), 1);
+    }
+
+    #[test]
+    fn test_failure_after_claiming_releases_the_claim() {
+        let ctx = completing_extraction().with_failing_key(PENDING_KEY);
+
+        assert!(handle_frame(&ctx, frame()).is_err());
+        assert!(ctx.subjects().is_empty());
+        assert!(!ctx
+            .values
+            .borrow()
+            .keys()
+            .any(|key| key.starts_with("decs:mining:completed:")));
+
+        // The next frame can claim the completion again rather than waiting for the guard
+        ctx.failing.borrow_mut().remove(PENDING_KEY);
+        handle_frame(&ctx, frame()).unwrap();
+        assert_eq!(count(&ctx, DEPOSIT_DELETE), 1);
+        assert_eq!(count(&ctx, INVENTORY_NEW), 1);
+    }
+
+    #[test]
+    fn test_frames_with_unsafe_ids_are_rejected() {
+        for (shard, entity_id) in &[
+            ("the_void", "player*"),
+            ("the_void", "player1.>"),
+            ("the_void", ""),
+            ("the void", "player1"),
+        ] {
+            let ctx = completing_extraction();
+            let msg = BrokerMessage {
+                subject: "decs.frames.the_void.mining".to_string(),
+                body: serde_json::to_vec(&json!({
+                    "seq_no": 12,
+                    "elapsed_ms": 1000,
+                    "shard": shard,
+                    "system": "mining",
+                    "entity_id": entity_id
+                }))
+                .unwrap(),
+                ..Default::default()
+            };
+            handle_frame(&ctx, msg).unwrap();
+            // Nothing but the rejection goes out
+            assert_eq!(ctx.subjects(), vec!["event.decs.system.frame.rejected"]);
+            let rejected = ctx.payload("event.decs.system.frame.rejected").unwrap();
+            assert_eq!(rejected["system"], "mining");
+            assert_eq!(rejected["entity_id"], *entity_id);
+            assert!(rejected["reason"].is_string());
+        }
+    }
+
+    #[test]
+    fn test_failed_delivery_is_retried_from_pending() {
+        let ctx = completing_extraction().with_failing_subject(INVENTORY_NEW);
+
+        handle_frame(&ctx, frame()).unwrap();
+        // The deposit is gone, but the resource it held is kept for the next frame
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                DEPOSIT_DELETE,
+                "event.decs.the_void.player1.mining.delivery_pending"
+            ]
+        );
+        let pending = ctx
+            .payload("event.decs.the_void.player1.mining.delivery_pending")
+            .unwrap();
+        assert_eq!(pending["stack_type"], "tasty");
+        assert_eq!(pending["qty"], 7);
+        assert!(ctx.values.borrow().contains_key(PENDING_KEY));
+
+        ctx.failing.borrow_mut().remove(INVENTORY_NEW);
+        handle_frame(&ctx, frame()).unwrap();
+        assert!(!ctx.values.borrow().contains_key(PENDING_KEY));
+        assert_eq!(
+            ctx.payload(INVENTORY_NEW).unwrap()["params"]["qty"],
+            json!(7)
+        );
+        assert_eq!(count(&ctx, EXTRACTOR_DELETE), 1);
+
+        // Once delivered, later frames neither deliver nor take anything again
+        handle_frame(&ctx, frame()).unwrap();
+        assert_eq!(count(&ctx, DEPOSIT_DELETE), 1);
+        assert_eq!(count(&ctx, INVENTORY_NEW), 1);
+    }
+
+    #[test]
+    fn test_pending_delivery_is_retried_once() {
+        let ctx = completing_extraction().with_failing_subject(INVENTORY_NEW);
+        handle_frame(&ctx, frame()).unwrap();
+        ctx.failing.borrow_mut().remove(INVENTORY_NEW);
+
+        // Another frame seeing the same pending delivery has claimed the retry
+        assert!(claim_completion(
+            &ctx,
+            "the_void",
+            "player1",
+            "decs.components.the_void.asteroid1.mining_resource"
+        )
+        .unwrap());
+        handle_frame(&ctx, frame()).unwrap();
+        assert_eq!(count(&ctx, INVENTORY_NEW), 0);
+        assert!(ctx.values.borrow().contains_key(PENDING_KEY));
+    }
+
+    #[test]
+    fn test_failed_cleanup_does_not_duplicate_delivery() {
+        let ctx = completing_extraction().with_failing_subject(EXTRACTOR_DELETE);
+
+        handle_frame(&ctx, frame()).unwrap();
+        handle_frame(&ctx, frame()).unwrap();
+        assert_eq!(count(&ctx, DEPOSIT_DELETE), 1);
+        assert_eq!(count(&ctx, INVENTORY_NEW), 1);
+        let errors = ctx
+            .payload("event.decs.system.mining.publish_errors")
+            .unwrap();
+        assert_eq!(errors["errors"][0]["subject"], EXTRACTOR_DELETE);
+        assert!(!ctx.values.borrow().contains_key(PENDING_KEY));
+    }
+
+    #[test]
+    fn test_depleted_target_is_not_mined() {
+        let target = "decs.components.the_void.asteroid1.mining_resource";
+        let ctx = MockCapabilitiesContext::new()
+            .with_extractor(
+                EXTRACTOR_RID,
+                json!({ "target": target, "remaining_ms": 100.0, "total_ms": 7000.0 }),
+            )
+            // The resource hasn't been deleted yet, but the transponder says it is mined out
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_resource",
+                json!({ "stack_type": "tasty", "qty": 7 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:transponder",
+                json!({
+                    "object_type": "asteroid",
+                    "display_name": "Rock (depleted)",
+                    "color": "#A9A9A9",
+                    "depleted": true
+                }),
+            );
+        let msg = BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 12,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        };
+
+        handle_frame(&ctx, msg).unwrap();
+
+        let failed = ctx
+            .payload("event.decs.the_void.player1.mining.failed")
+            .unwrap();
+        assert_eq!(failed["reason"], "target_depleted");
+        assert!(ctx
+            .payload("call.decs.components.the_void.player1.inventory.new")
+            .is_none());
+    }
+
+    /// Applies the extractor sets and deletes published so far to the context's store, the way
+    /// the component manager would between frames, and returns the subjects they were published on
+    fn apply_extractor_calls(ctx: &MockCapabilitiesContext) -> Vec<String> {
+        let published: Vec<(String, Vec<u8>)> = ctx.published.borrow_mut().drain(..).collect();
+        for (subject, payload) in &published {
+            let payload: serde_json::Value = serde_json::from_slice(payload).unwrap();
+            if subject.starts_with("call.decs.components.the_void.player1.extractors.") {
+                if subject.ends_with(".set") {
+                    let rid = &subject["call.".len()..subject.len() - ".set".len()];
+                    ctx.apply_set(rid, &payload["params"]);
+                } else if subject.ends_with(".delete") {
+                    let rid = payload["params"]["rid"].as_str().unwrap();
+                    ctx.values.borrow_mut().remove(&rid.replace('.', ":"));
+                    for list in ctx.lists.borrow_mut().values_mut() {
+                        list.retain(|member| member != rid);
+                    }
+                }
+            }
+        }
+        published.into_iter().map(|(subject, _)| subject).collect()
+    }
+
+    #[test]
+    fn test_concurrent_extractors_complete_independently() {
+        let first = "decs.components.the_void.player1.extractors.1";
+        let second = "decs.components.the_void.player1.extractors.2";
+        let mut ctx = MockCapabilitiesContext::new();
+        for (rid, asteroid, remaining_ms) in
+            &[(first, "asteroid1", 1500.0), (second, "asteroid2", 3500.0)]
+        {
+            ctx = ctx
+                .with_extractor(
+                    rid,
+                    json!({
+                        "target": format!("decs.components.the_void.{}.mining_resource", asteroid),
+                        "remaining_ms": remaining_ms,
+                        "total_ms": 4000.0
+                    }),
+                )
+                .with_value(
+                    &format!("decs:components:the_void:{}:mining_resource", asteroid),
+                    json!({ "stack_type": "tasty", "qty": 4 }),
+                )
+                .with_value(
+                    &format!("decs:components:the_void:{}:mining_lock", asteroid),
+                    json!({ "extractor": rid, "owner": "player1" }),
+                )
+                .with_value(
+                    &format!("decs:components:the_void:{}:transponder", asteroid),
+                    json!({ "object_type": "asteroid", "display_name": "Rock", "color": "gray" }),
+                );
+        }
+        let frame = || BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 12,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        };
+        let extractors = |ctx: &MockCapabilitiesContext| {
+            ctx.lists.borrow()["decs:components:the_void:player1:extractors"].clone()
+        };
+        let remaining_ms = |ctx: &MockCapabilitiesContext, rid: &str| {
+            let value = ctx.values.borrow()[&rid.replace('.', ":")].clone();
+            serde_json::from_str::<MiningExtractor>(&value)
+                .unwrap()
+                .remaining_ms
+        };
+
+        handle_frame(&ctx, frame()).unwrap();
+        apply_extractor_calls(&ctx);
+        assert_eq!(remaining_ms(&ctx, first), Millis(500.0));
+        assert_eq!(remaining_ms(&ctx, second), Millis(2500.0));
+
+        // The first extractor completes, only it is removed from the collection
+        handle_frame(&ctx, frame()).unwrap();
+        let subjects = apply_extractor_calls(&ctx);
+        assert!(subjects
+            .contains(&"call.decs.components.the_void.player1.extractors.delete".to_string()));
+        assert!(subjects.contains(&"event.decs.the_void.player1.mining.completed".to_string()));
+        assert_eq!(extractors(&ctx), vec![second.to_string()]);
+        assert_eq!(remaining_ms(&ctx, second), Millis(1500.0));
+
+        handle_frame(&ctx, frame()).unwrap();
+        apply_extractor_calls(&ctx);
+        assert_eq!(remaining_ms(&ctx, second), Millis(500.0));
+
+        handle_frame(&ctx, frame()).unwrap();
+        let subjects = apply_extractor_calls(&ctx);
+        assert!(subjects
+            .contains(&"call.decs.components.the_void.asteroid2.mining_lock.delete".to_string()));
+        assert!(!subjects.iter().any(|s| s.contains("asteroid1")));
+        assert!(extractors(&ctx).is_empty());
+    }
+
+    /// Applies the component sets and deletes published so far to the context's store, the way
+    /// the component manager would, and returns what was published
+    fn apply_component_calls(ctx: &MockCapabilitiesContext) -> Vec<(String, serde_json::Value)> {
+        let published: Vec<(String, Vec<u8>)> = ctx.published.borrow_mut().drain(..).collect();
+        let published: Vec<(String, serde_json::Value)> = published
+            .into_iter()
+            .map(|(subject, payload)| (subject, serde_json::from_slice(&payload).unwrap()))
+            .collect();
+        for (subject, payload) in &published {
+            if !subject.starts_with("call.decs.components.") {
+                continue;
+            }
+            if subject.ends_with(".set") {
+                let rid = &subject["call.".len()..subject.len() - ".set".len()];
+                ctx.apply_set(rid, &payload["params"]);
+            } else if subject.ends_with(".delete") {
+                let rid = payload["params"]["rid"].as_str().unwrap();
+                ctx.values.borrow_mut().remove(&rid.replace('.', ":"));
+                for list in ctx.lists.borrow_mut().values_mut() {
+                    list.retain(|member| member != rid);
+                }
+            }
+        }
+        published
+    }
+
+    /// Player 1 extracting the first and player 2 the second of the two deposits of an asteroid,
+    /// each holding the lock on their own deposit. Player 1 finishes one frame before player 2
+    fn shared_asteroid() -> MockCapabilitiesContext {
+        let mut ctx = MockCapabilitiesContext::new().with_value(
+            "decs:components:the_void:asteroid1:transponder",
+            json!({ "object_type": "asteroid", "display_name": "Rock", "color": "gray" }),
+        );
+        let mut deposits = vec![];
+        for (player, deposit, stack_type, remaining_ms) in &[
+            ("player1", "deposit1", "tasty", 1500.0),
+            ("player2", "deposit2", "critical", 2500.0),
+        ] {
+            let target = format!(
+                "decs.components.the_void.asteroid1.mining_resources.{}",
+                deposit
+            );
+            let extractor_rid = format!("decs.components.the_void.{}.extractors.1", player);
+            ctx = ctx
+                .with_extractor(
+                    &extractor_rid,
+                    json!({ "target": target, "remaining_ms": remaining_ms, "total_ms": 4000.0 }),
+                )
+                .with_value(
+                    &target.replace('.', ":"),
+                    json!({ "stack_type": stack_type, "qty": 4 }),
+                )
+                .with_value(
+                    &format!(
+                        "decs:components:the_void:asteroid1:mining_locks:{}",
+                        deposit
+                    ),
+                    json!({ "extractor": extractor_rid, "owner": player }),
+                );
+            deposits.push(target);
+        }
+        ctx.lists.borrow_mut().insert(
+            "decs:components:the_void:asteroid1:mining_resources".to_string(),
+            deposits,
+        );
+        ctx
+    }
+
+    fn player_frame(entity_id: &str) -> BrokerMessage {
+        BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 12,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": entity_id
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn subjects_of(published: &[(String, serde_json::Value)]) -> Vec<&str> {
+        published.iter().map(|(s, _)| s.as_str()).collect()
+    }
+
+    fn added_stack_types(
+        published: &[(String, serde_json::Value)],
+        entity_id: &str,
+    ) -> Vec<String> {
+        let inventory = format!("call.decs.components.the_void.{}.inventory.new", entity_id);
+        published
+            .iter()
+            .filter(|(s, _)| *s == inventory)
+            .map(|(_, payload)| payload["params"]["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    const TRANSPONDER_SET: &str = "call.decs.components.the_void.asteroid1.transponder.set";
+
+    #[test]
+    fn test_two_players_mine_different_deposits_of_one_asteroid() {
+        let ctx = shared_asteroid();
+        let mut published = vec![];
+        // Both extractions progress, frame by frame, in turn
+        for _ in 0..3 {
+            for player in &["player1", "player2"] {
+                handle_frame(&ctx, player_frame(player)).unwrap();
+                published.extend(apply_component_calls(&ctx));
+            }
+        }
+
+        // Player 1 finished first, releasing only the lock on its own deposit
+        let completion = published
+            .iter()
+            .position(|(s, _)| s == "event.decs.the_void.player1.mining.completed")
+            .unwrap();
+        let releases: Vec<&str> = subjects_of(&published[..completion])
+            .into_iter()
+            .filter(|s| s.ends_with("mining_locks.delete"))
+            .collect();
+        assert_eq!(releases.len(), 1);
+        assert!(!ctx
+            .values
+            .borrow()
+            .contains_key("decs:components:the_void:asteroid1:mining_locks:deposit1"));
+        assert!(!subjects_of(&published[..completion]).contains(&TRANSPONDER_SET));
+        assert!(subjects_of(&published).contains(&"event.decs.the_void.player2.mining.completed"));
+
+        // Each player got exactly their own deposit, and the asteroid was depleted once the
+        // second deposit was gone
+        assert_eq!(added_stack_types(&published, "player1"), vec!["tasty"]);
+        assert_eq!(added_stack_types(&published, "player2"), vec!["critical"]);
+        let released: Vec<&str> = published
+            .iter()
+            .filter(|(s, _)| s.ends_with("mining_locks.delete"))
+            .map(|(_, payload)| payload["params"]["rid"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            released,
+            vec![
+                "decs.components.the_void.asteroid1.mining_locks.deposit1",
+                "decs.components.the_void.asteroid1.mining_locks.deposit2",
+            ]
+        );
+        assert_eq!(
+            subjects_of(&published)
+                .iter()
+                .filter(|s| **s == TRANSPONDER_SET)
+                .count(),
+            1
+        );
+        assert!(
+            ctx.lists.borrow()["decs:components:the_void:asteroid1:mining_resources"].is_empty()
+        );
+    }
+
+    #[test]
+    fn test_deposits_taken_in_the_same_frame_deplete_the_asteroid() {
+        let ctx = shared_asteroid();
+        // Both extractions are one frame from completion
+        ctx.values.borrow_mut().insert(
+            "decs:components:the_void:player2:extractors:1".to_string(),
+            json!({
+                "target": "decs.components.the_void.asteroid1.mining_resources.deposit2",
+                "remaining_ms": 500.0,
+                "total_ms": 4000.0
+            })
+            .to_string(),
+        );
+        handle_frame(&ctx, player_frame("player1")).unwrap();
+        apply_component_calls(&ctx);
+        handle_frame(&ctx, player_frame("player1")).unwrap();
+        // Player 2 completes before the deletion of player 1's deposit has been applied
+        handle_frame(&ctx, player_frame("player2")).unwrap();
+        handle_frame(&ctx, player_frame("player2")).unwrap();
+        let published = apply_component_calls(&ctx);
+
+        assert_eq!(added_stack_types(&published, "player1"), vec!["tasty"]);
+        assert_eq!(added_stack_types(&published, "player2"), vec!["critical"]);
+        assert_eq!(
+            subjects_of(&published)
+                .iter()
+                .filter(|s| **s == TRANSPONDER_SET)
+                .count(),
+            1
+        );
+        assert!(!ctx
+            .sets
+            .borrow()
+            .contains_key("decs:mining:taken:the_void:asteroid1"));
     }
 }
@@ -0,0 +1,134 @@
+//! # Pause and Resume
+//!
+//! Gameplay such as combat can suspend an extraction without cancelling it. A call on
+//! `call.decs.{shard}.{entity}.mining.pause` or `call.decs.{shard}.{entity}.mining.resume` sets
+//! each of the entity's extractors with `paused` flipped accordingly and replies with the updated
+//! extractors.
+//! A paused extractor makes no progress and can't complete until it is resumed.
+use crate::capabilities::*;
+use crate::mining::{extractor_params, get_extractor_rids, publish_extractor};
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::migration::migrate_component;
+
+const PAUSE: &str = "pause";
+const RESUME: &str = "resume";
+
+/// Receives calls on `call.decs.{shard}.{entity}.mining.pause` and `.resume`
+pub(crate) fn handle_call(ctx: &impl Capabilities, msg: messaging::BrokerMessage) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let (shard, entity_id, paused) = match subject.as_slice() {
+        ["call", "decs", shard, entity_id, "mining", action] if *action == PAUSE => {
+            (*shard, *entity_id, true)
+        }
+        ["call", "decs", shard, entity_id, "mining", action] if *action == RESUME => {
+            (*shard, *entity_id, false)
+        }
+        _ => return Err(format!("Unexpected mining call subject: {}", msg.subject).into()),
+    };
+
+    let mut extractors = Vec::new();
+    for extractor_rid in get_extractor_rids(ctx, shard, entity_id)? {
+        if let Some(extractor_str) = ctx.kv().get(&extractor_rid.replace('.', ":"))? {
+            // A player's pause or resume takes over from overheating, so the extractor doesn't
+            // resume by itself once it has cooled off
+            let extractor = MiningExtractor {
+                paused,
+                overheated: false,
+                ..migrate_component(&extractor_str)?.into_inner()
+            };
+            publish_extractor(ctx, &extractor, &extractor_rid)?;
+            extractors.push(extractor_params(&extractor)?);
+        }
+    }
+    let response = if extractors.is_empty() {
+        json!({
+            "error": {
+                "code": "system.notFound",
+                "message": format!("entity {} has no extractor", entity_id)
+            }
+        })
+    } else {
+        json!({ "result": extractors })
+    };
+    if !msg.reply_to.is_empty() {
+        ctx.msg()
+            .publish(&msg.reply_to, None, &serde_json::to_vec(&response)?)?;
+    }
+
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle_call;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const EXTRACTOR_RID: &str = "decs.components.the_void.player1.extractors.1";
+
+    fn call(action: &str) -> BrokerMessage {
+        BrokerMessage {
+            subject: format!("call.decs.the_void.player1.mining.{}", action),
+            reply_to: "_INBOX.reply1".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pause_sets_extractor_and_replies() {
+        let ctx = MockCapabilitiesContext::new().with_extractor(
+            EXTRACTOR_RID,
+            json!({
+                "target": "decs.components.the_void.asteroid1.mining_resource",
+                "remaining_ms": 300.0,
+                "total_ms": 1200.0
+            }),
+        );
+        handle_call(&ctx, call("pause")).unwrap();
+
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "call.decs.components.the_void.player1.extractors.1.set",
+                "_INBOX.reply1"
+            ]
+        );
+        let set = ctx
+            .payload("call.decs.components.the_void.player1.extractors.1.set")
+            .unwrap();
+        assert_eq!(set["params"]["paused"], true);
+        assert_eq!(set["params"]["remaining_ms"], 300.0);
+        let reply = ctx.payload("_INBOX.reply1").unwrap();
+        assert_eq!(reply["result"], json!([set["params"]]));
+    }
+
+    #[test]
+    fn test_resume_clears_pause() {
+        let ctx = MockCapabilitiesContext::new().with_extractor(
+            EXTRACTOR_RID,
+            json!({
+                "target": "decs.components.the_void.asteroid1.mining_resource",
+                "remaining_ms": 300.0,
+                "total_ms": 1200.0,
+                "paused": true
+            }),
+        );
+        handle_call(&ctx, call("resume")).unwrap();
+
+        let reply = ctx.payload("_INBOX.reply1").unwrap();
+        assert_eq!(reply["result"][0]["paused"], false);
+    }
+
+    #[test]
+    fn test_pause_without_extractor_replies_with_error() {
+        let ctx = MockCapabilitiesContext::new();
+        handle_call(&ctx, call("pause")).unwrap();
+
+        assert_eq!(ctx.subjects(), vec!["_INBOX.reply1"]);
+        let reply = ctx.payload("_INBOX.reply1").unwrap();
+        assert_eq!(reply["error"]["code"], "system.notFound");
+        assert!(handle_call(&ctx, call("stop")).is_err());
+    }
+}
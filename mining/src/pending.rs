@@ -0,0 +1,65 @@
+//! # Pending Deliveries
+//!
+//! A completing extraction takes the deposit off the asteroid before it adds the resource to the
+//! inventory, so no failed publish can leave the resource in both places. Before the deposit is
+//! deleted, the inventory addition is written to the KV key
+//! `decs:mining:pending:{shard}:{entity}:{target}`. If the inventory publish then fails, the
+//! extractor is left in place and the next frame delivers the pending resource instead of mining
+//! a deposit that is already gone. The key is deleted once the resource is in the inventory.
+use crate::capabilities::*;
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use trader::components::*;
+
+/// A resource taken from its deposit that hasn't reached the inventory yet
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct PendingDelivery {
+    pub inventory_add: (String, Value), // Subject and payload adding the resource to the inventory
+    pub mined: MiningResource,          // What the inventory receives
+    pub resource: MiningResource,       // The deposit as it was before it was mined
+    #[serde(default)]
+    pub mined_at_ms: u64, // Game time the deposit was taken
+    #[serde(default)]
+    pub overshoot_ms: f64, // Frame time left over once the extraction completed
+}
+
+/// Retrieve the entity's pending delivery from the target, if there is one
+pub(crate) fn get_pending(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    target: &str,
+) -> std::result::Result<Option<PendingDelivery>, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&pending_key(shard, entity_id, target))? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn save_pending(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    target: &str,
+    pending: &PendingDelivery,
+) -> Result<()> {
+    ctx.kv().set(
+        &pending_key(shard, entity_id, target),
+        &serde_json::to_string(pending)?,
+        None,
+    )
+}
+
+pub(crate) fn clear_pending(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    target: &str,
+) -> Result<()> {
+    ctx.kv().del_key(&pending_key(shard, entity_id, target))
+}
+
+fn pending_key(shard: &str, entity_id: &str, target: &str) -> String {
+    format!("decs:mining:pending:{}:{}:{}", shard, entity_id, target)
+}
@@ -0,0 +1,134 @@
+//! # Message Queue
+//!
+//! Completing an extraction takes several publishes, and a failed publish returned with `?`
+//! would leave the ones after it unsent: a resource could reach the inventory while its deposit
+//! is never deleted. Messages are collected in a `MessageQueue` instead and flushed together.
+//! Every publish is attempted, and those that failed are reported on
+//! `event.decs.system.mining.publish_errors`.
+use crate::capabilities::*;
+use guest::prelude::*;
+use serde_json::Value;
+
+const PUBLISH_ERRORS_EVENT: &str = "event.decs.system.mining.publish_errors";
+
+/// A publish that failed when its queue was flushed
+#[derive(Debug, PartialEq)]
+pub(crate) struct PublishError {
+    pub subject: String,
+    pub error: String,
+}
+
+/// Messages (subject and payload) waiting to be published, in order
+#[derive(Debug, Default)]
+pub(crate) struct MessageQueue {
+    messages: Vec<(String, Vec<u8>)>,
+}
+
+impl MessageQueue {
+    pub fn new() -> Self {
+        MessageQueue::default()
+    }
+
+    pub fn push(&mut self, subject: &str, payload: &Value) -> Result<()> {
+        self.messages
+            .push((subject.to_string(), serde_json::to_vec(payload)?));
+        Ok(())
+    }
+
+    pub fn push_message(&mut self, (subject, payload): (String, Value)) -> Result<()> {
+        self.push(&subject, &payload)
+    }
+
+    /// Attempts every publish in order, returning the ones that failed
+    pub fn flush(self, ctx: &impl Capabilities) -> Vec<PublishError> {
+        self.messages
+            .into_iter()
+            .filter_map(|(subject, payload)| {
+                ctx.msg()
+                    .publish(&subject, None, &payload)
+                    .err()
+                    .map(|e| PublishError {
+                        subject,
+                        error: e.to_string(),
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Flushes the queue and publishes the failed publishes, if there were any, on
+/// `event.decs.system.mining.publish_errors`
+pub(crate) fn flush_and_report(
+    ctx: &impl Capabilities,
+    queue: MessageQueue,
+    shard: &str,
+    entity_id: &str,
+) -> Result<()> {
+    let errors = queue.flush(ctx);
+    if errors.is_empty() {
+        return Ok(());
+    }
+    ctx.log(&format!(
+        "{} publishes failed for entity {}",
+        errors.len(),
+        entity_id
+    ));
+    let errors: Vec<Value> = errors
+        .iter()
+        .map(|e| json!({ "subject": e.subject, "error": e.error }))
+        .collect();
+    ctx.msg().publish(
+        PUBLISH_ERRORS_EVENT,
+        None,
+        &serde_json::to_vec(&json!({
+            "shard": shard,
+            "entity": entity_id,
+            "errors": errors
+        }))?,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::flush_and_report;
+    use super::MessageQueue;
+    use super::PUBLISH_ERRORS_EVENT;
+    use crate::test_utils::MockCapabilitiesContext;
+
+    #[test]
+    fn test_failed_publish_does_not_stop_the_rest() {
+        let ctx = MockCapabilitiesContext::new().with_failing_subject("second");
+        let mut queue = MessageQueue::new();
+        for subject in &["first", "second", "third"] {
+            queue.push(subject, &json!({ "n": subject })).unwrap();
+        }
+
+        let errors = queue.flush(&ctx);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].subject, "second");
+        assert_eq!(ctx.subjects(), vec!["first", "third"]);
+        assert_eq!(ctx.payload("third"), Some(json!({ "n": "third" })));
+    }
+
+    #[test]
+    fn test_failed_publishes_are_reported() {
+        let ctx = MockCapabilitiesContext::new().with_failing_subject("second");
+        let mut queue = MessageQueue::new();
+        for subject in &["first", "second", "third"] {
+            queue.push(subject, &json!({})).unwrap();
+        }
+
+        flush_and_report(&ctx, queue, "the_void", "player1").unwrap();
+        assert_eq!(ctx.subjects(), vec!["first", "third", PUBLISH_ERRORS_EVENT]);
+        let report = ctx.payload(PUBLISH_ERRORS_EVENT).unwrap();
+        assert_eq!(report["entity"], "player1");
+        assert_eq!(report["errors"][0]["subject"], "second");
+
+        // Nothing is reported when every publish succeeds
+        let ctx = MockCapabilitiesContext::new();
+        let mut queue = MessageQueue::new();
+        queue.push("first", &json!({})).unwrap();
+        flush_and_report(&ctx, queue, "the_void", "player1").unwrap();
+        assert_eq!(ctx.subjects(), vec!["first"]);
+    }
+}
@@ -0,0 +1,217 @@
+//! # Mining Range
+//!
+//! A mining beam only reaches so far. When both the miner and the asteroid it is mining have a
+//! position, the extraction is abandoned as soon as they are further apart than the shard's
+//! mining range, which defaults to 10 km and can be changed per shard under the KV key
+//! `decs:{shard}:mining:range`. A `mining_upgrade` component extends the range of its owner's
+//! beam by its `range_multiplier`.
+use crate::cache::{fetch_component, FrameCache};
+use crate::capabilities::*;
+use crate::mining::parse_target;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const DEFAULT_MINING_RANGE: f64 = 10.0;
+
+/// Retrieve the entity's mining upgrade with any unusable multiplier clamped to 1.0. Entities
+/// without an upgrade get one that changes nothing
+pub(crate) fn get_mining_upgrade(
+    ctx: &impl Capabilities,
+    cache: &mut FrameCache,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<MiningUpgrade, Box<dyn std::error::Error>> {
+    let upgrade_value = fetch_component(
+        ctx,
+        cache,
+        &format!(
+            "decs:components:{}:{}:{}",
+            shard,
+            entity_id,
+            super::MINING_UPGRADE
+        ),
+    )?;
+    match upgrade_value {
+        Some(upgrade_str) => Ok(serde_json::from_str::<MiningUpgrade>(&upgrade_str)?.clamped()),
+        None => Ok(MiningUpgrade::default()),
+    }
+}
+
+/// Whether the extractor's target is within reach of the miner's beam. A miner or target
+/// without a position, or a target that doesn't name an entity on the shard, is never out of
+/// range
+pub(crate) fn target_in_range(
+    ctx: &impl Capabilities,
+    cache: &mut FrameCache,
+    extractor: &MiningExtractor,
+    shard: &str,
+    entity_id: &str,
+    upgrade: &MiningUpgrade,
+) -> std::result::Result<bool, Box<dyn std::error::Error>> {
+    let target = match parse_target(&extractor.target, shard) {
+        Ok(target) => target,
+        Err(_) => return Ok(true),
+    };
+    let miner = get_position(ctx, cache, shard, entity_id)?;
+    let asteroid = get_position(ctx, cache, shard, target.entity())?;
+    let range = mining_range(ctx, shard)? * upgrade.range_multiplier;
+    Ok(in_range(miner.as_ref(), asteroid.as_ref(), range))
+}
+
+/// The mining range of the shard, stored in the KV store under `decs:{shard}:mining:range`
+pub(crate) fn mining_range(ctx: &impl Capabilities, shard: &str) -> Result<f64> {
+    let configured = ctx
+        .kv()
+        .get(&format!("decs:{}:mining:range", shard))?
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|range| *range >= 0.0);
+    Ok(configured.unwrap_or(DEFAULT_MINING_RANGE))
+}
+
+pub(crate) fn get_position(
+    ctx: &impl Capabilities,
+    cache: &mut FrameCache,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<Option<Position>, Box<dyn std::error::Error>> {
+    let position_value = fetch_component(
+        ctx,
+        cache,
+        &format!(
+            "decs:components:{}:{}:{}",
+            shard,
+            entity_id,
+            super::POSITION
+        ),
+    )?;
+    match position_value {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+fn in_range(miner: Option<&Position>, target: Option<&Position>, range: f64) -> bool {
+    match (miner, target) {
+        (Some(miner), Some(target)) => miner.distance_to_3d(target) <= range,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::in_range;
+    use super::MiningUpgrade;
+    use super::Position;
+    use super::DEFAULT_MINING_RANGE;
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    fn context(asteroid_x: f64) -> MockCapabilitiesContext {
+        MockCapabilitiesContext::new()
+            .with_extractor(
+                "decs.components.the_void.player1.extractors.1",
+                json!({
+                    "target": "decs.components.the_void.asteroid1.mining_resource",
+                    "remaining_ms": 9000.0,
+                    "total_ms": 9000.0
+                }),
+            )
+            .with_value(
+                "decs:components:the_void:player1:position",
+                json!({ "x": 0.0, "y": 0.0, "z": 0.0 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:position",
+                json!({ "x": asteroid_x, "y": 0.0, "z": 0.0 }),
+            )
+    }
+
+    fn frame() -> BrokerMessage {
+        BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 12,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_upgrade_extends_range() {
+        let miner = Position::new(0.0, 0.0, 0.0);
+        let target = Position::new(10.5, 0.0, 0.0);
+        assert!(!in_range(Some(&miner), Some(&target), DEFAULT_MINING_RANGE));
+        assert!(in_range(
+            Some(&miner),
+            Some(&target),
+            DEFAULT_MINING_RANGE * 1.5
+        ));
+        // Nothing to measure without both positions
+        assert!(in_range(None, Some(&target), DEFAULT_MINING_RANGE));
+
+        // Just outside the base range, the extraction is abandoned
+        let ctx = context(10.5);
+        handle_frame(&ctx, frame()).unwrap();
+        let failed = ctx
+            .payload("event.decs.the_void.player1.mining.failed")
+            .unwrap();
+        assert_eq!(failed["reason"], "out_of_range");
+
+        // An upgraded beam keeps mining the same target
+        let ctx = context(10.5).with_value(
+            "decs:components:the_void:player1:mining_upgrade",
+            json!({ "range_multiplier": 1.5 }),
+        );
+        handle_frame(&ctx, frame()).unwrap();
+        assert!(ctx
+            .payload("event.decs.the_void.player1.mining.failed")
+            .is_none());
+        let extractor = ctx
+            .payload("call.decs.components.the_void.player1.extractors.1.set")
+            .unwrap();
+        assert_eq!(extractor["params"]["remaining_ms"], 8000.0);
+    }
+
+    #[test]
+    fn test_speed_stacks_with_efficiency() {
+        let ctx = context(1.0)
+            .with_value(
+                "decs:components:the_void:player1:mining_efficiency",
+                json!({ "multiplier": 2.0 }),
+            )
+            .with_value(
+                "decs:components:the_void:player1:mining_upgrade",
+                json!({ "speed_multiplier": 1.5 }),
+            );
+        handle_frame(&ctx, frame()).unwrap();
+        let extractor = ctx
+            .payload("call.decs.components.the_void.player1.extractors.1.set")
+            .unwrap();
+        // 1000ms at 2.0 * 1.5
+        assert_eq!(extractor["params"]["remaining_ms"], 6000.0);
+    }
+
+    #[test]
+    fn test_invalid_multipliers_are_clamped() {
+        let upgrade = MiningUpgrade {
+            range_multiplier: -2.0,
+            speed_multiplier: f64::NAN,
+            yield_multiplier: f64::INFINITY,
+        };
+        assert_eq!(upgrade.clamped(), MiningUpgrade::default());
+        let upgrade = MiningUpgrade {
+            range_multiplier: 0.0,
+            speed_multiplier: 3.0,
+            yield_multiplier: 1.0,
+        };
+        assert_eq!(upgrade.clamped().range_multiplier, 1.0);
+        assert_eq!(upgrade.clamped().speed_multiplier, 3.0);
+    }
+}
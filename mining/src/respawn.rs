@@ -0,0 +1,372 @@
+//! # Asteroid Respawn
+//!
+//! When the last deposit on an asteroid is mined, the mining system writes an `asteroid_respawn`
+//! marker to the asteroid holding the depleted resource and the asteroid's original transponder.
+//! The respawn system awaits frames for entities with that marker. The first time a marker is
+//! seen it is stamped with a `respawn_at_ms` of the current game time plus its cooldown. Once that
+//! time has passed, the transponder is restored, the deposit is re-created and the marker deleted.
+use crate::capabilities::*;
+use crate::scarcity::replenish_supply;
+use crate::transponder::{backup_key, original_transponder};
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const TRANSPONDER: &str = "transponder";
+
+pub(crate) fn handle_frame(ctx: &impl Capabilities, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+    let now_ms = trader::clock::frame_time_ms(&frame, super::FRAMERATE);
+
+    if let Some(marker) = get_marker(ctx, &frame.shard, &frame.entity_id)? {
+        if marker.is_due(now_ms) {
+            respawn_asteroid(ctx, &frame.shard, &frame.entity_id)?;
+        } else if let Some(stamped) = stamp_marker(&marker, now_ms) {
+            ctx.msg().publish(
+                &format!("call.{}.set", marker_rid(&frame.shard, &frame.entity_id)),
+                None,
+                &serde_json::to_vec(&json!({ "params": stamped }))?,
+            )?;
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// Brings a depleted asteroid back: its original transponder is published, then its deposit and
+/// finally the respawn marker is deleted, and the deposit is returned to its resource's supply. Publishing in that order, and stopping at the first
+/// publish that fails, means the asteroid never holds a resource while its transponder still
+/// reads depleted, and a marker is only gone once the asteroid is fully restored, so the next
+/// frame retries whatever didn't go out. Asteroids without a marker are left alone
+pub(crate) fn respawn_asteroid(
+    ctx: &impl Capabilities,
+    shard: &str,
+    asteroid_id: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let marker = match get_marker(ctx, shard, asteroid_id)? {
+        Some(marker) => marker,
+        None => return Ok(()),
+    };
+    let original = original_transponder(ctx, shard, asteroid_id, &marker.transponder)?;
+    for (subject, payload) in plan_respawn(shard, asteroid_id, &marker, &original) {
+        ctx.msg()
+            .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+    }
+    ctx.kv().del_key(&backup_key(shard, asteroid_id))?;
+    replenish_supply(ctx, shard, &marker.resource)?;
+    ctx.log(&format!("Asteroid {} respawned", asteroid_id));
+    Ok(())
+}
+
+fn get_marker(
+    ctx: &impl Capabilities,
+    shard: &str,
+    asteroid_id: &str,
+) -> std::result::Result<Option<AsteroidRespawn>, Box<dyn std::error::Error>> {
+    match ctx
+        .kv()
+        .get(&marker_rid(shard, asteroid_id).replace('.', ":"))?
+    {
+        Some(marker_str) => Ok(Some(serde_json::from_str(&marker_str)?)),
+        None => Ok(None),
+    }
+}
+
+fn marker_rid(shard: &str, entity_id: &str) -> String {
+    format!(
+        "decs.components.{}.{}.{}",
+        shard,
+        entity_id,
+        super::ASTEROID_RESPAWN
+    )
+}
+
+/// Writes the respawn marker to a freshly depleted asteroid, applying the asteroid's
+/// `resource_respawn` settings if it has any
+pub(crate) fn schedule_respawn(
+    ctx: &impl Capabilities,
+    shard: &str,
+    asteroid_entity_id: &str,
+    target: &str,
+    resource: &MiningResource,
+    transponder: RadarTransponder,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let settings: ResourceRespawn = match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard,
+        asteroid_entity_id,
+        super::RESOURCE_RESPAWN
+    ))? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => ResourceRespawn::default(),
+    };
+    let marker = respawn_marker(target, resource, transponder, &settings);
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard,
+            asteroid_entity_id,
+            super::ASTEROID_RESPAWN
+        ),
+        None,
+        &serde_json::to_vec(&json!({ "params": marker }))?,
+    )?;
+    Ok(())
+}
+
+fn respawn_marker(
+    target: &str,
+    resource: &MiningResource,
+    transponder: RadarTransponder,
+    settings: &ResourceRespawn,
+) -> AsteroidRespawn {
+    AsteroidRespawn {
+        target: target.to_string(),
+        resource: MiningResource {
+            qty: settings.qty.unwrap_or(resource.qty),
+            ..resource.clone()
+        },
+        transponder,
+        cooldown_ms: settings.cooldown_ms,
+        respawn_at_ms: None,
+    }
+}
+
+/// The marker stamped with the game time its cooldown ends, or nothing if it already has one
+fn stamp_marker(marker: &AsteroidRespawn, now_ms: u64) -> Option<AsteroidRespawn> {
+    match marker.respawn_at_ms {
+        Some(_) => None,
+        None => Some(AsteroidRespawn {
+            respawn_at_ms: Some(now_ms + marker.cooldown_ms),
+            ..marker.clone()
+        }),
+    }
+}
+
+/// Produces the messages (subject and payload) that respawn an asteroid, in the order they must
+/// be published: the original transponder, the deposit and the marker's deletion
+fn plan_respawn(
+    shard: &str,
+    entity_id: &str,
+    marker: &AsteroidRespawn,
+    original: &RadarTransponder,
+) -> Vec<(String, Value)> {
+    let marker_rid = marker_rid(shard, entity_id);
+    // Deposits that lived in a `mining_resources` collection are added back as a new item
+    let deposit_subject = match super::mining::deposit_collection(&marker.target) {
+        Some(collection) => format!("call.{}.new", collection),
+        None => format!("call.{}.set", marker.target),
+    };
+    vec![
+        (
+            format!(
+                "call.decs.components.{}.{}.{}.set",
+                shard, entity_id, TRANSPONDER
+            ),
+            json!({ "params": original }),
+        ),
+        (deposit_subject, json!({ "params": marker.resource })),
+        (
+            format!("call.{}.delete", marker_rid),
+            json!({ "params": { "rid": marker_rid } }),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle_frame;
+    use super::plan_respawn;
+    use super::respawn_asteroid;
+    use super::respawn_marker;
+    use super::stamp_marker;
+    use super::AsteroidRespawn;
+    use super::MiningResource;
+    use super::RadarTransponder;
+    use super::ResourceRespawn;
+    use crate::test_utils::MockCapabilitiesContext;
+    use crate::transponder::{deplete_transponder, depleted_transponder, DepletedAppearance};
+    use guest::prelude::messaging::BrokerMessage;
+
+    const TARGET: &str = "decs.components.the_void.asteroid1.mining_resource";
+    const TRANSPONDER_SUBJECT: &str = "call.decs.components.the_void.asteroid1.transponder.set";
+    const MARKER_KEY: &str = "decs:components:the_void:asteroid1:asteroid_respawn";
+
+    fn resource() -> MiningResource {
+        MiningResource {
+            stack_type: "spendy".to_string(),
+            qty: 12,
+            ..MiningResource::default()
+        }
+    }
+
+    fn transponder() -> RadarTransponder {
+        RadarTransponder {
+            object_type: "asteroid".to_string(),
+            display_name: "Ceres".to_string(),
+            color: "#FFA500".to_string(),
+            radar_signature: 1.0,
+            depleted: false,
+        }
+    }
+
+    fn due_marker(transponder: RadarTransponder) -> AsteroidRespawn {
+        AsteroidRespawn {
+            respawn_at_ms: Some(0),
+            ..respawn_marker(
+                TARGET,
+                &resource(),
+                transponder,
+                &ResourceRespawn::default(),
+            )
+        }
+    }
+
+    fn params(ctx: &MockCapabilitiesContext, index: usize) -> serde_json::Value {
+        let (_, payload) = ctx.published.borrow()[index].clone();
+        serde_json::from_slice::<serde_json::Value>(&payload).unwrap()["params"].clone()
+    }
+
+    #[test]
+    fn test_deplete_wait_respawn_cycle() {
+        let original = transponder();
+        let depleted = depleted_transponder(&original, &DepletedAppearance::default());
+        assert_eq!(depleted.display_name, "Ceres (depleted)");
+
+        let settings = ResourceRespawn {
+            cooldown_ms: 60_000,
+            qty: None,
+        };
+        let marker = respawn_marker(TARGET, &resource(), original.clone(), &settings);
+
+        // The first frame after depletion starts the cooldown
+        let marker = stamp_marker(&marker, 5_000).unwrap();
+        assert_eq!(marker.respawn_at_ms, Some(65_000));
+        assert!(stamp_marker(&marker, 6_000).is_none());
+        assert!(!marker.is_due(64_999));
+        assert!(marker.is_due(65_000));
+
+        let publishes = plan_respawn("the_void", "asteroid1", &marker, &original);
+        let subjects: Vec<&str> = publishes.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(
+            subjects,
+            vec![
+                TRANSPONDER_SUBJECT,
+                "call.decs.components.the_void.asteroid1.mining_resource.set",
+                "call.decs.components.the_void.asteroid1.asteroid_respawn.delete",
+            ]
+        );
+        let respawned: MiningResource =
+            serde_json::from_value(publishes[1].1["params"].clone()).unwrap();
+        assert_eq!(respawned, resource());
+        assert_eq!(marker.transponder, original);
+    }
+
+    #[test]
+    fn test_respawn_restores_the_backed_up_transponder() {
+        let ctx = MockCapabilitiesContext::new()
+            .with_value(
+                "decs:components:the_void:asteroid1:transponder",
+                serde_json::to_value(transponder()).unwrap(),
+            )
+            .with_value(
+                MARKER_KEY,
+                serde_json::to_value(due_marker(transponder())).unwrap(),
+            );
+        deplete_transponder(&ctx, "the_void", "asteroid1").unwrap();
+
+        let frame = json!({
+            "seq_no": 12,
+            "elapsed_ms": 1000,
+            "shard": "the_void",
+            "system": "asteroid_respawn",
+            "entity_id": "asteroid1"
+        });
+        let msg = BrokerMessage {
+            subject: "decs.frames.the_void.asteroid_respawn".to_string(),
+            body: serde_json::to_vec(&frame).unwrap(),
+            ..Default::default()
+        };
+        handle_frame(&ctx, msg).unwrap();
+
+        // The transponder is back to what it was before depletion before the asteroid holds a
+        // resource again
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                TRANSPONDER_SUBJECT,
+                TRANSPONDER_SUBJECT,
+                "call.decs.components.the_void.asteroid1.mining_resource.set",
+                "call.decs.components.the_void.asteroid1.asteroid_respawn.delete",
+            ]
+        );
+        let restored: RadarTransponder = serde_json::from_value(params(&ctx, 1)).unwrap();
+        assert_eq!(restored, transponder());
+        assert!(!ctx
+            .values
+            .borrow()
+            .contains_key("decs:components:the_void:asteroid1:transponder_original"));
+    }
+
+    #[test]
+    fn test_respawn_without_backup_synthesizes_transponder() {
+        // The marker was written with an asteroid that was already depleted
+        let depleted = depleted_transponder(&transponder(), &DepletedAppearance::default());
+        let ctx = MockCapabilitiesContext::new().with_value(
+            MARKER_KEY,
+            serde_json::to_value(due_marker(depleted)).unwrap(),
+        );
+        respawn_asteroid(&ctx, "the_void", "asteroid1").unwrap();
+
+        assert_eq!(ctx.subjects()[0], TRANSPONDER_SUBJECT);
+        let restored: RadarTransponder = serde_json::from_value(params(&ctx, 0)).unwrap();
+        assert_eq!(restored.display_name, "Ceres");
+        assert_eq!(restored.color, "#FFFFFF");
+        assert!(!restored.depleted);
+    }
+
+    #[test]
+    fn test_failed_transponder_restore_keeps_asteroid_depleted() {
+        let ctx = MockCapabilitiesContext::new()
+            .with_value(
+                MARKER_KEY,
+                serde_json::to_value(due_marker(transponder())).unwrap(),
+            )
+            .with_failing_subject(TRANSPONDER_SUBJECT);
+        assert!(respawn_asteroid(&ctx, "the_void", "asteroid1").is_err());
+
+        // Neither the deposit nor the marker's deletion go out, the next frame tries again
+        assert!(ctx.subjects().is_empty());
+
+        // Asteroids without a marker have nothing to respawn
+        let ctx = MockCapabilitiesContext::new();
+        respawn_asteroid(&ctx, "the_void", "asteroid1").unwrap();
+        assert!(ctx.subjects().is_empty());
+    }
+
+    #[test]
+    fn test_respawn_quantity_is_configurable() {
+        let settings = ResourceRespawn {
+            cooldown_ms: 1_000,
+            qty: Some(40),
+        };
+        let marker = respawn_marker(TARGET, &resource(), transponder(), &settings);
+        assert_eq!(marker.resource.qty, 40);
+        assert_eq!(marker.resource.stack_type, "spendy");
+        assert_eq!(marker.cooldown_ms, 1_000);
+    }
+
+    #[test]
+    fn test_collection_deposit_respawns_as_new_item() {
+        let marker = AsteroidRespawn {
+            target: "decs.components.the_void.asteroid1.mining_resources.deposit2".to_string(),
+            ..due_marker(transponder())
+        };
+        let publishes = plan_respawn("the_void", "asteroid1", &marker, &transponder());
+        assert_eq!(
+            publishes[1].0,
+            "call.decs.components.the_void.asteroid1.mining_resources.new"
+        );
+    }
+}
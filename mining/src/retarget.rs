@@ -0,0 +1,330 @@
+//! # Retargeting
+//!
+//! An extractor can be pointed at a different resource without deleting it, e.g. when its
+//! asteroid is destroyed or the player changes their mind. A call on
+//! `call.decs.{shard}.{entity}.mining.retarget` names the new target in its params and,
+//! optionally, which of the entity's extractors to retarget:
+//!
+//! ```json
+//! { "params": { "target": "decs.components.the_void.asteroid2.mining_resource" } }
+//! ```
+//!
+//! The new target must hold a resource and must not be locked by another entity. The entity's
+//! lock on the old target is released, a lock on the new one is taken and the extraction
+//! starts over with the duration of the new resource. The call replies with the updated
+//! extractor, or with an error describing why the extractor was left as it was.
+use crate::capabilities::*;
+use crate::duration::extraction_duration_ms;
+use crate::lock::{get_lock, lock_rid, verify_lock_owner};
+use crate::mining::{
+    extractor_params, get_extractor_rids, lock_release_message, parse_target, publish_extractor,
+};
+use crate::queue::{flush_and_report, MessageQueue};
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::migration::migrate_component;
+use trader::units::Millis;
+
+const NOT_FOUND: &str = "system.notFound";
+const INVALID_PARAMS: &str = "system.invalidParams";
+const ACCESS_DENIED: &str = "system.accessDenied";
+
+/// The error code and message a call is rejected with
+type Rejection = (&'static str, String);
+
+/// Receives calls on `call.decs.{shard}.{entity}.mining.retarget`
+pub(crate) fn handle_call(ctx: &impl Capabilities, msg: messaging::BrokerMessage) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let (shard, entity_id) = match subject.as_slice() {
+        ["call", "decs", shard, entity_id, "mining", "retarget"] => (*shard, *entity_id),
+        _ => return Err(format!("Unexpected mining call subject: {}", msg.subject).into()),
+    };
+
+    let response = match retarget(ctx, &msg.body, shard, entity_id)? {
+        Ok(extractor) => json!({ "result": extractor }),
+        Err((code, message)) => json!({
+            "error": {
+                "code": code,
+                "message": message
+            }
+        }),
+    };
+    if !msg.reply_to.is_empty() {
+        ctx.msg()
+            .publish(&msg.reply_to, None, &serde_json::to_vec(&response)?)?;
+    }
+
+    Ok(vec![])
+}
+
+/// Retargets the extractor, returning the published extractor or the error code and message
+/// the call is rejected with
+fn retarget(
+    ctx: &impl Capabilities,
+    body: &[u8],
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<std::result::Result<Value, Rejection>, Box<dyn std::error::Error>> {
+    let params: Value = serde_json::from_slice::<Value>(body)
+        .map(|body| body["params"].clone())
+        .unwrap_or(Value::Null);
+    let target = match params["target"].as_str() {
+        Some(target) => target,
+        None => return Ok(Err((INVALID_PARAMS, "missing target".to_string()))),
+    };
+    let new_target = match parse_target(target, shard) {
+        Ok(new_target) => new_target,
+        Err((_, message)) => return Ok(Err((INVALID_PARAMS, message))),
+    };
+
+    let extractor_rid = match choose_extractor(
+        &get_extractor_rids(ctx, shard, entity_id)?,
+        params["extractor"].as_str(),
+    ) {
+        Ok(rid) => rid,
+        Err(error) => return Ok(Err(error)),
+    };
+    let extractor: MiningExtractor = match ctx.kv().get(&extractor_rid.replace('.', ":"))? {
+        Some(extractor_str) => migrate_component(&extractor_str)?.into_inner(),
+        None => {
+            return Ok(Err((
+                NOT_FOUND,
+                format!("extractor {} not found", extractor_rid),
+            )))
+        }
+    };
+    let resource: MiningResource = match ctx.kv().get(&new_target.to_key())? {
+        Some(resource_str) => serde_json::from_str(&resource_str)?,
+        None => return Ok(Err((NOT_FOUND, format!("{} holds no resource", target)))),
+    };
+    let new_lock_rid = lock_rid(shard, new_target.entity(), target);
+    let new_lock = get_lock(ctx, &new_lock_rid)?;
+    if new_lock
+        .as_ref()
+        .is_some_and(|lock| verify_lock_owner(Some(lock), entity_id).is_err())
+    {
+        return Ok(Err((
+            ACCESS_DENIED,
+            format!("{} is locked by another entity", target),
+        )));
+    }
+    // The lock on the old target is only released if it is ours, and not at all when the new
+    // target is guarded by the same lock
+    let old_lock_rid = parse_target(&extractor.target, shard)
+        .ok()
+        .map(|old_target| lock_rid(shard, old_target.entity(), &extractor.target))
+        .filter(|old| *old != new_lock_rid);
+    let release_old = match &old_lock_rid {
+        Some(old) => verify_lock_owner(get_lock(ctx, old)?.as_ref(), entity_id).is_ok(),
+        None => false,
+    };
+
+    let retargeted = plan_retarget(&extractor, target, &resource);
+    let mut queue = MessageQueue::new();
+    if let (true, Some(old)) = (release_old, &old_lock_rid) {
+        queue.push_message(lock_release_message(old))?;
+    }
+    if new_lock.is_none() {
+        queue.push_message(lock_acquire_message(
+            &new_lock_rid,
+            entity_id,
+            &extractor_rid,
+        ))?;
+    }
+    flush_and_report(ctx, queue, shard, entity_id)?;
+    publish_extractor(ctx, &retargeted, &extractor_rid)?;
+    ctx.log(&format!(
+        "Entity {} retargeted its extractor from {} to {}",
+        entity_id, extractor.target, target
+    ));
+
+    Ok(Ok(extractor_params(&retargeted)?))
+}
+
+/// The extractor to retarget: the one named in the params, or the entity's only extractor
+fn choose_extractor(
+    rids: &[String],
+    requested: Option<&str>,
+) -> std::result::Result<String, Rejection> {
+    match (requested, rids) {
+        (Some(rid), _) if rids.iter().any(|r| r == rid) => Ok(rid.to_string()),
+        (Some(rid), _) => Err((NOT_FOUND, format!("extractor {} not found", rid))),
+        (None, [rid]) => Ok(rid.to_string()),
+        (None, []) => Err((NOT_FOUND, "entity has no extractor".to_string())),
+        (None, _) => Err((
+            INVALID_PARAMS,
+            "entity has several extractors, name one in the extractor param".to_string(),
+        )),
+    }
+}
+
+/// The extractor pointed at the new target, starting over with the new resource's duration
+fn plan_retarget(
+    extractor: &MiningExtractor,
+    target: &str,
+    resource: &MiningResource,
+) -> MiningExtractor {
+    let duration_ms = extraction_duration_ms(resource);
+    MiningExtractor {
+        target: target.to_string(),
+        remaining_ms: Millis(duration_ms),
+        total_ms: duration_ms,
+        ..extractor.clone()
+    }
+}
+
+/// A new lock stored under the lock rid, which the mining lock system stamps on its next frame
+pub(crate) fn lock_acquire_message(
+    lock_rid: &str,
+    entity_id: &str,
+    extractor_rid: &str,
+) -> (String, Value) {
+    let lock = MiningLock {
+        extractor: extractor_rid.to_string(),
+        owner: entity_id.to_string(),
+        ..MiningLock::default()
+    };
+    (format!("call.{}.set", lock_rid), json!({ "params": lock }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle_call;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const EXTRACTOR_RID: &str = "decs.components.the_void.player1.extractors.1";
+
+    fn context() -> MockCapabilitiesContext {
+        MockCapabilitiesContext::new()
+            .with_extractor(
+                EXTRACTOR_RID,
+                json!({
+                    "target": "decs.components.the_void.asteroid1.mining_resource",
+                    "remaining_ms": 300.0,
+                    "total_ms": 1200.0
+                }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid1:mining_lock",
+                json!({ "extractor": EXTRACTOR_RID, "owner": "player1", "created_ms": 0 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid2:mining_resource",
+                json!({ "stack_type": "critical", "qty": 4 }),
+            )
+    }
+
+    fn call(target: &str) -> BrokerMessage {
+        BrokerMessage {
+            subject: "call.decs.the_void.player1.mining.retarget".to_string(),
+            reply_to: "_INBOX.reply1".to_string(),
+            body: serde_json::to_vec(&json!({ "params": { "target": target } })).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_retarget_moves_lock_and_restarts_extraction() {
+        let ctx = context();
+        handle_call(
+            &ctx,
+            call("decs.components.the_void.asteroid2.mining_resource"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "call.decs.components.the_void.asteroid1.mining_lock.delete",
+                "call.decs.components.the_void.asteroid2.mining_lock.set",
+                "call.decs.components.the_void.player1.extractors.1.set",
+                "_INBOX.reply1",
+            ]
+        );
+        let lock = ctx
+            .payload("call.decs.components.the_void.asteroid2.mining_lock.set")
+            .unwrap();
+        assert_eq!(lock["params"]["extractor"], EXTRACTOR_RID);
+        assert_eq!(lock["params"]["owner"], "player1");
+        let set = ctx
+            .payload("call.decs.components.the_void.player1.extractors.1.set")
+            .unwrap();
+        assert_eq!(
+            set["params"]["target"],
+            "decs.components.the_void.asteroid2.mining_resource"
+        );
+        assert_eq!(set["params"]["remaining_ms"], 4000.0);
+        assert_eq!(set["params"]["total_ms"], 4000.0);
+        let reply = ctx.payload("_INBOX.reply1").unwrap();
+        assert_eq!(reply["result"], set["params"]);
+    }
+
+    #[test]
+    fn test_retarget_to_foreign_lock_is_rejected() {
+        let ctx = context().with_value(
+            "decs:components:the_void:asteroid2:mining_lock",
+            json!({
+                "extractor": "decs.components.the_void.player2.extractors.1",
+                "owner": "player2"
+            }),
+        );
+        handle_call(
+            &ctx,
+            call("decs.components.the_void.asteroid2.mining_resource"),
+        )
+        .unwrap();
+
+        // Nothing changes, the caller is told why
+        assert_eq!(ctx.subjects(), vec!["_INBOX.reply1"]);
+        let reply = ctx.payload("_INBOX.reply1").unwrap();
+        assert_eq!(reply["error"]["code"], "system.accessDenied");
+    }
+
+    #[test]
+    fn test_retarget_without_resource_is_rejected() {
+        let ctx = context();
+        handle_call(
+            &ctx,
+            call("decs.components.the_void.asteroid3.mining_resource"),
+        )
+        .unwrap();
+
+        assert_eq!(ctx.subjects(), vec!["_INBOX.reply1"]);
+        let reply = ctx.payload("_INBOX.reply1").unwrap();
+        assert_eq!(reply["error"]["code"], "system.notFound");
+    }
+
+    #[test]
+    fn test_retarget_to_free_deposit_beside_foreign_lock() {
+        let ctx = context()
+            .with_value(
+                "decs:components:the_void:asteroid3:mining_resources:deposit2",
+                json!({ "stack_type": "critical", "qty": 4 }),
+            )
+            .with_value(
+                "decs:components:the_void:asteroid3:mining_locks:deposit1",
+                json!({
+                    "extractor": "decs.components.the_void.player2.extractors.1",
+                    "owner": "player2"
+                }),
+            );
+        handle_call(
+            &ctx,
+            call("decs.components.the_void.asteroid3.mining_resources.deposit2"),
+        )
+        .unwrap();
+
+        // The other entity's deposit lock on the same asteroid is no obstacle
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "call.decs.components.the_void.asteroid1.mining_lock.delete",
+                "call.decs.components.the_void.asteroid3.mining_locks.deposit2.set",
+                "call.decs.components.the_void.player1.extractors.1.set",
+                "_INBOX.reply1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,289 @@
+//! # Mining Rig Upgrades
+//!
+//! Players improve their mining rig by applying `MiningRigUpgrade`s, usually kept in their
+//! `mining_rig_upgrades` collection. A call on `call.decs.{shard}.{entity}.mining.apply_upgrade`
+//! names the upgrade to apply, which multiplies the matching multiplier of the entity's
+//! `mining_upgrade` by the upgrade's magnitude and is then deleted. The upgrades applied so far
+//! are recorded in the entity's `applied_upgrades`. A rig takes at most `MAX_APPLIED_UPGRADES`,
+//! further upgrades are rejected, left in place and reported on
+//! `event.decs.system.mining.upgrade_rejected`.
+use crate::capabilities::*;
+use crate::mining::{member_collection, member_delete_message};
+use crate::queue::{flush_and_report, MessageQueue};
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::rid::parse_component_rid;
+
+const MINING_RIG_UPGRADES: &str = "mining_rig_upgrades";
+const APPLIED_UPGRADES: &str = "applied_upgrades";
+const UPGRADE_REJECTED_EVENT: &str = "event.decs.system.mining.upgrade_rejected";
+const MAX_UPGRADES: &str = "max_upgrades";
+
+/// Receives calls on `call.decs.{shard}.{entity}.mining.apply_upgrade`
+pub(crate) fn handle_apply_upgrade(
+    ctx: &impl Capabilities,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let (shard, entity_id) = match subject.as_slice() {
+        ["call", "decs", shard, entity_id, "mining", "apply_upgrade"] => (*shard, *entity_id),
+        _ => return Err(format!("Unexpected mining call subject: {}", msg.subject).into()),
+    };
+    let params = serde_json::from_slice::<Value>(&msg.body)
+        .map(|body| body["params"].clone())
+        .unwrap_or(Value::Null);
+
+    let response = match params["upgrade"].as_str() {
+        Some(rid) if owned_by(rid, shard, entity_id) => apply_upgrade(ctx, rid, shard, entity_id)?,
+        _ => error_response(
+            "system.invalidParams",
+            "upgrade must name one of the entity's mining rig upgrades",
+        ),
+    };
+    if !msg.reply_to.is_empty() {
+        ctx.msg()
+            .publish(&msg.reply_to, None, &serde_json::to_vec(&response)?)?;
+    }
+
+    Ok(vec![])
+}
+
+/// Applies the upgrade stored at the rid, returning the call's response
+fn apply_upgrade(
+    ctx: &impl Capabilities,
+    rid: &str,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<Value, Box<dyn std::error::Error>> {
+    let rig: MiningRigUpgrade = match ctx.kv().get(&rid.replace('.', ":"))? {
+        Some(rig_str) => serde_json::from_str(&rig_str)?,
+        None => {
+            return Ok(error_response(
+                "system.notFound",
+                &format!("upgrade {} not found", rid),
+            ))
+        }
+    };
+    let applied: AppliedUpgrades = get_component(ctx, shard, entity_id, APPLIED_UPGRADES)?;
+    let upgrade: MiningUpgrade = get_component(ctx, shard, entity_id, super::MINING_UPGRADE)?;
+
+    let (upgrade, applied) = match plan_upgrade(&upgrade, &applied, &rig) {
+        Some(planned) => planned,
+        None => {
+            ctx.log(&format!(
+                "Rejecting upgrade {} of entity {}, its rig is fully upgraded",
+                rid, entity_id
+            ));
+            ctx.msg().publish(
+                UPGRADE_REJECTED_EVENT,
+                None,
+                &serde_json::to_vec(&json!({
+                    "shard": shard,
+                    "entity": entity_id,
+                    "upgrade": rid,
+                    "reason": MAX_UPGRADES
+                }))?,
+            )?;
+            return Ok(error_response(
+                "system.accessDenied",
+                &format!("at most {} upgrades can be applied", MAX_APPLIED_UPGRADES),
+            ));
+        }
+    };
+
+    let mut queue = MessageQueue::new();
+    queue.push(
+        &set_subject(shard, entity_id, super::MINING_UPGRADE),
+        &json!({ "params": upgrade }),
+    )?;
+    queue.push(
+        &set_subject(shard, entity_id, APPLIED_UPGRADES),
+        &json!({ "params": applied }),
+    )?;
+    queue.push_message(member_delete_message(
+        rid,
+        member_collection(rid, MINING_RIG_UPGRADES),
+    ))?;
+    flush_and_report(ctx, queue, shard, entity_id)?;
+
+    Ok(json!({
+        "result": {
+            "mining_upgrade": upgrade,
+            "applied_upgrades": applied
+        }
+    }))
+}
+
+/// Whether the rid names a component, or an item of a collection, of the entity
+fn owned_by(rid: &str, shard: &str, entity_id: &str) -> bool {
+    parse_component_rid(rid)
+        .is_ok_and(|rid| rid.shard == shard && rid.entity == entity_id && !rid.path.is_empty())
+}
+
+/// Retrieve the entity's component, or its default if the entity doesn't have one
+fn get_component<T: serde::de::DeserializeOwned + Default>(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> std::result::Result<T, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, component
+    ))? {
+        Some(s) => Ok(serde_json::from_str(&s)?),
+        None => Ok(T::default()),
+    }
+}
+
+fn set_subject(shard: &str, entity_id: &str, component: &str) -> String {
+    format!(
+        "call.decs.components.{}.{}.{}.set",
+        shard, entity_id, component
+    )
+}
+
+fn error_response(code: &str, message: &str) -> Value {
+    json!({
+        "error": {
+            "code": code,
+            "message": message
+        }
+    })
+}
+
+/// The entity's mining upgrade and applied upgrades once the rig upgrade is applied, or nothing
+/// if the rig can't take another upgrade
+fn plan_upgrade(
+    upgrade: &MiningUpgrade,
+    applied: &AppliedUpgrades,
+    rig: &MiningRigUpgrade,
+) -> Option<(MiningUpgrade, AppliedUpgrades)> {
+    if applied.is_full() {
+        return None;
+    }
+    let mut upgrades = applied.upgrades.clone();
+    upgrades.push(rig.upgrade_type);
+    Some((upgrade.apply(rig), AppliedUpgrades { upgrades }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle_apply_upgrade;
+    use super::plan_upgrade;
+    use super::AppliedUpgrades;
+    use super::MiningRigUpgrade;
+    use super::MiningUpgrade;
+    use super::UpgradeType;
+    use super::UPGRADE_REJECTED_EVENT;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const UPGRADE_RID: &str = "decs.components.the_void.player1.mining_rig_upgrades.1";
+
+    fn rig(upgrade_type: UpgradeType, magnitude: f64) -> MiningRigUpgrade {
+        MiningRigUpgrade {
+            upgrade_type,
+            magnitude,
+        }
+    }
+
+    fn call() -> BrokerMessage {
+        BrokerMessage {
+            subject: "call.decs.the_void.player1.mining.apply_upgrade".to_string(),
+            reply_to: "_INBOX.reply1".to_string(),
+            body: serde_json::to_vec(&json!({ "params": { "upgrade": UPGRADE_RID } })).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_each_upgrade_type_improves_its_multiplier() {
+        let none = AppliedUpgrades::default();
+        let base = MiningUpgrade::default();
+
+        let (faster, _) =
+            plan_upgrade(&base, &none, &rig(UpgradeType::FasterExtraction, 1.5)).unwrap();
+        assert_eq!(faster.speed_multiplier, 1.5);
+        assert_eq!(faster.yield_multiplier, 1.0);
+
+        let (larger, _) = plan_upgrade(&base, &none, &rig(UpgradeType::LargerYield, 1.2)).unwrap();
+        assert_eq!(larger.yield_multiplier, 1.2);
+        assert_eq!(larger.range_multiplier, 1.0);
+
+        let (longer, applied) =
+            plan_upgrade(&faster, &none, &rig(UpgradeType::LongerRange, 2.0)).unwrap();
+        assert_eq!(longer.range_multiplier, 2.0);
+        // Earlier upgrades are kept
+        assert_eq!(longer.speed_multiplier, 1.5);
+        assert_eq!(applied.upgrades, vec![UpgradeType::LongerRange]);
+    }
+
+    #[test]
+    fn test_upgrade_is_applied_and_consumed() {
+        let ctx = MockCapabilitiesContext::new().with_value(
+            &UPGRADE_RID.replace('.', ":"),
+            json!({ "upgrade_type": "larger_yield", "magnitude": 1.25 }),
+        );
+        handle_apply_upgrade(&ctx, call()).unwrap();
+
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "call.decs.components.the_void.player1.mining_upgrade.set",
+                "call.decs.components.the_void.player1.applied_upgrades.set",
+                "call.decs.components.the_void.player1.mining_rig_upgrades.delete",
+                "_INBOX.reply1",
+            ]
+        );
+        let upgrade = ctx
+            .payload("call.decs.components.the_void.player1.mining_upgrade.set")
+            .unwrap();
+        assert_eq!(upgrade["params"]["yield_multiplier"], 1.25);
+        let deleted = ctx
+            .payload("call.decs.components.the_void.player1.mining_rig_upgrades.delete")
+            .unwrap();
+        assert_eq!(deleted["params"]["rid"], UPGRADE_RID);
+        let reply = ctx.payload("_INBOX.reply1").unwrap();
+        assert_eq!(
+            reply["result"]["applied_upgrades"]["upgrades"],
+            json!(["larger_yield"])
+        );
+    }
+
+    #[test]
+    fn test_fully_upgraded_rig_rejects_upgrade() {
+        let full = AppliedUpgrades {
+            upgrades: vec![
+                UpgradeType::FasterExtraction,
+                UpgradeType::LargerYield,
+                UpgradeType::LongerRange,
+            ],
+        };
+        let rig = rig(UpgradeType::FasterExtraction, 2.0);
+        assert!(plan_upgrade(&MiningUpgrade::default(), &full, &rig).is_none());
+
+        let ctx = MockCapabilitiesContext::new()
+            .with_value(
+                &UPGRADE_RID.replace('.', ":"),
+                json!({ "upgrade_type": "faster_extraction", "magnitude": 2.0 }),
+            )
+            .with_value(
+                "decs:components:the_void:player1:applied_upgrades",
+                serde_json::to_value(&full).unwrap(),
+            );
+        handle_apply_upgrade(&ctx, call()).unwrap();
+
+        // The upgrade is left where it was
+        assert_eq!(
+            ctx.subjects(),
+            vec![UPGRADE_REJECTED_EVENT, "_INBOX.reply1"]
+        );
+        let rejected = ctx.payload(UPGRADE_REJECTED_EVENT).unwrap();
+        assert_eq!(rejected["upgrade"], UPGRADE_RID);
+        assert_eq!(rejected["reason"], "max_upgrades");
+        let reply = ctx.payload("_INBOX.reply1").unwrap();
+        assert_eq!(reply["error"]["code"], "system.accessDenied");
+    }
+}
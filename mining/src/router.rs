@@ -0,0 +1,168 @@
+//! # Routing
+//!
+//! Messages are dispatched to their handlers by subject through a `HandlerRegistry`. Handlers are
+//! registered under NATS style subject patterns, the same ones the actor subscribes with: a `*`
+//! stands for any single token of the subject and a trailing `>` for one or more tokens. The
+//! first registered pattern matching the subject handles the message, so specific patterns go
+//! before broader ones.
+use guest::prelude::*;
+
+/// A message handler. Closures that capture nothing coerce to one
+pub(crate) type HandlerFn<C> = fn(&C, messaging::BrokerMessage) -> CallResult;
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Literal(String),
+    Single, // `*`
+    Tail,   // `>`
+}
+
+/// A subject pattern split into its tokens
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct CompiledPattern {
+    tokens: Vec<Token>,
+}
+
+impl CompiledPattern {
+    /// Compiles the pattern. A `>` anywhere but in the last token is an error
+    pub(crate) fn compile(pattern: &str) -> std::result::Result<Self, String> {
+        let parts: Vec<&str> = pattern.split('.').collect();
+        let mut tokens = Vec::with_capacity(parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            tokens.push(match *part {
+                "" => return Err(format!("Empty token in subject pattern '{}'", pattern)),
+                "*" => Token::Single,
+                ">" if i == parts.len() - 1 => Token::Tail,
+                ">" => return Err(format!("'>' is not last in subject pattern '{}'", pattern)),
+                literal => Token::Literal(literal.to_string()),
+            });
+        }
+        Ok(CompiledPattern { tokens })
+    }
+
+    pub(crate) fn matches(&self, subject: &str) -> bool {
+        let parts: Vec<&str> = subject.split('.').collect();
+        for (i, token) in self.tokens.iter().enumerate() {
+            match token {
+                Token::Tail => return parts.len() > i,
+                Token::Single if i < parts.len() => {}
+                Token::Literal(literal) if i < parts.len() && parts[i] == literal => {}
+                _ => return false,
+            }
+        }
+        parts.len() == self.tokens.len()
+    }
+}
+
+/// Handlers by subject pattern, tried in registration order
+pub(crate) struct HandlerRegistry<C> {
+    handlers: Vec<(CompiledPattern, HandlerFn<C>)>,
+}
+
+impl<C> HandlerRegistry<C> {
+    pub(crate) fn new() -> Self {
+        HandlerRegistry { handlers: vec![] }
+    }
+
+    /// Registers the handler for subjects matching the pattern. Panics on a malformed pattern,
+    /// patterns are written into the actor rather than received
+    pub(crate) fn register(mut self, pattern: &str, handler: HandlerFn<C>) -> Self {
+        let compiled = CompiledPattern::compile(pattern).unwrap_or_else(|e| panic!("{}", e));
+        self.handlers.push((compiled, handler));
+        self
+    }
+
+    /// Hands the message to the handler of the first pattern matching its subject
+    pub(crate) fn dispatch(&self, ctx: &C, msg: messaging::BrokerMessage) -> CallResult {
+        match self
+            .handlers
+            .iter()
+            .find(|(pattern, _)| pattern.matches(&msg.subject))
+        {
+            Some((_, handler)) => handler(ctx, msg),
+            None => Err(format!("No handler for subject: {}", msg.subject).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CompiledPattern;
+    use super::HandlerRegistry;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    /// Each handler publishes its own name, so the tests can tell which one ran
+    fn registry() -> HandlerRegistry<MockCapabilitiesContext> {
+        HandlerRegistry::new()
+            .register("decs.system.registry", |ctx, _| publish(ctx, "ping"))
+            .register("decs.frames.*.mining_lock", |ctx, _| publish(ctx, "lock"))
+            .register("decs.frames.*.mining", |ctx, _| publish(ctx, "mining"))
+            .register("call.decs.*.*.mining.>", |ctx, _| publish(ctx, "calls"))
+            .register("call.decs.*.*.mining.log", |ctx, _| publish(ctx, "never"))
+    }
+
+    fn publish(ctx: &MockCapabilitiesContext, name: &str) -> guest::prelude::CallResult {
+        use crate::capabilities::Capabilities;
+        ctx.msg().publish(name, None, &[])?;
+        Ok(vec![])
+    }
+
+    fn dispatch(subject: &str) -> Option<String> {
+        let ctx = MockCapabilitiesContext::new();
+        let result = registry().dispatch(
+            &ctx,
+            BrokerMessage {
+                subject: subject.to_string(),
+                ..Default::default()
+            },
+        );
+        result.ok().map(|_| ctx.subjects()[0].to_string())
+    }
+
+    #[test]
+    fn test_exact_match() {
+        assert_eq!(dispatch("decs.system.registry"), Some("ping".to_string()));
+        assert_eq!(dispatch("decs.system.registry.replies"), None);
+        assert_eq!(dispatch("decs.system"), None);
+    }
+
+    #[test]
+    fn test_single_wildcard_matches_one_token() {
+        assert_eq!(
+            dispatch("decs.frames.the_void.mining"),
+            Some("mining".to_string())
+        );
+        assert_eq!(
+            dispatch("decs.frames.the_void.mining_lock"),
+            Some("lock".to_string())
+        );
+        assert_eq!(dispatch("decs.frames.mining"), None);
+        assert_eq!(dispatch("decs.frames.shard.two.mining"), None);
+    }
+
+    #[test]
+    fn test_tail_wildcard_matches_the_rest() {
+        assert_eq!(
+            dispatch("call.decs.the_void.player1.mining.pause"),
+            Some("calls".to_string())
+        );
+        assert_eq!(
+            dispatch("call.decs.the_void.player1.mining.apply_upgrade.now"),
+            Some("calls".to_string())
+        );
+        // The tail stands for at least one token
+        assert_eq!(dispatch("call.decs.the_void.player1.mining"), None);
+    }
+
+    #[test]
+    fn test_first_matching_pattern_wins() {
+        // The tail wildcard was registered before the exact pattern
+        assert_eq!(
+            dispatch("call.decs.the_void.player1.mining.log"),
+            Some("calls".to_string())
+        );
+        assert!(CompiledPattern::compile("call.>.log").is_err());
+        assert!(CompiledPattern::compile("call..log").is_err());
+    }
+}
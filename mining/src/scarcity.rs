@@ -0,0 +1,184 @@
+//! # Resource Scarcity
+//!
+//! The supply of a resource type across the shard can be tracked by an item of the `universe`
+//! entity's `resource_scarcity` collection, e.g.
+//! `decs.components.{shard}.universe.resource_scarcity.tasty`. Extractions of a tracked resource
+//! yield the quantity mined times the scarcity's `effective_yield`, which rises as the supply runs
+//! out. Every completed extraction takes the quantity mined out of the `global_supply`, every
+//! respawned deposit puts its quantity back, and each change is published as a `set` of the
+//! item. Untracked resources yield what they always did.
+use crate::capabilities::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::rid::ResourceIdentifier;
+
+const UNIVERSE: &str = "universe";
+const RESOURCE_SCARCITY: &str = "resource_scarcity";
+
+/// The multiplier of the quantity an extraction of the resource type yields, 1 for a resource
+/// type whose supply isn't tracked
+pub(crate) fn scarcity_yield(
+    ctx: &impl Capabilities,
+    shard: &str,
+    resource_type: &str,
+) -> std::result::Result<f64, Box<dyn std::error::Error>> {
+    Ok(get_scarcity(ctx, shard, resource_type)?
+        .map(|(_, scarcity)| scarcity.effective_yield())
+        .unwrap_or(1.0))
+}
+
+/// Takes the mined quantity out of the supply of its resource type
+pub(crate) fn deplete_supply(
+    ctx: &impl Capabilities,
+    shard: &str,
+    mined: &MiningResource,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    change_supply(ctx, shard, &mined.stack_type, -i64::from(mined.qty))
+}
+
+/// Puts the respawned quantity back into the supply of its resource type
+pub(crate) fn replenish_supply(
+    ctx: &impl Capabilities,
+    shard: &str,
+    respawned: &MiningResource,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    change_supply(ctx, shard, &respawned.stack_type, i64::from(respawned.qty))
+}
+
+fn change_supply(
+    ctx: &impl Capabilities,
+    shard: &str,
+    resource_type: &str,
+    change: i64,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    if let Some((rid, scarcity)) = get_scarcity(ctx, shard, resource_type)? {
+        ctx.msg().publish(
+            &format!("call.{}.set", rid),
+            None,
+            &serde_json::to_vec(&json!({ "params": plan_supply_change(&scarcity, change) }))?,
+        )?;
+    }
+    Ok(())
+}
+
+/// The scarcity of the resource type and the rid it is stored under, if its supply is tracked
+fn get_scarcity(
+    ctx: &impl Capabilities,
+    shard: &str,
+    resource_type: &str,
+) -> std::result::Result<Option<(ResourceIdentifier, ResourceScarcity)>, Box<dyn std::error::Error>>
+{
+    // A resource type that can't name an item of the collection can't be tracked
+    let rid = match ResourceIdentifier::new(shard, UNIVERSE, RESOURCE_SCARCITY)
+        .and_then(|collection| collection.item(resource_type))
+    {
+        Ok(rid) => rid,
+        Err(_) => return Ok(None),
+    };
+    match ctx.kv().get(&rid.to_key())? {
+        Some(s) => Ok(Some((rid, serde_json::from_str(&s)?))),
+        None => Ok(None),
+    }
+}
+
+/// The scarcity after its supply changed by the quantity. The supply never drops below nothing
+fn plan_supply_change(scarcity: &ResourceScarcity, change: i64) -> ResourceScarcity {
+    ResourceScarcity {
+        global_supply: scarcity.global_supply.saturating_add_signed(change),
+        ..scarcity.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::deplete_supply;
+    use super::plan_supply_change;
+    use super::replenish_supply;
+    use super::scarcity_yield;
+    use super::MiningResource;
+    use super::ResourceScarcity;
+    use crate::test_utils::MockCapabilitiesContext;
+
+    const SCARCITY_KEY: &str = "decs:components:the_void:universe:resource_scarcity:tasty";
+    const SCARCITY_SET: &str = "call.decs.components.the_void.universe.resource_scarcity.tasty.set";
+
+    fn scarcity(global_supply: u64) -> ResourceScarcity {
+        ResourceScarcity {
+            resource_type: "tasty".to_string(),
+            global_supply,
+            base_yield: 1.5,
+            scarcity_curve: 16.0,
+        }
+    }
+
+    fn tasty(qty: u32) -> MiningResource {
+        MiningResource {
+            stack_type: "tasty".to_string(),
+            qty,
+            ..MiningResource::default()
+        }
+    }
+
+    #[test]
+    fn test_plentiful_resource_yields_a_small_bonus() {
+        // 1.5 * (1 + 16 / 1024)
+        assert_eq!(scarcity(1023).effective_yield(), 1.5234375);
+        // An infinite supply is no bonus at all
+        assert_eq!(scarcity(u64::MAX).effective_yield(), 1.5);
+    }
+
+    #[test]
+    fn test_scarce_resource_yields_a_large_bonus() {
+        // 1.5 * (1 + 16 / 4)
+        assert_eq!(scarcity(3).effective_yield(), 7.5);
+        // 1.5 * (1 + 16 / 1), the last of it
+        assert_eq!(scarcity(0).effective_yield(), 25.5);
+    }
+
+    #[test]
+    fn test_supply_follows_extractions_and_respawns() {
+        let ctx = MockCapabilitiesContext::new()
+            .with_value(SCARCITY_KEY, serde_json::to_value(scarcity(3)).unwrap());
+        assert_eq!(scarcity_yield(&ctx, "the_void", "tasty").unwrap(), 7.5);
+
+        deplete_supply(&ctx, "the_void", &tasty(2)).unwrap();
+        assert_eq!(
+            ctx.payload(SCARCITY_SET).unwrap()["params"],
+            serde_json::to_value(scarcity(1)).unwrap()
+        );
+        ctx.apply_set(
+            "decs.components.the_void.universe.resource_scarcity.tasty",
+            &ctx.payload(SCARCITY_SET).unwrap()["params"],
+        );
+        // 1.5 * (1 + 16 / 2)
+        assert_eq!(scarcity_yield(&ctx, "the_void", "tasty").unwrap(), 13.5);
+
+        ctx.published.borrow_mut().clear();
+        replenish_supply(&ctx, "the_void", &tasty(20)).unwrap();
+        assert_eq!(
+            ctx.payload(SCARCITY_SET).unwrap()["params"]["global_supply"],
+            21
+        );
+    }
+
+    #[test]
+    fn test_untracked_resources_are_left_alone() {
+        let ctx = MockCapabilitiesContext::new()
+            .with_value(SCARCITY_KEY, serde_json::to_value(scarcity(3)).unwrap());
+        assert_eq!(scarcity_yield(&ctx, "the_void", "spendy").unwrap(), 1.0);
+        assert_eq!(scarcity_yield(&ctx, "the_abyss", "tasty").unwrap(), 1.0);
+        deplete_supply(
+            &ctx,
+            "the_void",
+            &MiningResource {
+                stack_type: "spendy".to_string(),
+                ..tasty(2)
+            },
+        )
+        .unwrap();
+        assert!(ctx.subjects().is_empty());
+
+        // More taken than there is to take empties the supply
+        assert_eq!(plan_supply_change(&scarcity(3), -5).global_supply, 0);
+    }
+}
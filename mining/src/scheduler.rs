@@ -0,0 +1,222 @@
+//! # Scheduler
+//!
+//! Systems that need something to happen later, such as an asteroid respawning or an entity
+//! leaving a wormhole, can schedule a one-shot event instead of tracking the time themselves.
+//! A call on `call.decs.{shard}.scheduler.schedule` with a `ScheduledEvent` as its params adds
+//! the event to the shard's `decs:scheduled_events:{shard}` set. The scheduler system awaits
+//! frames for the shard's entity with a `scheduler` component and, on each one, publishes the
+//! payload of every event whose `trigger_time_ms` has been reached on its `event_topic`, in
+//! trigger order, removing it from the set. Events scheduled in the past go out on the next frame.
+use crate::capabilities::*;
+use crate::queue::{flush_and_report, MessageQueue};
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const SCHEDULE: &str = "schedule";
+
+/// Receives calls on `call.decs.{shard}.scheduler.schedule` and adds the event in the params to
+/// the shard's scheduled events
+pub(crate) fn handle_schedule_event(
+    ctx: &impl Capabilities,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let shard = match subject.as_slice() {
+        ["call", "decs", shard, "scheduler", action] if *action == SCHEDULE => *shard,
+        _ => return Err(format!("Unexpected scheduler call subject: {}", msg.subject).into()),
+    };
+
+    let body: serde_json::Value = serde_json::from_slice(&msg.body)?;
+    let response = match serde_json::from_value::<ScheduledEvent>(body["params"].clone()) {
+        Ok(event) if !event.event_topic.is_empty() => {
+            ctx.kv()
+                .set_add(&events_key(shard), &serde_json::to_string(&event)?)?;
+            json!({ "result": event })
+        }
+        _ => json!({
+            "error": {
+                "code": "system.invalidParams",
+                "message": "params must be an event with a trigger_time_ms and an event_topic"
+            }
+        }),
+    };
+    if !msg.reply_to.is_empty() {
+        ctx.msg()
+            .publish(&msg.reply_to, None, &serde_json::to_vec(&response)?)?;
+    }
+
+    Ok(vec![])
+}
+
+/// Receives frames on `decs.frames.{shard}.scheduler` and publishes the shard's due events
+pub(crate) fn handle_scheduled_frame(
+    ctx: &impl Capabilities,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+    let now_ms = trader::clock::frame_time_ms(&frame, super::FRAMERATE);
+    let key = events_key(&frame.shard);
+
+    let mut scheduled = Vec::new();
+    for member in ctx.kv().set_members(&key)? {
+        match serde_json::from_str::<ScheduledEvent>(&member) {
+            Ok(event) => scheduled.push((member, event)),
+            Err(e) => {
+                ctx.log(&format!("Dropping unreadable scheduled event: {}", e));
+                ctx.kv().set_remove(&key, &member)?;
+            }
+        }
+    }
+
+    let mut queue = MessageQueue::new();
+    for (member, event) in plan_due_events(scheduled, now_ms) {
+        // Only the frame that removes the event publishes it, so an event racing
+        // a concurrent frame goes out once
+        if ctx.kv().set_remove(&key, &member)? > 0 {
+            queue.push(&event.event_topic, &event.payload)?;
+        }
+    }
+    flush_and_report(ctx, queue, &frame.shard, &frame.entity_id)?;
+
+    Ok(vec![])
+}
+
+/// The KV key of the shard's scheduled events
+fn events_key(shard: &str) -> String {
+    format!("decs:scheduled_events:{}", shard)
+}
+
+/// The events due at the given game time, earliest first. Events due at the same time are
+/// ordered by topic so they always go out in the same order
+fn plan_due_events(
+    scheduled: Vec<(String, ScheduledEvent)>,
+    now_ms: u64,
+) -> Vec<(String, ScheduledEvent)> {
+    let mut due: Vec<(String, ScheduledEvent)> = scheduled
+        .into_iter()
+        .filter(|(_, event)| event.trigger_time_ms <= now_ms)
+        .collect();
+    due.sort_by(|(_, a), (_, b)| {
+        a.trigger_time_ms
+            .cmp(&b.trigger_time_ms)
+            .then_with(|| a.event_topic.cmp(&b.event_topic))
+    });
+    due
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle_schedule_event;
+    use super::handle_scheduled_frame;
+    use super::plan_due_events;
+    use super::ScheduledEvent;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    fn event(trigger_time_ms: u64, event_topic: &str) -> (String, ScheduledEvent) {
+        let event = ScheduledEvent {
+            trigger_time_ms,
+            event_topic: event_topic.to_string(),
+            payload: json!({ "topic": event_topic }),
+        };
+        (serde_json::to_string(&event).unwrap(), event)
+    }
+
+    fn topics(due: &[(String, ScheduledEvent)]) -> Vec<&str> {
+        due.iter().map(|(_, e)| e.event_topic.as_str()).collect()
+    }
+
+    fn schedule(trigger_time_ms: u64, event_topic: &str) -> BrokerMessage {
+        BrokerMessage {
+            subject: "call.decs.the_void.scheduler.schedule".to_string(),
+            reply_to: "_INBOX.reply1".to_string(),
+            body: serde_json::to_vec(&json!({
+                "params": {
+                    "trigger_time_ms": trigger_time_ms,
+                    "event_topic": event_topic,
+                    "payload": { "asteroid": "asteroid1" }
+                }
+            }))
+            .unwrap(),
+        }
+    }
+
+    fn frame(seq_no: u64) -> BrokerMessage {
+        BrokerMessage {
+            subject: "decs.frames.the_void.scheduler".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": seq_no,
+                "elapsed_ms": 1000,
+                "shard": "the_void",
+                "system": "scheduler",
+                "entity_id": "the_void"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_scheduled_event_is_published_once() {
+        let ctx = MockCapabilitiesContext::new();
+        handle_schedule_event(&ctx, schedule(5_000, "event.decs.the_void.respawn")).unwrap();
+        let reply = ctx.payload("_INBOX.reply1").unwrap();
+        assert_eq!(reply["result"]["trigger_time_ms"], 5_000);
+
+        // Not due yet
+        handle_scheduled_frame(&ctx, frame(4)).unwrap();
+        assert_eq!(ctx.subjects(), vec!["_INBOX.reply1"]);
+
+        handle_scheduled_frame(&ctx, frame(5)).unwrap();
+        handle_scheduled_frame(&ctx, frame(6)).unwrap();
+        assert_eq!(
+            ctx.subjects(),
+            vec!["_INBOX.reply1", "event.decs.the_void.respawn"]
+        );
+        assert_eq!(
+            ctx.payload("event.decs.the_void.respawn"),
+            Some(json!({ "asteroid": "asteroid1" }))
+        );
+    }
+
+    #[test]
+    fn test_event_without_topic_is_rejected() {
+        let ctx = MockCapabilitiesContext::new();
+        handle_schedule_event(&ctx, schedule(5_000, "")).unwrap();
+        let reply = ctx.payload("_INBOX.reply1").unwrap();
+        assert_eq!(reply["error"]["code"], "system.invalidParams");
+        assert!(ctx.sets.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_event_triggers_at_exact_time() {
+        assert!(plan_due_events(vec![event(5_000, "a")], 4_999).is_empty());
+        assert_eq!(
+            topics(&plan_due_events(vec![event(5_000, "a")], 5_000)),
+            vec!["a"]
+        );
+    }
+
+    #[test]
+    fn test_past_event_triggers_immediately() {
+        assert_eq!(
+            topics(&plan_due_events(vec![event(0, "a")], 60_000)),
+            vec!["a"]
+        );
+    }
+
+    #[test]
+    fn test_due_events_are_ordered() {
+        let due = plan_due_events(
+            vec![
+                event(3_000, "c"),
+                event(9_000, "later"),
+                event(1_000, "b"),
+                event(3_000, "a"),
+            ],
+            5_000,
+        );
+        assert_eq!(topics(&due), vec!["b", "a", "c"]);
+    }
+}
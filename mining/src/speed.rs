@@ -0,0 +1,138 @@
+//! # Stationary Mining
+//!
+//! A ship has to hold (nearly) still to mine. While the miner's `velocity` magnitude is at or
+//! above its extractor's `max_mining_speed`, the extraction makes no progress. The extractor is
+//! published unchanged and a warning goes out on `event.decs.{shard}.{entity}.mining.too_fast`,
+//! at most once every `WARNING_TTL_SECONDS`. Entities without a velocity count as stationary.
+use crate::capabilities::*;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const VELOCITY: &str = "velocity";
+// How long the HUD keeps showing a warning, so it isn't flooded with one per frame
+const WARNING_TTL_SECONDS: u32 = 5;
+
+/// Retrieve the entity's velocity, if it has one
+pub(crate) fn get_velocity(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<Option<Velocity>, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, VELOCITY
+    ))? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+/// Whether the miner moves too fast for the extraction to progress
+pub(crate) fn is_too_fast(velocity: Option<&Velocity>, max_mining_speed: f64) -> bool {
+    velocity
+        .map(|v| f64::from(v.mag) >= max_mining_speed)
+        .unwrap_or(false)
+}
+
+/// Publishes the warning that the miner is too fast to mine, unless it was already warned
+/// within the last `WARNING_TTL_SECONDS`
+pub(crate) fn warn_too_fast(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    velocity: &Velocity,
+    max_mining_speed: f64,
+) -> Result<()> {
+    let key = format!("decs:mining:too_fast:{}:{}", shard, entity_id);
+    // Only the frame that takes the counter from 0 to 1 warns, until the key expires
+    if ctx.kv().atomic_add(&key, 1)? != 1 {
+        return Ok(());
+    }
+    ctx.kv().set(&key, "1", Some(WARNING_TTL_SECONDS))?;
+    ctx.msg().publish(
+        &format!("event.decs.{}.{}.mining.too_fast", shard, entity_id),
+        None,
+        &serde_json::to_vec(&json!({
+            "speed": velocity.mag,
+            "max_mining_speed": max_mining_speed
+        }))?,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_too_fast;
+    use super::Velocity;
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    fn moving(mag: u32) -> Velocity {
+        Velocity::new(mag, 1.0, 0.0, 0.0)
+    }
+
+    #[test]
+    fn test_stationary_ship_mines() {
+        assert!(!is_too_fast(None, 5.0));
+        assert!(!is_too_fast(Some(&moving(0)), 5.0));
+        assert!(!is_too_fast(Some(&moving(4)), 5.0));
+    }
+
+    #[test]
+    fn test_moving_ship_does_not_mine() {
+        assert!(is_too_fast(Some(&moving(5)), 5.0));
+        assert!(is_too_fast(Some(&moving(900)), 5.0));
+        // A lenient extractor lets a slow ship keep mining
+        assert!(!is_too_fast(Some(&moving(5)), 5.5));
+    }
+
+    #[test]
+    fn test_too_fast_extraction_is_republished_unchanged_with_one_warning() {
+        let ctx = MockCapabilitiesContext::new()
+            .with_extractor(
+                "decs.components.the_void.player1.extractors.1",
+                json!({
+                    "target": "decs.components.the_void.asteroid1.mining_resource",
+                    "remaining_ms": 900.0,
+                    "total_ms": 1000.0
+                }),
+            )
+            .with_value(
+                "decs:components:the_void:player1:velocity",
+                json!({ "mag": 120, "ux": 1.0, "uy": 0.0, "uz": 0.0 }),
+            );
+        let frame = || BrokerMessage {
+            subject: "decs.frames.the_void.mining".to_string(),
+            body: serde_json::to_vec(&json!({
+                "seq_no": 12,
+                "elapsed_ms": 500,
+                "shard": "the_void",
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        };
+
+        handle_frame(&ctx, frame()).unwrap();
+        handle_frame(&ctx, frame()).unwrap();
+
+        assert_eq!(
+            ctx.subjects(),
+            vec![
+                "call.decs.components.the_void.player1.extractors.1.set",
+                "event.decs.the_void.player1.mining.too_fast",
+                "call.decs.components.the_void.player1.extractors.1.set",
+            ]
+        );
+        let extractor = ctx
+            .payload("call.decs.components.the_void.player1.extractors.1.set")
+            .unwrap();
+        assert_eq!(extractor["params"]["remaining_ms"], 900.0);
+        assert_eq!(
+            ctx.payload("event.decs.the_void.player1.mining.too_fast"),
+            Some(json!({ "speed": 120, "max_mining_speed": 5.0 }))
+        );
+    }
+}
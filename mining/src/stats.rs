@@ -0,0 +1,84 @@
+//! # Mining Statistics
+//!
+//! Every successful extraction is counted in the miner's `mining_stats` component. The component
+//! is created on the player's first extraction and afterwards read back from the KV store right
+//! before each update, so the update always builds on the latest statistics.
+use crate::capabilities::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+/// Reads the entity's statistics, counts the extraction and publishes a `set` with the result
+pub(crate) fn record_extraction(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    mined: &MiningResource,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let stats_value = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard,
+        entity_id,
+        super::MINING_STATS
+    ))?;
+    let existing: Option<MiningStats> = match stats_value {
+        Some(stats_str) => Some(serde_json::from_str(&stats_str)?),
+        None => None,
+    };
+    let stats = plan_stats_update(existing, mined);
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard,
+            entity_id,
+            super::MINING_STATS
+        ),
+        None,
+        &serde_json::to_vec(&json!({ "params": stats }))?,
+    )?;
+    Ok(())
+}
+
+/// The statistics after counting the extraction, starting from nothing on the first one
+fn plan_stats_update(existing: Option<MiningStats>, mined: &MiningResource) -> MiningStats {
+    let mut stats = existing.unwrap_or_default();
+    stats.record(mined);
+    stats
+}
+
+#[cfg(test)]
+mod test {
+    use super::plan_stats_update;
+    use super::MiningResource;
+
+    fn mined(stack_type: &str, qty: u32) -> MiningResource {
+        MiningResource {
+            stack_type: stack_type.to_string(),
+            qty,
+            ..MiningResource::default()
+        }
+    }
+
+    #[test]
+    fn test_first_extraction_creates_stats() {
+        let stats = plan_stats_update(None, &mined("tasty", 7));
+        assert_eq!(stats.asteroids_mined, 1);
+        assert_eq!(stats.units_extracted, 7);
+        assert_eq!(stats.resources["tasty"], 7);
+        assert_eq!(stats.favorite_resource.as_deref(), Some("tasty"));
+    }
+
+    #[test]
+    fn test_stats_accumulate_across_extractions() {
+        let stats = plan_stats_update(None, &mined("tasty", 7));
+        let stats = plan_stats_update(Some(stats), &mined("spendy", 12));
+        assert_eq!(stats.asteroids_mined, 2);
+        assert_eq!(stats.units_extracted, 19);
+        assert_eq!(stats.resources["tasty"], 7);
+        assert_eq!(stats.resources["spendy"], 12);
+        assert_eq!(stats.favorite_resource.as_deref(), Some("spendy"));
+
+        // A tie goes to the alphabetically first stack type
+        let stats = plan_stats_update(Some(stats), &mined("tasty", 5));
+        assert_eq!(stats.favorite_resource.as_deref(), Some("spendy"));
+    }
+}
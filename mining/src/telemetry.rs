@@ -0,0 +1,193 @@
+//! # Telemetry
+//!
+//! The mining system counts, per shard, the extractions that complete (`completed`), fail because
+//! their resource is gone (`failed_missing_resource`) or drifted out of range
+//! (`failed_out_of_range`), and those given up for any other reason (`cancelled`). The counts are
+//! kept in memory and published about once a minute on `event.decs.system.mining.metrics`, and
+//! added to the shard's persistent counters under `decs:metrics:{shard}:mining:{counter}`.
+use crate::capabilities::*;
+use crate::mining::{OUT_OF_RANGE, RESOURCE_MISSING};
+use stacktrader_types as trader;
+use trader::counters;
+
+pub(crate) const COMPLETED: &str = "completed";
+const CANCELLED: &str = "cancelled";
+const FAILED_MISSING_RESOURCE: &str = "failed_missing_resource";
+const FAILED_OUT_OF_RANGE: &str = "failed_out_of_range";
+
+/// Counts an occurrence on the shard, in memory and in the KV store
+pub(crate) fn count(
+    ctx: &impl Capabilities,
+    shard: &str,
+    counter: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let key = counters::plan_increment(shard, super::SYSTEM_NAME, counter);
+    ctx.kv().atomic_add(&key, 1)?;
+    Ok(())
+}
+
+/// Counts an extraction that ended without reaching the inventory, by its failure reason
+pub(crate) fn count_failure(
+    ctx: &impl Capabilities,
+    shard: &str,
+    reason: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    count(ctx, shard, failure_counter(reason))
+}
+
+/// Publishes the shard's counts if the frame is the first of a new interval
+pub(crate) fn publish_counters(
+    ctx: &impl Capabilities,
+    frame: &decs::systemmgr::EntityFrame,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    if let Some((subject, snapshot)) =
+        counters::plan_counter_publish(&frame.shard, super::SYSTEM_NAME, frame.seq_no)
+    {
+        ctx.msg()
+            .publish(&subject, None, &serde_json::to_vec(&snapshot)?)?;
+    }
+    Ok(())
+}
+
+fn failure_counter(reason: &str) -> &'static str {
+    match reason {
+        RESOURCE_MISSING => FAILED_MISSING_RESOURCE,
+        OUT_OF_RANGE => FAILED_OUT_OF_RANGE,
+        _ => CANCELLED,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mining::handle_frame;
+    use crate::test_utils::MockCapabilitiesContext;
+    use guest::prelude::messaging::BrokerMessage;
+
+    const METRICS: &str = "event.decs.system.mining.metrics";
+
+    /// An extractor that completes on the next frame, targeting `asteroid1` whose resource,
+    /// lock and position are only present when given
+    fn context(
+        shard: &str,
+        resource: bool,
+        asteroid_x: Option<f64>,
+        target: &str,
+    ) -> MockCapabilitiesContext {
+        let mut ctx = MockCapabilitiesContext::new()
+            .with_extractor(
+                &format!("decs.components.{}.player1.extractors.1", shard),
+                json!({ "target": target, "remaining_ms": 500.0, "total_ms": 1000.0 }),
+            )
+            .with_value(
+                &format!("decs:components:{}:asteroid1:mining_lock", shard),
+                json!({
+                    "extractor": format!("decs.components.{}.player1.extractors.1", shard),
+                    "owner": "player1"
+                }),
+            )
+            .with_value(
+                &format!("decs:components:{}:asteroid1:transponder", shard),
+                json!({ "object_type": "asteroid", "display_name": "Rock", "color": "gray" }),
+            );
+        if resource {
+            ctx = ctx.with_value(
+                &format!("decs:components:{}:asteroid1:mining_resource", shard),
+                json!({ "stack_type": "tasty", "qty": 3 }),
+            );
+        }
+        if let Some(x) = asteroid_x {
+            ctx = ctx
+                .with_value(
+                    &format!("decs:components:{}:player1:position", shard),
+                    json!({ "x": 0.0, "y": 0.0, "z": 0.0 }),
+                )
+                .with_value(
+                    &format!("decs:components:{}:asteroid1:position", shard),
+                    json!({ "x": x, "y": 0.0, "z": 0.0 }),
+                );
+        }
+        ctx
+    }
+
+    fn frame(shard: &str, seq_no: u64) -> BrokerMessage {
+        BrokerMessage {
+            subject: format!("decs.frames.{}.mining", shard),
+            body: serde_json::to_vec(&json!({
+                "seq_no": seq_no,
+                "elapsed_ms": 1000,
+                "shard": shard,
+                "system": "mining",
+                "entity_id": "player1"
+            }))
+            .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn counter(ctx: &MockCapabilitiesContext, shard: &str, name: &str) -> Option<String> {
+        ctx.values
+            .borrow()
+            .get(&format!("decs:metrics:{}:mining:{}", shard, name))
+            .cloned()
+    }
+
+    #[test]
+    fn test_each_outcome_is_counted() {
+        let target = "decs.components.the_void.asteroid1.mining_resource";
+        let outcomes = vec![
+            (context("the_void", true, None, target), "completed"),
+            (
+                context("the_void", false, None, target),
+                "failed_missing_resource",
+            ),
+            (
+                context("the_void", true, Some(50.0), target),
+                "failed_out_of_range",
+            ),
+            (
+                context("the_void", true, None, "decs.components.the_void"),
+                "cancelled",
+            ),
+        ];
+        for (ctx, expected) in outcomes {
+            handle_frame(&ctx, frame("the_void", 1)).unwrap();
+            for name in &[
+                "completed",
+                "cancelled",
+                "failed_missing_resource",
+                "failed_out_of_range",
+            ] {
+                let count = counter(&ctx, "the_void", name);
+                if *name == expected {
+                    assert_eq!(count, Some("1".to_string()), "{}", name);
+                } else {
+                    assert_eq!(count, None, "{} counted for {}", name, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_counts_are_published_each_interval() {
+        // The counts are kept per shard for the lifetime of the actor, so this shard is only
+        // used by this test
+        let shard = "metered_void";
+        let target = "decs.components.metered_void.asteroid1.mining_resource";
+        let ctx = context(shard, false, None, target);
+        handle_frame(&ctx, frame(shard, 1)).unwrap();
+        handle_frame(&ctx, frame(shard, 2)).unwrap();
+        assert!(!ctx.subjects().contains(&METRICS.to_string()));
+        ctx.published.borrow_mut().clear();
+
+        // The first frame of the next minute publishes what was counted before it
+        handle_frame(&ctx, frame(shard, 60)).unwrap();
+        assert_eq!(ctx.subjects()[0], METRICS);
+        let metrics = ctx.payload(METRICS).unwrap();
+        assert_eq!(metrics["shard"], shard);
+        assert_eq!(metrics["counters"], json!({ "failed_missing_resource": 2 }));
+        assert_eq!(
+            counter(&ctx, shard, "failed_missing_resource"),
+            Some("3".to_string())
+        );
+    }
+}
@@ -0,0 +1,289 @@
+//! Test doubles for running the mining system's handlers without a live runtime
+use crate::capabilities::*;
+use guest::prelude::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+/// Stands in for the waxosuit `CapabilitiesContext`. Values, lists and sets live in memory, keys
+/// written with an expiry are noted in `expiring`, every publish is recorded in order (unless its
+/// subject is set up to fail, as are writes to a failing key), every read is counted and random
+/// numbers are pinned to `random`
+pub(crate) struct MockCapabilitiesContext {
+    pub values: RefCell<HashMap<String, String>>,
+    pub lists: RefCell<HashMap<String, Vec<String>>>,
+    pub sets: RefCell<HashMap<String, HashSet<String>>>,
+    pub expiring: RefCell<HashSet<String>>,
+    pub published: RefCell<Vec<(String, Vec<u8>)>>,
+    pub failing: RefCell<HashSet<String>>,
+    pub read_counts: RefCell<HashMap<String, usize>>,
+    pub random: u32,
+}
+
+impl MockCapabilitiesContext {
+    /// A context whose random numbers are always the middle of the requested range
+    pub fn new() -> Self {
+        MockCapabilitiesContext {
+            values: RefCell::new(HashMap::new()),
+            lists: RefCell::new(HashMap::new()),
+            sets: RefCell::new(HashMap::new()),
+            expiring: RefCell::new(HashSet::new()),
+            published: RefCell::new(Vec::new()),
+            failing: RefCell::new(HashSet::new()),
+            read_counts: RefCell::new(HashMap::new()),
+            random: 1000,
+        }
+    }
+
+    pub fn with_value(self, key: &str, value: serde_json::Value) -> Self {
+        self.values
+            .borrow_mut()
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Stores the extractor under its rid and adds it to its entity's `extractors` collection
+    pub fn with_extractor(self, rid: &str, value: serde_json::Value) -> Self {
+        let collection = rid[..rid.rfind('.').unwrap()].replace('.', ":");
+        self.lists
+            .borrow_mut()
+            .entry(collection)
+            .or_default()
+            .push(rid.to_string());
+        self.with_value(&rid.replace('.', ":"), value)
+    }
+
+    /// Adds the member to the set, the way the entities holding a component are listed
+    pub fn with_member(self, key: &str, member: &str) -> Self {
+        self.sets
+            .borrow_mut()
+            .entry(key.to_string())
+            .or_default()
+            .insert(member.to_string());
+        self
+    }
+
+    /// Makes every publish on the subject fail
+    pub fn with_failing_subject(self, subject: &str) -> Self {
+        self.failing.borrow_mut().insert(subject.to_string());
+        self
+    }
+
+    /// Makes every write to the key fail
+    pub fn with_failing_key(self, key: &str) -> Self {
+        self.failing.borrow_mut().insert(key.to_string());
+        self
+    }
+
+    /// Lets every key written with an expiry expire
+    pub fn expire_all(&self) {
+        for key in self.expiring.borrow_mut().drain() {
+            self.values.borrow_mut().remove(&key);
+        }
+    }
+
+    /// Stores a plain string, the way configuration values are kept in the KV store
+    pub fn with_setting(self, key: &str, value: &str) -> Self {
+        self.values
+            .borrow_mut()
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Stores the params of a `set` on the component, the way the component manager applies
+    /// them: the fields of a partial update replace those of the stored component, and fields
+    /// set to `{ "action": "delete" }` are removed
+    pub fn apply_set(&self, rid: &str, params: &serde_json::Value) {
+        let key = rid.replace('.', ":");
+        let mut values = self.values.borrow_mut();
+        let mut stored = values
+            .get(&key)
+            .and_then(|value| serde_json::from_str::<serde_json::Value>(value).ok())
+            .filter(|value| value.is_object())
+            .unwrap_or_else(|| json!({}));
+        match params.as_object() {
+            Some(fields) => {
+                for (field, value) in fields {
+                    if *value == json!({ "action": "delete" }) {
+                        stored.as_object_mut().unwrap().remove(field);
+                    } else {
+                        stored[field] = value.clone();
+                    }
+                }
+            }
+            None => stored = params.clone(),
+        }
+        values.insert(key, stored.to_string());
+    }
+
+    /// The subjects published on so far, in order
+    pub fn subjects(&self) -> Vec<String> {
+        self.published
+            .borrow()
+            .iter()
+            .map(|(subject, _)| subject.to_string())
+            .collect()
+    }
+
+    /// How many times the key has been read from the KV store
+    pub fn reads(&self, key: &str) -> usize {
+        self.read_counts.borrow().get(key).copied().unwrap_or(0)
+    }
+
+    /// The payload of the first message published on the subject
+    pub fn payload(&self, subject: &str) -> Option<serde_json::Value> {
+        self.published
+            .borrow()
+            .iter()
+            .find(|(s, _)| s == subject)
+            .map(|(_, payload)| serde_json::from_slice(payload).unwrap())
+    }
+}
+
+impl Capabilities for MockCapabilitiesContext {
+    fn kv(&self) -> Box<dyn KeyValueStore + '_> {
+        Box::new(self)
+    }
+
+    fn msg(&self) -> Box<dyn MessageBroker + '_> {
+        Box::new(self)
+    }
+
+    fn extras(&self) -> Box<dyn Extras + '_> {
+        Box::new(self)
+    }
+
+    fn log(&self, _msg: &str) {}
+}
+
+impl KeyValueStore for &MockCapabilitiesContext {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        *self
+            .read_counts
+            .borrow_mut()
+            .entry(key.to_string())
+            .or_insert(0) += 1;
+        Ok(self.values.borrow().get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: &str, expires: Option<u32>) -> Result<()> {
+        if self.failing.borrow().contains(key) {
+            return Err(format!("failed to set {}", key).into());
+        }
+        self.values
+            .borrow_mut()
+            .insert(key.to_string(), value.to_string());
+        if expires.is_some() {
+            self.expiring.borrow_mut().insert(key.to_string());
+        }
+        Ok(())
+    }
+
+    fn del_key(&self, key: &str) -> Result<()> {
+        self.values.borrow_mut().remove(key);
+        self.lists.borrow_mut().remove(key);
+        self.sets.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    fn atomic_add(&self, key: &str, value: i32) -> Result<i32> {
+        let mut values = self.values.borrow_mut();
+        let counter = values
+            .entry(key.to_string())
+            .or_insert_with(|| "0".to_string());
+        let sum = counter.parse::<i32>()? + value;
+        *counter = sum.to_string();
+        Ok(sum)
+    }
+
+    fn list_add(&self, key: &str, item: &str) -> Result<usize> {
+        let mut lists = self.lists.borrow_mut();
+        let list = lists.entry(key.to_string()).or_default();
+        list.push(item.to_string());
+        Ok(list.len())
+    }
+
+    fn list_del_item(&self, key: &str, item: &str) -> Result<usize> {
+        let mut lists = self.lists.borrow_mut();
+        let list = match lists.get_mut(key) {
+            Some(list) => list,
+            None => return Ok(0),
+        };
+        let before = list.len();
+        list.retain(|i| i != item);
+        Ok(before - list.len())
+    }
+
+    fn list_range(&self, key: &str, start: isize, stop_inclusive: isize) -> Result<Vec<String>> {
+        let lists = self.lists.borrow();
+        let list = match lists.get(key) {
+            Some(list) => list,
+            None => return Ok(vec![]),
+        };
+        // Negative indexes count from the end of the list, as in Redis
+        let index = |i: isize| {
+            if i < 0 {
+                list.len() as isize + i
+            } else {
+                i
+            }
+        };
+        let (start, stop) = (index(start).max(0) as usize, index(stop_inclusive));
+        // A range ending before the start of the list is empty
+        if stop < 0 {
+            return Ok(vec![]);
+        }
+        let stop = stop as usize;
+        Ok(list
+            .iter()
+            .skip(start)
+            .take((stop + 1).saturating_sub(start))
+            .cloned()
+            .collect())
+    }
+
+    fn set_add(&self, key: &str, value: &str) -> Result<usize> {
+        let added = self
+            .sets
+            .borrow_mut()
+            .entry(key.to_string())
+            .or_default()
+            .insert(value.to_string());
+        Ok(added as usize)
+    }
+
+    fn set_remove(&self, key: &str, value: &str) -> Result<usize> {
+        let removed = self
+            .sets
+            .borrow_mut()
+            .get_mut(key)
+            .is_some_and(|set| set.remove(value));
+        Ok(removed as usize)
+    }
+
+    fn set_members(&self, key: &str) -> Result<Vec<String>> {
+        Ok(self
+            .sets
+            .borrow()
+            .get(key)
+            .map(|set| set.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+impl MessageBroker for &MockCapabilitiesContext {
+    fn publish(&self, subject: &str, _reply_to: Option<&str>, payload: &[u8]) -> Result<()> {
+        if self.failing.borrow().contains(subject) {
+            return Err(format!("failed to publish on {}", subject).into());
+        }
+        self.published
+            .borrow_mut()
+            .push((subject.to_string(), payload.to_vec()));
+        Ok(())
+    }
+}
+
+impl Extras for &MockCapabilitiesContext {
+    fn get_random(&self, min: u32, max: u32) -> Result<u32> {
+        Ok(self.random.max(min).min(max))
+    }
+}
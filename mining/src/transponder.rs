@@ -0,0 +1,264 @@
+//! # Depleted Transponders
+//!
+//! A depleted asteroid's transponder is greyed out, its name suffixed and its `depleted` flag
+//! set, which is what keeps extractors away from it. Before the depleted transponder is
+//! published, the original is backed up under the sibling KV key
+//! `decs:components:{shard}:{entity}:transponder_original`, so it can be restored later. The
+//! color and suffix default to `#A9A9A9` and ` (depleted)` and can be changed per shard under
+//! `decs:{shard}:mining:depleted_color` and `decs:{shard}:mining:depleted_suffix`. Without a
+//! backup, the original is pieced back together from the depleted transponder by stripping the
+//! suffix and using `#FFFFFF` as its color.
+use crate::capabilities::*;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const TRANSPONDER: &str = "transponder";
+const DEFAULT_DEPLETED_COLOR: &str = "#A9A9A9";
+const DEFAULT_DEPLETED_SUFFIX: &str = " (depleted)";
+const DEFAULT_RESTORED_COLOR: &str = "#FFFFFF";
+
+/// How the transponder of a depleted asteroid looks
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DepletedAppearance {
+    pub color: String,
+    pub suffix: String,
+}
+
+impl Default for DepletedAppearance {
+    fn default() -> Self {
+        DepletedAppearance {
+            color: DEFAULT_DEPLETED_COLOR.to_string(),
+            suffix: DEFAULT_DEPLETED_SUFFIX.to_string(),
+        }
+    }
+}
+
+/// The depleted appearance configured for the shard, falling back to the defaults
+fn depleted_appearance(ctx: &impl Capabilities, shard: &str) -> Result<DepletedAppearance> {
+    let defaults = DepletedAppearance::default();
+    Ok(DepletedAppearance {
+        color: ctx
+            .kv()
+            .get(&format!("decs:{}:mining:depleted_color", shard))?
+            .unwrap_or(defaults.color),
+        suffix: ctx
+            .kv()
+            .get(&format!("decs:{}:mining:depleted_suffix", shard))?
+            .unwrap_or(defaults.suffix),
+    })
+}
+
+/// The KV key holding the transponder an entity had before it was depleted
+pub(crate) fn backup_key(shard: &str, entity_id: &str) -> String {
+    format!(
+        "decs:components:{}:{}:{}_original",
+        shard, entity_id, TRANSPONDER
+    )
+}
+
+/// Backs up the asteroid's transponder and publishes its depleted version. Returns the original
+pub(crate) fn deplete_transponder(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<RadarTransponder, Box<dyn std::error::Error>> {
+    let original = get_transponder(ctx, shard, entity_id)?;
+    let depleted = depleted_transponder(&original, &depleted_appearance(ctx, shard)?);
+
+    ctx.kv().set(
+        &backup_key(shard, entity_id),
+        &serde_json::to_string(&original)?,
+        None,
+    )?;
+    publish_transponder(ctx, shard, entity_id, &depleted)?;
+    Ok(original)
+}
+
+/// The transponder the entity had before it was depleted: its backup if there is one,
+/// otherwise one synthesized from the given transponder
+pub(crate) fn original_transponder(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    archived: &RadarTransponder,
+) -> std::result::Result<RadarTransponder, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&backup_key(shard, entity_id))? {
+        Some(s) => Ok(serde_json::from_str(&s)?),
+        None => Ok(synthesized_original(
+            archived,
+            &depleted_appearance(ctx, shard)?,
+        )),
+    }
+}
+
+/// Undoes the depleted appearance as far as it can: the suffix is stripped from the name and
+/// the color, which is lost, replaced with a default. Transponders that aren't depleted are kept
+fn synthesized_original(
+    transponder: &RadarTransponder,
+    appearance: &DepletedAppearance,
+) -> RadarTransponder {
+    if !transponder.depleted {
+        return transponder.clone();
+    }
+    let display_name = transponder
+        .display_name
+        .strip_suffix(appearance.suffix.as_str())
+        .unwrap_or(&transponder.display_name);
+    RadarTransponder {
+        color: DEFAULT_RESTORED_COLOR.to_string(),
+        display_name: display_name.to_string(),
+        object_type: transponder.object_type.clone(),
+        radar_signature: transponder.radar_signature,
+        depleted: false,
+    }
+}
+
+pub(crate) fn depleted_transponder(
+    old_tp: &RadarTransponder,
+    appearance: &DepletedAppearance,
+) -> RadarTransponder {
+    RadarTransponder {
+        color: appearance.color.to_string(),
+        display_name: format!("{}{}", old_tp.display_name, appearance.suffix),
+        object_type: old_tp.object_type.clone(),
+        radar_signature: old_tp.radar_signature,
+        depleted: true,
+    }
+}
+
+/// Whether the entity's transponder marks it as depleted. Entities without a readable
+/// transponder are not
+pub(crate) fn transponder_depleted(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<bool, Box<dyn std::error::Error>> {
+    let raw = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, TRANSPONDER
+    ))?;
+    Ok(raw
+        .and_then(|s| serde_json::from_str::<RadarTransponder>(&s).ok())
+        .is_some_and(|transponder| transponder.depleted))
+}
+
+fn publish_transponder(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity_id: &str,
+    transponder: &RadarTransponder,
+) -> Result<()> {
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard, entity_id, TRANSPONDER
+        ),
+        None,
+        &serde_json::to_vec(&json!({ "params": transponder }))?,
+    )
+}
+
+fn get_transponder(
+    ctx: &impl Capabilities,
+    shard: &str,
+    entity: &str,
+) -> std::result::Result<RadarTransponder, Box<dyn std::error::Error>> {
+    let raw = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity, TRANSPONDER
+    ))?;
+    match raw {
+        Some(s) => match serde_json::from_str(&s) {
+            Ok(t) => Ok(t),
+            Err(_) => Err("unable to retrieve transponder".into()),
+        },
+        None => Err("attempted to retrieve non-existent transponder".into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::backup_key;
+    use super::deplete_transponder;
+    use super::original_transponder;
+    use super::RadarTransponder;
+    use crate::test_utils::MockCapabilitiesContext;
+
+    const SUBJECT: &str = "call.decs.components.the_void.asteroid1.transponder.set";
+
+    fn asteroid() -> MockCapabilitiesContext {
+        MockCapabilitiesContext::new().with_value(
+            "decs:components:the_void:asteroid1:transponder",
+            json!({
+                "object_type": "asteroid",
+                "display_name": "Ceres",
+                "color": "#FFA500",
+                "radar_signature": 1.0
+            }),
+        )
+    }
+
+    fn published(ctx: &MockCapabilitiesContext, index: usize) -> RadarTransponder {
+        let (subject, payload) = ctx.published.borrow()[index].clone();
+        assert_eq!(subject, SUBJECT);
+        let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        serde_json::from_value(payload["params"].clone()).unwrap()
+    }
+
+    #[test]
+    fn test_deplete_and_restore_round_trip() {
+        let ctx = asteroid();
+        let original = deplete_transponder(&ctx, "the_void", "asteroid1").unwrap();
+        assert_eq!(original.display_name, "Ceres");
+
+        let depleted = published(&ctx, 0);
+        assert_eq!(depleted.display_name, "Ceres (depleted)");
+        assert_eq!(depleted.color, "#A9A9A9");
+        let key = backup_key("the_void", "asteroid1");
+        assert_eq!(
+            key,
+            "decs:components:the_void:asteroid1:transponder_original"
+        );
+        assert!(ctx.values.borrow().contains_key(&key));
+
+        let restored = original_transponder(&ctx, "the_void", "asteroid1", &depleted).unwrap();
+        assert_eq!(restored, original);
+        assert_eq!(restored.display_name, "Ceres");
+    }
+
+    #[test]
+    fn test_missing_backup_is_synthesized() {
+        let ctx = asteroid().with_setting("decs:the_void:mining:depleted_suffix", " [empty]");
+        deplete_transponder(&ctx, "the_void", "asteroid1").unwrap();
+        let depleted = published(&ctx, 0);
+        ctx.values
+            .borrow_mut()
+            .remove(&backup_key("the_void", "asteroid1"));
+
+        let restored = original_transponder(&ctx, "the_void", "asteroid1", &depleted).unwrap();
+        assert_eq!(restored.display_name, "Ceres");
+        assert_eq!(restored.color, "#FFFFFF");
+        assert!(!restored.depleted);
+        assert_eq!(restored.object_type, "asteroid");
+
+        // A transponder that was never depleted is already the original
+        let original = RadarTransponder {
+            color: "#FFA500".to_string(),
+            ..restored
+        };
+        let kept = original_transponder(&ctx, "the_void", "asteroid1", &original).unwrap();
+        assert_eq!(kept, original);
+    }
+
+    #[test]
+    fn test_depleted_appearance_is_configurable() {
+        let ctx = asteroid()
+            .with_setting("decs:the_void:mining:depleted_color", "grey")
+            .with_setting("decs:the_void:mining:depleted_suffix", " [empty]");
+        deplete_transponder(&ctx, "the_void", "asteroid1").unwrap();
+        let depleted = published(&ctx, 0);
+        assert_eq!(depleted.display_name, "Ceres [empty]");
+        assert_eq!(depleted.color, "grey");
+    }
+}
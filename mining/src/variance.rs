@@ -0,0 +1,133 @@
+//! # Yield Variance
+//!
+//! The quantity an extraction yields varies around the resource's `qty` by up to a bound set by
+//! the resource's rarity (±15% for common resources). The variance is rolled through the host's
+//! random capability rather than a local RNG, so the host controls and can audit every yield.
+//! A yield is never less than one unit.
+use crate::capabilities::*;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+// Rolls are drawn from [0, ROLL_MAX], the midpoint yields exactly the resource's quantity
+const ROLL_MAX: u32 = 2000;
+
+/// Source of the random rolls that vary yields
+pub(crate) trait RandomSource {
+    fn random(&self, min: u32, max: u32) -> Result<u32>;
+}
+
+impl<C: Capabilities> RandomSource for C {
+    fn random(&self, min: u32, max: u32) -> Result<u32> {
+        self.extras().get_random(min, max)
+    }
+}
+
+/// Rolls the quantity actually extracted from the resource
+pub(crate) fn apply_yield_variance(
+    random: &impl RandomSource,
+    resource: &MiningResource,
+) -> Result<MiningResource> {
+    let roll = random.random(0, ROLL_MAX)?;
+    Ok(MiningResource {
+        qty: varied_qty(resource.qty, variance_bound(resource.rarity), roll),
+        ..resource.clone()
+    })
+}
+
+/// Scales the quantity extracted by the miner's yield multiplier
+pub(crate) fn apply_yield_multiplier(mined: MiningResource, multiplier: f64) -> MiningResource {
+    MiningResource {
+        qty: (f64::from(mined.qty) * multiplier).round() as u32,
+        ..mined
+    }
+}
+
+/// The largest fraction by which a resource of the given rarity can deviate from its quantity
+fn variance_bound(rarity: Rarity) -> f64 {
+    match rarity {
+        Rarity::Common => 0.15,
+        Rarity::Uncommon => 0.25,
+        Rarity::Rare => 0.40,
+        Rarity::Epic => 0.50,
+    }
+}
+
+fn varied_qty(qty: u32, bound: f64, roll: u32) -> u32 {
+    // Maps the roll onto [-bound, +bound]
+    let deviation = bound * (f64::from(roll.min(ROLL_MAX)) / f64::from(ROLL_MAX) * 2.0 - 1.0);
+    let varied = (f64::from(qty) * (1.0 + deviation)).round();
+    if varied < 1.0 {
+        1
+    } else {
+        varied as u32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::apply_yield_variance;
+    use super::MiningResource;
+    use super::RandomSource;
+    use super::Rarity;
+    use super::ROLL_MAX;
+    use guest::prelude::*;
+
+    /// Always rolls the same value
+    struct PinnedRoll(u32);
+
+    impl RandomSource for PinnedRoll {
+        fn random(&self, min: u32, max: u32) -> Result<u32> {
+            assert_eq!((min, max), (0, ROLL_MAX));
+            Ok(self.0)
+        }
+    }
+
+    fn resource(qty: u32, rarity: Rarity) -> MiningResource {
+        MiningResource {
+            stack_type: "tasty".to_string(),
+            qty,
+            rarity,
+            base_value: None,
+        }
+    }
+
+    fn yield_of(roll: u32, resource: &MiningResource) -> u32 {
+        apply_yield_variance(&PinnedRoll(roll), resource)
+            .unwrap()
+            .qty
+    }
+
+    #[test]
+    fn test_common_yield_varies_by_fifteen_percent() {
+        let common = resource(100, Rarity::Common);
+        assert_eq!(yield_of(0, &common), 85);
+        assert_eq!(yield_of(ROLL_MAX / 2, &common), 100);
+        assert_eq!(yield_of(ROLL_MAX, &common), 115);
+        assert_eq!(yield_of(1600, &common), 109);
+    }
+
+    #[test]
+    fn test_rarity_widens_the_variance() {
+        assert_eq!(yield_of(0, &resource(100, Rarity::Uncommon)), 75);
+        assert_eq!(yield_of(ROLL_MAX, &resource(100, Rarity::Uncommon)), 125);
+        assert_eq!(yield_of(0, &resource(100, Rarity::Rare)), 60);
+        assert_eq!(yield_of(ROLL_MAX, &resource(100, Rarity::Rare)), 140);
+        assert_eq!(yield_of(0, &resource(100, Rarity::Epic)), 50);
+        assert_eq!(yield_of(ROLL_MAX, &resource(100, Rarity::Epic)), 150);
+    }
+
+    #[test]
+    fn test_yield_is_never_zero() {
+        assert_eq!(yield_of(0, &resource(1, Rarity::Rare)), 1);
+        assert_eq!(yield_of(0, &resource(0, Rarity::Common)), 1);
+    }
+
+    #[test]
+    fn test_variance_keeps_the_stack_type() {
+        let rare = resource(10, Rarity::Rare);
+        let mined = apply_yield_variance(&PinnedRoll(ROLL_MAX), &rare).unwrap();
+        assert_eq!(mined.stack_type, rare.stack_type);
+        assert_eq!(mined.rarity, rare.rarity);
+    }
+}
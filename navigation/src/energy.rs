@@ -0,0 +1,72 @@
+//! # Energy
+//!
+//! Navigation draws on the ship's `energy_core` for every frame it steers the entity, at the
+//! `cost_per_ms` of the `navigation` entry in the entity's `energy_costs`. Navigation is last in
+//! the energy priority, so it is the first system to shut down when energy runs low: a starved
+//! frame leaves the velocity untouched and publishes `event.decs.system.energy.insufficient`.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::energy::{self, EnergyDraw};
+
+const ENERGY_CORE: &str = "energy_core";
+const ENERGY_COSTS: &str = "energy_costs";
+
+/// Draws the energy for the system's frame, publishing the drained core. Returns whether the
+/// system has the energy to run
+pub(crate) fn draw_frame_energy(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    system: &str,
+    elapsed_ms: u32,
+) -> std::result::Result<bool, Box<dyn std::error::Error>> {
+    let core: Option<EnergyCore> = get_component(ctx, shard, entity_id, ENERGY_CORE)?;
+    let costs: Vec<EnergyCost> =
+        get_component(ctx, shard, entity_id, ENERGY_COSTS)?.unwrap_or_default();
+
+    match energy::draw_energy(core.as_ref(), &costs, system, elapsed_ms) {
+        EnergyDraw::Free => Ok(true),
+        EnergyDraw::Powered(new_core) => {
+            if Some(&new_core) != core.as_ref() {
+                ctx.msg().publish(
+                    &format!(
+                        "call.decs.components.{}.{}.{}.set",
+                        shard, entity_id, ENERGY_CORE
+                    ),
+                    None,
+                    &serde_json::to_vec(&json!({ "params": new_core }))?,
+                )?;
+            }
+            Ok(true)
+        }
+        EnergyDraw::Insufficient {
+            required,
+            available,
+        } => {
+            ctx.msg().publish(
+                energy::INSUFFICIENT_ENERGY_EVENT,
+                None,
+                &serde_json::to_vec(&energy::insufficient_energy_event(
+                    shard, entity_id, system, required, available,
+                ))?,
+            )?;
+            Ok(false)
+        }
+    }
+}
+
+fn get_component<T: serde::de::DeserializeOwned>(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> std::result::Result<Option<T>, Box<dyn std::error::Error>> {
+    match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, component
+    ))? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
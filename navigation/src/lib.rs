@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[macro_use]
+extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 extern crate decscloud_common as decs;
@@ -19,6 +21,7 @@ extern crate waxosuit_guest as guest;
 
 use decs::systemmgr::*;
 use guest::prelude::*;
+use stacktrader_types::metrics;
 
 call_handler!(handle_call);
 
@@ -26,7 +29,16 @@ const NO_MESSAGE: &str = "(no message)";
 const POSITION: &str = "position";
 const VELOCITY: &str = "velocity";
 const TARGET: &str = "target";
+const NAVIGATION_WAYPOINT: &str = "navigation_waypoint";
+const WAYPOINT_QUEUE: &str = "waypoint_queue";
+const NPC_PATROL: &str = "npc_patrol";
+const EXPLORATION_MAP: &str = "exploration_map";
+const INTER_SHARD_ROUTE: &str = "inter_shard_route";
 const SYSTEM_NAME: &str = "navigation";
+const WAYPOINT_SYSTEM_NAME: &str = "waypoint";
+const NPC_PATROL_SYSTEM_NAME: &str = "npc_patrol";
+const MAP_SYSTEM_NAME: &str = "map";
+const MULTI_SHARD_NAV_SYSTEM_NAME: &str = "multi_shard_nav";
 const REGISTRY_SUBJECT: &str = "decs.system.registry";
 const FRAMERATE: u32 = 1;
 
@@ -38,7 +50,10 @@ pub fn handle_call(ctx: &CapabilitiesContext, operation: &str, msg: &[u8]) -> Ca
     }
 }
 
-/// Routes message either to the `handle_ping` function for registry pings or `handle_frame` for position updates
+/// Routes message either to the `handle_ping` function for registry pings, the waypoint system for
+/// waypoint frames and inserts, the patrol system for NPC patrol frames, the map system for
+/// exploration frames, the multi-shard navigation system for inter-shard route frames, or
+/// `handle_frame` for position updates
 fn handle_message(
     ctx: &CapabilitiesContext,
     msg: impl Into<messaging::DeliverMessage>,
@@ -54,33 +69,103 @@ fn handle_message(
     match subject.as_ref() {
         NO_MESSAGE => Err("No message".into()),
         REGISTRY_SUBJECT => handle_ping(ctx, msg.unwrap()),
-        _ => nav::handle_frame(ctx, msg.unwrap()),
+        s if s.starts_with("decs.frames.") && s.ends_with(".waypoint") => {
+            timed_frame(ctx, msg.unwrap(), waypoint::handle_frame)
+        }
+        s if s.starts_with("event.") && s.ends_with(".nav.waypoint_insert") => {
+            waypoint::handle_waypoint_insert(ctx, msg.unwrap())
+        }
+        s if s.starts_with("decs.frames.") && s.ends_with(".npc_patrol") => {
+            timed_frame(ctx, msg.unwrap(), patrol::handle_frame)
+        }
+        s if s.starts_with("decs.frames.") && s.ends_with(".map") => {
+            timed_frame(ctx, msg.unwrap(), map::handle_frame)
+        }
+        s if s.starts_with("decs.frames.") && s.ends_with(".multi_shard_nav") => {
+            timed_frame(ctx, msg.unwrap(), multi_shard_nav::handle_frame)
+        }
+        _ => timed_frame(ctx, msg.unwrap(), nav::handle_frame),
     }
 }
 
-/// Receives messages on the subject `system.registry` and replies with physics system metadata
+/// Runs a frame handler, publishing how long the frame took to process and storing a summary
+/// of the system's recent frame times every 100 frames, see `metrics::timed_frame`
+fn timed_frame(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+    handler: fn(&CapabilitiesContext, messaging::BrokerMessage) -> CallResult,
+) -> CallResult {
+    let body = msg.body.clone();
+    let subject = msg.subject.clone();
+    metrics::timed_frame(
+        &metrics::SystemClock,
+        metrics::frame_system(&subject),
+        &body,
+        || handler(ctx, msg),
+        |subject, payload| ctx.msg().publish(subject, None, payload),
+        |key, value| ctx.kv().set(key, value, None),
+        |msg| ctx.log(msg),
+    )
+}
+
+/// Receives messages on the subject `system.registry` and replies with the metadata of
+/// the navigation, waypoint, NPC patrol, map and multi-shard navigation systems
 fn handle_ping(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
-    let payload = System {
-        name: SYSTEM_NAME.to_string(),
-        framerate: FRAMERATE,
-        components: vec![
-            POSITION.to_string(),
-            VELOCITY.to_string(),
-            TARGET.to_string(),
-        ],
-    };
+    let payloads = vec![
+        System {
+            name: SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![
+                POSITION.to_string(),
+                VELOCITY.to_string(),
+                TARGET.to_string(),
+            ],
+        },
+        System {
+            name: WAYPOINT_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![
+                POSITION.to_string(),
+                VELOCITY.to_string(),
+                WAYPOINT_QUEUE.to_string(),
+            ],
+        },
+        System {
+            name: NPC_PATROL_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![POSITION.to_string(), NPC_PATROL.to_string()],
+        },
+        System {
+            name: MAP_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![POSITION.to_string(), EXPLORATION_MAP.to_string()],
+        },
+        System {
+            name: MULTI_SHARD_NAV_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![POSITION.to_string(), INTER_SHARD_ROUTE.to_string()],
+        },
+    ];
     let reply_to = if msg.reply_to.is_empty() {
         format!("{}.replies", REGISTRY_SUBJECT)
     } else {
         msg.reply_to
     };
-    if let Err(e) = ctx
-        .msg()
-        .publish(&reply_to, None, &serde_json::to_vec(&payload)?)
-    {
-        return Err(format!("Error publishing message: {}", e).into());
-    };
+    for payload in payloads {
+        if let Err(e) = ctx
+            .msg()
+            .publish(&reply_to, None, &serde_json::to_vec(&payload)?)
+        {
+            return Err(format!("Error publishing message: {}", e).into());
+        };
+    }
     Ok(vec![])
 }
 
+mod energy;
+mod map;
+mod multi_shard_nav;
 mod nav;
+mod patrol;
+mod signal_interference;
+mod waypoint;
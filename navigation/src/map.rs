@@ -0,0 +1,138 @@
+//! # Exploration Map
+//!
+//! The map system awaits frames for entities that have an `exploration_map` component and
+//! records which grid cells of the shard they have visited. Each frame, the entity's position is
+//! floor-divided by the map's `cell_size` to find its current cell. A cell the entity hasn't been
+//! to before is added to the map, the map is published with a `set` so it persists in the KV
+//! store between sessions, and the discovery is published on
+//! `event.decs.system.map.new_cell_explored` along with the entity's coverage of the shard. The
+//! coverage is measured against the shard's `TotalCells`, stored under the KV key
+//! `decs:{shard}:map:total_cells`, and is left out while that isn't known.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const NEW_CELL_EVENT: &str = "event.decs.system.map.new_cell_explored";
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let position_value = get_component(ctx, &frame.shard, &frame.entity_id, super::POSITION)?;
+    let map_value = get_component(ctx, &frame.shard, &frame.entity_id, super::EXPLORATION_MAP)?;
+    if let (Some(position_str), Some(map_str)) = (position_value, map_value) {
+        let position: Position = serde_json::from_str(&position_str)?;
+        let mut map: ExplorationMap = serde_json::from_str(&map_str)?;
+
+        if let Some(cell) = explore(&mut map, &position) {
+            let total_cells: Option<TotalCells> = match ctx
+                .kv()
+                .get(&format!("decs:{}:map:total_cells", frame.shard))?
+            {
+                Some(s) => serde_json::from_str(&s).ok(),
+                None => None,
+            };
+            let coverage_pct = total_cells.and_then(|t| t.coverage_pct(map.explored_cells.len()));
+
+            ctx.msg().publish(
+                &format!(
+                    "call.decs.components.{}.{}.{}.set",
+                    frame.shard,
+                    frame.entity_id,
+                    super::EXPLORATION_MAP
+                ),
+                None,
+                &serde_json::to_vec(&json!({ "params": map }))?,
+            )?;
+            ctx.msg().publish(
+                NEW_CELL_EVENT,
+                None,
+                &serde_json::to_vec(&json!({
+                    "shard": frame.shard,
+                    "entity": frame.entity_id,
+                    "cell": [cell.0, cell.1, cell.2],
+                    "explored_cells": map.explored_cells.len(),
+                    "coverage_pct": coverage_pct,
+                }))?,
+            )?;
+        }
+    }
+
+    Ok(vec![])
+}
+
+fn get_component(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> Result<Option<String>> {
+    ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, component
+    ))
+}
+
+/// Marks the cell containing the position as explored, returning it if it is a new discovery
+fn explore(map: &mut ExplorationMap, position: &Position) -> Option<(i64, i64, i64)> {
+    let cell = map.cell_of(position);
+    if map.explored_cells.insert(cell) {
+        Some(cell)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::explore;
+    use super::ExplorationMap;
+    use super::Position;
+    use super::TotalCells;
+
+    fn map(cell_size: f64) -> ExplorationMap {
+        ExplorationMap {
+            cell_size,
+            ..ExplorationMap::default()
+        }
+    }
+
+    #[test]
+    fn test_negative_coordinates_round_down() {
+        let map = map(10.0);
+        assert_eq!(map.cell_of(&Position::new(-0.5, -9.9, -10.1)), (-1, -1, -2));
+        assert_eq!(map.cell_of(&Position::new(5.0, -5.0, 15.0)), (0, -1, 1));
+    }
+
+    #[test]
+    fn test_boundary_belongs_to_the_cell_above() {
+        let map = map(10.0);
+        assert_eq!(map.cell_of(&Position::new(0.0, 0.0, 0.0)), (0, 0, 0));
+        assert_eq!(map.cell_of(&Position::new(10.0, -10.0, 20.0)), (1, -1, 2));
+        assert_eq!(map.cell_of(&Position::new(9.999, -10.001, 0.0)), (0, -2, 0));
+    }
+
+    #[test]
+    fn test_unusable_cell_size_falls_back_to_default() {
+        let position = Position::new(25.0, -5.0, 0.0);
+        assert_eq!(map(0.0).cell_of(&position), (2, -1, 0));
+        assert_eq!(map(f64::NAN).cell_of(&position), (2, -1, 0));
+    }
+
+    #[test]
+    fn test_only_new_cells_are_discoveries() {
+        let mut map = map(10.0);
+        assert_eq!(
+            explore(&mut map, &Position::new(1.0, 1.0, 1.0)),
+            Some((0, 0, 0))
+        );
+        assert_eq!(explore(&mut map, &Position::new(9.0, 2.0, 3.0)), None);
+        assert_eq!(
+            explore(&mut map, &Position::new(-1.0, 2.0, 3.0)),
+            Some((-1, 0, 0))
+        );
+        assert_eq!(map.explored_cells.len(), 2);
+
+        assert_eq!(TotalCells { count: 8 }.coverage_pct(2), Some(25.0));
+        assert_eq!(TotalCells { count: 0 }.coverage_pct(2), None);
+    }
+}
@@ -0,0 +1,456 @@
+//! # Inter-Shard Routes
+//!
+//! The multi-shard navigation system awaits frames for entities that have an `inter_shard_route`
+//! component, the warp gates they travel through to reach another shard. Once an entity is within
+//! `GATE_ARRIVAL_RADIUS_KM` of the gate entity of its next hop, it warps out of the shard:
+//! - `warp.initiate` is published for the gate, on `event.decs.{shard}.{gate}.warp.initiate`
+//! - a snapshot of the entity's components is published on `event.decs.system.shard.migrate`
+//!   along with the destination shard and gate. The snapshot's route holds the hops left after
+//!   this one and is left out once none are
+//! - each of the entity's components in the current shard is deleted
+//!
+//! The deletes take a while to land, and frames for the entity keep arriving at the gate until
+//! they have. Before warping, the entity is marked as warping with a KV key that only the first
+//! frame can claim, so later frames don't warp it again. The mark expires after
+//! `WARPING_TTL_SECONDS`, and is given up if the warp fails before the migration is published.
+//!
+//! The KV store can't scan keys, so the snapshot is made of the `MIGRATED_COMPONENTS` and the
+//! items of the `MIGRATED_COLLECTIONS` the entity has.
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use std::collections::BTreeMap;
+use trader::components::*;
+
+const MIGRATE_EVENT: &str = "event.decs.system.shard.migrate";
+const GATE_ARRIVAL_RADIUS_KM: f64 = 1.0;
+// Long enough for the deletes of a warped entity to reach the KV store
+const WARPING_TTL_SECONDS: u32 = 60;
+
+/// The components carried from one shard to the next
+const MIGRATED_COMPONENTS: &[&str] = &[
+    super::POSITION,
+    super::VELOCITY,
+    super::TARGET,
+    super::NAVIGATION_WAYPOINT,
+    super::WAYPOINT_QUEUE,
+    super::INTER_SHARD_ROUTE,
+    "transponder",
+    "wallet",
+    "fuel_tank",
+    "energy_core",
+    "energy_costs",
+    "cargo_hold",
+    "hull_integrity",
+    "mining_stats",
+];
+
+/// The collections whose items are carried from one shard to the next
+const MIGRATED_COLLECTIONS: &[&str] = &["inventory"];
+
+/// Body of a migration message: the entity's components, by name, as they are to be created in
+/// the destination shard. Collections hold the list of their items
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct ShardMigration {
+    entity_id: String,
+    from_shard: String,
+    to_shard: String,
+    entry_gate_id: String,
+    components: BTreeMap<String, Value>,
+}
+
+/// The entity's components in the current shard and the items of its collections, by their RID
+#[derive(Debug, Default, PartialEq)]
+struct EntitySnapshot {
+    components: BTreeMap<String, Value>,
+    collections: BTreeMap<String, Vec<(String, Value)>>,
+}
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let position_value = get_component(ctx, &frame.shard, &frame.entity_id, super::POSITION)?;
+    let route_value = get_component(
+        ctx,
+        &frame.shard,
+        &frame.entity_id,
+        super::INTER_SHARD_ROUTE,
+    )?;
+    if let (Some(position_str), Some(route_str)) = (position_value, route_value) {
+        let position: Position = serde_json::from_str(&position_str)?;
+        let route: InterShardRoute = serde_json::from_str(&route_str)?;
+        let hop = match route.hops.first() {
+            Some(hop) => hop,
+            None => return Ok(vec![]),
+        };
+        let gate_position: Position =
+            match get_component(ctx, &frame.shard, &hop.exit_gate_id, super::POSITION)? {
+                Some(s) => serde_json::from_str(&s)?,
+                None => {
+                    ctx.log(&format!(
+                        "Gate {} of entity {}'s route not found in shard {}",
+                        hop.exit_gate_id, frame.entity_id, frame.shard
+                    ));
+                    return Ok(vec![]);
+                }
+            };
+        if !has_arrived(&position, &gate_position) {
+            return Ok(vec![]);
+        }
+
+        let key = warping_key(&frame.shard, &frame.entity_id);
+        if !mark_warping(ctx, &key)? {
+            return Ok(vec![]);
+        }
+        let mut migrated = false;
+        let warped = read_snapshot(ctx, &frame.shard, &frame.entity_id).and_then(|snapshot| {
+            for (subject, payload) in plan_warp(&frame.shard, &frame.entity_id, &route, &snapshot) {
+                ctx.msg()
+                    .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+                migrated = migrated || subject == MIGRATE_EVENT;
+            }
+            Ok(())
+        });
+        // Until the migration is out, the next frame may try again. After it, the entity
+        // lives on in the destination shard and must not be warped a second time
+        if let Err(e) = warped {
+            if !migrated {
+                ctx.kv().del_key(&key)?;
+            }
+            return Err(e);
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// Marks the entity as warping out of the shard. Returns false if it already is, in which case
+/// nothing must be published
+fn mark_warping(ctx: &CapabilitiesContext, key: &str) -> Result<bool> {
+    // The increment is atomic, so exactly one frame sees the counter go from 0 to 1
+    if ctx.kv().atomic_add(key, 1)? != 1 {
+        return Ok(false);
+    }
+    // Resetting the counter gives the key its expiry, later frames still see it above 1. A key
+    // left without one would keep the entity from ever warping out again, so it's given up
+    if let Err(e) = ctx.kv().set(key, "1", Some(WARPING_TTL_SECONDS)) {
+        ctx.kv().del_key(key)?;
+        return Err(e);
+    }
+    Ok(true)
+}
+
+fn warping_key(shard: &str, entity_id: &str) -> String {
+    format!("decs:navigation:warping:{}:{}", shard, entity_id)
+}
+
+fn has_arrived(position: &Position, gate_position: &Position) -> bool {
+    position.distance_to_3d(gate_position) <= GATE_ARRIVAL_RADIUS_KM
+}
+
+/// Reads the entity's migrated components and the items of its migrated collections. An item
+/// that can't be read fails the snapshot rather than being left behind, see `read_items`
+fn read_snapshot(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+) -> std::result::Result<EntitySnapshot, Box<dyn std::error::Error>> {
+    let mut snapshot = EntitySnapshot::default();
+    for component in MIGRATED_COMPONENTS {
+        if let Some(s) = get_component(ctx, shard, entity_id, component)? {
+            snapshot
+                .components
+                .insert(component.to_string(), serde_json::from_str(&s)?);
+        }
+    }
+    for collection in MIGRATED_COLLECTIONS {
+        let rids = ctx.kv().list_range(
+            &format!("decs:components:{}:{}:{}", shard, entity_id, collection),
+            0,
+            -1,
+        )?;
+        let items = read_items(rids, |key| ctx.kv().get(key))?;
+        if !items.is_empty() {
+            snapshot.collections.insert(collection.to_string(), items);
+        }
+    }
+    Ok(snapshot)
+}
+
+/// Reads the items of a collection by their RID. A RID whose item no longer exists has nothing
+/// to carry over and is skipped, an item that can't be read or parsed is an error
+fn read_items(
+    rids: Vec<String>,
+    get: impl Fn(&str) -> Result<Option<String>>,
+) -> std::result::Result<Vec<(String, Value)>, Box<dyn std::error::Error>> {
+    let mut items = Vec::new();
+    for rid in rids {
+        let item = get(&rid.replace('.', ":"))
+            .map_err(|e| format!("Failed to read item {}: {}", rid, e))?;
+        if let Some(s) = item {
+            let item = serde_json::from_str(&s)
+                .map_err(|e| format!("Failed to parse item {}: {}", rid, e))?;
+            items.push((rid, item));
+        }
+    }
+    Ok(items)
+}
+
+/// The messages (subject and payload) that warp the entity out of the shard through the gate
+/// of the route's next hop
+fn plan_warp(
+    shard: &str,
+    entity_id: &str,
+    route: &InterShardRoute,
+    snapshot: &EntitySnapshot,
+) -> Vec<(String, Value)> {
+    let hop = match route.hops.first() {
+        Some(hop) => hop,
+        None => return vec![],
+    };
+    let mut messages = vec![(
+        format!("event.decs.{}.{}.warp.initiate", shard, hop.exit_gate_id),
+        json!({
+            "entity": entity_id,
+            "entry_shard": hop.entry_shard,
+            "entry_gate_id": hop.entry_gate_id,
+        }),
+    )];
+
+    let mut components = snapshot.components.clone();
+    let remaining = InterShardRoute {
+        hops: route.hops[1..].to_vec(),
+    };
+    if remaining.hops.is_empty() {
+        components.remove(super::INTER_SHARD_ROUTE);
+    } else {
+        components.insert(super::INTER_SHARD_ROUTE.to_string(), json!(remaining));
+    }
+    for (collection, items) in &snapshot.collections {
+        let items: Vec<&Value> = items.iter().map(|(_rid, item)| item).collect();
+        components.insert(collection.to_string(), json!(items));
+    }
+    let migration = ShardMigration {
+        entity_id: entity_id.to_string(),
+        from_shard: shard.to_string(),
+        to_shard: hop.entry_shard.to_string(),
+        entry_gate_id: hop.entry_gate_id.to_string(),
+        components,
+    };
+    messages.push((MIGRATE_EVENT.to_string(), json!(migration)));
+
+    messages.extend(snapshot.components.keys().map(|component| {
+        let rid = format!("decs.components.{}.{}.{}", shard, entity_id, component);
+        (
+            format!("call.{}.delete", rid),
+            json!({ "params": { "rid": rid } }),
+        )
+    }));
+    // Items are deleted through their collection
+    for (collection, items) in &snapshot.collections {
+        let collection_rid = format!("decs.components.{}.{}.{}", shard, entity_id, collection);
+        messages.extend(items.iter().map(|(rid, _item)| {
+            (
+                format!("call.{}.delete", collection_rid),
+                json!({ "params": { "rid": rid } }),
+            )
+        }));
+    }
+    messages
+}
+
+fn get_component(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> Result<Option<String>> {
+    ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, component
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::has_arrived;
+    use super::plan_warp;
+    use super::read_items;
+    use super::EntitySnapshot;
+    use super::InterShardRoute;
+    use super::Position;
+    use super::ShardHop;
+    use super::MIGRATE_EVENT;
+
+    fn hop(exit_gate_id: &str, entry_shard: &str, entry_gate_id: &str) -> ShardHop {
+        ShardHop {
+            exit_gate_id: exit_gate_id.to_string(),
+            entry_shard: entry_shard.to_string(),
+            entry_gate_id: entry_gate_id.to_string(),
+        }
+    }
+
+    fn snapshot(route: &InterShardRoute) -> EntitySnapshot {
+        let mut snapshot = EntitySnapshot::default();
+        snapshot.components.insert(
+            "position".to_string(),
+            json!({ "x": 10.0, "y": 0.0, "z": 0.5 }),
+        );
+        snapshot
+            .components
+            .insert("wallet".to_string(), json!({ "credits": 300 }));
+        snapshot
+            .components
+            .insert("inter_shard_route".to_string(), json!(route));
+        snapshot
+    }
+
+    fn subjects(messages: &[(String, serde_json::Value)]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|(subject, _payload)| subject.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_single_hop_warps_to_the_destination() {
+        let gate = Position {
+            x: 10.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        assert!(has_arrived(
+            &Position {
+                x: 10.0,
+                y: 0.0,
+                z: 0.5
+            },
+            &gate
+        ));
+        assert!(!has_arrived(&Position::default(), &gate));
+
+        let route = InterShardRoute {
+            hops: vec![hop("gate1", "shard_two", "gate2")],
+        };
+        let mut snapshot = snapshot(&route);
+        snapshot.collections.insert(
+            "inventory".to_string(),
+            vec![(
+                "decs.components.the_void.player1.inventory.4".to_string(),
+                json!({ "name": "tasty", "qty": 3 }),
+            )],
+        );
+        let messages = plan_warp("the_void", "player1", &route, &snapshot);
+
+        assert_eq!(
+            subjects(&messages),
+            vec![
+                "event.decs.the_void.gate1.warp.initiate",
+                MIGRATE_EVENT,
+                "call.decs.components.the_void.player1.inter_shard_route.delete",
+                "call.decs.components.the_void.player1.position.delete",
+                "call.decs.components.the_void.player1.wallet.delete",
+                "call.decs.components.the_void.player1.inventory.delete",
+            ]
+        );
+        assert_eq!(
+            messages[0].1,
+            json!({ "entity": "player1", "entry_shard": "shard_two", "entry_gate_id": "gate2" })
+        );
+        // The route is complete, so it isn't carried over
+        assert_eq!(
+            messages[1].1,
+            json!({
+                "entity_id": "player1",
+                "from_shard": "the_void",
+                "to_shard": "shard_two",
+                "entry_gate_id": "gate2",
+                "components": {
+                    "position": { "x": 10.0, "y": 0.0, "z": 0.5 },
+                    "wallet": { "credits": 300 },
+                    "inventory": [{ "name": "tasty", "qty": 3 }]
+                }
+            })
+        );
+        assert_eq!(
+            messages[5].1,
+            json!({ "params": { "rid": "decs.components.the_void.player1.inventory.4" } })
+        );
+    }
+
+    #[test]
+    fn test_two_hop_route_carries_the_remaining_hop() {
+        let route = InterShardRoute {
+            hops: vec![
+                hop("gate1", "shard_two", "gate2"),
+                hop("gate3", "shard_three", "gate4"),
+            ],
+        };
+        let messages = plan_warp("the_void", "player1", &route, &snapshot(&route));
+        assert_eq!(messages[1].1["to_shard"], "shard_two");
+        assert_eq!(
+            messages[1].1["components"]["inter_shard_route"],
+            json!({ "hops": [{
+                "exit_gate_id": "gate3",
+                "entry_shard": "shard_three",
+                "entry_gate_id": "gate4"
+            }] })
+        );
+
+        // In the next shard the entity heads for the second gate with what it carried over
+        let route: InterShardRoute =
+            serde_json::from_value(messages[1].1["components"]["inter_shard_route"].clone())
+                .unwrap();
+        let messages = plan_warp("shard_two", "player1", &route, &snapshot(&route));
+        assert_eq!(
+            subjects(&messages)[..2],
+            ["event.decs.shard_two.gate3.warp.initiate", MIGRATE_EVENT]
+        );
+        assert_eq!(messages[1].1["to_shard"], "shard_three");
+        assert_eq!(messages[1].1["entry_gate_id"], "gate4");
+        assert!(messages[1].1["components"]
+            .get("inter_shard_route")
+            .is_none());
+
+        assert!(plan_warp(
+            "shard_three",
+            "player1",
+            &InterShardRoute::default(),
+            &EntitySnapshot::default()
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_unreadable_items_fail_the_snapshot() {
+        let rids = || {
+            vec![
+                "decs.components.the_void.player1.inventory.1".to_string(),
+                "decs.components.the_void.player1.inventory.2".to_string(),
+            ]
+        };
+        let items = read_items(rids(), |key| match key {
+            "decs:components:the_void:player1:inventory:1" => Ok(Some(r#"{"qty": 3}"#.into())),
+            _ => Ok(None),
+        })
+        .unwrap();
+        assert_eq!(
+            items,
+            vec![(
+                "decs.components.the_void.player1.inventory.1".to_string(),
+                json!({ "qty": 3 })
+            )]
+        );
+
+        let corrupt = read_items(rids(), |_| Ok(Some("{".into())));
+        assert!(corrupt
+            .unwrap_err()
+            .to_string()
+            .starts_with("Failed to parse item decs.components.the_void.player1.inventory.1"));
+        let unavailable = read_items(rids(), |_| Err("connection refused".into()));
+        assert_eq!(
+            unavailable.unwrap_err().to_string(),
+            "Failed to read item decs.components.the_void.player1.inventory.1: connection refused"
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use crate::energy::draw_frame_energy;
+use crate::signal_interference;
 use guest::prelude::*;
 use stacktrader_types as trader;
 use trader::components::*;
@@ -44,6 +46,24 @@ pub(crate) fn handle_frame(
         let position: Position = serde_json::from_str(&position_str)?;
         let velocity: Velocity = serde_json::from_str(&velocity_str)?;
         let target: Target = serde_json::from_str(&target_str)?;
+        if !draw_frame_energy(
+            ctx,
+            &frame.shard,
+            &frame.entity_id,
+            super::SYSTEM_NAME,
+            frame.elapsed_ms,
+        )? {
+            return Ok(vec![]);
+        }
+        let target_dropped = signal_interference::is_dropped(
+            ctx,
+            &frame,
+            &position,
+            &format!(
+                "call.decs.components.{}.{}.target.set",
+                frame.shard, frame.entity_id
+            ),
+        )?;
         process_frame(
             ctx,
             frame.shard,
@@ -51,6 +71,7 @@ pub(crate) fn handle_frame(
             &position,
             &velocity,
             &target,
+            target_dropped,
         )
     } else {
         Err(format!(
@@ -68,6 +89,7 @@ fn process_frame(
     pos: &Position,
     vel: &Velocity,
     target: &Target,
+    target_dropped: bool,
 ) -> CallResult {
     let target_pos = get_target_position(ctx, &target.rid)?;
 
@@ -77,15 +99,19 @@ fn process_frame(
         rid: target.rid.clone(),
     };
 
-    let publish_subject = format!("call.decs.components.{}.{}.target.set", shard, entity_id);
-    let payload = json!({ "params": nt });
-    if ctx
-        .msg()
-        .publish(&publish_subject, None, &serde_json::to_vec(&payload)?)
-        .is_err()
-    {
-        return Err("Error publishing message".into());
-    };
+    // An update lost to signal interference is made up for on the next frame. Stopping at the
+    // target below is never dropped
+    if !target_dropped {
+        let publish_subject = format!("call.decs.components.{}.{}.target.set", shard, entity_id);
+        let payload = json!({ "params": nt });
+        if ctx
+            .msg()
+            .publish(&publish_subject, None, &serde_json::to_vec(&payload)?)
+            .is_err()
+        {
+            return Err("Error publishing message".into());
+        };
+    }
 
     // If we are within THRESHOLD km of the target, automatically set velocity to zero
     // If we expect to arrive at the target in 150ms (about the span of 1 frame with some padding)
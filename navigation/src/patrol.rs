@@ -0,0 +1,277 @@
+//! # NPC Patrols
+//!
+//! The patrol system awaits frames for entities that have an `npc_patrol` component. Each frame
+//! the NPC moves toward the route point at `current_index` at the patrol's speed. On arrival,
+//! an event is published on `event.decs.system.npc.patrol_point_reached`, the NPC waits
+//! `wait_ms` if it has to and the next point is picked according to the patrol's direction.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const PATROL_POINT_REACHED_EVENT: &str = "event.decs.system.npc.patrol_point_reached";
+const MS_PER_HOUR: f64 = 3_600_000.0;
+
+/// The outcome of a frame of patrolling
+#[derive(Debug, PartialEq)]
+struct PatrolStep {
+    patrol: NpcPatrol,
+    position: Position,
+    /// Index of the route point reached this frame
+    reached: Option<usize>,
+}
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let position_value = get_component(ctx, &frame.shard, &frame.entity_id, super::POSITION)?;
+    let patrol_value = get_component(ctx, &frame.shard, &frame.entity_id, super::NPC_PATROL)?;
+
+    if let (Some(position_str), Some(patrol_str)) = (position_value, patrol_value) {
+        let position: Position = serde_json::from_str(&position_str)?;
+        let patrol: NpcPatrol = serde_json::from_str(&patrol_str)?;
+
+        let step = advance_patrol(patrol.clone(), position, frame.elapsed_ms);
+        if step.position != position {
+            publish_set(
+                ctx,
+                &frame.shard,
+                &frame.entity_id,
+                super::POSITION,
+                &step.position,
+            )?;
+        }
+        if step.patrol != patrol {
+            publish_set(
+                ctx,
+                &frame.shard,
+                &frame.entity_id,
+                super::NPC_PATROL,
+                &step.patrol,
+            )?;
+        }
+        if let Some(index) = step.reached {
+            ctx.msg().publish(
+                PATROL_POINT_REACHED_EVENT,
+                None,
+                &serde_json::to_vec(&json!({
+                    "shard": frame.shard,
+                    "entity": frame.entity_id,
+                    "index": index,
+                    "position": step.position,
+                }))?,
+            )?;
+        }
+    }
+
+    Ok(vec![])
+}
+
+fn get_component(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> Result<Option<String>> {
+    ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, component
+    ))
+}
+
+fn publish_set<T: serde::Serialize>(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+    value: &T,
+) -> Result<()> {
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard, entity_id, component
+        ),
+        None,
+        &serde_json::to_vec(&json!({ "params": value }))?,
+    )
+}
+
+/// Waits out the current wait, or moves the NPC toward its current route point and moves on to
+/// the next point once it has arrived. An NPC without a route stays where it is
+fn advance_patrol(mut patrol: NpcPatrol, position: Position, elapsed_ms: u32) -> PatrolStep {
+    if patrol.route.is_empty() {
+        return PatrolStep {
+            patrol,
+            position,
+            reached: None,
+        };
+    }
+    if let PatrolState::Waiting(remaining_ms) = patrol.state {
+        patrol.state = match remaining_ms.checked_sub(u64::from(elapsed_ms)) {
+            Some(remaining_ms) if remaining_ms > 0 => PatrolState::Waiting(remaining_ms),
+            _ => PatrolState::Moving,
+        };
+        return PatrolStep {
+            patrol,
+            position,
+            reached: None,
+        };
+    }
+
+    let index = patrol.current_index.min(patrol.route.len() - 1);
+    let target = patrol.route[index];
+    let step_km = f64::from(patrol.speed) * f64::from(elapsed_ms) / MS_PER_HOUR;
+    let distance = position.distance_to_3d(&target);
+    if distance > step_km {
+        let fraction = step_km / distance;
+        return PatrolStep {
+            position: Position::new(
+                position.x + (target.x - position.x) * fraction,
+                position.y + (target.y - position.y) * fraction,
+                position.z + (target.z - position.z) * fraction,
+            ),
+            patrol: NpcPatrol {
+                current_index: index,
+                ..patrol
+            },
+            reached: None,
+        };
+    }
+
+    let (next_index, returning) = next_point(
+        index,
+        patrol.route.len(),
+        patrol.direction,
+        patrol.returning,
+    );
+    patrol.current_index = next_index;
+    patrol.returning = returning;
+    patrol.state = if patrol.wait_ms > 0 {
+        PatrolState::Waiting(patrol.wait_ms)
+    } else {
+        PatrolState::Moving
+    };
+    PatrolStep {
+        patrol,
+        position: target,
+        reached: Some(index),
+    }
+}
+
+/// The index of the route point after the one just reached and whether a `ping_pong` patrol is
+/// now walking the route backward
+fn next_point(
+    index: usize,
+    len: usize,
+    direction: PatrolDirection,
+    returning: bool,
+) -> (usize, bool) {
+    if len < 2 {
+        return (0, returning);
+    }
+    match direction {
+        PatrolDirection::Forward => ((index + 1) % len, false),
+        PatrolDirection::Backward => ((index + len - 1) % len, false),
+        PatrolDirection::PingPong => match (returning, index) {
+            (true, 0) => (1, false),
+            (true, _) => (index - 1, true),
+            (false, _) if index + 1 == len => (index - 1, true),
+            (false, _) => (index + 1, false),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::advance_patrol;
+    use super::NpcPatrol;
+    use super::PatrolDirection;
+    use super::PatrolState;
+    use super::Position;
+
+    /// Three points 10 km apart on the x axis, covered at 10 km per one second frame
+    fn patrol(direction: PatrolDirection, wait_ms: u64) -> NpcPatrol {
+        NpcPatrol {
+            route: vec![
+                Position::new(0.0, 0.0, 0.0),
+                Position::new(10.0, 0.0, 0.0),
+                Position::new(20.0, 0.0, 0.0),
+            ],
+            direction,
+            speed: 36_000,
+            wait_ms,
+            ..NpcPatrol::default()
+        }
+    }
+
+    /// The route points reached over the frames, starting at the first point
+    fn points_reached(mut patrol: NpcPatrol, frames: usize) -> Vec<usize> {
+        let mut position = patrol.route[0];
+        let mut reached = Vec::new();
+        for _ in 0..frames {
+            let step = advance_patrol(patrol, position, 1000);
+            reached.extend(step.reached);
+            patrol = step.patrol;
+            position = step.position;
+        }
+        reached
+    }
+
+    #[test]
+    fn test_forward_patrol_loops() {
+        assert_eq!(
+            points_reached(patrol(PatrolDirection::Forward, 0), 6),
+            vec![0, 1, 2, 0, 1]
+        );
+    }
+
+    #[test]
+    fn test_backward_patrol_loops_in_reverse() {
+        assert_eq!(
+            points_reached(patrol(PatrolDirection::Backward, 0), 6),
+            vec![0, 2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn test_ping_pong_patrol_turns_at_the_ends() {
+        assert_eq!(
+            points_reached(patrol(PatrolDirection::PingPong, 0), 7),
+            vec![0, 1, 2, 1, 0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_patrol_waits_at_each_point() {
+        let mut route = patrol(PatrolDirection::Forward, 1500);
+        route.current_index = 1;
+        let step = advance_patrol(route, Position::new(5.0, 0.0, 0.0), 500);
+        assert_eq!(step.position, Position::new(10.0, 0.0, 0.0));
+        assert_eq!(step.reached, Some(1));
+        assert_eq!(step.patrol.state, PatrolState::Waiting(1500));
+        assert_eq!(step.patrol.current_index, 2);
+
+        let step = advance_patrol(step.patrol, step.position, 1000);
+        assert_eq!(step.patrol.state, PatrolState::Waiting(500));
+        let step = advance_patrol(step.patrol, step.position, 1000);
+        assert_eq!(step.patrol.state, PatrolState::Moving);
+        assert_eq!(step.position, Position::new(10.0, 0.0, 0.0));
+
+        let step = advance_patrol(step.patrol, step.position, 500);
+        assert_eq!(step.position, Position::new(15.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_patrol_state_serialization() {
+        let waiting = serde_json::to_value(PatrolState::Waiting(500)).unwrap();
+        assert_eq!(waiting, json!({ "waiting": 500 }));
+        let patrol: NpcPatrol = serde_json::from_value(json!({
+            "route": [{ "x": 0.0, "y": 0.0, "z": 0.0 }],
+            "current_index": 0,
+            "direction": "ping_pong",
+            "speed": 100
+        }))
+        .unwrap();
+        assert_eq!(patrol.direction, PatrolDirection::PingPong);
+        assert_eq!(patrol.state, PatrolState::Moving);
+    }
+}
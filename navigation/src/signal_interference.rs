@@ -0,0 +1,37 @@
+//! # Signal Interference
+//!
+//! Navigation updates published near an entity with a `signal_mass` component may be lost, see
+//! `stacktrader_types::interference`. Every 100 checks, the running totals are
+//! published on `event.decs.system.signal_interference.dropped`.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::interference;
+
+/// Whether the message the entity is about to publish on `subject` is lost to interference
+pub(crate) fn is_dropped(
+    ctx: &CapabilitiesContext,
+    frame: &decs::systemmgr::EntityFrame,
+    position: &Position,
+    subject: &str,
+) -> Result<bool> {
+    let chance = interference::shard_drop_chance(&frame.shard, &frame.entity_id, position, || {
+        interference::load_signal_masses(
+            &frame.shard,
+            |keys| ctx.kv().set_intersect(keys),
+            |key| ctx.kv().get(key),
+        )
+    })?;
+    let dropped = interference::is_dropped(
+        chance,
+        interference::message_seed(&frame.entity_id, frame.seq_no, subject),
+    );
+    if let Some(counter) = interference::record_check(super::SYSTEM_NAME, dropped) {
+        ctx.msg().publish(
+            interference::DROPPED_MESSAGES_SUBJECT,
+            None,
+            &serde_json::to_vec(&counter)?,
+        )?;
+    }
+    Ok(dropped)
+}
@@ -0,0 +1,292 @@
+//! # Waypoints
+//!
+//! The waypoint system awaits frames for entities that have a `waypoint_queue` component. An
+//! entity steers toward its current `navigation_waypoint` until it is within the waypoint's
+//! arrival radius. At that point the waypoint is consumed and the next one is popped off the front
+//! of the queue, or, once the queue is empty, the entity stops and an event is published on
+//! `event.decs.system.nav.queue_complete`.
+//!
+//! Waypoints are added to the queue with a message on
+//! `event.decs.{shard}.{entity}.nav.waypoint_insert`, either at the back of the queue or, when
+//! `insert_front` is set, at the front so it is visited next.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const QUEUE_COMPLETE_EVENT: &str = "event.decs.system.nav.queue_complete";
+
+/// Body of a waypoint insert message
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+struct WaypointInsert {
+    position: Position,
+    arrival_radius: f64,
+    #[serde(default)]
+    insert_front: bool,
+}
+
+/// What to do with an entity's waypoints during a frame
+#[derive(Debug, PartialEq)]
+enum WaypointStep {
+    /// Keep heading for the current waypoint
+    Continue,
+    /// Head for a waypoint taken from the front of the queue
+    Next(NavigationWaypoint, WaypointQueue),
+    /// The last waypoint has been reached
+    Complete,
+    /// No current waypoint and nothing queued
+    Idle,
+}
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let position_value = get_component(ctx, &frame.shard, &frame.entity_id, super::POSITION)?;
+    let velocity_value = get_component(ctx, &frame.shard, &frame.entity_id, super::VELOCITY)?;
+    let queue_value = get_component(ctx, &frame.shard, &frame.entity_id, super::WAYPOINT_QUEUE)?;
+    let waypoint: Option<NavigationWaypoint> = match get_component(
+        ctx,
+        &frame.shard,
+        &frame.entity_id,
+        super::NAVIGATION_WAYPOINT,
+    )? {
+        Some(s) => Some(serde_json::from_str(&s)?),
+        None => None,
+    };
+
+    if let (Some(position_str), Some(velocity_str), Some(queue_str)) =
+        (position_value, velocity_value, queue_value)
+    {
+        let position: Position = serde_json::from_str(&position_str)?;
+        let velocity: Velocity = serde_json::from_str(&velocity_str)?;
+        let queue: WaypointQueue = serde_json::from_str(&queue_str)?;
+
+        match next_step(&position, waypoint.as_ref(), queue) {
+            WaypointStep::Next(waypoint, queue) => {
+                publish_set(
+                    ctx,
+                    &frame.shard,
+                    &frame.entity_id,
+                    super::NAVIGATION_WAYPOINT,
+                    &waypoint,
+                )?;
+                publish_set(
+                    ctx,
+                    &frame.shard,
+                    &frame.entity_id,
+                    super::WAYPOINT_QUEUE,
+                    &queue,
+                )?;
+                let heading = position.vector_to(&waypoint.position);
+                let velocity = Velocity::new(velocity.mag, heading.ux, heading.uy, heading.uz);
+                publish_set(
+                    ctx,
+                    &frame.shard,
+                    &frame.entity_id,
+                    super::VELOCITY,
+                    &velocity,
+                )?;
+            }
+            WaypointStep::Complete => {
+                let waypoint_rid = format!(
+                    "decs.components.{}.{}.{}",
+                    frame.shard,
+                    frame.entity_id,
+                    super::NAVIGATION_WAYPOINT
+                );
+                ctx.msg().publish(
+                    &format!("call.{}.delete", waypoint_rid),
+                    None,
+                    &serde_json::to_vec(&json!({ "params": { "rid": waypoint_rid } }))?,
+                )?;
+                publish_set(
+                    ctx,
+                    &frame.shard,
+                    &frame.entity_id,
+                    super::VELOCITY,
+                    &Velocity { mag: 0, ..velocity },
+                )?;
+                ctx.msg().publish(
+                    QUEUE_COMPLETE_EVENT,
+                    None,
+                    &serde_json::to_vec(&json!({
+                        "shard": frame.shard,
+                        "entity": frame.entity_id,
+                    }))?,
+                )?;
+            }
+            WaypointStep::Continue | WaypointStep::Idle => {}
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// Receives messages on the subject `event.decs.{shard}.{entity}.nav.waypoint_insert` and adds
+/// the waypoint to the entity's queue
+pub(crate) fn handle_waypoint_insert(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    if subject.len() != 6 {
+        return Err("Unknown message subject received".into());
+    }
+    let (shard, entity_id) = (subject[2], subject[3]);
+    let insert: WaypointInsert = serde_json::from_slice(&msg.body)?;
+
+    let queue: WaypointQueue = match get_component(ctx, shard, entity_id, super::WAYPOINT_QUEUE)? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => WaypointQueue::default(),
+    };
+    let queue = insert_waypoint(queue, &insert);
+    publish_set(ctx, shard, entity_id, super::WAYPOINT_QUEUE, &queue)?;
+
+    Ok(vec![])
+}
+
+fn get_component(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> Result<Option<String>> {
+    ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, component
+    ))
+}
+
+fn publish_set<T: serde::Serialize>(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+    value: &T,
+) -> Result<()> {
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard, entity_id, component
+        ),
+        None,
+        &serde_json::to_vec(&json!({ "params": value }))?,
+    )
+}
+
+/// Decides whether the entity keeps heading for its current waypoint, moves on to the next one
+/// in the queue or has finished the queue
+fn next_step(
+    position: &Position,
+    waypoint: Option<&NavigationWaypoint>,
+    mut queue: WaypointQueue,
+) -> WaypointStep {
+    let arrived = match waypoint {
+        Some(w) if position.distance_to_3d(&w.position) > w.arrival_radius => {
+            return WaypointStep::Continue;
+        }
+        Some(_) => true,
+        None => false,
+    };
+    match queue.waypoints.pop_front() {
+        Some((position, arrival_radius)) => WaypointStep::Next(
+            NavigationWaypoint {
+                position,
+                arrival_radius,
+            },
+            queue,
+        ),
+        None if arrived => WaypointStep::Complete,
+        None => WaypointStep::Idle,
+    }
+}
+
+fn insert_waypoint(mut queue: WaypointQueue, insert: &WaypointInsert) -> WaypointQueue {
+    let waypoint = (insert.position, insert.arrival_radius);
+    if insert.insert_front {
+        queue.waypoints.push_front(waypoint);
+    } else {
+        queue.waypoints.push_back(waypoint);
+    }
+    queue
+}
+
+#[cfg(test)]
+mod test {
+    use super::insert_waypoint;
+    use super::next_step;
+    use super::NavigationWaypoint;
+    use super::Position;
+    use super::WaypointInsert;
+    use super::WaypointQueue;
+    use super::WaypointStep;
+
+    fn queue(xs: &[f64]) -> WaypointQueue {
+        WaypointQueue {
+            waypoints: xs
+                .iter()
+                .map(|x| (Position::new(*x, 0.0, 0.0), 1.0))
+                .collect(),
+        }
+    }
+
+    fn waypoint(x: f64) -> NavigationWaypoint {
+        NavigationWaypoint {
+            position: Position::new(x, 0.0, 0.0),
+            arrival_radius: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_continue_toward_current_waypoint() {
+        let step = next_step(
+            &Position::new(0.0, 0.0, 0.0),
+            Some(&waypoint(10.0)),
+            queue(&[20.0]),
+        );
+        assert_eq!(step, WaypointStep::Continue);
+    }
+
+    #[test]
+    fn test_pop_front_when_no_waypoint() {
+        let step = next_step(&Position::new(0.0, 0.0, 0.0), None, queue(&[20.0, 30.0]));
+        assert_eq!(step, WaypointStep::Next(waypoint(20.0), queue(&[30.0])));
+    }
+
+    #[test]
+    fn test_arrival_moves_to_next_waypoint() {
+        let step = next_step(
+            &Position::new(9.5, 0.0, 0.0),
+            Some(&waypoint(10.0)),
+            queue(&[20.0]),
+        );
+        assert_eq!(step, WaypointStep::Next(waypoint(20.0), queue(&[])));
+    }
+
+    #[test]
+    fn test_arrival_at_last_waypoint_completes_queue() {
+        let step = next_step(
+            &Position::new(20.0, 0.5, 0.0),
+            Some(&waypoint(20.0)),
+            queue(&[]),
+        );
+        assert_eq!(step, WaypointStep::Complete);
+
+        // Once the waypoint has been deleted the entity has nothing left to do
+        let step = next_step(&Position::new(20.0, 0.5, 0.0), None, queue(&[]));
+        assert_eq!(step, WaypointStep::Idle);
+    }
+
+    #[test]
+    fn test_insert_front_and_back() {
+        let insert = |x: f64, insert_front: bool| WaypointInsert {
+            position: Position::new(x, 0.0, 0.0),
+            arrival_radius: 1.0,
+            insert_front,
+        };
+
+        let q = insert_waypoint(queue(&[20.0]), &insert(30.0, false));
+        assert_eq!(q, queue(&[20.0, 30.0]));
+        let q = insert_waypoint(q, &insert(5.0, true));
+        assert_eq!(q, queue(&[5.0, 20.0, 30.0]));
+    }
+}
@@ -0,0 +1,76 @@
+//! # Timed Buffs
+//!
+//! Buffs on `speed` make the entity cover more (or less) ground each frame until they expire,
+//! without changing the `velocity` it has been set to. They are applied by an event on
+//! `event.decs.{shard}.{entity}.buff.applied` carrying the buff, and buffs on other stats are left
+//! to the systems those stats belong to. Each physics frame clears the entity's expired buffs and
+//! publishes `event.decs.system.buff.expired` for each of them.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::buffs::{self, StatTarget, TimedBuff};
+use trader::components::*;
+
+/// Receives buffs on `event.decs.{shard}.{entity}.buff.applied`, keeping those on speed
+pub(crate) fn handle_buff_applied(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let (shard, entity_id) = match subject.as_slice() {
+        ["event", "decs", shard, entity_id, "buff", "applied"] => (*shard, *entity_id),
+        _ => return Err(format!("Unexpected buff subject: {}", msg.subject).into()),
+    };
+    let buff: TimedBuff = serde_json::from_slice(&msg.body)?;
+    if buff.stat == StatTarget::Speed {
+        ctx.log(&format!(
+            "Applying buff {} to the speed of entity {}",
+            buff.id, entity_id
+        ));
+        buffs::apply_buff(shard, entity_id, buff);
+    }
+    Ok(vec![])
+}
+
+/// Clears the entity's expired buffs as of the frame, publishing an event for each of them
+pub(crate) fn handle_buff_expiry_check(
+    ctx: &CapabilitiesContext,
+    frame: &decs::systemmgr::EntityFrame,
+) -> CallResult {
+    let now_ms = trader::clock::frame_time_ms(frame, super::FRAMERATE);
+    for (subject, payload) in buffs::plan_buff_expiry(&frame.shard, &frame.entity_id, now_ms) {
+        ctx.msg()
+            .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+    }
+    Ok(vec![])
+}
+
+/// The velocity the entity moves at for the frame, its own scaled by its speed buffs
+pub(crate) fn boosted_velocity(velocity: &Velocity, multiplier: f64) -> Velocity {
+    Velocity {
+        mag: (f64::from(velocity.mag) * multiplier.max(0.0)).round() as u32,
+        ..*velocity
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::boosted_velocity;
+    use super::Velocity;
+
+    #[test]
+    fn test_buff_scales_magnitude_only() {
+        let velocity = Velocity {
+            mag: 1000,
+            ux: 0.0,
+            uy: 1.0,
+            uz: 0.0,
+        };
+        let boosted = boosted_velocity(&velocity, 1.25);
+        assert_eq!(boosted.mag, 1250);
+        assert_eq!((boosted.ux, boosted.uy, boosted.uz), (0.0, 1.0, 0.0));
+
+        assert_eq!(boosted_velocity(&velocity, 1.0), velocity);
+        // A debuff can't push the entity backwards
+        assert_eq!(boosted_velocity(&velocity, -2.0).mag, 0);
+    }
+}
@@ -0,0 +1,347 @@
+//! # Deployables
+//!
+//! Inventory items of the `deployable` kind can be deployed into the world as entities of their
+//! own. A command on `event.decs.components.{shard}.{entity}.inventory.deploy` names the item by
+//! its rid. The item's `component_template` is looked up under `decs:templates:{template}`, a JSON
+//! object of component names to values, and the new entity `{template}_{n}`, numbered by the
+//! shard's deployment counter, is created with a `new` call for each of the template's components.
+//! Its `position` is a random point within `deploy_radius` km of the deploying entity. One unit
+//! of the item is then taken out of the inventory and `event.decs.system.deployment.complete` is
+//! published. A deployment that can't be carried out leaves the inventory untouched and is
+//! published on `event.decs.system.deployment.failed` instead.
+use crate::hazard::random_unit_vector;
+use decs::gateway::ResProtocolRequest;
+use guest::prelude::*;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{Map, Value};
+use stacktrader_types as trader;
+use trader::components::*;
+
+const INVENTORY: &str = "inventory";
+const DEPLOYMENT_COMPLETE_EVENT: &str = "event.decs.system.deployment.complete";
+const DEPLOYMENT_FAILED_EVENT: &str = "event.decs.system.deployment.failed";
+
+/// Receives deploy commands on `event.decs.components.{shard}.{entity}.inventory.deploy`, whose
+/// payload's `item` is the rid of the inventory item to deploy
+pub(crate) fn handle_deploy_command(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let (shard, entity_id) = match subject.as_slice() {
+        ["event", "decs", "components", shard, entity_id, "inventory", "deploy"] => {
+            (*shard, *entity_id)
+        }
+        _ => return Err(format!("Unexpected deploy subject: {}", msg.subject).into()),
+    };
+    let command: Value = serde_json::from_slice(&msg.body)?;
+    let item_rid = match command["item"].as_str() {
+        Some(rid) => rid,
+        None => return Err("Deploy command without an item".into()),
+    };
+
+    let item: InventoryItem = match ctx.kv().get(&item_rid.replace('.', ":"))? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => return publish_failure(ctx, shard, entity_id, item_rid, "the item is gone"),
+    };
+    let origin: Position = match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard,
+        entity_id,
+        super::POSITION
+    ))? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => {
+            return publish_failure(
+                ctx,
+                shard,
+                entity_id,
+                item_rid,
+                "the entity has no position",
+            )
+        }
+    };
+    let deployable = match &item.deployable {
+        Some(deployable) => deployable,
+        None => return publish_failure(ctx, shard, entity_id, item_rid, "it isn't deployable"),
+    };
+    let template = ctx
+        .kv()
+        .get(&format!("decs:templates:{}", deployable.component_template))?;
+
+    let deployment = ctx
+        .kv()
+        .atomic_add(&format!("decs:{}:deployments", shard), 1)?;
+    let mut rng = SmallRng::seed_from_u64(super::frame_seed(entity_id, deployment as u64));
+    let direction = random_unit_vector(&mut rng);
+    let distance_fraction: f64 = rng.gen_range(0.0, 1.0);
+
+    match build_entity(
+        deployable,
+        template.as_deref(),
+        &origin,
+        direction,
+        distance_fraction,
+    ) {
+        Ok(components) => {
+            let new_entity = format!("{}_{}", deployable.component_template, deployment);
+            for (subject, payload) in
+                deployment_messages(shard, entity_id, item_rid, &item, &new_entity, components)
+            {
+                ctx.msg()
+                    .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+            }
+            Ok(vec![])
+        }
+        Err(reason) => publish_failure(ctx, shard, entity_id, item_rid, &reason),
+    }
+}
+
+fn publish_failure(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    item_rid: &str,
+    reason: &str,
+) -> CallResult {
+    ctx.log(&format!(
+        "Entity {} can't deploy {}: {}",
+        entity_id, item_rid, reason
+    ));
+    ctx.msg().publish(
+        DEPLOYMENT_FAILED_EVENT,
+        None,
+        &serde_json::to_vec(&json!({
+            "shard": shard,
+            "entity": entity_id,
+            "item": item_rid,
+            "reason": reason
+        }))?,
+    )?;
+    Ok(vec![])
+}
+
+/// The components of the deployed entity, those of its template placed at the given direction
+/// and fraction of the deploy radius away from the origin, or why there are none
+fn build_entity(
+    deployable: &DeployableItem,
+    template: Option<&str>,
+    origin: &Position,
+    direction: (f64, f64, f64),
+    distance_fraction: f64,
+) -> std::result::Result<Map<String, Value>, String> {
+    let template = match template {
+        Some(template) => template,
+        None => {
+            return Err(format!(
+                "there is no template named {}",
+                deployable.component_template
+            ))
+        }
+    };
+    let mut components = match serde_json::from_str::<Value>(template) {
+        Ok(Value::Object(components)) => components,
+        _ => {
+            return Err(format!(
+                "template {} is not a set of components",
+                deployable.component_template
+            ))
+        }
+    };
+
+    let position = spawn_position(
+        origin,
+        deployable.deploy_radius,
+        direction,
+        distance_fraction,
+    );
+    components.insert(super::POSITION.to_string(), json!(position));
+    Ok(components)
+}
+
+/// A point in the direction of the unit vector from the origin, at the given fraction of the
+/// radius. Scaling by the cube root of the fraction spreads uniform fractions evenly through the
+/// volume of the sphere rather than bunching them up at its center
+fn spawn_position(
+    origin: &Position,
+    radius: f64,
+    direction: (f64, f64, f64),
+    distance_fraction: f64,
+) -> Position {
+    let distance = radius.max(0.0) * distance_fraction.clamp(0.0, 1.0).cbrt();
+    Position::new(
+        origin.x + direction.0 * distance,
+        origin.y + direction.1 * distance,
+        origin.z + direction.2 * distance,
+    )
+}
+
+/// The `new` calls creating each of the deployed entity's components, the call taking one unit
+/// of the item out of the inventory and the completion event
+fn deployment_messages(
+    shard: &str,
+    entity_id: &str,
+    item_rid: &str,
+    item: &InventoryItem,
+    new_entity: &str,
+    components: Map<String, Value>,
+) -> Vec<(String, Value)> {
+    let position = components.get(super::POSITION).cloned();
+    let mut messages: Vec<(String, Value)> = components
+        .into_iter()
+        .map(|(component, value)| {
+            (
+                ResProtocolRequest::New(format!(
+                    "decs.components.{}.{}.{}",
+                    shard, new_entity, component
+                ))
+                .to_string(),
+                json!({ "params": value }),
+            )
+        })
+        .collect();
+
+    messages.push(if item.qty > 1 {
+        (
+            ResProtocolRequest::Set(item_rid.to_string()).to_string(),
+            json!({
+                "params": InventoryItem {
+                    qty: item.qty - 1,
+                    ..item.clone()
+                }
+            }),
+        )
+    } else {
+        (
+            ResProtocolRequest::Delete(format!(
+                "decs.components.{}.{}.{}",
+                shard, entity_id, INVENTORY
+            ))
+            .to_string(),
+            json!({ "params": { "rid": item_rid } }),
+        )
+    });
+    messages.push((
+        DEPLOYMENT_COMPLETE_EVENT.to_string(),
+        json!({
+            "shard": shard,
+            "entity": entity_id,
+            "item": item.name,
+            "deployed": new_entity,
+            "position": position
+        }),
+    ));
+    messages
+}
+
+#[cfg(test)]
+mod test {
+    use super::build_entity;
+    use super::deployment_messages;
+    use super::random_unit_vector;
+    use super::spawn_position;
+    use super::DeployableItem;
+    use super::InventoryItem;
+    use super::Position;
+    use super::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    const EPSILON: f64 = 1e-9;
+    const ITEM_RID: &str = "decs.components.the_void.player1.inventory.3";
+    const BEACON: &str = r#"{ "transponder": { "object_type": "beacon" } }"#;
+
+    fn beacon(qty: u32) -> InventoryItem {
+        InventoryItem {
+            name: "nav_beacon".to_string(),
+            kind: "deployable".to_string(),
+            qty,
+            unit_value: 40,
+            rarity: Default::default(),
+            version: 2,
+            deployable: Some(DeployableItem {
+                component_template: "nav_beacon".to_string(),
+                deploy_radius: 2.0,
+            }),
+        }
+    }
+
+    fn template() -> DeployableItem {
+        beacon(1).deployable.unwrap()
+    }
+
+    #[test]
+    fn test_missing_template_fails_deployment() {
+        let origin = Position::new(0.0, 0.0, 0.0);
+        assert_eq!(
+            build_entity(&template(), None, &origin, (1.0, 0.0, 0.0), 0.5),
+            Err("there is no template named nav_beacon".to_string())
+        );
+        assert_eq!(
+            build_entity(&template(), Some("[]"), &origin, (1.0, 0.0, 0.0), 0.5),
+            Err("template nav_beacon is not a set of components".to_string())
+        );
+    }
+
+    #[test]
+    fn test_entity_is_placed_within_deploy_radius() {
+        let origin = Position::new(10.0, -4.0, 3.0);
+        let mut rng = SmallRng::seed_from_u64(42);
+        for _ in 0..100 {
+            let direction = random_unit_vector(&mut rng);
+            let position = spawn_position(&origin, 2.0, direction, rng.gen_range(0.0, 1.0));
+            assert!(position.distance_to_3d(&origin) <= 2.0 + EPSILON);
+        }
+
+        // The extremes of the fraction land on the origin and on the edge of the sphere
+        let center = spawn_position(&origin, 2.0, (0.0, 1.0, 0.0), 0.0);
+        assert!(center.distance_to_3d(&origin) < EPSILON);
+        let edge = spawn_position(&origin, 2.0, (0.0, 1.0, 0.0), 1.0);
+        assert!((edge.y + 2.0).abs() < EPSILON);
+
+        let components =
+            build_entity(&template(), Some(BEACON), &origin, (0.0, 0.0, -1.0), 1.0).unwrap();
+        assert_eq!(components["transponder"]["object_type"], "beacon");
+        assert_eq!(components["position"]["z"], 1.0);
+    }
+
+    #[test]
+    fn test_deployment_creates_entity_and_takes_one_item() {
+        let origin = Position::new(0.0, 0.0, 0.0);
+        let components =
+            build_entity(&template(), Some(BEACON), &origin, (1.0, 0.0, 0.0), 1.0).unwrap();
+        let messages = deployment_messages(
+            "the_void",
+            "player1",
+            ITEM_RID,
+            &beacon(1),
+            "nav_beacon_7",
+            components.clone(),
+        );
+        let subjects: Vec<&str> = messages.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(
+            subjects,
+            vec![
+                "call.decs.components.the_void.nav_beacon_7.position.new",
+                "call.decs.components.the_void.nav_beacon_7.transponder.new",
+                "call.decs.components.the_void.player1.inventory.delete",
+                "event.decs.system.deployment.complete",
+            ]
+        );
+        assert_eq!(messages[2].1["params"]["rid"], ITEM_RID);
+        assert_eq!(messages[3].1["deployed"], "nav_beacon_7");
+        assert_eq!(messages[3].1["position"]["x"], 2.0);
+
+        // With more than one in the stack, the stack shrinks instead
+        let messages = deployment_messages(
+            "the_void",
+            "player1",
+            ITEM_RID,
+            &beacon(3),
+            "nav_beacon_8",
+            components,
+        );
+        assert_eq!(messages[2].0, format!("call.{}.set", ITEM_RID));
+        assert_eq!(messages[2].1["params"]["qty"], 2);
+    }
+}
@@ -0,0 +1,39 @@
+//! # Energy Regeneration
+//!
+//! The energy system awaits frames for entities that have an `energy_core` component and
+//! recharges the core by `regen_rate_per_ms * elapsed_ms`, up to its `max_energy`. The systems
+//! drawing on the core, e.g. mining, navigation and the turret, each take their own cost out of
+//! it in their frames. A full core isn't republished.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::energy;
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let core: EnergyCore = match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard,
+        frame.entity_id,
+        super::ENERGY_CORE
+    ))? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => return Ok(vec![]),
+    };
+    let regenerated = energy::regenerate(&core, frame.elapsed_ms);
+    if regenerated != core {
+        ctx.msg().publish(
+            &format!(
+                "call.decs.components.{}.{}.{}.set",
+                frame.shard,
+                frame.entity_id,
+                super::ENERGY_CORE
+            ),
+            None,
+            &serde_json::to_vec(&json!({ "params": regenerated }))?,
+        )?;
+    }
+
+    Ok(vec![])
+}
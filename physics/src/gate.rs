@@ -0,0 +1,303 @@
+//! # Access Gates
+//!
+//! The gate system awaits frames for entities with an `access_gate` component, each of which
+//! guards a spherical zone of `radius` km around its `center`. Every frame, the entities whose
+//! position lies inside the zone are looked up. While the gate is locked, an entity inside the
+//! zone that carries the gate's `required_item` in its inventory unlocks it: the gate is
+//! published with `locked` false and the unlock is announced on
+//! `event.decs.system.gate.unlocked`. Without a key holder, every entity inside the zone is pushed
+//! back out through its edge with a `position.set`. An unlocked gate locks itself again
+//! `relock_after_ms` after it was unlocked.
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::migration::migrate_component;
+use trader::validation::Validate;
+
+const INVENTORY: &str = "inventory";
+const GATE_UNLOCKED_EVENT: &str = "event.decs.system.gate.unlocked";
+const PUSH_BACK_MARGIN_KM: f64 = 0.1;
+
+/// An entity inside a gate's zone
+#[derive(Debug, PartialEq, Clone)]
+struct Visitor {
+    entity_id: String,
+    position: Position,
+    has_key: bool,
+}
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+    let now_ms = trader::clock::frame_time_ms(&frame, super::FRAMERATE);
+
+    let gate_value = get_component(ctx, &frame.shard, &frame.entity_id, super::ACCESS_GATE)?;
+    if let Some(gate_str) = gate_value {
+        let gate: AccessGate = serde_json::from_str(&gate_str)?;
+        let visitors = get_visitors(ctx, &frame.shard, &frame.entity_id, &gate)?;
+        for (subject, payload) in
+            plan_gate(&frame.shard, &frame.entity_id, &gate, &visitors, now_ms)
+        {
+            ctx.msg()
+                .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// Retrieve every entity other than the gate itself whose position lies inside the gate's zone,
+/// along with whether it carries the gate's key
+fn get_visitors(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    gate_entity_id: &str,
+    gate: &AccessGate,
+) -> std::result::Result<Vec<Visitor>, Box<dyn std::error::Error>> {
+    let mut visitors = Vec::new();
+    for entity_id in
+        ctx.kv()
+            .set_members(&format!("decs:{}:{}:entities", shard, super::POSITION))?
+    {
+        if entity_id == gate_entity_id {
+            continue;
+        }
+        let position: Position = match get_component(ctx, shard, &entity_id, super::POSITION)? {
+            Some(s) => serde_json::from_str(&s)?,
+            None => continue,
+        };
+        if position.distance_to_3d(&gate.center) <= gate.radius {
+            let has_key = has_item(ctx, shard, &entity_id, &gate.required_item)?;
+            visitors.push(Visitor {
+                entity_id,
+                position,
+                has_key,
+            });
+        }
+    }
+    Ok(visitors)
+}
+
+/// Whether the entity's inventory holds an item with the given name
+fn has_item(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    item_name: &str,
+) -> std::result::Result<bool, Box<dyn std::error::Error>> {
+    let key = format!("decs:components:{}:{}:{}", shard, entity_id, INVENTORY);
+    for rid in ctx.kv().list_range(&key, 0, -1)? {
+        if let Some(item_str) = ctx.kv().get(&rid.replace('.', ":"))? {
+            let item: InventoryItem = migrate_component(&item_str)?.into_inner();
+            if item.name == item_name {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn get_component(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> Result<Option<String>> {
+    ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, component
+    ))
+}
+
+/// Produces the messages (subject and payload) that maintain a gate at the given game time: the
+/// gate relocks once its time is up, a locked gate is unlocked by the first visitor holding the
+/// key, and every visitor of a gate that stays locked is pushed back out of its zone
+fn plan_gate(
+    shard: &str,
+    gate_entity_id: &str,
+    gate: &AccessGate,
+    visitors: &[Visitor],
+    now_ms: u64,
+) -> Vec<(String, Value)> {
+    let mut updated = gate.clone();
+    let mut publishes = Vec::new();
+    if updated.relock_due(now_ms) {
+        updated.locked = true;
+        updated.unlocked_at_ms = None;
+    } else if !updated.locked && updated.unlocked_at_ms.is_none() {
+        // Gates opened by hand start their relock timer now
+        updated.unlocked_at_ms = Some(now_ms);
+    }
+
+    if updated.locked {
+        match visitors.iter().find(|v| v.has_key) {
+            Some(key_holder) => {
+                updated.locked = false;
+                updated.unlocked_at_ms = Some(now_ms);
+                publishes.push((
+                    GATE_UNLOCKED_EVENT.to_string(),
+                    json!({
+                        "shard": shard,
+                        "gate": gate_entity_id,
+                        "entity": key_holder.entity_id,
+                    }),
+                ));
+            }
+            None => {
+                for visitor in visitors {
+                    let pushed = push_back(&updated, &visitor.position);
+                    if pushed.validate().is_ok() {
+                        publishes.push((
+                            set_subject(shard, &visitor.entity_id, super::POSITION),
+                            json!({ "params": pushed }),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    if updated != *gate {
+        publishes.insert(
+            0,
+            (
+                set_subject(shard, gate_entity_id, super::ACCESS_GATE),
+                json!({ "params": updated }),
+            ),
+        );
+    }
+    publishes
+}
+
+fn set_subject(shard: &str, entity_id: &str, component: &str) -> String {
+    format!(
+        "call.decs.components.{}.{}.{}.set",
+        shard, entity_id, component
+    )
+}
+
+/// The point just outside the gate's zone in the direction of the position from the gate's
+/// center. A position at the very center is pushed out along the x axis
+fn push_back(gate: &AccessGate, position: &Position) -> Position {
+    let distance = gate.center.distance_to_3d(position);
+    let (ux, uy, uz) = if distance > 0.0 {
+        (
+            (position.x - gate.center.x) / distance,
+            (position.y - gate.center.y) / distance,
+            (position.z - gate.center.z) / distance,
+        )
+    } else {
+        (1.0, 0.0, 0.0)
+    };
+    let edge = gate.radius + PUSH_BACK_MARGIN_KM;
+    Position::new(
+        gate.center.x + ux * edge,
+        gate.center.y + uy * edge,
+        gate.center.z + uz * edge,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::plan_gate;
+    use super::push_back;
+    use super::AccessGate;
+    use super::Position;
+    use super::Visitor;
+    use super::GATE_UNLOCKED_EVENT;
+    use super::PUSH_BACK_MARGIN_KM;
+
+    const GATE_SUBJECT: &str = "call.decs.components.the_void.gate1.access_gate.set";
+
+    fn gate() -> AccessGate {
+        AccessGate {
+            center: Position::new(0.0, 0.0, 0.0),
+            radius: 5.0,
+            required_item: "vault_key".to_string(),
+            locked: true,
+            relock_after_ms: 10_000,
+            unlocked_at_ms: None,
+        }
+    }
+
+    fn visitor(entity_id: &str, x: f64, has_key: bool) -> Visitor {
+        Visitor {
+            entity_id: entity_id.to_string(),
+            position: Position::new(x, 0.0, 0.0),
+            has_key,
+        }
+    }
+
+    fn subjects(publishes: &[(String, serde_json::Value)]) -> Vec<&str> {
+        publishes.iter().map(|(s, _)| s.as_str()).collect()
+    }
+
+    #[test]
+    fn test_key_holder_unlocks_gate() {
+        let visitors = vec![visitor("ship1", -2.0, false), visitor("ship2", 3.0, true)];
+        let publishes = plan_gate("the_void", "gate1", &gate(), &visitors, 5_000);
+
+        // Nobody is pushed back once the gate opens
+        assert_eq!(
+            subjects(&publishes),
+            vec![GATE_SUBJECT, GATE_UNLOCKED_EVENT]
+        );
+        assert_eq!(publishes[0].1["params"]["locked"], false);
+        assert_eq!(publishes[0].1["params"]["unlocked_at_ms"], 5_000);
+        assert_eq!(publishes[1].1["entity"], "ship2");
+        assert_eq!(publishes[1].1["gate"], "gate1");
+    }
+
+    #[test]
+    fn test_entity_without_key_is_pushed_back() {
+        let visitors = vec![visitor("ship1", -2.0, false)];
+        let publishes = plan_gate("the_void", "gate1", &gate(), &visitors, 5_000);
+
+        assert_eq!(
+            subjects(&publishes),
+            vec!["call.decs.components.the_void.ship1.position.set"]
+        );
+        let pushed: Position = serde_json::from_value(publishes[0].1["params"].clone()).unwrap();
+        assert_eq!(pushed, Position::new(-5.0 - PUSH_BACK_MARGIN_KM, 0.0, 0.0));
+
+        // An entity at the very center still has a way out
+        let pushed = push_back(&gate(), &Position::new(0.0, 0.0, 0.0));
+        assert_eq!(pushed, Position::new(5.0 + PUSH_BACK_MARGIN_KM, 0.0, 0.0));
+
+        // Nothing happens at a locked gate nobody is visiting
+        assert!(plan_gate("the_void", "gate1", &gate(), &[], 5_000).is_empty());
+    }
+
+    #[test]
+    fn test_gate_relocks_after_timer() {
+        let unlocked = AccessGate {
+            locked: false,
+            unlocked_at_ms: Some(5_000),
+            ..gate()
+        };
+        let visitors = vec![visitor("ship1", 1.0, false)];
+
+        // Entities pass freely while the gate is open
+        assert!(plan_gate("the_void", "gate1", &unlocked, &visitors, 14_999).is_empty());
+
+        let publishes = plan_gate("the_void", "gate1", &unlocked, &visitors, 15_000);
+        assert_eq!(
+            subjects(&publishes),
+            vec![
+                GATE_SUBJECT,
+                "call.decs.components.the_void.ship1.position.set"
+            ]
+        );
+        assert_eq!(publishes[0].1["params"]["locked"], true);
+        assert!(publishes[0].1["params"]["unlocked_at_ms"].is_null());
+
+        // A gate opened by hand starts its timer on the next frame
+        let opened = AccessGate {
+            locked: false,
+            ..gate()
+        };
+        let publishes = plan_gate("the_void", "gate1", &opened, &[], 2_000);
+        assert_eq!(subjects(&publishes), vec![GATE_SUBJECT]);
+        assert_eq!(publishes[0].1["params"]["unlocked_at_ms"], 2_000);
+    }
+}
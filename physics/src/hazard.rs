@@ -0,0 +1,186 @@
+//! # Navigation Hazards
+//!
+//! Entities with a `navigation_hazard` component describe a zone of turbulence. Every frame, an
+//! entity inside one or more hazard zones has a random perturbation added to its velocity, with a
+//! magnitude equal to the combined intensity of those zones. The randomness is seeded from the
+//! entity ID and frame sequence number, so a given frame always produces the same perturbation.
+use guest::prelude::*;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use stacktrader_types as trader;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::sync::RwLock;
+use trader::components::*;
+
+const NAVIGATION_HAZARD: &str = "navigation_hazard";
+
+lazy_static! {
+    static ref HAZARDS: RwLock<HashMap<String, Vec<NavigationHazard>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Applies the turbulence of any hazard zones the entity is in to its velocity, publishing and
+/// returning the perturbed velocity. Entities outside of every hazard keep their velocity
+pub(crate) fn apply_turbulence(
+    ctx: &CapabilitiesContext,
+    frame: &decs::systemmgr::EntityFrame,
+    position: &Position,
+    velocity: Velocity,
+) -> Result<Velocity> {
+    let intensity = hazard_intensity(position, &get_hazards(ctx, &frame.shard)?);
+    if intensity <= 0.0 {
+        return Ok(velocity);
+    }
+
+    let mut rng = SmallRng::seed_from_u64(super::frame_seed(&frame.entity_id, frame.seq_no));
+    let new_velocity = perturb_velocity(&velocity, intensity, random_unit_vector(&mut rng));
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            frame.shard,
+            frame.entity_id,
+            super::VELOCITY
+        ),
+        None,
+        &serde_json::to_vec(&json!({ "params": new_velocity }))?,
+    )?;
+    Ok(new_velocity)
+}
+
+/// Retrieve the hazards for the shard from the cache. If the shard isn't cached yet, load every
+/// `navigation_hazard` component from the KV store. Hazards are assumed not to change once they
+/// have been created.
+fn get_hazards(ctx: &CapabilitiesContext, shard: &str) -> Result<Vec<NavigationHazard>> {
+    if let Some(hazards) = HAZARDS.read().unwrap().get(shard) {
+        return Ok(hazards.clone());
+    }
+
+    let mut hazards = Vec::new();
+    for entity in ctx
+        .kv()
+        .set_members(&format!("decs:{}:{}:entities", shard, NAVIGATION_HAZARD))?
+    {
+        if let Some(hazard_str) = ctx.kv().get(&format!(
+            "decs:components:{}:{}:{}",
+            shard, entity, NAVIGATION_HAZARD
+        ))? {
+            hazards.push(serde_json::from_str(&hazard_str)?);
+        }
+    }
+    // Don't cache an empty shard so hazards created after the first frame are picked up
+    if !hazards.is_empty() {
+        HAZARDS
+            .write()
+            .unwrap()
+            .insert(shard.to_string(), hazards.clone());
+    }
+    Ok(hazards)
+}
+
+/// Combined intensity of every hazard zone containing the position
+fn hazard_intensity(position: &Position, hazards: &[NavigationHazard]) -> f64 {
+    hazards
+        .iter()
+        .filter(|h| position.distance_to_3d(&h.center) <= h.radius)
+        .map(|h| h.intensity)
+        .sum()
+}
+
+/// A direction picked uniformly at random from the unit sphere
+pub(crate) fn random_unit_vector<R: Rng>(rng: &mut R) -> (f64, f64, f64) {
+    let z: f64 = rng.gen_range(-1.0, 1.0);
+    let theta: f64 = rng.gen_range(0.0, 2.0 * PI);
+    let r = (1.0 - z * z).sqrt();
+    (r * theta.cos(), r * theta.sin(), z)
+}
+
+/// Adds a perturbation of the given magnitude (in KPH) and direction to the velocity
+fn perturb_velocity(velocity: &Velocity, intensity: f64, direction: (f64, f64, f64)) -> Velocity {
+    let mag = f64::from(velocity.mag);
+    let vx = mag * velocity.ux + intensity * direction.0;
+    let vy = mag * velocity.uy + intensity * direction.1;
+    let vz = mag * velocity.uz + intensity * direction.2;
+    let new_mag = (vx * vx + vy * vy + vz * vz).sqrt();
+    if new_mag == 0.0 {
+        return Velocity {
+            mag: 0,
+            ..*velocity
+        };
+    }
+    Velocity::new(
+        new_mag.round() as u32,
+        vx / new_mag,
+        vy / new_mag,
+        vz / new_mag,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::hazard_intensity;
+    use super::perturb_velocity;
+    use super::random_unit_vector;
+    use super::NavigationHazard;
+    use super::Position;
+    use super::SmallRng;
+    use super::Velocity;
+    use rand::SeedableRng;
+
+    const EPSILON: f64 = 1e-9;
+    // The direction drawn by `SmallRng::seed_from_u64(42)`
+    const EXPECTED: (f64, f64, f64) = (0.66196658257413, 0.7349403788286982, 0.14718316181634794);
+
+    fn hazard(x: f64, intensity: f64) -> NavigationHazard {
+        NavigationHazard {
+            center: Position::new(x, 0.0, 0.0),
+            radius: 10.0,
+            intensity,
+        }
+    }
+
+    #[test]
+    fn test_known_seed_perturbation() {
+        let mut rng = SmallRng::seed_from_u64(42);
+        let (x, y, z) = random_unit_vector(&mut rng);
+
+        assert!((x - EXPECTED.0).abs() < EPSILON);
+        assert!((y - EXPECTED.1).abs() < EPSILON);
+        assert!((z - EXPECTED.2).abs() < EPSILON);
+        assert!(((x * x + y * y + z * z).sqrt() - 1.0).abs() < EPSILON);
+
+        let velocity = perturb_velocity(&Velocity::new(0, 0.0, 0.0, 0.0), 100.0, (x, y, z));
+        assert_eq!(velocity.mag, 100);
+        assert!((velocity.ux - EXPECTED.0).abs() < EPSILON);
+        assert!((velocity.uy - EXPECTED.1).abs() < EPSILON);
+        assert!((velocity.uz - EXPECTED.2).abs() < EPSILON);
+    }
+
+    #[test]
+    fn test_perturbation_added_to_velocity() {
+        let velocity = perturb_velocity(&Velocity::new(300, 1.0, 0.0, 0.0), 100.0, (0.0, 1.0, 0.0));
+
+        assert_eq!(velocity.mag, 316);
+        assert!((velocity.ux - 300.0 / 10.0_f64.sqrt() / 100.0).abs() < EPSILON);
+        assert!((velocity.uy - 100.0 / 10.0_f64.sqrt() / 100.0).abs() < EPSILON);
+        assert_eq!(velocity.uz, 0.0);
+    }
+
+    #[test]
+    fn test_hazard_intensity() {
+        let hazards = vec![hazard(0.0, 50.0), hazard(15.0, 20.0), hazard(80.0, 999.0)];
+
+        assert_eq!(
+            hazard_intensity(&Position::new(-5.0, 0.0, 0.0), &hazards),
+            50.0
+        );
+        assert_eq!(
+            hazard_intensity(&Position::new(7.0, 0.0, 0.0), &hazards),
+            70.0
+        );
+        assert_eq!(
+            hazard_intensity(&Position::new(40.0, 0.0, 0.0), &hazards),
+            0.0
+        );
+    }
+}
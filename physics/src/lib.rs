@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[macro_use]
+extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 extern crate decscloud_common as decs;
@@ -22,9 +24,13 @@ extern crate lazy_static;
 use decs::systemmgr::*;
 use guest::prelude::*;
 use stacktrader_types as trader;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::sync::RwLock;
 use trader::components::*;
+use trader::metrics;
+use trader::validation::Validate;
 
 lazy_static! {
     static ref UNIVERSE_METADATA: RwLock<HashMap<String, UniverseMetadata>> =
@@ -39,6 +45,14 @@ const POSITION: &str = "position";
 const VELOCITY: &str = "velocity";
 const FRAMERATE: u32 = 1;
 const SYSTEM_NAME: &str = "physics";
+const GATE_SYSTEM_NAME: &str = "gate";
+const ACCESS_GATE: &str = "access_gate";
+const ENERGY_SYSTEM_NAME: &str = "energy";
+const ENERGY_CORE: &str = "energy_core";
+const THERMAL_SYSTEM_NAME: &str = "thermal";
+const THERMAL_PROFILE: &str = "thermal_profile";
+const SECTOR_SYSTEM_NAME: &str = "sector_control";
+const FACTION_ID: &str = "faction_id";
 
 pub fn handle_call(ctx: &CapabilitiesContext, operation: &str, msg: &[u8]) -> CallResult {
     match operation {
@@ -48,7 +62,14 @@ pub fn handle_call(ctx: &CapabilitiesContext, operation: &str, msg: &[u8]) -> Ca
     }
 }
 
-/// Routes message either to the `handle_ping` function for registry pings or `handle_frame` for position updates
+/// Routes message either to the `handle_ping` function for registry pings,
+/// `thrust::handle_throttle_command` for throttle commands, `deploy::handle_deploy_command` for
+/// deploying inventory items, `worldgen::handle_generate` for generating asteroid fields,
+/// `buffs::handle_buff_applied` for speed buffs, `gate::handle_frame` for access gate
+/// frames, `energy::handle_frame` for energy core frames, `thermal::handle_frame` for thermal
+/// profile frames, `sector::handle_frame` for sector control frames,
+/// `shipclass::handle_component_add_validation` for adds to ship hardware collections or
+/// `handle_frame` for position updates
 fn handle_message(
     ctx: &CapabilitiesContext,
     msg: impl Into<messaging::DeliverMessage>,
@@ -64,28 +85,100 @@ fn handle_message(
     match subject.as_ref() {
         NO_MESSAGE => Err("No message".into()),
         REGISTRY_SUBJECT => handle_ping(ctx, msg.unwrap()),
-        _ => handle_frame(ctx, msg.unwrap()),
+        s if s.starts_with("event.decs.components.") && s.ends_with(".thruster.set_throttle") => {
+            thrust::handle_throttle_command(ctx, msg.unwrap())
+        }
+        s if s.starts_with("event.decs.") && s.ends_with(".buff.applied") => {
+            buffs::handle_buff_applied(ctx, msg.unwrap())
+        }
+        s if s.starts_with("event.decs.components.") && s.ends_with(".inventory.deploy") => {
+            deploy::handle_deploy_command(ctx, msg.unwrap())
+        }
+        s if s.starts_with("call.decs.") && s.ends_with(".worldgen.generate") => {
+            worldgen::handle_generate(ctx, msg.unwrap())
+        }
+        s if s.starts_with("decs.frames.") && s.ends_with(".gate") => {
+            timed_frame(ctx, msg.unwrap(), gate::handle_frame)
+        }
+        s if s.starts_with("decs.frames.") && s.ends_with(".energy") => {
+            timed_frame(ctx, msg.unwrap(), energy::handle_frame)
+        }
+        s if s.starts_with("decs.frames.") && s.ends_with(".thermal") => {
+            timed_frame(ctx, msg.unwrap(), thermal::handle_frame)
+        }
+        s if s.starts_with("decs.frames.") && s.ends_with(".sector_control") => {
+            timed_frame(ctx, msg.unwrap(), sector::handle_frame)
+        }
+        s if s.starts_with("call.decs.shipclass.") && s.ends_with(".add") => {
+            shipclass::handle_component_add_validation(ctx, msg.unwrap())
+        }
+        _ => timed_frame(ctx, msg.unwrap(), handle_frame),
     }
 }
 
-/// Receives messages on the subject `system.registry` and replies with physics system metadata
+/// Runs a frame handler, publishing how long the frame took to process and storing a summary
+/// of the system's recent frame times every 100 frames, see `metrics::timed_frame`
+fn timed_frame(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+    handler: fn(&CapabilitiesContext, messaging::BrokerMessage) -> CallResult,
+) -> CallResult {
+    let body = msg.body.clone();
+    let subject = msg.subject.clone();
+    metrics::timed_frame(
+        &metrics::SystemClock,
+        metrics::frame_system(&subject),
+        &body,
+        || handler(ctx, msg),
+        |subject, payload| ctx.msg().publish(subject, None, payload),
+        |key, value| ctx.kv().set(key, value, None),
+        |msg| ctx.log(msg),
+    )
+}
+
+/// Receives messages on the subject `system.registry` and replies with the metadata of the
+/// physics, gate, energy, thermal and sector control systems
 fn handle_ping(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
-    let payload = System {
-        name: SYSTEM_NAME.to_string(),
-        framerate: FRAMERATE,
-        components: vec![POSITION.to_string(), VELOCITY.to_string()],
-    };
+    let payloads = vec![
+        System {
+            name: SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![POSITION.to_string(), VELOCITY.to_string()],
+        },
+        System {
+            name: GATE_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![ACCESS_GATE.to_string()],
+        },
+        System {
+            name: ENERGY_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![ENERGY_CORE.to_string()],
+        },
+        System {
+            name: THERMAL_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![THERMAL_PROFILE.to_string()],
+        },
+        System {
+            name: SECTOR_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![FACTION_ID.to_string()],
+        },
+    ];
     let reply_to = if msg.reply_to.is_empty() {
         format!("{}.replies", REGISTRY_SUBJECT)
     } else {
         msg.reply_to
     };
-    if let Err(e) = ctx
-        .msg()
-        .publish(&reply_to, None, &serde_json::to_vec(&payload)?)
-    {
-        return Err(format!("Error publishing message: {}", e).into());
-    };
+    for payload in payloads {
+        if let Err(e) = ctx
+            .msg()
+            .publish(&reply_to, None, &serde_json::to_vec(&payload)?)
+        {
+            return Err(format!("Error publishing message: {}", e).into());
+        };
+    }
     Ok(vec![])
 }
 
@@ -103,6 +196,7 @@ fn handle_frame(
     }
 
     let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+    buffs::handle_buff_expiry_check(ctx, &frame)?;
 
     let position_value = ctx.kv().get(&format!(
         "decs:components:{}:{}:{}",
@@ -116,13 +210,33 @@ fn handle_frame(
         let position: Position = serde_json::from_str(&position_str)?;
         let velocity: Velocity = serde_json::from_str(&velocity_str)?;
 
+        // Entities travelling through a wormhole are not in normal space
+        if wormhole::handle_frame(ctx, &frame, &position)? {
+            return Ok(vec![]);
+        }
+        // Turbulence inside navigation hazards knocks entities off course
+        let velocity = hazard::apply_turbulence(ctx, &frame, &position, velocity)?;
+        // Thrusters accelerate the entity before it is moved
+        let velocity = thrust::apply_thrust(ctx, &frame, velocity)?;
+
         if velocity.mag == 0 {
             return Ok(vec![]);
         } else if velocity.ux == 0.0 && velocity.uy == 0.0 && velocity.uz == 0.0 {
             return Err("Bad target vector".into());
         }
 
-        if let Ok(new_position) = new_position(frame.elapsed_ms.into(), &position, &velocity) {
+        // Speed buffs move the entity further without changing its velocity
+        let now_ms = trader::clock::frame_time_ms(&frame, FRAMERATE);
+        let boosted = buffs::boosted_velocity(
+            &velocity,
+            trader::buffs::buff_multiplier(
+                &frame.shard,
+                &frame.entity_id,
+                trader::buffs::StatTarget::Speed,
+                now_ms,
+            ),
+        );
+        if let Ok(new_position) = new_position(frame.elapsed_ms.into(), &position, &boosted) {
             // If new position is outside the edge of universe, do not set that position, instead set v mag to 0
             if out_of_bounds(&new_position, &get_metadata(ctx, &frame.shard)) {
                 let ps = format!(
@@ -135,6 +249,7 @@ fn handle_frame(
                     .publish(&ps, None, &serde_json::to_vec(&payload)?)?
             } else {
                 // New position is within the shard's universe boundaries
+                new_position.validate()?;
                 let publish_subject = &format!(
                     "call.decs.components.{}.{}.{}.set",
                     frame.shard, frame.entity_id, POSITION
@@ -161,12 +276,7 @@ fn handle_frame(
 
 /// Calculates a new position based on a current position and velocity over an elapsed time
 fn new_position(elapsed: u64, pos: &Position, vel: &Velocity) -> Result<Position> {
-    let multiplier = (u64::from(vel.mag) * elapsed) as f64 / 3_600_000.0;
-    Ok(Position {
-        x: pos.x + vel.ux * multiplier,
-        y: pos.y + vel.uy * multiplier,
-        z: pos.z + vel.uz * multiplier,
-    })
+    Ok(*pos + vel.displacement(elapsed))
 }
 
 fn out_of_bounds(pos: &Position, md: &UniverseMetadata) -> bool {
@@ -214,8 +324,29 @@ fn load_universe_md(ctx: &CapabilitiesContext, shard: &str) -> Result<UniverseMe
     Ok(umd)
 }
 
+/// Derives a deterministic random seed for an entity in a given frame
+fn frame_seed(entity_id: &str, seq_no: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    entity_id.hash(&mut hasher);
+    seq_no.hash(&mut hasher);
+    hasher.finish()
+}
+
+mod buffs;
+mod deploy;
+mod energy;
+mod gate;
+mod hazard;
+mod sector;
+mod shipclass;
+mod thermal;
+mod thrust;
+mod worldgen;
+mod wormhole;
+
 #[cfg(test)]
 mod test {
+    use super::frame_seed;
     use super::new_position;
     use super::Position;
     use super::Velocity;
@@ -280,4 +411,11 @@ mod test {
         assert!(new_pos.y - pos.y <= FLOATEPSILON);
         assert!(new_pos.z - pos.z <= FLOATEPSILON);
     }
+
+    #[test]
+    fn test_frame_seed_is_deterministic() {
+        assert_eq!(frame_seed("ship", 42), frame_seed("ship", 42));
+        assert_ne!(frame_seed("ship", 42), frame_seed("ship", 43));
+        assert_ne!(frame_seed("ship", 42), frame_seed("other_ship", 42));
+    }
 }
@@ -0,0 +1,233 @@
+//! # Sector Control
+//!
+//! The sector control system awaits frames for entities with a `faction_id` component. The
+//! shard's universe is divided into the grid of sectors of its `universe` entity's
+//! `sector_grid` component (or the default grid). Every frame, the sector the entity is in earns
+//! its faction `points_per_ms * elapsed_ms` points on the `sector_control` component of the
+//! sector's `sector_{id}` entity. The faction with the highest score above the grid's
+//! `control_threshold` takes control of the sector; while two factions share the highest score
+//! the sector is contested and stays with its controller. A change of controller is published on
+//! `event.decs.system.sector.control_changed`.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use trader::components::*;
+
+const SECTOR_GRID: &str = "sector_grid";
+const SECTOR_CONTROL: &str = "sector_control";
+const CONTROL_CHANGED_EVENT: &str = "event.decs.system.sector.control_changed";
+
+lazy_static! {
+    // {shard}.{entity} -> the ID of the sector the entity was last seen in
+    static ref SECTOR_MEMBERSHIP: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+}
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let faction: FactionId =
+        match get_component(ctx, &frame.shard, &frame.entity_id, super::FACTION_ID)? {
+            Some(s) => serde_json::from_str(&s)?,
+            None => return Ok(vec![]),
+        };
+    let position: Position =
+        match get_component(ctx, &frame.shard, &frame.entity_id, super::POSITION)? {
+            Some(s) => serde_json::from_str(&s)?,
+            None => return Ok(vec![]),
+        };
+    let grid: SectorGrid = match get_component(ctx, &frame.shard, "universe", SECTOR_GRID)? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => SectorGrid::default(),
+    };
+    let sector_id = grid.sector_id(&position, &super::get_metadata(ctx, &frame.shard));
+    if let Some(previous) = record_membership(&frame.shard, &frame.entity_id, &sector_id) {
+        ctx.log(&format!(
+            "Entity {} moved from sector {} to {}",
+            frame.entity_id, previous, sector_id
+        ));
+    }
+
+    let sector_entity = format!("sector_{}", sector_id);
+    let control: SectorControl =
+        match get_component(ctx, &frame.shard, &sector_entity, SECTOR_CONTROL)? {
+            Some(s) => serde_json::from_str(&s)?,
+            None => SectorControl {
+                sector_id: sector_id.to_string(),
+                ..SectorControl::default()
+            },
+        };
+    let updated = plan_sector_control(&control, &faction.faction, frame.elapsed_ms, &grid);
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            frame.shard, sector_entity, SECTOR_CONTROL
+        ),
+        None,
+        &serde_json::to_vec(&json!({ "params": updated }))?,
+    )?;
+    if updated.controlling_faction != control.controlling_faction {
+        ctx.msg().publish(
+            CONTROL_CHANGED_EVENT,
+            None,
+            &serde_json::to_vec(&json!({
+                "shard": frame.shard,
+                "sector_id": sector_id,
+                "previous_faction": control.controlling_faction,
+                "controlling_faction": updated.controlling_faction,
+                "control_score": updated.control_score,
+            }))?,
+        )?;
+    }
+
+    Ok(vec![])
+}
+
+/// Caches the sector the entity is in, returning the sector it was in before if it moved
+fn record_membership(shard: &str, entity_id: &str, sector_id: &str) -> Option<String> {
+    let key = format!("{}.{}", shard, entity_id);
+    if SECTOR_MEMBERSHIP
+        .read()
+        .unwrap()
+        .get(&key)
+        .map(String::as_str)
+        == Some(sector_id)
+    {
+        return None;
+    }
+    SECTOR_MEMBERSHIP
+        .write()
+        .unwrap()
+        .insert(key, sector_id.to_string())
+}
+
+/// Adds the points the faction earned over the elapsed time to its score and hands control of
+/// the sector to the faction with the highest score above the threshold
+fn plan_sector_control(
+    control: &SectorControl,
+    faction: &str,
+    elapsed_ms: u32,
+    grid: &SectorGrid,
+) -> SectorControl {
+    let mut updated = control.clone();
+    *updated
+        .control_score
+        .entry(faction.to_string())
+        .or_insert(0.0) += grid.points_per_ms * f64::from(elapsed_ms);
+    if let Some(leader) = leading_faction(&updated, grid.control_threshold) {
+        updated.controlling_faction = Some(leader);
+    }
+    updated
+}
+
+/// The faction with the highest score above the threshold, none if there isn't one or if the
+/// sector is contested by factions sharing the highest score
+fn leading_faction(control: &SectorControl, threshold: f64) -> Option<String> {
+    let mut ranked: Vec<(&String, f64)> = control
+        .control_score
+        .iter()
+        .map(|(faction, score)| (faction, *score))
+        .filter(|(_, score)| *score > threshold)
+        .collect();
+    ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    match ranked.as_slice() {
+        [(_, first), (_, second), ..] if first == second => None,
+        [(faction, _), ..] => Some(faction.to_string()),
+        [] => None,
+    }
+}
+
+fn get_component(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> Result<Option<String>> {
+    ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, component
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::plan_sector_control;
+    use super::record_membership;
+    use super::SectorControl;
+    use super::SectorGrid;
+
+    fn grid() -> SectorGrid {
+        SectorGrid {
+            points_per_ms: 0.001,
+            control_threshold: 10.0,
+            ..SectorGrid::default()
+        }
+    }
+
+    fn sector() -> SectorControl {
+        SectorControl {
+            sector_id: "1_2_0".to_string(),
+            ..SectorControl::default()
+        }
+    }
+
+    #[test]
+    fn test_contested_sector_does_not_change_hands() {
+        // Two entities of each faction in the sector, a second a frame
+        let mut control = sector();
+        let mut controllers = Vec::new();
+        for _ in 0..8 {
+            for faction in &["miners", "pirates", "miners", "pirates"] {
+                control = plan_sector_control(&control, faction, 1000, &grid());
+                controllers.push(control.controlling_faction.clone());
+            }
+        }
+        assert_eq!(control.control_score["miners"], 16.0);
+        assert_eq!(control.control_score["pirates"], 16.0);
+        // Nobody controls the sector until the miners pass the threshold, a frame ahead of the
+        // pirates, who then only ever draw level
+        assert!(controllers[..20].iter().all(Option::is_none));
+        assert!(controllers[20..]
+            .iter()
+            .all(|c| c.as_deref() == Some("miners")));
+
+        // Without a controller, a tie at the top leaves the sector to nobody
+        let mut control = sector();
+        control.control_score.insert("miners".to_string(), 12.0);
+        control.control_score.insert("pirates".to_string(), 12.0);
+        let control = plan_sector_control(&control, "traders", 1000, &grid());
+        assert_eq!(control.controlling_faction, None);
+    }
+
+    #[test]
+    fn test_control_flips_to_the_higher_score() {
+        let mut control = sector();
+        control.control_score.insert("miners".to_string(), 20.0);
+        control.controlling_faction = Some("miners".to_string());
+
+        // Pirates pass the threshold but not the miners' score
+        let mut control = plan_sector_control(&control, "pirates", 15_000, &grid());
+        assert_eq!(control.controlling_faction, Some("miners".to_string()));
+        // Drawing level contests the sector, which stays with the miners
+        control = plan_sector_control(&control, "pirates", 5000, &grid());
+        assert_eq!(control.controlling_faction, Some("miners".to_string()));
+
+        let control = plan_sector_control(&control, "pirates", 1000, &grid());
+        assert_eq!(control.controlling_faction, Some("pirates".to_string()));
+        assert_eq!(control.control_score["pirates"], 21.0);
+        assert_eq!(control.sector_id, "1_2_0");
+    }
+
+    #[test]
+    fn test_membership_reports_moves() {
+        assert_eq!(record_membership("the_void", "scout1", "0_0_0"), None);
+        assert_eq!(record_membership("the_void", "scout1", "0_0_0"), None);
+        assert_eq!(
+            record_membership("the_void", "scout1", "1_0_0"),
+            Some("0_0_0".to_string())
+        );
+        // Shards don't share sectors
+        assert_eq!(record_membership("other_shard", "scout1", "3_3_0"), None);
+    }
+}
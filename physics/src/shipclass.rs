@@ -0,0 +1,187 @@
+//! # Ship Classes
+//!
+//! A ship's `ship_class` limits how much hardware it can carry. Hardware is added to the `weapons`,
+//! `modules` and `crew` collections of an entity through the physics actor, by calling
+//! `call.decs.shipclass.{shard}.{entity}.{collection}.add` with the item as the `params`. The
+//! component manager doesn't subscribe to that subject, so every add goes through
+//! `handle_component_add_validation`. The items already in the collection are counted and, if one
+//! more would exceed the ship class's limit, the call is answered with an error and goes no
+//! further. Accepted adds are published, reply subject included, as a `new` on the collection,
+//! `call.decs.components.{shard}.{entity}.{collection}.new`, for the component manager to apply.
+//! Entities without a ship class carry as much as they like.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const SHIP_CLASS: &str = "ship_class";
+const WEAPONS: &str = "weapons";
+const MODULES: &str = "modules";
+const CREW: &str = "crew";
+const LIMIT_EXCEEDED: &str = "system.limitExceeded";
+
+/// What becomes of an add to a ship hardware collection
+#[derive(Debug, PartialEq)]
+enum AddOutcome {
+    /// Publish the add on this subject for the component manager to apply
+    Apply(String),
+    /// Answer the call with this error and drop the add
+    Refuse(String),
+}
+
+/// Receives calls on `call.decs.shipclass.{shard}.{entity}.{collection}.add` for the ship
+/// hardware collections
+pub(crate) fn handle_component_add_validation(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let (shard, entity_id, collection) = match subject.as_slice() {
+        ["call", "decs", "shipclass", shard, entity_id, collection, "add"]
+            if [WEAPONS, MODULES, CREW].contains(collection) =>
+        {
+            (*shard, *entity_id, *collection)
+        }
+        _ => return Err(format!("Unexpected hardware add subject: {}", msg.subject).into()),
+    };
+
+    let ship_class: Option<ShipClass> = match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, SHIP_CLASS
+    ))? {
+        Some(s) => Some(serde_json::from_str(&s)?),
+        None => None,
+    };
+    let existing = ctx
+        .kv()
+        .list_range(
+            &format!("decs:components:{}:{}:{}", shard, entity_id, collection),
+            0,
+            -1,
+        )?
+        .len();
+
+    match plan_add(shard, entity_id, collection, ship_class.as_ref(), existing) {
+        AddOutcome::Apply(subject) => {
+            let reply_to = if msg.reply_to.is_empty() {
+                None
+            } else {
+                Some(msg.reply_to.as_str())
+            };
+            ctx.msg().publish(&subject, reply_to, &msg.body)?;
+        }
+        AddOutcome::Refuse(message) => {
+            ctx.log(&format!(
+                "Refusing to add to the {} of entity {}: {}",
+                collection, entity_id, message
+            ));
+            if !msg.reply_to.is_empty() {
+                ctx.msg().publish(
+                    &msg.reply_to,
+                    None,
+                    &serde_json::to_vec(&json!({
+                        "error": {
+                            "code": LIMIT_EXCEEDED,
+                            "message": message
+                        }
+                    }))?,
+                )?;
+            }
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// Decides whether an add to a collection already holding `existing` items is applied
+fn plan_add(
+    shard: &str,
+    entity_id: &str,
+    collection: &str,
+    ship_class: Option<&ShipClass>,
+    existing: usize,
+) -> AddOutcome {
+    match validate_add(collection, ship_class, existing) {
+        Ok(()) => AddOutcome::Apply(format!(
+            "call.decs.components.{}.{}.{}.new",
+            shard, entity_id, collection
+        )),
+        Err(message) => AddOutcome::Refuse(message),
+    }
+}
+
+/// The number of items the ship class allows in the collection. Collections other than the ship
+/// hardware ones aren't limited
+fn limit(ship_class: &ShipClass, collection: &str) -> Option<usize> {
+    match collection {
+        WEAPONS => Some(usize::from(ship_class.max_weapon_slots)),
+        MODULES => Some(usize::from(ship_class.max_module_slots)),
+        CREW => Some(ship_class.max_crew as usize),
+        _ => None,
+    }
+}
+
+/// Whether one more item fits in a collection already holding `existing` items, or why not
+fn validate_add(
+    collection: &str,
+    ship_class: Option<&ShipClass>,
+    existing: usize,
+) -> std::result::Result<(), String> {
+    match ship_class.and_then(|class| limit(class, collection).map(|max| (class, max))) {
+        Some((class, max)) if existing >= max => Err(format!(
+            "a tier {} ship holds at most {} {}",
+            class.tier, max, collection
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::plan_add;
+    use super::validate_add;
+    use super::AddOutcome;
+    use super::ShipClass;
+
+    fn frigate() -> ShipClass {
+        ShipClass {
+            tier: 2,
+            max_weapon_slots: 2,
+            max_module_slots: 3,
+            max_crew: 10,
+            max_cargo_volume: 500.0,
+        }
+    }
+
+    #[test]
+    fn test_add_up_to_the_limit_is_allowed() {
+        assert_eq!(validate_add("weapons", Some(&frigate()), 1), Ok(()));
+        assert_eq!(validate_add("modules", Some(&frigate()), 2), Ok(()));
+        assert_eq!(validate_add("crew", Some(&frigate()), 9), Ok(()));
+    }
+
+    #[test]
+    fn test_add_over_the_limit_is_denied() {
+        let message = validate_add("weapons", Some(&frigate()), 2).unwrap_err();
+        assert_eq!(message, "a tier 2 ship holds at most 2 weapons");
+        assert!(validate_add("modules", Some(&frigate()), 3).is_err());
+        assert!(validate_add("crew", Some(&frigate()), 10).is_err());
+    }
+
+    #[test]
+    fn test_unlimited_without_ship_class_or_for_other_collections() {
+        assert_eq!(validate_add("weapons", None, 100), Ok(()));
+        assert_eq!(validate_add("inventory", Some(&frigate()), 100), Ok(()));
+    }
+
+    #[test]
+    fn test_over_capacity_add_is_not_applied() {
+        assert_eq!(
+            plan_add("the_void", "ship1", "weapons", Some(&frigate()), 1),
+            AddOutcome::Apply("call.decs.components.the_void.ship1.weapons.new".to_string())
+        );
+        assert_eq!(
+            plan_add("the_void", "ship1", "weapons", Some(&frigate()), 2),
+            AddOutcome::Refuse("a tier 2 ship holds at most 2 weapons".to_string())
+        );
+    }
+}
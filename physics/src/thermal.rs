@@ -0,0 +1,279 @@
+//! # Thermal Buildup
+//!
+//! The thermal system awaits frames for entities that have a `thermal_profile` component. Every
+//! frame, each of the entity's thrusters adds `heat_per_ms * throttle * elapsed_ms` to the
+//! profile and the profile dissipates `dissipation_rate_per_ms * elapsed_ms`. The turret adds its
+//! heat in its own frames, as it fires. When the heat reaches the profile's
+//! `overheat_threshold`, the hottest component still running is disabled; at `max_heat` every
+//! one of them is. Either way, `event.decs.system.thermal.overheating` is published. A thruster
+//! is disabled by cutting its throttle, the turret by no longer firing when aimed.
+use crate::thrust::get_thrusters;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+use trader::thermal::{self, ThermalState};
+
+const TURRET: &str = "turret";
+
+/// What the thermal system does with an entity during a frame
+#[derive(Debug, PartialEq)]
+struct ThermalPlan {
+    profile: ThermalProfile,
+    state: ThermalState,
+    /// RIDs of the components to disable
+    disable: Vec<String>,
+    /// Whether to publish the overheating event
+    announce: bool,
+}
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let profile: ThermalProfile = match get_component(ctx, &frame, super::THERMAL_PROFILE)? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => return Ok(vec![]),
+    };
+    let thrusters = get_thrusters(ctx, &frame.shard, &frame.entity_id)?;
+    let turret: Option<Turret> = match get_component(ctx, &frame, TURRET)? {
+        Some(s) => Some(serde_json::from_str(&s)?),
+        None => None,
+    };
+    let turret_rid = format!(
+        "decs.components.{}.{}.{}",
+        frame.shard, frame.entity_id, TURRET
+    );
+    let turret = turret.map(|turret| (turret_rid, turret));
+
+    let plan = plan_thermal(&profile, &thrusters, turret.as_ref(), frame.elapsed_ms);
+    if plan.profile != profile {
+        ctx.msg().publish(
+            &format!(
+                "call.decs.components.{}.{}.{}.set",
+                frame.shard,
+                frame.entity_id,
+                super::THERMAL_PROFILE
+            ),
+            None,
+            &serde_json::to_vec(&json!({ "params": plan.profile }))?,
+        )?;
+    }
+    for rid in &plan.disable {
+        if let Some((_, thruster)) = thrusters.iter().find(|(r, _)| r == rid) {
+            let thruster = Thruster {
+                current_throttle: 0.0,
+                ..thruster.clone()
+            };
+            publish_rid_set(ctx, rid, &thruster)?;
+        } else if let Some((_, turret)) = turret.as_ref().filter(|(r, _)| r == rid) {
+            let turret = Turret {
+                fire_when_aimed: false,
+                ..turret.clone()
+            };
+            publish_rid_set(ctx, rid, &turret)?;
+        }
+    }
+    if plan.announce {
+        ctx.log(&format!(
+            "Entity {} is overheating at {:.1} heat",
+            frame.entity_id, plan.profile.current_heat
+        ));
+        ctx.msg().publish(
+            thermal::OVERHEATING_EVENT,
+            None,
+            &serde_json::to_vec(&json!({
+                "shard": frame.shard,
+                "entity": frame.entity_id,
+                "current_heat": plan.profile.current_heat,
+                "max_heat": plan.profile.max_heat,
+                "shutdown": plan.state == ThermalState::Shutdown,
+                "disabled": plan.disable,
+            }))?,
+        )?;
+    }
+
+    Ok(vec![])
+}
+
+/// Heats the profile by the thrusters and dissipates it for the elapsed time. On reaching the
+/// overheat threshold the hottest running component is disabled, at the maximum heat every
+/// running component is. The event is announced when the entity reaches either, or when
+/// components are disabled
+fn plan_thermal(
+    profile: &ThermalProfile,
+    thrusters: &[(String, Thruster)],
+    turret: Option<&(String, Turret)>,
+    elapsed_ms: u32,
+) -> ThermalPlan {
+    let previous_state = thermal::thermal_state(profile);
+    let heated = thrusters
+        .iter()
+        .fold(profile.clone(), |heated, (rid, thruster)| {
+            let heat = thruster.heat_per_ms
+                * thruster.current_throttle.clamp(0.0, 1.0)
+                * f64::from(elapsed_ms);
+            thermal::add_heat(&heated, rid, heat)
+        });
+    let profile = thermal::dissipate(&heated, elapsed_ms);
+    let state = thermal::thermal_state(&profile);
+
+    let running: Vec<&str> = thrusters
+        .iter()
+        .filter(|(_, thruster)| thruster.current_throttle > 0.0)
+        .map(|(rid, _)| rid.as_str())
+        .chain(
+            turret
+                .filter(|(_, turret)| turret.fire_when_aimed)
+                .map(|(rid, _)| rid.as_str()),
+        )
+        .collect();
+    let disable: Vec<String> = match state {
+        ThermalState::Shutdown => running.iter().map(|rid| rid.to_string()).collect(),
+        ThermalState::Overheating if previous_state == ThermalState::Nominal => {
+            thermal::hottest_source(&profile, &running)
+                .map(|rid| vec![rid.to_string()])
+                .unwrap_or_default()
+        }
+        _ => vec![],
+    };
+    let announce =
+        (state != previous_state && state != ThermalState::Nominal) || !disable.is_empty();
+    ThermalPlan {
+        profile,
+        state,
+        disable,
+        announce,
+    }
+}
+
+fn get_component(
+    ctx: &CapabilitiesContext,
+    frame: &decs::systemmgr::EntityFrame,
+    component: &str,
+) -> Result<Option<String>> {
+    ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard, frame.entity_id, component
+    ))
+}
+
+fn publish_rid_set<T: serde::Serialize>(
+    ctx: &CapabilitiesContext,
+    rid: &str,
+    value: &T,
+) -> Result<()> {
+    ctx.msg().publish(
+        &format!("call.{}.set", rid),
+        None,
+        &serde_json::to_vec(&json!({ "params": value }))?,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::plan_thermal;
+    use super::Position;
+    use super::ThermalProfile;
+    use super::ThermalState;
+    use super::Thruster;
+    use super::Turret;
+    use stacktrader_types::thermal::add_heat;
+
+    const MAIN: &str = "decs.components.the_void.player1.thrusters.main";
+    const AUX: &str = "decs.components.the_void.player1.thrusters.aux";
+    const TURRET: &str = "decs.components.the_void.player1.turret";
+
+    fn profile(current_heat: f64) -> ThermalProfile {
+        ThermalProfile {
+            current_heat,
+            max_heat: 100.0,
+            dissipation_rate_per_ms: 0.001,
+            overheat_threshold: 80.0,
+            ..ThermalProfile::default()
+        }
+    }
+
+    fn thruster(rid: &str, heat_per_ms: f64, current_throttle: f64) -> (String, Thruster) {
+        (
+            rid.to_string(),
+            Thruster {
+                direction: Position::new(0.0, 1.0, 0.0),
+                max_force: 100.0,
+                current_throttle,
+                heat_per_ms,
+            },
+        )
+    }
+
+    fn turret() -> (String, Turret) {
+        (
+            TURRET.to_string(),
+            Turret {
+                fire_when_aimed: true,
+                heat_per_shot: 5.0,
+                ..Turret::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_gradual_buildup_disables_the_hottest_component() {
+        // 3 heat a frame from the main thruster at half throttle and 2 from the auxiliary one,
+        // less the 1 dissipated
+        let thrusters = vec![thruster(MAIN, 0.006, 0.5), thruster(AUX, 0.002, 1.0)];
+        let mut profile = profile(0.0);
+        for frame in 1..=19 {
+            let plan = plan_thermal(&profile, &thrusters, None, 1000);
+            assert_eq!(plan.state, ThermalState::Nominal, "frame {}", frame);
+            assert!(!plan.announce);
+            profile = plan.profile;
+        }
+        assert!((profile.current_heat - 76.0).abs() < 1e-9);
+
+        let plan = plan_thermal(&profile, &thrusters, Some(&turret()), 1000);
+        assert_eq!(plan.state, ThermalState::Overheating);
+        assert!(plan.announce);
+        // The main thruster gave off most of the heat, the turret never fired
+        assert_eq!(plan.disable, vec![MAIN.to_string()]);
+
+        // Still overheating, nothing more to do until the maximum is reached
+        let plan = plan_thermal(&plan.profile, &thrusters, Some(&turret()), 1000);
+        assert_eq!(plan.state, ThermalState::Overheating);
+        assert!(!plan.announce);
+        assert!(plan.disable.is_empty());
+    }
+
+    #[test]
+    fn test_dissipation_curve() {
+        let heated = add_heat(&profile(50.0), TURRET, 10.0);
+        let curve: Vec<f64> = (0..5)
+            .scan(heated, |profile, _| {
+                *profile = plan_thermal(profile, &[], None, 20_000).profile;
+                Some(profile.current_heat)
+            })
+            .collect();
+        // Heat falls off linearly and stops at zero
+        assert_eq!(curve, vec![40.0, 20.0, 0.0, 0.0, 0.0]);
+
+        let plan = plan_thermal(&profile(90.0), &[], None, 20_000);
+        assert_eq!(plan.state, ThermalState::Nominal);
+        assert!(!plan.announce);
+    }
+
+    #[test]
+    fn test_max_heat_shuts_everything_down() {
+        let thrusters = vec![thruster(MAIN, 0.01, 1.0), thruster(AUX, 0.0, 0.0)];
+        let plan = plan_thermal(&profile(95.0), &thrusters, Some(&turret()), 1000);
+        assert_eq!(plan.state, ThermalState::Shutdown);
+        assert!(plan.announce);
+        // The idle thruster is already off
+        assert_eq!(plan.disable, vec![MAIN.to_string(), TURRET.to_string()]);
+
+        // Once everything is off, the entity just cools down
+        let off = vec![thruster(MAIN, 0.01, 0.0), thruster(AUX, 0.0, 0.0)];
+        let idle = (TURRET.to_string(), Turret::default());
+        let plan = plan_thermal(&plan.profile, &off, Some(&idle), 1000);
+        assert_eq!(plan.state, ThermalState::Shutdown);
+        assert!(plan.disable.is_empty());
+        assert!(!plan.announce);
+    }
+}
@@ -0,0 +1,217 @@
+//! # Thrusters
+//!
+//! Entities can carry any number of thrusters in a `thrusters` collection. Every frame, the
+//! forces of all of an entity's thrusters are summed into its `acceleration`, which is added to
+//! its velocity over the frame's elapsed time before its position is updated. Throttles are set
+//! by publishing on `event.decs.components.{shard}.{entity}.thruster.set_throttle`.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const THRUSTERS: &str = "thrusters";
+const ACCELERATION: &str = "acceleration";
+
+/// Receives throttle commands on `event.decs.components.{shard}.{entity}.thruster.set_throttle`.
+/// The payload's `throttle` is clamped to 0.0 - 1.0 and applied to the thruster named by its
+/// `thruster` rid, or to all of the entity's thrusters if it names none
+pub(crate) fn handle_throttle_command(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let (shard, entity_id) = match subject.as_slice() {
+        ["event", "decs", "components", shard, entity_id, "thruster", "set_throttle"] => {
+            (*shard, *entity_id)
+        }
+        _ => return Err(format!("Unexpected throttle subject: {}", msg.subject).into()),
+    };
+    let command: serde_json::Value = serde_json::from_slice(&msg.body)?;
+    let throttle = match command["throttle"].as_f64() {
+        Some(throttle) => clamp_throttle(throttle),
+        None => return Err("Throttle command without a throttle".into()),
+    };
+
+    for (rid, thruster) in get_thrusters(ctx, shard, entity_id)? {
+        if command["thruster"]
+            .as_str()
+            .is_some_and(|requested| requested != rid)
+        {
+            continue;
+        }
+        let thruster = Thruster {
+            current_throttle: throttle,
+            ..thruster
+        };
+        ctx.msg().publish(
+            &format!("call.{}.set", rid),
+            None,
+            &serde_json::to_vec(&json!({ "params": thruster }))?,
+        )?;
+    }
+
+    Ok(vec![])
+}
+
+/// Applies the thrust of the entity's thrusters to its velocity over the frame, publishing the
+/// entity's acceleration when it changes and the new velocity. Entities without thrust keep
+/// their velocity
+pub(crate) fn apply_thrust(
+    ctx: &CapabilitiesContext,
+    frame: &decs::systemmgr::EntityFrame,
+    velocity: Velocity,
+) -> Result<Velocity> {
+    let thrusters: Vec<Thruster> = get_thrusters(ctx, &frame.shard, &frame.entity_id)?
+        .into_iter()
+        .map(|(_, thruster)| thruster)
+        .collect();
+    if thrusters.is_empty() {
+        return Ok(velocity);
+    }
+
+    let acceleration = total_acceleration(&thrusters);
+    let previous: Option<Acceleration> = match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard, frame.entity_id, ACCELERATION
+    ))? {
+        Some(s) => Some(serde_json::from_str(&s)?),
+        None => None,
+    };
+    if previous.as_ref() != Some(&acceleration) {
+        publish_set(ctx, frame, ACCELERATION, &acceleration)?;
+    }
+    if acceleration == Acceleration::default() {
+        return Ok(velocity);
+    }
+
+    let new_velocity = accelerate(&velocity, &acceleration, frame.elapsed_ms);
+    publish_set(ctx, frame, super::VELOCITY, &new_velocity)?;
+    Ok(new_velocity)
+}
+
+/// Retrieve the rid and contents of each of the entity's thrusters
+pub(crate) fn get_thrusters(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+) -> Result<Vec<(String, Thruster)>> {
+    let mut thrusters = Vec::new();
+    for rid in ctx.kv().list_range(
+        &format!("decs:components:{}:{}:{}", shard, entity_id, THRUSTERS),
+        0,
+        -1,
+    )? {
+        if let Some(thruster_str) = ctx.kv().get(&rid.replace('.', ":"))? {
+            thrusters.push((rid, serde_json::from_str(&thruster_str)?));
+        }
+    }
+    Ok(thrusters)
+}
+
+fn publish_set<T: serde::Serialize>(
+    ctx: &CapabilitiesContext,
+    frame: &decs::systemmgr::EntityFrame,
+    component: &str,
+    value: &T,
+) -> Result<()> {
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            frame.shard, frame.entity_id, component
+        ),
+        None,
+        &serde_json::to_vec(&json!({ "params": value }))?,
+    )
+}
+
+/// The sum of the forces of every thruster
+fn total_acceleration(thrusters: &[Thruster]) -> Acceleration {
+    thrusters
+        .iter()
+        .map(Thruster::force)
+        .fold(Acceleration::default(), |total, force| Acceleration {
+            x: total.x + force.x,
+            y: total.y + force.y,
+            z: total.z + force.z,
+        })
+}
+
+/// Adds the acceleration over the elapsed time to the velocity (an Euler step)
+fn accelerate(velocity: &Velocity, acceleration: &Acceleration, elapsed_ms: u32) -> Velocity {
+    let seconds = f64::from(elapsed_ms) / 1000.0;
+    let mag = f64::from(velocity.mag);
+    let vx = mag * velocity.ux + acceleration.x * seconds;
+    let vy = mag * velocity.uy + acceleration.y * seconds;
+    let vz = mag * velocity.uz + acceleration.z * seconds;
+    let new_mag = (vx * vx + vy * vy + vz * vz).sqrt();
+    if new_mag == 0.0 {
+        return Velocity {
+            mag: 0,
+            ..*velocity
+        };
+    }
+    Velocity::new(
+        new_mag.round() as u32,
+        vx / new_mag,
+        vy / new_mag,
+        vz / new_mag,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::accelerate;
+    use super::total_acceleration;
+    use super::Acceleration;
+    use super::Position;
+    use super::Thruster;
+    use super::Velocity;
+
+    const EPSILON: f64 = 1e-9;
+
+    fn thruster(x: f64, y: f64, max_force: f64, current_throttle: f64) -> Thruster {
+        Thruster {
+            direction: Position::new(x, y, 0.0),
+            max_force,
+            current_throttle,
+            heat_per_ms: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_opposing_thrusters_cancel_out() {
+        let acceleration = total_acceleration(&[
+            thruster(1.0, 0.0, 100.0, 0.5),
+            thruster(-1.0, 0.0, 50.0, 1.0),
+        ]);
+        assert_eq!(acceleration, Acceleration::default());
+
+        // Nothing changes for an entity at rest
+        let velocity = Velocity::new(0, 0.0, 0.0, 0.0);
+        assert_eq!(accelerate(&velocity, &acceleration, 1000), velocity);
+    }
+
+    #[test]
+    fn test_diagonal_thrust_magnitude() {
+        let diagonal = std::f64::consts::FRAC_1_SQRT_2;
+        let acceleration = total_acceleration(&[
+            thruster(diagonal, diagonal, 100.0, 1.0),
+            // Not a unit vector, normalized to the same direction
+            thruster(3.0, 3.0, 100.0, 1.0),
+        ]);
+        assert!((acceleration.x - 200.0 * diagonal).abs() < EPSILON);
+        assert!((acceleration.y - 200.0 * diagonal).abs() < EPSILON);
+
+        // Half a second of 200 KPH/s thrust from rest
+        let velocity = accelerate(&Velocity::new(0, 0.0, 0.0, 0.0), &acceleration, 500);
+        assert_eq!(velocity.mag, 100);
+        assert!((velocity.ux - diagonal).abs() < EPSILON);
+        assert!((velocity.uy - diagonal).abs() < EPSILON);
+    }
+
+    #[test]
+    fn test_throttle_is_clamped() {
+        assert_eq!(thruster(1.0, 0.0, 100.0, 2.0).force().x, 100.0);
+        assert_eq!(thruster(1.0, 0.0, 100.0, -1.0).force().x, 0.0);
+        assert_eq!(thruster(0.0, 0.0, 100.0, 1.0).force(), Position::default());
+    }
+}
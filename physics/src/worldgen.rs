@@ -0,0 +1,292 @@
+//! # World Generation
+//!
+//! A call on `call.decs.{shard}.worldgen.generate` fills the shard with an asteroid field grown
+//! from a `seed`, so the same seed and `config` always produce the same field. Each asteroid,
+//! `asteroid_{n}`, gets a `position` within the config's bounds and a `transponder` colored from
+//! its palette. A `density` share of them also gets a `mining_resource` of one of the config's
+//! resource types, with a quantity between `min_qty` and `max_qty`; the rest are barren rock.
+//! Every component is created with a `new` call.
+use decs::gateway::ResProtocolRequest;
+use guest::prelude::*;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde_json::Value;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const TRANSPONDER: &str = "transponder";
+const MINING_RESOURCE: &str = "mining_resource";
+
+/// The shape of a generated asteroid field
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct WorldGenConfig {
+    pub asteroid_count: u32,
+    pub resource_types: Vec<String>, // Stack types the deposits are drawn from
+    pub min_qty: u32,
+    pub max_qty: u32,
+    pub min: Position, // Corner of the bounds with the lowest coordinates
+    pub max: Position, // Corner of the bounds with the highest coordinates
+    #[serde(default = "default_density")]
+    pub density: f64, // Share of the asteroids holding a deposit, between 0 and 1
+    #[serde(default = "default_palette")]
+    pub palette: Vec<String>, // Transponder colors
+}
+
+fn default_density() -> f64 {
+    1.0
+}
+
+fn default_palette() -> Vec<String> {
+    vec![
+        "gray".to_string(),
+        "brown".to_string(),
+        "silver".to_string(),
+    ]
+}
+
+/// Receives calls on `call.decs.{shard}.worldgen.generate`, whose params hold the `seed` and the
+/// `config` of the field, and replies with the number of asteroids created
+pub(crate) fn handle_generate(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let shard = match subject.as_slice() {
+        ["call", "decs", shard, "worldgen", "generate"] => *shard,
+        _ => return Err(format!("Unexpected world generation subject: {}", msg.subject).into()),
+    };
+    let body: Value = serde_json::from_slice(&msg.body)?;
+    let seed = body["params"]["seed"].as_u64();
+    let config = serde_json::from_value::<WorldGenConfig>(body["params"]["config"].clone());
+
+    let response = match (seed, config) {
+        (Some(seed), Ok(config)) => match generate_shard(ctx, shard, seed, config) {
+            Ok(asteroids) => json!({ "result": { "asteroids": asteroids } }),
+            Err(reason) => invalid_params(&reason),
+        },
+        _ => invalid_params("params must hold a numeric seed and a config"),
+    };
+    if !msg.reply_to.is_empty() {
+        ctx.msg()
+            .publish(&msg.reply_to, None, &serde_json::to_vec(&response)?)?;
+    }
+    Ok(vec![])
+}
+
+fn invalid_params(message: &str) -> Value {
+    json!({
+        "error": {
+            "code": "system.invalidParams",
+            "message": message
+        }
+    })
+}
+
+/// Creates the asteroid field grown from the seed on the shard, returning the number of
+/// asteroids created or why the config can't produce a field
+pub(crate) fn generate_shard(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    seed: u64,
+    config: WorldGenConfig,
+) -> std::result::Result<u32, String> {
+    let messages = plan_shard(shard, seed, &config)?;
+    for (subject, payload) in messages {
+        let payload = serde_json::to_vec(&payload).map_err(|e| e.to_string())?;
+        ctx.msg()
+            .publish(&subject, None, &payload)
+            .map_err(|e| e.to_string())?;
+    }
+    ctx.log(&format!(
+        "Generated {} asteroids on shard {} from seed {}",
+        config.asteroid_count, shard, seed
+    ));
+    Ok(config.asteroid_count)
+}
+
+/// The `new` calls creating every component of the field, asteroid by asteroid
+fn plan_shard(
+    shard: &str,
+    seed: u64,
+    config: &WorldGenConfig,
+) -> std::result::Result<Vec<(String, Value)>, String> {
+    validate(config)?;
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut messages = vec![];
+    for n in 0..config.asteroid_count {
+        let entity_id = format!("asteroid_{}", n);
+        let (position, transponder, resource) = generate_asteroid(&mut rng, config);
+        messages.push(new_component(
+            shard,
+            &entity_id,
+            super::POSITION,
+            json!(position),
+        ));
+        messages.push(new_component(
+            shard,
+            &entity_id,
+            TRANSPONDER,
+            json!(transponder),
+        ));
+        if let Some(resource) = resource {
+            messages.push(new_component(
+                shard,
+                &entity_id,
+                MINING_RESOURCE,
+                json!(resource),
+            ));
+        }
+    }
+    Ok(messages)
+}
+
+fn validate(config: &WorldGenConfig) -> std::result::Result<(), String> {
+    if config.resource_types.is_empty() {
+        Err("there must be at least one resource type".to_string())
+    } else if config.palette.is_empty() {
+        Err("the palette must hold at least one color".to_string())
+    } else if config.min_qty == 0 || config.min_qty > config.max_qty {
+        Err("min_qty must be positive and no more than max_qty".to_string())
+    } else if config.min.x > config.max.x
+        || config.min.y > config.max.y
+        || config.min.z > config.max.z
+    {
+        Err("the bounds' min must not exceed their max".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Draws one asteroid: its position, its transponder and, unless it is barren, its deposit
+fn generate_asteroid(
+    rng: &mut SmallRng,
+    config: &WorldGenConfig,
+) -> (Position, RadarTransponder, Option<MiningResource>) {
+    let position = Position::new(
+        between(rng, config.min.x, config.max.x),
+        between(rng, config.min.y, config.max.y),
+        between(rng, config.min.z, config.max.z),
+    );
+    let transponder = RadarTransponder {
+        object_type: "asteroid".to_string(),
+        display_name: "Asteroid".to_string(),
+        color: config.palette[rng.gen_range(0, config.palette.len())].clone(),
+        ..Default::default()
+    };
+    let resource = if rng.gen_range(0.0, 1.0) < config.density {
+        Some(MiningResource {
+            stack_type: config.resource_types[rng.gen_range(0, config.resource_types.len())]
+                .clone(),
+            qty: rng.gen_range(config.min_qty, config.max_qty + 1),
+            ..Default::default()
+        })
+    } else {
+        None
+    };
+    (position, transponder, resource)
+}
+
+/// A coordinate drawn uniformly between the bounds, which may be the same
+fn between(rng: &mut SmallRng, min: f64, max: f64) -> f64 {
+    if max > min {
+        rng.gen_range(min, max)
+    } else {
+        min
+    }
+}
+
+fn new_component(shard: &str, entity_id: &str, component: &str, value: Value) -> (String, Value) {
+    (
+        ResProtocolRequest::New(format!(
+            "decs.components.{}.{}.{}",
+            shard, entity_id, component
+        ))
+        .to_string(),
+        json!({ "params": value }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::plan_shard;
+    use super::Position;
+    use super::WorldGenConfig;
+
+    fn config(asteroid_count: u32, density: f64) -> WorldGenConfig {
+        WorldGenConfig {
+            asteroid_count,
+            resource_types: vec![
+                "spendy".to_string(),
+                "tasty".to_string(),
+                "critical".to_string(),
+            ],
+            min_qty: 10,
+            max_qty: 50,
+            min: Position::new(-1000.0, -1000.0, -100.0),
+            max: Position::new(1000.0, 1000.0, 100.0),
+            density,
+            palette: vec!["gray".to_string(), "brown".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_fixed_seed_places_the_same_field() {
+        let messages = plan_shard("the_void", 42, &config(3, 1.0)).unwrap();
+        assert_eq!(messages.len(), 9);
+        assert_eq!(
+            messages[0].0,
+            "call.decs.components.the_void.asteroid_0.position.new"
+        );
+        assert_eq!(
+            messages[0].1["params"],
+            json!({ "x": 147.18316181634805, "y": -733.3867349912114, "z": -28.812831943109614 })
+        );
+        assert_eq!(
+            messages[2].0,
+            "call.decs.components.the_void.asteroid_0.mining_resource.new"
+        );
+        assert_eq!(messages[2].1["params"]["qty"], 50);
+
+        assert_eq!(
+            messages,
+            plan_shard("the_void", 42, &config(3, 1.0)).unwrap()
+        );
+        assert_ne!(
+            messages,
+            plan_shard("the_void", 43, &config(3, 1.0)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_field_stays_within_config() {
+        let messages = plan_shard("the_void", 7, &config(200, 0.5)).unwrap();
+        let deposits: Vec<_> = messages
+            .iter()
+            .filter(|(subject, _)| subject.ends_with(".mining_resource.new"))
+            .collect();
+        // Roughly half of the asteroids are barren
+        assert!(deposits.len() > 60 && deposits.len() < 140);
+        for (_, payload) in deposits {
+            let qty = payload["params"]["qty"].as_u64().unwrap();
+            assert!((10..=50).contains(&qty));
+        }
+        for (subject, payload) in &messages {
+            if subject.ends_with(".position.new") {
+                let x = payload["params"]["x"].as_f64().unwrap();
+                let z = payload["params"]["z"].as_f64().unwrap();
+                assert!((-1000.0..1000.0).contains(&x));
+                assert!((-100.0..100.0).contains(&z));
+            }
+        }
+    }
+
+    #[test]
+    fn test_config_that_cant_produce_a_field_is_refused() {
+        let mut inverted = config(3, 1.0);
+        inverted.min_qty = 60;
+        assert!(plan_shard("the_void", 42, &inverted).is_err());
+        let mut no_resources = config(3, 1.0);
+        no_resources.resource_types.clear();
+        assert!(plan_shard("the_void", 42, &no_resources).is_err());
+    }
+}
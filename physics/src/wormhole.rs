@@ -0,0 +1,477 @@
+//! # Wormholes
+//!
+//! Entities with a `wormhole` component act as one end of a wormhole pair. When an entity comes
+//! within capture range of a wormhole, a `wormhole_transit` component is attached to it, it drops
+//! off of radar, and physics stops moving it through normal space. Once the wormhole's transit time
+//! has elapsed the entity emerges at the exit position or, if the wormhole proved unstable during
+//! the crossing, somewhere near the exit. An entity that has emerged carries a `wormhole_exit`
+//! component until it leaves the capture range of the wormhole it came out of, which can't
+//! capture it in the meantime.
+use guest::prelude::*;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use stacktrader_types as trader;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use trader::components::*;
+use trader::validation::Validate;
+
+const WORMHOLE: &str = "wormhole";
+const WORMHOLE_TRANSIT: &str = "wormhole_transit";
+const WORMHOLE_EXIT: &str = "wormhole_exit";
+const TRANSPONDER: &str = "transponder";
+const CAPTURE_RADIUS_KM: f64 = 1.0;
+const EJECTION_RADIUS_KM: f64 = 25.0;
+// Game time after which a shard's cached wormholes are loaded again
+const WORMHOLE_CACHE_TTL_MS: u64 = 10_000;
+
+// wormhole entity ID -> (wormhole position, wormhole)
+type ShardWormholes = HashMap<String, (Position, Wormhole)>;
+
+lazy_static! {
+    // shard -> (game time the wormholes were loaded at, wormholes)
+    static ref WORMHOLES: RwLock<HashMap<String, (u64, ShardWormholes)>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Moves the frame's entity into or out of a wormhole. Returns true while the entity is
+/// in transit, in which case it must not be moved through normal space during this frame
+pub(crate) fn handle_frame(
+    ctx: &CapabilitiesContext,
+    frame: &decs::systemmgr::EntityFrame,
+    position: &Position,
+) -> Result<bool> {
+    let now_ms = trader::clock::frame_time_ms(frame, super::FRAMERATE);
+
+    let transit_value = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard, frame.entity_id, WORMHOLE_TRANSIT
+    ))?;
+    if let Some(transit_str) = transit_value {
+        let transit: WormholeTransit = serde_json::from_str(&transit_str)?;
+        if transit_complete(&transit, now_ms) {
+            exit_wormhole(ctx, &frame.shard, &frame.entity_id, &transit)?;
+        }
+        return Ok(true);
+    }
+
+    let wormholes = get_wormholes(ctx, &frame.shard, now_ms)?;
+    let exit_value = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard, frame.entity_id, WORMHOLE_EXIT
+    ))?;
+    let exit: Option<WormholeExit> = match exit_value {
+        Some(exit_str) => Some(serde_json::from_str(&exit_str)?),
+        None => None,
+    };
+    if let Some(exit) = &exit {
+        if left_exit(exit, position, &wormholes) {
+            delete_component(ctx, &frame.shard, &frame.entity_id, WORMHOLE_EXIT)?;
+        }
+    }
+
+    let exited_id = exit.as_ref().map(|exit| exit.wormhole_id.as_str());
+    if let Some(wormhole) = find_wormhole(&frame.entity_id, exited_id, position, &wormholes) {
+        let mut rng = SmallRng::seed_from_u64(super::frame_seed(&frame.entity_id, frame.seq_no));
+        let transit = plan_transit(wormhole, now_ms, &mut rng);
+        enter_wormhole(ctx, &frame.shard, &frame.entity_id, transit)?;
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+/// Hides the entity from radar and attaches the transit component to it, remembering the
+/// radar signature to restore on exit
+fn enter_wormhole(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    transit: WormholeTransit,
+) -> Result<()> {
+    let radar_signature = set_radar_signature(ctx, shard, entity_id, 0.0)?;
+    let transit = WormholeTransit {
+        radar_signature,
+        ..transit
+    };
+    let subject = format!(
+        "call.decs.components.{}.{}.{}.set",
+        shard, entity_id, WORMHOLE_TRANSIT
+    );
+    ctx.msg().publish(
+        &subject,
+        None,
+        &serde_json::to_vec(&json!({ "params": transit }))?,
+    )?;
+    Ok(())
+}
+
+/// Places the entity at the transit's exit position, removes the transit component,
+/// marks the wormhole it emerges from and makes the entity visible to radar again
+fn exit_wormhole(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    transit: &WormholeTransit,
+) -> Result<()> {
+    let exit_position = clamp_to_universe(&transit.exit_position, &super::get_metadata(ctx, shard));
+    exit_position.validate()?;
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard,
+            entity_id,
+            super::POSITION
+        ),
+        None,
+        &serde_json::to_vec(&json!({ "params": exit_position }))?,
+    )?;
+
+    delete_component(ctx, shard, entity_id, WORMHOLE_TRANSIT)?;
+
+    if !transit.exit_wormhole_id.is_empty() {
+        let exit = WormholeExit {
+            wormhole_id: transit.exit_wormhole_id.to_string(),
+        };
+        ctx.msg().publish(
+            &format!(
+                "call.decs.components.{}.{}.{}.set",
+                shard, entity_id, WORMHOLE_EXIT
+            ),
+            None,
+            &serde_json::to_vec(&json!({ "params": exit }))?,
+        )?;
+    }
+
+    // Transits that started before signatures were remembered get the default one back
+    let radar_signature = transit
+        .radar_signature
+        .unwrap_or(RadarTransponder::default().radar_signature);
+    set_radar_signature(ctx, shard, entity_id, radar_signature)?;
+    Ok(())
+}
+
+fn delete_component(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> Result<()> {
+    let rid = format!("decs.components.{}.{}.{}", shard, entity_id, component);
+    ctx.msg().publish(
+        &format!("call.{}.delete", rid),
+        None,
+        &serde_json::to_vec(&json!({
+            "params": {
+                "rid": rid
+            }
+        }))?,
+    )?;
+    Ok(())
+}
+
+/// Republishes the entity's transponder with the given radar signature, returning the
+/// signature it had. Entities without a transponder never show up on radar, so there is
+/// nothing to do for them
+fn set_radar_signature(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    radar_signature: f64,
+) -> Result<Option<f64>> {
+    let raw = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, TRANSPONDER
+    ))?;
+    if let Some(tp_str) = raw {
+        let tp: RadarTransponder = serde_json::from_str(&tp_str)?;
+        let previous = tp.radar_signature;
+        let tp = RadarTransponder {
+            radar_signature,
+            ..tp
+        };
+        ctx.msg().publish(
+            &format!(
+                "call.decs.components.{}.{}.{}.set",
+                shard, entity_id, TRANSPONDER
+            ),
+            None,
+            &serde_json::to_vec(&json!({ "params": tp }))?,
+        )?;
+        return Ok(Some(previous));
+    }
+    Ok(None)
+}
+
+/// Retrieve the wormholes for the shard from the cache. If the shard isn't cached yet, or
+/// was loaded more than `WORMHOLE_CACHE_TTL_MS` of game time ago, load every entity with a
+/// `wormhole` component (and its position) from the KV store, so wormholes that are created,
+/// moved or removed are picked up.
+fn get_wormholes(ctx: &CapabilitiesContext, shard: &str, now_ms: u64) -> Result<ShardWormholes> {
+    if let Some((loaded_at_ms, wormholes)) = WORMHOLES.read().unwrap().get(shard) {
+        if cache_fresh(*loaded_at_ms, now_ms) {
+            return Ok(wormholes.clone());
+        }
+    }
+
+    let mut wormholes = HashMap::new();
+    for entity in ctx
+        .kv()
+        .set_members(&format!("decs:{}:{}:entities", shard, WORMHOLE))?
+    {
+        let wormhole_value = ctx.kv().get(&format!(
+            "decs:components:{}:{}:{}",
+            shard, entity, WORMHOLE
+        ))?;
+        let position_value = ctx.kv().get(&format!(
+            "decs:components:{}:{}:{}",
+            shard,
+            entity,
+            super::POSITION
+        ))?;
+        if let (Some(wormhole_str), Some(position_str)) = (wormhole_value, position_value) {
+            wormholes.insert(
+                entity,
+                (
+                    serde_json::from_str(&position_str)?,
+                    serde_json::from_str(&wormhole_str)?,
+                ),
+            );
+        }
+    }
+    WORMHOLES
+        .write()
+        .unwrap()
+        .insert(shard.to_string(), (now_ms, wormholes.clone()));
+    Ok(wormholes)
+}
+
+/// A cache loaded at a later game time than now belongs to a shard that has been restarted
+fn cache_fresh(loaded_at_ms: u64, now_ms: u64) -> bool {
+    now_ms >= loaded_at_ms && now_ms - loaded_at_ms < WORMHOLE_CACHE_TTL_MS
+}
+
+/// Finds the closest wormhole within capture range of the position, other than the entity
+/// itself and the wormhole it has just emerged from
+fn find_wormhole<'a>(
+    entity_id: &str,
+    exited_id: Option<&str>,
+    position: &Position,
+    wormholes: &'a ShardWormholes,
+) -> Option<&'a Wormhole> {
+    wormholes
+        .iter()
+        .filter(|(id, (pos, _))| {
+            id.as_str() != entity_id
+                && Some(id.as_str()) != exited_id
+                && position.distance_to_3d(pos) <= CAPTURE_RADIUS_KM
+        })
+        .min_by(|(_, (a, _)), (_, (b, _))| {
+            position
+                .distance_to_3d(a)
+                .partial_cmp(&position.distance_to_3d(b))
+                .unwrap_or(Ordering::Equal)
+        })
+        .map(|(_, (_, wormhole))| wormhole)
+}
+
+/// Whether the entity is out of capture range of the wormhole it emerged from, or that
+/// wormhole is gone
+fn left_exit(exit: &WormholeExit, position: &Position, wormholes: &ShardWormholes) -> bool {
+    match wormholes.get(&exit.wormhole_id) {
+        Some((pos, _)) => position.distance_to_3d(pos) > CAPTURE_RADIUS_KM,
+        None => true,
+    }
+}
+
+/// Plans an entity's trip through the wormhole. The stability roll happens on entry, so
+/// an unstable crossing already knows where it will eject the entity
+fn plan_transit<R: Rng>(wormhole: &Wormhole, now_ms: u64, rng: &mut R) -> WormholeTransit {
+    let exit_position = if rng.gen::<f64>() < wormhole.stability {
+        wormhole.exit_position
+    } else {
+        Position::new(
+            wormhole.exit_position.x + rng.gen_range(-EJECTION_RADIUS_KM, EJECTION_RADIUS_KM),
+            wormhole.exit_position.y + rng.gen_range(-EJECTION_RADIUS_KM, EJECTION_RADIUS_KM),
+            wormhole.exit_position.z + rng.gen_range(-EJECTION_RADIUS_KM, EJECTION_RADIUS_KM),
+        )
+    };
+    WormholeTransit {
+        entry_time_ms: now_ms,
+        exit_position,
+        transit_time_ms: wormhole.transit_time_ms,
+        exit_wormhole_id: wormhole.pair_id.to_string(),
+        radar_signature: None,
+    }
+}
+
+fn transit_complete(transit: &WormholeTransit, now_ms: u64) -> bool {
+    now_ms
+        >= transit
+            .entry_time_ms
+            .saturating_add(transit.transit_time_ms)
+}
+
+/// An ejected entity could otherwise land outside of the universe, where physics would
+/// never let it move again
+fn clamp_to_universe(pos: &Position, md: &UniverseMetadata) -> Position {
+    Position {
+        x: pos.x.max(md.min_x).min(md.max_x),
+        y: pos.y.max(md.min_y).min(md.max_y),
+        z: pos.z.max(md.min_z).min(md.max_z),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::cache_fresh;
+    use super::clamp_to_universe;
+    use super::find_wormhole;
+    use super::left_exit;
+    use super::plan_transit;
+    use super::transit_complete;
+    use super::HashMap;
+    use super::Position;
+    use super::SmallRng;
+    use super::UniverseMetadata;
+    use super::Wormhole;
+    use super::WormholeExit;
+    use super::WormholeTransit;
+    use super::CAPTURE_RADIUS_KM;
+    use super::EJECTION_RADIUS_KM;
+    use super::WORMHOLE_CACHE_TTL_MS;
+    use crate::frame_seed;
+    use rand::SeedableRng;
+
+    fn wormhole(stability: f64) -> Wormhole {
+        Wormhole {
+            pair_id: "wormhole_b".to_string(),
+            exit_position: Position::new(50.0, 50.0, 50.0),
+            transit_time_ms: 3_000,
+            stability,
+        }
+    }
+
+    #[test]
+    fn test_stable_transit_exits_at_pair() {
+        let mut rng = SmallRng::seed_from_u64(frame_seed("ship", 1));
+        let transit = plan_transit(&wormhole(1.0), 10_000, &mut rng);
+
+        assert_eq!(transit.entry_time_ms, 10_000);
+        assert_eq!(transit.transit_time_ms, 3_000);
+        assert_eq!(transit.exit_position, Position::new(50.0, 50.0, 50.0));
+        assert_eq!(transit.exit_wormhole_id, "wormhole_b");
+    }
+
+    #[test]
+    fn test_unstable_transit_ejects_nearby() {
+        for seq_no in 0..50 {
+            let mut rng = SmallRng::seed_from_u64(frame_seed("ship", seq_no));
+            let transit = plan_transit(&wormhole(0.0), 0, &mut rng);
+            let exit = wormhole(0.0).exit_position;
+
+            assert_ne!(transit.exit_position, exit);
+            assert!((transit.exit_position.x - exit.x).abs() <= EJECTION_RADIUS_KM);
+            assert!((transit.exit_position.y - exit.y).abs() <= EJECTION_RADIUS_KM);
+            assert!((transit.exit_position.z - exit.z).abs() <= EJECTION_RADIUS_KM);
+        }
+    }
+
+    #[test]
+    fn test_transit_complete() {
+        let transit = WormholeTransit {
+            entry_time_ms: 1_000,
+            exit_position: Position::default(),
+            transit_time_ms: 500,
+            ..Default::default()
+        };
+
+        assert!(!transit_complete(&transit, 1_000));
+        assert!(!transit_complete(&transit, 1_499));
+        assert!(transit_complete(&transit, 1_500));
+        assert!(transit_complete(&transit, 9_000));
+    }
+
+    #[test]
+    fn test_find_wormhole() {
+        let mut wormholes = HashMap::new();
+        wormholes.insert(
+            "wormhole_a".to_string(),
+            (Position::new(0.0, 0.0, 0.0), wormhole(1.0)),
+        );
+        wormholes.insert(
+            "wormhole_c".to_string(),
+            (
+                Position::new(1.5, 0.0, 0.0),
+                Wormhole {
+                    pair_id: "wormhole_d".to_string(),
+                    ..wormhole(1.0)
+                },
+            ),
+        );
+
+        // Closest wormhole within capture range wins
+        let found = find_wormhole("ship", None, &Position::new(0.8, 0.0, 0.0), &wormholes);
+        assert_eq!(found.unwrap().pair_id, "wormhole_d");
+        let found = find_wormhole("ship", None, &Position::new(0.2, 0.0, 0.0), &wormholes);
+        assert_eq!(found.unwrap().pair_id, "wormhole_b");
+
+        // Out of range of every wormhole
+        assert!(find_wormhole("ship", None, &Position::new(10.0, 0.0, 0.0), &wormholes).is_none());
+
+        // A wormhole can't swallow itself
+        assert!(find_wormhole(
+            "wormhole_a",
+            None,
+            &Position::new(-0.6, 0.0, 0.0),
+            &wormholes
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_no_recapture_by_exit_wormhole() {
+        let mut wormholes = HashMap::new();
+        wormholes.insert(
+            "wormhole_b".to_string(),
+            (
+                Position::new(50.0, 50.0, 50.0),
+                Wormhole {
+                    pair_id: "wormhole_a".to_string(),
+                    ..wormhole(1.0)
+                },
+            ),
+        );
+        let exit = WormholeExit {
+            wormhole_id: "wormhole_b".to_string(),
+        };
+
+        // An ejection can land the entity within capture range of the wormhole it came out of
+        let ejected = Position::new(50.5, 50.0, 50.0);
+        assert!(find_wormhole("ship", None, &ejected, &wormholes).is_some());
+        assert!(find_wormhole("ship", Some("wormhole_b"), &ejected, &wormholes).is_none());
+        assert!(!left_exit(&exit, &ejected, &wormholes));
+
+        // Once out of range, the exit is forgotten and the wormhole can capture the entity again
+        let away = Position::new(50.0 + CAPTURE_RADIUS_KM + 0.5, 50.0, 50.0);
+        assert!(left_exit(&exit, &away, &wormholes));
+        assert!(left_exit(&exit, &ejected, &HashMap::new()));
+    }
+
+    #[test]
+    fn test_cache_fresh() {
+        assert!(cache_fresh(1_000, 1_000));
+        assert!(cache_fresh(1_000, 1_000 + WORMHOLE_CACHE_TTL_MS - 1));
+        assert!(!cache_fresh(1_000, 1_000 + WORMHOLE_CACHE_TTL_MS));
+        assert!(!cache_fresh(1_000, 500));
+    }
+
+    #[test]
+    fn test_clamp_to_universe() {
+        let md = UniverseMetadata::default();
+        let clamped = clamp_to_universe(&Position::new(120.0, -130.0, 10.0), &md);
+
+        assert_eq!(clamped, Position::new(100.0, -100.0, 10.0));
+    }
+}
@@ -0,0 +1,47 @@
+//! 100 individual radar frames, each taking its own snapshot of the cached positions, vs. one
+//! 100 frame batch sharing a snapshot. Run with `cargo bench -p radar`
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use radar::bench_radar_updates;
+use stacktrader_types::components::{Position, RadarReceiver};
+use stacktrader_types::units::Distance;
+use std::collections::HashMap;
+
+const ENTITIES: usize = 100;
+
+fn positions() -> HashMap<String, Position> {
+    (0..ENTITIES * 10)
+        .map(|i| {
+            let i = i as f64;
+            (format!("entity{}", i), Position::new(i, i * 0.5, -i))
+        })
+        .collect()
+}
+
+fn individual_vs_batch(c: &mut Criterion) {
+    let all_positions = positions();
+    let receiver = RadarReceiver {
+        radius: Distance(250.0),
+        cross_shard_range: None,
+    };
+    let entities: Vec<String> = (0..ENTITIES).map(|i| format!("entity{}", i)).collect();
+
+    c.bench_function("100 individual frames", |b| {
+        b.iter(|| {
+            for entity_id in &entities {
+                let snapshot = all_positions.clone();
+                black_box(bench_radar_updates(entity_id, &receiver, &snapshot));
+            }
+        })
+    });
+    c.bench_function("one 100 frame batch", |b| {
+        b.iter(|| {
+            let snapshot = all_positions.clone();
+            for entity_id in &entities {
+                black_box(bench_radar_updates(entity_id, &receiver, &snapshot));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, individual_vs_batch);
+criterion_main!(benches);
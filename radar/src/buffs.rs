@@ -0,0 +1,65 @@
+//! # Timed Buffs
+//!
+//! Buffs on `radar_range` extend (or shrink) both the local and cross-shard range of the entity's
+//! radar receiver until they expire. They are applied by an event on
+//! `event.decs.{shard}.{entity}.buff.applied` carrying the buff, and buffs on other stats are left
+//! to the systems those stats belong to. Each radar frame clears the entity's expired buffs and
+//! publishes `event.decs.system.buff.expired` for each of them.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::buffs::{self, StatTarget, TimedBuff};
+use trader::components::*;
+
+/// Receives buffs on `event.decs.{shard}.{entity}.buff.applied`, keeping those on radar range
+pub(crate) fn handle_buff_applied(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let (shard, entity_id) = match subject.as_slice() {
+        ["event", "decs", shard, entity_id, "buff", "applied"] => (*shard, *entity_id),
+        _ => return Err(format!("Unexpected buff subject: {}", msg.subject).into()),
+    };
+    let buff: TimedBuff = serde_json::from_slice(&msg.body)?;
+    if buff.stat == StatTarget::RadarRange {
+        ctx.log(&format!(
+            "Applying buff {} to the radar range of entity {}",
+            buff.id, entity_id
+        ));
+        buffs::apply_buff(shard, entity_id, buff);
+    }
+    Ok(vec![])
+}
+
+/// The receiver with its ranges scaled by the multiplier of the entity's radar range buffs
+pub(crate) fn boosted_receiver(receiver: RadarReceiver, multiplier: f64) -> RadarReceiver {
+    RadarReceiver {
+        radius: receiver.radius * multiplier,
+        cross_shard_range: receiver.cross_shard_range.map(|range| range * multiplier),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::boosted_receiver;
+    use super::RadarReceiver;
+    use stacktrader_types::units::Distance;
+
+    #[test]
+    fn test_buff_scales_both_ranges() {
+        let receiver = RadarReceiver {
+            radius: Distance(5.0),
+            cross_shard_range: Some(2.0),
+        };
+        let boosted = boosted_receiver(receiver, 1.5);
+        assert_eq!(boosted.radius, Distance(7.5));
+        assert_eq!(boosted.cross_shard_range, Some(3.0));
+
+        // A receiver that doesn't look across shards still doesn't
+        let local = RadarReceiver {
+            radius: Distance(5.0),
+            cross_shard_range: None,
+        };
+        assert_eq!(boosted_receiver(local, 2.0).cross_shard_range, None);
+    }
+}
@@ -0,0 +1,293 @@
+//! # Cross-Shard Radar
+//!
+//! Radar receivers with a `cross_shard_range` also detect entities in adjacent shards. The shards
+//! adjacent to a shard are the members of the KV set `decs:{shard}:radar:adjacent_shards`. Once
+//! per game second, the radar system shares the positions of a shard's entities on
+//! `event.decs.system.cross_shard_positions.{shard}`, unless the shard has no adjacent shards.
+//! Every radar system collects what the other shards share, and receivers opted in to
+//! cross-shard detection get a contact, flagged with `cross_shard`, for each entity of an
+//! adjacent shard within their cross-shard range.
+use crate::radar::{transponder_for_entity, RadarContactDelta};
+use guest::prelude::*;
+use stacktrader_types as trader;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use trader::components::*;
+use trader::rid::ResourceIdentifier;
+use trader::units::Distance;
+
+const CROSS_SHARD_POSITIONS: &str = "event.decs.system.cross_shard_positions";
+
+lazy_static! {
+    // shard -> entity -> position, as last shared by each shard
+    static ref REMOTE_POSITIONS: RwLock<HashMap<String, HashMap<String, Position>>> =
+        RwLock::new(HashMap::new());
+    // shard -> game time its positions were last shared at
+    static ref LAST_SHARED_MS: RwLock<HashMap<String, u64>> = RwLock::new(HashMap::new());
+}
+
+/// Receives the positions shared by a shard on `event.decs.system.cross_shard_positions.{shard}`
+pub(crate) fn handle_remote_positions(
+    _ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let shard = match msg.subject.rsplit('.').next() {
+        Some(shard) if !shard.is_empty() => shard.to_string(),
+        _ => return Err(format!("Unexpected cross-shard subject: {}", msg.subject).into()),
+    };
+    let positions: HashMap<String, Position> = serde_json::from_slice(&msg.body)?;
+    REMOTE_POSITIONS.write().unwrap().insert(shard, positions);
+    Ok(vec![])
+}
+
+/// Shares the positions of the shard's entities with the radar systems of its adjacent shards,
+/// at most once for each game second
+pub(crate) fn share_positions(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    now_ms: u64,
+    positions: &HashMap<String, Position>,
+) -> Result<()> {
+    if !share_due(shard, now_ms) || adjacent_shards(ctx, shard)?.is_empty() {
+        return Ok(());
+    }
+    ctx.msg().publish(
+        &format!("{}.{}", CROSS_SHARD_POSITIONS, shard),
+        None,
+        &serde_json::to_vec(positions)?,
+    )
+}
+
+/// Whether the shard's positions haven't been shared yet in the game second of `now_ms`. If
+/// not, they are taken to be shared now
+fn share_due(shard: &str, now_ms: u64) -> bool {
+    let second = now_ms / 1000;
+    let mut last_shared = LAST_SHARED_MS.write().unwrap();
+    match last_shared.get(shard) {
+        Some(last) if last / 1000 >= second => false,
+        _ => {
+            last_shared.insert(shard.to_string(), now_ms);
+            true
+        }
+    }
+}
+
+/// The positions last shared by each shard adjacent to the shard
+pub(crate) fn adjacent_positions(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+) -> Result<HashMap<String, HashMap<String, Position>>> {
+    let adjacent = adjacent_shards(ctx, shard)?;
+    let remote = REMOTE_POSITIONS.read().unwrap();
+    Ok(adjacent
+        .into_iter()
+        .filter_map(|adjacent| {
+            let positions = remote.get(&adjacent)?.clone();
+            Some((adjacent, positions))
+        })
+        .collect())
+}
+
+fn adjacent_shards(ctx: &CapabilitiesContext, shard: &str) -> Result<Vec<String>> {
+    Ok(ctx
+        .kv()
+        .set_members(&format!("decs:{}:radar:adjacent_shards", shard))?
+        .into_iter()
+        .filter(|adjacent| adjacent != shard)
+        .collect())
+}
+
+/// Computes the changes to an entity's cross-shard contacts. Entities of other shards within
+/// `range` are added, or changed if they already are contacts, and contacts that are no longer
+/// within range are removed. Without a range, every cross-shard contact is removed
+pub(crate) fn cross_shard_updates(
+    shard: &str,
+    current_position: &Position,
+    range: Option<f64>,
+    old_contacts: &HashMap<String, RadarContact>,
+    remote_positions: &HashMap<String, HashMap<String, Position>>,
+) -> Vec<RadarContactDelta> {
+    // (shard, entity) -> rid of the existing contact
+    let known: HashMap<(String, String), &String> = old_contacts
+        .iter()
+        .filter_map(|(rid, contact)| {
+            ResourceIdentifier::parse_rid(&contact.transponder.rid)
+                .ok()
+                .map(|t| ((t.shard().to_string(), t.entity().to_string()), rid))
+        })
+        .collect();
+
+    let mut seen = HashSet::new();
+    let mut deltas = Vec::new();
+    if let Some(range) = range {
+        for (remote_shard, positions) in remote_positions {
+            if remote_shard == shard {
+                continue;
+            }
+            for (entity_id, position) in positions {
+                if current_position.distance_to_3d(position) > range {
+                    continue;
+                }
+                let transponder = match transponder_for_entity(remote_shard, entity_id) {
+                    Some(transponder) => transponder,
+                    None => continue,
+                };
+                let vector_to = current_position.vector_to(position);
+                let contact = RadarContact {
+                    entity_id: entity_id.to_string(),
+                    distance: Distance::from(vector_to.mag),
+                    distance_xy: vector_to.distance_xy,
+                    azimuth: vector_to.azimuth,
+                    elevation: vector_to.elevation,
+                    transponder,
+                    cross_shard: true,
+                };
+                match known.get(&(remote_shard.to_string(), entity_id.to_string())) {
+                    Some(rid) => {
+                        seen.insert(rid.to_string());
+                        deltas.push(RadarContactDelta::Change(rid.to_string(), contact));
+                    }
+                    None => deltas.push(RadarContactDelta::Add(contact)),
+                }
+            }
+        }
+    }
+    for rid in old_contacts.keys() {
+        if !seen.contains(rid) {
+            deltas.push(RadarContactDelta::Remove(rid.to_string()));
+        }
+    }
+    deltas
+}
+
+#[cfg(test)]
+mod test {
+    use super::cross_shard_updates;
+    use super::share_due;
+    use super::Distance;
+    use super::HashMap;
+    use super::Position;
+    use super::RadarContact;
+    use super::RadarContactDelta;
+    use decs::gateway::ResourceIdentifier;
+
+    const CONTACT_RID: &str = "decs.components.the_void.player1.radar_contacts.c1";
+
+    /// The void's neighbor, the abyss, shares two entities. The void shares its own player too
+    fn two_shards() -> HashMap<String, HashMap<String, Position>> {
+        let mut abyss = HashMap::new();
+        abyss.insert("ship1".to_string(), Position::new(30.0, 40.0, 0.0));
+        abyss.insert("ship2".to_string(), Position::new(3000.0, 0.0, 0.0));
+        let mut void = HashMap::new();
+        void.insert("player2".to_string(), Position::new(1.0, 0.0, 0.0));
+
+        let mut shards = HashMap::new();
+        shards.insert("the_abyss".to_string(), abyss);
+        shards.insert("the_void".to_string(), void);
+        shards
+    }
+
+    fn abyss_contact(entity_id: &str) -> RadarContact {
+        RadarContact {
+            entity_id: entity_id.to_string(),
+            transponder: ResourceIdentifier {
+                rid: format!("decs.components.the_abyss.{}.transponder", entity_id),
+            },
+            cross_shard: true,
+            ..RadarContact::default()
+        }
+    }
+
+    #[test]
+    fn test_entities_in_adjacent_shard_within_range_are_added() {
+        let deltas = cross_shard_updates(
+            "the_void",
+            &Position::new(0.0, 0.0, 0.0),
+            Some(100.0),
+            &HashMap::new(),
+            &two_shards(),
+        );
+
+        // Only ship1 is within range, the void's own entities are left to the regular scan
+        assert_eq!(deltas.len(), 1);
+        match &deltas[0] {
+            RadarContactDelta::Add(contact) => {
+                assert_eq!(contact.entity_id, "ship1");
+                assert_eq!(contact.distance, Distance(50.0));
+                assert!(contact.cross_shard);
+                assert_eq!(
+                    contact.transponder.rid,
+                    "decs.components.the_abyss.ship1.transponder"
+                );
+            }
+            other => panic!("expected an add, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_known_cross_shard_contacts_change_and_leave() {
+        let mut old_contacts = HashMap::new();
+        old_contacts.insert(CONTACT_RID.to_string(), abyss_contact("ship1"));
+        let deltas = cross_shard_updates(
+            "the_void",
+            &Position::new(0.0, 0.0, 0.0),
+            Some(100.0),
+            &old_contacts,
+            &two_shards(),
+        );
+        assert_eq!(deltas.len(), 1);
+        match &deltas[0] {
+            RadarContactDelta::Change(rid, contact) => {
+                assert_eq!(rid, CONTACT_RID);
+                assert_eq!(contact.distance, Distance(50.0));
+            }
+            other => panic!("expected a change, got {:?}", other),
+        }
+
+        // Moving away from the border loses the contact
+        let deltas = cross_shard_updates(
+            "the_void",
+            &Position::new(-500.0, 0.0, 0.0),
+            Some(100.0),
+            &old_contacts,
+            &two_shards(),
+        );
+        assert_eq!(
+            deltas,
+            vec![RadarContactDelta::Remove(CONTACT_RID.to_string())]
+        );
+    }
+
+    #[test]
+    fn test_receiver_without_cross_shard_range_drops_cross_shard_contacts() {
+        let mut old_contacts = HashMap::new();
+        old_contacts.insert(CONTACT_RID.to_string(), abyss_contact("ship1"));
+        let deltas = cross_shard_updates(
+            "the_void",
+            &Position::new(0.0, 0.0, 0.0),
+            None,
+            &old_contacts,
+            &two_shards(),
+        );
+        assert_eq!(deltas.len(), 1);
+        assert!(cross_shard_updates(
+            "the_void",
+            &Position::new(0.0, 0.0, 0.0),
+            None,
+            &HashMap::new(),
+            &two_shards(),
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_positions_are_shared_once_per_game_second() {
+        assert!(share_due("sharing_shard", 5_000));
+        assert!(!share_due("sharing_shard", 5_000));
+        assert!(!share_due("sharing_shard", 5_999));
+        assert!(share_due("sharing_shard", 6_000));
+        // Late frames don't share older positions again
+        assert!(!share_due("sharing_shard", 4_000));
+        assert!(share_due("other_sharing_shard", 5_000));
+    }
+}
@@ -0,0 +1,269 @@
+//! # Death Recaps
+//!
+//! When an entity is destroyed, `event.decs.components.{shard}.{entity}.hull.destroyed` is
+//! published with `{ "killer_entity": ..., "cause_of_death": ... }`. The radar actor recaps the
+//! entity's final state: its last cached position, the hit points its `hull_integrity` had left
+//! and which of the `RECAP_COMPONENTS` it still had. The `DeathRecap` is stored in the KV store
+//! under `decs:death_recaps:{shard}:{entity}` and published on `event.decs.system.death.recap`.
+//!
+//! The KV store only keeps the recap for `RECAP_TTL_MS` of game time: a cleanup event is
+//! scheduled on `event.decs.system.death.recap_expired`, which removes the recap unless the
+//! entity has died again since.
+use crate::radar::cached_positions;
+use guest::prelude::*;
+use serde_json::Value;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const HULL_INTEGRITY: &str = "hull_integrity";
+const RECAP_EVENT: &str = "event.decs.system.death.recap";
+pub(crate) const RECAP_EXPIRED_EVENT: &str = "event.decs.system.death.recap_expired";
+const RECAP_TTL_MS: u64 = 3_600_000;
+const UNKNOWN_CAUSE: &str = "unknown";
+
+/// The components whose presence is recorded in a recap. The KV store can't list an entity's
+/// components, so only these are looked up
+const RECAP_COMPONENTS: &[&str] = &[
+    "velocity",
+    "radar_receiver",
+    "turret",
+    "targeting_computer",
+    "escape_protocol",
+    "energy_core",
+    "thermal_profile",
+    "fuel_tank",
+    "extractors",
+];
+
+/// Body of a destruction event
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+struct EntityDestroyed {
+    #[serde(default)]
+    killer_entity: Option<String>,
+    #[serde(default)]
+    cause_of_death: Option<String>,
+}
+
+/// Body of a recap's cleanup event
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct RecapExpired {
+    shard: String,
+    entity_id: String,
+    timestamp_ms: u64,
+}
+
+/// Receives destruction events on `event.decs.components.{shard}.{entity}.hull.destroyed`
+pub(crate) fn handle_entity_destroyed(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let (shard, entity_id) = match subject.as_slice() {
+        ["event", "decs", "components", shard, entity_id, "hull", "destroyed"] => {
+            (*shard, *entity_id)
+        }
+        _ => return Err(format!("Unexpected hull destroyed subject: {}", msg.subject).into()),
+    };
+    let destroyed: EntityDestroyed = serde_json::from_slice(&msg.body)?;
+
+    let last_position = match cached_positions(ctx, shard)?.get(entity_id) {
+        Some(position) => *position,
+        None => match get_component(ctx, shard, entity_id, super::POSITION)? {
+            Some(s) => serde_json::from_str(&s)?,
+            None => Position::default(),
+        },
+    };
+    let final_hull_hp = match get_component(ctx, shard, entity_id, HULL_INTEGRITY)? {
+        Some(s) => serde_json::from_str::<HullIntegrity>(&s)?.current,
+        None => 0.0,
+    };
+    let mut active_components = Vec::new();
+    for component in RECAP_COMPONENTS {
+        if get_component(ctx, shard, entity_id, component)?.is_some() {
+            active_components.push(component.to_string());
+        }
+    }
+
+    let recap = build_recap(
+        entity_id,
+        destroyed,
+        last_position,
+        final_hull_hp,
+        active_components,
+        trader::clock::latest_time_ms(shard),
+    );
+    ctx.kv().set(
+        &recap_key(shard, entity_id),
+        &serde_json::to_string(&recap)?,
+        None,
+    )?;
+    for (subject, payload) in plan_recap(shard, &recap)? {
+        ctx.msg()
+            .publish(&subject, None, &serde_json::to_vec(&payload)?)?;
+    }
+    Ok(vec![])
+}
+
+/// Receives cleanup events on `event.decs.system.death.recap_expired` and removes the recap they
+/// were scheduled for. A newer recap of the same entity is left alone
+pub(crate) fn handle_recap_expired(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let expired: RecapExpired = serde_json::from_slice(&msg.body)?;
+    let key = recap_key(&expired.shard, &expired.entity_id);
+    if let Some(s) = ctx.kv().get(&key)? {
+        let recap: DeathRecap = serde_json::from_str(&s)?;
+        if recap.timestamp_ms <= expired.timestamp_ms {
+            ctx.kv().del_key(&key)?;
+        }
+    }
+    Ok(vec![])
+}
+
+fn recap_key(shard: &str, entity_id: &str) -> String {
+    format!("decs:death_recaps:{}:{}", shard, entity_id)
+}
+
+fn build_recap(
+    entity_id: &str,
+    destroyed: EntityDestroyed,
+    last_position: Position,
+    final_hull_hp: f64,
+    active_components: Vec<String>,
+    timestamp_ms: u64,
+) -> DeathRecap {
+    DeathRecap {
+        entity_id: entity_id.to_string(),
+        last_position,
+        killer_entity: destroyed.killer_entity,
+        cause_of_death: destroyed
+            .cause_of_death
+            .unwrap_or_else(|| UNKNOWN_CAUSE.to_string()),
+        final_hull_hp,
+        active_components,
+        timestamp_ms,
+    }
+}
+
+/// The recap event, carrying the recap and its shard, and the scheduling of its cleanup
+fn plan_recap(shard: &str, recap: &DeathRecap) -> Result<Vec<(String, Value)>> {
+    let mut event = serde_json::to_value(recap)?;
+    event["shard"] = serde_json::json!(shard);
+    let cleanup = ScheduledEvent {
+        trigger_time_ms: recap.timestamp_ms + RECAP_TTL_MS,
+        event_topic: RECAP_EXPIRED_EVENT.to_string(),
+        payload: serde_json::to_value(RecapExpired {
+            shard: shard.to_string(),
+            entity_id: recap.entity_id.to_string(),
+            timestamp_ms: recap.timestamp_ms,
+        })?,
+    };
+    Ok(vec![
+        (RECAP_EVENT.to_string(), event),
+        (
+            format!("call.decs.{}.scheduler.schedule", shard),
+            serde_json::json!({ "params": cleanup }),
+        ),
+    ])
+}
+
+fn get_component(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> Result<Option<String>> {
+    ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, component
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::build_recap;
+    use super::plan_recap;
+    use super::EntityDestroyed;
+    use super::Position;
+
+    fn destroyed(body: &str) -> EntityDestroyed {
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn test_recap_construction() {
+        let recap = build_recap(
+            "player1",
+            destroyed(r#"{"killer_entity": "pirate7", "cause_of_death": "turret_fire"}"#),
+            Position::new(10.0, -4.0, 2.0),
+            -12.5,
+            vec!["turret".to_string(), "energy_core".to_string()],
+            90_000,
+        );
+        assert_eq!(recap.entity_id, "player1");
+        assert_eq!(recap.killer_entity, Some("pirate7".to_string()));
+        assert_eq!(recap.cause_of_death, "turret_fire");
+        assert_eq!(recap.last_position, Position::new(10.0, -4.0, 2.0));
+        assert_eq!(recap.final_hull_hp, -12.5);
+        assert_eq!(recap.active_components, vec!["turret", "energy_core"]);
+        assert_eq!(recap.timestamp_ms, 90_000);
+
+        // Killed by nothing in particular
+        let recap = build_recap(
+            "player1",
+            destroyed("{}"),
+            Position::default(),
+            0.0,
+            vec![],
+            0,
+        );
+        assert_eq!(recap.killer_entity, None);
+        assert_eq!(recap.cause_of_death, "unknown");
+    }
+
+    #[test]
+    fn test_recap_publication() {
+        let recap = build_recap(
+            "player1",
+            destroyed(r#"{"killer_entity": "pirate7", "cause_of_death": "collision"}"#),
+            Position::new(1.0, 2.0, 3.0),
+            0.0,
+            vec!["velocity".to_string()],
+            90_000,
+        );
+        let publications = plan_recap("the_void", &recap).unwrap();
+        assert_eq!(
+            publications[0],
+            (
+                "event.decs.system.death.recap".to_string(),
+                serde_json::json!({
+                    "shard": "the_void",
+                    "entity_id": "player1",
+                    "last_position": { "x": 1.0, "y": 2.0, "z": 3.0 },
+                    "killer_entity": "pirate7",
+                    "cause_of_death": "collision",
+                    "final_hull_hp": 0.0,
+                    "active_components": ["velocity"],
+                    "timestamp_ms": 90_000,
+                })
+            )
+        );
+        // Cleaned up an hour of game time later
+        assert_eq!(
+            publications[1],
+            (
+                "call.decs.the_void.scheduler.schedule".to_string(),
+                serde_json::json!({ "params": {
+                    "trigger_time_ms": 3_690_000,
+                    "event_topic": "event.decs.system.death.recap_expired",
+                    "payload": {
+                        "shard": "the_void",
+                        "entity_id": "player1",
+                        "timestamp_ms": 90_000,
+                    },
+                }})
+            )
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! # Escape Protocol
+//!
+//! Entities with an `escape_protocol` component retreat on their own once their hull is badly
+//! damaged. After every damage event on `event.decs.components.{shard}.{entity}.hull.damage`, the
+//! entity's `hull_integrity` is checked against the protocol's `hull_threshold_pct`. Below it, the
+//! entity is given a `navigation_waypoint` `ESCAPE_DISTANCE` km away, directly opposite the nearest
+//! hostile, and the retreat is announced on `event.decs.system.combat.retreat`. The protocol only
+//! fires once until its `activated` flag is reset.
+use crate::radar::cached_positions;
+use crate::targeting::faction_for;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const ESCAPE_PROTOCOL: &str = "escape_protocol";
+const HULL_INTEGRITY: &str = "hull_integrity";
+const NAVIGATION_WAYPOINT: &str = "navigation_waypoint";
+const RETREAT_EVENT: &str = "event.decs.system.combat.retreat";
+const ESCAPE_DISTANCE: f64 = 1000.0;
+const ESCAPE_ARRIVAL_RADIUS: f64 = 10.0;
+
+/// Receives damage events on `event.decs.components.{shard}.{entity}.hull.damage`
+pub(crate) fn handle_escape_check(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let subject: Vec<&str> = msg.subject.split('.').collect();
+    let (shard, entity_id) = match subject.as_slice() {
+        ["event", "decs", "components", shard, entity_id, "hull", "damage"] => (*shard, *entity_id),
+        _ => return Err(format!("Unexpected hull damage subject: {}", msg.subject).into()),
+    };
+
+    let protocol: EscapeProtocol = match get_component(ctx, shard, entity_id, ESCAPE_PROTOCOL)? {
+        Some(protocol_str) => serde_json::from_str(&protocol_str)?,
+        None => return Ok(vec![]),
+    };
+    let hull: HullIntegrity = match get_component(ctx, shard, entity_id, HULL_INTEGRITY)? {
+        Some(hull_str) => serde_json::from_str(&hull_str)?,
+        None => return Ok(vec![]),
+    };
+    let position: Position = match get_component(ctx, shard, entity_id, super::POSITION)? {
+        Some(position_str) => serde_json::from_str(&position_str)?,
+        None => return Ok(vec![]),
+    };
+    // An entity without a faction has nobody to flee from
+    let faction = match faction_for(ctx, shard, entity_id)? {
+        Some(faction) => faction,
+        None => return Ok(vec![]),
+    };
+
+    let mut hostiles = Vec::new();
+    for (ent_id, pos) in cached_positions(ctx, shard)? {
+        if ent_id == entity_id {
+            continue;
+        }
+        let hostile = faction_for(ctx, shard, &ent_id)?
+            .map(|f| faction.is_hostile_to(&f))
+            .unwrap_or(false);
+        if hostile {
+            hostiles.push(pos);
+        }
+    }
+
+    if let Some((protocol, waypoint)) = plan_escape(&protocol, &hull, &position, &hostiles) {
+        ctx.log(&format!(
+            "Entity {} is retreating with {:.1}% hull integrity left",
+            entity_id,
+            hull.percent()
+        ));
+        publish_set(ctx, shard, entity_id, NAVIGATION_WAYPOINT, &waypoint)?;
+        publish_set(ctx, shard, entity_id, ESCAPE_PROTOCOL, &protocol)?;
+        ctx.msg().publish(
+            RETREAT_EVENT,
+            None,
+            &serde_json::to_vec(&serde_json::json!({
+                "shard": shard,
+                "entity": entity_id,
+                "hull_pct": hull.percent(),
+                "waypoint": waypoint.position,
+            }))?,
+        )?;
+    }
+
+    Ok(vec![])
+}
+
+fn get_component(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+) -> Result<Option<String>> {
+    ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, component
+    ))
+}
+
+fn publish_set<T: serde::Serialize>(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+    value: &T,
+) -> Result<()> {
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard, entity_id, component
+        ),
+        None,
+        &serde_json::to_vec(&serde_json::json!({ "params": value }))?,
+    )
+}
+
+/// Decides whether the entity retreats, returning the activated protocol and the waypoint to
+/// flee to. Nothing happens while the hull holds, once the protocol has already fired, or when
+/// there is no hostile to flee from
+fn plan_escape(
+    protocol: &EscapeProtocol,
+    hull: &HullIntegrity,
+    position: &Position,
+    hostiles: &[Position],
+) -> Option<(EscapeProtocol, NavigationWaypoint)> {
+    if protocol.activated || hull.percent() >= protocol.hull_threshold_pct {
+        return None;
+    }
+    let nearest = hostiles.iter().min_by(|a, b| {
+        position
+            .distance_to_3d(a)
+            .partial_cmp(&position.distance_to_3d(b))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    })?;
+    let distance = position.distance_to_3d(nearest);
+    // A hostile right on top of the entity gives no direction to flee in
+    if distance <= 0.0 {
+        return None;
+    }
+    let escape_vector = Position::new(
+        (position.x - nearest.x) / distance,
+        (position.y - nearest.y) / distance,
+        (position.z - nearest.z) / distance,
+    );
+    let waypoint = NavigationWaypoint {
+        position: Position::new(
+            position.x + escape_vector.x * ESCAPE_DISTANCE,
+            position.y + escape_vector.y * ESCAPE_DISTANCE,
+            position.z + escape_vector.z * ESCAPE_DISTANCE,
+        ),
+        arrival_radius: ESCAPE_ARRIVAL_RADIUS,
+    };
+    Some((
+        EscapeProtocol {
+            escape_vector: Some(escape_vector),
+            activated: true,
+            ..protocol.clone()
+        },
+        waypoint,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::plan_escape;
+    use super::EscapeProtocol;
+    use super::HullIntegrity;
+    use super::Position;
+    use stacktrader_types::assert_approx_eq;
+
+    fn protocol() -> EscapeProtocol {
+        EscapeProtocol {
+            hull_threshold_pct: 25.0,
+            escape_vector: None,
+            activated: false,
+        }
+    }
+
+    fn hull(current: f64) -> HullIntegrity {
+        HullIntegrity {
+            current,
+            max: 200.0,
+        }
+    }
+
+    #[test]
+    fn test_retreat_below_threshold() {
+        let position = Position::new(10.0, 0.0, 0.0);
+        let hostiles = vec![
+            Position::new(10.0, 50.0, 0.0),
+            // The nearest hostile, straight behind on the x axis
+            Position::new(0.0, 0.0, 0.0),
+        ];
+        // 30% integrity holds, 20% doesn't
+        assert!(plan_escape(&protocol(), &hull(60.0), &position, &hostiles).is_none());
+        let (activated, waypoint) =
+            plan_escape(&protocol(), &hull(40.0), &position, &hostiles).unwrap();
+        assert!(activated.activated);
+        assert_approx_eq!(
+            activated.escape_vector.unwrap(),
+            Position::new(1.0, 0.0, 0.0)
+        );
+        assert_approx_eq!(waypoint.position, Position::new(1010.0, 0.0, 0.0));
+
+        // Nobody to flee from
+        assert!(plan_escape(&protocol(), &hull(40.0), &position, &[]).is_none());
+    }
+
+    #[test]
+    fn test_already_activated_is_idempotent() {
+        let position = Position::new(10.0, 0.0, 0.0);
+        let hostiles = vec![Position::new(0.0, 0.0, 0.0)];
+        let (activated, _) = plan_escape(&protocol(), &hull(40.0), &position, &hostiles).unwrap();
+        // Further damage doesn't send the entity off in a new direction
+        assert!(plan_escape(&activated, &hull(10.0), &position, &hostiles).is_none());
+        assert!(plan_escape(
+            &activated,
+            &hull(0.0),
+            &Position::new(0.0, 0.0, 5.0),
+            &hostiles
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_hull_percent() {
+        assert_eq!(hull(50.0).percent(), 25.0);
+        assert_eq!(hull(250.0).percent(), 100.0);
+        assert_eq!(
+            HullIntegrity {
+                current: 0.0,
+                max: 0.0
+            }
+            .percent(),
+            0.0
+        );
+    }
+}
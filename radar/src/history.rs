@@ -0,0 +1,81 @@
+//! # Radar History
+//!
+//! Entities with a `radar_history_log` component keep their last `max_entries` radar scans for
+//! replay. After each radar frame, the contacts the radar sees (the old contacts with the frame's
+//! deltas applied) are recorded in the log as a snapshot stamped with the frame's game time.
+use crate::radar::RadarContactDelta;
+use stacktrader_types as trader;
+use std::collections::HashMap;
+use trader::components::*;
+#[cfg(test)]
+use trader::units::Distance;
+
+/// The contacts after applying the deltas to the old contacts, ordered by entity
+pub(crate) fn current_contacts<'a>(
+    old_contacts: impl IntoIterator<Item = (&'a String, &'a RadarContact)>,
+    deltas: &[RadarContactDelta],
+) -> Vec<RadarContact> {
+    let mut contacts: HashMap<String, RadarContact> = old_contacts
+        .into_iter()
+        .map(|(rid, contact)| (rid.to_string(), contact.clone()))
+        .collect();
+    let mut added = Vec::new();
+    for delta in deltas {
+        match delta {
+            RadarContactDelta::Add(contact) => added.push(contact.clone()),
+            RadarContactDelta::Remove(rid) => {
+                contacts.remove(rid);
+            }
+            RadarContactDelta::Change(rid, contact) => {
+                contacts.insert(rid.to_string(), contact.clone());
+            }
+        }
+    }
+    let mut contacts: Vec<RadarContact> = contacts.into_values().chain(added).collect();
+    contacts.sort_by(|a, b| a.entity_id.cmp(&b.entity_id));
+    contacts
+}
+
+#[cfg(test)]
+mod test {
+    use super::current_contacts;
+    use super::Distance;
+    use super::HashMap;
+    use super::RadarContact;
+    use super::RadarContactDelta;
+
+    fn contact(entity_id: &str, distance: u32) -> RadarContact {
+        RadarContact {
+            entity_id: entity_id.to_string(),
+            distance: Distance::from(distance),
+            ..RadarContact::default()
+        }
+    }
+
+    #[test]
+    fn test_current_contacts_apply_deltas() {
+        let mut old_contacts = HashMap::new();
+        old_contacts.insert(
+            "decs.components.the_void.player1.radar_contacts.c1".to_string(),
+            contact("ship1", 10),
+        );
+        old_contacts.insert(
+            "decs.components.the_void.player1.radar_contacts.c2".to_string(),
+            contact("ship2", 20),
+        );
+        let deltas = vec![
+            RadarContactDelta::Change(
+                "decs.components.the_void.player1.radar_contacts.c1".to_string(),
+                contact("ship1", 15),
+            ),
+            RadarContactDelta::Remove(
+                "decs.components.the_void.player1.radar_contacts.c2".to_string(),
+            ),
+            RadarContactDelta::Add(contact("asteroid1", 30)),
+        ];
+        assert_eq!(
+            current_contacts(&old_contacts, &deltas),
+            vec![contact("asteroid1", 30), contact("ship1", 15)]
+        );
+    }
+}
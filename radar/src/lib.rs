@@ -29,13 +29,20 @@ extern crate waxosuit_guest as guest;
 
 use decs::systemmgr::*;
 use guest::prelude::*;
+use stacktrader_types::{clock, metrics};
 
 call_handler!(handle_call);
 
 // const NO_MESSAGE: &str = "(no message)";
 const FRAMERATE: u32 = 1;
 const SYSTEM_NAME: &str = "radar";
+const SURVEY_SYSTEM_NAME: &str = "survey";
+const TURRET_SYSTEM_NAME: &str = "turret";
+const TARGETING_SYSTEM_NAME: &str = "targeting";
 const RADAR_RECEIVER: &str = "radar_receiver";
+const SURVEY_SCANNER: &str = "survey_scanner";
+const TURRET: &str = "turret";
+const TARGETING_COMPUTER: &str = "targeting_computer";
 const POSITION: &str = "position";
 const REGISTRY_SUBJECT: &str = "decs.system.registry";
 
@@ -50,7 +57,19 @@ pub fn handle_call(ctx: &CapabilitiesContext, operation: &str, msg: &[u8]) -> Ca
 /// Routes message to corresponding function depending on the subject of the message
 /// `decs.system.registry` => handle_ping function for registry pings
 /// `event.decs.components.{shard}.{entity}.position.change` => handle_entity_position_change for caching positions
-/// `decs.frames.{shard}.{system}` => handle_frame for updating an entities radar_contacts
+/// `event.decs.system.cross_shard_positions.{shard}` => handle_remote_positions for caching the positions of other shards
+/// `event.decs.{shard}.{entity}.buff.applied` => handle_buff_applied for buffing an entities radar range
+/// `event.decs.components.{shard}.{entity}.hull.damage` => handle_escape_check for retreating badly damaged entities
+/// `event.decs.components.{shard}.{entity}.hull.destroyed` => handle_entity_destroyed for recapping a destroyed entity
+/// `event.decs.system.death.recap_expired` => handle_recap_expired for removing an expired death recap
+/// `event.decs.components.{shard}.{entity}.ping.send` => handle_ping_send for pinging another entity
+/// `event.decs.components.{shard}.{entity}.ping.request` => handle_ping_response for echoing a ping
+/// `event.decs.components.{shard}.{entity}.ping.response` => handle_ping_receive for recording a ping's round trip
+/// `decs.frames.{shard}.radar` => handle_frame for updating an entities radar_contacts
+/// `decs.frames.{shard}.radar.batch` => handle_frame_batch for updating the radar_contacts of every entity in a batch of frames
+/// `decs.frames.{shard}.survey` => survey handle_frame for progressing an entities survey scan
+/// `decs.frames.{shard}.turret` => turret handle_frame for aiming an entities turret at its target
+/// `decs.frames.{shard}.targeting` => targeting handle_frame for selecting the closest hostile as an entities target
 fn handle_message(
     ctx: &CapabilitiesContext,
     msg: impl Into<messaging::DeliverMessage>,
@@ -67,10 +86,34 @@ fn handle_message(
 
         if subject == REGISTRY_SUBJECT {
             handle_ping(ctx, msg.unwrap())
+        } else if subject.starts_with("event.decs.system.cross_shard_positions.") {
+            cross_shard::handle_remote_positions(ctx, msg.unwrap())
+        } else if subject.starts_with("event.decs.") && subject.ends_with(".buff.applied") {
+            buffs::handle_buff_applied(ctx, msg.unwrap())
+        } else if subject.starts_with("event.") && subject.ends_with(".hull.damage") {
+            escape::handle_escape_check(ctx, msg.unwrap())
+        } else if subject.starts_with("event.") && subject.ends_with(".hull.destroyed") {
+            death::handle_entity_destroyed(ctx, msg.unwrap())
+        } else if subject == death::RECAP_EXPIRED_EVENT {
+            death::handle_recap_expired(ctx, msg.unwrap())
+        } else if subject.starts_with("event.") && subject.ends_with(".ping.send") {
+            ping::handle_ping_send(ctx, msg.unwrap())
+        } else if subject.starts_with("event.") && subject.ends_with(".ping.request") {
+            ping::handle_ping_response(ctx, msg.unwrap())
+        } else if subject.starts_with("event.") && subject.ends_with(".ping.response") {
+            ping::handle_ping_receive(ctx, msg.unwrap())
         } else if subject.starts_with("event.") && subject.ends_with(".change") {
             radar::handle_entity_position_change(ctx, msg.unwrap())
+        } else if subject.starts_with("decs.frames.") && subject.ends_with(".radar.batch") {
+            timed_frame(ctx, msg.unwrap(), radar::handle_frame_batch)
         } else if subject.starts_with("decs.frames.") && subject.ends_with(".radar") {
-            radar::handle_frame(ctx, msg.unwrap())
+            timed_frame(ctx, msg.unwrap(), radar::handle_frame)
+        } else if subject.starts_with("decs.frames.") && subject.ends_with(".survey") {
+            timed_frame(ctx, msg.unwrap(), survey::handle_frame)
+        } else if subject.starts_with("decs.frames.") && subject.ends_with(".turret") {
+            timed_frame(ctx, msg.unwrap(), turret::handle_frame)
+        } else if subject.starts_with("decs.frames.") && subject.ends_with(".targeting") {
+            timed_frame(ctx, msg.unwrap(), targeting::handle_frame)
         } else {
             Err(format!("Unexpected message received on subject: {}", subject).into())
         }
@@ -79,25 +122,102 @@ fn handle_message(
     }
 }
 
-/// Receives messages on the subject `system.registry` and replies with radar system metadata
+/// Runs a frame or frame batch handler, publishing how long each frame took to process and
+/// storing a summary of the system's recent frame times every 100 frames, see
+/// `metrics::timed_frame`
+fn timed_frame(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+    handler: fn(&CapabilitiesContext, messaging::BrokerMessage) -> CallResult,
+) -> CallResult {
+    if let Ok(frame) = serde_json::from_slice::<EntityFrame>(&msg.body) {
+        clock::observe(&frame.shard, clock::frame_time_ms(&frame, FRAMERATE));
+    }
+    let body = msg.body.clone();
+    let subject = msg.subject.clone();
+    metrics::timed_frame(
+        &metrics::SystemClock,
+        metrics::frame_system(&subject),
+        &body,
+        || handler(ctx, msg),
+        |subject, payload| ctx.msg().publish(subject, None, payload),
+        |key, value| ctx.kv().set(key, value, None),
+        |msg| ctx.log(msg),
+    )
+}
+
+/// Receives messages on the subject `system.registry` and replies with the metadata of
+/// the radar, survey, turret and targeting systems
 fn handle_ping(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
-    let payload = System {
-        name: SYSTEM_NAME.to_string(),
-        framerate: FRAMERATE,
-        components: vec![RADAR_RECEIVER.to_string(), POSITION.to_string()],
-    };
+    let payloads = vec![
+        System {
+            name: SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![RADAR_RECEIVER.to_string(), POSITION.to_string()],
+        },
+        System {
+            name: SURVEY_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![SURVEY_SCANNER.to_string(), POSITION.to_string()],
+        },
+        System {
+            name: TURRET_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![TURRET.to_string(), POSITION.to_string()],
+        },
+        System {
+            name: TARGETING_SYSTEM_NAME.to_string(),
+            framerate: FRAMERATE,
+            components: vec![TARGETING_COMPUTER.to_string(), POSITION.to_string()],
+        },
+    ];
     let reply_to = if msg.reply_to.is_empty() {
         format!("{}.replies", REGISTRY_SUBJECT)
     } else {
         msg.reply_to
     };
-    if let Err(e) = ctx
-        .msg()
-        .publish(&reply_to, None, &serde_json::to_vec(&payload)?)
-    {
-        return Err(format!("Error publishing message: {}", e).into());
-    };
+    for payload in payloads {
+        if let Err(e) = ctx
+            .msg()
+            .publish(&reply_to, None, &serde_json::to_vec(&payload)?)
+        {
+            return Err(format!("Error publishing message: {}", e).into());
+        };
+    }
     Ok(vec![])
 }
 
+/// The number of contact changes `radar_updates` finds for the entity, with no previous
+/// contacts and no obstacles. Only here for the benchmarks in `benches/`
+#[doc(hidden)]
+pub fn bench_radar_updates(
+    entity_id: &str,
+    receiver: &stacktrader_types::components::RadarReceiver,
+    all_positions: &std::collections::HashMap<String, stacktrader_types::components::Position>,
+) -> usize {
+    radar::radar_updates(
+        entity_id,
+        "the_shard",
+        &all_positions[entity_id],
+        receiver,
+        &std::collections::HashMap::new(),
+        all_positions,
+        &[],
+        None,
+    )
+    .len()
+}
+
+mod buffs;
+mod cross_shard;
+mod death;
+mod energy;
+mod escape;
+mod history;
+mod line_of_sight;
+mod ping;
 mod radar;
+mod signal_interference;
+mod survey;
+mod targeting;
+mod turret;
@@ -0,0 +1,156 @@
+//! # Line of Sight
+//!
+//! Entities with a `terrain_obstacle` component block radar. A contact is only seen when the
+//! straight line from the scanner to it doesn't touch the bounding box of any obstacle. The
+//! obstacles are loaded from the KV store on the first frame and reloaded every
+//! `RELOAD_INTERVAL` frames after that, since terrain rarely changes.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use trader::components::*;
+
+const TERRAIN_OBSTACLE: &str = "terrain_obstacle";
+const RELOAD_INTERVAL: u64 = 100;
+
+lazy_static! {
+    static ref OBSTACLES: RwLock<Vec<TerrainObstacle>> = RwLock::new(Vec::new());
+}
+
+static SINCE_RELOAD: AtomicU64 = AtomicU64::new(0);
+
+/// Retrieve the shard's terrain obstacles, loading them from the KV store on the first call and
+/// every `RELOAD_INTERVAL` calls after that
+pub(crate) fn obstacles(ctx: &CapabilitiesContext, shard: &str) -> Result<Vec<TerrainObstacle>> {
+    let since_reload = SINCE_RELOAD.fetch_add(1, Ordering::Relaxed);
+    if since_reload == 0 || since_reload >= RELOAD_INTERVAL {
+        SINCE_RELOAD.store(1, Ordering::Relaxed);
+        let mut obstacles = Vec::new();
+        for entity in ctx
+            .kv()
+            .set_members(&format!("decs:{}:{}:entities", shard, TERRAIN_OBSTACLE))?
+        {
+            if let Some(obstacle_str) = ctx.kv().get(&format!(
+                "decs:components:{}:{}:{}",
+                shard, entity, TERRAIN_OBSTACLE
+            ))? {
+                obstacles.push(serde_json::from_str(&obstacle_str)?);
+            }
+        }
+        *OBSTACLES.write().unwrap() = obstacles;
+    }
+    Ok(OBSTACLES.read().unwrap().clone())
+}
+
+/// Whether the segment from the scanner to the target passes clear of every obstacle. A segment
+/// that only grazes the edge or face of a bounding box counts as blocked
+pub(crate) fn has_line_of_sight(
+    scanner: &Position,
+    target: &Position,
+    obstacles: &[TerrainObstacle],
+) -> bool {
+    !obstacles
+        .iter()
+        .any(|obstacle| intersects_segment(&obstacle.bounding_box, scanner, target))
+}
+
+/// Clips the segment against the box one axis at a time (the slab method). Whatever is left of
+/// the segment after all three axes lies inside the box
+fn intersects_segment(bounding_box: &Aabb, from: &Position, to: &Position) -> bool {
+    let axes = [
+        (from.x, to.x, bounding_box.min.x, bounding_box.max.x),
+        (from.y, to.y, bounding_box.min.y, bounding_box.max.y),
+        (from.z, to.z, bounding_box.min.z, bounding_box.max.z),
+    ];
+    // The part of the segment, as fractions of its length, that is inside the box
+    let (mut enter, mut exit) = (0.0_f64, 1.0_f64);
+    for (start, end, min, max) in axes.iter() {
+        let delta = end - start;
+        if delta == 0.0 {
+            // Parallel to this axis' slab, so it is either always or never within it
+            if start < min || start > max {
+                return false;
+            }
+            continue;
+        }
+        let (t1, t2) = ((min - start) / delta, (max - start) / delta);
+        enter = enter.max(t1.min(t2));
+        exit = exit.min(t1.max(t2));
+        if enter > exit {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::has_line_of_sight;
+    use super::Aabb;
+    use super::Position;
+    use super::TerrainObstacle;
+
+    /// A 2x2x2 box centered on (5, 0, 0)
+    fn obstacle() -> TerrainObstacle {
+        TerrainObstacle {
+            bounding_box: Aabb {
+                min: Position::new(4.0, -1.0, -1.0),
+                max: Position::new(6.0, 1.0, 1.0),
+            },
+        }
+    }
+
+    fn origin() -> Position {
+        Position::new(0.0, 0.0, 0.0)
+    }
+
+    #[test]
+    fn test_direct_line_of_sight() {
+        let target = Position::new(10.0, 0.0, 0.0);
+        assert!(has_line_of_sight(&origin(), &target, &[]));
+        // The obstacle is off to the side of the line
+        let beside = Position::new(0.0, 10.0, 0.0);
+        assert!(has_line_of_sight(&origin(), &beside, &[obstacle()]));
+        // The target is in front of the obstacle
+        let in_front = Position::new(3.0, 0.0, 0.0);
+        assert!(has_line_of_sight(&origin(), &in_front, &[obstacle()]));
+    }
+
+    #[test]
+    fn test_blocked_by_obstacle() {
+        let behind = Position::new(10.0, 0.0, 0.0);
+        assert!(!has_line_of_sight(&origin(), &behind, &[obstacle()]));
+        assert!(!has_line_of_sight(&behind, &origin(), &[obstacle()]));
+        // Diagonally through a corner region
+        let diagonal = Position::new(10.0, 1.5, 1.5);
+        assert!(!has_line_of_sight(&origin(), &diagonal, &[obstacle()]));
+        // Only one of several obstacles needs to be in the way
+        let elsewhere = TerrainObstacle {
+            bounding_box: Aabb {
+                min: Position::new(100.0, 100.0, 100.0),
+                max: Position::new(101.0, 101.0, 101.0),
+            },
+        };
+        assert!(!has_line_of_sight(
+            &origin(),
+            &behind,
+            &[elsewhere, obstacle()]
+        ));
+    }
+
+    #[test]
+    fn test_edge_grazing() {
+        // Skimming along the top face of the box touches it
+        let from = Position::new(0.0, 1.0, 0.0);
+        let to = Position::new(10.0, 1.0, 0.0);
+        assert!(!has_line_of_sight(&from, &to, &[obstacle()]));
+        // Touching only the box's edge
+        let from = Position::new(3.0, 0.0, 0.0);
+        let to = Position::new(5.0, 2.0, 0.0);
+        assert!(!has_line_of_sight(&from, &to, &[obstacle()]));
+        // Passing just above the top face doesn't
+        let from = Position::new(0.0, 1.001, 0.0);
+        let to = Position::new(10.0, 1.001, 0.0);
+        assert!(has_line_of_sight(&from, &to, &[obstacle()]));
+    }
+}
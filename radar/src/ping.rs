@@ -0,0 +1,236 @@
+//! # Pings
+//!
+//! Measures the round-trip latency between two entities of a shard. A message on
+//! `event.decs.components.{shard}.{entity}.ping.send` with `{ "target_entity": ... }` sends a ping
+//! from the entity to the target on `event.decs.components.{shard}.{target}.ping.request`. The
+//! target echoes it straight back on `event.decs.components.{shard}.{entity}.ping.response`, and
+//! the round trip is recorded in the sender's `latency_stats` component.
+//!
+//! Actors have no wall clock, so the ping is timed in game time, that of the latest radar frame
+//! of the shard. Only the echo of the entity's latest ping counts; a stale or unknown echo is
+//! dropped.
+use guest::prelude::*;
+use stacktrader_types as trader;
+use trader::components::*;
+
+const LATENCY_STATS: &str = "latency_stats";
+
+/// Body of a ping send message
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct PingSend {
+    target_entity: String,
+}
+
+/// Body of a ping request and of its echo, the response
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct PingMessage {
+    source_entity: String,
+    target_entity: String,
+    sent_at_ms: u64,
+    nonce: u64,
+}
+
+/// Receives ping sends on `event.decs.components.{shard}.{entity}.ping.send`
+pub(crate) fn handle_ping_send(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let (shard, entity_id) = subject_entity(&msg.subject, "send")?;
+    let send: PingSend = serde_json::from_slice(&msg.body)?;
+    let nonce = ctx
+        .kv()
+        .atomic_add(&format!("decs:{}:ping:nonce", shard), 1)? as u64;
+    let request = PingRequest {
+        target_entity: send.target_entity,
+        sent_at_ms: trader::clock::latest_time_ms(shard),
+        nonce,
+    };
+    // Kept in the KV store rather than as a component, so it is in place before the echo
+    ctx.kv().set(
+        &pending_key(shard, entity_id),
+        &serde_json::to_string(&request)?,
+        None,
+    )?;
+    let (subject, message) = plan_ping(shard, entity_id, &request);
+    ctx.msg()
+        .publish(&subject, None, &serde_json::to_vec(&message)?)?;
+    Ok(vec![])
+}
+
+/// Receives pings on `event.decs.components.{shard}.{entity}.ping.request` and echoes them back
+pub(crate) fn handle_ping_response(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let (shard, _entity_id) = subject_entity(&msg.subject, "request")?;
+    let message: PingMessage = serde_json::from_slice(&msg.body)?;
+    let (subject, echo) = plan_echo(shard, message);
+    ctx.msg()
+        .publish(&subject, None, &serde_json::to_vec(&echo)?)?;
+    Ok(vec![])
+}
+
+/// Receives echoes on `event.decs.components.{shard}.{entity}.ping.response` and records the
+/// round trip of the entity's latest ping
+pub(crate) fn handle_ping_receive(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let (shard, entity_id) = subject_entity(&msg.subject, "response")?;
+    let echo: PingMessage = serde_json::from_slice(&msg.body)?;
+    let pending: PingRequest = match ctx.kv().get(&pending_key(shard, entity_id))? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => return Ok(vec![]),
+    };
+    let stats: Option<LatencyStats> = match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, LATENCY_STATS
+    ))? {
+        Some(s) => serde_json::from_str(&s).ok(),
+        None => None,
+    };
+    let now_ms = trader::clock::latest_time_ms(shard);
+    if let Some(stats) = plan_receive(&pending, &echo, stats.as_ref(), now_ms) {
+        ctx.kv().del_key(&pending_key(shard, entity_id))?;
+        ctx.msg().publish(
+            &format!(
+                "call.decs.components.{}.{}.{}.set",
+                shard, entity_id, LATENCY_STATS
+            ),
+            None,
+            &serde_json::to_vec(&serde_json::json!({ "params": stats }))?,
+        )?;
+    }
+    Ok(vec![])
+}
+
+/// The shard and entity of a `event.decs.components.{shard}.{entity}.ping.{kind}` subject
+fn subject_entity<'a>(subject: &'a str, kind: &str) -> Result<(&'a str, &'a str)> {
+    match subject.split('.').collect::<Vec<_>>().as_slice() {
+        ["event", "decs", "components", shard, entity_id, "ping", k] if *k == kind => {
+            Ok((*shard, *entity_id))
+        }
+        _ => Err(format!("Unexpected ping subject: {}", subject).into()),
+    }
+}
+
+fn pending_key(shard: &str, entity_id: &str) -> String {
+    format!("decs:{}:ping:pending:{}", shard, entity_id)
+}
+
+/// The ping request (subject and body) from the entity to the request's target
+fn plan_ping(shard: &str, entity_id: &str, request: &PingRequest) -> (String, PingMessage) {
+    (
+        format!(
+            "event.decs.components.{}.{}.ping.request",
+            shard, request.target_entity
+        ),
+        PingMessage {
+            source_entity: entity_id.to_string(),
+            target_entity: request.target_entity.to_string(),
+            sent_at_ms: request.sent_at_ms,
+            nonce: request.nonce,
+        },
+    )
+}
+
+/// The echo (subject and body) of a ping, back to its source
+fn plan_echo(shard: &str, message: PingMessage) -> (String, PingMessage) {
+    (
+        format!(
+            "event.decs.components.{}.{}.ping.response",
+            shard, message.source_entity
+        ),
+        message,
+    )
+}
+
+/// The latency stats with the round trip of the echo, none if it isn't the echo of the pending
+/// ping. A clock that went backwards counts as no time passing
+fn plan_receive(
+    pending: &PingRequest,
+    echo: &PingMessage,
+    stats: Option<&LatencyStats>,
+    now_ms: u64,
+) -> Option<LatencyStats> {
+    if echo.nonce != pending.nonce || echo.target_entity != pending.target_entity {
+        return None;
+    }
+    Some(LatencyStats::record(
+        stats,
+        now_ms.saturating_sub(pending.sent_at_ms),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::plan_echo;
+    use super::plan_ping;
+    use super::plan_receive;
+    use super::subject_entity;
+    use super::LatencyStats;
+    use super::PingRequest;
+
+    fn request(sent_at_ms: u64, nonce: u64) -> PingRequest {
+        PingRequest {
+            target_entity: "player2".to_string(),
+            sent_at_ms,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn test_echoed_ping_measures_the_round_trip() {
+        let pending = request(4000, 7);
+        let (subject, ping) = plan_ping("the_void", "player1", &pending);
+        assert_eq!(
+            subject,
+            "event.decs.components.the_void.player2.ping.request"
+        );
+        assert_eq!(
+            subject_entity(&subject, "request").unwrap(),
+            ("the_void", "player2")
+        );
+
+        let (subject, echo) = plan_echo("the_void", ping);
+        assert_eq!(
+            subject,
+            "event.decs.components.the_void.player1.ping.response"
+        );
+        assert_eq!(echo.sent_at_ms, 4000);
+
+        let stats = plan_receive(&pending, &echo, None, 6000).unwrap();
+        assert_eq!(
+            stats,
+            LatencyStats {
+                last_rtt_ms: 2000,
+                avg_rtt_ms: 2000.0,
+            }
+        );
+        // Later round trips move the average by an eighth of the difference
+        let pending = request(10000, 8);
+        let (_, echo) = plan_echo("the_void", plan_ping("the_void", "player1", &pending).1);
+        let stats = plan_receive(&pending, &echo, Some(&stats), 11000).unwrap();
+        assert_eq!(
+            stats,
+            LatencyStats {
+                last_rtt_ms: 1000,
+                avg_rtt_ms: 1875.0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_stale_echo_is_dropped() {
+        let (_, echo) = plan_echo(
+            "the_void",
+            plan_ping("the_void", "player1", &request(0, 1)).1,
+        );
+        assert!(plan_receive(&request(1000, 2), &echo, None, 2000).is_none());
+        assert!(subject_entity(
+            "event.decs.components.the_void.player1.ping.request",
+            "response"
+        )
+        .is_err());
+    }
+}
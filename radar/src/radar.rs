@@ -1,20 +1,161 @@
 extern crate waxosuit_guest as guest;
 
+use crate::buffs::boosted_receiver;
+use crate::cross_shard;
+use crate::history;
+use crate::line_of_sight::{self, has_line_of_sight};
+use crate::signal_interference;
 use decs::gateway::*;
+use decs::systemmgr::EntityFrame;
 use guest::prelude::*;
 use stacktrader_types as trader;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::RwLock;
+use trader::approx::ApproxEq;
+use trader::buffs::{self, buff_multiplier, StatTarget};
 use trader::components::*;
+use trader::rid::{validate_entity_id, validate_shard};
+use trader::units::Distance;
 
 lazy_static! {
-    static ref POSITIONS: RwLock<HashMap<String, Position>> = RwLock::new(HashMap::new());
+    // shard -> entity -> position
+    static ref POSITIONS: RwLock<HashMap<String, HashMap<String, Position>>> =
+        RwLock::new(HashMap::new());
 }
 
+/// The number of position changes that weren't stored because the cache already held them
+static DEDUP_SKIPS: AtomicU64 = AtomicU64::new(0);
+
 const RADAR_CONTACTS: &str = "radar_contacts";
+const RADAR_HISTORY_LOG: &str = "radar_history_log";
+const DEFAULT_MAX_BATCH_SIZE: usize = 100;
+const FRAME_REJECTED_EVENT: &str = "event.decs.system.frame.rejected";
 
 pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
-    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+    let frame: EntityFrame = serde_json::from_slice(&msg.body)?;
+    if rejected(ctx, &frame)? {
+        return Ok(vec![]);
+    }
+    let all_positions = cached_positions(ctx, &frame.shard)?;
+    flush(ctx, frame_publishes(ctx, &frame, &all_positions)?)?;
+    cross_shard::share_positions(
+        ctx,
+        &frame.shard,
+        trader::clock::frame_time_ms(&frame, super::FRAMERATE),
+        &all_positions,
+    )?;
+    Ok(vec![])
+}
+
+/// Whether the frame names a shard or entity whose ID can't be embedded in subjects and keys.
+/// The reason such a frame is ignored is published on `event.decs.system.frame.rejected`
+fn rejected(ctx: &CapabilitiesContext, frame: &EntityFrame) -> Result<bool> {
+    let reason =
+        match validate_shard(&frame.shard).and_then(|_| validate_entity_id(&frame.entity_id)) {
+            Ok(()) => return Ok(false),
+            Err(reason) => reason,
+        };
+    ctx.log(&format!("Rejecting frame: {}", reason));
+    ctx.msg().publish(
+        FRAME_REJECTED_EVENT,
+        None,
+        &serde_json::to_vec(&serde_json::json!({
+            "system": super::SYSTEM_NAME,
+            "shard": frame.shard,
+            "entity_id": frame.entity_id,
+            "reason": reason
+        }))?,
+    )?;
+    Ok(true)
+}
+
+/// Receives every pending frame for a shard in one message on `decs.frames.{shard}.radar.batch`.
+/// Only the latest frame of each entity is processed. All entities share a single snapshot of
+/// the cached positions, and their publishes are collected and flushed at the end of each chunk
+/// of at most `max_batch_size` entities
+pub(crate) fn handle_frame_batch(
+    ctx: &CapabilitiesContext,
+    msg: messaging::BrokerMessage,
+) -> CallResult {
+    let frames_in: Vec<EntityFrame> = serde_json::from_slice(&msg.body)?;
+    let mut frames = Vec::new();
+    for frame in latest_frames_by_entity(frames_in) {
+        if !rejected(ctx, &frame)? {
+            frames.push(frame);
+        }
+    }
+    let shard = match frames.first() {
+        Some(frame) => frame.shard.to_string(),
+        None => return Ok(vec![]),
+    };
+
+    let max_batch_size = max_batch_size(ctx, &shard)?;
+    let all_positions = cached_positions(ctx, &shard)?;
+    for chunk in frames.chunks(max_batch_size) {
+        let mut publishes = Vec::new();
+        for frame in chunk {
+            publishes.extend(frame_publishes(ctx, frame, &all_positions)?);
+        }
+        flush(ctx, publishes)?;
+    }
+    let now_ms = frames
+        .iter()
+        .map(|frame| trader::clock::frame_time_ms(frame, super::FRAMERATE))
+        .max()
+        .unwrap_or(0);
+    cross_shard::share_positions(ctx, &shard, now_ms, &all_positions)?;
+
+    Ok(vec![])
+}
+
+/// The batch size limit for the shard, stored in the KV store under
+/// `decs:{shard}:radar:max_batch_size`
+fn max_batch_size(ctx: &CapabilitiesContext, shard: &str) -> Result<usize> {
+    let configured = ctx
+        .kv()
+        .get(&format!("decs:{}:radar:max_batch_size", shard))?
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|size| *size > 0);
+    Ok(configured.unwrap_or(DEFAULT_MAX_BATCH_SIZE))
+}
+
+/// Keeps only the frame with the highest sequence number for each entity, in the order the
+/// entities first appear
+fn latest_frames_by_entity(frames: Vec<EntityFrame>) -> Vec<EntityFrame> {
+    let mut latest: Vec<EntityFrame> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for frame in frames {
+        match index.get(&frame.entity_id) {
+            Some(i) => {
+                if frame.seq_no > latest[*i].seq_no {
+                    latest[*i] = frame;
+                }
+            }
+            None => {
+                index.insert(frame.entity_id.to_string(), latest.len());
+                latest.push(frame);
+            }
+        }
+    }
+    latest
+}
+
+fn flush(ctx: &CapabilitiesContext, publishes: Vec<(String, serde_json::Value)>) -> CallResult {
+    for (subject, payload) in publishes {
+        publish_message(ctx, &subject, payload)?;
+    }
+    Ok(vec![])
+}
+
+/// Computes the publishes (subject and payload) that bring an entity's radar contacts up to date
+fn frame_publishes(
+    ctx: &CapabilitiesContext,
+    frame: &EntityFrame,
+    all_positions: &HashMap<String, Position>,
+) -> Result<Vec<(String, serde_json::Value)>> {
+    let now_ms = trader::clock::frame_time_ms(frame, super::FRAMERATE);
+    let mut publishes = buffs::plan_buff_expiry(&frame.shard, &frame.entity_id, now_ms);
 
     let radar_receiver_value = ctx.kv().get(&format!(
         "decs:components:{}:{}:{}",
@@ -33,7 +174,15 @@ pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMess
     let resource_id = format!("decs.components.{}.{}", frame.shard, frame.entity_id);
 
     if let (Some(radar_str), Some(position_str)) = (radar_receiver_value, position_value) {
-        let radar_receiver: RadarReceiver = serde_json::from_str(&radar_str)?;
+        let radar_receiver = boosted_receiver(
+            serde_json::from_str(&radar_str)?,
+            buff_multiplier(
+                &frame.shard,
+                &frame.entity_id,
+                StatTarget::RadarRange,
+                now_ms,
+            ),
+        );
         let position: Position = serde_json::from_str(&position_str)?;
 
         let radar_contacts_key = &format!(
@@ -46,114 +195,159 @@ pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMess
             .list_range(radar_contacts_key, 0, -1)? // Get all items of a list from index 0 to the last item
             .iter()
             .filter_map(|c| {
-                if let Ok(Some(contact_str)) = ctx.kv().get(&c.replace(".", ":")) {
-                    if let Some(contact) = serde_json::from_str(&contact_str).unwrap_or(None) {
-                        Some((c, contact))
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
+                // Contacts are keyed by their rid, an item that isn't one has nothing to look up
+                let rid = trader::rid::ResourceIdentifier::parse_rid(c).ok()?;
+                let contact_str = ctx.kv().get(&rid.to_key()).ok()??;
+                let contact = serde_json::from_str(&contact_str).unwrap_or(None)?;
+                Some((rid, contact))
             })
             .fold(
                 HashMap::<String, RadarContact>::new(),
-                |mut acc, (c, contact)| {
-                    acc.insert(c.to_string(), contact);
+                |mut acc, (rid, contact)| {
+                    acc.insert(rid.to_rid(), contact);
                     acc
                 },
             );
 
-        let updates = {
-            let all_positions = POSITIONS.read().unwrap().clone();
-
-            // If the positions cache is ever empty, ensure that all previously existing entities
-            // are loaded into that cache
-            if all_positions.is_empty() {
-                let entities = ctx.kv().set_intersect(&vec![
-                    format!("decs:{}:transponder:entities", frame.shard),
-                    format!("decs:{}:position:entities", frame.shard),
-                ])?;
-                for entity in entities {
-                    if let Ok(Some(position_str)) = ctx.kv().get(&format!(
-                        "decs:components:{}:{}:position",
-                        frame.shard, entity
-                    )) {
-                        ctx.log(&format!(
-                            "Adding entity {} at position {} to the cache",
-                            entity, position_str
-                        ));
-                        POSITIONS
-                            .write()
-                            .unwrap()
-                            .insert(entity, serde_json::from_str(&position_str)?);
-                    }
-                }
-                ctx.log(&format!(
-                    "Cache repleted with {} entities",
-                    POSITIONS.read().unwrap().len()
-                ));
-            }
+        // Contacts in adjacent shards are tracked separately from the shard's own
+        let (cross_shard_contacts, old_contacts): (HashMap<_, _>, HashMap<_, _>) = old_contacts
+            .into_iter()
+            .partition(|(_rid, contact)| contact.cross_shard);
 
-            radar_updates(
-                &frame.entity_id,
-                &frame.shard,
-                &position,
-                &radar_receiver,
-                &old_contacts,
-                &all_positions,
-                Some(&ctx),
-            )
+        // Only the positions of adjacent shards are needed, and only by a cross-shard receiver
+        let remote_positions = match radar_receiver.cross_shard_range {
+            Some(_) => cross_shard::adjacent_positions(ctx, &frame.shard)?,
+            None => HashMap::new(),
         };
+        let mut updates = radar_updates(
+            &frame.entity_id,
+            &frame.shard,
+            &position,
+            &radar_receiver,
+            &old_contacts,
+            all_positions,
+            &line_of_sight::obstacles(ctx, &frame.shard)?,
+            Some(&ctx),
+        );
+        updates.extend(cross_shard::cross_shard_updates(
+            &frame.shard,
+            &position,
+            radar_receiver.cross_shard_range,
+            &cross_shard_contacts,
+            &remote_positions,
+        ));
 
-        let _results = updates
-            .iter()
-            .map(|update| match update {
-                RadarContactDelta::Add(rc) => (
-                    ResProtocolRequest::New(format!(
-                        "{}.{}",
-                        resource_id.to_string(),
-                        RADAR_CONTACTS
-                    ))
+        for (subject, payload) in updates.iter().map(|update| match update {
+            RadarContactDelta::Add(rc) => (
+                ResProtocolRequest::New(format!("{}.{}", resource_id.to_string(), RADAR_CONTACTS))
                     .to_string()
                     .clone(),
-                    serde_json::json!({ "params": rc }),
-                ),
-                RadarContactDelta::Remove(rid) => (
-                    ResProtocolRequest::Delete(format!(
-                        "{}.{}",
-                        resource_id.to_string(),
-                        RADAR_CONTACTS
-                    ))
-                    .to_string(),
-                    serde_json::json!({"params": {"rid": rid.replace(":", ".")}}),
-                ),
-                RadarContactDelta::Change(rid, rc) => (
-                    format!("call.{}.set", rid.clone()),
-                    serde_json::json!({ "params": rc }),
-                ),
-            })
-            .map(|(subject, payload)| publish_message(ctx, &subject, payload))
-            .collect::<Vec<CallResult>>();
+                serde_json::json!({ "params": rc }),
+            ),
+            RadarContactDelta::Remove(rid) => (
+                ResProtocolRequest::Delete(format!(
+                    "{}.{}",
+                    resource_id.to_string(),
+                    RADAR_CONTACTS
+                ))
+                .to_string(),
+                serde_json::json!({"params": {"rid": rid}}),
+            ),
+            RadarContactDelta::Change(rid, rc) => (
+                format!("call.{}.set", rid.clone()),
+                serde_json::json!({ "params": rc }),
+            ),
+        }) {
+            // Lost updates are picked up again on a later frame, since the contact list
+            // still differs from what the radar sees
+            if signal_interference::is_dropped(ctx, frame, &position, &subject)? {
+                continue;
+            }
+            publishes.push((subject, payload));
+        }
 
         // If we modified a player's contacts at all, publish a change message to make
         // RESgate requery the source of truth.
-        if !updates.is_empty() {
-            ctx.msg().publish(
-                "system.reset",
-                None,
-                &serde_json::to_vec(&serde_json::json!({
-                    "resources":
-                        [format!(
-                            "decs.components.{}.{}.radar_contacts",
-                            frame.shard, frame.entity_id
-                        )]
-                }))?,
-            )?;
+        if !publishes.is_empty() {
+            publishes.push((
+                "system.reset".to_string(),
+                serde_json::json!({
+                    "resources": [format!(
+                        "decs.components.{}.{}.radar_contacts",
+                        frame.shard, frame.entity_id
+                    )]
+                }),
+            ));
+        }
+
+        // Entities keeping a radar history record what their radar saw this frame
+        if let Some(log_str) = ctx.kv().get(&format!(
+            "decs:components:{}:{}:{}",
+            frame.shard, frame.entity_id, RADAR_HISTORY_LOG
+        ))? {
+            let mut log: RadarHistoryLog = serde_json::from_str(&log_str)?;
+            let snapshot = RadarSnapshot {
+                timestamp_ms: now_ms,
+                contacts: history::current_contacts(
+                    old_contacts.iter().chain(cross_shard_contacts.iter()),
+                    &updates,
+                ),
+            };
+            log.record(snapshot);
+            publishes.push((
+                format!("call.{}.{}.set", resource_id, RADAR_HISTORY_LOG),
+                serde_json::json!({ "params": log }),
+            ));
         }
     }
 
-    Ok(vec![])
+    Ok(publishes)
+}
+
+/// Retrieve a snapshot of all cached entity positions. If the positions cache is ever empty,
+/// ensure that all previously existing entities are loaded into that cache first
+pub(crate) fn cached_positions(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+) -> Result<HashMap<String, Position>> {
+    let cached = POSITIONS
+        .read()
+        .unwrap()
+        .get(shard)
+        .is_some_and(|positions| !positions.is_empty());
+    if !cached {
+        let entities = ctx.kv().set_intersect(&vec![
+            format!("decs:{}:transponder:entities", shard),
+            format!("decs:{}:position:entities", shard),
+        ])?;
+        for entity in entities {
+            if let Ok(Some(position_str)) = ctx
+                .kv()
+                .get(&format!("decs:components:{}:{}:position", shard, entity))
+            {
+                ctx.log(&format!(
+                    "Adding entity {} at position {} to the cache",
+                    entity, position_str
+                ));
+                POSITIONS
+                    .write()
+                    .unwrap()
+                    .entry(shard.to_string())
+                    .or_default()
+                    .insert(entity, serde_json::from_str(&position_str)?);
+            }
+        }
+        ctx.log(&format!(
+            "Cache repleted with {} entities",
+            POSITIONS.read().unwrap().get(shard).map_or(0, HashMap::len)
+        ));
+    }
+    Ok(POSITIONS
+        .read()
+        .unwrap()
+        .get(shard)
+        .cloned()
+        .unwrap_or_default())
 }
 
 /// Helper function used to publish a payload on a specified subjct
@@ -176,13 +370,16 @@ fn publish_message(
 /// Function to compute all changes to a contact list needed given a resources id, current position,
 /// radar receiver, all old contacts, and a map of all entity positions that are published.
 /// Changes are in the form of RadarContactDeltas, either specifying to Add, Remove, or Change a contact.
-fn radar_updates(
+/// Entities hidden behind any of the terrain obstacles are not seen.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn radar_updates(
     entity_id: &str,
     shard: &str,
     current_position: &Position,
     radar_receiver: &RadarReceiver,
     old_contacts: &HashMap<String, RadarContact>,
     all_positions: &HashMap<String, Position>,
+    obstacles: &[TerrainObstacle],
     ctx: Option<&CapabilitiesContext>,
 ) -> Vec<RadarContactDelta> {
     let contacts: Vec<String> = old_contacts
@@ -197,7 +394,7 @@ fn radar_updates(
                 if let Some((entity_rid, _val)) =
                     old_contacts.iter().find(|(_k, v)| v.entity_id == *ent_id)
                 {
-                    rid = entity_rid.to_string().replace(":", ".");
+                    rid = entity_rid.to_string();
                 }
                 if ctx.is_some()
                     && !ctx
@@ -207,40 +404,57 @@ fn radar_updates(
                         .unwrap_or(true)
                 {
                     ctx.unwrap().log(&format!("Removing: {}", ent_id));
-                    POSITIONS.write().unwrap().remove(ent_id);
+                    if let Some(positions) = POSITIONS.write().unwrap().get_mut(shard) {
+                        positions.remove(ent_id);
+                    }
                     Some(RadarContactDelta::Remove(rid))
-                } else if within_radius(current_position, pos, radar_receiver.radius)
-                    || ent_id == "starbase_0"
+                } else if ((within_radius(current_position, pos, radar_receiver.radius)
+                    && has_line_of_sight(current_position, pos, obstacles))
+                    || ent_id == "starbase_0")
+                    && ctx
+                        .map(|ctx| is_detectable(ctx, shard, ent_id))
+                        .unwrap_or(true)
                 {
                     let vector_to = current_position.vector_to(pos);
-                    let transponder = transponder_for_entity(shard, &ent_id.clone());
+                    // A contact that can no longer be named by a transponder rid is taken off
+                    // the radar rather than left as it was
+                    let transponder = match transponder_for_entity(shard, ent_id) {
+                        Some(transponder) => transponder,
+                        None => return Some(RadarContactDelta::Remove(rid)),
+                    };
                     Some(RadarContactDelta::Change(
                         rid,
                         RadarContact {
                             entity_id: ent_id.clone().to_string(),
-                            distance: vector_to.mag,
+                            distance: Distance::from(vector_to.mag),
                             distance_xy: vector_to.distance_xy,
                             azimuth: vector_to.azimuth,
                             elevation: vector_to.elevation,
                             transponder,
+                            cross_shard: false,
                         },
                     ))
                 } else {
                     Some(RadarContactDelta::Remove(rid))
                 }
-            } else if (entity_id != ent_id
-                && within_radius(current_position, &pos, radar_receiver.radius))
-                || ent_id == "starbase_0"
+            } else if ((entity_id != ent_id
+                && within_radius(current_position, &pos, radar_receiver.radius)
+                && has_line_of_sight(current_position, pos, obstacles))
+                || ent_id == "starbase_0")
+                && ctx
+                    .map(|ctx| is_detectable(ctx, shard, ent_id))
+                    .unwrap_or(true)
             {
                 let vector_to = current_position.vector_to(pos);
-                let transponder = transponder_for_entity(shard, &ent_id.clone());
+                let transponder = transponder_for_entity(shard, ent_id)?;
                 Some(RadarContactDelta::Add(RadarContact {
                     entity_id: ent_id.clone().to_string(),
-                    distance: vector_to.mag,
+                    distance: Distance::from(vector_to.mag),
                     distance_xy: vector_to.distance_xy,
                     azimuth: vector_to.azimuth,
                     elevation: vector_to.elevation,
                     transponder,
+                    cross_shard: false,
                 }))
             } else {
                 None
@@ -250,14 +464,14 @@ fn radar_updates(
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
-enum RadarContactDelta {
+pub(crate) enum RadarContactDelta {
     Add(RadarContact),
     Remove(String),
     Change(String, RadarContact),
 }
 
 /// Receives messages on the subject `event.decs.components.{shard}.{entity}.position.change`
-/// Stores entity position in-memory in the POSITIONS HashMap
+/// Stores entity position in-memory in the POSITIONS HashMap, under the entity's shard
 /// The cache is used later to discover nearby radar_contacts
 pub(crate) fn handle_entity_position_change(
     _ctx: &CapabilitiesContext,
@@ -266,35 +480,168 @@ pub(crate) fn handle_entity_position_change(
     let subject: Vec<&str> = msg.subject.split('.').collect();
     let position_value: serde_json::Value = serde_json::from_slice(&msg.body)?;
     let position: Position = serde_json::from_value::<Position>(position_value["values"].clone())?;
+    store_position(subject[3], subject[4], position);
+    Ok(vec![])
+}
+
+/// Caches the entity's position, returning whether it was written. A position the cache already
+/// holds, e.g. from a redelivered change event, only takes the read lock and is counted in
+/// `DEDUP_SKIPS`
+fn store_position(shard: &str, entity_id: &str, position: Position) -> bool {
+    let unchanged = POSITIONS
+        .read()
+        .unwrap()
+        .get(shard)
+        .and_then(|positions| positions.get(entity_id))
+        .is_some_and(|cached| cached.approx_eq(&position, f64::EPSILON));
+    if unchanged {
+        DEDUP_SKIPS.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
     POSITIONS
         .write()
         .unwrap()
-        .insert(subject[4].to_string(), position);
-    Ok(vec![])
+        .entry(shard.to_string())
+        .or_default()
+        .insert(entity_id.to_string(), position);
+    true
 }
 
 /// Helper function to clean up determining if an entity is within a radius
-fn within_radius(entity: &Position, target: &Position, radius: f64) -> bool {
-    entity.distance_to_3d(target) <= radius
+pub(crate) fn within_radius(entity: &Position, target: &Position, radius: Distance) -> bool {
+    Distance(entity.distance_to_3d(target)) <= radius
 }
 
-/// Helper function format a `radar_transponder` ResourceIdentifier given a specific entity
-fn transponder_for_entity(shard: &str, entity_id: &str) -> ResourceIdentifier {
-    ResourceIdentifier {
-        rid: format!("decs.components.{}.{}.transponder", shard, entity_id),
+/// Helper function to determine if an entity can currently be seen on radar. Entities whose
+/// transponder reports no radar signature (e.g. while travelling through a wormhole) are invisible
+pub(crate) fn is_detectable(ctx: &CapabilitiesContext, shard: &str, entity_id: &str) -> bool {
+    match ctx.kv().get(&format!(
+        "decs:components:{}:{}:transponder",
+        shard, entity_id
+    )) {
+        Ok(Some(tp_str)) => serde_json::from_str::<RadarTransponder>(&tp_str)
+            .map(|tp| tp.radar_signature > 0.0)
+            .unwrap_or(true),
+        _ => true,
     }
 }
 
+/// Helper function format a `radar_transponder` ResourceIdentifier given a specific entity. An
+/// entity ID that can't be part of an rid has none, and isn't shown on radar
+pub(crate) fn transponder_for_entity(shard: &str, entity_id: &str) -> Option<ResourceIdentifier> {
+    trader::rid::ResourceIdentifier::new(shard, entity_id, "transponder")
+        .ok()
+        .map(|transponder| ResourceIdentifier {
+            rid: transponder.to_rid(),
+        })
+}
+
 #[cfg(test)]
 mod test {
+    use super::latest_frames_by_entity;
     use super::radar_updates;
+    use super::store_position;
     use super::within_radius;
+    use super::Aabb;
+    use super::ApproxEq;
+    use super::Distance;
+    use super::EntityFrame;
     use super::HashMap;
     use super::Position;
     use super::RadarContact;
     use super::RadarContactDelta;
     use super::RadarReceiver;
     use super::ResourceIdentifier;
+    use super::TerrainObstacle;
+    use super::DEDUP_SKIPS;
+    use super::POSITIONS;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+
+    /// A known initial state of the radar system and the deltas it must produce, stored in
+    /// `radar/tests/golden`. Maps are ordered so regenerated files only change where the
+    /// output does
+    #[derive(Serialize, Deserialize)]
+    struct GoldenCase {
+        description: String,
+        entity_id: String,
+        shard: String,
+        current_position: Position,
+        radar_receiver: RadarReceiver,
+        old_contacts: BTreeMap<String, RadarContact>,
+        positions: BTreeMap<String, Position>,
+        expected: Vec<RadarContactDelta>,
+    }
+
+    /// Orders deltas by their serialized form, `radar_updates` returns them in map order.
+    /// Angles are rounded to a microdegree, JSON doesn't round-trip every last bit of a float
+    fn canonical(deltas: Vec<RadarContactDelta>) -> Vec<RadarContactDelta> {
+        let round = |rc: RadarContact| RadarContact {
+            azimuth: (rc.azimuth * 1e6).round() / 1e6,
+            elevation: (rc.elevation * 1e6).round() / 1e6,
+            ..rc
+        };
+        let mut deltas: Vec<RadarContactDelta> = deltas
+            .into_iter()
+            .map(|delta| match delta {
+                RadarContactDelta::Add(rc) => RadarContactDelta::Add(round(rc)),
+                RadarContactDelta::Change(rid, rc) => RadarContactDelta::Change(rid, round(rc)),
+                remove => remove,
+            })
+            .collect();
+        deltas.sort_by_cached_key(|delta| serde_json::to_string(delta).unwrap());
+        deltas
+    }
+
+    /// Compares the output of `radar_updates` to every golden file. Run with `UPDATE_GOLDEN=1`
+    /// to regenerate the expected output after an intended change in behavior
+    #[test]
+    fn test_golden_files() {
+        let update = std::env::var("UPDATE_GOLDEN")
+            .map(|v| v == "1")
+            .unwrap_or(false);
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        let mut paths: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map(|ext| ext == "json").unwrap_or(false))
+            .collect();
+        paths.sort();
+        assert!(
+            paths.len() >= 5,
+            "missing golden files in {}",
+            dir.display()
+        );
+
+        for path in paths {
+            let mut case: GoldenCase =
+                serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            let actual = canonical(radar_updates(
+                &case.entity_id,
+                &case.shard,
+                &case.current_position,
+                &case.radar_receiver,
+                &case.old_contacts.clone().into_iter().collect(),
+                &case.positions.clone().into_iter().collect(),
+                &[],
+                None,
+            ));
+            if update {
+                case.expected = actual;
+                let golden = serde_json::to_string_pretty(&case).unwrap() + "\n";
+                std::fs::write(&path, golden).unwrap();
+            } else {
+                assert_eq!(
+                    actual,
+                    canonical(case.expected),
+                    "{}: {}",
+                    path.display(),
+                    case.description
+                );
+            }
+        }
+    }
 
     #[test]
     fn test_within_radius() {
@@ -308,7 +655,7 @@ mod test {
             y: 2.0,
             z: 1.0,
         };
-        let radius = 3.0;
+        let radius = Distance(3.0);
         assert!(within_radius(&a, &b, radius));
     }
 
@@ -324,7 +671,7 @@ mod test {
             y: 200.0,
             z: 1.0,
         };
-        let radius = 3.0;
+        let radius = Distance(3.0);
         assert!(!within_radius(&a, &b, radius));
     }
 
@@ -340,53 +687,189 @@ mod test {
             y: 0.0,
             z: 0.0,
         };
-        let radius = 20.0;
+        let radius = Distance(20.0);
         assert!(within_radius(&a, &b, radius));
     }
 
+    #[test]
+    fn test_obstacles_hide_contacts() {
+        let receiver = RadarReceiver {
+            radius: Distance(50.0),
+            cross_shard_range: None,
+        };
+        let mut all_positions = HashMap::new();
+        all_positions.insert("hidden".to_string(), Position::new(10.0, 0.0, 0.0));
+        all_positions.insert("visible".to_string(), Position::new(0.0, 10.0, 0.0));
+        let obstacles = [TerrainObstacle {
+            bounding_box: Aabb {
+                min: Position::new(4.0, -1.0, -1.0),
+                max: Position::new(6.0, 1.0, 1.0),
+            },
+        }];
+        let updates = |old_contacts: &HashMap<String, RadarContact>| {
+            radar_updates(
+                "player1",
+                "the_void",
+                &Position::new(0.0, 0.0, 0.0),
+                &receiver,
+                old_contacts,
+                &all_positions,
+                &obstacles,
+                None,
+            )
+        };
+
+        // Contacts behind an obstacle are never added
+        let added = updates(&HashMap::new());
+        assert_eq!(added.len(), 1);
+        match &added[0] {
+            RadarContactDelta::Add(contact) => assert_eq!(contact.entity_id, "visible"),
+            other => panic!("expected an add, got {:?}", other),
+        }
+
+        // and known contacts that move behind one are lost
+        let mut old_contacts = HashMap::new();
+        old_contacts.insert(
+            "decs.components.the_void.player1.radar_contacts.c1".to_string(),
+            RadarContact {
+                entity_id: "hidden".to_string(),
+                ..RadarContact::default()
+            },
+        );
+        let changes = updates(&old_contacts);
+        assert!(changes.contains(&RadarContactDelta::Remove(
+            "decs.components.the_void.player1.radar_contacts.c1".to_string()
+        )));
+    }
+
+    #[test]
+    fn test_entities_without_a_transponder_rid_are_not_shown() {
+        let receiver = RadarReceiver {
+            radius: Distance(50.0),
+            cross_shard_range: None,
+        };
+        let mut all_positions = HashMap::new();
+        all_positions.insert("ship1".to_string(), Position::new(1.0, 0.0, 0.0));
+        // Its transponder's key would name an item of ship1's transponder
+        all_positions.insert(
+            "ship1:transponder".to_string(),
+            Position::new(2.0, 0.0, 0.0),
+        );
+        let updates = radar_updates(
+            "player1",
+            "the_void",
+            &Position::new(0.0, 0.0, 0.0),
+            &receiver,
+            &HashMap::new(),
+            &all_positions,
+            &[],
+            None,
+        );
+        assert_eq!(updates.len(), 1);
+        match &updates[0] {
+            RadarContactDelta::Add(contact) => {
+                assert_eq!(contact.entity_id, "ship1");
+                assert_eq!(
+                    contact.transponder.rid,
+                    "decs.components.the_void.ship1.transponder"
+                );
+            }
+            other => panic!("expected an add, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_contacts_without_a_transponder_rid_are_removed() {
+        let receiver = RadarReceiver {
+            radius: Distance(50.0),
+            cross_shard_range: None,
+        };
+        let mut all_positions = HashMap::new();
+        all_positions.insert(
+            "ship1:transponder".to_string(),
+            Position::new(2.0, 0.0, 0.0),
+        );
+        let contact_rid = "decs.components.the_void.player1.radar_contacts.1";
+        let mut old_contacts = HashMap::new();
+        old_contacts.insert(
+            contact_rid.to_string(),
+            RadarContact {
+                entity_id: "ship1:transponder".to_string(),
+                distance: Distance(2.0),
+                distance_xy: 2,
+                azimuth: 0.0,
+                elevation: 0.0,
+                transponder: ResourceIdentifier {
+                    rid: "decs.components.the_void.ship1.transponder".to_string(),
+                },
+                cross_shard: false,
+            },
+        );
+        let updates = radar_updates(
+            "player1",
+            "the_void",
+            &Position::new(0.0, 0.0, 0.0),
+            &receiver,
+            &old_contacts,
+            &all_positions,
+            &[],
+            None,
+        );
+        assert_eq!(
+            updates,
+            vec![RadarContactDelta::Remove(contact_rid.to_string())]
+        );
+    }
+
     #[test]
     fn test_add_contacts() {
-        let rid = "decs.components.the_shard.myownentity".to_string();
+        let rid = "myownentity".to_string();
         let current_position = Position {
             x: 0.0,
             y: 0.0,
             z: 0.0,
         };
-        let radar_receiver = RadarReceiver { radius: 5.0 };
+        let radar_receiver = RadarReceiver {
+            radius: Distance(5.0),
+            cross_shard_range: None,
+        };
         let old_contacts: HashMap<String, RadarContact> = HashMap::new();
         let mut all_positions: HashMap<String, Position> = HashMap::new();
 
         let vector_to = current_position.vector_to(&current_position);
 
         let nearby_asteroid = RadarContact {
-            entity_id: "decs.components.the_shard.asteroid".to_string(),
-            distance: vector_to.mag,
+            entity_id: "asteroid".to_string(),
+            distance: Distance::from(vector_to.mag),
             distance_xy: vector_to.distance_xy,
             azimuth: vector_to.azimuth,
             elevation: vector_to.elevation,
             transponder: ResourceIdentifier {
                 rid: "decs.components.the_shard.asteroid.transponder".to_string(),
             },
+            cross_shard: false,
         };
         let nearby_ship = RadarContact {
-            entity_id: "decs.components.the_shard.ship".to_string(),
-            distance: vector_to.mag,
+            entity_id: "ship".to_string(),
+            distance: Distance::from(vector_to.mag),
             distance_xy: vector_to.distance_xy,
             azimuth: vector_to.azimuth,
             elevation: vector_to.elevation,
             transponder: ResourceIdentifier {
                 rid: "decs.components.the_shard.ship.transponder".to_string(),
             },
+            cross_shard: false,
         };
         let mut far_away_money = RadarContact {
-            entity_id: "decs.components.the_shard.money".to_string(),
-            distance: vector_to.mag,
+            entity_id: "money".to_string(),
+            distance: Distance::from(vector_to.mag),
             distance_xy: vector_to.distance_xy,
             azimuth: vector_to.azimuth,
             elevation: vector_to.elevation,
             transponder: ResourceIdentifier {
                 rid: "decs.components.the_shard.money.transponder".to_string(),
             },
+            cross_shard: false,
         };
         let far_away_money_pos = Position {
             x: 500.0,
@@ -394,7 +877,7 @@ mod test {
             z: 0.0,
         };
         let new_vector_to = current_position.vector_to(&far_away_money_pos);
-        far_away_money.distance = new_vector_to.mag;
+        far_away_money.distance = Distance::from(new_vector_to.mag);
         far_away_money.azimuth = new_vector_to.azimuth;
         far_away_money.elevation = new_vector_to.elevation;
 
@@ -410,6 +893,7 @@ mod test {
             &radar_receiver,
             &old_contacts,
             &all_positions,
+            &[],
             None,
         );
 
@@ -434,49 +918,55 @@ mod test {
 
     #[test]
     fn test_remove_contacts() {
-        let rid = "decs.components.the_shard.myownentity".to_string();
+        let rid = "myownentity".to_string();
         let current_position = Position {
             x: 0.0,
             y: 0.0,
             z: 0.0,
         };
-        let radar_receiver = RadarReceiver { radius: 5.0 };
+        let radar_receiver = RadarReceiver {
+            radius: Distance(5.0),
+            cross_shard_range: None,
+        };
         let mut all_positions: HashMap<String, Position> = HashMap::new();
 
         let vector_to = current_position.vector_to(&current_position);
 
-        let asteroid_entity = "decs.components.the_shard.asteroid";
+        let asteroid_entity = "asteroid";
         let nearby_asteroid = RadarContact {
             entity_id: asteroid_entity.to_string(),
-            distance: vector_to.mag,
+            distance: Distance::from(vector_to.mag),
             distance_xy: vector_to.distance_xy,
             azimuth: vector_to.azimuth,
             elevation: vector_to.elevation,
             transponder: ResourceIdentifier {
                 rid: "decs.components.the_shard.asteroid.transponder".to_string(),
             },
+            cross_shard: false,
         };
-        let nearby_entity = "decs.components.the_shard.ship";
+        let nearby_entity = "ship";
         let nearby_ship = RadarContact {
             entity_id: nearby_entity.to_string(),
-            distance: vector_to.mag,
+            distance: Distance::from(vector_to.mag),
             distance_xy: vector_to.distance_xy,
             azimuth: vector_to.azimuth,
             elevation: vector_to.elevation,
             transponder: ResourceIdentifier {
                 rid: "decs.components.the_shard.ship.transponder".to_string(),
             },
+            cross_shard: false,
         };
-        let faraway_entity = "decs.components.the_shard.money";
+        let faraway_entity = "money";
         let far_away_money = RadarContact {
             entity_id: faraway_entity.to_string(),
-            distance: vector_to.mag,
+            distance: Distance::from(vector_to.mag),
             distance_xy: vector_to.distance_xy,
             azimuth: vector_to.azimuth,
             elevation: vector_to.elevation,
             transponder: ResourceIdentifier {
                 rid: "decs.components.the_shard.money.transponder".to_string(),
             },
+            cross_shard: false,
         };
 
         let mut old_contacts: HashMap<String, RadarContact> = HashMap::new();
@@ -492,7 +982,7 @@ mod test {
         };
         let new_vector_to = current_position.vector_to(&current_position_clone);
         let far_away_money = RadarContact {
-            distance: new_vector_to.mag,
+            distance: Distance::from(new_vector_to.mag),
             azimuth: new_vector_to.azimuth,
             elevation: new_vector_to.elevation,
             ..far_away_money
@@ -510,6 +1000,7 @@ mod test {
             &radar_receiver,
             &old_contacts,
             &all_positions,
+            &[],
             None,
         );
 
@@ -531,49 +1022,55 @@ mod test {
 
     #[test]
     fn test_change_contact() {
-        let rid = "decs.components.the_shard.myownentity".to_string();
+        let rid = "myownentity".to_string();
         let current_position = Position {
             x: 0.0,
             y: 0.0,
             z: 0.0,
         };
-        let radar_receiver = RadarReceiver { radius: 5.0 };
+        let radar_receiver = RadarReceiver {
+            radius: Distance(5.0),
+            cross_shard_range: None,
+        };
         let mut all_positions: HashMap<String, Position> = HashMap::new();
 
         let vector_to = current_position.vector_to(&current_position);
 
-        let asteroid_entity = "decs.components.the_shard.asteroid";
+        let asteroid_entity = "asteroid";
         let nearby_asteroid = RadarContact {
             entity_id: asteroid_entity.to_string(),
-            distance: vector_to.mag,
+            distance: Distance::from(vector_to.mag),
             distance_xy: vector_to.distance_xy,
             azimuth: vector_to.azimuth,
             elevation: vector_to.elevation,
             transponder: ResourceIdentifier {
                 rid: "decs.components.the_shard.asteroid.transponder".to_string(),
             },
+            cross_shard: false,
         };
-        let nearby_entity_id = "decs.components.the_shard.ship";
+        let nearby_entity_id = "ship";
         let nearby_ship = RadarContact {
             entity_id: nearby_entity_id.to_string(),
-            distance: vector_to.mag,
+            distance: Distance::from(vector_to.mag),
             distance_xy: vector_to.distance_xy,
             azimuth: vector_to.azimuth,
             elevation: vector_to.elevation,
             transponder: ResourceIdentifier {
                 rid: "decs.components.the_shard.ship.transponder".to_string(),
             },
+            cross_shard: false,
         };
-        let faraway_entity_id = "decs.components.the_shard.money";
+        let faraway_entity_id = "money";
         let far_away_money = RadarContact {
             entity_id: faraway_entity_id.to_string(),
-            distance: vector_to.mag,
+            distance: Distance::from(vector_to.mag),
             distance_xy: vector_to.distance_xy,
             azimuth: vector_to.azimuth,
             elevation: vector_to.elevation,
             transponder: ResourceIdentifier {
                 rid: "decs.components.the_shard.money.transponder".to_string(),
             },
+            cross_shard: false,
         };
 
         let mut old_contacts: HashMap<String, RadarContact> = HashMap::new();
@@ -603,6 +1100,7 @@ mod test {
             &radar_receiver,
             &old_contacts,
             &all_positions,
+            &[],
             None,
         );
 
@@ -620,49 +1118,55 @@ mod test {
 
     #[test]
     fn test_modify_all_contacts() {
-        let rid = "decs.components.the_shard.myownentity".to_string();
+        let rid = "myownentity".to_string();
         let current_position = Position {
             x: 0.0,
             y: 0.0,
             z: 0.0,
         };
-        let radar_receiver = RadarReceiver { radius: 5.0 };
+        let radar_receiver = RadarReceiver {
+            radius: Distance(5.0),
+            cross_shard_range: None,
+        };
         let mut all_positions: HashMap<String, Position> = HashMap::new();
 
         let vector_to = current_position.vector_to(&current_position);
 
-        let asteroid_entity = "decs.components.the_shard.asteroid";
+        let asteroid_entity = "asteroid";
         let nearby_asteroid = RadarContact {
             entity_id: asteroid_entity.to_string(),
-            distance: vector_to.mag,
+            distance: Distance::from(vector_to.mag),
             distance_xy: vector_to.distance_xy,
             azimuth: vector_to.azimuth,
             elevation: vector_to.elevation,
             transponder: ResourceIdentifier {
                 rid: "decs.components.the_shard.asteroid.transponder".to_string(),
             },
+            cross_shard: false,
         };
-        let nearby_entity_id = "decs.components.the_shard.ship";
+        let nearby_entity_id = "ship";
         let mut nearby_ship = RadarContact {
             entity_id: nearby_entity_id.to_string(),
-            distance: vector_to.mag,
+            distance: Distance::from(vector_to.mag),
             distance_xy: vector_to.distance_xy,
             azimuth: vector_to.azimuth,
             elevation: vector_to.elevation,
             transponder: ResourceIdentifier {
                 rid: "decs.components.the_shard.ship.transponder".to_string(),
             },
+            cross_shard: false,
         };
-        let faraway_entity_id = "decs.components.the_shard.money";
+        let faraway_entity_id = "money";
         let far_away_money = RadarContact {
             entity_id: faraway_entity_id.to_string(),
-            distance: vector_to.mag,
+            distance: Distance::from(vector_to.mag),
             distance_xy: vector_to.distance_xy,
             azimuth: vector_to.azimuth,
             elevation: vector_to.elevation,
             transponder: ResourceIdentifier {
                 rid: "decs.components.the_shard.money.transponder".to_string(),
             },
+            cross_shard: false,
         };
 
         let mut old_contacts: HashMap<String, RadarContact> = HashMap::new();
@@ -698,7 +1202,7 @@ mod test {
 
         // Add a new nearby ship, which wasn't an old contact.
         let new_vector_to = new_position.vector_to(&new_position);
-        nearby_ship.distance = new_vector_to.mag;
+        nearby_ship.distance = Distance::from(new_vector_to.mag);
         nearby_ship.azimuth = new_vector_to.azimuth;
         nearby_ship.elevation = new_vector_to.elevation;
         all_positions.insert(nearby_ship.entity_id.clone(), current_position);
@@ -710,6 +1214,7 @@ mod test {
             &radar_receiver,
             &old_contacts,
             &all_positions,
+            &[],
             None,
         );
 
@@ -717,11 +1222,166 @@ mod test {
         for c in changes {
             match c {
                 RadarContactDelta::Add(rc) => {
-                    assert!(rc.entity_id == "decs.components.the_shard.ship")
+                    assert!(rc.entity_id == "ship")
                 }
                 RadarContactDelta::Remove(s) => assert_eq!(s, remove_rid_2),
                 RadarContactDelta::Change(s, _rc) => assert_eq!(s, change_rid_1),
             }
         }
     }
+
+    fn frame(entity_id: &str, seq_no: u64) -> EntityFrame {
+        serde_json::from_value(serde_json::json!({
+            "seq_no": seq_no,
+            "elapsed_ms": 1000,
+            "shard": "the_shard",
+            "system": "radar",
+            "entity_id": entity_id,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_batch_keeps_latest_frame_per_entity() {
+        let frames = latest_frames_by_entity(vec![
+            frame("ship1", 4),
+            frame("ship2", 4),
+            frame("ship1", 6),
+            frame("ship1", 5),
+        ]);
+        let frames: Vec<(&str, u64)> = frames
+            .iter()
+            .map(|f| (f.entity_id.as_str(), f.seq_no))
+            .collect();
+        assert_eq!(frames, vec![("ship1", 6), ("ship2", 4)]);
+    }
+
+    #[test]
+    fn test_repeated_position_is_not_written_again() {
+        let position = Position::new(1.0, 2.0, 3.0);
+        let skips = DEDUP_SKIPS.load(Ordering::Relaxed);
+        assert!(store_position("the_void", "dedup_ship", position));
+        assert!(!store_position("the_void", "dedup_ship", position));
+        assert_eq!(DEDUP_SKIPS.load(Ordering::Relaxed), skips + 1);
+
+        // A real move is still written
+        let moved = Position::new(1.0, 2.0, 3.5);
+        assert!(store_position("the_void", "dedup_ship", moved));
+        assert_eq!(
+            POSITIONS.read().unwrap()["the_void"].get("dedup_ship"),
+            Some(&moved)
+        );
+
+        // The same entity ID in another shard is another entity
+        assert!(store_position("the_abyss", "dedup_ship", position));
+    }
+
+    #[test]
+    fn test_dedup_compares_every_coordinate() {
+        let a = Position::new(1.0, 2.0, 3.0);
+        assert!(a.approx_eq(&Position::new(1.0, 2.0, 3.0), f64::EPSILON));
+        assert!(a.approx_eq(
+            &Position::new(1.0 + f64::EPSILON / 2.0, 2.0, 3.0),
+            f64::EPSILON
+        ));
+        assert!(!a.approx_eq(&Position::new(1.0, 2.0, 3.001), f64::EPSILON));
+        assert!(!a.approx_eq(&Position::new(1.0, 2.1, 3.0), f64::EPSILON));
+        assert!(!a.approx_eq(&Position::new(0.9, 2.0, 3.0), f64::EPSILON));
+    }
+}
+
+#[cfg(test)]
+mod proptests {
+    use super::radar_updates;
+    use super::Distance;
+    use super::HashMap;
+    use super::Position;
+    use super::RadarContact;
+    use super::RadarReceiver;
+    use super::ResourceIdentifier;
+    use proptest::collection::hash_map;
+    use proptest::prelude::*;
+
+    fn position() -> impl Strategy<Value = Position> {
+        (-1e7..1e7f64, -1e7..1e7f64, -1e7..1e7f64).prop_map(|(x, y, z)| Position { x, y, z })
+    }
+
+    fn radar_contact() -> impl Strategy<Value = RadarContact> {
+        (
+            any::<String>(),
+            any::<u32>(),
+            any::<u32>(),
+            any::<f64>(),
+            any::<f64>(),
+            any::<String>(),
+        )
+            .prop_map(
+                |(entity_id, distance, distance_xy, azimuth, elevation, rid)| RadarContact {
+                    entity_id,
+                    distance: Distance::from(distance),
+                    distance_xy,
+                    azimuth,
+                    elevation,
+                    transponder: ResourceIdentifier { rid },
+                    cross_shard: false,
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn radar_updates_never_panics(
+            entity_id in any::<String>(),
+            current_position in position(),
+            radius in 0.0..=1e6f64,
+            old_contacts in hash_map(any::<String>(), radar_contact(), 0..100),
+            all_positions in hash_map(any::<String>(), position(), 0..=100),
+        ) {
+            let deltas = radar_updates(
+                &entity_id,
+                "the_void",
+                &current_position,
+                &RadarReceiver { radius: Distance(radius), cross_shard_range: None },
+                &old_contacts,
+                &all_positions,
+                &[],
+                None,
+            );
+            prop_assert!(deltas.len() <= all_positions.len());
+        }
+
+        #[test]
+        fn radar_updates_with_known_contacts_never_panics(
+            current_position in position(),
+            radius in 0.0..=1e6f64,
+            all_positions in hash_map("[a-z0-9_:.]{0,12}", position(), 0..=100),
+        ) {
+            // Contacts for some of the positioned entities, keyed by their rids
+            let old_contacts: HashMap<String, RadarContact> = all_positions
+                .keys()
+                .enumerate()
+                .filter(|(i, _)| i % 2 == 0)
+                .map(|(i, entity_id)| {
+                    (
+                        format!("decs.components.the_void.player1.radar_contacts.{}", i),
+                        RadarContact {
+                            entity_id: entity_id.to_string(),
+                            ..RadarContact::default()
+                        },
+                    )
+                })
+                .collect();
+            let deltas = radar_updates(
+                "player1",
+                "the_void",
+                &current_position,
+                &RadarReceiver { radius: Distance(radius), cross_shard_range: None },
+                &old_contacts,
+                &all_positions,
+                &[],
+                None,
+            );
+            prop_assert!(deltas.len() <= all_positions.len());
+        }
+    }
 }
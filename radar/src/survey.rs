@@ -0,0 +1,255 @@
+//! # Survey
+//!
+//! The survey system awaits frames for entities that have a `survey_scanner` component. Each frame
+//! the scan's remaining duration is reduced by the elapsed time. When the scan completes:
+//! - every entity within the scan radius that has an unrevealed `hidden_resource` component is
+//!   revealed, and an `event.decs.system.survey.resource_revealed` event is published for it
+//! - the entity IDs of the revealed resources are added to the scanning entity's `survey_log`
+//! - the `survey_scanner` component is deleted
+use crate::radar::{cached_positions, within_radius};
+use guest::prelude::*;
+use stacktrader_types as trader;
+use std::collections::HashMap;
+use trader::components::*;
+use trader::units::Distance;
+
+const HIDDEN_RESOURCE: &str = "hidden_resource";
+const SURVEY_LOG: &str = "survey_log";
+const RESOURCE_REVEALED_EVENT: &str = "event.decs.system.survey.resource_revealed";
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let scanner_value = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard,
+        frame.entity_id,
+        super::SURVEY_SCANNER
+    ))?;
+    let position_value = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard,
+        frame.entity_id,
+        super::POSITION
+    ))?;
+
+    if let (Some(scanner_str), Some(position_str)) = (scanner_value, position_value) {
+        let scanner: SurveyScanner = serde_json::from_str(&scanner_str)?;
+        let position: Position = serde_json::from_str(&position_str)?;
+        let scanner = advance_scan(scanner, frame.elapsed_ms);
+
+        if scanner.scan_duration_remaining_ms > 0.0 {
+            ctx.msg().publish(
+                &format!(
+                    "call.decs.components.{}.{}.{}.set",
+                    frame.shard,
+                    frame.entity_id,
+                    super::SURVEY_SCANNER
+                ),
+                None,
+                &serde_json::to_vec(&serde_json::json!({ "params": scanner }))?,
+            )?;
+        } else {
+            complete_scan(ctx, &frame.shard, &frame.entity_id, &position, &scanner)?;
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// Reveals all hidden resources in range of the scanner, records them in the scanning
+/// entity's survey log and removes the finished scanner
+fn complete_scan(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    position: &Position,
+    scanner: &SurveyScanner,
+) -> Result<()> {
+    let all_positions = cached_positions(ctx, shard)?;
+    let hidden: HashMap<String, HiddenResource> =
+        entities_in_range(entity_id, position, scanner.scan_radius, &all_positions)
+            .into_iter()
+            .filter_map(|ent_id| {
+                match ctx.kv().get(&format!(
+                    "decs:components:{}:{}:{}",
+                    shard, ent_id, HIDDEN_RESOURCE
+                )) {
+                    Ok(Some(hr_str)) => serde_json::from_str(&hr_str).ok().map(|hr| (ent_id, hr)),
+                    _ => None,
+                }
+            })
+            .collect();
+
+    let revealed = resources_to_reveal(&hidden);
+    for ent_id in &revealed {
+        ctx.msg().publish(
+            &format!(
+                "call.decs.components.{}.{}.{}.set",
+                shard, ent_id, HIDDEN_RESOURCE
+            ),
+            None,
+            &serde_json::to_vec(&serde_json::json!({
+                "params": HiddenResource { revealed: true }
+            }))?,
+        )?;
+        ctx.msg().publish(
+            RESOURCE_REVEALED_EVENT,
+            None,
+            &serde_json::to_vec(&serde_json::json!({
+                "shard": shard,
+                "scanner": entity_id,
+                "resource": ent_id,
+            }))?,
+        )?;
+    }
+
+    let log_key = format!("decs:components:{}:{}:{}", shard, entity_id, SURVEY_LOG);
+    let log: SurveyLog = match ctx.kv().get(&log_key)? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => SurveyLog::default(),
+    };
+    ctx.msg().publish(
+        &format!("call.{}.set", log_key.replace(':', ".")),
+        None,
+        &serde_json::to_vec(&serde_json::json!({ "params": append_to_log(log, &revealed) }))?,
+    )?;
+
+    let scanner_rid = format!(
+        "decs.components.{}.{}.{}",
+        shard,
+        entity_id,
+        super::SURVEY_SCANNER
+    );
+    ctx.msg().publish(
+        &format!("call.{}.delete", scanner_rid),
+        None,
+        &serde_json::to_vec(&serde_json::json!({
+            "params": {
+                "rid": scanner_rid
+            }
+        }))?,
+    )?;
+
+    Ok(())
+}
+
+/// Reduces the time remaining on the scan by the elapsed time
+fn advance_scan(scanner: SurveyScanner, elapsed_ms: u32) -> SurveyScanner {
+    let remaining = scanner.scan_duration_remaining_ms - f64::from(elapsed_ms);
+    SurveyScanner {
+        scan_duration_remaining_ms: remaining.max(0.0),
+        ..scanner
+    }
+}
+
+/// Returns the IDs of all entities (other than the scanner itself) within the scan radius
+fn entities_in_range(
+    entity_id: &str,
+    position: &Position,
+    radius: f64,
+    all_positions: &HashMap<String, Position>,
+) -> Vec<String> {
+    let mut in_range: Vec<String> = all_positions
+        .iter()
+        .filter(|(ent_id, pos)| {
+            *ent_id != entity_id && within_radius(position, pos, Distance(radius))
+        })
+        .map(|(ent_id, _)| ent_id.to_string())
+        .collect();
+    in_range.sort();
+    in_range
+}
+
+/// Returns the IDs of the hidden resources that haven't already been revealed
+fn resources_to_reveal(hidden: &HashMap<String, HiddenResource>) -> Vec<String> {
+    let mut revealed: Vec<String> = hidden
+        .iter()
+        .filter(|(_, hr)| !hr.revealed)
+        .map(|(ent_id, _)| ent_id.to_string())
+        .collect();
+    revealed.sort();
+    revealed
+}
+
+fn append_to_log(log: SurveyLog, revealed: &[String]) -> SurveyLog {
+    let mut entries = log.revealed;
+    for ent_id in revealed {
+        if !entries.contains(ent_id) {
+            entries.push(ent_id.to_string());
+        }
+    }
+    SurveyLog { revealed: entries }
+}
+
+#[cfg(test)]
+mod test {
+    use super::advance_scan;
+    use super::append_to_log;
+    use super::entities_in_range;
+    use super::resources_to_reveal;
+    use super::HashMap;
+    use super::HiddenResource;
+    use super::Position;
+    use super::SurveyLog;
+    use super::SurveyScanner;
+
+    fn scanner(remaining_ms: f64) -> SurveyScanner {
+        SurveyScanner {
+            scan_radius: 10.0,
+            scan_duration_remaining_ms: remaining_ms,
+            shard: "the_void".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_scan_partial_completion() {
+        let scan = advance_scan(scanner(2500.0), 1000);
+        assert_eq!(scan.scan_duration_remaining_ms, 1500.0);
+        let scan = advance_scan(scan, 1000);
+        assert_eq!(scan.scan_duration_remaining_ms, 500.0);
+        let scan = advance_scan(scan, 1000);
+        assert_eq!(scan.scan_duration_remaining_ms, 0.0);
+        assert_eq!(scan.scan_radius, 10.0);
+    }
+
+    #[test]
+    fn test_entities_in_range_boundary() {
+        let origin = Position::new(0.0, 0.0, 0.0);
+        let mut all_positions = HashMap::new();
+        all_positions.insert("scanner".to_string(), origin);
+        all_positions.insert("inside".to_string(), Position::new(3.0, 4.0, 0.0));
+        all_positions.insert("on_edge".to_string(), Position::new(0.0, 0.0, 10.0));
+        all_positions.insert("just_outside".to_string(), Position::new(0.0, 10.001, 0.0));
+        all_positions.insert("far_away".to_string(), Position::new(500.0, 0.0, 0.0));
+
+        let in_range = entities_in_range("scanner", &origin, 10.0, &all_positions);
+        assert_eq!(in_range, vec!["inside".to_string(), "on_edge".to_string()]);
+    }
+
+    #[test]
+    fn test_only_unrevealed_resources_are_revealed() {
+        let mut hidden = HashMap::new();
+        hidden.insert("asteroid_2".to_string(), HiddenResource { revealed: false });
+        hidden.insert("asteroid_1".to_string(), HiddenResource { revealed: false });
+        hidden.insert("asteroid_3".to_string(), HiddenResource { revealed: true });
+
+        assert_eq!(
+            resources_to_reveal(&hidden),
+            vec!["asteroid_1".to_string(), "asteroid_2".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_survey_log_appends_without_duplicates() {
+        let log = SurveyLog {
+            revealed: vec!["asteroid_1".to_string()],
+        };
+        let log = append_to_log(log, &["asteroid_1".to_string(), "asteroid_7".to_string()]);
+
+        assert_eq!(
+            log.revealed,
+            vec!["asteroid_1".to_string(), "asteroid_7".to_string()]
+        );
+    }
+}
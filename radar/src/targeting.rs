@@ -0,0 +1,293 @@
+//! # Targeting
+//!
+//! The targeting system awaits frames for entities that have a `targeting_computer` component.
+//! Each frame it looks for detectable entities within the computer's `scan_radius` whose faction is
+//! hostile to the scanning entity's faction and selects the closest one, preferring hostiles whose
+//! transponder matches the computer's `preferred_target_type`. When the selection changes, the
+//! computer's `current_target` is updated and the entity's `target` component is pointed at the new
+//! target (or deleted when there is nothing left to target).
+use crate::radar::{cached_positions, is_detectable, within_radius};
+use guest::prelude::*;
+use stacktrader_types as trader;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use trader::components::*;
+use trader::units::Distance;
+
+const FACTION_ID: &str = "faction_id";
+const TARGET: &str = "target";
+const TRANSPONDER: &str = "transponder";
+
+lazy_static! {
+    // shard -> entity ID -> faction
+    static ref FACTION_CACHE: RwLock<HashMap<String, HashMap<String, FactionId>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// A hostile entity that could be selected as a target
+#[derive(Debug, Clone)]
+struct Candidate {
+    entity_id: String,
+    position: Position,
+    object_type: Option<String>,
+}
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let computer_value = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard,
+        frame.entity_id,
+        super::TARGETING_COMPUTER
+    ))?;
+    let position_value = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard,
+        frame.entity_id,
+        super::POSITION
+    ))?;
+
+    if let (Some(computer_str), Some(position_str)) = (computer_value, position_value) {
+        let computer: TargetingComputer = serde_json::from_str(&computer_str)?;
+        let position: Position = serde_json::from_str(&position_str)?;
+        // An entity without a faction has nobody to be hostile to
+        let faction = match faction_for(ctx, &frame.shard, &frame.entity_id)? {
+            Some(f) => f,
+            None => return Ok(vec![]),
+        };
+
+        let mut candidates = Vec::new();
+        for (ent_id, pos) in cached_positions(ctx, &frame.shard)? {
+            if ent_id == frame.entity_id
+                || !within_radius(&position, &pos, Distance(computer.scan_radius))
+            {
+                continue;
+            }
+            let hostile = faction_for(ctx, &frame.shard, &ent_id)?
+                .map(|f| faction.is_hostile_to(&f))
+                .unwrap_or(false);
+            if hostile && is_detectable(ctx, &frame.shard, &ent_id) {
+                candidates.push(Candidate {
+                    object_type: object_type(ctx, &frame.shard, &ent_id),
+                    entity_id: ent_id,
+                    position: pos,
+                });
+            }
+        }
+
+        let selected = select_target(
+            &position,
+            computer.scan_radius,
+            &candidates,
+            computer.preferred_target_type.as_deref(),
+        );
+        if selected.as_ref().map(|c| &c.entity_id) != computer.current_target.as_ref() {
+            publish_selection(
+                ctx,
+                &frame.shard,
+                &frame.entity_id,
+                &position,
+                computer,
+                selected,
+            )?;
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// Publishes the computer's new `current_target` and points the entity's `target` at it
+fn publish_selection(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    position: &Position,
+    computer: TargetingComputer,
+    selected: Option<&Candidate>,
+) -> Result<()> {
+    let computer = TargetingComputer {
+        current_target: selected.map(|c| c.entity_id.to_string()),
+        ..computer
+    };
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard,
+            entity_id,
+            super::TARGETING_COMPUTER
+        ),
+        None,
+        &serde_json::to_vec(&serde_json::json!({ "params": computer }))?,
+    )?;
+
+    let target_rid = format!("decs.components.{}.{}.{}", shard, entity_id, TARGET);
+    match selected {
+        Some(candidate) => {
+            // The navigation system keeps the ETA and distance up to date from here on
+            let target = Target {
+                rid: format!("decs.components.{}.{}", shard, candidate.entity_id),
+                eta_ms: 0.0,
+                distance_km: position.distance_to_3d(&candidate.position),
+            };
+            ctx.msg().publish(
+                &format!("call.{}.set", target_rid),
+                None,
+                &serde_json::to_vec(&serde_json::json!({ "params": target }))?,
+            )
+        }
+        None => ctx.msg().publish(
+            &format!("call.{}.delete", target_rid),
+            None,
+            &serde_json::to_vec(&serde_json::json!({
+                "params": {
+                    "rid": target_rid
+                }
+            }))?,
+        ),
+    }
+}
+
+/// Retrieve an entity's faction from the cache, loading it from the KV store if it isn't
+/// cached yet. Factions are assumed not to change once they have been assigned
+pub(crate) fn faction_for(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+) -> Result<Option<FactionId>> {
+    if let Some(faction) = FACTION_CACHE
+        .read()
+        .unwrap()
+        .get(shard)
+        .and_then(|factions| factions.get(entity_id))
+    {
+        return Ok(Some(faction.clone()));
+    }
+
+    match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, FACTION_ID
+    ))? {
+        Some(faction_str) => {
+            let faction: FactionId = serde_json::from_str(&faction_str)?;
+            FACTION_CACHE
+                .write()
+                .unwrap()
+                .entry(shard.to_string())
+                .or_default()
+                .insert(entity_id.to_string(), faction.clone());
+            Ok(Some(faction))
+        }
+        None => Ok(None),
+    }
+}
+
+fn object_type(ctx: &CapabilitiesContext, shard: &str, entity_id: &str) -> Option<String> {
+    match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, TRANSPONDER
+    )) {
+        Ok(Some(tp_str)) => serde_json::from_str::<RadarTransponder>(&tp_str)
+            .ok()
+            .map(|tp| tp.object_type),
+        _ => None,
+    }
+}
+
+/// Selects the closest candidate within the scan radius. If any candidates in range are of the
+/// preferred type, the closest of those is selected instead
+fn select_target<'a>(
+    position: &Position,
+    scan_radius: f64,
+    candidates: &'a [Candidate],
+    preferred_target_type: Option<&str>,
+) -> Option<&'a Candidate> {
+    let in_range: Vec<&Candidate> = candidates
+        .iter()
+        .filter(|c| within_radius(position, &c.position, Distance(scan_radius)))
+        .collect();
+    let preferred: Vec<&Candidate> = in_range
+        .iter()
+        .filter(|c| {
+            preferred_target_type.is_some() && c.object_type.as_deref() == preferred_target_type
+        })
+        .cloned()
+        .collect();
+    let pool = if preferred.is_empty() {
+        in_range
+    } else {
+        preferred
+    };
+
+    pool.into_iter().min_by(|a, b| {
+        position
+            .distance_to_3d(&a.position)
+            .partial_cmp(&position.distance_to_3d(&b.position))
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.entity_id.cmp(&b.entity_id))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::select_target;
+    use super::Candidate;
+    use super::FactionId;
+    use super::Position;
+
+    fn candidate(entity_id: &str, x: f64, object_type: &str) -> Candidate {
+        Candidate {
+            entity_id: entity_id.to_string(),
+            position: Position::new(x, 0.0, 0.0),
+            object_type: Some(object_type.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_nearest_hostile_selected_until_out_of_range() {
+        let origin = Position::new(0.0, 0.0, 0.0);
+        let mut hostiles = vec![
+            candidate("pirate_far", 40.0, "ship"),
+            candidate("pirate_near", 10.0, "ship"),
+        ];
+
+        let selected = select_target(&origin, 50.0, &hostiles, None);
+        assert_eq!(selected.unwrap().entity_id, "pirate_near");
+
+        // The nearer hostile flies out of range
+        hostiles[1].position = Position::new(75.0, 0.0, 0.0);
+        let selected = select_target(&origin, 50.0, &hostiles, None);
+        assert_eq!(selected.unwrap().entity_id, "pirate_far");
+
+        hostiles[0].position = Position::new(0.0, 60.0, 0.0);
+        assert!(select_target(&origin, 50.0, &hostiles, None).is_none());
+    }
+
+    #[test]
+    fn test_preferred_target_type_wins_over_distance() {
+        let origin = Position::new(0.0, 0.0, 0.0);
+        let hostiles = vec![
+            candidate("fighter", 5.0, "ship"),
+            candidate("outpost", 30.0, "starbase"),
+        ];
+
+        let selected = select_target(&origin, 50.0, &hostiles, Some("starbase"));
+        assert_eq!(selected.unwrap().entity_id, "outpost");
+        let selected = select_target(&origin, 50.0, &hostiles, Some("asteroid"));
+        assert_eq!(selected.unwrap().entity_id, "fighter");
+    }
+
+    #[test]
+    fn test_faction_hostility() {
+        let miners = FactionId {
+            faction: "miners".to_string(),
+        };
+        let pirates = FactionId {
+            faction: "pirates".to_string(),
+        };
+
+        assert!(miners.is_hostile_to(&pirates));
+        assert!(!miners.is_hostile_to(&miners.clone()));
+    }
+}
@@ -0,0 +1,232 @@
+//! # Turret
+//!
+//! The turret system awaits frames for entities that have a `turret` component. When the turret
+//! has a `target_entity`, it is rotated toward that entity's position by at most
+//! `turn_rate_rad_per_ms * elapsed_ms` on each axis and the updated turret is published. Once the
+//! turret is within `AIM_THRESHOLD_RAD` of the target and `fire_when_aimed` is set, a fire event is
+//! published on `event.decs.system.turret.fired`. Aiming costs the ship the energy of its
+//! `weapons` system, see `energy`. Each shot adds the turret's `heat_per_shot` to the entity's
+//! `thermal_profile`, if it has one.
+use crate::energy::draw_frame_energy;
+use guest::prelude::*;
+use stacktrader_types as trader;
+use std::f64::consts::PI;
+use trader::components::*;
+use trader::energy;
+use trader::thermal;
+
+const AIM_THRESHOLD_RAD: f64 = 0.01;
+const TURRET_FIRED_EVENT: &str = "event.decs.system.turret.fired";
+const THERMAL_PROFILE: &str = "thermal_profile";
+
+pub(crate) fn handle_frame(ctx: &CapabilitiesContext, msg: messaging::BrokerMessage) -> CallResult {
+    let frame: decs::systemmgr::EntityFrame = serde_json::from_slice(&msg.body)?;
+
+    let turret_value = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard,
+        frame.entity_id,
+        super::TURRET
+    ))?;
+    let position_value = ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        frame.shard,
+        frame.entity_id,
+        super::POSITION
+    ))?;
+
+    if let (Some(turret_str), Some(position_str)) = (turret_value, position_value) {
+        let turret: Turret = serde_json::from_str(&turret_str)?;
+        let position: Position = serde_json::from_str(&position_str)?;
+        let target_entity = match turret.target_entity {
+            Some(ref t) => t.to_string(),
+            None => return Ok(vec![]),
+        };
+        let target_value = ctx.kv().get(&format!(
+            "decs:components:{}:{}:{}",
+            frame.shard,
+            target_entity,
+            super::POSITION
+        ))?;
+        // A target without a position (e.g. destroyed) leaves the turret where it is
+        let target_position: Position = match target_value {
+            Some(s) => serde_json::from_str(&s)?,
+            None => return Ok(vec![]),
+        };
+        if !draw_frame_energy(
+            ctx,
+            &frame.shard,
+            &frame.entity_id,
+            energy::WEAPONS,
+            frame.elapsed_ms,
+        )? {
+            return Ok(vec![]);
+        }
+
+        let (azimuth, elevation) = required_angles(&position, &target_position);
+        let turret = aim_turret(turret, azimuth, elevation, frame.elapsed_ms);
+        ctx.msg().publish(
+            &format!(
+                "call.decs.components.{}.{}.{}.set",
+                frame.shard,
+                frame.entity_id,
+                super::TURRET
+            ),
+            None,
+            &serde_json::to_vec(&serde_json::json!({ "params": turret }))?,
+        )?;
+
+        if turret.fire_when_aimed && is_aimed(&turret, azimuth, elevation) {
+            ctx.msg().publish(
+                TURRET_FIRED_EVENT,
+                None,
+                &serde_json::to_vec(&serde_json::json!({
+                    "shard": frame.shard,
+                    "entity": frame.entity_id,
+                    "target": target_entity,
+                }))?,
+            )?;
+            add_shot_heat(ctx, &frame.shard, &frame.entity_id, turret.heat_per_shot)?;
+        }
+    }
+
+    Ok(vec![])
+}
+
+/// Adds the heat of a shot to the entity's thermal profile, if it has one. The thermal system
+/// dissipates it and deals with overheating in its own frames
+fn add_shot_heat(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    entity_id: &str,
+    heat_per_shot: f64,
+) -> Result<()> {
+    if heat_per_shot <= 0.0 {
+        return Ok(());
+    }
+    let profile: ThermalProfile = match ctx.kv().get(&format!(
+        "decs:components:{}:{}:{}",
+        shard, entity_id, THERMAL_PROFILE
+    ))? {
+        Some(s) => serde_json::from_str(&s)?,
+        None => return Ok(()),
+    };
+    let turret_rid = format!("decs.components.{}.{}.{}", shard, entity_id, super::TURRET);
+    let profile = thermal::add_heat(&profile, &turret_rid, heat_per_shot);
+    ctx.msg().publish(
+        &format!(
+            "call.decs.components.{}.{}.{}.set",
+            shard, entity_id, THERMAL_PROFILE
+        ),
+        None,
+        &serde_json::to_vec(&serde_json::json!({ "params": profile }))?,
+    )
+}
+
+/// Azimuth and elevation (in radians) required to point from `position` at `target`
+fn required_angles(position: &Position, target: &Position) -> (f64, f64) {
+    let vector = position.vector_to(target);
+    (vector.azimuth.to_radians(), vector.elevation.to_radians())
+}
+
+/// Rotates the turret toward the required angles, limited by its turn rate
+fn aim_turret(turret: Turret, azimuth: f64, elevation: f64, elapsed_ms: u32) -> Turret {
+    let max_step = turret.turn_rate_rad_per_ms * f64::from(elapsed_ms);
+    let azimuth_step = clamp_step(azimuth_error(turret.current_azimuth, azimuth), max_step);
+    let elevation_step = clamp_step(elevation - turret.current_elevation, max_step);
+    Turret {
+        current_azimuth: normalize_angle(turret.current_azimuth + azimuth_step),
+        current_elevation: turret.current_elevation + elevation_step,
+        ..turret
+    }
+}
+
+fn is_aimed(turret: &Turret, azimuth: f64, elevation: f64) -> bool {
+    let azimuth_error = azimuth_error(turret.current_azimuth, azimuth);
+    let elevation_error = elevation - turret.current_elevation;
+    azimuth_error.hypot(elevation_error) < AIM_THRESHOLD_RAD
+}
+
+/// Signed shortest rotation from `current` to `target`, so the turret never turns the long way around
+fn azimuth_error(current: f64, target: f64) -> f64 {
+    normalize_angle(target - current)
+}
+
+fn clamp_step(error: f64, max_step: f64) -> f64 {
+    error.clamp(-max_step, max_step)
+}
+
+/// Wraps an angle into the range (-PI, PI]
+fn normalize_angle(angle: f64) -> f64 {
+    let wrapped = (angle + PI).rem_euclid(2.0 * PI) - PI;
+    if wrapped <= -PI {
+        wrapped + 2.0 * PI
+    } else {
+        wrapped
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::aim_turret;
+    use super::is_aimed;
+    use super::normalize_angle;
+    use super::required_angles;
+    use super::Position;
+    use super::Turret;
+    use super::PI;
+    use stacktrader_types::assert_approx_eq;
+
+    fn turret(turn_rate_rad_per_ms: f64) -> Turret {
+        Turret {
+            current_azimuth: 0.0,
+            current_elevation: PI / 2.0,
+            turn_rate_rad_per_ms,
+            target_entity: Some("asteroid_1".to_string()),
+            fire_when_aimed: true,
+            heat_per_shot: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_stationary_target_aimed_in_n_frames() {
+        // The target is a quarter turn away in azimuth, at 1/8th of a turn per frame it takes 4 frames
+        let (azimuth, elevation) = required_angles(
+            &Position::new(0.0, 0.0, 0.0),
+            &Position::new(0.0, 10.0, 0.0),
+        );
+        assert_approx_eq!(azimuth, PI / 2.0);
+        assert_approx_eq!(elevation, PI / 2.0);
+
+        let mut t = turret(PI / 8.0 / 1000.0);
+        for _ in 0..3 {
+            t = aim_turret(t, azimuth, elevation, 1000);
+            assert!(!is_aimed(&t, azimuth, elevation));
+        }
+        t = aim_turret(t, azimuth, elevation, 1000);
+        assert!(is_aimed(&t, azimuth, elevation));
+        assert_approx_eq!(t.current_azimuth, PI / 2.0);
+    }
+
+    #[test]
+    fn test_turret_does_not_overshoot() {
+        let t = aim_turret(turret(1.0), 0.5, PI / 2.0 - 0.25, 1000);
+        assert_approx_eq!(t.current_azimuth, 0.5);
+        assert_approx_eq!(t.current_elevation, PI / 2.0 - 0.25);
+    }
+
+    #[test]
+    fn test_turret_turns_the_short_way_around() {
+        let mut t = turret(0.1 / 1000.0);
+        t.current_azimuth = PI - 0.05;
+        let t = aim_turret(t, -PI + 0.05, PI / 2.0, 1000);
+        assert_approx_eq!(t.current_azimuth, -PI + 0.05);
+    }
+
+    #[test]
+    fn test_normalize_angle() {
+        assert_approx_eq!(normalize_angle(3.0 * PI / 2.0), -PI / 2.0);
+        assert_approx_eq!(normalize_angle(-PI), PI);
+        assert_approx_eq!(normalize_angle(0.25), 0.25);
+    }
+}
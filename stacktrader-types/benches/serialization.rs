@@ -0,0 +1,52 @@
+//! JSON vs. bincode round trips of the high-churn components, 10k at a time.
+//! Run with `cargo bench -p stacktrader-types`
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use decscloud_common::gateway::ResourceIdentifier;
+use stacktrader_types::components::{Position, RadarContact};
+use stacktrader_types::units::Distance;
+use stacktrader_types::util::{deserialize_component, serialize_component, SerializationMode};
+
+const ITERATIONS: usize = 10_000;
+
+fn round_trips<T>(val: &T, mode: SerializationMode)
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    for _ in 0..ITERATIONS {
+        let bytes = serialize_component(black_box(val), mode).unwrap();
+        let _: T = deserialize_component(black_box(&bytes), mode).unwrap();
+    }
+}
+
+fn position_round_trip(c: &mut Criterion) {
+    let position = Position::new(1234.5678, -2345.6789, 3456.789);
+    c.bench_function("position json x10k", |b| {
+        b.iter(|| round_trips(&position, SerializationMode::Json))
+    });
+    c.bench_function("position bincode x10k", |b| {
+        b.iter(|| round_trips(&position, SerializationMode::Bincode))
+    });
+}
+
+fn radar_contact_round_trip(c: &mut Criterion) {
+    let contact = RadarContact {
+        entity_id: "asteroid1".to_string(),
+        distance: Distance(1200.0),
+        distance_xy: 1100,
+        azimuth: 45.5,
+        elevation: -3.25,
+        transponder: ResourceIdentifier {
+            rid: "decs.components.the_void.asteroid1.transponder".to_string(),
+        },
+        cross_shard: false,
+    };
+    c.bench_function("radar_contact json x10k", |b| {
+        b.iter(|| round_trips(&contact, SerializationMode::Json))
+    });
+    c.bench_function("radar_contact bincode x10k", |b| {
+        b.iter(|| round_trips(&contact, SerializationMode::Bincode))
+    });
+}
+
+criterion_group!(benches, position_round_trip, radar_contact_round_trip);
+criterion_main!(benches);
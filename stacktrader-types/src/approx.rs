@@ -0,0 +1,124 @@
+//! Approximate equality for floating-point results. Positions and vectors are computed through
+//! square roots and trigonometry, so two results that are meant to be the same often differ in
+//! their last bits and exact comparison is brittle.
+//!
+//! Comparisons are absolute: two values are approximately equal when they differ by at most
+//! `epsilon`, boundary included, whatever their magnitude. `DEFAULT_EPSILON` suits the kilometre
+//! and degree values of the game. Values that are exactly equal, infinities included, are always
+//! approximately equal, and NaN never is.
+
+use crate::components::{Position, TargetVector};
+
+/// The epsilon `assert_approx_eq!` uses when none is given
+pub const DEFAULT_EPSILON: f64 = 1e-9;
+
+/// Equality within an absolute `epsilon`
+pub trait ApproxEq {
+    fn approx_eq(&self, other: &Self, epsilon: f64) -> bool;
+}
+
+impl ApproxEq for f64 {
+    fn approx_eq(&self, other: &f64, epsilon: f64) -> bool {
+        self == other || (self - other).abs() <= epsilon
+    }
+}
+
+/// Every coordinate is within `epsilon` of the other's
+impl ApproxEq for Position {
+    fn approx_eq(&self, other: &Position, epsilon: f64) -> bool {
+        self.x.approx_eq(&other.x, epsilon)
+            && self.y.approx_eq(&other.y, epsilon)
+            && self.z.approx_eq(&other.z, epsilon)
+    }
+}
+
+/// The unit vector and the angles are within `epsilon` of the other's. The distances are
+/// already rounded to the kilometre, so they must be equal. Angles are compared as numbers, so
+/// an azimuth of -180 degrees isn't equal to one of 180
+impl ApproxEq for TargetVector {
+    fn approx_eq(&self, other: &TargetVector, epsilon: f64) -> bool {
+        self.mag == other.mag
+            && self.distance_xy == other.distance_xy
+            && self.ux.approx_eq(&other.ux, epsilon)
+            && self.uy.approx_eq(&other.uy, epsilon)
+            && self.uz.approx_eq(&other.uz, epsilon)
+            && self.azimuth.approx_eq(&other.azimuth, epsilon)
+            && self.elevation.approx_eq(&other.elevation, epsilon)
+    }
+}
+
+/// Asserts that two values are approximately equal, within `DEFAULT_EPSILON` unless an epsilon
+/// is given
+///
+/// ```ignore
+/// assert_approx_eq!(waypoint.position, Position::new(1010.0, 0.0, 0.0));
+/// assert_approx_eq!(azimuth, 90.0, 1e-6);
+/// ```
+#[cfg(feature = "test-util")]
+#[macro_export]
+macro_rules! assert_approx_eq {
+    ($left:expr, $right:expr) => {
+        $crate::assert_approx_eq!($left, $right, $crate::approx::DEFAULT_EPSILON)
+    };
+    ($left:expr, $right:expr, $epsilon:expr) => {
+        match (&$left, &$right, $epsilon) {
+            (left, right, epsilon) => {
+                if !$crate::approx::ApproxEq::approx_eq(left, right, epsilon) {
+                    panic!(
+                        "assertion failed: `(left ≈ right)`\n  left: `{:?}`,\n right: `{:?}`,\n epsilon: `{:?}`",
+                        left, right, epsilon
+                    )
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::ApproxEq;
+    use super::DEFAULT_EPSILON;
+    use crate::components::Position;
+
+    #[test]
+    fn epsilon_is_absolute_and_inclusive() {
+        // Differences that are exact in binary
+        assert!(1.0.approx_eq(&1.5, 0.5));
+        assert!(1.5.approx_eq(&1.0, 0.5));
+        assert!(!1.0.approx_eq(&1.5000001, 0.5));
+        assert!(0.0.approx_eq(&DEFAULT_EPSILON, DEFAULT_EPSILON));
+        assert!(!0.0.approx_eq(&(DEFAULT_EPSILON * 2.0), DEFAULT_EPSILON));
+        // Large values get no more room than small ones
+        assert!(!1e12.approx_eq(&(1e12 + 1e-3), DEFAULT_EPSILON));
+        assert!(0.0.approx_eq(&0.0, 0.0));
+    }
+
+    #[test]
+    fn infinities_are_equal_and_nan_never_is() {
+        assert!(f64::INFINITY.approx_eq(&f64::INFINITY, DEFAULT_EPSILON));
+        assert!(!f64::INFINITY.approx_eq(&f64::NEG_INFINITY, DEFAULT_EPSILON));
+        assert!(!f64::NAN.approx_eq(&f64::NAN, DEFAULT_EPSILON));
+        assert!(!f64::NAN.approx_eq(&0.0, f64::INFINITY));
+    }
+
+    #[test]
+    fn vectors_compare_every_field() {
+        let a = Position::new(1.0, 2.0, 3.0);
+        assert!(a.approx_eq(&Position::new(1.0, 2.0, 3.0 + 1e-10), DEFAULT_EPSILON));
+        assert!(!a.approx_eq(&Position::new(1.0, 2.0 + 1e-8, 3.0), DEFAULT_EPSILON));
+        assert!(!a.approx_eq(&Position::new(1.0 - 1e-8, 2.0, 3.0), DEFAULT_EPSILON));
+
+        // The same direction reached two ways
+        let origin = Position::new(0.0, 0.0, 0.0);
+        let vector = origin.vector_to(&Position::new(0.1 + 0.2, 0.3, 0.0));
+        let other = origin.vector_to(&Position::new(0.3, 0.3, 0.0));
+        assert!(vector.approx_eq(&other, DEFAULT_EPSILON));
+        assert!(!vector.approx_eq(
+            &origin.vector_to(&Position::new(0.3, 0.3001, 0.0)),
+            DEFAULT_EPSILON
+        ));
+        // Distances are whole kilometres and must match
+        let far = origin.vector_to(&Position::new(3.0, 3.0, 0.0));
+        assert!(!vector.approx_eq(&far, 1.0));
+    }
+}
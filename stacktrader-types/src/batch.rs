@@ -0,0 +1,123 @@
+//! Transactional batches. Rather than publishing each of the calls and events a change takes
+//! separately, a system can assemble them into one `BatchRequest` and publish it on
+//! `call.decs.batch.{shard}`. A batch-aware component manager applies the operations in order,
+//! all of them or none, so a failure partway can't leave the shard half changed.
+
+use serde_json::Value;
+
+/// Published on `call.decs.batch.{shard}` with the operations to apply, in order
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct BatchRequest {
+    pub shard: String,
+    pub operations: Vec<BatchOperation>,
+}
+
+/// A call or event of a batch, as it would have been published on its own
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct BatchOperation {
+    pub subject: String,
+    pub payload: Value,
+}
+
+impl BatchRequest {
+    /// A batch of the messages (subject and payload) for the shard
+    pub fn new(shard: &str, messages: Vec<(String, Value)>) -> Self {
+        BatchRequest {
+            shard: shard.to_string(),
+            operations: messages
+                .into_iter()
+                .map(|(subject, payload)| BatchOperation { subject, payload })
+                .collect(),
+        }
+    }
+
+    /// The subject the batch is published on
+    pub fn subject(&self) -> String {
+        format!("call.decs.batch.{}", self.shard)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BatchOperation;
+    use super::BatchRequest;
+    use serde_json::json;
+
+    fn batch() -> BatchRequest {
+        BatchRequest::new(
+            "the_void",
+            vec![
+                (
+                    "call.decs.components.the_void.asteroid1.mining_resource.delete".to_string(),
+                    json!({ "params": { "rid": "decs.components.the_void.asteroid1.mining_resource" } }),
+                ),
+                (
+                    "event.decs.the_void.player1.mining.completed".to_string(),
+                    json!({ "qty": 7 }),
+                ),
+            ],
+        )
+    }
+
+    #[test]
+    fn batch_keeps_its_operations_in_order() {
+        let batch = batch();
+        assert_eq!(batch.subject(), "call.decs.batch.the_void");
+        assert_eq!(
+            batch.operations[1],
+            BatchOperation {
+                subject: "event.decs.the_void.player1.mining.completed".to_string(),
+                payload: json!({ "qty": 7 }),
+            }
+        );
+    }
+
+    #[test]
+    fn batch_serialization() {
+        assert_eq!(
+            serde_json::to_value(batch()).unwrap(),
+            json!({
+                "shard": "the_void",
+                "operations": [
+                    {
+                        "subject": "call.decs.components.the_void.asteroid1.mining_resource.delete",
+                        "payload": { "params": { "rid": "decs.components.the_void.asteroid1.mining_resource" } }
+                    },
+                    {
+                        "subject": "event.decs.the_void.player1.mining.completed",
+                        "payload": { "qty": 7 }
+                    }
+                ]
+            })
+        );
+        let bytes = serde_json::to_vec(&batch()).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<BatchRequest>(&bytes).unwrap(),
+            batch()
+        );
+    }
+
+    #[test]
+    fn empty_batch_serialization() {
+        let empty = BatchRequest::new("the_void", vec![]);
+        assert_eq!(
+            serde_json::to_string(&empty).unwrap(),
+            r#"{"shard":"the_void","operations":[]}"#
+        );
+    }
+
+    #[test]
+    fn incomplete_batch_is_refused() {
+        assert!(serde_json::from_str::<BatchRequest>(r#"{"operations": []}"#).is_err());
+        assert!(serde_json::from_str::<BatchRequest>(
+            r#"{"shard": "the_void", "operations": [{"payload": {}}]}"#
+        )
+        .is_err());
+        // Any JSON can be a payload
+        let batch: BatchRequest = serde_json::from_str(
+            r#"{"shard": "the_void", "operations": [{"subject": "a", "payload": null}]}"#,
+        )
+        .unwrap();
+        assert_eq!(batch.operations[0].payload, serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,265 @@
+//! Timed buffs. A buff multiplies one of an entity's stats until the game time in its
+//! `expires_at_ms`. Buffs are kept in memory, in `ACTIVE_BUFFS`, by the actors hosting the
+//! systems whose stats they modify, and each of those systems checks them whenever it computes
+//! the stat. Buffs on the same stat combine by their `stack_behavior`: the bonuses of additive
+//! buffs are summed, multiplicative buffs multiply, and of the buffs taking the highest only the
+//! strongest counts. The three are then multiplied together.
+//!
+//! Like every game time, `expires_at_ms` is only comparable within the system checking the buff,
+//! see `clock`.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Published for every buff that runs out
+pub const BUFF_EXPIRED_EVENT: &str = "event.decs.system.buff.expired";
+
+lazy_static! {
+    // (shard, entity) -> the entity's buffs, expired ones included until they are cleared
+    static ref ACTIVE_BUFFS: RwLock<HashMap<(String, String), Vec<TimedBuff>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// The stats a buff can modify
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum StatTarget {
+    RadarRange,
+    MiningEfficiency,
+    Speed,
+    Shield,
+}
+
+/// How a buff combines with the other buffs on the same stat
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum StackBehavior {
+    Additive,
+    #[default]
+    Multiplicative,
+    TakeHighest,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TimedBuff {
+    pub id: String, // Applying a buff with the ID of an active one replaces it
+    pub stat: StatTarget,
+    pub multiplier: f64,
+    pub expires_at_ms: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub stack_behavior: StackBehavior,
+}
+
+impl TimedBuff {
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        now_ms >= self.expires_at_ms
+    }
+}
+
+/// Adds the buff to the entity's active buffs, replacing any buff with the same ID
+pub fn apply_buff(shard: &str, entity_id: &str, buff: TimedBuff) {
+    let mut active = ACTIVE_BUFFS.write().unwrap();
+    let buffs = active
+        .entry((shard.to_string(), entity_id.to_string()))
+        .or_default();
+    buffs.retain(|b| b.id != buff.id);
+    buffs.push(buff);
+}
+
+/// The factor the entity's unexpired buffs multiply the stat by, 1.0 without any
+pub fn buff_multiplier(shard: &str, entity_id: &str, stat: StatTarget, now_ms: u64) -> f64 {
+    match ACTIVE_BUFFS
+        .read()
+        .unwrap()
+        .get(&(shard.to_string(), entity_id.to_string()))
+    {
+        Some(buffs) => combine(buffs, stat, now_ms),
+        None => 1.0,
+    }
+}
+
+/// Removes the entity's expired buffs, returning the subject and payload announcing each of them
+#[cfg(feature = "json")]
+pub fn plan_buff_expiry(
+    shard: &str,
+    entity_id: &str,
+    now_ms: u64,
+) -> Vec<(String, serde_json::Value)> {
+    let mut active = ACTIVE_BUFFS.write().unwrap();
+    let key = (shard.to_string(), entity_id.to_string());
+    let buffs = match active.get_mut(&key) {
+        Some(buffs) => buffs,
+        None => return vec![],
+    };
+    let (expired, remaining): (Vec<TimedBuff>, Vec<TimedBuff>) =
+        buffs.drain(..).partition(|b| b.is_expired(now_ms));
+    if remaining.is_empty() {
+        active.remove(&key);
+    } else {
+        *buffs = remaining;
+    }
+
+    expired
+        .into_iter()
+        .map(|buff| {
+            (
+                BUFF_EXPIRED_EVENT.to_string(),
+                serde_json::json!({
+                    "shard": shard,
+                    "entity_id": entity_id,
+                    "id": buff.id,
+                    "stat": buff.stat
+                }),
+            )
+        })
+        .collect()
+}
+
+/// Combines the unexpired buffs on the stat into a single multiplier
+pub fn combine(buffs: &[TimedBuff], stat: StatTarget, now_ms: u64) -> f64 {
+    let active = buffs
+        .iter()
+        .filter(|b| b.stat == stat && !b.is_expired(now_ms));
+    let (mut additive, mut multiplicative, mut highest) = (1.0, 1.0, None::<f64>);
+    for buff in active {
+        match buff.stack_behavior {
+            StackBehavior::Additive => additive += buff.multiplier - 1.0,
+            StackBehavior::Multiplicative => multiplicative *= buff.multiplier,
+            StackBehavior::TakeHighest => {
+                highest = Some(highest.map_or(buff.multiplier, |h| h.max(buff.multiplier)))
+            }
+        }
+    }
+    additive * multiplicative * highest.unwrap_or(1.0)
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "json")]
+    use super::plan_buff_expiry;
+    use super::{apply_buff, buff_multiplier, combine};
+    use super::{StackBehavior, StatTarget, TimedBuff};
+
+    const EPSILON: f64 = 1e-9;
+
+    fn buff(id: &str, stat: StatTarget, multiplier: f64, behavior: StackBehavior) -> TimedBuff {
+        TimedBuff {
+            id: id.to_string(),
+            stat,
+            multiplier,
+            expires_at_ms: 10_000,
+            stack_behavior: behavior,
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_buff_lasts_until_it_expires() {
+        // Every test uses entities of its own, the buffs are shared by all of them
+        let haste = buff(
+            "haste",
+            StatTarget::Speed,
+            1.5,
+            StackBehavior::Multiplicative,
+        );
+        apply_buff("the_void", "expiring", haste);
+
+        assert_eq!(
+            buff_multiplier("the_void", "expiring", StatTarget::Speed, 9_999),
+            1.5
+        );
+        assert!(plan_buff_expiry("the_void", "expiring", 9_999).is_empty());
+        // At its expiry time the buff no longer counts, even before it has been cleared
+        assert_eq!(
+            buff_multiplier("the_void", "expiring", StatTarget::Speed, 10_000),
+            1.0
+        );
+
+        let expired = plan_buff_expiry("the_void", "expiring", 10_000);
+        assert_eq!(expired.len(), 1);
+        let (subject, payload) = &expired[0];
+        assert_eq!(subject, "event.decs.system.buff.expired");
+        assert_eq!(payload["entity_id"], "expiring");
+        assert_eq!(payload["id"], "haste");
+        assert_eq!(payload["stat"], "speed");
+        // It is only announced once
+        assert!(plan_buff_expiry("the_void", "expiring", 10_000).is_empty());
+    }
+
+    #[test]
+    fn test_stacking_modes() {
+        let stat = StatTarget::MiningEfficiency;
+        let additive = vec![
+            buff("a", stat, 1.2, StackBehavior::Additive),
+            buff("b", stat, 1.3, StackBehavior::Additive),
+        ];
+        assert!((combine(&additive, stat, 0) - 1.5).abs() < EPSILON);
+
+        let multiplicative = vec![
+            buff("a", stat, 1.2, StackBehavior::Multiplicative),
+            buff("b", stat, 1.5, StackBehavior::Multiplicative),
+        ];
+        assert!((combine(&multiplicative, stat, 0) - 1.8).abs() < EPSILON);
+
+        let highest = vec![
+            buff("a", stat, 1.2, StackBehavior::TakeHighest),
+            buff("b", stat, 2.0, StackBehavior::TakeHighest),
+        ];
+        assert!((combine(&highest, stat, 0) - 2.0).abs() < EPSILON);
+
+        // Mixed behaviors each combine on their own and then multiply
+        let mixed = vec![
+            buff("a", stat, 1.5, StackBehavior::Additive),
+            buff("b", stat, 2.0, StackBehavior::Multiplicative),
+            buff("c", stat, 1.1, StackBehavior::TakeHighest),
+        ];
+        assert!((combine(&mixed, stat, 0) - 3.3).abs() < EPSILON);
+    }
+
+    #[test]
+    fn test_buffs_on_different_stats_apply_separately() {
+        apply_buff(
+            "the_void",
+            "decorated",
+            buff(
+                "scope",
+                StatTarget::RadarRange,
+                2.0,
+                StackBehavior::TakeHighest,
+            ),
+        );
+        apply_buff(
+            "the_void",
+            "decorated",
+            buff(
+                "drill",
+                StatTarget::MiningEfficiency,
+                1.25,
+                StackBehavior::Multiplicative,
+            ),
+        );
+        // Reapplying a buff replaces it rather than stacking with it
+        apply_buff(
+            "the_void",
+            "decorated",
+            buff(
+                "drill",
+                StatTarget::MiningEfficiency,
+                1.5,
+                StackBehavior::Multiplicative,
+            ),
+        );
+
+        let multiplier = |stat| buff_multiplier("the_void", "decorated", stat, 0);
+        assert_eq!(multiplier(StatTarget::RadarRange), 2.0);
+        assert_eq!(multiplier(StatTarget::MiningEfficiency), 1.5);
+        assert_eq!(multiplier(StatTarget::Speed), 1.0);
+        assert_eq!(
+            buff_multiplier("the_void", "someone_else", StatTarget::RadarRange, 0),
+            1.0
+        );
+    }
+}
@@ -0,0 +1,34 @@
+//! Actors have no wall clock available to them, so game time is derived from the
+//! sequence number of the frames the system manager publishes to each system.
+//! Timestamps produced this way are only comparable within a single system.
+//!
+//! Work done outside of a frame, e.g. in response to a component change, has no frame to derive
+//! the time from. A system can record the time of each of its frames with `observe` so such work
+//! can use the latest one, `latest_time_ms`.
+
+use decscloud_common::systemmgr::EntityFrame;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    // shard -> game time of the latest frame observed on it
+    static ref LATEST: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+}
+
+/// Returns the current game time, in milliseconds, for a frame delivered to a
+/// system running at the given framerate (frames per second)
+pub fn frame_time_ms(frame: &EntityFrame, framerate: u32) -> u64 {
+    frame.seq_no * 1000 / u64::from(framerate.max(1))
+}
+
+/// Records the game time of a frame on the shard, unless a later one was already recorded
+pub fn observe(shard: &str, now_ms: u64) {
+    let mut latest = LATEST.lock().unwrap();
+    let time = latest.entry(shard.to_string()).or_insert(now_ms);
+    *time = (*time).max(now_ms);
+}
+
+/// The game time of the latest frame observed on the shard, 0 before its first one
+pub fn latest_time_ms(shard: &str) -> u64 {
+    LATEST.lock().unwrap().get(shard).copied().unwrap_or(0)
+}
@@ -1,10 +1,17 @@
 extern crate decscloud_common as decs;
 
+use crate::units::{Distance, Millis};
+use crate::validation::ValidationError;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::{Add, Mul, Neg, Sub};
+
 const MS_PER_HOUR: f64 = 3_600_000.0;
+pub const DEFAULT_MAX_MINING_SPEED: f64 = 5.0;
 
 /// Represents the metadata and parameters for a given universe (the physical space
 /// contained within a shard)
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct UniverseMetadata {
     pub min_x: f64,
     pub min_y: f64,
@@ -27,8 +34,12 @@ impl Default for UniverseMetadata {
     }
 }
 
-/// Represents a position in 3-dimensional space, assumed unit is Kilometers
-#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Copy)]
+/// Represents a position in 3-dimensional space, assumed unit is Kilometers. Every coordinate is
+/// required and unknown fields are rejected: a position with more or fewer keys is most likely
+/// another component stored at the wrong key, and taking it for a point would teleport the entity
+#[derive(Debug, Default, Clone, PartialEq, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
 pub struct Position {
     pub x: f64,
     pub y: f64,
@@ -93,11 +104,82 @@ impl Position {
             elevation,
         }
     }
+
+    /// The length of the position taken as a vector from the origin
+    pub fn magnitude(&self) -> f64 {
+        self.dot(self).sqrt()
+    }
+
+    /// The vector of length 1 pointing the same way. A zero-length vector has no direction and
+    /// stays zero rather than turning into NaNs
+    pub fn normalize(&self) -> Position {
+        let magnitude = self.magnitude();
+        if magnitude == 0.0 {
+            Position::default()
+        } else {
+            *self * (1.0 / magnitude)
+        }
+    }
+
+    /// The dot product of the two vectors, zero for perpendicular ones
+    pub fn dot(&self, other: &Position) -> f64 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    /// The point a fraction `t` of the way from this position to the other, with `t` clamped
+    /// to [0, 1] so the result always lies between the two
+    pub fn lerp(&self, other: &Position, t: f64) -> Position {
+        self.lerp_unclamped(other, t.clamp(0.0, 1.0))
+    }
+
+    /// The point a fraction `t` of the way from this position to the other. A `t` past 1.0
+    /// extrapolates beyond the other position, a negative one behind this position
+    pub fn lerp_unclamped(&self, other: &Position, t: f64) -> Position {
+        *self + (*other - *self) * t
+    }
+
+    /// The point halfway between the two positions
+    pub fn midpoint(&self, other: &Position) -> Position {
+        self.lerp_unclamped(other, 0.5)
+    }
+}
+
+impl Add for Position {
+    type Output = Position;
+
+    fn add(self, other: Position) -> Position {
+        Position::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl Sub for Position {
+    type Output = Position;
+
+    fn sub(self, other: Position) -> Position {
+        Position::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl Mul<f64> for Position {
+    type Output = Position;
+
+    fn mul(self, scale: f64) -> Position {
+        Position::new(self.x * scale, self.y * scale, self.z * scale)
+    }
+}
+
+impl Neg for Position {
+    type Output = Position;
+
+    fn neg(self) -> Position {
+        self * -1.0
+    }
 }
 
 /// Represents a velocity, which includes a magnitude and a direction. The direction
 /// is represented by a unit vector (normalized values between 0-1). Magnitude is in KPH
-#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Copy)]
+#[derive(Debug, Default, Clone, PartialEq, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Velocity {
     pub mag: u32,
     pub ux: f64,
@@ -109,11 +191,45 @@ impl Velocity {
     pub fn new(mag: u32, ux: f64, uy: f64, uz: f64) -> Self {
         Velocity { mag, ux, uy, uz }
     }
+
+    /// The velocity of the speed (in KPH) along the heading, in degrees with the same convention
+    /// as `TargetVector`: the azimuth turns from the x axis toward the y axis and the elevation
+    /// is measured down from the z axis. A zero speed keeps its heading
+    pub fn from_heading(speed: u32, azimuth: f64, elevation: f64) -> Self {
+        let (azimuth, elevation) = (azimuth.to_radians(), elevation.to_radians());
+        Velocity {
+            mag: speed,
+            ux: elevation.sin() * azimuth.cos(),
+            uy: elevation.sin() * azimuth.sin(),
+            uz: elevation.cos(),
+        }
+    }
+
+    /// The azimuth and elevation, in degrees, of the velocity's direction. A velocity without a
+    /// direction heads along the z axis, at (0, 0), like a `TargetVector` to its own position.
+    /// Straight up or down, the azimuth is 0
+    pub fn heading(&self) -> (f64, f64) {
+        let direction = Position::new(self.ux, self.uy, self.uz).normalize();
+        if direction == Position::default() {
+            return (0.0, 0.0);
+        }
+        (
+            direction.y.atan2(direction.x).to_degrees(),
+            direction.z.clamp(-1.0, 1.0).acos().to_degrees(),
+        )
+    }
+
+    /// How far, in kilometers along each axis, the velocity carries an entity in the elapsed time
+    pub fn displacement(&self, elapsed_ms: u64) -> Position {
+        let km = (u64::from(self.mag) * elapsed_ms) as f64 / MS_PER_HOUR;
+        Position::new(self.ux * km, self.uy * km, self.uz * km)
+    }
 }
 
 pub type Vector = Velocity;
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct TargetVector {
     pub mag: u32,
     pub ux: f64,
@@ -125,68 +241,1533 @@ pub struct TargetVector {
 }
 
 /// Represents a selected target for the navigation system.
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Target {
     pub rid: String, // The resource ID (e.g. decs.components.the_void.entity25) of the target
     pub eta_ms: f64, // Estimated time of arrival at the target, in milliseconds
     pub distance_km: f64, // Distance to the target in kilometers
 }
 
+/// A point the navigation system steers an entity toward. The waypoint is reached once the
+/// entity is within `arrival_radius` km of `position`
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NavigationWaypoint {
+    pub position: Position,
+    pub arrival_radius: f64,
+}
+
+/// Waypoints to visit, in order, after the current `NavigationWaypoint` has been reached.
+/// Each entry is a `(target, arrival_radius)` pair
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct WaypointQueue {
+    pub waypoints: VecDeque<(Position, f64)>,
+}
+
+/// The order in which an NPC visits the points of its patrol route. `forward` and `backward`
+/// loop around the route, `ping_pong` walks it back and forth
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum PatrolDirection {
+    #[default]
+    Forward,
+    Backward,
+    PingPong,
+}
+
+/// Whether a patrolling NPC is heading for its current route point or waiting, with the
+/// milliseconds left to wait
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum PatrolState {
+    #[default]
+    Moving,
+    Waiting(u64),
+}
+
+/// Moves an NPC along a route of points at `speed` KPH, waiting `wait_ms` at each point
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NpcPatrol {
+    pub route: Vec<Position>,
+    pub current_index: usize, // Index of the route point the NPC is heading for
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub direction: PatrolDirection,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub state: PatrolState,
+    pub speed: u32, // Magnitude in KPH
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub wait_ms: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub returning: bool, // Whether a `ping_pong` patrol is walking the route backward
+}
+
+/// The warp gates an entity travels through to reach another shard, in order
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InterShardRoute {
+    pub hops: Vec<ShardHop>,
+}
+
+/// Leaves the current shard through the gate entity `exit_gate_id` and arrives in `entry_shard`
+/// at its gate entity `entry_gate_id`
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ShardHop {
+    pub exit_gate_id: String,
+    pub entry_shard: String,
+    pub entry_gate_id: String,
+}
+
+pub const DEFAULT_MAP_CELL_SIZE: f64 = 10.0;
+
+/// The grid cells of `cell_size` km an entity has visited. A cell is identified by the
+/// coordinates of its lowest corner divided by the cell size
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExplorationMap {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub explored_cells: HashSet<(i64, i64, i64)>,
+    #[cfg_attr(feature = "serde", serde(default = "default_map_cell_size"))]
+    pub cell_size: f64,
+}
+
+impl Default for ExplorationMap {
+    fn default() -> Self {
+        ExplorationMap {
+            explored_cells: HashSet::new(),
+            cell_size: DEFAULT_MAP_CELL_SIZE,
+        }
+    }
+}
+
+impl ExplorationMap {
+    /// The cell containing the position. Positions on a boundary belong to the cell above it,
+    /// and a cell size that isn't a positive number counts as the default
+    pub fn cell_of(&self, position: &Position) -> (i64, i64, i64) {
+        let size = if self.cell_size.is_finite() && self.cell_size > 0.0 {
+            self.cell_size
+        } else {
+            DEFAULT_MAP_CELL_SIZE
+        };
+        (
+            (position.x / size).floor() as i64,
+            (position.y / size).floor() as i64,
+            (position.z / size).floor() as i64,
+        )
+    }
+}
+
+#[cfg(feature = "serde")]
+fn default_map_cell_size() -> f64 {
+    DEFAULT_MAP_CELL_SIZE
+}
+
+/// The number of map cells in a shard, against which exploration coverage is measured
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TotalCells {
+    pub count: u64,
+}
+
+impl TotalCells {
+    /// The percentage of the shard's cells that have been explored, if the count is known
+    pub fn coverage_pct(&self, explored: usize) -> Option<f64> {
+        match self.count {
+            0 => None,
+            count => Some((explored as f64 / count as f64 * 100.0).clamp(0.0, 100.0)),
+        }
+    }
+}
+
 /// Represents a radar component that scans for entities around the entity with the receiver.
-#[derive(Serialize, Deserialize, Debug, Default)]
+/// Unknown fields are ignored, so receivers written by a newer version still scan
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct RadarReceiver {
-    pub radius: f64, // The range of the radar as a radius in km
+    pub radius: Distance, // The range of the radar as a radius
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub cross_shard_range: Option<f64>, // Opt-in range in km for detecting entities in adjacent shards
 }
 
-/// Represents a single radar contact
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+/// Represents a single radar contact. Unknown fields are ignored, so contacts recorded by a newer
+/// version can still be replayed from the radar history
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct RadarContact {
     pub entity_id: String,
-    pub distance: u32,
+    #[cfg_attr(feature = "serde", serde(with = "crate::units::whole_km"))]
+    pub distance: Distance, // Rounded to the kilometer
     pub distance_xy: u32,
     pub azimuth: f64,
     pub elevation: f64,
     pub transponder: decs::gateway::ResourceIdentifier,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub cross_shard: bool, // Whether the contact was detected in an adjacent shard
+}
+
+/// The contacts an entity's radar held at a point in game time
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RadarSnapshot {
+    pub timestamp_ms: u64,
+    pub contacts: Vec<RadarContact>,
+}
+
+/// The most recent radar snapshots of an entity, oldest first, for replaying what it saw.
+/// Holds at most `max_entries` snapshots
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RadarHistoryLog {
+    pub entries: VecDeque<RadarSnapshot>,
+    pub max_entries: usize,
+}
+
+impl RadarHistoryLog {
+    /// Adds the snapshot, keeping the entries ordered by time, and drops the oldest entries
+    /// beyond `max_entries`
+    pub fn record(&mut self, snapshot: RadarSnapshot) {
+        // Frames normally arrive in order, so the snapshot almost always goes last
+        let index = self
+            .entries
+            .iter()
+            .rposition(|entry| entry.timestamp_ms <= snapshot.timestamp_ms)
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        self.entries.insert(index, snapshot);
+        while self.entries.len() > self.max_entries {
+            self.entries.pop_front();
+        }
+    }
+
+    /// The snapshot closest in time to `timestamp_ms`, preferring the earlier one on a tie
+    pub fn get_radar_at_time(&self, timestamp_ms: u64) -> Option<&RadarSnapshot> {
+        let index = match self
+            .entries
+            .binary_search_by_key(&timestamp_ms, |entry| entry.timestamp_ms)
+        {
+            Ok(index) => index,
+            Err(0) => 0,
+            Err(index) if index == self.entries.len() => index - 1,
+            Err(index) => {
+                let before = timestamp_ms - self.entries[index - 1].timestamp_ms;
+                let after = self.entries[index].timestamp_ms - timestamp_ms;
+                if before <= after {
+                    index - 1
+                } else {
+                    index
+                }
+            }
+        };
+        self.entries.get(index)
+    }
+}
+
+/// A ping an entity sent to `target_entity` and awaits the echo of, identified by its `nonce`
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PingRequest {
+    pub target_entity: String,
+    pub sent_at_ms: u64, // Game time at which the ping was sent
+    pub nonce: u64,
+}
+
+/// Weight of the newest round trip in the running average
+pub const LATENCY_SMOOTHING: f64 = 0.125;
+
+/// The round-trip times of an entity's pings. `avg_rtt_ms` is an exponentially weighted moving
+/// average, each round trip counting for `LATENCY_SMOOTHING` of it
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LatencyStats {
+    pub last_rtt_ms: u64,
+    pub avg_rtt_ms: f64,
+}
+
+impl LatencyStats {
+    /// The stats with a new round trip. The first one sets the average outright
+    pub fn record(previous: Option<&LatencyStats>, rtt_ms: u64) -> LatencyStats {
+        let rtt = rtt_ms as f64;
+        LatencyStats {
+            last_rtt_ms: rtt_ms,
+            avg_rtt_ms: previous.map_or(rtt, |stats| {
+                stats.avg_rtt_ms + (rtt - stats.avg_rtt_ms) * LATENCY_SMOOTHING
+            }),
+        }
+    }
 }
 
 /// Represents a transponder component for a radar contact that dictates how it should be displayed in the game UI
 /// object_type should be ["starbase" | "ship" | "asteroid"]
 /// display_name should be the name to display on the UI.
 /// color can either be in the form of a hex code `#ff0000` or a CSS recognized color `red` or `aliceblue`
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+/// radar_signature scales how visible the entity is to radar, an entity with a signature of 0 cannot be detected
+/// Unknown fields are ignored, so transponders written by a newer version are still displayed
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct RadarTransponder {
     pub object_type: String,
     pub display_name: String,
     pub color: String,
+    #[cfg_attr(feature = "serde", serde(default = "default_radar_signature"))]
+    pub radar_signature: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub depleted: bool, // Set once an asteroid has been mined out, until it respawns
+}
+
+impl Default for RadarTransponder {
+    fn default() -> Self {
+        RadarTransponder {
+            object_type: String::default(),
+            display_name: String::default(),
+            color: String::default(),
+            radar_signature: default_radar_signature(),
+            depleted: false,
+        }
+    }
+}
+
+fn default_radar_signature() -> f64 {
+    1.0
+}
+
+/// How rare a resource is. Rarer resources take longer to extract, vary more in yield and are
+/// worth more
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Rarity {
+    #[default]
+    Common,
+    Uncommon,
+    Rare,
+    Epic,
+}
+
+impl Rarity {
+    /// The factor by which extracting a unit of the resource takes longer than a common one
+    pub fn duration_multiplier(&self) -> f64 {
+        match self {
+            Rarity::Common => 1.0,
+            Rarity::Uncommon => 1.25,
+            Rarity::Rare => 1.5,
+            Rarity::Epic => 2.0,
+        }
+    }
+
+    /// The factor by which a unit of the resource is worth more than a common one
+    pub fn value_multiplier(&self) -> f64 {
+        match self {
+            Rarity::Common => 1.0,
+            Rarity::Uncommon => 1.5,
+            Rarity::Rare => 2.5,
+            Rarity::Epic => 5.0,
+        }
+    }
+
+    pub fn is_common(&self) -> bool {
+        *self == Rarity::Common
+    }
 }
 
 // At this point in the game development, mining resources are the only things that can be
 // in a player inventory, so they are moved directly from the resource to inventory.
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MiningResource {
     pub stack_type: String, // Type of the stack ("spendy", "tasty", or "critical")
     pub qty: u32,           // Quantity of stack item in the resource
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Rarity::is_common")
+    )]
+    pub rarity: Rarity,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub base_value: Option<u32>, // Credits a common unit sells for, instead of the stack type's value
+}
+
+impl MiningResource {
+    /// Resources with the same stack key are interchangeable and can be merged into a single stack
+    pub fn stack_key(&self) -> &str {
+        &self.stack_type
+    }
+
+    /// The credits a single unit of the resource sells for: its base value, or that of its stack
+    /// type, scaled by its rarity. Unknown stack types without a base value are worthless
+    pub fn unit_value(&self) -> u32 {
+        let base_value = self.base_value.unwrap_or(match self.stack_type.as_str() {
+            "critical" => 100,
+            "tasty" => 50,
+            "spendy" => 30,
+            _ => 0,
+        });
+        (f64::from(base_value) * self.rarity.value_multiplier()).round() as u32
+    }
+}
+
+pub const INVENTORY_ITEM_VERSION: u32 = 2;
+pub const RESOURCE_ITEM_KIND: &str = "resource";
+pub const DEPLOYABLE_ITEM_KIND: &str = "deployable";
+
+/// An item in an entity's `inventory` collection. Items of the same `kind`, `name` and `rarity`
+/// are interchangeable and stack. Inventories from before items had a version (v1) hold raw
+/// `MiningResource` blobs instead, which are upgraded on read, see `migration`
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InventoryItem {
+    pub name: String, // e.g. the stack type of a mined resource
+    pub kind: String, // What sort of item this is, e.g. "resource"
+    #[cfg_attr(feature = "serde", serde(default = "default_item_qty"))]
+    pub qty: u32,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unit_value: u32, // Credits a single unit sells for
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Rarity::is_common")
+    )]
+    pub rarity: Rarity,
+    #[cfg_attr(feature = "serde", serde(default = "default_item_version"))]
+    pub version: u32,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub deployable: Option<DeployableItem>, // Set on items of the `deployable` kind
+}
+
+impl InventoryItem {
+    /// Items with the same stack key can be merged into a single stack
+    pub fn stack_key(&self) -> (&str, &str, Rarity) {
+        (&self.kind, &self.name, self.rarity)
+    }
+}
+
+impl From<&MiningResource> for InventoryItem {
+    fn from(resource: &MiningResource) -> Self {
+        InventoryItem {
+            name: resource.stack_type.to_string(),
+            kind: RESOURCE_ITEM_KIND.to_string(),
+            qty: resource.qty,
+            unit_value: resource.unit_value(),
+            rarity: resource.rarity,
+            version: INVENTORY_ITEM_VERSION,
+            deployable: None,
+        }
+    }
+}
+
+/// An inventory item that can be deployed into the world as an entity of its own, built from the
+/// components of a template stored under `decs:templates:{component_template}`
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DeployableItem {
+    pub component_template: String,
+    pub deploy_radius: f64, // How far from the deploying entity, in km, the new entity may appear
+}
+
+#[cfg(feature = "serde")]
+fn default_item_qty() -> u32 {
+    1
+}
+
+#[cfg(feature = "serde")]
+fn default_item_version() -> u32 {
+    INVENTORY_ITEM_VERSION
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+/// Extractors persisted before `total_ms` existed are upgraded on read, see `migration`. Unknown
+/// fields are ignored, so an extraction started by a newer version runs to completion
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MiningExtractor {
     pub target: String, // Fully-qualified ID of the mining resource component to which extractor is attached
-    pub remaining_ms: f64, // Time remaining for extraction
+    pub remaining_ms: Millis, // Time remaining for extraction
+    pub total_ms: f64,  // Total duration of the extraction, set when the extractor is created
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub paused: bool, // A paused extraction makes no progress until it is resumed
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub deliver_to: Option<String>, // Fully-qualified ID of the entity receiving the mined resource, e.g. `decs.components.{shard}.{entity}`
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub max_mining_speed: Option<f64>, // Speed in KPH at or above which the extraction makes no progress
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "is_cold"))]
+    pub heat: f64, // Builds up while the extraction runs and dissipates while it is paused
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "std::ops::Not::not")
+    )]
+    pub overheated: bool, // Paused by its own heat, resumes by itself once it has cooled down
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub heat_limit: Option<HeatLimit>, // How hot the extractor may run, no limit if unset
+}
+
+impl MiningExtractor {
+    /// The speed at or above which the extraction stalls, `DEFAULT_MAX_MINING_SPEED` unless set
+    pub fn max_mining_speed(&self) -> f64 {
+        self.max_mining_speed.unwrap_or(DEFAULT_MAX_MINING_SPEED)
+    }
+
+    /// Computes how far along the extraction is as a percentage between 0 and 100
+    pub fn percent_complete(&self) -> f64 {
+        if self.total_ms <= 0.0 || self.total_ms.is_nan() {
+            return if self.remaining_ms <= Millis::ZERO {
+                100.0
+            } else {
+                0.0
+            };
+        }
+        let pct = (self.total_ms - self.remaining_ms.0) / self.total_ms * 100.0;
+        pct.clamp(0.0, 100.0)
+    }
+
+    /// Normalizes an extractor read from the KV store. A negative `remaining_ms` or `heat`
+    /// becomes 0, so an extraction with negative time left completes on its next frame, and a
+    /// negative `total_ms` becomes the time remaining. A NaN or infinite number can't be made
+    /// sense of and is an error
+    pub fn sanitize(&self) -> Result<Self, ValidationError> {
+        for (field, value) in &[
+            ("remaining_ms", self.remaining_ms.0),
+            ("total_ms", self.total_ms),
+            ("heat", self.heat),
+        ] {
+            if !value.is_finite() {
+                return Err(ValidationError {
+                    component: "extractor",
+                    reason: format!("{} must be a finite number, got {}", field, value),
+                });
+            }
+        }
+        let remaining_ms = self.remaining_ms.max(Millis::ZERO);
+        Ok(MiningExtractor {
+            remaining_ms,
+            total_ms: if self.total_ms < 0.0 {
+                remaining_ms.0
+            } else {
+                self.total_ms
+            },
+            heat: self.heat.max(0.0),
+            ..self.clone()
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+fn is_cold(heat: &f64) -> bool {
+    *heat <= 0.0
+}
+
+/// The heat an extractor can take. Heat is counted in milliseconds of extraction at a
+/// `heating_rate` of 1.0, so a `max_heat` of 30000.0 lets an extractor run for 30 seconds in a row
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HeatLimit {
+    pub max_heat: f64, // Heat at which the extractor overheats and pauses itself
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub resume_heat: Option<f64>, // Heat at or below which it resumes, half of `max_heat` unless set
+    #[cfg_attr(feature = "serde", serde(default = "default_multiplier"))]
+    pub heating_rate: f64, // Heat gained per millisecond of extraction
+    #[cfg_attr(feature = "serde", serde(default = "default_multiplier"))]
+    pub cooling_rate: f64, // Heat lost per millisecond while paused
+}
+
+impl HeatLimit {
+    /// The heat an overheated extractor has to cool down to before it resumes
+    pub fn resume_heat(&self) -> f64 {
+        self.resume_heat.unwrap_or(self.max_heat / 2.0)
+    }
+}
+
+/// Equipped by a player with an improved extractor. Extractions progress `multiplier` times
+/// as fast, e.g. 2.0 mines twice as fast
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MiningEfficiency {
+    pub multiplier: f64,
+}
+
+impl Default for MiningEfficiency {
+    fn default() -> Self {
+        MiningEfficiency { multiplier: 1.0 }
+    }
+}
+
+impl MiningEfficiency {
+    /// Only a finite, positive multiplier can be applied to an extraction
+    pub fn is_valid(&self) -> bool {
+        self.multiplier.is_finite() && self.multiplier > 0.0
+    }
+}
+
+/// A purchasable upgrade to a player's mining beam. `range_multiplier` extends the distance at
+/// which the beam can hold its target and `speed_multiplier` makes extractions progress faster,
+/// on top of any `MiningEfficiency`
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MiningUpgrade {
+    #[cfg_attr(feature = "serde", serde(default = "default_multiplier"))]
+    pub range_multiplier: f64,
+    #[cfg_attr(feature = "serde", serde(default = "default_multiplier"))]
+    pub speed_multiplier: f64,
+    #[cfg_attr(feature = "serde", serde(default = "default_multiplier"))]
+    pub yield_multiplier: f64, // Scales the quantity of every completed extraction
+}
+
+impl Default for MiningUpgrade {
+    fn default() -> Self {
+        MiningUpgrade {
+            range_multiplier: 1.0,
+            speed_multiplier: 1.0,
+            yield_multiplier: 1.0,
+        }
+    }
+}
+
+impl MiningUpgrade {
+    /// The upgrade with every multiplier that is zero, negative or not a number replaced by 1.0
+    pub fn clamped(&self) -> MiningUpgrade {
+        let clamp = |m: f64| if m.is_finite() && m > 0.0 { m } else { 1.0 };
+        MiningUpgrade {
+            range_multiplier: clamp(self.range_multiplier),
+            speed_multiplier: clamp(self.speed_multiplier),
+            yield_multiplier: clamp(self.yield_multiplier),
+        }
+    }
+
+    /// The upgrade with the multiplier the rig upgrade improves scaled by its magnitude
+    pub fn apply(&self, rig: &MiningRigUpgrade) -> MiningUpgrade {
+        let upgrade = self.clamped();
+        match rig.upgrade_type {
+            UpgradeType::FasterExtraction => MiningUpgrade {
+                speed_multiplier: upgrade.speed_multiplier * rig.magnitude,
+                ..upgrade
+            },
+            UpgradeType::LargerYield => MiningUpgrade {
+                yield_multiplier: upgrade.yield_multiplier * rig.magnitude,
+                ..upgrade
+            },
+            UpgradeType::LongerRange => MiningUpgrade {
+                range_multiplier: upgrade.range_multiplier * rig.magnitude,
+                ..upgrade
+            },
+        }
+        .clamped()
+    }
+}
+
+/// The part of a mining rig a `MiningRigUpgrade` improves
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum UpgradeType {
+    FasterExtraction,
+    LargerYield,
+    LongerRange,
+}
+
+/// A one-off upgrade a player can apply to their mining rig, multiplying the matching
+/// `MiningUpgrade` multiplier by `magnitude`. The upgrade is used up when applied
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MiningRigUpgrade {
+    pub upgrade_type: UpgradeType,
+    pub magnitude: f64,
+}
+
+pub const MAX_APPLIED_UPGRADES: usize = 3;
+
+/// The rig upgrades a player has applied, at most `MAX_APPLIED_UPGRADES` of them
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AppliedUpgrades {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub upgrades: Vec<UpgradeType>,
+}
+
+impl AppliedUpgrades {
+    pub fn is_full(&self) -> bool {
+        self.upgrades.len() >= MAX_APPLIED_UPGRADES
+    }
+}
+
+#[cfg(feature = "serde")]
+fn default_multiplier() -> f64 {
+    1.0
+}
+
+/// A player's lifetime mining statistics, updated on every successful extraction
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MiningStats {
+    pub asteroids_mined: u32, // Number of successful extractions
+    pub units_extracted: u64, // Total quantity extracted across all resources
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub resources: HashMap<String, u64>, // Quantity extracted per stack type
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub favorite_resource: Option<String>, // Stack type extracted the most
+}
+
+impl MiningStats {
+    /// Counts a successful extraction
+    pub fn record(&mut self, mined: &MiningResource) {
+        self.asteroids_mined += 1;
+        self.units_extracted += u64::from(mined.qty);
+        *self
+            .resources
+            .entry(mined.stack_type.to_string())
+            .or_default() += u64::from(mined.qty);
+        // Ties go to the alphabetically first stack type so the favorite doesn't flap
+        self.favorite_resource = self
+            .resources
+            .iter()
+            .max_by(|(a_type, a_qty), (b_type, b_qty)| {
+                a_qty.cmp(b_qty).then_with(|| b_type.cmp(a_type))
+            })
+            .map(|(stack_type, _)| stack_type.to_string());
+    }
+}
+
+/// Published on `event.decs.{shard}.{entity}.mining.completed` when an extraction has added its
+/// resource to the miner's inventory
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MiningCompleted {
+    pub target: String,     // Fully-qualified ID of the mined deposit
+    pub stack_type: String, // Type of the resource that was mined
+    pub qty: u32,           // Quantity added to the inventory
+    pub elapsed_ms: f64,    // Total duration of the extraction
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rarity: Rarity,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub overshoot_ms: f64, // Frame time left over once the extraction completed, it yields nothing as the whole deposit is taken
+}
+
+/// An item of a shard's `decs.shard.{shard}.mining_events` collection, the feed of extractions
+/// that ended on the shard. Items are addressed by their `seq`, the oldest being deleted once
+/// the feed is full
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MiningFeedEvent {
+    pub seq: u64,       // Position of the event in the shard's feed, starting at 1
+    pub miner: String,  // Entity ID of the miner
+    pub target: String, // Fully-qualified ID of the deposit the extraction targeted
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub stack_type: Option<String>, // Type of the resource mined, for completed extractions
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub qty: Option<u32>, // Quantity added to the inventory, for completed extractions
+    pub timestamp_ms: u64, // Game time of the mining system when the extraction ended
+    pub outcome: MiningOutcome,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub reason: Option<String>, // Why the extraction didn't complete
+}
+
+/// How an extraction ended: with the resource in the inventory, failed because the resource
+/// is gone or out of range, or given up for any other reason
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum MiningOutcome {
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// Placed on an entity being mined so that only one extractor can target it at a time. Locks
+/// are stamped with `created_ms` (mining lock system game time) the first time the system sees
+/// them and are deleted once `expires_after_ms` has passed, so a crashed extraction can't lock
+/// an asteroid forever
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MiningLock {
+    pub extractor: String, // Fully-qualified ID of the extractor holding the lock
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub owner: String, // Entity ID of the player holding the lock
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub created_ms: Option<u64>,
+    #[cfg_attr(feature = "serde", serde(default = "default_lock_ttl_ms"))]
+    pub expires_after_ms: u64,
+}
+
+impl Default for MiningLock {
+    fn default() -> Self {
+        MiningLock {
+            extractor: String::default(),
+            owner: String::default(),
+            created_ms: None,
+            expires_after_ms: default_lock_ttl_ms(),
+        }
+    }
+}
+
+impl MiningLock {
+    /// The entity holding the lock. Locks created without an owner belong to the entity
+    /// that owns the lock's extractor
+    pub fn owner_id(&self) -> Option<&str> {
+        if self.owner.is_empty() {
+            self.extractor.split('.').nth(3)
+        } else {
+            Some(&self.owner)
+        }
+    }
+
+    /// Whether the lock has outlived its TTL. Locks that haven't been stamped yet never expire
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        self.created_ms
+            .map(|created| now_ms.saturating_sub(created) >= self.expires_after_ms)
+            .unwrap_or(false)
+    }
+}
+
+fn default_lock_ttl_ms() -> u64 {
+    600_000
+}
+
+/// Lets a ship start mining on its own: while `enabled` and not extracting anything, it locks
+/// and mines the nearest asteroid within `max_range` km
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AutoMiner {
+    pub enabled: bool,
+    pub max_range: f64,
+}
+
+/// The credits an entity holds. Change them through `credit` and `debit`, see `wallet`
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Wallet {
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "crate::wallet::credits::deserialize")
+    )]
+    pub credits: i64,
+}
+
+/// Limits how many items an entity's inventory can hold. Entities without a cargo hold
+/// have unlimited inventory space
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CargoHold {
+    pub capacity: u32, // Maximum number of items in the inventory collection
+}
+
+/// Optional settings for how an asteroid regenerates once it has been depleted. Asteroids
+/// without this component respawn the resource they were depleted of after the default cooldown
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ResourceRespawn {
+    #[cfg_attr(feature = "serde", serde(default = "default_respawn_cooldown_ms"))]
+    pub cooldown_ms: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub qty: Option<u32>, // Quantity of the respawned resource, defaults to the depleted quantity
+}
+
+impl Default for ResourceRespawn {
+    fn default() -> Self {
+        ResourceRespawn {
+            cooldown_ms: default_respawn_cooldown_ms(),
+            qty: None,
+        }
+    }
+}
+
+/// Shard-wide supply of a resource type. The scarcer the resource, the more an extraction of it
+/// yields: `base_yield` is raised by `scarcity_curve / (global_supply + 1)`. Extractions take
+/// the quantity mined out of `global_supply`, respawns put theirs back
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ResourceScarcity {
+    pub resource_type: String, // The stack type of the resource
+    pub global_supply: u64,
+    pub base_yield: f64,
+    pub scarcity_curve: f64,
+}
+
+impl ResourceScarcity {
+    /// The multiplier of the quantity an extraction of the resource yields
+    pub fn effective_yield(&self) -> f64 {
+        self.base_yield * (1.0 + self.scarcity_curve / (self.global_supply as f64 + 1.0))
+    }
+}
+
+/// Marker written to a depleted asteroid. It remembers everything needed to put the asteroid
+/// back the way it was, since the depleted transponder no longer carries the original name
+/// and color
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AsteroidRespawn {
+    pub target: String, // Fully-qualified ID of the deposit to re-create
+    pub resource: MiningResource,
+    pub transponder: RadarTransponder, // The asteroid's transponder before it was depleted
+    #[cfg_attr(feature = "serde", serde(default = "default_respawn_cooldown_ms"))]
+    pub cooldown_ms: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub respawn_at_ms: Option<u64>,
+}
+
+impl AsteroidRespawn {
+    /// Whether the cooldown has passed. Markers that haven't been stamped yet are never due
+    pub fn is_due(&self, now_ms: u64) -> bool {
+        self.respawn_at_ms
+            .map(|respawn_at| now_ms >= respawn_at)
+            .unwrap_or(false)
+    }
+}
+
+fn default_respawn_cooldown_ms() -> u64 {
+    900_000
+}
+
+/// The faction an entity belongs to. Entities of different factions are hostile to each other
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FactionId {
+    pub faction: String,
+}
+
+impl FactionId {
+    pub fn is_hostile_to(&self, other: &FactionId) -> bool {
+        self.faction != other.faction
+    }
+}
+
+/// Divides a shard's universe into a grid of sectors, `divisions_x` by `divisions_y` by
+/// `divisions_z`, for the factions to compete over. Every millisecond spent in a sector earns the
+/// entity's faction `points_per_ms`, and the faction with the highest score above
+/// `control_threshold` controls it
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SectorGrid {
+    pub divisions_x: u32,
+    pub divisions_y: u32,
+    pub divisions_z: u32,
+    pub points_per_ms: f64,
+    pub control_threshold: f64,
+}
+
+impl Default for SectorGrid {
+    fn default() -> Self {
+        SectorGrid {
+            divisions_x: 4,
+            divisions_y: 4,
+            divisions_z: 1,
+            points_per_ms: 0.001,
+            control_threshold: 60.0,
+        }
+    }
+}
+
+impl SectorGrid {
+    /// The ID, `{x}_{y}_{z}` by the sector's indices along each axis, of the sector containing
+    /// the position. Positions outside of the universe belong to the nearest sector on its edge
+    pub fn sector_id(&self, position: &Position, md: &UniverseMetadata) -> String {
+        let index = |value: f64, min: f64, max: f64, divisions: u32| {
+            let divisions = divisions.max(1);
+            if max <= min {
+                return 0;
+            }
+            let cell = ((value - min) / (max - min) * f64::from(divisions)).floor();
+            cell.max(0.0).min(f64::from(divisions - 1)) as u32
+        };
+        format!(
+            "{}_{}_{}",
+            index(position.x, md.min_x, md.max_x, self.divisions_x),
+            index(position.y, md.min_y, md.max_y, self.divisions_y),
+            index(position.z, md.min_z, md.max_z, self.divisions_z)
+        )
+    }
+}
+
+/// The factions' scores in a sector of the `SectorGrid` and the faction controlling it, if any
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SectorControl {
+    pub sector_id: String,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub controlling_faction: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub control_score: HashMap<String, f64>,
+}
+
+/// Automatically selects the closest hostile entity within `scan_radius` as the entity's target.
+/// When `preferred_target_type` is set, hostiles whose transponder `object_type` matches it are
+/// chosen over closer hostiles of other types
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TargetingComputer {
+    pub scan_radius: f64, // Range of the targeting computer as a radius in km
+    pub preferred_target_type: Option<String>,
+    pub current_target: Option<String>, // Entity ID of the selected target
+}
+
+/// Represents a survey scanner. Once the scan has run for its full duration, every hidden
+/// resource within `scan_radius` of the scanning entity is revealed
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SurveyScanner {
+    pub scan_radius: f64,                // Range of the scan as a radius in km
+    pub scan_duration_remaining_ms: f64, // Time remaining until the scan completes
+    pub shard: String,                   // The shard being surveyed
+}
+
+/// Marks a resource that cannot be seen until it has been found by a survey scan
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HiddenResource {
+    pub revealed: bool,
+}
+
+/// The list of entity IDs of every hidden resource revealed by an entity's survey scans
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SurveyLog {
+    pub revealed: Vec<String>,
+}
+
+/// Represents one end of a wormhole. Entities that fly close enough to the wormhole's
+/// position are pulled in and, after `transit_time_ms`, emerge at `exit_position`
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Wormhole {
+    pub pair_id: String, // Entity ID of the wormhole at the other end of this one
+    pub exit_position: Position, // Where entities emerge, should be outside the capture range of the paired wormhole
+    pub transit_time_ms: u64,    // Time spent inside the wormhole, in milliseconds
+    pub stability: f64, // 0.0 - 1.0, the chance of emerging at the exit rather than being ejected nearby
+}
+
+/// Attached to an entity while it is travelling through a wormhole
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct WormholeTransit {
+    pub entry_time_ms: u64, // Game time at which the entity entered the wormhole
+    pub exit_position: Position, // Where the entity will emerge
+    pub transit_time_ms: u64, // Time spent inside the wormhole, in milliseconds
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub exit_wormhole_id: String, // Entity ID of the wormhole the entity emerges from
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub radar_signature: Option<f64>, // The transponder's radar signature on entry, restored on exit
+}
+
+/// Attached to an entity that has emerged from a wormhole until it leaves that wormhole's
+/// capture range, so it isn't pulled straight back in
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct WormholeExit {
+    pub wormhole_id: String,
+}
+
+/// A zone of `radius` km around `center` that can only be entered while the gate is unlocked.
+/// An entity carrying an inventory item named `required_item` unlocks the gate, which locks
+/// itself again `relock_after_ms` after it was unlocked
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AccessGate {
+    pub center: Position,
+    pub radius: f64,
+    pub required_item: String, // Name of the inventory item that opens the gate
+    pub locked: bool,
+    #[cfg_attr(feature = "serde", serde(default = "default_relock_after_ms"))]
+    pub relock_after_ms: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unlocked_at_ms: Option<u64>, // Game time at which the gate was last unlocked
+}
+
+impl AccessGate {
+    /// Whether an unlocked gate has been open long enough to lock again. Gates unlocked
+    /// without a timestamp are never due
+    pub fn relock_due(&self, now_ms: u64) -> bool {
+        !self.locked
+            && self
+                .unlocked_at_ms
+                .is_some_and(|unlocked| now_ms >= unlocked.saturating_add(self.relock_after_ms))
+    }
+}
+
+#[cfg(feature = "serde")]
+fn default_relock_after_ms() -> u64 {
+    30_000
+}
+
+/// One of an entity's thrusters. At full throttle it adds `max_force` KPH per second to the
+/// entity's velocity along `direction`
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Thruster {
+    pub direction: Position, // Unit vector the thruster pushes the entity along
+    pub max_force: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub current_throttle: f64, // 0.0 - 1.0
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub heat_per_ms: f64, // Heat the thruster gives off per millisecond at full throttle
+}
+
+impl Thruster {
+    /// The force the thruster currently exerts along each axis. Directions that aren't unit
+    /// vectors are normalized and the throttle is kept within 0.0 - 1.0
+    pub fn force(&self) -> Position {
+        let length = self.direction.distance_to_3d(&Position::default());
+        if length == 0.0 || !length.is_finite() {
+            return Position::default();
+        }
+        let force = self.max_force * clamp_throttle(self.current_throttle) / length;
+        Position::new(
+            self.direction.x * force,
+            self.direction.y * force,
+            self.direction.z * force,
+        )
+    }
+}
+
+/// Keeps a throttle setting within 0.0 - 1.0, treating anything that isn't a number as 0.0
+pub fn clamp_throttle(throttle: f64) -> f64 {
+    if throttle.is_nan() {
+        0.0
+    } else {
+        throttle.clamp(0.0, 1.0)
+    }
+}
+
+/// The change in an entity's velocity per second, in KPH, along each axis
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Acceleration {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+/// Represents a zone of turbulence. Every frame, entities within `radius` of `center` have a
+/// random perturbation of `intensity` added to their velocity
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NavigationHazard {
+    pub center: Position,
+    pub radius: f64,    // Size of the hazard zone as a radius in km
+    pub intensity: f64, // Magnitude of the perturbation in KPH
+}
+
+/// A body large enough to interfere with the messages of entities within `interference_radius`
+/// of it. The closer an entity is and the heavier the body, the more of its messages are lost
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SignalMass {
+    pub mass: f64,
+    pub interference_radius: f64, // Radius in km beyond which the body causes no interference
+}
+
+/// An axis-aligned bounding box (AABB) spanning from its `min` to its `max` corner
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Aabb {
+    pub min: Position,
+    pub max: Position,
+}
+
+/// Terrain that radar can't see through, such as a dense asteroid field
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TerrainObstacle {
+    pub bounding_box: Aabb,
+}
+
+/// Running totals of the messages a system checked against signal interference and the number
+/// of those that were dropped
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DroppedMessageCounter {
+    pub system: String,
+    pub checked: u64,
+    pub dropped: u64,
+}
+
+/// Represents a turret that rotates toward `target_entity` at a limited rate. Angles are in radians,
+/// azimuth is measured in the x/y plane and elevation from the z axis, as in `TargetVector`
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Turret {
+    pub current_azimuth: f64,
+    pub current_elevation: f64,
+    pub turn_rate_rad_per_ms: f64, // Maximum rotation per millisecond on each axis
+    pub target_entity: Option<String>, // Entity ID of the target in the turret's shard
+    pub fire_when_aimed: bool,     // Fire automatically once the turret is on target
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub heat_per_shot: f64, // Heat the turret gives off each time it fires
+}
+
+/// The heat an entity's weapons and engines have built up. The entity overheats at
+/// `overheat_threshold` and shuts down at `max_heat`, see `thermal`
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ThermalProfile {
+    pub current_heat: f64,
+    pub max_heat: f64,
+    pub dissipation_rate_per_ms: f64,
+    pub overheat_threshold: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub heat_sources: BTreeMap<String, f64>, // RID of each component -> its share of `current_heat`
+}
+
+/// Represents a station's fuel supply, used to refuel the ships docked to it
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FuelDepot {
+    pub fuel_available: f64,     // Units of fuel remaining in the depot
+    pub refuel_rate_per_ms: f64, // Units of fuel transferred to each docked ship per millisecond
+    pub fuel_type: String,       // The type of fuel the depot dispenses
+}
+
+/// Attached to a ship while it is docked to a station
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DockedShip {
+    pub docked_to: String, // Entity ID of the station the ship is docked to
+}
+
+/// An entity that fills a buffer with a resource over time, e.g. a refinery or a mine
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ResourceProducer {
+    pub output_resource: String, // The resource produced
+    pub output_rate_per_ms: f64, // Units produced per millisecond
+    pub output_buffer: f64,      // Units produced and not yet taken by a consumer
+    pub max_buffer: f64,         // Units the buffer can hold, production stops once it is full
+}
+
+/// An entity that draws a resource from the producer it is linked to over time
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ResourceConsumer {
+    pub input_resource: String, // The resource consumed, must match the producer's output
+    pub input_rate_per_ms: f64, // Units drawn from the producer per millisecond
+    pub input_buffer: f64,      // Units received so far
+    pub max_buffer: f64,        // Units the buffer can hold
+}
+
+/// Connects a consumer to the producer it draws its resource from
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SupplyLink {
+    pub producer_entity: String, // Entity ID of the producer
+}
+
+/// The energy a ship's systems draw on while they operate
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EnergyCore {
+    pub max_energy: f64,
+    pub current_energy: f64,
+    pub regen_rate_per_ms: f64, // Energy regained per millisecond, up to `max_energy`
+}
+
+/// What a system costs to run, an entity's `energy_costs` component is a list of these
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EnergyCost {
+    pub system: String, // Name of the system, e.g. `mining`
+    pub cost_per_ms: f64,
+}
+
+/// Breaks items down into raw materials, one item per cycle
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ResourceRecycler {
+    pub efficiency: f64,    // Fraction of an item's recycle value recovered
+    pub cycle_time_ms: f64, // Time it takes to recycle one item
+    pub remaining_ms: f64,  // Time left in the current cycle, the recycler is ready at 0
+}
+
+/// The items waiting to be recycled, front first
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RecycleQueue {
+    pub items: VecDeque<String>, // Names of the items
+}
+
+/// What is known about an item regardless of who holds it, stored under
+/// `decs:item_properties:{name}`
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ItemProperties {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub recycle_value: HashMap<String, f64>, // Raw material stack type -> quantity recovered at full efficiency
+}
+
+/// The tier of a ship's hull, limiting how many weapons, modules and crew members it can carry
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ShipClass {
+    pub tier: u8,
+    pub max_weapon_slots: u8,  // Items the `weapons` collection can hold
+    pub max_module_slots: u8,  // Items the `modules` collection can hold
+    pub max_crew: u32,         // Members the `crew` collection can hold
+    pub max_cargo_volume: f64, // Volume the ship's cargo can take up
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
-pub struct CreditWallet {
+/// Represents a ship's fuel tank
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FuelTank {
+    pub current: f64, // Units of fuel in the tank
+    pub max: f64,     // Capacity of the tank
+}
+
+/// Represents the structural health of an entity's hull
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HullIntegrity {
+    pub current: f64, // Hit points left
+    pub max: f64,     // Hit points of an undamaged hull
+}
+
+impl HullIntegrity {
+    /// The hull's remaining integrity as a percentage between 0 and 100. A hull without any
+    /// hit points to lose counts as destroyed
+    pub fn percent(&self) -> f64 {
+        if self.max <= 0.0 || self.max.is_nan() {
+            return 0.0;
+        }
+        (self.current / self.max * 100.0).clamp(0.0, 100.0)
+    }
+}
+
+/// What an entity was doing when it was destroyed, kept for an hour after its destruction
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DeathRecap {
+    pub entity_id: String,
+    pub last_position: Position,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub killer_entity: Option<String>,
+    pub cause_of_death: String,
+    pub final_hull_hp: f64,
+    pub active_components: Vec<String>, // The components the entity still had, e.g. `turret`
+    pub timestamp_ms: u64,              // Game time of the destruction
+}
+
+/// Removes an entity that has been left alone for too long, e.g. a derelict ship. Any change to
+/// one of the entity's components resets `idle_since_ms` to the current game time
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SurvivalTimer {
+    pub idle_since_ms: u64, // Game time of the latest change to the entity
+    pub max_idle_ms: u64,
+}
+
+impl SurvivalTimer {
+    /// Whether the entity has been idle for longer than it may be
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        now_ms.saturating_sub(self.idle_since_ms) > self.max_idle_ms
+    }
+}
+
+/// Sends an entity fleeing from the nearest hostile once its hull integrity drops below
+/// `hull_threshold_pct`. The protocol only fires once, until `activated` is reset
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EscapeProtocol {
+    pub hull_threshold_pct: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub escape_vector: Option<Position>, // Unit vector of the direction the entity fled in
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub activated: bool,
+}
+
+/// A message to publish once, when the game time reaches `trigger_time_ms`
+#[cfg(feature = "json")]
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ScheduledEvent {
+    pub trigger_time_ms: u64, // Game time at which the event is published
+    pub event_topic: String,  // Subject the payload is published on
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub payload: serde_json::Value,
+}
+
+/// What a player has to do to earn an achievement: mine a number of resource units, travel a
+/// distance in km or destroy a number of enemies
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum AchievementTrigger {
+    MineNResources(u32),
+    TravelDistance(f64),
+    KillNEnemies(u32),
+}
+
+impl AchievementTrigger {
+    /// The progress at which the achievement is earned
+    pub fn threshold(&self) -> f64 {
+        match self {
+            AchievementTrigger::MineNResources(n) => f64::from(*n),
+            AchievementTrigger::TravelDistance(km) => *km,
+            AchievementTrigger::KillNEnemies(n) => f64::from(*n),
+        }
+    }
+
+    /// Whether the progress has reached the threshold
+    pub fn is_met(&self, current: f64) -> bool {
+        current >= self.threshold()
+    }
+}
+
+/// Credits paid into a player's wallet when they earn an achievement
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Reward {
     pub credits: i32,
 }
 
+/// An achievement players of a shard can earn once
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AchievementDefinition {
+    pub id: String,
+    pub trigger: AchievementTrigger,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub reward: Reward,
+}
+
+/// How far a player has come towards an achievement. A completed achievement can't be
+/// earned again
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AchievementProgress {
+    pub id: String, // ID of the achievement's definition
+    pub current: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub completed: bool,
+}
+
 #[cfg(test)]
 mod test {
-    use super::{Position, Velocity};
+    use super::{
+        InventoryItem, MiningExtractor, MiningResource, Position, RadarHistoryLog, RadarSnapshot,
+        Rarity, SectorGrid, UniverseMetadata, Velocity,
+    };
+    #[cfg(feature = "json")]
+    use super::{
+        MiningFeedEvent, MiningLock, MiningOutcome, RadarContact, RadarReceiver, RadarTransponder,
+    };
+    #[cfg(feature = "json")]
+    use crate::migration::migrate_component;
+    #[cfg(feature = "json")]
+    use crate::units::Distance;
+    use crate::units::Millis;
 
     const FLOATEPSILON: f64 = std::f64::EPSILON;
     const PI: f64 = std::f64::consts::PI;
     const DEGREE_CONVERSION: f64 = 180_f64 / PI;
 
+    #[test]
+    fn vector_arithmetic() {
+        let a = Position::new(1.0, 2.0, 3.0);
+        let b = Position::new(-4.0, 0.5, 2.0);
+        assert_eq!(a + b, Position::new(-3.0, 2.5, 5.0));
+        assert_eq!(a - b, Position::new(5.0, 1.5, 1.0));
+        assert_eq!(a * 2.0, Position::new(2.0, 4.0, 6.0));
+        assert_eq!(-a, Position::new(-1.0, -2.0, -3.0));
+        assert_eq!(a + b - b, a);
+        assert_eq!(a.dot(&b), 3.0);
+        assert_eq!(Position::new(3.0, 4.0, 12.0).magnitude(), 13.0);
+        // The distance between two positions is the length of their difference
+        assert!(((a - b).magnitude() - a.distance_to_3d(&b)).abs() < FLOATEPSILON * 10.0);
+    }
+
+    #[test]
+    fn normalized_vectors_have_unit_length() {
+        for v in &[
+            Position::new(1.0, 0.0, 0.0),
+            Position::new(3.0, -4.0, 12.0),
+            Position::new(1e-9, 2e-9, -3e-9),
+            Position::new(-12345.6, 789.0, 0.25),
+        ] {
+            let unit = v.normalize();
+            assert!((unit.magnitude() - 1.0).abs() < 1e-12, "{:?}", v);
+            // Same direction
+            assert!((unit.dot(v) - v.magnitude()).abs() < 1e-6 * v.magnitude().max(1.0));
+        }
+        assert_eq!(Position::default().normalize(), Position::default());
+    }
+
+    #[test]
+    fn perpendicular_vectors_have_zero_dot_product() {
+        let pairs = [
+            (Position::new(1.0, 0.0, 0.0), Position::new(0.0, 1.0, 0.0)),
+            (Position::new(2.0, 3.0, 0.0), Position::new(-3.0, 2.0, 7.0)),
+            (Position::new(1.0, 1.0, 1.0), Position::new(1.0, -2.0, 1.0)),
+        ];
+        for (a, b) in &pairs {
+            assert!(a.dot(b).abs() < FLOATEPSILON);
+            assert!(a.normalize().dot(&b.normalize()).abs() < FLOATEPSILON);
+        }
+    }
+
+    #[test]
+    fn lerp_between_positions() {
+        let from = Position::new(0.0, 10.0, -4.0);
+        let to = Position::new(10.0, 20.0, 4.0);
+        assert_eq!(from.lerp(&to, 0.0), from);
+        assert_eq!(from.lerp(&to, 1.0), to);
+        assert_eq!(from.lerp(&to, 0.5), Position::new(5.0, 15.0, 0.0));
+        assert_eq!(from.midpoint(&to), from.lerp(&to, 0.5));
+        assert_eq!(to.midpoint(&from), from.midpoint(&to));
+    }
+
+    #[test]
+    fn lerp_clamps_and_lerp_unclamped_extrapolates() {
+        let from = Position::new(0.0, 10.0, -4.0);
+        let to = Position::new(10.0, 20.0, 4.0);
+        assert_eq!(from.lerp(&to, -0.5), from);
+        assert_eq!(from.lerp(&to, 1.5), to);
+        assert_eq!(
+            from.lerp_unclamped(&to, 1.5),
+            Position::new(15.0, 25.0, 8.0)
+        );
+        assert_eq!(
+            from.lerp_unclamped(&to, -0.5),
+            Position::new(-5.0, 5.0, -8.0)
+        );
+        assert_eq!(from.lerp_unclamped(&to, 0.25), from.lerp(&to, 0.25));
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn vector_math_leaves_serialization_unchanged() {
+        let moved = Position::new(1.0, 2.0, 3.0) + Position::new(0.5, 0.5, 0.5) * 2.0;
+        assert_eq!(
+            serde_json::to_value(moved).unwrap(),
+            serde_json::json!({ "x": 2.0, "y": 3.0, "z": 4.0 })
+        );
+    }
+
     #[test]
     fn simple_distance_1() {
         let p1 = Position::new(5.0, 7.0, 9.0);
@@ -292,4 +1873,487 @@ mod test {
         assert!((159.762 - v.azimuth) <= FLOATEPSILON);
         assert!((60.5169 - v.elevation) <= FLOATEPSILON);
     }
+
+    #[test]
+    fn velocity_heading_round_trip() {
+        for &(azimuth, elevation) in &[
+            (0.0, 90.0),
+            (45.0, 30.0),
+            (159.762, 60.5169),
+            (-20.2379, 120.0),
+            (-135.0, 179.0),
+        ] {
+            let v = Velocity::from_heading(3000, azimuth, elevation);
+            assert_eq!(v.mag, 3000);
+            assert!((v.ux.powi(2) + v.uy.powi(2) + v.uz.powi(2) - 1.0).abs() < 1e-12);
+            let (a, e) = v.heading();
+            assert!((a - azimuth).abs() < 1e-9, "azimuth {} != {}", a, azimuth);
+            assert!(
+                (e - elevation).abs() < 1e-9,
+                "elevation {} != {}",
+                e,
+                elevation
+            );
+        }
+
+        // Agrees with the vector to a target in the same direction
+        let p1 = Position::new(1_200.12, -60.14, 654.0);
+        let target = p1.vector_to(&Position::new(647.5, 143.6, 987.0));
+        let v = Velocity::new(3000, target.ux, target.uy, target.uz);
+        let (azimuth, elevation) = v.heading();
+        assert!((azimuth - target.azimuth).abs() < 1e-9);
+        assert!((elevation - target.elevation).abs() < 1e-9);
+
+        // Straight up
+        let (azimuth, elevation) = Velocity::from_heading(10, 75.0, 0.0).heading();
+        assert_eq!((azimuth, elevation), (0.0, 0.0));
+    }
+
+    #[test]
+    fn velocity_zero_speed() {
+        // Stopped, the velocity keeps its heading but goes nowhere
+        let v = Velocity::from_heading(0, 90.0, 90.0);
+        let (azimuth, elevation) = v.heading();
+        assert!((azimuth - 90.0).abs() < 1e-9);
+        assert!((elevation - 90.0).abs() < 1e-9);
+        assert_eq!(v.displacement(60_000), Position::default());
+
+        // Without a direction there is no heading to speak of
+        assert_eq!(Velocity::default().heading(), (0.0, 0.0));
+        assert_eq!(
+            Velocity::default().displacement(60_000),
+            Position::default()
+        );
+    }
+
+    #[test]
+    fn velocity_displacement_per_axis() {
+        // 3600 KPH covers a kilometer a second
+        let v = Velocity::new(3600, 0.6, 0.0, -0.8);
+        let d = v.displacement(5000);
+        assert!((d.x - 3.0).abs() < FLOATEPSILON * 10.0);
+        assert_eq!(d.y, 0.0);
+        assert!((d.z + 4.0).abs() < FLOATEPSILON * 10.0);
+        assert!((d.magnitude() - 5.0).abs() < FLOATEPSILON * 10.0);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn velocity_serialization_is_unchanged() {
+        let v = Velocity::from_heading(1200, 0.0, 90.0);
+        let json = serde_json::to_value(v).unwrap();
+        assert_eq!(
+            json.as_object().unwrap().keys().collect::<Vec<_>>(),
+            vec!["mag", "ux", "uy", "uz"]
+        );
+        let stored: Velocity =
+            serde_json::from_str(r#"{"mag": 1200, "ux": 1.0, "uy": 0.0, "uz": 0.0}"#).unwrap();
+        assert_eq!(stored.mag, 1200);
+        assert_eq!(stored.heading(), (0.0, 90.0));
+    }
+
+    #[test]
+    fn sector_ids_follow_the_grid() {
+        let grid = SectorGrid::default();
+        let md = UniverseMetadata::default();
+        assert_eq!(
+            grid.sector_id(&Position::new(-100.0, -100.0, -100.0), &md),
+            "0_0_0"
+        );
+        assert_eq!(
+            grid.sector_id(&Position::new(-49.9, 0.0, 99.0), &md),
+            "1_2_0"
+        );
+        // The far edges and beyond belong to the last sectors
+        assert_eq!(
+            grid.sector_id(&Position::new(100.0, 250.0, -300.0), &md),
+            "3_3_0"
+        );
+    }
+
+    fn extractor(remaining_ms: f64, total_ms: f64) -> MiningExtractor {
+        MiningExtractor {
+            target: "decs.components.the_void.asteroid1.mining_resource".to_string(),
+            remaining_ms: Millis(remaining_ms),
+            total_ms,
+            paused: false,
+            deliver_to: None,
+            max_mining_speed: None,
+            heat: 0.0,
+            overheated: false,
+            heat_limit: None,
+        }
+    }
+
+    #[test]
+    fn inventory_item_from_resource() {
+        let resource = MiningResource {
+            stack_type: "tasty".to_string(),
+            qty: 7,
+            rarity: Rarity::Rare,
+            base_value: None,
+        };
+        assert_eq!(
+            InventoryItem::from(&resource),
+            InventoryItem {
+                name: "tasty".to_string(),
+                kind: "resource".to_string(),
+                qty: 7,
+                unit_value: 125,
+                rarity: Rarity::Rare,
+                version: 2,
+                deployable: None,
+            }
+        );
+        let unknown = MiningResource {
+            stack_type: "shiny".to_string(),
+            qty: 1,
+            ..MiningResource::default()
+        };
+        assert_eq!(InventoryItem::from(&unknown).unit_value, 0);
+        let valued = MiningResource {
+            base_value: Some(40),
+            rarity: Rarity::Uncommon,
+            ..unknown
+        };
+        assert_eq!(InventoryItem::from(&valued).unit_value, 60);
+    }
+
+    #[test]
+    fn rarity_multipliers_grow_with_rarity() {
+        let tiers = [Rarity::Common, Rarity::Uncommon, Rarity::Rare, Rarity::Epic];
+        assert_eq!(Rarity::Common.duration_multiplier(), 1.0);
+        assert_eq!(Rarity::Common.value_multiplier(), 1.0);
+        for pair in tiers.windows(2) {
+            assert!(pair[0].duration_multiplier() < pair[1].duration_multiplier());
+            assert!(pair[0].value_multiplier() < pair[1].value_multiplier());
+        }
+        assert_eq!(Rarity::Epic.duration_multiplier(), 2.0);
+        assert_eq!(Rarity::Epic.value_multiplier(), 5.0);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn resource_without_rarity_or_value_is_still_readable() {
+        let resource: MiningResource =
+            serde_json::from_str(r#"{"stack_type": "tasty", "qty": 3}"#).unwrap();
+        assert_eq!(resource.rarity, Rarity::Common);
+        assert_eq!(resource.base_value, None);
+        assert_eq!(
+            serde_json::to_value(&resource).unwrap(),
+            serde_json::json!({ "stack_type": "tasty", "qty": 3 })
+        );
+
+        let rare: MiningResource =
+            serde_json::from_str(r#"{"stack_type": "tasty", "qty": 3, "rarity": "rare"}"#).unwrap();
+        assert_eq!(rare.rarity, Rarity::Rare);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn feed_event_omits_what_its_outcome_lacks() {
+        let cancelled = MiningFeedEvent {
+            seq: 7,
+            miner: "player1".to_string(),
+            target: "decs.components.the_void.asteroid1.mining_resource".to_string(),
+            stack_type: None,
+            qty: None,
+            timestamp_ms: 12000,
+            outcome: MiningOutcome::Cancelled,
+            reason: Some("cargo_full".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_value(&cancelled).unwrap(),
+            serde_json::json!({
+                "seq": 7,
+                "miner": "player1",
+                "target": "decs.components.the_void.asteroid1.mining_resource",
+                "timestamp_ms": 12000,
+                "outcome": "cancelled",
+                "reason": "cargo_full"
+            })
+        );
+
+        let completed: MiningFeedEvent = serde_json::from_str(
+            r#"{"seq": 8, "miner": "player1", "target": "t", "stack_type": "tasty", "qty": 3,
+                "timestamp_ms": 13000, "outcome": "completed"}"#,
+        )
+        .unwrap();
+        assert_eq!(completed.outcome, MiningOutcome::Completed);
+        assert_eq!(completed.qty, Some(3));
+        assert_eq!(completed.reason, None);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn inventory_item_qty_defaults_to_one() {
+        let item: InventoryItem =
+            serde_json::from_str(r#"{"name": "critical", "kind": "resource", "unit_value": 100}"#)
+                .unwrap();
+        assert_eq!(item.qty, 1);
+        assert_eq!(item.version, 2);
+    }
+
+    #[test]
+    fn extractor_percent_complete() {
+        assert_eq!(0.0, extractor(1000.0, 1000.0).percent_complete());
+        assert_eq!(25.0, extractor(750.0, 1000.0).percent_complete());
+        assert_eq!(50.0, extractor(500.0, 1000.0).percent_complete());
+        assert_eq!(100.0, extractor(0.0, 1000.0).percent_complete());
+    }
+
+    #[test]
+    fn extractor_percent_complete_clamped() {
+        // More time remaining than the total (e.g. a client-edited extractor) and overshoot
+        assert_eq!(0.0, extractor(1500.0, 1000.0).percent_complete());
+        assert_eq!(100.0, extractor(-20.0, 1000.0).percent_complete());
+    }
+
+    #[test]
+    fn extractor_percent_complete_zero_total() {
+        assert_eq!(0.0, extractor(10.0, 0.0).percent_complete());
+        assert_eq!(100.0, extractor(0.0, 0.0).percent_complete());
+        assert_eq!(0.0, extractor(10.0, f64::NAN).percent_complete());
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn lock_owner_defaults_to_extractor_entity() {
+        let lock: MiningLock =
+            serde_json::from_str(r#"{"extractor": "decs.components.the_void.player1.extractor"}"#)
+                .unwrap();
+        assert_eq!(lock.owner_id(), Some("player1"));
+
+        let lock = MiningLock {
+            owner: "player2".to_string(),
+            ..lock
+        };
+        assert_eq!(lock.owner_id(), Some("player2"));
+    }
+
+    fn snapshot(timestamp_ms: u64) -> RadarSnapshot {
+        RadarSnapshot {
+            timestamp_ms,
+            contacts: vec![],
+        }
+    }
+
+    fn timestamps(log: &RadarHistoryLog) -> Vec<u64> {
+        log.entries.iter().map(|e| e.timestamp_ms).collect()
+    }
+
+    #[test]
+    fn test_radar_history_is_truncated_to_max_entries() {
+        let mut log = RadarHistoryLog {
+            max_entries: 3,
+            ..RadarHistoryLog::default()
+        };
+        for timestamp in 1..=5 {
+            log.record(snapshot(timestamp * 1000));
+        }
+        assert_eq!(timestamps(&log), vec![3000, 4000, 5000]);
+
+        // Shrinking the log drops the oldest entries on the next frame
+        log.max_entries = 1;
+        log.record(snapshot(6000));
+        assert_eq!(timestamps(&log), vec![6000]);
+    }
+
+    #[test]
+    fn test_radar_history_stays_ordered_by_time() {
+        let mut log = RadarHistoryLog {
+            max_entries: 10,
+            ..RadarHistoryLog::default()
+        };
+        for timestamp in &[1000, 3000, 2000, 3000, 0] {
+            log.record(snapshot(*timestamp));
+        }
+        assert_eq!(timestamps(&log), vec![0, 1000, 2000, 3000, 3000]);
+    }
+
+    #[test]
+    fn test_get_radar_at_time_finds_the_closest_snapshot() {
+        let mut log = RadarHistoryLog {
+            max_entries: 10,
+            ..RadarHistoryLog::default()
+        };
+        assert!(log.get_radar_at_time(1000).is_none());
+        for timestamp in &[1000, 2000, 4000] {
+            log.record(snapshot(*timestamp));
+        }
+        let at = |timestamp_ms| log.get_radar_at_time(timestamp_ms).unwrap().timestamp_ms;
+        assert_eq!(at(2000), 2000);
+        assert_eq!(at(0), 1000);
+        assert_eq!(at(9000), 4000);
+        assert_eq!(at(2900), 2000);
+        assert_eq!(at(3100), 4000);
+        // Halfway between two snapshots the earlier one wins
+        assert_eq!(at(1500), 1000);
+    }
+
+    fn heated_extractor(remaining_ms: f64, total_ms: f64, heat: f64) -> MiningExtractor {
+        MiningExtractor {
+            heat,
+            ..extractor(remaining_ms, total_ms)
+        }
+    }
+
+    #[test]
+    fn test_sanitize_keeps_a_sound_extractor() {
+        let sound = heated_extractor(500.0, 1000.0, 20.0);
+        assert_eq!(sound.sanitize(), Ok(sound));
+    }
+
+    #[test]
+    fn test_sanitize_zeroes_negatives() {
+        assert_eq!(
+            heated_extractor(-500.0, 1000.0, 0.0).sanitize(),
+            Ok(heated_extractor(0.0, 1000.0, 0.0))
+        );
+        assert_eq!(
+            heated_extractor(500.0, -1000.0, 0.0).sanitize(),
+            Ok(heated_extractor(500.0, 500.0, 0.0))
+        );
+        assert_eq!(
+            heated_extractor(500.0, 1000.0, -20.0).sanitize(),
+            Ok(heated_extractor(500.0, 1000.0, 0.0))
+        );
+    }
+
+    #[test]
+    fn test_sanitize_rejects_nan_and_infinity() {
+        for bad in &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            for (field, corrupt) in &[
+                ("remaining_ms", heated_extractor(*bad, 1000.0, 0.0)),
+                ("total_ms", heated_extractor(500.0, *bad, 0.0)),
+                ("heat", heated_extractor(500.0, 1000.0, *bad)),
+            ] {
+                let err = corrupt.sanitize().unwrap_err();
+                assert_eq!(err.component, "extractor");
+                assert!(err.reason.starts_with(field), "{}", err.reason);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_old_shapes_are_still_read() {
+        let receiver: RadarReceiver = serde_json::from_str(r#"{"radius": 50.0}"#).unwrap();
+        assert_eq!(receiver.cross_shard_range, None);
+
+        let contact: RadarContact = serde_json::from_str(
+            r#"{"entity_id": "asteroid1", "distance": 10, "distance_xy": 8, "azimuth": 45.0,
+                "elevation": 90.0, "transponder": {"rid": "decs.components.the_void.asteroid1.transponder"}}"#,
+        )
+        .unwrap();
+        assert!(!contact.cross_shard);
+
+        let transponder: RadarTransponder = serde_json::from_str(
+            r#"{"object_type": "asteroid", "display_name": "Rock", "color": "grey"}"#,
+        )
+        .unwrap();
+        assert_eq!(transponder.radar_signature, 1.0);
+        assert!(!transponder.depleted);
+
+        // Extractors from before `total_ms` go through their migration
+        let raw = r#"{"target": "decs.components.the_void.asteroid1.mining_resource", "remaining_ms": 500.0}"#;
+        assert!(serde_json::from_str::<MiningExtractor>(raw).is_err());
+        let extractor = migrate_component::<MiningExtractor>(raw).unwrap();
+        assert!(extractor.is_upgraded());
+        assert_eq!(extractor.into_inner().total_ms, 500.0);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_new_shapes_round_trip() {
+        let receiver = RadarReceiver {
+            radius: Distance(50.0),
+            cross_shard_range: Some(20.0),
+        };
+        let json = serde_json::to_string(&receiver).unwrap();
+        assert_eq!(
+            serde_json::from_str::<RadarReceiver>(&json).unwrap(),
+            receiver
+        );
+
+        let contact = RadarContact {
+            entity_id: "asteroid1".to_string(),
+            distance: Distance(10.4),
+            cross_shard: true,
+            ..RadarContact::default()
+        };
+        let json = serde_json::to_string(&contact).unwrap();
+        // Contacts have always carried whole kilometers
+        assert!(json.contains(r#""distance":10,"#));
+        let contact = RadarContact {
+            distance: Distance(10.0),
+            ..contact
+        };
+        assert_eq!(
+            serde_json::from_str::<RadarContact>(&json).unwrap(),
+            contact
+        );
+
+        let transponder = RadarTransponder {
+            object_type: "asteroid".to_string(),
+            radar_signature: 0.5,
+            depleted: true,
+            ..RadarTransponder::default()
+        };
+        let json = serde_json::to_string(&transponder).unwrap();
+        assert_eq!(
+            serde_json::from_str::<RadarTransponder>(&json).unwrap(),
+            transponder
+        );
+
+        let extractor = MiningExtractor {
+            heat: 12.0,
+            overheated: true,
+            ..extractor(100.0, 1000.0)
+        };
+        let json = serde_json::to_string(&extractor).unwrap();
+        assert_eq!(
+            serde_json::from_str::<MiningExtractor>(&json).unwrap(),
+            extractor
+        );
+
+        let position = Position::new(1.5, -2.0, 3.25);
+        let json = serde_json::to_string(&position).unwrap();
+        assert_eq!(serde_json::from_str::<Position>(&json).unwrap(), position);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_unknown_fields_policy() {
+        // Components a newer version may extend ignore what they don't know
+        let receiver: RadarReceiver =
+            serde_json::from_str(r#"{"radius": 50.0, "jammed": true}"#).unwrap();
+        assert_eq!(receiver.radius, Distance(50.0));
+        let contact: RadarContact = serde_json::from_str(
+            r#"{"entity_id": "asteroid1", "distance": 10, "distance_xy": 8, "azimuth": 45.0,
+                "elevation": 90.0, "transponder": {"rid": "decs.components.the_void.asteroid1.transponder"},
+                "threat_level": 3}"#,
+        )
+        .unwrap();
+        assert_eq!(contact.entity_id, "asteroid1");
+        let transponder: RadarTransponder = serde_json::from_str(
+            r#"{"object_type": "ship", "display_name": "Scout", "color": "red", "icon": "scout.png"}"#,
+        )
+        .unwrap();
+        assert_eq!(transponder.display_name, "Scout");
+        let extractor: MiningExtractor = serde_json::from_str(
+            r#"{"target": "decs.components.the_void.asteroid1.mining_resource", "remaining_ms": 500.0,
+                "total_ms": 1000.0, "drill_bit": "diamond"}"#,
+        )
+        .unwrap();
+        assert_eq!(extractor.percent_complete(), 50.0);
+
+        // A position is a closed shape, and missing coordinates aren't made up either
+        assert!(serde_json::from_str::<Position>(r#"{"x": 1.0, "y": 2.0, "z": 3.0}"#).is_ok());
+        assert!(
+            serde_json::from_str::<Position>(r#"{"x": 1.0, "y": 2.0, "z": 3.0, "w": 4.0}"#)
+                .is_err()
+        );
+        assert!(serde_json::from_str::<Position>(r#"{"x": 1.0, "y": 2.0}"#).is_err());
+    }
 }
@@ -0,0 +1,149 @@
+//! Operational counters. A system counts what happens to it, e.g. the mining system counts
+//! completed and failed extractions, per shard in memory and in the KV store under
+//! `decs:metrics:{shard}:{system}:{counter}`. The in-memory counts are totals since the actor
+//! started. The first frame of a shard's system in each interval of `PUBLISH_INTERVAL` sequence
+//! numbers publishes them, as a map of counter names to counts, on
+//! `event.decs.system.{system}.metrics`. Going by sequence number publishes once per interval
+//! however many entities the system has frames for. The KV counts persist across restarts and are
+//! shared by every instance of the actor.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+pub const PUBLISH_INTERVAL: u64 = 60;
+
+lazy_static! {
+    // (shard, system) -> the system's counters on the shard
+    static ref COUNTERS: Mutex<HashMap<(String, String), CounterSet>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Published once every `PUBLISH_INTERVAL` sequence numbers
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CounterSnapshot {
+    pub shard: String,
+    pub counters: BTreeMap<String, u64>,
+}
+
+/// The counts of one system on one shard
+#[derive(Debug, Default)]
+pub struct CounterSet {
+    counts: BTreeMap<String, u64>,
+    interval: Option<u64>, // The interval of the last frame that published, or of the first seen
+}
+
+impl CounterSet {
+    pub fn increment(&mut self, counter: &str) {
+        *self.counts.entry(counter.to_string()).or_insert(0) += 1;
+    }
+
+    /// Returns the counts if the frame is the first in a new interval
+    pub fn record_frame(&mut self, seq_no: u64) -> Option<BTreeMap<String, u64>> {
+        let interval = seq_no / PUBLISH_INTERVAL;
+        match self.interval {
+            Some(last) if interval <= last => None,
+            Some(_) => {
+                self.interval = Some(interval);
+                Some(self.counts.clone())
+            }
+            None => {
+                self.interval = Some(interval);
+                None
+            }
+        }
+    }
+}
+
+/// Counts an occurrence in memory, returning the KV key to increment to persist it
+pub fn plan_increment(shard: &str, system: &str, counter: &str) -> String {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .entry((shard.to_string(), system.to_string()))
+        .or_default()
+        .increment(counter);
+    counter_key(shard, system, counter)
+}
+
+/// Returns the subject and snapshot to publish if the frame is the first of the shard's system
+/// in a new interval
+pub fn plan_counter_publish(
+    shard: &str,
+    system: &str,
+    seq_no: u64,
+) -> Option<(String, CounterSnapshot)> {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .entry((shard.to_string(), system.to_string()))
+        .or_default()
+        .record_frame(seq_no)
+        .map(|counters| {
+            (
+                counters_subject(system),
+                CounterSnapshot {
+                    shard: shard.to_string(),
+                    counters,
+                },
+            )
+        })
+}
+
+/// The subject the counters of a system are published on
+pub fn counters_subject(system: &str) -> String {
+    format!("event.decs.system.{}.metrics", system)
+}
+
+/// The KV key a counter of a shard's system is persisted under
+pub fn counter_key(shard: &str, system: &str, counter: &str) -> String {
+    format!("decs:metrics:{}:{}:{}", shard, system, counter)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{plan_counter_publish, plan_increment};
+    use super::{CounterSet, PUBLISH_INTERVAL};
+
+    #[test]
+    fn test_counts_are_published_once_per_interval() {
+        let mut set = CounterSet::default();
+        set.increment("completed");
+        set.increment("completed");
+        set.increment("cancelled");
+        // The first frame seen only starts the interval
+        assert_eq!(set.record_frame(5), None);
+        assert_eq!(set.record_frame(PUBLISH_INTERVAL - 1), None);
+        let counts = set.record_frame(PUBLISH_INTERVAL).unwrap();
+        assert_eq!(counts["completed"], 2);
+        assert_eq!(counts["cancelled"], 1);
+        assert_eq!(counts.len(), 2);
+        // Other entities' frames in the same interval don't publish again
+        assert_eq!(set.record_frame(PUBLISH_INTERVAL), None);
+        assert_eq!(set.record_frame(PUBLISH_INTERVAL + 1), None);
+
+        // Counts keep accumulating across publishes
+        set.increment("completed");
+        assert_eq!(
+            set.record_frame(3 * PUBLISH_INTERVAL).unwrap()["completed"],
+            3
+        );
+    }
+
+    #[test]
+    fn test_shards_are_counted_separately() {
+        assert_eq!(
+            plan_increment("counted_shard", "mining", "completed"),
+            "decs:metrics:counted_shard:mining:completed"
+        );
+        plan_increment("other_shard", "mining", "completed");
+        plan_increment("other_shard", "mining", "completed");
+
+        assert_eq!(plan_counter_publish("counted_shard", "mining", 1), None);
+        let (subject, snapshot) =
+            plan_counter_publish("counted_shard", "mining", PUBLISH_INTERVAL).unwrap();
+        assert_eq!(subject, "event.decs.system.mining.metrics");
+        assert_eq!(snapshot.shard, "counted_shard");
+        assert_eq!(snapshot.counters["completed"], 1);
+    }
+}
@@ -0,0 +1,197 @@
+//! Ship energy. An entity with an `energy_core` and a list of `energy_costs` pays for every frame
+//! one of its systems runs with `cost_per_ms * elapsed_ms` of the core's `current_energy`, and the
+//! core regains `regen_rate_per_ms * elapsed_ms` each frame. Systems are powered in the order of
+//! `ENERGY_PRIORITY`: the energy a system may draw on is what is left once the frame's costs of
+//! every higher priority system are set aside, so running low shuts down navigation first and
+//! the shields last. A system without a cost, or an entity without a core, runs for free.
+
+use crate::components::{EnergyCore, EnergyCost};
+
+pub const SHIELD: &str = "shield";
+pub const WEAPONS: &str = "weapons";
+pub const MINING: &str = "mining";
+pub const NAVIGATION: &str = "navigation";
+
+/// Systems in the order they are powered, systems not listed come after all of these
+pub const ENERGY_PRIORITY: [&str; 4] = [SHIELD, WEAPONS, MINING, NAVIGATION];
+
+/// Published when a system skips its frame for lack of energy
+pub const INSUFFICIENT_ENERGY_EVENT: &str = "event.decs.system.energy.insufficient";
+
+/// The outcome of a system asking for the energy to run a frame
+#[derive(Debug, PartialEq, Clone)]
+pub enum EnergyDraw {
+    /// The system costs nothing to run for this entity
+    Free,
+    /// The system may run, leaving the core with this much energy
+    Powered(EnergyCore),
+    /// The system must skip the frame
+    Insufficient { required: f64, available: f64 },
+}
+
+/// Draws the energy for the system to run for the elapsed time
+pub fn draw_energy(
+    core: Option<&EnergyCore>,
+    costs: &[EnergyCost],
+    system: &str,
+    elapsed_ms: u32,
+) -> EnergyDraw {
+    let (core, cost) = match (core, costs.iter().find(|c| c.system == system)) {
+        (Some(core), Some(cost)) => (core, cost),
+        _ => return EnergyDraw::Free,
+    };
+    let elapsed_ms = f64::from(elapsed_ms);
+    let required = cost.cost_per_ms * elapsed_ms;
+    let reserved: f64 = costs
+        .iter()
+        .filter(|c| priority(&c.system) < priority(system))
+        .map(|c| c.cost_per_ms * elapsed_ms)
+        .sum();
+    let available = (core.current_energy - reserved).max(0.0);
+
+    if available < required {
+        EnergyDraw::Insufficient {
+            required,
+            available,
+        }
+    } else {
+        EnergyDraw::Powered(EnergyCore {
+            current_energy: core.current_energy - required,
+            ..core.clone()
+        })
+    }
+}
+
+/// Regains energy for the elapsed time, up to the core's maximum
+pub fn regenerate(core: &EnergyCore, elapsed_ms: u32) -> EnergyCore {
+    EnergyCore {
+        current_energy: (core.current_energy + core.regen_rate_per_ms * f64::from(elapsed_ms))
+            .min(core.max_energy),
+        ..core.clone()
+    }
+}
+
+/// The payload of the `INSUFFICIENT_ENERGY_EVENT` for a system that had to skip its frame
+#[cfg(feature = "json")]
+pub fn insufficient_energy_event(
+    shard: &str,
+    entity_id: &str,
+    system: &str,
+    required: f64,
+    available: f64,
+) -> serde_json::Value {
+    serde_json::json!({
+        "shard": shard,
+        "entity_id": entity_id,
+        "system": system,
+        "required": required,
+        "available": available
+    })
+}
+
+fn priority(system: &str) -> usize {
+    ENERGY_PRIORITY
+        .iter()
+        .position(|s| *s == system)
+        .unwrap_or_else(|| ENERGY_PRIORITY.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{draw_energy, regenerate, EnergyCore, EnergyCost, EnergyDraw};
+    use super::{MINING, NAVIGATION, WEAPONS};
+
+    fn core(current_energy: f64) -> EnergyCore {
+        EnergyCore {
+            max_energy: 100.0,
+            current_energy,
+            regen_rate_per_ms: 0.0,
+        }
+    }
+
+    fn costs() -> Vec<EnergyCost> {
+        vec![
+            EnergyCost {
+                system: NAVIGATION.to_string(),
+                cost_per_ms: 0.01,
+            },
+            EnergyCost {
+                system: MINING.to_string(),
+                cost_per_ms: 0.03,
+            },
+            EnergyCost {
+                system: WEAPONS.to_string(),
+                cost_per_ms: 0.02,
+            },
+        ]
+    }
+
+    /// Runs every system once for a second, highest priority first, returning the core left
+    /// over and the systems that ran
+    fn run_frame(core: EnergyCore) -> (EnergyCore, Vec<&'static str>) {
+        let mut core = core;
+        let mut powered = Vec::new();
+        for system in &[WEAPONS, MINING, NAVIGATION] {
+            if let EnergyDraw::Powered(new_core) = draw_energy(Some(&core), &costs(), system, 1000)
+            {
+                core = new_core;
+                powered.push(*system);
+            }
+        }
+        (core, powered)
+    }
+
+    #[test]
+    fn test_systems_without_cost_or_core_run_free() {
+        assert_eq!(draw_energy(None, &costs(), MINING, 1000), EnergyDraw::Free);
+        assert_eq!(
+            draw_energy(Some(&core(0.0)), &costs(), "radar", 1000),
+            EnergyDraw::Free
+        );
+    }
+
+    #[test]
+    fn test_depletion_halts_low_priority_systems_first() {
+        let (core, powered) = run_frame(core(100.0));
+        assert_eq!(powered, vec![WEAPONS, MINING]);
+        assert_eq!(core.current_energy, 50.0);
+
+        let (core, powered) = run_frame(core);
+        assert_eq!(powered, vec![WEAPONS]);
+        assert_eq!(core.current_energy, 30.0);
+
+        let (core, powered) = run_frame(core);
+        assert_eq!(powered, vec![WEAPONS]);
+        assert_eq!(core.current_energy, 10.0);
+
+        let (core, powered) = run_frame(core);
+        assert!(powered.is_empty());
+        assert_eq!(core.current_energy, 10.0);
+    }
+
+    #[test]
+    fn test_energy_is_reserved_for_higher_priority_systems() {
+        // Mining alone could afford its 30, but 20 of the 40 belong to the weapons
+        assert_eq!(
+            draw_energy(Some(&core(40.0)), &costs(), MINING, 1000),
+            EnergyDraw::Insufficient {
+                required: 30.0,
+                available: 20.0
+            }
+        );
+        assert_eq!(
+            draw_energy(Some(&core(40.0)), &costs(), WEAPONS, 1000),
+            EnergyDraw::Powered(core(20.0))
+        );
+    }
+
+    #[test]
+    fn test_regeneration_caps_at_max_energy() {
+        let charging = EnergyCore {
+            regen_rate_per_ms: 0.05,
+            ..core(50.0)
+        };
+        assert_eq!(regenerate(&charging, 500).current_energy, 75.0);
+        assert_eq!(regenerate(&charging, 2000).current_energy, 100.0);
+    }
+}
@@ -0,0 +1,256 @@
+//! Signal interference from large bodies. Messages published by an entity close to a
+//! `signal_mass` may be lost, with a chance that grows with the body's mass and falls off
+//! with the square of the distance to it. Rolls are seeded so a given message in a given
+//! frame is always either dropped or delivered.
+//!
+//! The systems checking their messages cache the signal masses of each shard. Every
+//! `METRIC_INTERVAL` checks, the cache is emptied so bodies that have moved are picked up, and
+//! the running totals are handed back for publishing on `DROPPED_MESSAGES_SUBJECT`.
+
+use crate::components::{DroppedMessageCounter, Position, SignalMass};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+#[cfg(feature = "json")]
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+pub const DROPPED_MESSAGES_SUBJECT: &str = "event.decs.system.signal_interference.dropped";
+const METRIC_INTERVAL: u64 = 100;
+
+// Distances are clamped to this so an entity on top of a body doesn't divide by zero
+const MIN_DISTANCE_KM: f64 = 1.0;
+
+/// The signal masses of a shard and their positions, keyed by the entity carrying them
+pub type SignalMasses = HashMap<String, (Position, SignalMass)>;
+
+lazy_static! {
+    // shard -> its signal masses. A shard is present once loaded, even if it has none
+    static ref SIGNAL_MASSES: RwLock<HashMap<String, SignalMasses>> =
+        RwLock::new(HashMap::new());
+}
+
+static CHECKED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static SINCE_METRIC: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the chance that a message published by the entity at the given position is dropped,
+/// from the cached signal masses of its shard other than the entity's own. A shard that isn't
+/// cached yet is loaded with `load` first
+pub fn shard_drop_chance<E>(
+    shard: &str,
+    entity_id: &str,
+    position: &Position,
+    load: impl FnOnce() -> Result<SignalMasses, E>,
+) -> Result<f64, E> {
+    if let Some(masses) = SIGNAL_MASSES.read().unwrap().get(shard) {
+        return Ok(entity_drop_chance(entity_id, position, masses));
+    }
+    let masses = load()?;
+    let chance = entity_drop_chance(entity_id, position, &masses);
+    SIGNAL_MASSES
+        .write()
+        .unwrap()
+        .insert(shard.to_string(), masses);
+    Ok(chance)
+}
+
+fn entity_drop_chance(entity_id: &str, position: &Position, masses: &SignalMasses) -> f64 {
+    drop_chance(
+        position,
+        masses
+            .iter()
+            .filter(|(mass_entity_id, _)| *mass_entity_id != entity_id)
+            .map(|(_, mass)| mass),
+    )
+}
+
+/// Counts a checked message of the system. Every `METRIC_INTERVAL` checks, the cached signal
+/// masses are forgotten and the running totals returned
+pub fn record_check(system: &str, dropped: bool) -> Option<DroppedMessageCounter> {
+    if dropped {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    let checked = CHECKED.fetch_add(1, Ordering::Relaxed) + 1;
+    if SINCE_METRIC.fetch_add(1, Ordering::Relaxed) + 1 < METRIC_INTERVAL {
+        return None;
+    }
+    SINCE_METRIC.store(0, Ordering::Relaxed);
+    SIGNAL_MASSES.write().unwrap().clear();
+    Some(DroppedMessageCounter {
+        system: system.to_string(),
+        checked,
+        dropped: DROPPED.load(Ordering::Relaxed),
+    })
+}
+
+/// Loads every `signal_mass` of the shard that has a position, given the KV store's
+/// `set_intersect` and `get`
+#[cfg(feature = "json")]
+pub fn load_signal_masses(
+    shard: &str,
+    set_intersect: impl Fn(&[String]) -> Result<Vec<String>, Box<dyn Error>>,
+    get: impl Fn(&str) -> Result<Option<String>, Box<dyn Error>>,
+) -> Result<SignalMasses, Box<dyn Error>> {
+    let mut masses = HashMap::new();
+    for entity in set_intersect(&[
+        format!("decs:{}:signal_mass:entities", shard),
+        format!("decs:{}:position:entities", shard),
+    ])? {
+        let mass_value = get(&format!("decs:components:{}:{}:signal_mass", shard, entity))?;
+        let position_value = get(&format!("decs:components:{}:{}:position", shard, entity))?;
+        if let (Some(mass_str), Some(position_str)) = (mass_value, position_value) {
+            masses.insert(
+                entity,
+                (
+                    serde_json::from_str(&position_str)?,
+                    serde_json::from_str(&mass_str)?,
+                ),
+            );
+        }
+    }
+    Ok(masses)
+}
+
+/// Returns the chance, between 0 and 1, that a message published at the given position is
+/// dropped. Interference from several bodies combines as independent chances
+pub fn drop_chance<'a>(
+    position: &Position,
+    masses: impl IntoIterator<Item = &'a (Position, SignalMass)>,
+) -> f64 {
+    let delivered = masses
+        .into_iter()
+        .filter_map(|(mass_position, mass)| {
+            let distance = position.distance_to_3d(mass_position);
+            if distance > mass.interference_radius {
+                None
+            } else {
+                let distance = distance.max(MIN_DISTANCE_KM);
+                Some((mass.mass / (distance * distance)).clamp(0.0, 1.0))
+            }
+        })
+        .fold(1.0, |delivered, chance| delivered * (1.0 - chance));
+    1.0 - delivered
+}
+
+/// Derives the seed for the roll deciding whether a message is dropped
+pub fn message_seed(entity_id: &str, seq_no: u64, subject: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    entity_id.hash(&mut hasher);
+    seq_no.hash(&mut hasher);
+    subject.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Rolls a number between 0 (inclusive) and 1 (exclusive) from the seed and drops the message
+/// if it falls below the drop chance
+pub fn is_dropped(drop_chance: f64, seed: u64) -> bool {
+    roll(seed) < drop_chance
+}
+
+// SplitMix64, enough to turn a seed into a uniformly distributed number
+fn roll(seed: u64) -> f64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        drop_chance, is_dropped, message_seed, shard_drop_chance, Position, SignalMass,
+        SignalMasses,
+    };
+    use std::cell::Cell;
+
+    const EPSILON: f64 = 1e-9;
+
+    fn mass(x: f64) -> (Position, SignalMass) {
+        (
+            Position::new(x, 0.0, 0.0),
+            SignalMass {
+                mass: 50.0,
+                interference_radius: 20.0,
+            },
+        )
+    }
+
+    #[test]
+    fn test_drop_chance_falls_off_with_distance() {
+        let origin = Position::new(0.0, 0.0, 0.0);
+
+        assert!((drop_chance(&origin, &[mass(10.0)]) - 0.5).abs() < EPSILON);
+        assert!((drop_chance(&origin, &[mass(-10.0), mass(10.0)]) - 0.75).abs() < EPSILON);
+        assert_eq!(drop_chance(&origin, &[mass(2.0)]), 1.0);
+        assert_eq!(drop_chance(&origin, &[mass(25.0)]), 0.0);
+        assert_eq!(drop_chance(&origin, &[]), 0.0);
+    }
+
+    #[test]
+    fn test_fixed_seeds_drop_expected_rate() {
+        let chance = drop_chance(&Position::new(0.0, 0.0, 0.0), &[mass(10.0)]);
+        let dropped = (0..10_000)
+            .filter(|seq_no| is_dropped(chance, message_seed("player1", *seq_no, "subject")))
+            .count();
+
+        // Half of the messages are lost, give or take a couple of percent
+        assert!(dropped > 4_800 && dropped < 5_200, "dropped {}", dropped);
+    }
+
+    #[test]
+    fn test_roll_is_deterministic() {
+        let seed = message_seed(
+            "player1",
+            42,
+            "call.decs.components.the_void.player1.target.set",
+        );
+        assert_eq!(is_dropped(0.5, seed), is_dropped(0.5, seed));
+        assert!(!is_dropped(0.0, seed));
+        assert!(is_dropped(1.0, seed));
+    }
+
+    #[test]
+    fn test_shards_are_loaded_once_even_without_masses() {
+        let loads = Cell::new(0);
+        let load_empty = || -> Result<SignalMasses, ()> {
+            loads.set(loads.get() + 1);
+            Ok(SignalMasses::new())
+        };
+        let origin = Position::new(0.0, 0.0, 0.0);
+        assert_eq!(
+            shard_drop_chance("massless", "player1", &origin, load_empty),
+            Ok(0.0)
+        );
+        assert_eq!(
+            shard_drop_chance("massless", "player1", &origin, load_empty),
+            Ok(0.0)
+        );
+        assert_eq!(loads.get(), 1);
+    }
+
+    #[test]
+    fn test_masses_only_interfere_within_their_shard() {
+        let origin = Position::new(0.0, 0.0, 0.0);
+        let load_planet = || -> Result<SignalMasses, ()> {
+            let mut masses = SignalMasses::new();
+            masses.insert("planet1".to_string(), mass(10.0));
+            Ok(masses)
+        };
+        let chance = shard_drop_chance("planetary", "player1", &origin, load_planet).unwrap();
+        assert!((chance - 0.5).abs() < EPSILON);
+        // The planet is no interference to itself
+        assert_eq!(
+            shard_drop_chance("planetary", "planet1", &mass(10.0).0, load_planet),
+            Ok(0.0)
+        );
+
+        let load_empty = || -> Result<SignalMasses, ()> { Ok(SignalMasses::new()) };
+        assert_eq!(
+            shard_drop_chance("planetless", "player1", &origin, load_empty),
+            Ok(0.0)
+        );
+    }
+}
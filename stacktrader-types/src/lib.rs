@@ -1,4 +1,24 @@
 #[macro_use]
+extern crate lazy_static;
+#[cfg(feature = "serde")]
+#[macro_use]
 extern crate serde_derive;
 
+pub mod approx;
+#[cfg(feature = "json")]
+pub mod batch;
+pub mod buffs;
+pub mod clock;
 pub mod components;
+pub mod counters;
+pub mod energy;
+pub mod interference;
+pub mod metrics;
+#[cfg(feature = "json")]
+pub mod migration;
+pub mod rid;
+pub mod thermal;
+pub mod units;
+pub mod util;
+pub mod validation;
+pub mod wallet;
@@ -0,0 +1,431 @@
+//! Frame processing time metrics. Each system times its frames with `timed_frame`, which
+//! publishes every duration on `event.decs.metrics.{system}.frame_time` and, every
+//! `SUMMARY_INTERVAL` frames, stores a summary of the last `SUMMARY_INTERVAL` durations in the
+//! KV store under `decs:metrics:{shard}:{system}:summary`.
+//!
+//! Frames are timed with the `Clock` the system is given. `SystemClock` is the clock of the
+//! process. `wasm32-unknown-unknown` has no such clock and the host doesn't provide one, so
+//! there a frame is timed from game time instead: its duration is the `elapsed_ms` the system
+//! manager reports since the entity's previous frame. That is the time between frames rather
+//! than the time spent processing one, which grows the same way when a system falls behind.
+
+use decscloud_common::systemmgr::EntityFrame;
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "json")]
+use std::error::Error;
+use std::sync::Mutex;
+
+pub const SUMMARY_INTERVAL: usize = 100;
+
+lazy_static! {
+    // (shard, system) -> durations of the most recent frames
+    static ref WINDOWS: Mutex<HashMap<(String, String), FrameTimeWindow>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Published for every timed frame
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FrameTime {
+    pub entity_id: String,
+    pub shard: String,
+    pub duration_us: u64,
+}
+
+/// Statistics over a window of frame durations, in microseconds
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MetricsSummary {
+    pub min_us: u64,
+    pub max_us: u64,
+    pub avg_us: u64,
+    pub p99_us: u64,
+}
+
+/// The durations of the last `SUMMARY_INTERVAL` frames of a system
+#[derive(Debug, Default)]
+pub struct FrameTimeWindow {
+    durations: VecDeque<u64>,
+    since_summary: usize,
+}
+
+impl FrameTimeWindow {
+    /// Adds a frame duration to the window, returning the window's summary every
+    /// `SUMMARY_INTERVAL` frames
+    pub fn record(&mut self, duration_us: u64) -> Option<MetricsSummary> {
+        if self.durations.len() == SUMMARY_INTERVAL {
+            self.durations.pop_front();
+        }
+        self.durations.push_back(duration_us);
+        self.since_summary += 1;
+        if self.since_summary == SUMMARY_INTERVAL {
+            self.since_summary = 0;
+            summarize(self.durations.iter().cloned())
+        } else {
+            None
+        }
+    }
+}
+
+/// Records a frame duration in the rolling window of the shard's system
+pub fn record_frame_time(shard: &str, system: &str, duration_us: u64) -> Option<MetricsSummary> {
+    WINDOWS
+        .lock()
+        .unwrap()
+        .entry((shard.to_string(), system.to_string()))
+        .or_default()
+        .record(duration_us)
+}
+
+/// Computes the summary of a set of durations. The 99th percentile uses the nearest rank
+pub fn summarize(durations: impl IntoIterator<Item = u64>) -> Option<MetricsSummary> {
+    let mut sorted: Vec<u64> = durations.into_iter().collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_unstable();
+    let n = sorted.len();
+    let p99_rank = ((n as f64) * 0.99).ceil() as usize;
+    Some(MetricsSummary {
+        min_us: sorted[0],
+        max_us: sorted[n - 1],
+        avg_us: sorted.iter().sum::<u64>() / n as u64,
+        p99_us: sorted[p99_rank - 1],
+    })
+}
+
+/// Records the duration of a frame, returning the subject and payload to publish it with and,
+/// every `SUMMARY_INTERVAL` frames, the KV key and summary to store
+pub fn plan_frame_metrics(
+    system: &str,
+    frame: &EntityFrame,
+    duration_us: u64,
+) -> (String, FrameTime, Option<(String, MetricsSummary)>) {
+    let frame_time = FrameTime {
+        entity_id: frame.entity_id.to_string(),
+        shard: frame.shard.to_string(),
+        duration_us,
+    };
+    let summary = record_frame_time(&frame.shard, system, duration_us)
+        .map(|summary| (summary_key(&frame.shard, system), summary));
+    (frame_time_subject(system), frame_time, summary)
+}
+
+/// The subject frame times of a system are published on
+pub fn frame_time_subject(system: &str) -> String {
+    format!("event.decs.metrics.{}.frame_time", system)
+}
+
+/// The system a frame subject, `decs.frames.{shard}.{system}` or `decs.frames.{shard}.{system}.batch`
+/// for a batch, carries frames for
+pub fn frame_system(subject: &str) -> &str {
+    subject.split('.').nth(3).unwrap_or_default()
+}
+
+/// The KV key the summary of a shard's system is stored under
+pub fn summary_key(shard: &str, system: &str) -> String {
+    format!("decs:metrics:{}:{}:summary", shard, system)
+}
+
+/// The game time a frame covers, in microseconds
+pub fn game_time_us(frame: &EntityFrame) -> u64 {
+    u64::from(frame.elapsed_ms) * 1000
+}
+
+/// Runs the handler of a message holding a frame, or a batch of frames, and publishes how long
+/// it took with `publish`, storing a summary of the system's recent frame times with `store`
+/// every `SUMMARY_INTERVAL` frames. Each frame of a batch counts an equal share of the batch's
+/// time. While the clock can't tell the time, each frame counts its `game_time_us`. A message
+/// that holds no frames is only handled.
+///
+/// Metrics are best effort: a failure to publish or store them is passed to `log` and the
+/// handler's own result is returned
+#[cfg(feature = "json")]
+pub fn timed_frame<T>(
+    clock: &impl Clock,
+    system: &str,
+    body: &[u8],
+    handler: impl FnOnce() -> Result<T, Box<dyn Error>>,
+    publish: impl Fn(&str, &[u8]) -> Result<(), Box<dyn Error>>,
+    store: impl Fn(&str, &str) -> Result<(), Box<dyn Error>>,
+    log: impl Fn(&str),
+) -> Result<T, Box<dyn Error>> {
+    let frames = match serde_json::from_slice::<EntityFrame>(body) {
+        Ok(frame) => vec![frame],
+        Err(_) => serde_json::from_slice::<Vec<EntityFrame>>(body).unwrap_or_default(),
+    };
+    let stopwatch = Stopwatch::start(clock);
+    let result = handler();
+    if frames.is_empty() {
+        return result;
+    }
+    let elapsed_us = stopwatch.elapsed_us();
+    for frame in &frames {
+        let duration_us = elapsed_us
+            .map(|elapsed_us| elapsed_us / frames.len() as u64)
+            .unwrap_or_else(|| game_time_us(frame));
+        let (subject, frame_time, summary) = plan_frame_metrics(system, frame, duration_us);
+        let published = serde_json::to_vec(&frame_time)
+            .map_err(Into::into)
+            .and_then(|payload| publish(&subject, &payload));
+        if let Err(e) = published {
+            log(&format!(
+                "Failed to publish the frame time of {}: {}",
+                frame.entity_id, e
+            ));
+        }
+        if let Some((key, summary)) = summary {
+            let stored = serde_json::to_string(&summary)
+                .map_err(Into::into)
+                .and_then(|value| store(&key, &value));
+            if let Err(e) = stored {
+                log(&format!(
+                    "Failed to store the frame time summary {}: {}",
+                    key, e
+                ));
+            }
+        }
+    }
+    result
+}
+
+/// Tells the time frames are timed with
+pub trait Clock {
+    /// Microseconds since a fixed point in time, if the clock can tell
+    fn now_us(&self) -> Option<u64>;
+}
+
+/// The clock of the process, on targets that have one. On `wasm32` it can't tell the time, so
+/// frames are timed from game time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn now_us(&self) -> Option<u64> {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|since_epoch| since_epoch.as_micros() as u64)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn now_us(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Measures how long a frame takes to process
+pub struct Stopwatch<'a, C: Clock> {
+    clock: &'a C,
+    start_us: Option<u64>,
+}
+
+impl<'a, C: Clock> Stopwatch<'a, C> {
+    pub fn start(clock: &'a C) -> Self {
+        Stopwatch {
+            clock,
+            start_us: clock.now_us(),
+        }
+    }
+
+    /// Microseconds since the stopwatch was started, if the clock can tell
+    pub fn elapsed_us(&self) -> Option<u64> {
+        Some(self.clock.now_us()?.saturating_sub(self.start_us?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "json")]
+    use super::timed_frame;
+    use super::{frame_system, summarize, Clock, FrameTimeWindow, MetricsSummary};
+    use super::{Stopwatch, SystemClock};
+    #[cfg(feature = "json")]
+    use std::cell::{Cell, RefCell};
+
+    /// A clock that moves on by 300us each time it is read
+    #[cfg(feature = "json")]
+    struct TickingClock(Cell<u64>);
+
+    #[cfg(feature = "json")]
+    impl Clock for TickingClock {
+        fn now_us(&self) -> Option<u64> {
+            self.0.set(self.0.get() + 300);
+            Some(self.0.get())
+        }
+    }
+
+    struct StoppedClock;
+
+    impl Clock for StoppedClock {
+        fn now_us(&self) -> Option<u64> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_summary_over_200_frames() {
+        let mut window = FrameTimeWindow::default();
+        let summaries: Vec<(u64, MetricsSummary)> = (1..=200)
+            .filter_map(|duration_us| window.record(duration_us).map(|s| (duration_us, s)))
+            .collect();
+
+        // One summary per 100 frames, each covering only the last 100 frames
+        assert_eq!(
+            summaries,
+            vec![
+                (
+                    100,
+                    MetricsSummary {
+                        min_us: 1,
+                        max_us: 100,
+                        avg_us: 50,
+                        p99_us: 99,
+                    }
+                ),
+                (
+                    200,
+                    MetricsSummary {
+                        min_us: 101,
+                        max_us: 200,
+                        avg_us: 150,
+                        p99_us: 199,
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_summarize_unordered_durations() {
+        let summary = summarize(vec![30, 10, 20]).unwrap();
+        assert_eq!(summary.min_us, 10);
+        assert_eq!(summary.max_us, 30);
+        assert_eq!(summary.avg_us, 20);
+        assert_eq!(summary.p99_us, 30);
+        assert_eq!(summarize(vec![]), None);
+    }
+
+    #[test]
+    fn test_frame_system() {
+        assert_eq!(frame_system("decs.frames.the_void.radar"), "radar");
+        assert_eq!(frame_system("decs.frames.the_void.radar.batch"), "radar");
+        assert_eq!(frame_system("decs.frames"), "");
+    }
+
+    #[test]
+    fn test_stopwatch_measures_outside_wasm() {
+        assert!(Stopwatch::start(&SystemClock).elapsed_us().is_some());
+        assert_eq!(Stopwatch::start(&StoppedClock).elapsed_us(), None);
+    }
+
+    #[cfg(feature = "json")]
+    fn frame(entity_id: &str, elapsed_ms: u32) -> serde_json::Value {
+        serde_json::json!({
+            "seq_no": 1,
+            "elapsed_ms": elapsed_ms,
+            "shard": "timed_shard",
+            "entity_id": entity_id
+        })
+    }
+
+    /// Times a handler of the message, returning the frame times published
+    #[cfg(feature = "json")]
+    fn time(clock: &impl Clock, body: serde_json::Value) -> Vec<(String, u64)> {
+        let published = RefCell::new(Vec::new());
+        let handled = timed_frame(
+            clock,
+            "timed",
+            &serde_json::to_vec(&body).unwrap(),
+            || Ok(7),
+            |subject, payload| {
+                let frame_time: serde_json::Value = serde_json::from_slice(payload)?;
+                published.borrow_mut().push((
+                    format!("{} {}", subject, frame_time["entity_id"].as_str().unwrap()),
+                    frame_time["duration_us"].as_u64().unwrap(),
+                ));
+                Ok(())
+            },
+            |_, _| Ok(()),
+            |msg| panic!("{}", msg),
+        );
+        assert_eq!(handled.unwrap(), 7);
+        published.into_inner()
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_batches_are_timed_per_frame() {
+        let clock = TickingClock(Cell::new(0));
+        assert_eq!(
+            time(&clock, frame("ship1", 1000)),
+            vec![("event.decs.metrics.timed.frame_time ship1".to_string(), 300)]
+        );
+        assert_eq!(
+            time(
+                &clock,
+                serde_json::json!([frame("ship2", 1000), frame("ship3", 1000)])
+            ),
+            vec![
+                ("event.decs.metrics.timed.frame_time ship2".to_string(), 150),
+                ("event.decs.metrics.timed.frame_time ship3".to_string(), 150),
+            ]
+        );
+
+        // Without a frame, the handler only runs
+        assert!(time(&clock, serde_json::json!({ "params": {} })).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_frames_fall_back_to_game_time() {
+        assert_eq!(
+            time(
+                &StoppedClock,
+                serde_json::json!([frame("ship1", 250), frame("ship2", 40)])
+            ),
+            vec![
+                (
+                    "event.decs.metrics.timed.frame_time ship1".to_string(),
+                    250_000
+                ),
+                (
+                    "event.decs.metrics.timed.frame_time ship2".to_string(),
+                    40_000
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_metrics_failures_keep_the_handler_result() {
+        let logged = RefCell::new(Vec::new());
+        let body = serde_json::to_vec(&frame("ship1", 1000)).unwrap();
+        let handled: Result<u32, _> = timed_frame(
+            &StoppedClock,
+            "failing",
+            &body,
+            || Ok(7),
+            |_, _| Err("no broker".into()),
+            |_, _| Err("no kv".into()),
+            |msg| logged.borrow_mut().push(msg.to_string()),
+        );
+        assert_eq!(handled.unwrap(), 7);
+        assert_eq!(
+            logged.into_inner(),
+            vec!["Failed to publish the frame time of ship1: no broker".to_string()]
+        );
+
+        let handled: Result<u32, _> = timed_frame(
+            &StoppedClock,
+            "failing",
+            &body,
+            || Err("handler failed".into()),
+            |_, _| Err("no broker".into()),
+            |_, _| Ok(()),
+            |_| {},
+        );
+        assert_eq!(handled.unwrap_err().to_string(), "handler failed");
+    }
+}
@@ -0,0 +1,181 @@
+//! Components persisted by an older version of the game can lack fields the current schema
+//! requires. Rather than failing the frame, such components are upgraded on read through
+//! their `Migrate` implementation, and the caller is told so it can persist the upgraded
+//! component and spare the next frame the migration.
+
+use crate::components::{InventoryItem, MiningExtractor, MiningResource};
+use crate::units::Millis;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::error::Error;
+
+/// Upgrades a component from an older schema
+pub trait Migrate: Sized {
+    fn from_legacy(value: Value) -> Result<Self, Box<dyn Error>>;
+}
+
+/// A component read with `migrate_component`
+#[derive(Debug, PartialEq)]
+pub enum Migrated<T> {
+    /// The component was already in the current schema
+    Current(T),
+    /// The component was upgraded from an older schema and should be persisted again
+    Upgraded(T),
+}
+
+impl<T> Migrated<T> {
+    pub fn is_upgraded(&self) -> bool {
+        match self {
+            Migrated::Current(_) => false,
+            Migrated::Upgraded(_) => true,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        match self {
+            Migrated::Current(t) | Migrated::Upgraded(t) => t,
+        }
+    }
+}
+
+/// Deserializes a component, falling back to its legacy migration when the current schema
+/// is missing a field. Any other error is returned as-is
+pub fn migrate_component<T: Migrate + DeserializeOwned>(
+    raw: &str,
+) -> Result<Migrated<T>, Box<dyn Error>> {
+    match serde_json::from_str(raw) {
+        Ok(t) => Ok(Migrated::Current(t)),
+        Err(e) if e.is_data() && e.to_string().starts_with("missing field") => {
+            let value: Value = serde_json::from_str(raw)?;
+            Ok(Migrated::Upgraded(T::from_legacy(value)?))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Extractors created before `total_ms` existed (v1) only know how much time remains, so
+/// that becomes their total duration
+impl Migrate for MiningExtractor {
+    fn from_legacy(value: Value) -> Result<Self, Box<dyn Error>> {
+        let target = value["target"]
+            .as_str()
+            .ok_or("legacy extractor has no target")?;
+        let remaining_ms = value["remaining_ms"]
+            .as_f64()
+            .ok_or("legacy extractor has no remaining_ms")?;
+        Ok(MiningExtractor {
+            target: target.to_string(),
+            remaining_ms: Millis(remaining_ms),
+            total_ms: value["total_ms"].as_f64().unwrap_or(remaining_ms),
+            paused: value["paused"].as_bool().unwrap_or(false),
+            deliver_to: value["deliver_to"].as_str().map(|rid| rid.to_string()),
+            max_mining_speed: value["max_mining_speed"].as_f64(),
+            // Legacy extractors predate heat and run without a limit
+            heat: 0.0,
+            overheated: false,
+            heat_limit: None,
+        })
+    }
+}
+
+/// Inventory items from before items had a version (v1) are the `MiningResource` they were
+/// mined as, named after their stack type. A missing quantity counts as a single unit
+impl Migrate for InventoryItem {
+    fn from_legacy(value: Value) -> Result<Self, Box<dyn Error>> {
+        let stack_type = value["stack_type"]
+            .as_str()
+            .ok_or("legacy inventory item has no stack_type")?;
+        let resource = MiningResource {
+            stack_type: stack_type.to_string(),
+            qty: value["qty"].as_u64().unwrap_or(1) as u32,
+            rarity: serde_json::from_value(value["rarity"].clone()).unwrap_or_default(),
+            base_value: None,
+        };
+        Ok(InventoryItem::from(&resource))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{migrate_component, InventoryItem, Migrated, MiningExtractor};
+    use crate::components::Rarity;
+    use crate::units::Millis;
+
+    const TARGET: &str = "decs.components.the_void.asteroid1.mining_resource";
+
+    #[test]
+    fn test_v1_extractor_is_upgraded() {
+        let migrated: Migrated<MiningExtractor> = migrate_component(&format!(
+            r#"{{"target": "{}", "remaining_ms": 400.0}}"#,
+            TARGET
+        ))
+        .unwrap();
+        assert!(migrated.is_upgraded());
+
+        let extractor = migrated.into_inner();
+        assert_eq!(extractor.total_ms, 400.0);
+        assert_eq!(extractor.remaining_ms, Millis(400.0));
+        assert_eq!(extractor.target, TARGET);
+        assert_eq!(extractor.percent_complete(), 0.0);
+    }
+
+    #[test]
+    fn test_v2_extractor_is_current() {
+        let migrated: Migrated<MiningExtractor> = migrate_component(&format!(
+            r#"{{"target": "{}", "remaining_ms": 400.0, "total_ms": 800.0}}"#,
+            TARGET
+        ))
+        .unwrap();
+        assert_eq!(
+            migrated,
+            Migrated::Current(MiningExtractor {
+                target: TARGET.to_string(),
+                remaining_ms: Millis(400.0),
+                total_ms: 800.0,
+                paused: false,
+                deliver_to: None,
+                max_mining_speed: None,
+                heat: 0.0,
+                overheated: false,
+                heat_limit: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_unmigratable_extractor_is_an_error() {
+        assert!(migrate_component::<MiningExtractor>(r#"{"remaining_ms": 400.0}"#).is_err());
+        assert!(migrate_component::<MiningExtractor>("not json").is_err());
+    }
+
+    #[test]
+    fn test_v1_inventory_item_is_upgraded() {
+        let migrated: Migrated<InventoryItem> =
+            migrate_component(r#"{"stack_type": "spendy", "qty": 4, "rarity": "rare"}"#).unwrap();
+        assert!(migrated.is_upgraded());
+        let item = migrated.into_inner();
+        assert_eq!(item.name, "spendy");
+        assert_eq!(item.kind, "resource");
+        assert_eq!(item.qty, 4);
+        assert_eq!(item.unit_value, 75);
+        assert_eq!(item.rarity, Rarity::Rare);
+        assert_eq!(item.version, 2);
+
+        // Old items without a quantity hold a single unit
+        let single: InventoryItem = migrate_component(r#"{"stack_type": "tasty"}"#)
+            .unwrap()
+            .into_inner();
+        assert_eq!(single.qty, 1);
+        assert!(migrate_component::<InventoryItem>(r#"{"qty": 4}"#).is_err());
+    }
+
+    #[test]
+    fn test_v2_inventory_item_is_current() {
+        let migrated: Migrated<InventoryItem> = migrate_component(
+            r#"{"name": "tasty", "kind": "resource", "qty": 3, "unit_value": 50, "version": 2}"#,
+        )
+        .unwrap();
+        assert!(!migrated.is_upgraded());
+        assert_eq!(migrated.into_inner().qty, 3);
+    }
+}
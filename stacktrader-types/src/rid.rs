@@ -0,0 +1,374 @@
+//! Building and parsing of the resource IDs (rids) components are published under, e.g.
+//! `decs.components.the_void.asteroid1.mining_resource`.
+
+const COMPONENTS_PREFIX: &str = "decs.components";
+const KEY_PREFIX: &str = "decs:components";
+const SEPARATORS: &[char] = &['.', ':'];
+
+/// The longest a shard or entity ID may be, in characters
+pub const MAX_ID_LEN: usize = 64;
+
+/// The characters shard and entity IDs may hold besides ASCII letters and digits. Anything else
+/// could separate the tokens of a NATS subject or a KV key (`.`, `:`, whitespace) or turn a
+/// subject into a wildcard (`*`, `>`)
+pub const ID_PUNCTUATION: &[char] = &['_', '-'];
+
+/// Checks that the shard ID can be embedded in NATS subjects and KV keys, see `ID_PUNCTUATION`
+/// and `MAX_ID_LEN`
+pub fn validate_shard(shard: &str) -> Result<(), String> {
+    validate_id("shard", shard)
+}
+
+/// Checks that the entity ID can be embedded in NATS subjects and KV keys, see `ID_PUNCTUATION`
+/// and `MAX_ID_LEN`
+pub fn validate_entity_id(entity_id: &str) -> Result<(), String> {
+    validate_id("entity ID", entity_id)
+}
+
+fn validate_id(kind: &str, id: &str) -> Result<(), String> {
+    if id.is_empty() {
+        return Err(format!("{} must not be empty", kind));
+    }
+    if id.chars().count() > MAX_ID_LEN {
+        return Err(format!(
+            "{} '{}' is longer than {} characters",
+            kind, id, MAX_ID_LEN
+        ));
+    }
+    match id
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !ID_PUNCTUATION.contains(c))
+    {
+        Some(c) => Err(format!("{} '{}' contains '{}'", kind, id, c)),
+        None => Ok(()),
+    }
+}
+
+/// The parts of a component rid, `decs.components.{shard}.{entity}[.{path}]`. The path is
+/// empty for an rid naming the entity itself
+#[derive(Debug, PartialEq, Clone)]
+pub struct ComponentRid<'a> {
+    pub shard: &'a str,
+    pub entity: &'a str,
+    pub path: Vec<&'a str>,
+}
+
+/// The rid of an entity's component, `decs.components.{shard}.{entity}.{component}`
+pub fn component_rid(shard: &str, entity: &str, component: &str) -> String {
+    format!("{}.{}.{}.{}", COMPONENTS_PREFIX, shard, entity, component)
+}
+
+/// Parses a component rid, returning an error naming the offending rid if it doesn't have
+/// the shape `decs.components.{shard}.{entity}[...]`
+pub fn parse_component_rid(rid: &str) -> Result<ComponentRid<'_>, String> {
+    let parts: Vec<&str> = rid.split('.').collect();
+    match parts.as_slice() {
+        ["decs", "components", shard, entity, path @ ..]
+            if !shard.is_empty() && !entity.is_empty() =>
+        {
+            Ok(ComponentRid {
+                shard,
+                entity,
+                path: path.to_vec(),
+            })
+        }
+        _ => Err(format!(
+            "malformed component rid '{}', expected {}.{{shard}}.{{entity}}[...]",
+            rid, COMPONENTS_PREFIX
+        )),
+    }
+}
+
+/// The ID of an entity's component, which formats both to the rid the component is published
+/// under and to the key it is stored under in the KV store, e.g.
+/// `decs:components:the_void:asteroid1:mining_resource`. No segment may contain the `.` of rids
+/// or the `:` of keys, so an entity ID holding one can't end up naming another component. The
+/// segments following the component name an item of a collection component, e.g.
+/// `decs.components.the_void.player1.radar_contacts.c1`
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct ResourceIdentifier {
+    shard: String,
+    entity: String,
+    component: String,
+    item_path: Vec<String>,
+}
+
+impl ResourceIdentifier {
+    /// The ID of an entity's component, an error if a segment is empty or contains a separator
+    pub fn new(shard: &str, entity: &str, component: &str) -> Result<Self, String> {
+        check_segments(&[shard, entity, component])?;
+        Ok(ResourceIdentifier {
+            shard: shard.to_string(),
+            entity: entity.to_string(),
+            component: component.to_string(),
+            item_path: Vec::new(),
+        })
+    }
+
+    /// The ID of an item of this collection component
+    pub fn item(&self, item: &str) -> Result<Self, String> {
+        check_segments(&[item])?;
+        let mut id = self.clone();
+        id.item_path.push(item.to_string());
+        Ok(id)
+    }
+
+    /// Parses a component rid, `decs.components.{shard}.{entity}.{component}[...]`
+    pub fn parse_rid(rid: &str) -> Result<Self, String> {
+        parse(rid, '.', COMPONENTS_PREFIX, "rid")
+    }
+
+    /// Parses a component's KV key, `decs:components:{shard}:{entity}:{component}[...]`
+    pub fn parse_key(key: &str) -> Result<Self, String> {
+        parse(key, ':', KEY_PREFIX, "key")
+    }
+
+    pub fn shard(&self) -> &str {
+        &self.shard
+    }
+
+    pub fn entity(&self) -> &str {
+        &self.entity
+    }
+
+    pub fn component(&self) -> &str {
+        &self.component
+    }
+
+    /// The segments naming an item of a collection component, empty for the component itself
+    pub fn item_path(&self) -> &[String] {
+        &self.item_path
+    }
+
+    pub fn to_rid(&self) -> String {
+        self.join(COMPONENTS_PREFIX, '.')
+    }
+
+    pub fn to_key(&self) -> String {
+        self.join(KEY_PREFIX, ':')
+    }
+
+    fn join(&self, prefix: &str, separator: char) -> String {
+        let mut joined = prefix.to_string();
+        for segment in [&self.shard, &self.entity, &self.component]
+            .iter()
+            .copied()
+            .chain(&self.item_path)
+        {
+            joined.push(separator);
+            joined.push_str(segment);
+        }
+        joined
+    }
+}
+
+impl std::fmt::Display for ResourceIdentifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.to_rid())
+    }
+}
+
+fn parse(
+    id: &str,
+    separator: char,
+    prefix: &str,
+    kind: &str,
+) -> Result<ResourceIdentifier, String> {
+    let segments: Vec<&str> = id.split(separator).collect();
+    match segments.as_slice() {
+        ["decs", "components", shard, entity, component, item_path @ ..] => {
+            check_segments(&segments[2..])
+                .map_err(|reason| format!("malformed component {} '{}': {}", kind, id, reason))?;
+            Ok(ResourceIdentifier {
+                shard: shard.to_string(),
+                entity: entity.to_string(),
+                component: component.to_string(),
+                item_path: item_path.iter().map(|s| s.to_string()).collect(),
+            })
+        }
+        _ => Err(format!(
+            "malformed component {} '{}', expected {}{}{{shard}}{}{{entity}}{}{{component}}[...]",
+            kind, id, prefix, separator, separator, separator
+        )),
+    }
+}
+
+fn check_segments(segments: &[&str]) -> Result<(), String> {
+    match segments
+        .iter()
+        .find(|segment| segment.is_empty() || segment.contains(SEPARATORS))
+    {
+        Some(segment) => Err(format!(
+            "segment '{}' is empty or contains one of {:?}",
+            segment, SEPARATORS
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{component_rid, parse_component_rid, ComponentRid, ResourceIdentifier};
+    use super::{validate_entity_id, validate_shard, MAX_ID_LEN};
+
+    #[test]
+    fn test_parse_component_rid() {
+        assert_eq!(
+            parse_component_rid("decs.components.the_void.asteroid1.mining_resources.deposit1"),
+            Ok(ComponentRid {
+                shard: "the_void",
+                entity: "asteroid1",
+                path: vec!["mining_resources", "deposit1"],
+            })
+        );
+        assert_eq!(
+            parse_component_rid("decs.components.the_void.asteroid1")
+                .unwrap()
+                .path
+                .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_component_rid_round_trip() {
+        let rid = component_rid("the_void", "asteroid1", "mining_lock");
+        assert_eq!(rid, "decs.components.the_void.asteroid1.mining_lock");
+        assert_eq!(
+            parse_component_rid(&rid),
+            Ok(ComponentRid {
+                shard: "the_void",
+                entity: "asteroid1",
+                path: vec!["mining_lock"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_malformed_rids_are_errors() {
+        for rid in &[
+            "",
+            "asteroid1",
+            "decs.components.the_void",
+            "decs.components..asteroid1",
+            "call.components.the_void.asteroid1.mining_resource",
+        ] {
+            let err = parse_component_rid(rid).unwrap_err();
+            assert!(err.contains(&format!("'{}'", rid)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_resource_identifier_round_trip() {
+        let rid = "decs.components.the_void.asteroid1.mining_resource";
+        let key = "decs:components:the_void:asteroid1:mining_resource";
+        let id = ResourceIdentifier::parse_rid(rid).unwrap();
+        assert_eq!(id.shard(), "the_void");
+        assert_eq!(id.entity(), "asteroid1");
+        assert_eq!(id.component(), "mining_resource");
+        assert!(id.item_path().is_empty());
+        assert_eq!(id.to_rid(), rid);
+        assert_eq!(id.to_key(), key);
+        assert_eq!(ResourceIdentifier::parse_key(key), Ok(id.clone()));
+        assert_eq!(
+            ResourceIdentifier::new("the_void", "asteroid1", "mining_resource"),
+            Ok(id)
+        );
+    }
+
+    #[test]
+    fn test_collection_item_identifiers() {
+        let rid = "decs.components.the_void.player1.radar_contacts.c1";
+        let id = ResourceIdentifier::parse_rid(rid).unwrap();
+        assert_eq!(id.component(), "radar_contacts");
+        assert_eq!(id.item_path(), ["c1"]);
+        assert_eq!(
+            id.to_key(),
+            "decs:components:the_void:player1:radar_contacts:c1"
+        );
+        assert_eq!(
+            ResourceIdentifier::new("the_void", "player1", "radar_contacts")
+                .and_then(|contacts| contacts.item("c1")),
+            Ok(id.clone())
+        );
+        assert_eq!(ResourceIdentifier::parse_key(&id.to_key()), Ok(id));
+
+        let nested = ResourceIdentifier::parse_rid("decs.components.s.e.extractors.x1.history.3");
+        assert_eq!(nested.unwrap().item_path(), ["x1", "history", "3"]);
+    }
+
+    #[test]
+    fn test_malformed_identifiers_are_errors() {
+        for rid in &[
+            "",
+            "asteroid1",
+            "decs.components.the_void.asteroid1",
+            "decs.components..asteroid1.position",
+            "decs.components.the_void.asteroid1.radar_contacts.",
+            "decs:components:the_void:asteroid1:position",
+            "event.components.the_void.asteroid1.position",
+            // A key's separator inside a segment
+            "decs.components.the_void.ship:1.position",
+        ] {
+            let err = ResourceIdentifier::parse_rid(rid).unwrap_err();
+            assert!(err.contains(&format!("'{}'", rid)), "{}", err);
+        }
+        for key in &[
+            "decs.components.the_void.asteroid1.position",
+            "decs:components:the_void:ship.1:position",
+            "decs:components:the_void:asteroid1",
+        ] {
+            let err = ResourceIdentifier::parse_key(key).unwrap_err();
+            assert!(err.contains(&format!("'{}'", key)), "{}", err);
+        }
+        // An entity ID holding a separator would name another component's key
+        assert!(ResourceIdentifier::new("the_void", "ship.1", "position").is_err());
+        assert!(ResourceIdentifier::new("the_void", "ship1", "").is_err());
+        let contacts = ResourceIdentifier::new("the_void", "player1", "radar_contacts").unwrap();
+        assert!(contacts.item("c:1").is_err());
+    }
+
+    #[test]
+    fn test_valid_ids() {
+        for id in &["the_void", "shard-two", "asteroid1", "sector_1_2_0", "X"] {
+            assert_eq!(validate_shard(id), Ok(()));
+            assert_eq!(validate_entity_id(id), Ok(()));
+        }
+        assert!(validate_entity_id(&"a".repeat(MAX_ID_LEN)).is_ok());
+        assert!(validate_entity_id("9b2f6c1e-5d7a-4e1b-8f3c-2a6d9e0b4c7f").is_ok());
+    }
+
+    #[test]
+    fn test_invalid_ids() {
+        // Wildcards, token separators and anything else that could break a subject or a key
+        for id in &[
+            "player*",
+            ">",
+            "ship.1",
+            "ship:1",
+            "ship 1",
+            "ship\t1",
+            "ship\n1",
+            "player$1",
+            "pl/ayer",
+            "schiff_ü",
+        ] {
+            let err = validate_entity_id(id).unwrap_err();
+            assert!(err.contains(&format!("'{}'", id)), "{}", err);
+            assert!(validate_shard(id).is_err());
+        }
+        assert_eq!(
+            validate_entity_id(""),
+            Err("entity ID must not be empty".to_string())
+        );
+        assert_eq!(
+            validate_shard(""),
+            Err("shard must not be empty".to_string())
+        );
+        let long = "a".repeat(MAX_ID_LEN + 1);
+        assert!(validate_entity_id(&long)
+            .unwrap_err()
+            .contains("longer than 64"));
+        assert!(validate_shard(&long).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Thermal buildup. An entity with a `thermal_profile` heats up as its weapons fire and its
+//! engines burn: a turret adds its `heat_per_shot` on each shot and a thruster
+//! `heat_per_ms * throttle * elapsed_ms` each frame. The profile loses
+//! `dissipation_rate_per_ms * elapsed_ms` of its heat every frame. The heat each component added
+//! is kept by the component's RID and dissipates along with the rest, in proportion, so the
+//! hottest component can be picked out once the entity overheats.
+
+use crate::components::ThermalProfile;
+
+/// Published when an entity overheats or shuts down, with the components that were disabled
+pub const OVERHEATING_EVENT: &str = "event.decs.system.thermal.overheating";
+
+/// How hot an entity runs
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ThermalState {
+    Nominal,
+    /// At or above the overheat threshold, its hottest component must be disabled
+    Overheating,
+    /// At or above the maximum heat, all of its systems must shut down
+    Shutdown,
+}
+
+/// Adds the heat given off by the component with the RID
+pub fn add_heat(profile: &ThermalProfile, source: &str, heat: f64) -> ThermalProfile {
+    if heat <= 0.0 {
+        return profile.clone();
+    }
+    let mut heated = ThermalProfile {
+        current_heat: profile.current_heat + heat,
+        ..profile.clone()
+    };
+    *heated.heat_sources.entry(source.to_string()).or_insert(0.0) += heat;
+    heated
+}
+
+/// Loses heat for the elapsed time, never dropping below zero. Each component's share of the
+/// heat shrinks in proportion
+pub fn dissipate(profile: &ThermalProfile, elapsed_ms: u32) -> ThermalProfile {
+    let lost = profile.dissipation_rate_per_ms * f64::from(elapsed_ms);
+    let current_heat = (profile.current_heat - lost).max(0.0);
+    let remaining = if profile.current_heat > 0.0 {
+        current_heat / profile.current_heat
+    } else {
+        0.0
+    };
+    ThermalProfile {
+        current_heat,
+        heat_sources: profile
+            .heat_sources
+            .iter()
+            .map(|(source, heat)| (source.to_string(), heat * remaining))
+            .filter(|(_, heat)| *heat > 0.0)
+            .collect(),
+        ..profile.clone()
+    }
+}
+
+/// Where the profile's heat stands against its thresholds, each reached at its value
+pub fn thermal_state(profile: &ThermalProfile) -> ThermalState {
+    if profile.current_heat >= profile.max_heat {
+        ThermalState::Shutdown
+    } else if profile.current_heat >= profile.overheat_threshold {
+        ThermalState::Overheating
+    } else {
+        ThermalState::Nominal
+    }
+}
+
+/// The component among the candidates whose share of the heat is largest, the first one on a tie
+pub fn hottest_source<'a>(profile: &ThermalProfile, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .copied()
+        .fold(None, |hottest, candidate| {
+            let heat = profile.heat_sources.get(candidate).copied().unwrap_or(0.0);
+            match hottest {
+                Some((_, max)) if max >= heat => hottest,
+                _ => Some((candidate, heat)),
+            }
+        })
+        .map(|(source, _)| source)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{add_heat, dissipate, hottest_source, thermal_state, ThermalState};
+    use crate::components::ThermalProfile;
+
+    fn profile() -> ThermalProfile {
+        ThermalProfile {
+            current_heat: 0.0,
+            max_heat: 100.0,
+            dissipation_rate_per_ms: 0.01,
+            overheat_threshold: 80.0,
+            ..ThermalProfile::default()
+        }
+    }
+
+    #[test]
+    fn heat_sources_dissipate_in_proportion() {
+        let heated = add_heat(&add_heat(&profile(), "turret", 30.0), "engine", 10.0);
+        assert_eq!(heated.current_heat, 40.0);
+        let cooled = dissipate(&heated, 2000);
+        assert_eq!(cooled.current_heat, 20.0);
+        assert_eq!(cooled.heat_sources["turret"], 15.0);
+        assert_eq!(cooled.heat_sources["engine"], 5.0);
+        assert_eq!(
+            hottest_source(&cooled, &["engine", "turret"]),
+            Some("turret")
+        );
+        assert_eq!(hottest_source(&cooled, &["engine"]), Some("engine"));
+        assert_eq!(hottest_source(&cooled, &[]), None);
+
+        // Cooled off completely
+        let cold = dissipate(&cooled, 60_000);
+        assert_eq!(cold.current_heat, 0.0);
+        assert!(cold.heat_sources.is_empty());
+        assert_eq!(thermal_state(&cold), ThermalState::Nominal);
+    }
+
+    #[test]
+    fn thresholds_are_inclusive() {
+        let at = |heat| {
+            thermal_state(&ThermalProfile {
+                current_heat: heat,
+                ..profile()
+            })
+        };
+        assert_eq!(at(79.9), ThermalState::Nominal);
+        assert_eq!(at(80.0), ThermalState::Overheating);
+        assert_eq!(at(100.0), ThermalState::Shutdown);
+    }
+}
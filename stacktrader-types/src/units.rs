@@ -0,0 +1,219 @@
+//! Units for durations and distances. A duration in seconds written where milliseconds are
+//! expected is off by a factor of a thousand and nothing catches it while both are plain `f64`s.
+//! `Millis` and `Distance` only add up, subtract and compare with their own kind, and converting
+//! from another unit is spelled out, e.g. `Millis::from_secs`:
+//!
+//! ```compile_fail
+//! use stacktrader_types::units::{Distance, Millis};
+//! let _ = Millis(1000.0) + Distance(5.0);
+//! ```
+//!
+//! ```compile_fail
+//! use stacktrader_types::units::Millis;
+//! let _ = Millis(1000.0) > 0.0;
+//! ```
+//!
+//! Both are serialized as the plain number they wrap, so the fields using them keep their wire
+//! format, in JSON and in bincode alike.
+
+use std::fmt;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+/// A duration in milliseconds
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Millis(pub f64);
+
+impl Millis {
+    pub fn from_secs(secs: f64) -> Self {
+        Millis(secs * 1000.0)
+    }
+
+    pub fn as_secs(self) -> f64 {
+        self.0 / 1000.0
+    }
+}
+
+/// Whole milliseconds, e.g. the time a frame covers
+impl From<u32> for Millis {
+    fn from(ms: u32) -> Self {
+        Millis(f64::from(ms))
+    }
+}
+
+/// A distance in kilometers
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Distance(pub f64);
+
+impl Distance {
+    pub fn from_meters(meters: f64) -> Self {
+        Distance(meters / 1000.0)
+    }
+
+    pub fn as_meters(self) -> f64 {
+        self.0 * 1000.0
+    }
+}
+
+/// Whole kilometers, e.g. the rounded magnitude of a `TargetVector`
+impl From<u32> for Distance {
+    fn from(km: u32) -> Self {
+        Distance(f64::from(km))
+    }
+}
+
+macro_rules! unit {
+    ($unit:ident) => {
+        impl $unit {
+            pub const ZERO: $unit = $unit(0.0);
+
+            pub fn max(self, other: $unit) -> $unit {
+                $unit(self.0.max(other.0))
+            }
+
+            pub fn min(self, other: $unit) -> $unit {
+                $unit(self.0.min(other.0))
+            }
+
+            pub fn is_finite(self) -> bool {
+                self.0.is_finite()
+            }
+
+            pub fn is_nan(self) -> bool {
+                self.0.is_nan()
+            }
+        }
+
+        impl Add for $unit {
+            type Output = $unit;
+
+            fn add(self, other: $unit) -> $unit {
+                $unit(self.0 + other.0)
+            }
+        }
+
+        impl Sub for $unit {
+            type Output = $unit;
+
+            fn sub(self, other: $unit) -> $unit {
+                $unit(self.0 - other.0)
+            }
+        }
+
+        impl AddAssign for $unit {
+            fn add_assign(&mut self, other: $unit) {
+                self.0 += other.0;
+            }
+        }
+
+        impl SubAssign for $unit {
+            fn sub_assign(&mut self, other: $unit) {
+                self.0 -= other.0;
+            }
+        }
+
+        impl Mul<f64> for $unit {
+            type Output = $unit;
+
+            fn mul(self, factor: f64) -> $unit {
+                $unit(self.0 * factor)
+            }
+        }
+
+        impl Div<f64> for $unit {
+            type Output = $unit;
+
+            fn div(self, divisor: f64) -> $unit {
+                $unit(self.0 / divisor)
+            }
+        }
+
+        /// The ratio of the two
+        impl Div for $unit {
+            type Output = f64;
+
+            fn div(self, other: $unit) -> f64 {
+                self.0 / other.0
+            }
+        }
+
+        impl Neg for $unit {
+            type Output = $unit;
+
+            fn neg(self) -> $unit {
+                $unit(-self.0)
+            }
+        }
+
+        /// The plain number, as it is serialized
+        impl fmt::Display for $unit {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+unit!(Millis);
+unit!(Distance);
+
+/// Serializes a `Distance` as a whole number of kilometers, for the fields that have always held
+/// rounded distances. Used with `#[serde(with = "whole_km")]`
+#[cfg(feature = "serde")]
+pub mod whole_km {
+    use super::Distance;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(distance: &Distance, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(distance.0.round() as u32)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Distance, D::Error> {
+        u32::deserialize(deserializer).map(Distance::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Distance, Millis};
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Millis::from_secs(1.5), Millis(1500.0));
+        assert_eq!(Millis(250.0).as_secs(), 0.25);
+        assert_eq!(Millis::from(16_u32), Millis(16.0));
+        assert_eq!(Distance::from_meters(2500.0), Distance(2.5));
+        assert_eq!(Distance(0.75).as_meters(), 750.0);
+        assert_eq!(Distance::from(12_u32), Distance(12.0));
+    }
+
+    #[test]
+    fn arithmetic_stays_in_the_unit() {
+        let mut remaining = Millis(1000.0);
+        remaining -= Millis::from(400_u32);
+        assert_eq!(remaining, Millis(600.0));
+        assert_eq!(remaining * 0.5 + Millis(100.0), Millis(400.0));
+        assert_eq!((remaining - Millis(900.0)).max(Millis::ZERO), Millis::ZERO);
+        assert_eq!(Millis(600.0) / Millis(1200.0), 0.5);
+        assert!(Distance(5.0) > Distance(4.9));
+        assert_eq!(-Distance(5.0) / 2.0, Distance(-2.5));
+        assert_eq!(format!("{}", Millis(-5.0)), "-5");
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn serialized_as_plain_numbers() {
+        assert_eq!(serde_json::to_string(&Millis(1500.5)).unwrap(), "1500.5");
+        assert_eq!(
+            serde_json::from_str::<Distance>("42").unwrap(),
+            Distance(42.0)
+        );
+        assert_eq!(
+            bincode::serialize(&Millis(1500.5)).unwrap(),
+            bincode::serialize(&1500.5_f64).unwrap()
+        );
+    }
+}
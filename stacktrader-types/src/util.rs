@@ -0,0 +1,132 @@
+//! Component persistence in either JSON or bincode. High-churn components such as `position`
+//! and `velocity` take less space and parse faster as bincode. Each mode stores components under
+//! its own key suffix, so a component can be held in both formats while readers migrate.
+
+#[cfg(feature = "json")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "json")]
+use serde::Serialize;
+#[cfg(feature = "json")]
+use std::error::Error;
+
+/// The format a component is persisted in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SerializationMode {
+    Json,
+    Bincode,
+}
+
+impl SerializationMode {
+    /// The suffix appended to the KV key of a component persisted in this mode
+    pub fn key_suffix(self) -> &'static str {
+        match self {
+            SerializationMode::Json => "json",
+            SerializationMode::Bincode => "bin",
+        }
+    }
+}
+
+/// Serializes a component for persistence
+#[cfg(feature = "json")]
+pub fn serialize_component<T: Serialize>(
+    val: &T,
+    mode: SerializationMode,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    match mode {
+        SerializationMode::Json => Ok(serde_json::to_vec(val)?),
+        SerializationMode::Bincode => Ok(bincode::serialize(val)?),
+    }
+}
+
+/// Deserializes a component persisted with `serialize_component` in the same mode
+#[cfg(feature = "json")]
+pub fn deserialize_component<T: DeserializeOwned>(
+    bytes: &[u8],
+    mode: SerializationMode,
+) -> Result<T, Box<dyn Error>> {
+    match mode {
+        SerializationMode::Json => Ok(serde_json::from_slice(bytes)?),
+        SerializationMode::Bincode => Ok(bincode::deserialize(bytes)?),
+    }
+}
+
+/// The KV key of a component persisted in the given mode, e.g.
+/// `decs:components:the_void:player1:position:bin`
+pub fn component_key(
+    shard: &str,
+    entity_id: &str,
+    component: &str,
+    mode: SerializationMode,
+) -> String {
+    format!(
+        "decs:components:{}:{}:{}:{}",
+        shard,
+        entity_id,
+        component,
+        mode.key_suffix()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{component_key, SerializationMode};
+    #[cfg(feature = "json")]
+    use super::{deserialize_component, serialize_component};
+    #[cfg(feature = "json")]
+    use crate::components::{Position, RadarContact};
+    #[cfg(feature = "json")]
+    use crate::units::Distance;
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_round_trip_in_both_modes() {
+        let position = Position::new(1.5, -20.0, 300.25);
+        let contact = RadarContact {
+            entity_id: "asteroid1".to_string(),
+            distance: Distance(12.0),
+            distance_xy: 10,
+            azimuth: 45.0,
+            elevation: -3.5,
+            transponder: decscloud_common::gateway::ResourceIdentifier {
+                rid: "decs.components.the_void.asteroid1.transponder".to_string(),
+            },
+            cross_shard: false,
+        };
+        for mode in &[SerializationMode::Json, SerializationMode::Bincode] {
+            let bytes = serialize_component(&position, *mode).unwrap();
+            let read: Position = deserialize_component(&bytes, *mode).unwrap();
+            assert_eq!(read, position);
+
+            let bytes = serialize_component(&contact, *mode).unwrap();
+            let read: RadarContact = deserialize_component(&bytes, *mode).unwrap();
+            assert_eq!(read, contact);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_bincode_position_is_smaller() {
+        let position = Position::new(1234.5678, -2345.6789, 3456.789);
+        let json = serialize_component(&position, SerializationMode::Json).unwrap();
+        let bin = serialize_component(&position, SerializationMode::Bincode).unwrap();
+        assert_eq!(bin.len(), 24);
+        assert!(bin.len() < json.len());
+    }
+
+    #[test]
+    fn test_modes_use_separate_keys() {
+        assert_eq!(
+            component_key("the_void", "player1", "position", SerializationMode::Json),
+            "decs:components:the_void:player1:position:json"
+        );
+        assert_eq!(
+            component_key(
+                "the_void",
+                "player1",
+                "position",
+                SerializationMode::Bincode
+            ),
+            "decs:components:the_void:player1:position:bin"
+        );
+    }
+}
@@ -0,0 +1,256 @@
+//! Field constraints checked before a component is written. A component that fails
+//! validation is never published, so a bad calculation cannot corrupt the stored state.
+
+use crate::components::{FuelTank, HeatLimit, MiningExtractor, Position, RadarReceiver};
+use crate::units::{Distance, Millis};
+use std::error::Error;
+use std::fmt;
+
+/// A component field that violates its constraint
+#[derive(Debug, PartialEq, Clone)]
+pub struct ValidationError {
+    pub component: &'static str,
+    pub reason: String,
+}
+
+impl ValidationError {
+    fn new(component: &'static str, reason: String) -> Self {
+        ValidationError { component, reason }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid {}: {}", self.component, self.reason)
+    }
+}
+
+impl Error for ValidationError {}
+
+/// Checks a component's fields against their constraints
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationError>;
+}
+
+impl Validate for RadarReceiver {
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.radius.is_nan() || self.radius <= Distance::ZERO {
+            return Err(ValidationError::new(
+                "radar_receiver",
+                format!("radius must be positive, got {}", self.radius),
+            ));
+        }
+        match self.cross_shard_range {
+            Some(range) if range.is_nan() || range <= 0.0 => Err(ValidationError::new(
+                "radar_receiver",
+                format!("cross_shard_range must be positive, got {}", range),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Validate for MiningExtractor {
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.remaining_ms.is_nan() || self.remaining_ms < Millis::ZERO {
+            Err(ValidationError::new(
+                "extractor",
+                format!(
+                    "remaining_ms must not be negative, got {}",
+                    self.remaining_ms
+                ),
+            ))
+        } else if self.total_ms.is_nan() || self.total_ms <= 0.0 {
+            Err(ValidationError::new(
+                "extractor",
+                format!("total_ms must be positive, got {}", self.total_ms),
+            ))
+        } else if self.max_mining_speed().is_nan() || self.max_mining_speed() <= 0.0 {
+            Err(ValidationError::new(
+                "extractor",
+                format!(
+                    "max_mining_speed must be positive, got {}",
+                    self.max_mining_speed()
+                ),
+            ))
+        } else if let Some(reason) = self.heat_limit.as_ref().and_then(heat_limit_violation) {
+            Err(ValidationError::new("extractor", reason))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Why the heat limit can't work, if it can't: an extractor needs room to heat up, has to cool
+/// down below its limit to resume, and neither heats nor cools backwards
+fn heat_limit_violation(limit: &HeatLimit) -> Option<String> {
+    if limit.max_heat.is_nan() || limit.max_heat <= 0.0 {
+        Some(format!("max_heat must be positive, got {}", limit.max_heat))
+    } else if limit.resume_heat().is_nan() || limit.resume_heat() >= limit.max_heat {
+        Some(format!(
+            "resume_heat must be below max_heat {}, got {}",
+            limit.max_heat,
+            limit.resume_heat()
+        ))
+    } else if limit.heating_rate.is_nan()
+        || limit.heating_rate < 0.0
+        || limit.cooling_rate.is_nan()
+        || limit.cooling_rate < 0.0
+    {
+        Some(format!(
+            "heating_rate and cooling_rate must not be negative, got {} and {}",
+            limit.heating_rate, limit.cooling_rate
+        ))
+    } else {
+        None
+    }
+}
+
+impl Validate for Position {
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.x.is_finite() && self.y.is_finite() && self.z.is_finite() {
+            Ok(())
+        } else {
+            Err(ValidationError::new(
+                "position",
+                format!(
+                    "coordinates must be finite, got ({}, {}, {})",
+                    self.x, self.y, self.z
+                ),
+            ))
+        }
+    }
+}
+
+impl Validate for FuelTank {
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.current >= 0.0 && self.current <= self.max {
+            Ok(())
+        } else {
+            Err(ValidationError::new(
+                "fuel_tank",
+                format!(
+                    "current must be within [0, {}], got {}",
+                    self.max, self.current
+                ),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Distance, FuelTank, HeatLimit, Millis, MiningExtractor, Position, RadarReceiver, Validate,
+    };
+
+    #[test]
+    fn test_extractor_constraints() {
+        let extractor = MiningExtractor {
+            target: "decs.components.the_void.asteroid1.mining_resource".to_string(),
+            remaining_ms: Millis(0.0),
+            total_ms: 1000.0,
+            paused: false,
+            deliver_to: None,
+            max_mining_speed: None,
+            heat: 0.0,
+            overheated: false,
+            heat_limit: None,
+        };
+        assert!(extractor.validate().is_ok());
+
+        let negative = MiningExtractor {
+            remaining_ms: Millis(-1.0),
+            ..extractor.clone()
+        };
+        assert_eq!(negative.validate().unwrap_err().component, "extractor");
+        let no_duration = MiningExtractor {
+            total_ms: 0.0,
+            ..extractor.clone()
+        };
+        assert!(no_duration.validate().is_err());
+        let nan = MiningExtractor {
+            remaining_ms: Millis(f64::NAN),
+            ..extractor.clone()
+        };
+        assert!(nan.validate().is_err());
+        let never_mines = MiningExtractor {
+            max_mining_speed: Some(0.0),
+            ..extractor.clone()
+        };
+        assert!(never_mines.validate().is_err());
+
+        let limit = HeatLimit {
+            max_heat: 3000.0,
+            resume_heat: None,
+            heating_rate: 1.0,
+            cooling_rate: 2.0,
+        };
+        let heated = MiningExtractor {
+            heat_limit: Some(limit.clone()),
+            ..extractor.clone()
+        };
+        assert!(heated.validate().is_ok());
+        let never_resumes = MiningExtractor {
+            heat_limit: Some(HeatLimit {
+                resume_heat: Some(3000.0),
+                ..limit.clone()
+            }),
+            ..extractor.clone()
+        };
+        assert!(never_resumes.validate().is_err());
+        let never_cools = MiningExtractor {
+            heat_limit: Some(HeatLimit {
+                cooling_rate: -1.0,
+                ..limit
+            }),
+            ..extractor
+        };
+        assert!(never_cools.validate().is_err());
+    }
+
+    #[test]
+    fn test_component_constraints() {
+        assert!(RadarReceiver {
+            radius: Distance(10.0),
+            cross_shard_range: None
+        }
+        .validate()
+        .is_ok());
+        assert!(RadarReceiver {
+            radius: Distance(0.0),
+            cross_shard_range: None
+        }
+        .validate()
+        .is_err());
+        assert!(RadarReceiver {
+            radius: Distance(10.0),
+            cross_shard_range: Some(0.0)
+        }
+        .validate()
+        .is_err());
+
+        assert!(Position::new(1.0, -2.0, 3.0).validate().is_ok());
+        assert!(Position::new(f64::NAN, 0.0, 0.0).validate().is_err());
+        assert!(Position::new(0.0, f64::INFINITY, 0.0).validate().is_err());
+
+        assert!(FuelTank {
+            current: 50.0,
+            max: 50.0
+        }
+        .validate()
+        .is_ok());
+        assert!(FuelTank {
+            current: 51.0,
+            max: 50.0
+        }
+        .validate()
+        .is_err());
+        assert!(FuelTank {
+            current: -1.0,
+            max: 50.0
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,256 @@
+//! Credits. Every change to a `wallet` goes through `Wallet::credit` and `Wallet::debit`, which
+//! refuse to leave the wallet with a negative balance or one that no longer fits, rather than
+//! wrapping around or silently capping it. Each change can be recorded as a `TransactionRecord`
+//! for the audit trail.
+
+use crate::components::Wallet;
+#[cfg(feature = "json")]
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+
+/// Why a wallet refused a change. The wallet is left as it was
+#[derive(Debug, PartialEq, Clone)]
+pub enum WalletError {
+    /// Amounts are never negative, a debit takes credits out
+    NegativeAmount(i64),
+    /// The debit is more than the wallet holds
+    InsufficientCredits { balance: i64, amount: i64 },
+    /// The credit would take the balance past `i64::MAX`
+    Overflow { balance: i64, amount: i64 },
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WalletError::NegativeAmount(amount) => {
+                write!(f, "amount must not be negative, got {}", amount)
+            }
+            WalletError::InsufficientCredits { balance, amount } => write!(
+                f,
+                "cannot debit {} credits from a wallet holding {}",
+                amount, balance
+            ),
+            WalletError::Overflow { balance, amount } => write!(
+                f,
+                "crediting {} credits to a wallet holding {} overflows",
+                amount, balance
+            ),
+        }
+    }
+}
+
+impl Error for WalletError {}
+
+impl Wallet {
+    /// The wallet with the amount paid in
+    pub fn credit(&self, amount: i64) -> Result<Wallet, WalletError> {
+        if amount < 0 {
+            return Err(WalletError::NegativeAmount(amount));
+        }
+        match self.credits.checked_add(amount) {
+            Some(credits) => Ok(Wallet { credits }),
+            None => Err(WalletError::Overflow {
+                balance: self.credits,
+                amount,
+            }),
+        }
+    }
+
+    /// The wallet with the amount taken out
+    pub fn debit(&self, amount: i64) -> Result<Wallet, WalletError> {
+        if amount < 0 {
+            return Err(WalletError::NegativeAmount(amount));
+        }
+        match self.credits.checked_sub(amount) {
+            Some(credits) if credits >= 0 => Ok(Wallet { credits }),
+            _ => Err(WalletError::InsufficientCredits {
+                balance: self.credits,
+                amount,
+            }),
+        }
+    }
+}
+
+/// One change to a wallet, as it is published for auditing
+#[derive(Debug, Default, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TransactionRecord {
+    pub shard: String,
+    pub entity_id: String,
+    pub amount: i64,       // Paid in when positive, taken out when negative
+    pub balance: i64,      // The wallet's credits after the change
+    pub reason: String,    // What the credits were for, e.g. `merchant_sale`
+    pub timestamp_ms: u64, // Game time of the change
+}
+
+/// Reads a stored wallet. Wallets written by older versions of the game could hold fractional
+/// credits, which are truncated. The warning returned with the wallet says so, for the caller to
+/// log
+#[cfg(feature = "json")]
+pub fn read_wallet(raw: &str) -> Result<(Wallet, Option<String>), Box<dyn Error>> {
+    let value: Value = serde_json::from_str(raw)?;
+    let warning = match value["credits"].as_f64() {
+        Some(credits) if credits.fract() != 0.0 => Some(format!(
+            "Truncated fractional wallet credits {} to {}",
+            credits,
+            credits.trunc()
+        )),
+        _ => None,
+    };
+    Ok((serde_json::from_value(value)?, warning))
+}
+
+/// Reads credits stored as a whole number, truncating fractional ones, see `read_wallet`. Used
+/// by `Wallet::credits`
+#[cfg(feature = "serde")]
+pub mod credits {
+    use serde::de::{self, Deserialize, Deserializer, Visitor};
+    use std::fmt;
+
+    struct CreditsVisitor;
+
+    impl<'de> Visitor<'de> for CreditsVisitor {
+        type Value = i64;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a number of credits")
+        }
+
+        fn visit_i64<E: de::Error>(self, credits: i64) -> Result<i64, E> {
+            Ok(credits)
+        }
+
+        fn visit_u64<E: de::Error>(self, credits: u64) -> Result<i64, E> {
+            if credits > i64::MAX as u64 {
+                return Err(E::custom(format!("{} credits don't fit a wallet", credits)));
+            }
+            Ok(credits as i64)
+        }
+
+        fn visit_f64<E: de::Error>(self, credits: f64) -> Result<i64, E> {
+            if !credits.is_finite() || credits.trunc().abs() >= i64::MAX as f64 {
+                return Err(E::custom(format!("{} credits don't fit a wallet", credits)));
+            }
+            Ok(credits.trunc() as i64)
+        }
+    }
+
+    /// Only self-describing formats such as JSON can hold a float where an integer is expected.
+    /// Bincode wallets were always written with whole credits
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(CreditsVisitor)
+        } else {
+            i64::deserialize(deserializer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "json")]
+    use super::read_wallet;
+    use super::{Wallet, WalletError};
+
+    #[test]
+    fn credits_and_debits() {
+        let wallet = Wallet { credits: 100 };
+        assert_eq!(wallet.credit(50), Ok(Wallet { credits: 150 }));
+        assert_eq!(wallet.debit(100), Ok(Wallet { credits: 0 }));
+        assert_eq!(wallet.credit(-1), Err(WalletError::NegativeAmount(-1)));
+        assert_eq!(wallet.debit(-1), Err(WalletError::NegativeAmount(-1)));
+    }
+
+    #[test]
+    fn refuses_to_go_negative() {
+        let wallet = Wallet { credits: 100 };
+        assert_eq!(
+            wallet.debit(101),
+            Err(WalletError::InsufficientCredits {
+                balance: 100,
+                amount: 101
+            })
+        );
+        assert_eq!(
+            Wallet { credits: 0 }.debit(i64::MAX),
+            Err(WalletError::InsufficientCredits {
+                balance: 0,
+                amount: i64::MAX
+            })
+        );
+    }
+
+    #[test]
+    fn refuses_to_overflow() {
+        let wallet = Wallet {
+            credits: i64::MAX - 10,
+        };
+        assert_eq!(wallet.credit(10), Ok(Wallet { credits: i64::MAX }));
+        assert_eq!(
+            wallet.credit(11),
+            Err(WalletError::Overflow {
+                balance: i64::MAX - 10,
+                amount: 11
+            })
+        );
+        assert_eq!(
+            wallet.credit(11).unwrap_err().to_string(),
+            "crediting 11 credits to a wallet holding 9223372036854775797 overflows"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn fractional_credits_are_truncated() {
+        let read = |json: &str| serde_json::from_str::<Wallet>(json);
+        assert_eq!(
+            read(r#"{"credits": 300}"#).unwrap(),
+            Wallet { credits: 300 }
+        );
+        assert_eq!(
+            read(r#"{"credits": 300.0}"#).unwrap(),
+            Wallet { credits: 300 }
+        );
+        assert_eq!(
+            read(r#"{"credits": 299.99}"#).unwrap(),
+            Wallet { credits: 299 }
+        );
+        assert_eq!(read(r#"{"credits": -0.5}"#).unwrap(), Wallet { credits: 0 });
+        assert!(read(r#"{"credits": 1e19}"#).is_err());
+        assert!(read(r#"{"credits": "300"}"#).is_err());
+        assert_eq!(
+            serde_json::to_string(&Wallet { credits: 300 }).unwrap(),
+            r#"{"credits":300}"#
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn truncation_is_reported() {
+        assert_eq!(
+            read_wallet(r#"{"credits": 299.99}"#).unwrap(),
+            (
+                Wallet { credits: 299 },
+                Some("Truncated fractional wallet credits 299.99 to 299".to_string())
+            )
+        );
+        assert_eq!(
+            read_wallet(r#"{"credits": 300.0}"#).unwrap(),
+            (Wallet { credits: 300 }, None)
+        );
+        assert_eq!(
+            read_wallet(r#"{"credits": 300}"#).unwrap(),
+            (Wallet { credits: 300 }, None)
+        );
+        assert!(read_wallet(r#"{"credits": -1e19}"#).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn bincode_round_trip() {
+        let wallet = Wallet { credits: -42 };
+        let bytes = bincode::serialize(&wallet).unwrap();
+        assert_eq!(bincode::deserialize::<Wallet>(&bytes).unwrap(), wallet);
+    }
+}
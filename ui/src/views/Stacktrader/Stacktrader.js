@@ -155,7 +155,7 @@ class Stacktrader extends Component {
         toast.error("Resource is already being mined")
       }).catch(_err => {
         this.client.call(`decs.components.${this.state.shard}.${this.state.entity_id}.extractor`, 'set', extractor).then(_res => {
-          this.client.call(`${target}.mining_lock`, 'set', { extractor: `decs.components.${this.state.shard}.${this.state.entity_id}.extractor` })
+          this.client.call(`${target}.mining_lock`, 'set', { extractor: `decs.components.${this.state.shard}.${this.state.entity_id}.extractor`, owner: this.state.entity_id })
           this.client.get(`decs.components.${this.state.shard}.${this.state.entity_id}.extractor`).then(extractor => {
             this.setState({ extractor })
             extractor.on('change', this.onUpdate)